@@ -10,6 +10,9 @@ use paste::paste;
 use crate::dma::DmaInput;
 #[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
 use crate::dma::{self, ChannelCfg, DmaChannel};
+// Used by `Adc::dma_source()`; `Adc` isn't implemented on the other families.
+#[cfg(any(feature = "f3", feature = "l4", feature = "g4", feature = "h7"))]
+use crate::dma_route;
 use crate::{
     clocks::Clocks,
     pac::{self, RCC},
@@ -38,6 +41,24 @@ cfg_if! {
     }
 }
 
+// The VBAT channel, and the ratio of its internal resistor bridge. Like VREFINT, VBAT is only
+// connected to some ADCs; eg ADC1 on most families, and ADC3 on H7.
+cfg_if! {
+    if #[cfg(any(feature = "h7", feature = "g4"))] {
+        const VBAT_CH: u8 = 17;
+        #[cfg(feature = "h7")]
+        const VBAT_RATIO: f32 = 4.;
+        #[cfg(feature = "g4")]
+        const VBAT_RATIO: f32 = 3.;
+    } else if #[cfg(feature = "f303")] {
+        const VBAT_CH: u8 = 17;
+        const VBAT_RATIO: f32 = 2.;
+    } else if #[cfg(any(feature = "l4", feature = "l5"))] {
+        const VBAT_CH: u8 = 18;
+        const VBAT_RATIO: f32 = 3.;
+    }
+}
+
 const MAX_ADVREGEN_STARTUP_US: u32 = 10;
 
 #[derive(Clone, Copy, PartialEq)]
@@ -51,7 +72,8 @@ pub enum AdcDevice {
     Five,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select a trigger. Sets CFGR reg, EXTSEL field. See G4 RM, table 163: ADC1/2 - External
 /// triggers for regular channels.
@@ -93,7 +115,8 @@ pub enum TriggerEdge {
     HardwareBoth = 0b11,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// ADC interrupts. See L44 RM, section 16.5: ADC interrupts. Set in the IER register, and cleared
 /// in the ISR register.
@@ -130,7 +153,8 @@ pub enum AdcInterrupt {
 /// There is always an overhead of 13 ADC clock cycles.
 /// E.g. For Sampletime T_19 the total conversion time (in ADC clock cycles) is
 /// 13 + 19 = 32 ADC Clock Cycles
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum SampleTime {
     /// 1.5 ADC clock cycles (2.5 on G4)
@@ -167,7 +191,8 @@ pub enum InputType {
     Differential = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// ADC operation mode
 pub enum OperationMode {
@@ -178,7 +203,8 @@ pub enum OperationMode {
 
 // todo: Check the diff ways of configuring clock; i don't think teh enum below covers all.(?)
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// ADC Clock mode
 /// (L44 RM, Section 16.4.3) The input clock is the same for the three ADCs and can be selected between two different
@@ -208,7 +234,8 @@ pub enum ClockMode {
 }
 
 /// Sets ADC clock prescaler; ADCx_CCR register, PRESC field.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum Prescaler {
     D1 = 0b0000,
@@ -304,7 +331,8 @@ impl Default for Align {
 // }
 
 /// Initial configuration data for the ADC peripheral.
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct AdcConfig {
     /// ADC clock mode. Defaults to AHB clock rcc_hclk3 (or hclk) divided by 2.
     pub clock_mode: ClockMode,
@@ -917,6 +945,57 @@ macro_rules! hal {
                 };
             }
 
+            // todo: H7B3 has no ADC3, which VBAT is connected to on H7.
+            #[cfg(not(feature = "h7b3"))]
+            /// Connect VBAT to its ADC channel, through its resistor bridge. Disable it when not
+            /// measuring, since the bridge drains the battery. Sets ADC_CCR register, VBATEN field
+            /// (CH18SEL on L4 and L5, and VBATSEL on G4).
+            pub fn enable_vbat(&mut self) {
+                let common_regs = unsafe { &*pac::$ADC_COMMON::ptr() };
+
+                cfg_if! {
+                    if #[cfg(any(feature = "l4", feature = "l5"))] {
+                        common_regs.ccr.modify(|_, w| w.ch18sel().set_bit());
+                    } else if #[cfg(feature = "g4")] {
+                        common_regs.ccr.modify(|_, w| w.vbatsel().set_bit());
+                    } else {
+                        common_regs.ccr.modify(|_, w| w.vbaten().set_bit());
+                    }
+                }
+            }
+
+            #[cfg(not(feature = "h7b3"))]
+            /// Disconnect VBAT from its ADC channel.
+            pub fn disable_vbat(&mut self) {
+                let common_regs = unsafe { &*pac::$ADC_COMMON::ptr() };
+
+                cfg_if! {
+                    if #[cfg(any(feature = "l4", feature = "l5"))] {
+                        common_regs.ccr.modify(|_, w| w.ch18sel().clear_bit());
+                    } else if #[cfg(feature = "g4")] {
+                        common_regs.ccr.modify(|_, w| w.vbatsel().clear_bit());
+                    } else {
+                        common_regs.ccr.modify(|_, w| w.vbaten().clear_bit());
+                    }
+                }
+            }
+
+            #[cfg(not(feature = "h7b3"))]
+            /// Measure VBAT, in Volts. Connects VBAT, takes a reading, and disconnects it. This
+            /// uses the longest sample time on the VBAT channel; reset it if you use the channel for
+            /// something else.
+            pub fn read_vbat(&mut self) -> f32 {
+                self.enable_vbat();
+
+                self.set_sample_time(VBAT_CH, SampleTime::T601);
+                let reading = self.read(VBAT_CH);
+                self.stop_conversions();
+
+                self.disable_vbat();
+
+                self.reading_to_voltage(reading) * VBAT_RATIO
+            }
+
             /// Convert a raw measurement into a voltage in Volts, using the calibrated VDDA.
             /// See RM0394, section 16.4.34
             pub fn reading_to_voltage(&self, reading: u16) -> f32 {
@@ -998,6 +1077,26 @@ macro_rules! hal {
                 });
             }
 
+            #[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+            /// Enable a DMA request on each conversion, for use as the source of a DMA route. (See the
+            /// `dma_route` module). `request` is this ADC's DMA input. Set the sequence and start
+            /// conversions separately.
+            pub fn dma_source(&mut self, request: dma::DmaInput) -> dma_route::Endpoint<u16> {
+                self.stop_conversions();
+
+                // Circular mode keeps requests going past the DMA transfer count.
+                #[cfg(not(feature = "h7"))]
+                self.regs.cfgr.modify(|_, w| {
+                    w.dmacfg().set_bit();
+                    w.dmaen().set_bit()
+                });
+
+                #[cfg(feature = "h7")]
+                self.regs.cfgr.modify(|_, w| unsafe { w.dmngt().bits(0b11) });
+
+                dma_route::Endpoint::register(&self.regs.dr as *const _ as u32, request)
+            }
+
             #[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
             /// Take a reading, using DMA. Sets conversion sequence; no need to set it directly.
             /// Note that the `channel` argument is unused on F3 and L4, since it is hard-coded,
@@ -1194,6 +1293,36 @@ macro_rules! hal {
         unsafe { self.regs.isr.read().bits() }
     }
         }
+
+        #[cfg(any(feature = "f3", feature = "l4", feature = "g4", feature = "h7"))]
+        impl crate::analog_loop::AdcCapture for Adc<pac::$ADC> {
+            unsafe fn start_capture(
+                &mut self,
+                buf: &mut [u16],
+                channel: u8,
+                trigger: Trigger,
+                dma_channel: DmaChannel,
+                dma_periph: dma::DmaPeriph,
+            ) -> DmaChannel {
+                self.set_trigger(trigger, TriggerEdge::HardwareRising);
+
+                let channel_cfg = ChannelCfg {
+                    priority: dma::Priority::High,
+                    circular: dma::Circular::Enabled,
+                    ..Default::default()
+                };
+                self.read_dma(buf, &[channel], dma_channel, channel_cfg, dma_periph);
+
+                // On F3 and L4, `read_dma` uses the ADC's fixed channel.
+                #[cfg(any(feature = "f3", feature = "l4"))]
+                let dma_channel = match self.device {
+                    AdcDevice::One => DmaInput::Adc1.dma1_channel(),
+                    _ => DmaInput::Adc2.dma1_channel(),
+                };
+
+                dma_channel
+            }
+        }
     }
 }
 
@@ -1233,6 +1362,8 @@ cfg_if! {
     if #[cfg(feature = "h7")] {
         hal!(ADC1, ADC12_COMMON, adc1, 12);
         hal!(ADC2, ADC12_COMMON, adc2, 12);
+        // H7B3 has no ADC3.
+        #[cfg(not(feature = "h7b3"))]
         hal!(ADC3, ADC3_COMMON, adc3, 3);
     }
 }
@@ -0,0 +1,335 @@
+//! Compile-time checked alternate function (AF) pin binding for SPI, I2C, and U[S]ART. This is an
+//! opt-in layer over the `Pin` API: Pass a tuple of pin types, eg `(PA5, PA6, PA7)`, to a
+//! peripheral's `new_with_pins()` constructor. The pins must be valid for that peripheral's signals,
+//! per the family's AF table; otherwise, this fails to compile. The constructor sets each pin's
+//! mode to its alternate function (and open drain for I2C), then initializes the peripheral as in `new()`.
+//!
+//! The tables cover the common pin mappings only; for other mappings, configure pins with `Pin`
+//! and use `new()`. Use `NoPin` for unused signals, eg MISO on a transmit-only SPI bus.
+//!
+//! Example:
+//! ```rust
+//! let spi = Spi::new_with_pins(dp.SPI1, (PA5, PA6, PA7), Default::default(), BaudRate::Div32);
+//! let uart = Usart::new_with_pins(dp.USART1, (PA9, PA10), 115_200, Default::default(), &clock_cfg);
+//!
+//! // Fails to compile: PB13 isn't an SPI1 SCK pin.
+//! // let spi = Spi::new_with_pins(dp.SPI1, (PB13, PA6, PA7), Default::default(), BaudRate::Div32);
+//! ```
+
+use cfg_if::cfg_if;
+
+use crate::{
+    gpio::{OutputType, Pin, PinMode, Port},
+    pac,
+};
+
+/// A type representing a GPIO pin, or no pin.
+pub trait PinId {
+    /// The pin's port and number; `None` for `NoPin`.
+    const PIN: Option<(Port, u8)>;
+}
+
+/// A placeholder for an unused peripheral signal.
+pub struct NoPin;
+
+impl PinId for NoPin {
+    const PIN: Option<(Port, u8)> = None;
+}
+
+macro_rules! pin_ids {
+    ($port:ident, [$($name:ident: $num:expr),+ $(,)?]) => {
+        $(
+            #[doc = concat!("Pin ", stringify!($name), ".")]
+            pub struct $name;
+
+            impl PinId for $name {
+                const PIN: Option<(Port, u8)> = Some((Port::$port, $num));
+            }
+        )+
+    };
+}
+
+pin_ids!(A, [PA0: 0, PA1: 1, PA2: 2, PA3: 3, PA4: 4, PA5: 5, PA6: 6, PA7: 7, PA8: 8, PA9: 9,
+    PA10: 10, PA11: 11, PA12: 12, PA13: 13, PA14: 14, PA15: 15]);
+pin_ids!(B, [PB0: 0, PB1: 1, PB2: 2, PB3: 3, PB4: 4, PB5: 5, PB6: 6, PB7: 7, PB8: 8, PB9: 9,
+    PB10: 10, PB11: 11, PB12: 12, PB13: 13, PB14: 14, PB15: 15]);
+#[cfg(not(feature = "wl"))]
+pin_ids!(C, [PC0: 0, PC1: 1, PC2: 2, PC3: 3, PC4: 4, PC5: 5, PC6: 6, PC7: 7, PC8: 8, PC9: 9,
+    PC10: 10, PC11: 11, PC12: 12, PC13: 13, PC14: 14, PC15: 15]);
+
+/// A pin that can be an SPI peripheral's SCK signal.
+pub trait SckPin<R>: PinId {
+    const AF: u8;
+}
+
+/// A pin that can be an SPI peripheral's MISO signal.
+pub trait MisoPin<R>: PinId {
+    const AF: u8;
+}
+
+/// A pin that can be an SPI peripheral's MOSI signal.
+pub trait MosiPin<R>: PinId {
+    const AF: u8;
+}
+
+/// A pin that can be an I2C peripheral's SCL signal.
+pub trait SclPin<R>: PinId {
+    const AF: u8;
+}
+
+/// A pin that can be an I2C peripheral's SDA signal.
+pub trait SdaPin<R>: PinId {
+    const AF: u8;
+}
+
+/// A pin that can be a U[S]ART peripheral's TX signal.
+pub trait TxPin<R>: PinId {
+    const AF: u8;
+}
+
+/// A pin that can be a U[S]ART peripheral's RX signal.
+pub trait RxPin<R>: PinId {
+    const AF: u8;
+}
+
+// A SPI bus may omit MISO or MOSI, and a U[S]ART may omit TX or RX.
+impl<R> MisoPin<R> for NoPin {
+    const AF: u8 = 0;
+}
+
+impl<R> MosiPin<R> for NoPin {
+    const AF: u8 = 0;
+}
+
+impl<R> TxPin<R> for NoPin {
+    const AF: u8 = 0;
+}
+
+impl<R> RxPin<R> for NoPin {
+    const AF: u8 = 0;
+}
+
+/// Set a pin to its alternate function mode, if it's not `NoPin`.
+fn connect<P: PinId>(af: u8, output_type: OutputType) {
+    if let Some((port, pin)) = P::PIN {
+        let mut pin = Pin::new(port, pin, PinMode::Alt(af));
+        pin.output_type(output_type);
+    }
+}
+
+/// SCK, MISO, and MOSI pins for an SPI peripheral.
+pub trait SpiPins<R> {
+    /// Set the pins to their alternate function modes.
+    fn connect(&self);
+}
+
+impl<R, SCK, MISO, MOSI> SpiPins<R> for (SCK, MISO, MOSI)
+where
+    SCK: SckPin<R>,
+    MISO: MisoPin<R>,
+    MOSI: MosiPin<R>,
+{
+    fn connect(&self) {
+        connect::<SCK>(SCK::AF, OutputType::PushPull);
+        connect::<MISO>(MISO::AF, OutputType::PushPull);
+        connect::<MOSI>(MOSI::AF, OutputType::PushPull);
+    }
+}
+
+/// SCL and SDA pins for an I2C peripheral.
+pub trait I2cPins<R> {
+    /// Set the pins to their alternate function modes, with open drain outputs.
+    fn connect(&self);
+}
+
+impl<R, SCL, SDA> I2cPins<R> for (SCL, SDA)
+where
+    SCL: SclPin<R>,
+    SDA: SdaPin<R>,
+{
+    fn connect(&self) {
+        connect::<SCL>(SCL::AF, OutputType::OpenDrain);
+        connect::<SDA>(SDA::AF, OutputType::OpenDrain);
+    }
+}
+
+/// TX and RX pins for a U[S]ART peripheral.
+pub trait UsartPins<R> {
+    /// Set the pins to their alternate function modes.
+    fn connect(&self);
+}
+
+impl<R, TX, RX> UsartPins<R> for (TX, RX)
+where
+    TX: TxPin<R>,
+    RX: RxPin<R>,
+{
+    fn connect(&self) {
+        connect::<TX>(TX::AF, OutputType::PushPull);
+        connect::<RX>(RX::AF, OutputType::PushPull);
+    }
+}
+
+/// Implement a signal trait for a peripheral, for a list of pins and their AF numbers.
+macro_rules! af_map {
+    ($signal:ident, $periph:ty, [$(($pin:ident, $af:expr)),+ $(,)?]) => {
+        $(
+            impl $signal<$periph> for $pin {
+                const AF: u8 = $af;
+            }
+        )+
+    };
+}
+
+// AF tables. See the "Alternate function" table in each MCU's datasheet.
+cfg_if! {
+    if #[cfg(feature = "g0")] {
+        af_map!(SckPin, pac::SPI1, [(PA1, 0), (PA5, 0), (PB3, 0)]);
+        af_map!(MisoPin, pac::SPI1, [(PA6, 0), (PA11, 0), (PB4, 0)]);
+        af_map!(MosiPin, pac::SPI1, [(PA2, 0), (PA7, 0), (PA12, 0), (PB5, 0)]);
+
+        af_map!(SckPin, pac::SPI2, [(PB13, 0)]);
+        af_map!(MisoPin, pac::SPI2, [(PB14, 0)]);
+        af_map!(MosiPin, pac::SPI2, [(PB15, 0)]);
+
+        af_map!(SclPin, pac::I2C1, [(PA9, 6), (PB6, 6), (PB8, 6)]);
+        af_map!(SdaPin, pac::I2C1, [(PA10, 6), (PB7, 6), (PB9, 6)]);
+
+        af_map!(SclPin, pac::I2C2, [(PA11, 6), (PB10, 6)]);
+        af_map!(SdaPin, pac::I2C2, [(PA12, 6), (PB11, 6)]);
+
+        af_map!(TxPin, pac::USART1, [(PA9, 1), (PB6, 0)]);
+        af_map!(RxPin, pac::USART1, [(PA10, 1), (PB7, 0)]);
+
+        af_map!(TxPin, pac::USART2, [(PA2, 1)]);
+        af_map!(RxPin, pac::USART2, [(PA3, 1)]);
+    } else {
+        // The other families mostly share mappings: AF5 for SPI1 and SPI2, AF6 for SPI3, AF4 for
+        // I2C, and AF7 for USART.
+        #[cfg(not(feature = "f301"))]
+        af_map!(SckPin, pac::SPI1, [(PA5, 5)]);
+        #[cfg(not(feature = "f301"))]
+        af_map!(MisoPin, pac::SPI1, [(PA6, 5)]);
+        #[cfg(not(feature = "f301"))]
+        af_map!(MosiPin, pac::SPI1, [(PA7, 5)]);
+
+        #[cfg(not(any(feature = "f301", feature = "f410")))]
+        af_map!(SckPin, pac::SPI1, [(PB3, 5)]);
+        #[cfg(not(any(feature = "f301", feature = "f410")))]
+        af_map!(MisoPin, pac::SPI1, [(PB4, 5)]);
+        #[cfg(not(any(feature = "f301", feature = "f410")))]
+        af_map!(MosiPin, pac::SPI1, [(PB5, 5)]);
+
+        #[cfg(not(any(feature = "f373", feature = "f3x4", feature = "wb", feature = "wl")))]
+        af_map!(SckPin, pac::SPI2, [(PB13, 5)]);
+        #[cfg(not(any(feature = "f373", feature = "f3x4", feature = "wb", feature = "wl")))]
+        af_map!(MisoPin, pac::SPI2, [(PB14, 5)]);
+        #[cfg(not(any(feature = "f373", feature = "f3x4", feature = "wb", feature = "wl")))]
+        af_map!(MosiPin, pac::SPI2, [(PB15, 5)]);
+
+        #[cfg(not(any(feature = "f3x4", feature = "f410", feature = "wb", feature = "wl")))]
+        af_map!(SckPin, pac::SPI3, [(PB3, 6), (PC10, 6)]);
+        #[cfg(not(any(feature = "f3x4", feature = "f410", feature = "wb", feature = "wl")))]
+        af_map!(MisoPin, pac::SPI3, [(PB4, 6), (PC11, 6)]);
+        #[cfg(not(any(feature = "f3x4", feature = "f410", feature = "wb", feature = "wl")))]
+        af_map!(MosiPin, pac::SPI3, [(PC12, 6)]);
+        #[cfg(not(any(feature = "f3x4", feature = "f410", feature = "wb", feature = "wl")))]
+        af_map!(MosiPin, pac::SPI3, [(PB5, if cfg!(feature = "h7") { 7 } else { 6 })]);
+
+        // PB6 isn't an I2C1 pin on G4.
+        #[cfg(not(feature = "g4"))]
+        af_map!(SclPin, pac::I2C1, [(PB6, 4)]);
+        #[cfg(feature = "g4")]
+        af_map!(SclPin, pac::I2C1, [(PA15, 4)]);
+        af_map!(SclPin, pac::I2C1, [(PB8, 4)]);
+        af_map!(SdaPin, pac::I2C1, [(PB7, 4)]);
+        #[cfg(not(feature = "wl"))]
+        af_map!(SdaPin, pac::I2C1, [(PB9, 4)]);
+
+        cfg_if! {
+            if #[cfg(feature = "f3")] {
+                #[cfg(not(feature = "f3x4"))]
+                af_map!(SclPin, pac::I2C2, [(PA9, 4)]);
+                #[cfg(not(feature = "f3x4"))]
+                af_map!(SdaPin, pac::I2C2, [(PA10, 4)]);
+            } else if #[cfg(feature = "f4")] {
+                af_map!(SclPin, pac::I2C2, [(PB10, 4)]);
+                #[cfg(any(
+                    feature = "f405",
+                    feature = "f407",
+                    feature = "f427",
+                    feature = "f429",
+                    feature = "f469"
+                ))]
+                af_map!(SdaPin, pac::I2C2, [(PB11, 4)]);
+            } else if #[cfg(feature = "l4")] {
+                af_map!(SclPin, pac::I2C2, [(PB10, 4), (PB13, 4)]);
+                af_map!(SdaPin, pac::I2C2, [(PB11, 4), (PB14, 4)]);
+            } else if #[cfg(any(feature = "l5", feature = "h7"))] {
+                af_map!(SclPin, pac::I2C2, [(PB10, 4)]);
+                af_map!(SdaPin, pac::I2C2, [(PB11, 4)]);
+            } else if #[cfg(feature = "g4")] {
+                af_map!(SclPin, pac::I2C2, [(PA9, 4)]);
+                af_map!(SdaPin, pac::I2C2, [(PA8, 4)]);
+            } else if #[cfg(feature = "wl")] {
+                af_map!(SclPin, pac::I2C2, [(PA12, 4)]);
+                af_map!(SdaPin, pac::I2C2, [(PA11, 4)]);
+            }
+        }
+
+        #[cfg(feature = "h7")]
+        af_map!(SclPin, pac::I2C3, [(PA8, 4)]);
+        #[cfg(feature = "h7")]
+        af_map!(SdaPin, pac::I2C3, [(PC9, 4)]);
+        #[cfg(feature = "wb")]
+        af_map!(SclPin, pac::I2C3, [(PC0, 4)]);
+        #[cfg(feature = "wb")]
+        af_map!(SdaPin, pac::I2C3, [(PC1, 4)]);
+
+        af_map!(TxPin, pac::USART1, [(PA9, 7), (PB6, 7)]);
+        af_map!(RxPin, pac::USART1, [(PA10, 7), (PB7, 7)]);
+
+        #[cfg(not(any(feature = "wb", feature = "wl")))]
+        af_map!(TxPin, pac::USART2, [(PA2, 7)]);
+        #[cfg(not(any(feature = "wb", feature = "wl")))]
+        af_map!(RxPin, pac::USART2, [(PA3, 7)]);
+
+        #[cfg(not(any(
+            feature = "f373",
+            feature = "f401",
+            feature = "f410",
+            feature = "f411",
+            feature = "f412",
+            feature = "f413",
+            feature = "l4x1",
+            feature = "wb",
+            feature = "wl",
+        )))]
+        af_map!(TxPin, pac::USART3, [(PB10, 7), (PC10, 7)]);
+        #[cfg(not(any(
+            feature = "f373",
+            feature = "f401",
+            feature = "f410",
+            feature = "f411",
+            feature = "f412",
+            feature = "f413",
+            feature = "f446",
+            feature = "l4x1",
+            feature = "wb",
+            feature = "wl",
+        )))]
+        af_map!(RxPin, pac::USART3, [(PB11, 7)]);
+        #[cfg(not(any(
+            feature = "f373",
+            feature = "f401",
+            feature = "f410",
+            feature = "f411",
+            feature = "f412",
+            feature = "f413",
+            feature = "l4x1",
+            feature = "wb",
+            feature = "wl",
+        )))]
+        af_map!(RxPin, pac::USART3, [(PC11, 7)]);
+    }
+}
@@ -0,0 +1,264 @@
+//! A synchronized ADC capture to DAC playback loop, for simple audio I/O on MCUs without a codec;
+//! eg effects, or telephony-quality voice paths.
+//!
+//! The ADC and DAC are triggered by the same timer, so they run at exactly the same sample rate.
+//! Each uses circular DMA over a buffer split into two blocks. When the ADC finishes a block,
+//! `service()` calls your processing function with that input block, and the corresponding output block,
+//! while DMA works on the other halves. The DAC plays processed output one buffer length after it
+//! was captured, so total latency is two blocks. Your processing function must complete within one
+//! block period.
+//!
+//! Example:
+//! ```rust
+//! // 16kHz sample rate, on an 80Mhz timer clock. Output TRGO on the update event.
+//! let mut timer = Timer::new_tim6(dp.TIM6, 16_000., Default::default(), &clock_cfg);
+//! timer.set_mastermode(MasterModeSelection::Update);
+//!
+//! fn process(input: &[u16], output: &mut [u16]) {
+//!     for (i, o) in input.iter().zip(output.iter_mut()) {
+//!         *o = *i / 2 + 1_024; // Halve the volume, around mid-scale.
+//!     }
+//! }
+//!
+//! static mut INPUT: [u16; 256] = [0; 256];
+//! static mut OUTPUT: [u16; 256] = [0; 256];
+//!
+//! let mut analog_loop = AnalogLoop::new(unsafe { &mut INPUT }, unsafe { &mut OUTPUT }, process);
+//!
+//! let cfg = AnalogLoopConfig {
+//!     adc_channel: 1,
+//!     adc_trigger: adc::Trigger::Tim6Trgo,
+//!     dac_channel: DacChannel::C1,
+//!     dac_trigger: dac::Trigger::Tim6,
+//!     dma_periph: DmaPeriph::Dma1,
+//!     adc_dma_channel: DmaChannel::C1,
+//!     dac_dma_channel: DmaChannel::C3,
+//!     idle_level: 2_048,
+//! };
+//!
+//! unsafe { analog_loop.start(&mut adc, &mut dac, &cfg) };
+//! timer.enable();
+//!
+//! // In the ADC DMA channel's interrupt handler:
+//! analog_loop.service();
+//! ```
+//!
+//! Configure the ADC in single (not continuous) conversion mode; each trigger converts one sample.
+
+#[cfg(any(feature = "f3", feature = "l4"))]
+use crate::dma::DmaInput;
+use crate::{
+    adc,
+    dac::{self, Dac, DacChannel},
+    dma::{self, ChannelCfg, Circular, DmaChannel, DmaInterrupt, DmaPeriph, Priority},
+    pac,
+    util::RccPeriph,
+};
+
+cfg_if::cfg_if! {
+    if #[cfg(any(feature = "f3", feature = "l412", feature = "g4", feature = "h7b3"))] {
+        use pac::dac1 as dac_p;
+    } else {
+        use pac::dac as dac_p;
+    }
+}
+
+/// An ADC that can capture a single channel on an external trigger, using circular DMA. This is
+/// implemented for `Adc`.
+pub trait AdcCapture {
+    /// Set the trigger, and start a circular DMA transfer of `channel`'s conversions into `buf`.
+    /// Returns the DMA channel used, which is fixed by the ADC on F3 and L4.
+    ///
+    /// # Safety
+    /// `buf` must remain valid while the transfer runs.
+    unsafe fn start_capture(
+        &mut self,
+        buf: &mut [u16],
+        channel: u8,
+        trigger: adc::Trigger,
+        dma_channel: DmaChannel,
+        dma_periph: DmaPeriph,
+    ) -> DmaChannel;
+}
+
+/// Configuration for the analog loop.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AnalogLoopConfig {
+    /// The ADC input channel to capture.
+    pub adc_channel: u8,
+    /// The ADC trigger; set this to the timer's TRGO, or a compare channel.
+    pub adc_trigger: adc::Trigger,
+    /// The DAC output channel to play to.
+    pub dac_channel: DacChannel,
+    /// The DAC trigger; set this to the same timer as `adc_trigger`.
+    pub dac_trigger: dac::Trigger,
+    /// The DMA peripheral used by both transfers.
+    pub dma_periph: DmaPeriph,
+    /// The DMA channel used by the ADC. Unused on F3 and L4, where it's fixed.
+    pub adc_dma_channel: DmaChannel,
+    /// The DMA channel used by the DAC. Unused on F3 and L4, where it's fixed.
+    pub dac_dma_channel: DmaChannel,
+    /// The DAC output before processed data is available, eg mid-scale (2048, for 12 bits).
+    pub idle_level: u16,
+}
+
+/// An ADC to DAC loop, using circular input and output buffers of equal length.
+pub struct AnalogLoop {
+    input: *mut u16,
+    output: *mut u16,
+    len: usize,
+    process: fn(input: &[u16], output: &mut [u16]),
+    dma_periph: DmaPeriph,
+    dma_channel: DmaChannel,
+    dac_dma_channel: DmaChannel,
+    blocks_processed: u32,
+}
+
+impl AnalogLoop {
+    /// Create an analog loop. `input` and `output` must have the same, even length; each is
+    /// processed in two blocks of half this length. `process` is run from `service()` on each input
+    /// block, and must fill the output block.
+    pub fn new(
+        input: &'static mut [u16],
+        output: &'static mut [u16],
+        process: fn(input: &[u16], output: &mut [u16]),
+    ) -> Self {
+        assert!(
+            input.len() == output.len() && input.len().is_multiple_of(2) && !input.is_empty(),
+            "Input and output buffers must have the same, even length."
+        );
+
+        Self {
+            len: input.len(),
+            input: input.as_mut_ptr(),
+            output: output.as_mut_ptr(),
+            process,
+            dma_periph: DmaPeriph::Dma1,
+            dma_channel: DmaChannel::C1,
+            dac_dma_channel: DmaChannel::C1,
+            blocks_processed: 0,
+        }
+    }
+
+    /// Set up the transfers, and wait for the trigger. Start the timer after running this. This
+    /// enables the ADC DMA channel's half transfer and transfer complete interrupts; unmask its
+    /// interrupt line, and run `service()` from its handler.
+    ///
+    /// # Safety
+    /// Sets up DMA transfers using the buffers passed to `new()`.
+    pub unsafe fn start<A, R>(&mut self, adc: &mut A, dac: &mut Dac<R>, cfg: &AnalogLoopConfig)
+    where
+        A: AdcCapture,
+        R: core::ops::Deref<Target = dac_p::RegisterBlock> + RccPeriph,
+    {
+        let output = core::slice::from_raw_parts_mut(self.output, self.len);
+        output.fill(cfg.idle_level);
+
+        self.dma_periph = cfg.dma_periph;
+
+        #[cfg(any(feature = "f3", feature = "l4"))]
+        {
+            self.dac_dma_channel = match cfg.dac_channel {
+                DacChannel::C1 => DmaInput::Dac1Ch1.dma1_channel(),
+                DacChannel::C2 => DmaInput::Dac1Ch2.dma1_channel(),
+            };
+        }
+        #[cfg(not(any(feature = "f3", feature = "l4")))]
+        {
+            self.dac_dma_channel = cfg.dac_dma_channel;
+        }
+
+        let channel_cfg = ChannelCfg {
+            priority: Priority::High,
+            circular: Circular::Enabled,
+            ..Default::default()
+        };
+
+        // The first sample must be in the DAC's holding register before the first trigger.
+        dac.write(cfg.dac_channel, cfg.idle_level);
+        dac.set_trigger(cfg.dac_channel, cfg.dac_trigger);
+        dac.write_dma(
+            output,
+            cfg.dac_channel,
+            self.dac_dma_channel,
+            channel_cfg.clone(),
+            cfg.dma_periph,
+        );
+        dac.enable(cfg.dac_channel);
+
+        let input = core::slice::from_raw_parts_mut(self.input, self.len);
+        self.dma_channel = adc.start_capture(
+            input,
+            cfg.adc_channel,
+            cfg.adc_trigger,
+            cfg.adc_dma_channel,
+            cfg.dma_periph,
+        );
+
+        // No transfers occur until the timer starts, so it's safe to enable the half transfer
+        // interrupt now. `cfg_channel` enables the transfer complete one.
+        dma::enable_interrupt(cfg.dma_periph, self.dma_channel, DmaInterrupt::HalfTransfer);
+    }
+
+    /// Process the input block the ADC just completed, into the matching output block. Run this from
+    /// the ADC DMA channel's interrupt handler; this clears the interrupt flag. The block is selected
+    /// from the half transfer and transfer complete flags. If both are set, the handler fell a block
+    /// behind; this processes the second half, and drops the first, which DMA is overwriting.
+    pub fn service(&mut self) {
+        let (half_flag, complete_flag) = dma::transfer_flags(self.dma_periph, self.dma_channel);
+
+        let half = if complete_flag {
+            1
+        } else if half_flag {
+            0
+        } else {
+            return;
+        };
+
+        if half_flag {
+            dma::clear_interrupt(
+                self.dma_periph,
+                self.dma_channel,
+                DmaInterrupt::HalfTransfer,
+            );
+        }
+        if complete_flag {
+            dma::clear_interrupt(
+                self.dma_periph,
+                self.dma_channel,
+                DmaInterrupt::TransferComplete,
+            );
+        }
+
+        let block_len = self.len / 2;
+        let offset = half * block_len;
+
+        // DMA is working on the other half of each buffer.
+        let (input, output) = unsafe {
+            (
+                core::slice::from_raw_parts(self.input.add(offset), block_len),
+                core::slice::from_raw_parts_mut(self.output.add(offset), block_len),
+            )
+        };
+        (self.process)(input, output);
+
+        self.blocks_processed = self.blocks_processed.wrapping_add(1);
+    }
+
+    /// Stop both DMA transfers. The ADC and DAC remain enabled; stop the timer too.
+    pub fn stop(&mut self) {
+        dma::stop(self.dma_periph, self.dma_channel);
+        dma::stop(self.dma_periph, self.dac_dma_channel);
+    }
+
+    /// The number of blocks processed since `start()`.
+    pub fn blocks_processed(&self) -> u32 {
+        self.blocks_processed
+    }
+
+    /// The number of samples in each block processed by `service()`.
+    pub fn block_len(&self) -> usize {
+        self.len / 2
+    }
+}
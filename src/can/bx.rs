@@ -0,0 +1,143 @@
+//! bxCAN helpers, for use with the `bxcan` crate: Bit timing calculation, filter bank
+//! configuration, and the second CAN peripheral on F4.
+//!
+//! Example:
+//! ```rust
+//! let can = Can::new(dp.CAN1);
+//!
+//! // 500kbps, with an 87.5% sample point, as recommended by CANopen.
+//! let timing = bit_timing(clock_cfg.apb1(), 500_000, 0.875)?;
+//!
+//! let mut can = bxcan::Can::builder(can)
+//!     .set_bit_timing(timing.btr())
+//!     .leave_disabled();
+//!
+//! set_filters(&mut can, &[FilterBank::accept_all()]);
+//! nb::block!(can.enable_non_blocking()).ok();
+//! ```
+
+use bxcan::{
+    filter::{BankConfig, Mask32},
+    Fifo, FilterOwner,
+};
+
+use super::{calc_bit_timing, CanError, TimingLimits};
+
+#[cfg(feature = "f4")]
+use crate::{
+    pac::{CAN2, RCC},
+    util::rcc_en_reset,
+};
+
+const LIMITS: TimingLimits = TimingLimits {
+    max_prescaler: 1_024,
+    max_seg1: 16,
+    max_seg2: 8,
+    min_tq: 8,
+    max_tq: 1 + 16 + 8,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Bit timing parameters, as calculated by `bit_timing()`. See L4 RM, section 44.7.7: Bit timing.
+pub struct BitTiming {
+    /// Clock prescaler; 1 - 1,024.
+    pub prescaler: u16,
+    /// Time segment 1, in time quanta. Includes the propagation segment. 1 - 16.
+    pub seg1: u8,
+    /// Time segment 2, in time quanta. 1 - 8.
+    pub seg2: u8,
+    /// Resynchronization jump width, in time quanta. 1 - 4.
+    pub sjw: u8,
+}
+
+impl BitTiming {
+    /// The value of the BTR register, for `bxcan::CanBuilder::set_bit_timing()`.
+    pub fn btr(&self) -> u32 {
+        ((self.sjw as u32 - 1) << 24)
+            | ((self.seg2 as u32 - 1) << 20)
+            | ((self.seg1 as u32 - 1) << 16)
+            | (self.prescaler as u32 - 1)
+    }
+
+    /// The sample point, as a portion of the bit time.
+    pub fn sample_point(&self) -> f32 {
+        let tq = 1 + self.seg1 as u32 + self.seg2 as u32;
+        (1 + self.seg1 as u32) as f32 / tq as f32
+    }
+}
+
+/// Calculate bit timing for a bitrate, in bits per second, and a sample point, as a portion of the
+/// bit time; eg 0.875 for 87.5%. `pclk` is the peripheral clock, ie APB1. Of the exact matches
+/// for the bitrate, this selects the one with the sample point nearest the one requested, preferring
+/// more time quanta per bit. SJW is set to 1 time quantum.
+pub fn bit_timing(pclk: u32, bitrate: u32, sample_point: f32) -> Result<BitTiming, CanError> {
+    let (prescaler, seg1, seg2) = calc_bit_timing(pclk, bitrate, sample_point, &LIMITS)?;
+
+    Ok(BitTiming {
+        prescaler: prescaler as u16,
+        seg1: seg1 as u8,
+        seg2: seg2 as u8,
+        sjw: 1,
+    })
+}
+
+#[derive(Clone, Copy)]
+/// A filter bank configuration, for `set_filters()`.
+pub struct FilterBank {
+    /// The receive FIFO accepted frames are placed in.
+    pub fifo: Fifo,
+    pub config: BankConfig,
+}
+
+impl FilterBank {
+    /// Accept all frames, into FIFO 0.
+    pub fn accept_all() -> Self {
+        Self {
+            fifo: Fifo::Fifo0,
+            config: BankConfig::Mask32(Mask32::accept_all()),
+        }
+    }
+}
+
+/// Disable all filter banks, then enable `banks` in order, starting at bank 0. Frames only
+/// pass if a filter accepts them; with none enabled, all frames are discarded. On F4, banks
+/// assigned to CAN2 (see `set_split()` in the `bxcan` crate) can't be set this way.
+pub fn set_filters<I: FilterOwner>(can: &mut bxcan::Can<I>, banks: &[FilterBank]) {
+    let mut filters = can.modify_filters();
+    filters.clear();
+
+    for (i, bank) in banks.iter().enumerate() {
+        filters.enable_bank(i as u8, bank.fifo, bank.config);
+    }
+}
+
+#[cfg(feature = "f4")]
+/// Interface to the second CAN peripheral. This doesn't own filter banks; CAN1 assigns them to
+/// it, using the `bxcan` crate's `set_split()` and `slave_filters()`.
+pub struct Can2 {
+    pub regs: CAN2,
+}
+
+#[cfg(feature = "f4")]
+impl Can2 {
+    /// Initialize the CAN2 peripheral, including enabling and resetting its RCC peripheral clock.
+    /// This also enables CAN1's clock, since CAN2 uses CAN1's filter banks. It doesn't reset CAN1.
+    pub fn new(regs: CAN2) -> Self {
+        let rcc = unsafe { &*RCC::ptr() };
+
+        rcc.apb1enr.modify(|_, w| w.can1en().set_bit());
+        rcc_en_reset!(apb1, can2, rcc);
+
+        Self { regs }
+    }
+
+    /// Print the (raw) contents of the status register.
+    pub fn read_status(&self) -> u32 {
+        unsafe { self.regs.msr.read().bits() }
+    }
+}
+
+#[cfg(feature = "f4")]
+unsafe impl bxcan::Instance for Can2 {
+    const REGISTERS: *mut bxcan::RegisterBlock = CAN2::ptr() as *mut _;
+}
@@ -0,0 +1,105 @@
+//! FD CAN helpers, for use with the `fdcan` crate: Nominal and data phase bit timing calculation,
+//! and message RAM layout on H7.
+//!
+//! The `fdcan` crate handles standard and extended filters, the TX FIFO or queue, RX
+//! FIFO interrupts, and bit rate switching.
+//!
+//! Example, for a CAN-FD node at 500kbps nominal, and 2Mbps data, with bit rate switching:
+//! ```rust
+//! let can = Can::new(dp.FDCAN1);
+//!
+//! let mut can = fdcan::FdCan::new(can).into_config_mode();
+//!
+//! // The kernel clock is selected in RCC; eg HSE, or PLLQ.
+//! can.set_nominal_bit_timing(nominal_bit_timing(80_000_000, 500_000, 0.875)?);
+//! can.set_data_bit_timing(data_bit_timing(80_000_000, 2_000_000, 0.75)?);
+//! can.set_frame_transmit(FrameTransmissionConfig::AllowFdCanAndBRS);
+//!
+//! can.set_standard_filter(
+//!     StandardFilterSlot::_0,
+//!     StandardFilter::accept_all_into_fifo0(),
+//! );
+//! can.enable_interrupt_line(InterruptLine::_0, true);
+//! can.enable_interrupt(Interrupt::RxFifo0NewMsg);
+//!
+//! let mut can = can.into_normal();
+//! ```
+
+use core::num::{NonZeroU16, NonZeroU8};
+
+use fdcan::config::{DataBitTiming, NominalBitTiming};
+
+use super::{calc_bit_timing, CanError, TimingLimits};
+
+#[cfg(feature = "h7")]
+/// H743 RM, table 8: Register boundary addresses. CAN message RAM: 0x4000_AC00 - 0x4000_D3FF.
+pub const MESSAGE_RAM_BASE_ADDRESS: u32 = 0x4000_ac00;
+
+#[cfg(feature = "h7")]
+/// The size of each peripheral's message RAM section, in 32-bit words. This matches the layout
+/// the `fdcan` crate uses, which is fixed by hardware on G4.
+pub const MESSAGE_RAM_WORDS: u16 = {
+    use fdcan::message_ram::*;
+    // Filters, RX FIFOs 0 and 1, TX event FIFO, and TX buffers. RX and TX elements are 18 words.
+    STANDARD_FILTER_MAX as u16
+        + 2 * EXTENDED_FILTER_MAX as u16
+        + 2 * 18 * RX_FIFO_MAX as u16
+        + 2 * TX_EVENT_MAX as u16
+        + 18 * TX_FIFO_MAX as u16
+};
+
+// Limits are from the NBTP and DBTP registers, and the ranges `fdcan` accepts.
+const NOMINAL_LIMITS: TimingLimits = TimingLimits {
+    max_prescaler: 512,
+    max_seg1: 255,
+    max_seg2: 127,
+    min_tq: 8,
+    max_tq: 1 + 255 + 127,
+};
+
+const DATA_LIMITS: TimingLimits = TimingLimits {
+    max_prescaler: 31,
+    max_seg1: 31,
+    max_seg2: 15,
+    min_tq: 5,
+    max_tq: 1 + 31 + 15,
+};
+
+/// Calculate nominal (arbitration phase) bit timing for a bitrate in bits per second, and a
+/// sample point as a portion of the bit time; eg 0.875 for 87.5%. `kernel_clock` is the FDCAN kernel
+/// clock. SJW is set to the length of segment 2.
+pub fn nominal_bit_timing(
+    kernel_clock: u32,
+    bitrate: u32,
+    sample_point: f32,
+) -> Result<NominalBitTiming, CanError> {
+    let (prescaler, seg1, seg2) =
+        calc_bit_timing(kernel_clock, bitrate, sample_point, &NOMINAL_LIMITS)?;
+
+    // The limits above ensure these are non-zero, and fit.
+    Ok(NominalBitTiming {
+        prescaler: NonZeroU16::new(prescaler as u16).unwrap(),
+        seg1: NonZeroU8::new(seg1 as u8).unwrap(),
+        seg2: NonZeroU8::new(seg2 as u8).unwrap(),
+        sync_jump_width: NonZeroU8::new(seg2 as u8).unwrap(),
+    })
+}
+
+/// Calculate data phase bit timing, used with bit rate switching. Arguments are as for
+/// `nominal_bit_timing()`. Transceiver delay compensation is enabled for bitrates above 1Mbps.
+pub fn data_bit_timing(
+    kernel_clock: u32,
+    bitrate: u32,
+    sample_point: f32,
+) -> Result<DataBitTiming, CanError> {
+    let (prescaler, seg1, seg2) =
+        calc_bit_timing(kernel_clock, bitrate, sample_point, &DATA_LIMITS)?;
+
+    Ok(DataBitTiming {
+        transceiver_delay_compensation: bitrate > 1_000_000,
+        prescaler: NonZeroU8::new(prescaler as u8).unwrap(),
+        seg1: NonZeroU8::new(seg1 as u8).unwrap(),
+        seg2: NonZeroU8::new(seg2 as u8).unwrap(),
+        sync_jump_width: NonZeroU8::new(seg2 as u8).unwrap(),
+    })
+}
@@ -2,10 +2,17 @@
 //! or [can-fd](https://crates.io/keywords/can-fd) libraries.
 //!
 //! Requires the `can_bx` or `can_fd_g[h]` features. F3, F4, and L4 use BX CAN. G0, G4, L5, and H7 use FD CAN.
+//!
+//! For BX CAN, use `bit_timing()` to calculate bit timing from the APB1 clock, and `set_filters()`
+//! to configure filter banks; see the `bx` module. For FD CAN, use `nominal_bit_timing()` and
+//! `data_bit_timing()`; see the `fd` module.
 
 use cfg_if::cfg_if;
 
-use crate::{pac::RCC, util::rcc_en_reset};
+#[cfg(not(feature = "g4"))]
+use crate::pac::RCC;
+#[cfg(not(any(feature = "g4", feature = "h7", feature = "l5")))]
+use crate::util::rcc_en_reset;
 
 // todo: H5 support.
 cfg_if! {
@@ -19,11 +26,89 @@ cfg_if! {
         use crate::pac::{CAN1 as CAN};
     } else { // eg G0, H7
         use fdcan;
-        // todo: CAN2 on H7.
         use crate::pac::{FDCAN1 as CAN};
     }
 }
 
+#[cfg(feature = "can_bx")]
+pub mod bx;
+#[cfg(feature = "can_bx")]
+pub use bx::*;
+
+#[cfg(any(feature = "can_fd_g", feature = "can_fd_h"))]
+pub mod fd;
+#[cfg(any(feature = "can_fd_g", feature = "can_fd_h"))]
+pub use fd::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// CAN errors.
+pub enum CanError {
+    /// The requested bitrate can't be generated from the peripheral clock, or the
+    /// sample point isn't achievable.
+    BitTiming,
+}
+
+/// Limits on bit timing fields, in time quanta, or clock divisions for the prescaler.
+pub(crate) struct TimingLimits {
+    pub max_prescaler: u32,
+    pub max_seg1: u32,
+    pub max_seg2: u32,
+    /// Time quanta per bit. 8 or more is recommended for reliable resynchronization.
+    pub min_tq: u32,
+    pub max_tq: u32,
+}
+
+/// Find the prescaler, and segment 1 and 2 lengths for a bitrate and sample point, given the
+/// peripheral's limits. Of exact matches for the bitrate, selects the one with the sample point
+/// nearest the one requested, preferring lower prescalers, ie more time quanta per bit.
+/// Returns `(prescaler, seg1, seg2)`.
+pub(crate) fn calc_bit_timing(
+    clock: u32,
+    bitrate: u32,
+    sample_point: f32,
+    limits: &TimingLimits,
+) -> Result<(u32, u32, u32), CanError> {
+    if bitrate == 0 || !(0.5..1.).contains(&sample_point) {
+        return Err(CanError::BitTiming);
+    }
+
+    let mut best: Option<((u32, u32, u32), f32)> = None;
+
+    for prescaler in 1..=limits.max_prescaler {
+        let tq_rate = match bitrate.checked_mul(prescaler) {
+            Some(r) if r <= clock => r,
+            _ => break, // Higher prescalers only increase this.
+        };
+        if clock % tq_rate != 0 {
+            continue;
+        }
+        let tq = clock / tq_rate;
+        if tq < limits.min_tq {
+            break; // Higher prescalers only reduce this.
+        }
+        if tq > limits.max_tq {
+            continue;
+        }
+
+        // One time quantum for sync; the sample point is at the end of segment 1.
+        let sample_tq = (sample_point * tq as f32 + 0.5) as u32;
+        let seg1 = sample_tq.saturating_sub(1).clamp(1, limits.max_seg1);
+        let seg2 = tq - 1 - seg1;
+        if seg2 < 1 || seg2 > limits.max_seg2 {
+            continue;
+        }
+
+        let error = ((1 + seg1) as f32 / tq as f32 - sample_point).abs();
+        match best {
+            Some((_, best_error)) if best_error <= error => (),
+            _ => best = Some(((prescaler, seg1, seg2), error)),
+        }
+    }
+
+    best.map(|(t, _)| t).ok_or(CanError::BitTiming)
+}
+
 cfg_if! {
     if #[cfg(feature = "g4")] {
         pub mod g4;
@@ -53,6 +138,11 @@ cfg_if! {
 
                         // set_message_ram_layout();
 
+                    } else if #[cfg(feature = "l5")] {
+                        // FDCAN is on APB1ENR2, which `rcc_en_reset` doesn't support.
+                        rcc.apb1enr2.modify(|_, w| w.fdcan1en().set_bit());
+                        rcc.apb1rstr2.modify(|_, w| w.fdcan1rst().set_bit());
+                        rcc.apb1rstr2.modify(|_, w| w.fdcan1rst().clear_bit());
                     } else {
                         rcc_en_reset!(apb1, fdcan, rcc);
                     }
@@ -64,8 +154,10 @@ cfg_if! {
             /// Print the (raw) contents of the status register.
             pub fn read_status(&self) -> u32 {
                 cfg_if! {
-                    if #[cfg(any(feature = "h7", feature = "l5"))] {
+                    if #[cfg(feature = "h7")] {
                         unsafe { self.regs.psr.read().bits() }
+                    } else if #[cfg(feature = "l5")] {
+                        unsafe { self.regs.fdcan_psr.read().bits() }
                     } else {
                         unsafe { self.regs.msr.read().bits() }
                     }
@@ -84,7 +176,14 @@ cfg_if! {
         /// This must be done after initial setup (Enabling RCC clocks most-likely).
         pub fn set_message_ram_layout() {
             let regs = unsafe { &(*CAN::ptr()) };
+            set_message_ram_layout_internal(regs, 0);
+        }
 
+        #[cfg(feature = "h7")]
+        /// Set the message RAM layout for an FDCAN peripheral, with its section starting at
+        /// `word_addr`, in 32-bit words from the start of message RAM. Each section uses
+        /// `MESSAGE_RAM_WORDS` words.
+        fn set_message_ram_layout_internal(regs: &crate::pac::fdcan1::RegisterBlock, word_addr: u16) {
             // RM, section 56.4.1: Operation modes: "Access to the FDCAN configuration registers is only
             // enabled when both INIT bit in FDCAN_CCCR register and CCE bit in FDCAN_CCCR register are set.
             // Note: we do this as 2 separate writes. RM: "CCE bit in FDCAN_CCCR register can only be set/cleared while INIT bit in FDCAN_CCCR
@@ -95,7 +194,7 @@ cfg_if! {
             regs.cccr.modify(|_, w| w.cce().set_bit());
             while regs.cccr.read().cce().bit_is_clear() {}
 
-            let mut word_addr = 0x000; // todo: 0x400 for FDCAN2?
+            let mut word_addr = word_addr;
 
             use fdcan::message_ram::*;
 
@@ -168,12 +267,15 @@ cfg_if! {
                 }
 
                 unsafe impl bxcan::FilterOwner for Can {
-                    #[cfg(any(feature = "f3", feature = "f4"))]
+                    // F4 shares 28 filter banks between CAN1 and CAN2. F3 and L4 have a single CAN,
+                    // with 14.
+                    #[cfg(feature = "f4")]
                     const NUM_FILTER_BANKS: u8 = 28;
-                    #[cfg(any(feature = "l4"))]
+                    #[cfg(any(feature = "f3", feature = "l4"))]
                     const NUM_FILTER_BANKS: u8 = 14;
                 }
 
+                #[cfg(feature = "f4")]
                 unsafe impl bxcan::MasterInstance for Can {}
             } else {
                 unsafe impl fdcan::Instance for Can {
@@ -183,13 +285,55 @@ cfg_if! {
                 unsafe impl fdcan::message_ram::Instance for Can {
                     #[cfg(feature = "h7")]
                     // H743 RM, table 8. "Register boundary addresses". 0x4000_AC00 - 0x4000_D3FF". CAN message RAM.
+                    const MSG_RAM: *mut fdcan::message_ram::RegisterBlock = (MESSAGE_RAM_BASE_ADDRESS as *mut _);
+                    #[cfg(feature = "l5")]
+                    // L552 RM, table 4: Memory map. FDCAN1 message RAM: 0x4000_AC00. The layout is fixed by hardware.
                     const MSG_RAM: *mut fdcan::message_ram::RegisterBlock = (0x4000_ac00 as *mut _);
-                    // todo: (0x4000_ac00 + 0x1000) for H7, CAN2.
                     // todo: (0x4000_a750 as *mut _) for G4, CAN2
                     // todo: (0x4000_aaa0 as *mut _) fir G4 CAN3.
                 }
             }
         }
+
+        #[cfg(all(feature = "h7", feature = "can_fd_h"))]
+        /// Interface to the second FDCAN peripheral. It shares message RAM with FDCAN1; its
+        /// section follows FDCAN1's.
+        pub struct Can2 {
+            pub regs: crate::pac::FDCAN2,
+        }
+
+        #[cfg(all(feature = "h7", feature = "can_fd_h"))]
+        impl Can2 {
+            /// Initialize the FDCAN2 peripheral, including enabling its RCC peripheral clock. Its RCC
+            /// enable and reset are shared with FDCAN1, so this doesn't reset it.
+            pub fn new(regs: crate::pac::FDCAN2) -> Self {
+                let rcc = unsafe { &*RCC::ptr() };
+                rcc.apb1henr.modify(|_, w| w.fdcanen().set_bit());
+
+                Self { regs }
+            }
+
+            /// Set this peripheral's message RAM layout. See the note on `set_message_ram_layout()`.
+            pub fn set_message_ram_layout(&mut self) {
+                set_message_ram_layout_internal(&self.regs, MESSAGE_RAM_WORDS);
+            }
+
+            /// Print the (raw) contents of the status register.
+            pub fn read_status(&self) -> u32 {
+                unsafe { self.regs.psr.read().bits() }
+            }
+        }
+
+        #[cfg(all(feature = "h7", feature = "can_fd_h"))]
+        unsafe impl fdcan::Instance for Can2 {
+            const REGISTERS: *mut fdcan::RegisterBlock = crate::pac::FDCAN2::ptr() as *mut _;
+        }
+
+        #[cfg(all(feature = "h7", feature = "can_fd_h"))]
+        unsafe impl fdcan::message_ram::Instance for Can2 {
+            const MSG_RAM: *mut fdcan::message_ram::RegisterBlock =
+                ((MESSAGE_RAM_BASE_ADDRESS + MESSAGE_RAM_WORDS as u32 * 4) as *mut _);
+        }
         // todo: H5 support.
 
     }
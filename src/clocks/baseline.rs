@@ -8,9 +8,12 @@ use cfg_if::cfg_if;
 
 #[cfg(any(feature = "l4", feature = "l5", feature = "wb", feature = "g4"))]
 use crate::pac::CRS;
+#[cfg(not(any(feature = "g0", feature = "wl")))]
+use crate::clocks::validate_usb_speed;
 use crate::{
-    clocks::RccError,
+    clocks::{ClockFreqs, RccError},
     pac::{self, FLASH, RCC},
+    power::{self, VoltageRange},
     util::rcc_en_reset,
     MAX_ITERS,
 };
@@ -18,7 +21,8 @@ use crate::{
 // todo: WB is missing second LSI2, and perhaps other things.
 
 #[cfg(not(any(feature = "g0", feature = "wl")))]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum Clk48Src {
     // Note: On G4 which only has HSI48 and PLLQ, PLLSai1 and MSI are marked "reserved", and
@@ -42,7 +46,8 @@ pub enum CrsSyncSrc {
 }
 
 #[cfg(not(any(feature = "g0", feature = "g4")))]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PllSrc {
     None,
     Msi(MsiRange),
@@ -51,7 +56,8 @@ pub enum PllSrc {
 }
 
 #[cfg(any(feature = "g0", feature = "g4"))]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PllSrc {
     None,
     Hsi,
@@ -81,7 +87,8 @@ impl PllSrc {
 }
 
 #[cfg(any(feature = "l4", feature = "l5", feature = "wb", feature = "wl"))]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select the system clock used when exiting Stop mode. Sets RCC_CFGR register, STOPWUCK field.
 pub enum StopWuck {
@@ -91,7 +98,8 @@ pub enum StopWuck {
 
 cfg_if! {
 if #[cfg(feature = "g0")] {
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Clock input source, also known as system clock switch. Sets RCC_CFGR register, SW field.
 pub enum InputSrc {
 Hsi,
@@ -115,7 +123,8 @@ Self::Lse => 0b100,
 }
 }
 } else if #[cfg(feature = "g4")] {
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum InputSrc {
 Hsi,
 Hse(u32), // freq in Hz,
@@ -134,7 +143,8 @@ Self::Pll(_) => 0b11,
 }
 }
 } else {  // ie L4 and L5
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum InputSrc {
 Msi(MsiRange),
 Hsi,
@@ -158,7 +168,8 @@ Self::Pll(_) => 0b11,
 }
 
 #[cfg(feature = "wb")]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// RF system wakeup clock source selection
 pub enum RfWakeupSrc {
@@ -169,25 +180,9 @@ pub enum RfWakeupSrc {
     Hse = 0b11,
 }
 
-// L4 uses 0 - 4 only. Others use 1 - 15, but it's not clear when you'd
-// set more than WS5 or so.
-#[derive(Clone, Copy)]
-#[repr(u8)]
-/// Represents Flash wait states in the FLASH_ACR register.
-enum WaitState {
-    W0 = 0,
-    W1 = 1,
-    W2 = 2,
-    #[cfg(not(feature = "wl"))]
-    W3 = 3,
-    #[cfg(not(any(feature = "wb", feature = "wl")))]
-    W4 = 4,
-    #[cfg(feature = "l5")]
-    W5 = 5,
-}
-
 #[cfg(not(any(feature = "g0", feature = "g4")))]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Specify the range of MSI - this is effectively it's oscillation speed.
 pub enum MsiRange {
@@ -228,6 +223,8 @@ impl MsiRange {
 
 /// Configures the speeds, and enable status of an individual PLL (PLL1, or SAIPLL). Note that the `enable`
 /// field has no effect for PLL1.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct PllCfg {
     /// Only relevant for PLLSAI1.
     pub enabled: bool,
@@ -297,7 +294,8 @@ impl PllCfg {
 }
 
 #[cfg(not(any(feature = "l5", feature = "g4")))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum Pllm {
     Div1 = 0b000,
@@ -311,7 +309,8 @@ pub enum Pllm {
 }
 
 #[cfg(any(feature = "l5", feature = "g4"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum Pllm {
     Div1 = 0b0000,
@@ -369,7 +368,8 @@ impl Pllm {
 }
 
 #[cfg(any(feature = "g0", feature = "wb"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Main PLL division factor for PLLCLK (system clock). Also usd for PllQ.
 /// Sets `PLLCFGR` reg.
@@ -399,7 +399,8 @@ impl Pllr {
 }
 
 #[cfg(not(any(feature = "g0", feature = "wb")))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Main PLL division factor for PLLCLK (system clock). G4 RM 7.4.4. Also used to set PLLQ.
 pub enum Pllr {
@@ -421,7 +422,8 @@ impl Pllr {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Divisor for PLLP. Sets `PLLCFGR` reg, `PLLP` field.
 pub enum Pllp {
@@ -438,7 +440,8 @@ impl Pllp {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Division factor for the AHB clock. Also known as AHB Prescaler. L4 RM, 6.4.3
 /// on WB, used for all 3 HCLK prescalers.
@@ -490,7 +493,8 @@ impl HclkPrescaler {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// For use with `RCC_APBPPRE1`, and `RCC_APBPPRE2`. Ie, low-speed and high-speed prescalers respectively.
 pub enum ApbPrescaler {
@@ -513,7 +517,8 @@ impl ApbPrescaler {
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// SAI clock input source. Sets RCC_CCIPR register, SAIxSEL fields.
 pub enum SaiSrc {
@@ -528,7 +533,8 @@ pub enum SaiSrc {
 }
 
 #[cfg(any(feature = "g0", feature = "g4"))]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// CAN clock input source. Sets RCC_CCIPR register, FDCANSEL field.
 pub enum CanSrc {
@@ -543,6 +549,8 @@ pub enum CanSrc {
 /// Settings used to configure clocks. Create this struct by using its `Default::default()`
 /// implementation, then modify as required, referencing your RM's clock tree,
 /// or Stm32Cube IDE's interactive clock manager. Apply settings by running `.setup()`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Clocks {
     /// The input source for the system and peripheral clocks. Eg HSE, HSI, PLL etc
     pub input_src: InputSrc,
@@ -635,19 +643,34 @@ impl Clocks {
         }
         }
 
+        // Raise the voltage range if the HCLK frequency requires it. We need to do this before
+        // increasing the frequency. We leave a higher range as-is, eg if set by the user
+        // with `power::set_voltage_range()`.
+        #[cfg(feature = "g4")]
+        let required = if self.boost_mode {
+            VoltageRange::Range1Boost
+        } else {
+            VoltageRange::required(hclk)
+        };
+        #[cfg(not(feature = "g4"))]
+        let required = VoltageRange::required(hclk);
+
+        // On G4, this is set if we're using range 1 boost mode, which requires the AHB prescaler
+        // to be set to /2 while switching to the new frequency.
+        #[cfg(feature = "g4")]
+        let boost_switch = required == VoltageRange::Range1Boost;
+
         cfg_if! {
         if #[cfg(feature = "g4")] {
-        if self.boost_mode {
+        if boost_switch {
         // The sequence to switch from Range1 normal mode to Range1 boost mode is:
         // 1. The system clock must be divided by 2 using the AHB prescaler before switching to a
         // higher system frequency.
         rcc.cfgr.modify(|_, w| unsafe { w.hpre().bits(HclkPrescaler::Div2 as u8) });
-        // 2. Clear the R1MODE bit is in the PWR_CR5 register.
-        let pwr = unsafe { &(*pac::PWR::ptr()) };
-        pwr.cr5.modify(|_, w| w.r1mode().clear_bit());
         }
 
         // (Remaining steps accomplished below)
+        // 2. Clear the R1MODE bit is in the PWR_CR5 register.
         // 3. Adjust the number of wait states according to the new frequency target in range1 boost
         // mode
         // 4. Configure and switch to new system frequency.
@@ -656,91 +679,18 @@ impl Clocks {
         }
         }
 
-        cfg_if! {
-        if #[cfg(feature = "l4")] {  // RM section 3.3.3
-        let wait_state = if hclk <= 16_000_000 {
-        WaitState::W0
-        } else if hclk <= 32_000_000 {
-        WaitState::W1
-        } else if hclk <= 48_000_000 {
-        WaitState::W2
-        } else if hclk <= 64_000_000 {
-        WaitState::W3
-        } else {
-        WaitState::W4
-        };
-        } else if #[cfg(feature = "l5")] {  // RM section 6.3.3
-        let wait_state = if hclk <= 20_000_000 {
-        WaitState::W0
-        } else if hclk <= 40_000_000 {
-        WaitState::W1
-        } else if hclk <= 60_000_000 {
-        WaitState::W2
-        } else if hclk <= 80_000_000 {
-        WaitState::W3
-        } else if hclk <= 100_000_000 {
-        WaitState::W4
-        } else {
-        WaitState::W5
-        };
-        } else if #[cfg(feature = "g0")] {  // G0. RM section 3.3.4
-        let wait_state = if hclk <= 24_000_000 {
-        WaitState::W0
-        } else if hclk <= 48_000_000 {
-        WaitState::W1
+        let current = power::voltage_range();
+        let vos_range = if required > current {
+            power::apply_voltage_range(required)?;
+            required
         } else {
-        WaitState::W2
+            current
         };
-        } else if #[cfg(feature = "wb")] {  // WB. RM section 3.3.4, Table 4.
-        // Note: This applies to HCLK4 HCLK. (See HCLK4 used above for hclk var.)
-        let wait_state = if hclk <= 18_000_000 {
-        WaitState::W0
-        } else if hclk <= 36_000_000 {
-        WaitState::W1
-        } else if hclk <= 54_000_000 {
-        WaitState::W2
-        } else {
-        WaitState::W3
-        };
-        } else if #[cfg(any(feature = "wb", feature = "wl"))] {  // WL. RM section 3.3.4, Table 5.
-        // Note: This applies to HCLK3 HCLK. (See HCLK3 used above for hclk var.)
-        let wait_state = if hclk <= 18_000_000 {
-        WaitState::W0
-        } else if hclk <= 36_000_000 {
-        WaitState::W1
-        } else {
-        WaitState::W2
-        };
-        } else {  // G4. RM section 3.3.3
-        let wait_state = if self.boost_mode {
-        // Vcore Range 1 boost mode
-        if hclk <= 34_000_000 {
-        WaitState::W0
-        } else if hclk <= 68_000_000 {
-        WaitState::W1
-        } else if hclk <= 102_000_000 {
-        WaitState::W2
-        } else if hclk <= 136_000_000 {
-        WaitState::W3
-        } else {
-        WaitState::W4
-        }
-        } else {
-        // Vcore Range 1 normal mode.
-        if hclk <= 30_000_000 {
-        WaitState::W0
-        } else if hclk <= 60_000_000 {
-        WaitState::W1
-        } else if hclk <= 90_000_000 {
-        WaitState::W2
-        } else if hclk <= 120_000_000 {
-        WaitState::W3
-        } else {
-        WaitState::W4
-        }
-        };
-        }
-        }
+
+        let wait_state = vos_range.wait_states(hclk);
+        // Don't lower wait states until the new system clock is selected, below; the current one may
+        // need them, eg the HSI16 selected by `reconfigure()`.
+        let wait_state_switch = wait_state.max(flash.acr.read().latency().bits());
 
         // Enable instruction and data caches, for a potential performance increase.
         // Note that this can make a significant performance impact for some CPU-bound tasks.
@@ -757,7 +707,7 @@ impl Clocks {
         #[cfg(not(feature = "l5"))]
         flash.acr.modify(|_, w| unsafe {
             // G0: Instruction cache, but no data cache.
-            w.latency().bits(wait_state as u8);
+            w.latency().bits(wait_state_switch);
             #[cfg(not(feature = "g0"))]
             w.dcen().set_bit();
             w.icen().set_bit();
@@ -772,7 +722,7 @@ impl Clocks {
         #[cfg(feature = "l5")]
         flash
             .acr
-            .modify(|_, w| unsafe { w.latency().bits(wait_state as u8) });
+            .modify(|_, w| unsafe { w.latency().bits(wait_state_switch) });
 
         #[cfg(feature = "l5")] // todo: u5 too.
         icache.icache_cr.modify(|_, w| w.en().set_bit());
@@ -915,6 +865,13 @@ impl Clocks {
 
         rcc.cfgr.modify(|_, w| unsafe {
             w.sw().bits(self.input_src.bits());
+            // When switching to boost mode on G4, HPRE stays at /2 until the new frequency is
+            // reached. (Step 5 above)
+            #[cfg(feature = "g4")]
+            if !boost_switch {
+                w.hpre().bits(self.hclk_prescaler as u8);
+            }
+            #[cfg(not(feature = "g4"))]
             w.hpre().bits(self.hclk_prescaler as u8);
             #[cfg(not(feature = "g0"))]
             w.ppre2().bits(self.apb2_prescaler as u8); // HCLK division for APB2.
@@ -1067,6 +1024,25 @@ impl Clocks {
             }
         }
 
+        // G4 boost mode step 5: Wait for at least 1us, then set the AHB prescaler to get the needed
+        // HCLK frequency. The core runs at sysclk / 2 here, so this waits at least 1us.
+        #[cfg(feature = "g4")]
+        if boost_switch {
+            cortex_m::asm::delay(sysclk / 1_000_000 + 1);
+            rcc.cfgr
+                .modify(|_, w| unsafe { w.hpre().bits(self.hclk_prescaler as u8) });
+        }
+
+        if wait_state < wait_state_switch {
+            i = 0;
+            while rcc.cfgr.read().sws().bits() != self.input_src.bits() {
+                wait_hang!(i);
+            }
+            flash
+                .acr
+                .modify(|_, w| unsafe { w.latency().bits(wait_state) });
+        }
+
         // Enable the HSI48 as required, which is used for USB, RNG, etc.
         // Only valid for some devices (On at least L4, and G4.)
         #[cfg(not(any(feature = "g0", feature = "wl")))]
@@ -1434,20 +1410,49 @@ impl Clocks {
     pub fn usb(&self) -> u32 {
     unimplemented!("No USB on G0 or WL");
     }
-    } else if #[cfg(feature = "g4")] {
-    pub fn usb(&self) -> u32 {
-    48_000_000 // Uses hsi48.
-    }
-    } else { // L4 and L5
+    } else {
+    /// Get the USB (CLK48) clock frequency, in hz.
     pub fn usb(&self) -> u32 {
-    match self.clk48_src {
-    Clk48Src::Hsi48 => 48_000_000,
-    Clk48Src::PllSai1 => unimplemented!(),
-    Clk48Src::Pllq => unimplemented!(),
-    Clk48Src::Msi => unimplemented!(),
+        let pll_src = match self.input_src {
+            #[cfg(not(feature = "g4"))]
+            InputSrc::Msi(msi_rng) => PllSrc::Msi(msi_rng),
+            InputSrc::Hsi => PllSrc::Hsi,
+            InputSrc::Hse(freq) => PllSrc::Hse(freq),
+            InputSrc::Pll(pll_src) => pll_src,
+        };
+
+        let pll_input_freq = match pll_src {
+            #[cfg(not(feature = "g4"))]
+            PllSrc::Msi(range) => range.value() as u32,
+            PllSrc::Hsi => 16_000_000,
+            PllSrc::Hse(freq) => freq,
+            PllSrc::None => 0,
+        } / self.pll.divm.value() as u32;
+
+        match self.clk48_src {
+            Clk48Src::Hsi48 => 48_000_000,
+            #[cfg(not(feature = "g4"))]
+            Clk48Src::PllSai1 => {
+                pll_input_freq * self.pllsai1.divn as u32 / self.pllsai1.divq.value() as u32
+            }
+            Clk48Src::Pllq => pll_input_freq * self.pll.divn as u32 / self.pll.divq.value() as u32,
+            #[cfg(not(feature = "g4"))]
+            Clk48Src::Msi => match self.input_src {
+                InputSrc::Msi(range) => range.value() as u32,
+                // Eg set with `enable_msi_48()`.
+                _ => 48_000_000,
+            }
+        }
     }
     }
     }
+
+    #[cfg(not(any(feature = "g0", feature = "wl")))]
+    /// Check that the USB clock is 48Mhz, within the tolerance required by USB full-speed. Note that HSI48,
+    /// and MSI must also be trimmed to meet this: HSI48 with the CRS synchronized to USB SOF or LSE,
+    /// or MSI in PLL mode with the LSE.
+    pub fn validate_usb(&self) -> Result<(), RccError> {
+        validate_usb_speed(self.usb())
     }
 
     /// Get the APB1 peripheral clock frequency frequency, in hz
@@ -1498,6 +1503,96 @@ impl Clocks {
     }
     }
 
+    /// Calculate bus and peripheral kernel clock frequencies, for passing to peripheral
+    /// constructors. U[S]ART and I2C kernel clock selections are read from the RCC CCIPR register,
+    /// so this is accurate if they've been changed from their defaults.
+    pub fn freqs(&self) -> ClockFreqs {
+        let rcc = unsafe { &(*RCC::ptr()) };
+
+        let sysclk = self.sysclk();
+        let pclk1 = self.apb1();
+        let pclk2 = self.apb2();
+
+        cfg_if! {
+            if #[cfg(feature = "l5")] {
+                let ccipr = rcc.ccipr1.read().bits();
+            } else {
+                let ccipr = rcc.ccipr.read().bits();
+            }
+        }
+
+        // RM, RCC_CCIPR: Each U[S]ART and I2C selection uses 2 bits, with the same encoding.
+        // USART: 0: PCLK, 1: SYSCLK, 2: HSI16, 3: LSE. I2C: 0: PCLK, 1: SYSCLK, 2: HSI16.
+        let kernel_clk = |pos: u8, pclk: u32| match (ccipr >> pos) & 0b11 {
+            0 => pclk,
+            1 => sysclk,
+            2 => 16_000_000,
+            _ => 32_768,
+        };
+
+        cfg_if! {
+            if #[cfg(feature = "g0")] {
+                // On G0, only USART1-3 have a kernel clock selection.
+                let usart = [
+                    kernel_clk(0, pclk2),
+                    kernel_clk(2, pclk1),
+                    kernel_clk(4, pclk1),
+                    pclk1, pclk1, pclk1, pclk1, pclk1, pclk1, pclk1,
+                ];
+            } else {
+                let usart = [
+                    kernel_clk(0, pclk2),
+                    kernel_clk(2, pclk1),
+                    kernel_clk(4, pclk1),
+                    kernel_clk(6, pclk1),
+                    kernel_clk(8, pclk1),
+                    pclk2, pclk1, pclk1, pclk1, pclk1,
+                ];
+            }
+        }
+
+        cfg_if! {
+            if #[cfg(any(feature = "g4", feature = "l5"))] {
+                // I2C4 is selected in CCIPR2, with the same encoding.
+                let i2c4 = match rcc.ccipr2.read().bits() & 0b11 {
+                    0 => pclk1,
+                    1 => sysclk,
+                    _ => 16_000_000,
+                };
+            } else {
+                // todo: I2C4 selection on L4 variants that have it.
+                let i2c4 = pclk1;
+            }
+        }
+
+        cfg_if! {
+            if #[cfg(all(feature = "g0", not(any(feature = "g0b1", feature = "g0c1"))))] {
+                // Other G0 variants have no I2C2 selection, and no I2C3; bits 15:14 are I2S1SEL.
+                let (i2c2, i2c3) = (pclk1, pclk1);
+            } else {
+                let (i2c2, i2c3) = (kernel_clk(14, pclk1), kernel_clk(16, pclk1));
+            }
+        }
+
+        ClockFreqs {
+            sysclk,
+            hclk: self.hclk(),
+            pclk1,
+            pclk2,
+            timer1: self.apb1_timer(),
+            timer2: self.apb2_timer(),
+            usart,
+            lpuart1: kernel_clk(10, pclk1),
+            i2c: [
+                kernel_clk(12, pclk1),
+                i2c2,
+                i2c3,
+                i2c4,
+            ],
+            spi: [pclk2, pclk1, pclk1, pclk2, pclk2, pclk2],
+        }
+    }
+
     /// Get the SAI audio clock frequency, in hz
     #[cfg(not(any(feature = "g0", feature = "g4", feature = "wl")))]
     pub fn sai1_speed(&self) -> u32 {
@@ -1698,3 +1793,309 @@ pub fn enable_crs(sync_src: CrsSyncSrc) {
     // Standby mode. When the CRS is not used, the HSI48 RC oscillator runs on its default
     // frequency which is subject to manufacturing process variations
 }
+
+cfg_if! {
+    if #[cfg(any(feature = "l4", feature = "l5"))] {
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Clock output on the MCO pin. Sets RCC_CFGR register, MCOSEL field.
+        pub enum McoSrc {
+            Sysclk = 1,
+            Msi = 2,
+            Hsi = 3,
+            Hse = 4,
+            /// The main PLL's R output.
+            Pll = 5,
+            Lsi = 6,
+            Lse = 7,
+            Hsi48 = 8,
+        }
+    } else if #[cfg(feature = "g4")] {
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Clock output on the MCO pin. Sets RCC_CFGR register, MCOSEL field.
+        pub enum McoSrc {
+            Sysclk = 1,
+            Hsi = 3,
+            Hse = 4,
+            /// The main PLL's R output.
+            Pll = 5,
+            Lsi = 6,
+            Lse = 7,
+            Hsi48 = 8,
+        }
+    } else if #[cfg(feature = "g0")] {
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Clock output on the MCO pin. Sets RCC_CFGR register, MCOSEL field.
+        pub enum McoSrc {
+            Sysclk = 1,
+            #[cfg(any(feature = "g0b1", feature = "g0c1"))]
+            Hsi48 = 2,
+            Hsi = 3,
+            Hse = 4,
+            /// The PLL's R output.
+            Pll = 5,
+            Lsi = 6,
+            Lse = 7,
+        }
+    } else if #[cfg(feature = "wb")] {
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Clock output on the MCO pin. Sets RCC_CFGR register, MCOSEL field.
+        pub enum McoSrc {
+            Sysclk = 1,
+            Msi = 2,
+            Hsi = 3,
+            Hse = 4,
+            /// The main PLL's R output.
+            Pll = 5,
+            Lsi1 = 6,
+            Lsi2 = 7,
+            Lse = 8,
+            Hsi48 = 9,
+        }
+    } else {
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Clock output on the MCO pin. Sets RCC_CFGR register, MCOSEL field.
+        pub enum McoSrc {
+            Sysclk = 1,
+            Msi = 2,
+            Hsi = 3,
+            Hse = 4,
+            /// The main PLL's R output.
+            Pll = 5,
+            Lsi = 6,
+            Lse = 8,
+            PllP = 0b1101,
+            PllQ = 0b1110,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// Divider for the MCO output. Sets RCC_CFGR register, MCOPRE field.
+pub enum McoPrescaler {
+    Div1 = 0,
+    Div2 = 1,
+    Div4 = 2,
+    Div8 = 3,
+    Div16 = 4,
+    #[cfg(feature = "g0")]
+    Div32 = 5,
+    #[cfg(feature = "g0")]
+    Div64 = 6,
+    #[cfg(feature = "g0")]
+    Div128 = 7,
+    #[cfg(any(feature = "g0b1", feature = "g0c1"))]
+    Div256 = 8,
+    #[cfg(any(feature = "g0b1", feature = "g0c1"))]
+    Div512 = 9,
+    #[cfg(any(feature = "g0b1", feature = "g0c1"))]
+    Div1024 = 10,
+}
+
+// MCOSEL is RCC_CFGR bits 27:24, and MCOPRE starts at bit 28. We write these as raw bits, since
+// the fields are missing from some PACs.
+#[cfg(any(feature = "g0b1", feature = "g0c1"))]
+const MCO_MASK: u32 = 0xff << 24;
+#[cfg(not(any(feature = "g0b1", feature = "g0c1")))]
+const MCO_MASK: u32 = 0x7f << 24;
+
+/// Output a clock on the MCO pin, eg to clock an external device, or to check clock settings
+/// with an oscilloscope. The MCO pin (eg PA8) must be set to its alternate function (AF0)
+/// separately.
+pub fn enable_mco(src: McoSrc, prescaler: McoPrescaler) {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    rcc.cfgr.modify(|r, w| unsafe {
+        w.bits((r.bits() & !MCO_MASK) | ((src as u32) << 24) | ((prescaler as u32) << 28))
+    });
+}
+
+/// Stop the MCO output.
+pub fn disable_mco() {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.cfgr
+        .modify(|r, w| unsafe { w.bits(r.bits() & !MCO_MASK) });
+}
+
+#[cfg(any(feature = "l4", feature = "l5", feature = "wb", feature = "wl"))]
+/// Enable MSI PLL mode, where the MSI continuously calibrates itself to the LSE. This makes MSI
+/// accurate enough for USB, and UART without an HSE crystal. The LSE must be enabled and ready
+/// first; returns `RccError::Hardware` if it isn't. Sets RCC_CR register, MSIPLLEN field.
+pub fn enable_msi_pll() -> Result<(), RccError> {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    if rcc.bdcr.read().lserdy().bit_is_clear() {
+        return Err(RccError::Hardware);
+    }
+
+    rcc.cr.modify(|_, w| w.msipllen().set_bit());
+    Ok(())
+}
+
+#[cfg(any(feature = "l4", feature = "l5", feature = "wb", feature = "wl"))]
+/// Disable MSI PLL mode. This must be done before disabling the LSE.
+pub fn disable_msi_pll() {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.cr.modify(|_, w| w.msipllen().clear_bit());
+}
+
+/// The largest HSI16 trim value. The HSITRIM field is 5 bits wide on L412, L4x1, L4x2, L4x3, and
+/// L4x5, and 7 bits wide on other families.
+#[cfg(any(
+    feature = "l412",
+    feature = "l4x1",
+    feature = "l4x2",
+    feature = "l4x3",
+    feature = "l4x5"
+))]
+pub const HSITRIM_MAX: u8 = 31;
+#[cfg(not(any(
+    feature = "l412",
+    feature = "l4x1",
+    feature = "l4x2",
+    feature = "l4x3",
+    feature = "l4x5"
+)))]
+pub const HSITRIM_MAX: u8 = 127;
+
+/// Set the HSI16 trim value, to adjust its frequency. Higher values increase the frequency. The
+/// reset value is 64 on most families (16 on L47x and L48x, which use a 5-bit field), and each
+/// step adjusts the frequency by roughly 0.2 - 0.4%; see the datasheet. Values above
+/// `HSITRIM_MAX` are clamped to it. Sets RCC_ICSCR register, HSITRIM field.
+pub fn set_hsi_trim(trim: u8) {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.icscr
+        .modify(|_, w| unsafe { w.hsitrim().bits(trim.min(HSITRIM_MAX)) });
+}
+
+/// Read the HSI16 trim value. Reads RCC_ICSCR register, HSITRIM field.
+pub fn hsi_trim() -> u8 {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.icscr.read().hsitrim().bits()
+}
+
+/// The number of LSE periods between TIM16 captures, set by the input capture prescaler.
+const LSE_CAPTURE_DIV: u32 = 8;
+/// The number of capture intervals averaged per measurement.
+const LSE_MEASUREMENT_COUNT: u32 = 16;
+
+/// Measure the TIM16 clock frequency, in Hz, by capturing the LSE with it. Compare the result to
+/// `ClockFreqs::timer2` to find the error in the internal oscillator driving the system clock. The
+/// LSE must be enabled and ready. This enables and resets TIM16, and uses it for the duration.
+pub fn measure_with_lse(tim: &mut pac::TIM16) -> Result<u32, RccError> {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    if rcc.bdcr.read().lserdy().bit_is_clear() {
+        return Err(RccError::Hardware);
+    }
+
+    rcc_en_reset!(apb2, tim16, rcc);
+
+    // Route the LSE to TI1.
+    cfg_if! {
+        if #[cfg(any(feature = "g0", feature = "g4"))] {
+            tim.tisel.modify(|_, w| unsafe { w.ti1sel().bits(0b0010) });
+        } else if #[cfg(feature = "wb")] {
+            tim.or.modify(|_, w| unsafe { w.ti1_rmp().bits(0b10) });
+        } else {
+            tim.or1.modify(|_, w| unsafe { w.ti1_rmp().bits(0b10) });
+        }
+    }
+
+    tim.psc.write(|w| unsafe { w.bits(0) });
+    tim.arr.write(|w| unsafe { w.bits(0xffff) });
+    // Capture from TI1, every 8 events.
+    tim.ccmr1_input().modify(|_, w| unsafe {
+        w.cc1s().bits(0b01);
+        w.ic1psc().bits(0b11)
+    });
+    tim.ccer.modify(|_, w| w.cc1e().set_bit());
+    tim.cr1.modify(|_, w| w.cen().set_bit());
+
+    let result = capture_lse_ticks(tim);
+
+    tim.cr1.modify(|_, w| w.cen().clear_bit());
+    tim.ccer.modify(|_, w| w.cc1e().clear_bit());
+
+    let ticks = result?;
+    Ok((ticks as u64 * 32_768 / (LSE_CAPTURE_DIV * LSE_MEASUREMENT_COUNT) as u64) as u32)
+}
+
+/// Sum the TIM16 ticks between LSE captures, over `LSE_MEASUREMENT_COUNT` intervals.
+fn capture_lse_ticks(tim: &pac::TIM16) -> Result<u32, RccError> {
+    let capture = || {
+        let mut i = 0;
+        while tim.sr.read().cc1if().bit_is_clear() {
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(RccError::Hardware);
+            }
+        }
+        // Reading CCR1 clears the flag.
+        #[cfg(any(feature = "l4", feature = "g4"))]
+        return Ok(tim.ccr1().read().bits() as u16);
+        #[cfg(not(any(feature = "l4", feature = "g4")))]
+        Ok(tim.ccr1.read().bits() as u16)
+    };
+
+    let mut prev = capture()?;
+    let mut ticks: u32 = 0;
+
+    for _ in 0..LSE_MEASUREMENT_COUNT {
+        let val = capture()?;
+        ticks += val.wrapping_sub(prev) as u32;
+        prev = val;
+    }
+
+    Ok(ticks)
+}
+
+/// Trim the HSI16 using LSE measurements, when the HSI16 drives the system clock, directly or
+/// through the PLL. `expected` is the TIM16 clock frequency with an accurate HSI, in Hz; eg
+/// `ClockFreqs::timer2`. Steps the trim value until the measured frequency crosses `expected`,
+/// and keeps the closer of the last two. Returns the selected trim value, or `RccError::Speed`
+/// if the trim range ends before reaching `expected`; the trim is left at the end of its range.
+pub fn calibrate_hsi(tim: &mut pac::TIM16, expected: u32) -> Result<u8, RccError> {
+    let mut trim = hsi_trim();
+    let mut measured = measure_with_lse(tim)?;
+    let increase = measured < expected;
+
+    loop {
+        let next = if increase {
+            if trim >= HSITRIM_MAX {
+                return Err(RccError::Speed);
+            }
+            trim + 1
+        } else {
+            if trim == 0 {
+                return Err(RccError::Speed);
+            }
+            trim - 1
+        };
+
+        set_hsi_trim(next);
+        let next_measured = measure_with_lse(tim)?;
+
+        let crossed = if increase {
+            next_measured >= expected
+        } else {
+            next_measured <= expected
+        };
+
+        if crossed {
+            if next_measured.abs_diff(expected) > measured.abs_diff(expected) {
+                set_hsi_trim(trim);
+                return Ok(trim);
+            }
+            return Ok(next);
+        }
+
+        trim = next;
+        measured = next_measured;
+    }
+}
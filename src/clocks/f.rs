@@ -1,14 +1,17 @@
 use cfg_if::cfg_if;
 
+#[cfg(feature = "f4")]
+use crate::power::{self, VoltageRange};
 use crate::{
-    clocks::RccError,
+    clocks::{validate_usb_speed, ClockFreqs, RccError},
     pac::{self, FLASH, RCC},
     util::rcc_en_reset,
 };
 
 cfg_if! {
    if #[cfg(feature = "f3")] {
-       #[derive(Clone, Copy)]
+       #[derive(Clone, Copy, Debug, PartialEq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
         /// The clocks source input used by the PLL.
         /// Note that this corresponds to Bits 16:15: Applicable only to some models,
         ///303xB/C etc use only bit 16, with bit 15 at reset value (0?) but it's equiv. 303xD/E and xE use bits 16:15.
@@ -51,7 +54,8 @@ cfg_if! {
        // }
 
    } else if #[cfg(feature = "f4")] {
-           #[derive(Clone, Copy)]
+           #[derive(Clone, Copy, Debug, PartialEq)]
+            #[cfg_attr(feature = "defmt", derive(defmt::Format))]
             /// The clocks source input used by the PLL.
             pub enum PllSrc {
                 Hsi,
@@ -71,7 +75,8 @@ cfg_if! {
    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum InputSrc {
     Hsi,
     Hse(u32), // freq in Mhz
@@ -95,7 +100,8 @@ impl InputSrc {
 }
 
 #[cfg(feature = "f3")]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// RCC_cfgr2. Scales the input source before the PLL.
 pub enum Prediv {
@@ -142,7 +148,8 @@ impl Prediv {
 }
 
 #[cfg(feature = "f3")]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum PllMul {
     Mul2 = 0b0000,
@@ -186,7 +193,8 @@ impl PllMul {
 }
 
 #[cfg(feature = "f4")]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum Pllp {
     Div2 = 0b00,
@@ -207,7 +215,8 @@ impl Pllp {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Division factor for the AHB clock. Also known as AHB Prescaler.
 pub enum HclkPrescaler {
@@ -258,7 +267,8 @@ enum WaitState {
     W7 = 7,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// For use with `RCC_APBPPRE1`, and `RCC_APBPPRE2`. Ie, low-speed and high-speed prescalers respectively.
 pub enum ApbPrescaler {
@@ -282,7 +292,8 @@ impl ApbPrescaler {
 }
 
 #[cfg(feature = "f3")]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum UsbPrescaler {
     Div1_5 = 0,
@@ -308,7 +319,8 @@ impl UsbPrescaler {
 }
 
 #[cfg(feature = "f4")]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// RCC_cfgr2. Scales the input source before the PLL.
 pub enum Pllq {
@@ -350,9 +362,62 @@ impl Pllq {
     }
 }
 
+#[cfg(feature = "f4")]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// Spread spectrum modulation shape. Sets RCC_SSCGR register, SPREADSEL field.
+pub enum SpreadSel {
+    /// The PLL frequency is modulated above and below its nominal value.
+    Center = 0,
+    /// The PLL frequency is modulated below its nominal value only.
+    Down = 1,
+}
+
+#[cfg(feature = "f4")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Spread spectrum clock generation (SSCG) settings for the main PLL. This spreads the PLL's
+/// output over a band of frequencies, reducing peak EMI. Only available on F4; the RCC on H7 and
+/// G4 doesn't include SSCG. See RM0090, section 6.2.11.
+pub struct SpreadSpectrum {
+    /// Modulation frequency, in Hz. Should be no higher than 10kHz.
+    pub mod_freq: u32,
+    /// Peak modulation depth, as a percent of the VCO frequency. Eg 0.25 - 2.0.
+    pub depth: f32,
+    pub spread: SpreadSel,
+}
+
+#[cfg(feature = "f4")]
+impl SpreadSpectrum {
+    /// Calculate the MODPER and INCSTEP values, from the PLL input frequency and PLLN.
+    fn sscgr_vals(&self, pll_input_freq: u32, plln: u16) -> Result<(u16, u16), RccError> {
+        if self.mod_freq == 0 || self.depth <= 0. {
+            return Err(RccError::Speed);
+        }
+
+        // MODPER = round(fPLL_IN / (4 x fMod))
+        let modper = (pll_input_freq + 2 * self.mod_freq) / (4 * self.mod_freq);
+        if modper == 0 || modper > 0x1fff {
+            return Err(RccError::Speed);
+        }
+
+        // INCSTEP = round(((2^15 - 1) x md x PLLN) / (100 x 5 x MODPER))
+        let incstep = (((1 << 15) - 1) as f32 * self.depth * plln as f32 / (500 * modper) as f32
+            + 0.5) as u32;
+        if incstep == 0 || incstep > 0x7fff || modper * incstep > (1 << 15) - 1 {
+            return Err(RccError::Speed);
+        }
+
+        Ok((modper as u16, incstep as u16))
+    }
+}
+
 /// Settings used to configure clocks. Create this struct by using its `Default::default()`
 /// implementation, then modify as required, referencing your RM's clock tree,
 /// or Stm32Cube IDE's interactive clock manager. Apply settings by running `.setup()`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Clocks {
     /// The input source for the system and peripheral clocks. Eg HSE, HSI, PLL etc
     pub input_src: InputSrc,
@@ -384,6 +449,9 @@ pub struct Clocks {
     /// frees up the pin for use as GPIO.
     pub hse_bypass: bool,
     pub security_system: bool,
+    #[cfg(feature = "f4")]
+    /// Spread spectrum modulation of the main PLL. Only applies when the PLL is used.
+    pub spread_spectrum: Option<SpreadSpectrum>,
 }
 
 impl Clocks {
@@ -403,7 +471,6 @@ impl Clocks {
         // We need to do this before enabling PLL, or it won't enable.
         let sysclk = self.sysclk();
 
-        // todo: We don't yet take into account other voltage settings for f4 wait states.
         let hclk = sysclk / self.hclk_prescaler.value() as u32;
         cfg_if! {
             if #[cfg(feature = "f3")] {  // RM section 4.5.1
@@ -501,12 +568,39 @@ impl Clocks {
                     }
                 });
                 } else if #[cfg(feature = "f4")] {
+                    // The voltage scale can only be changed with the PLL off. Raise it if HCLK
+                    // requires it; leave a higher scale as-is.
+                    let required = VoltageRange::required(hclk);
+                    if required > power::voltage_range() {
+                        power::apply_voltage_range(required)?;
+                    }
+
                     rcc.pllcfgr.modify(|_, w| unsafe {
                         w.pllsrc().bit(pll_src.bits() != 0);
                         w.plln().bits(self.plln);
                         w.pllm().bits(self.pllm);
                         w.pllp().bits(self.pllp as u8)
                     });
+
+                    // SSCGR must be written before the PLL is enabled.
+                    match self.spread_spectrum {
+                        Some(ss) => {
+                            let input_freq = match pll_src {
+                                PllSrc::Hsi => 16_000_000,
+                                PllSrc::Hse(freq) => freq,
+                            };
+                            let (modper, incstep) =
+                                ss.sscgr_vals(input_freq / self.pllm as u32, self.plln)?;
+
+                            rcc.sscgr.write(|w| unsafe {
+                                w.modper().bits(modper);
+                                w.incstep().bits(incstep);
+                                w.spreadsel().bit(ss.spread == SpreadSel::Down);
+                                w.sscgen().set_bit()
+                            });
+                        }
+                        None => rcc.sscgr.modify(|_, w| w.sscgen().clear_bit()),
+                    }
                 }
             }
 
@@ -517,6 +611,13 @@ impl Clocks {
             rcc.cr.modify(|_, w| w.pllon().on());
 
             while rcc.cr.read().pllrdy().is_not_ready() {}
+
+            // Overdrive must be enabled after the PLL, and before switching the system clock to
+            // it.
+            #[cfg(any(feature = "f427", feature = "f429", feature = "f446", feature = "f469"))]
+            if hclk > power::voltage_range().max_hclk() {
+                power::enable_overdrive()?;
+            }
         }
 
         rcc.cfgr.modify(|_, w| unsafe {
@@ -645,7 +746,19 @@ impl Clocks {
         #[cfg(feature = "f3")]
         return self.sysclk() / self.usb_pre.value() as u32;
         #[cfg(feature = "f4")]
-        return 0; // todo
+        {
+            // PLL48CLK = VCO / PLLQ.
+            let input_freq = match self.input_src {
+                InputSrc::Pll(PllSrc::Hse(freq)) | InputSrc::Hse(freq) => freq,
+                _ => 16_000_000,
+            };
+            input_freq / self.pllm as u32 * self.plln as u32 / self.pllq.value() as u32
+        }
+    }
+
+    /// Check that the USB clock is 48Mhz, within the tolerance required by USB full-speed.
+    pub fn validate_usb(&self) -> Result<(), RccError> {
+        validate_usb_speed(self.usb())
     }
 
     pub fn apb1(&self) -> u32 {
@@ -672,6 +785,58 @@ impl Clocks {
         }
     }
 
+    /// Calculate bus and peripheral kernel clock frequencies, for passing to peripheral
+    /// constructors. On F3, U[S]ART and I2C kernel clock selections are read from the RCC CFGR3
+    /// register. On F4, peripherals use their PCLK.
+    pub fn freqs(&self) -> ClockFreqs {
+        let sysclk = self.sysclk();
+        let pclk1 = self.apb1();
+        let pclk2 = self.apb2();
+
+        cfg_if! {
+            if #[cfg(feature = "f3")] {
+                let rcc = unsafe { &(*RCC::ptr()) };
+                let cfgr3 = rcc.cfgr3.read().bits();
+
+                // F3 RM, RCC_CFGR3: 0: PCLK, 1: SYSCLK, 2: LSE, 3: HSI.
+                let usart_clk = |pos: u8, pclk: u32| match (cfgr3 >> pos) & 0b11 {
+                    0 => pclk,
+                    1 => sysclk,
+                    2 => 32_768,
+                    _ => 8_000_000,
+                };
+                // 0: HSI, 1: SYSCLK.
+                let i2c_clk = |pos: u8| if (cfgr3 >> pos) & 1 == 0 { 8_000_000 } else { sysclk };
+
+                let usart = [
+                    usart_clk(0, pclk2),
+                    usart_clk(16, pclk1),
+                    usart_clk(18, pclk1),
+                    usart_clk(20, pclk1),
+                    usart_clk(22, pclk1),
+                    pclk2, pclk1, pclk1, pclk1, pclk1,
+                ];
+                let i2c = [i2c_clk(4), i2c_clk(5), i2c_clk(6), pclk1];
+            } else {
+                let usart = [pclk2, pclk1, pclk1, pclk1, pclk1, pclk2, pclk1, pclk1, pclk1, pclk1];
+                let i2c = [pclk1; 4];
+            }
+        }
+
+        ClockFreqs {
+            sysclk,
+            hclk: self.hclk(),
+            pclk1,
+            pclk2,
+            timer1: self.apb1_timer(),
+            timer2: self.apb2_timer(),
+            usart,
+            lpuart1: pclk1,
+            i2c,
+            spi: [pclk2, pclk1, pclk1, pclk2, pclk2, pclk2],
+        }
+    }
+
     pub fn validate_speeds(&self) -> Result<(), RccError> {
         cfg_if! {
             if #[cfg(feature = "f3")] {
@@ -692,6 +857,15 @@ impl Clocks {
             return Err(RccError::Speed);
         }
 
+        #[cfg(feature = "f4")]
+        if let (InputSrc::Pll(pll_src), Some(ss)) = (self.input_src, self.spread_spectrum) {
+            let input_freq = match pll_src {
+                PllSrc::Hsi => 16_000_000,
+                PllSrc::Hse(freq) => freq,
+            };
+            ss.sscgr_vals(input_freq / self.pllm as u32, self.plln)?;
+        }
+
         let max_hclk = max_clock;
 
         // todo: min clock? eg for apxb?
@@ -770,6 +944,138 @@ impl Default for Clocks {
             apb2_prescaler: ApbPrescaler::Div2,
             hse_bypass: false,
             security_system: false,
+            spread_spectrum: None,
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "f3")] {
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Clock output on the MCO pin. Sets RCC_CFGR register, MCO field.
+        pub enum McoSrc {
+            Lsi = 0b010,
+            Lse = 0b011,
+            Sysclk = 0b100,
+            Hsi = 0b101,
+            Hse = 0b110,
+            /// The PLL output, divided by 2.
+            Pll = 0b111,
+        }
+
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Divider for the MCO output. Sets RCC_CFGR register, MCOPRE field. Not available
+        /// on F373.
+        pub enum McoPrescaler {
+            Div1 = 0,
+            Div2 = 1,
+            Div4 = 2,
+            Div8 = 3,
+            Div16 = 4,
+            Div32 = 5,
+            Div64 = 6,
+            Div128 = 7,
+        }
+
+        /// Output a clock on the MCO pin (PA8), eg to clock an external device, or to check
+        /// clock settings with an oscilloscope. The pin must be set to its alternate function
+        /// (AF0) separately. On F373, `prescaler` is ignored.
+        pub fn enable_mco(src: McoSrc, prescaler: McoPrescaler) {
+            let rcc = unsafe { &(*RCC::ptr()) };
+
+            cfg_if! {
+                if #[cfg(feature = "f373")] {
+                    let _ = prescaler;
+                    rcc.cfgr.modify(|_, w| unsafe { w.mco().bits(src as u8) });
+                } else {
+                    rcc.cfgr.modify(|_, w| unsafe {
+                        w.mco().bits(src as u8);
+                        w.mcopre().bits(prescaler as u8)
+                    });
+                }
+            }
+        }
+
+        /// Stop the MCO output.
+        pub fn disable_mco() {
+            let rcc = unsafe { &(*RCC::ptr()) };
+            rcc.cfgr.modify(|_, w| unsafe { w.mco().bits(0) });
+        }
+    } else {
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Clock output on the MCO1 pin. Sets RCC_CFGR register, MCO1 field.
+        pub enum McoSrc {
+            Hsi = 0b00,
+            Lse = 0b01,
+            Hse = 0b10,
+            Pll = 0b11,
+        }
+
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Clock output on the MCO2 pin. Sets RCC_CFGR register, MCO2 field.
+        pub enum Mco2Src {
+            Sysclk = 0b00,
+            PllI2s = 0b01,
+            Hse = 0b10,
+            Pll = 0b11,
+        }
+
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        /// Divider for the MCO outputs. Sets RCC_CFGR register, MCO1PRE and MCO2PRE fields.
+        pub enum McoPrescaler {
+            Div1 = 0b000,
+            Div2 = 0b100,
+            Div3 = 0b101,
+            Div4 = 0b110,
+            Div5 = 0b111,
+        }
+
+        /// Output a clock on the MCO1 pin (PA8), eg to clock an external device, or to check
+        /// clock settings with an oscilloscope. The pin must be set to its alternate function
+        /// (AF0) separately.
+        pub fn enable_mco(src: McoSrc, prescaler: McoPrescaler) {
+            let rcc = unsafe { &(*RCC::ptr()) };
+            rcc.cfgr.modify(|_, w| unsafe {
+                #[cfg(feature = "f410")]
+                w.mco1en().set_bit();
+                w.mco1().bits(src as u8);
+                w.mco1pre().bits(prescaler as u8)
+            });
+        }
+
+        /// Output a clock on the MCO2 pin (PC9). The pin must be set to its alternate function
+        /// (AF0) separately.
+        pub fn enable_mco2(src: Mco2Src, prescaler: McoPrescaler) {
+            let rcc = unsafe { &(*RCC::ptr()) };
+            rcc.cfgr.modify(|_, w| unsafe {
+                #[cfg(feature = "f410")]
+                w.mco2en().set_bit();
+                w.mco2().bits(src as u8);
+                w.mco2pre().bits(prescaler as u8)
+            });
         }
     }
 }
+
+/// The largest HSI trim value. (The HSITRIM field is 5 bits wide)
+pub const HSITRIM_MAX: u8 = 31;
+
+/// Set the HSI trim value, to adjust its frequency. Higher values increase the frequency. The
+/// reset value is 16, and each step adjusts the frequency by roughly 40kHz (F3) or 80kHz (F4).
+/// Values above `HSITRIM_MAX` are clamped to it. Sets RCC_CR register, HSITRIM field.
+pub fn set_hsi_trim(trim: u8) {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.cr
+        .modify(|_, w| unsafe { w.hsitrim().bits(trim.min(HSITRIM_MAX)) });
+}
+
+/// Read the HSI trim value. Reads RCC_CR register, HSITRIM field.
+pub fn hsi_trim() -> u8 {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.cr.read().hsitrim().bits()
+}
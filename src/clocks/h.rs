@@ -6,15 +6,18 @@
 
 use cfg_if::cfg_if;
 
-#[cfg(not(any(feature = "h5", feature = "h7b3", feature = "h735")))]
-use crate::pac::SYSCFG;
+#[cfg(feature = "h5")]
+use crate::pac::PWR;
+#[cfg(feature = "h7")]
+use crate::power;
 use crate::{
-    clocks::RccError,
-    pac::{CRS, FLASH, PWR, RCC},
+    clocks::{validate_usb_speed, ClockFreqs, RccError},
+    pac::{CRS, FLASH, RCC},
     MAX_ITERS,
 };
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PllSrc {
     None,
     Csi,
@@ -36,7 +39,8 @@ impl PllSrc {
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select the system clock used when exiting Stop mode. Sets RCC_CFGR register, STOPWUCK field.
 pub enum StopWuck {
@@ -44,7 +48,8 @@ pub enum StopWuck {
     Csi = 1,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Selects USB clock source. Sets D2CCIP2R reg, USBSEL field.
 pub enum UsbSrc {
@@ -70,7 +75,8 @@ pub enum CrsSyncSrc {
     OtgHs = 0b10,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Clock input source, also known as system clock switch. Sets RCC_CFGR register, SW field.
 pub enum InputSrc {
     Hsi(HsiDiv),
@@ -95,6 +101,8 @@ impl InputSrc {
 
 /// Configures the speeds, and enable status of an individual PLL. Note that the `enable`
 /// field has no effect for PLL1.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct PllCfg {
     pub enabled: bool,
     // pub fractional: bool,
@@ -159,7 +167,8 @@ impl PllCfg {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Division factor for the AHB clock. Also known as AHB Prescaler. See RCC_D1CFGR reg.
 pub enum HclkPrescaler {
@@ -190,7 +199,8 @@ impl HclkPrescaler {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// For use with `RCC_APBPPRE1`, and `RCC_APBPPRE2`. Ie, low-speed and high-speed prescalers respectively.
 pub enum ApbPrescaler {
@@ -213,7 +223,8 @@ impl ApbPrescaler {
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// SAI clock input source. Sets RCC_D2CCIP1R register, SAIxSEL field.
 pub enum SaiSrc {
@@ -224,7 +235,8 @@ pub enum SaiSrc {
     PerClk = 0100,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// SPI clock input source. Sets RCC_D2CCIP1R register, SPI123SEL field..
 pub enum Spi123Src {
@@ -236,7 +248,8 @@ pub enum Spi123Src {
     PerClk = 0100,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// SPI clock input source. Sets RCC_D2CCIP1R register, SPI45SEL field.
 pub enum Spi45Src {
@@ -248,7 +261,8 @@ pub enum Spi45Src {
     HseCk = 0b101,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// SAI clock input source. Sets RCC_D2CCIP1R register, DFSDM1SEL field.
 pub enum DfsdmSrc {
@@ -258,7 +272,8 @@ pub enum DfsdmSrc {
     Sysclk = 1,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// CAN clock input source. Sets RCC_D2CCIP1R register, FDCANSEL field.
 pub enum CanSrc {
@@ -270,7 +285,8 @@ pub enum CanSrc {
     Pll2Q = 0b10,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Clock divider for the HSI. See RCC_CR register, HSIDIV field.
 pub enum HsiDiv {
@@ -291,7 +307,8 @@ impl HsiDiv {
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Range for the VOS. See H743 RM, section 6.8.6: PWR D3 domain control register. Sets PWR_D3CR,
 /// `VOS` field.
@@ -309,6 +326,44 @@ pub enum VosRange {
 }
 
 impl VosRange {
+    /// The maximum HCLK frequency in this range, in Hz. H743 datasheet, Table 23: General
+    /// operating conditions. H735 datasheet, Table 24.
+    pub fn max_hclk(&self) -> u32 {
+        cfg_if! {
+            if #[cfg(feature = "h735")] {
+                match self {
+                    Self::VOS0 => 275_000_000,
+                    Self::VOS1 => 200_000_000,
+                    Self::VOS2 => 150_000_000,
+                    Self::VOS3 => 85_000_000,
+                }
+            } else {
+                match self {
+                    #[cfg(not(feature = "h7b3"))]
+                    Self::VOS0 => 240_000_000,
+                    Self::VOS1 => 200_000_000,
+                    Self::VOS2 => 150_000_000,
+                    Self::VOS3 => 100_000_000,
+                }
+            }
+        }
+    }
+
+    /// The lowest-power range that supports a given HCLK frequency. Returns the highest range if
+    /// none do.
+    pub fn required(hclk: u32) -> Self {
+        #[cfg(not(feature = "h7b3"))]
+        let ranges = [Self::VOS3, Self::VOS2, Self::VOS1, Self::VOS0];
+        #[cfg(feature = "h7b3")]
+        let ranges = [Self::VOS3, Self::VOS2, Self::VOS1];
+
+        let highest = ranges[ranges.len() - 1];
+        ranges
+            .into_iter()
+            .find(|r| hclk <= r.max_hclk())
+            .unwrap_or(highest)
+    }
+
     /// Power regulator voltage scale.
     /// Choose the wait states based on VSO range and hclk frequency.. See H743 RM, Table 17: FLASH,
     /// or RM0468, table 16.
@@ -387,6 +442,8 @@ impl VosRange {
 /// Settings used to configure clocks. Create this struct by using its `Default::default()`
 /// implementation, then modify as required, referencing your RM's clock tree,
 /// or Stm32Cube IDE's interactive clock manager. Apply settings by running `.setup()`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Clocks {
     /// The main input source
     pub input_src: InputSrc,
@@ -444,7 +501,8 @@ impl Clocks {
     /// Use the STM32CubeIDE Clock Configuration tab to identify valid configs.
     /// Use the `default()` implementation as a safe baseline.
     /// This method also configures the PWR VOS setting, and can be used to enable VOS boost,
-    /// if `vos_range` is set to `VosRange::VOS0`.
+    /// if `vos_range` is set to `VosRange::VOS0`. If HCLK is too high for `vos_range`, it uses the
+    /// lowest range that supports it instead.
     pub fn setup(&self) -> Result<(), RccError> {
         if let Err(e) = self.validate_speeds() {
             return Err(e);
@@ -452,6 +510,7 @@ impl Clocks {
 
         let rcc = unsafe { &(*RCC::ptr()) };
         let flash = unsafe { &(*FLASH::ptr()) };
+        #[cfg(feature = "h5")]
         let pwr = unsafe { &(*PWR::ptr()) };
 
         // Enable and reset System Configuration Controller, ie for interrupts.
@@ -463,7 +522,7 @@ impl Clocks {
             rcc.apb4rstr.modify(|_, w| w.syscfgrst().clear_bit());
         }
 
-        let mut i = 0;
+        let mut i;
 
         macro_rules! wait_hang {
             ($i:expr) => {
@@ -474,84 +533,30 @@ impl Clocks {
             };
         }
 
-        // H743 RM, sefction 6.8.6, and section 6.6.2: Voltage Scaling
-        //  Voltage scaling selection according to performance
-        // These bits control the VCORE voltage level and allow to obtains the best trade-off between
-        // power consumption and performance:
-        // – When increasing the performance, the voltage scaling shall be changed before increasing
-        // the system frequency.
-        // – When decreasing performance, the system frequency shall first be decreased before
-        // changing the voltage scaling.
-        match self.vos_range {
-            // todo: Do we need this on H5?
-            #[cfg(not(any(feature = "h5", feature = "h7b3", feature = "h735")))]
-            // Note:H735 etc have VOS0, but not oden; the RM doesn't list these steps.
-            VosRange::VOS0 => {
-                let syscfg = unsafe { &(*SYSCFG::ptr()) };
-
-                // VOS0 activation/deactivation sequence: H743 HRM, section 6.6.2:
-                // The system maximum frequency can be reached by boosting the voltage scaling level to
-                // VOS0. This is done through the ODEN bit in the SYSCFG_PWRCR register.
-                // The sequence to activate the VOS0 is the following:
-                // 1. Ensure that the system voltage scaling is set to VOS1 by checking the VOS bits in
-                // PWR D3 domain control register (PWR D3 domain control register (PWR_D3CR))
-                cfg_if! {
-                    if #[cfg(feature = "h7")] {
-                        pwr.d3cr
-                            .modify(|_, w| unsafe { w.vos().bits(VosRange::VOS1 as u8) });
-
-                        i = 0;
-                        while pwr.d3cr.read().vosrdy().bit_is_clear() {wait_hang!(i);}
-                    } else {
-                        pwr.voscr
-                            .modify(|_, w| unsafe { w.vos().bits(VosRange::VOS1 as u8) });
-
-                        i = 0;
-                        while pwr.vossr.read().vosrdy().bit_is_clear() {wait_hang!(i);}
-                    }
-                }
-
-                // 2. Enable the SYSCFG clock in the RCC by setting the SYSCFGEN bit in the
-                // RCC_APB4ENR register.
-                // (Handled above)
-
-                // 3. Enable the ODEN bit in the SYSCFG_PWRCR register.
-                syscfg.pwrcr.modify(|_, w| w.oden().set_bit());
-
-                // 4. Wait for VOSRDY to be set.
-                i = 0;
-                #[cfg(feature = "h7")]
-                while pwr.d3cr.read().vosrdy().bit_is_clear() {
-                    wait_hang!(i);
-                }
-                #[cfg(feature = "h5")]
-                while pwr.vossr.read().vosrdy().bit_is_clear() {
-                    wait_hang!(i);
-                }
+        // Set the voltage scale before increasing the system frequency. Use a higher scale than
+        // `vos_range` if the HCLK frequency requires it.
+        let vos_range = if self.hclk() > self.vos_range.max_hclk() {
+            VosRange::required(self.hclk())
+        } else {
+            self.vos_range
+        };
 
-                // Once the VCORE supply has reached the required level, the system frequency can be
-                // increased. Figure 31 shows the recommended sequence for switching VCORE from VOS1 to
-                // VOS0 sequence.
-                // The sequence to deactivate the VOS0 is the following:
-                // 1. Ensure that the system frequency was decreased.
-                // 2. Ensure that the SYSCFG clock is enabled in the RCC by setting the SYSCFGEN bit set
-                // in the RCC_APB4ENR register.
-                // 3. Reset the ODEN bit in the SYSCFG_PWRCR register to disable VOS0.
-            }
-            _ => {
-                #[cfg(feature = "h7")]
-                pwr.d3cr
-                    .modify(|_, w| unsafe { w.vos().bits(self.vos_range as u8) });
-                #[cfg(feature = "h5")]
-                pwr.voscr
-                    .modify(|_, w| unsafe { w.vos().bits(self.vos_range as u8) });
+        #[cfg(feature = "h7")]
+        power::apply_voltage_range(vos_range)?;
+        #[cfg(feature = "h5")]
+        {
+            pwr.voscr
+                .modify(|_, w| unsafe { w.vos().bits(vos_range as u8) });
+            i = 0;
+            while pwr.vossr.read().vosrdy().bit_is_clear() {
+                wait_hang!(i);
             }
         }
 
         // Adjust flash wait states according to the HCLK frequency.
         // We need to do this before enabling PLL, or it won't enable.
         // H742 RM, Table 17.
-        let wait_states = self.vos_range.wait_states(self.hclk());
+        let wait_states = vos_range.wait_states(self.hclk());
 
         flash.acr.modify(|_, w| unsafe {
             w.latency().bits(wait_states.0);
@@ -1119,11 +1124,13 @@ impl Clocks {
         return self.sysclk() / self.hclk_prescaler.value() as u32;
     }
 
-    /// Get the systick speed. Note that for dual core variants, this is for CPU1.
-    /// CPU2 systick is equal to the HCLK (possibly divided by 8), so use the `hclk()` method.
+    /// Get the systick speed. On dual core variants, this is for the core the firmware is built
+    /// for: CPU1 uses the D1 core prescaler clock, and CPU2 uses the HCLK.
     pub fn systick(&self) -> u32 {
         // todo: There's an optional /8 divider we're not taking into account here.
-        #[cfg(feature = "h7")]
+        #[cfg(feature = "h747cm4")]
+        return self.hclk();
+        #[cfg(all(feature = "h7", not(feature = "h747cm4")))]
         return self.d1cpreclk();
         #[cfg(feature = "h5")]
         return self.sysclk();
@@ -1131,9 +1138,25 @@ impl Clocks {
 
     /// Get the USB clock frequency, in hz
     pub fn usb(&self) -> u32 {
-        // let (input_freq, _) = sysclock(self.input_src, self.divm1, self.divn1, self.divp1);
-        // (input_freq * 1_000_000) as u32 / self.divm1 as u32 * self.pll_sai1_mul as u32 / 2
-        0 // todo
+        let pll_src = match self.input_src {
+            InputSrc::Pll1 => self.pll_src,
+            InputSrc::Csi => PllSrc::Csi,
+            InputSrc::Hsi(div) => PllSrc::Hsi(div),
+            InputSrc::Hse(freq) => PllSrc::Hse(freq),
+        };
+
+        match self.usb_src {
+            UsbSrc::Disabled => 0,
+            UsbSrc::Pll1Q => self.vco_output_freq(pll_src, 1) / self.pll1.divq as u32,
+            UsbSrc::Pll3Q => self.vco_output_freq(pll_src, 3) / self.pll3.divq as u32,
+            UsbSrc::Hsi48 => 48_000_000,
+        }
+    }
+
+    /// Check that the USB clock is 48Mhz, within the tolerance required by USB full-speed. Note that
+    /// HSI48 must also be trimmed using the CRS, eg synchronized to USB SOF, to meet this.
+    pub fn validate_usb(&self) -> Result<(), RccError> {
+        validate_usb_speed(self.usb())
     }
 
     pub fn apb1(&self) -> u32 {
@@ -1186,6 +1209,121 @@ impl Clocks {
         }
     }
 
+    /// Calculate bus and peripheral kernel clock frequencies, for passing to peripheral
+    /// constructors. SPI kernel clocks are from this config. U[S]ART and I2C kernel clock
+    /// selections are read from the RCC D2CCIP2R and D3CCIPR registers, so this is accurate if
+    /// they've been changed from their defaults.
+    pub fn freqs(&self) -> ClockFreqs {
+        let sysclk = self.sysclk();
+        let hclk = self.hclk();
+        let pclk1 = self.apb1();
+        let pclk2 = self.apb2();
+
+        cfg_if! {
+            if #[cfg(all(feature = "h7", not(feature = "h7b3")))] {
+                let rcc = unsafe { &(*RCC::ptr()) };
+                let d2ccip2r = rcc.d2ccip2r.read().bits();
+                let d3ccipr = rcc.d3ccipr.read().bits();
+
+                let pclk4 = hclk / self.d3_prescaler.value() as u32;
+
+                let hsi = match (self.input_src, self.pll_src) {
+                    (InputSrc::Hsi(div), _) | (_, PllSrc::Hsi(div)) => {
+                        64_000_000 / div.value() as u32
+                    }
+                    _ => 64_000_000,
+                };
+                let hse = match (self.input_src, self.pll_src) {
+                    (InputSrc::Hse(freq), _) | (_, PllSrc::Hse(freq)) => freq,
+                    _ => 0,
+                };
+
+                let pll_out = |pll_num: u8, div: u8| {
+                    if div == 0 {
+                        0
+                    } else {
+                        self.vco_output_freq(self.pll_src, pll_num) / div as u32
+                    }
+                };
+
+                // H743 RM, RCC_D2CCIP2R and RCC_D3CCIPR: 0: PCLK, 1: PLL2Q, 2: PLL3Q, 3: HSI,
+                // 4: CSI, 5: LSE.
+                let usart_clk = |sel: u32, pclk: u32| match sel & 0b111 {
+                    0 => pclk,
+                    1 => pll_out(2, self.pll2.divq),
+                    2 => pll_out(3, self.pll3.divq),
+                    3 => hsi,
+                    4 => 4_000_000,
+                    _ => 32_768,
+                };
+                // 0: PCLK, 1: PLL3R, 2: HSI, 3: CSI.
+                let i2c_clk = |sel: u32, pclk: u32| match sel & 0b11 {
+                    0 => pclk,
+                    1 => pll_out(3, self.pll3.divr),
+                    2 => hsi,
+                    _ => 4_000_000,
+                };
+
+                let usart16 = usart_clk(d2ccip2r >> 3, pclk2);
+                let usart234578 = usart_clk(d2ccip2r, pclk1);
+                let i2c123 = i2c_clk(d2ccip2r >> 12, pclk1);
+
+                let spi123 = match self.spi123_src {
+                    Spi123Src::Pll1Q => pll_out(1, self.pll1.divq),
+                    Spi123Src::Pll2P => pll_out(2, self.pll2.divp),
+                    Spi123Src::Pll3P => pll_out(3, self.pll3.divp),
+                    // External clock; unknown.
+                    Spi123Src::I2sCkin => 0,
+                    // PER_CK defaults to HSI.
+                    Spi123Src::PerClk => hsi,
+                };
+                let spi45 = match self.spi45_src {
+                    Spi45Src::Apb => pclk2,
+                    Spi45Src::Pll2Q => pll_out(2, self.pll2.divq),
+                    Spi45Src::Pll3Q => pll_out(3, self.pll3.divq),
+                    Spi45Src::Hsi => hsi,
+                    Spi45Src::Csi => 4_000_000,
+                    Spi45Src::HseCk => hse,
+                };
+
+                let usart = [
+                    usart16,
+                    usart234578,
+                    usart234578,
+                    usart234578,
+                    usart234578,
+                    usart16,
+                    usart234578,
+                    usart234578,
+                    usart16,
+                    usart16,
+                ];
+                let lpuart1 = usart_clk(d3ccipr, pclk4);
+                let i2c = [i2c123, i2c123, i2c123, i2c_clk(d3ccipr >> 8, pclk4)];
+                let spi = [spi123, spi123, spi123, spi45, spi45, pclk4];
+            } else {
+                // todo: Kernel clock selections on H5 and H7B3.
+                let usart = [pclk2, pclk1, pclk1, pclk1, pclk1, pclk2, pclk1, pclk1, pclk2, pclk2];
+                let lpuart1 = pclk1;
+                let i2c = [pclk1; 4];
+                let spi = [pclk2, pclk1, pclk1, pclk2, pclk2, pclk2];
+            }
+        }
+
+        ClockFreqs {
+            sysclk,
+            hclk,
+            pclk1,
+            pclk2,
+            timer1: self.apb1_timer(),
+            timer2: self.apb2_timer(),
+            usart,
+            lpuart1,
+            i2c,
+            spi,
+        }
+    }
+
     pub fn validate_speeds(&self) -> Result<(), RccError> {
         cfg_if! {
             if #[cfg(feature = "h735")] {
@@ -1411,3 +1549,67 @@ pub fn enable_crs(sync_src: CrsSyncSrc) {
     // Standby mode. When the CRS is not used, the HSI48 RC oscillator runs on its default
     // frequency which is subject to manufacturing process variations
 }
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// Clock output on the MCO1 pin. Sets RCC_CFGR register, MCO1 field.
+pub enum McoSrc {
+    Hsi = 0b000,
+    Lse = 0b001,
+    Hse = 0b010,
+    Pll1Q = 0b011,
+    Hsi48 = 0b100,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// Clock output on the MCO2 pin. Sets RCC_CFGR register, MCO2 field.
+pub enum Mco2Src {
+    Sysclk = 0b000,
+    Pll2P = 0b001,
+    Hse = 0b010,
+    Pll1P = 0b011,
+    Csi = 0b100,
+    Lsi = 0b101,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// Divider for the MCO outputs. Sets RCC_CFGR register, MCO1PRE and MCO2PRE fields.
+pub enum McoPrescaler {
+    Div1 = 1,
+    Div2 = 2,
+    Div3 = 3,
+    Div4 = 4,
+    Div5 = 5,
+    Div6 = 6,
+    Div7 = 7,
+    Div8 = 8,
+    Div9 = 9,
+    Div10 = 10,
+    Div11 = 11,
+    Div12 = 12,
+    Div13 = 13,
+    Div14 = 14,
+    Div15 = 15,
+}
+
+/// Output a clock on the MCO1 pin (PA8), eg to clock an external device, or to check clock
+/// settings with an oscilloscope. The pin must be set to its alternate function (AF0) separately.
+pub fn enable_mco(src: McoSrc, prescaler: McoPrescaler) {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.cfgr.modify(|_, w| unsafe {
+        w.mco1().bits(src as u8);
+        w.mco1pre().bits(prescaler as u8)
+    });
+}
+
+/// Output a clock on the MCO2 pin (PC9). The pin must be set to its alternate function (AF0)
+/// separately.
+pub fn enable_mco2(src: Mco2Src, prescaler: McoPrescaler) {
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.cfgr.modify(|_, w| unsafe {
+        w.mco2().bits(src as u8);
+        w.mco2pre().bits(prescaler as u8)
+    });
+}
@@ -28,6 +28,10 @@ cfg_if::cfg_if! {
     }
 }
 
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "h5", feature = "h7")))]
+use crate::{pac, power};
+use crate::{pac::RCC, MAX_ITERS};
+
 // todo: Consider merging the modules into a single file: There's more similar than different.
 // todo: You have a good deal of DRY atm between modules.
 
@@ -36,12 +40,279 @@ cfg_if::cfg_if! {
 // todo: Continue working through DRY between the clock modules.
 
 /// Speed out of limits.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RccError {
     Speed,
     Hardware,
 }
 
+/// Clock frequencies, in Hz, computed from a `Clocks` config, and the RCC's peripheral kernel clock
+/// selections. Get this from `Clocks::freeze()`, or from `Clocks::freqs()` after clocks are set up,
+/// and pass it to peripheral constructors, so baud rates and timings use the actual clock
+/// speeds. Constructors that accept this also accept `&Clocks`, and compute it.
+///
+/// Kernel clocks are indexed by peripheral number, starting at 0; eg `usart[0]` is USART1, and
+/// `i2c[1]` is I2C2. Entries for peripherals not present on the MCU are unspecified.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClockFreqs {
+    pub sysclk: u32,
+    pub hclk: u32,
+    /// The APB1 peripheral clock.
+    pub pclk1: u32,
+    /// The APB2 peripheral clock. On G0, this is the same as `pclk1`.
+    pub pclk2: u32,
+    /// The clock used by timers on APB1.
+    pub timer1: u32,
+    /// The clock used by timers on APB2.
+    pub timer2: u32,
+    /// U[S]ART kernel clocks.
+    pub usart: [u32; 10],
+    /// LPUART1 kernel clock.
+    pub lpuart1: u32,
+    /// I2C kernel clocks.
+    pub i2c: [u32; 4],
+    /// SPI kernel clocks. For SPI that don't have a kernel clock selection, this is their PCLK.
+    pub spi: [u32; 6],
+}
+
+impl From<&Clocks> for ClockFreqs {
+    fn from(clocks: &Clocks) -> Self {
+        clocks.freqs()
+    }
+}
+
+impl From<&ClockFreqs> for ClockFreqs {
+    fn from(freqs: &ClockFreqs) -> Self {
+        *freqs
+    }
+}
+
+/// Implemented by peripherals that calculate dividers or timings from their clock, so they can
+/// be updated after clocks change. Pass these to `Clocks::reconfigure()`.
+pub trait ClockListener {
+    /// Recalculate dividers, eg baud rate or timing registers, using the new clock frequencies.
+    fn clocks_changed(&mut self, freqs: &ClockFreqs);
+}
+
+impl Clocks {
+    /// Set up clocks, as with `setup()`, and return the resulting frequencies. Pass these to
+    /// peripheral constructors.
+    pub fn freeze(&self) -> Result<ClockFreqs, RccError> {
+        self.setup()?;
+        Ok(self.freqs())
+    }
+
+    /// Change clock settings at runtime, eg to reduce speed and power use when idle. Switches the
+    /// system clock to an internal oscillator while the PLL and prescalers are changed, then applies
+    /// `new_cfg` using `setup()`, which sets flash wait states, and voltage scaling where applicable,
+    /// for the new speeds. Each peripheral in `listeners` then recalculates its dividers.
+    ///
+    /// On success, `self` is updated to `new_cfg`, and the new frequencies are returned. If
+    /// `new_cfg` fails validation, no changes are made. If setup fails, the system clock is left on
+    /// the internal oscillator.
+    ///
+    /// Example:
+    /// ```rust
+    /// let freqs = clock_cfg.reconfigure(low_speed_cfg, &mut [&mut uart, &mut i2c, &mut timer])?;
+    /// ```
+    pub fn reconfigure(
+        &mut self,
+        new_cfg: Self,
+        listeners: &mut [&mut dyn ClockListener],
+    ) -> Result<ClockFreqs, RccError> {
+        new_cfg.validate_speeds()?;
+
+        select_safe_sysclk()?;
+        new_cfg.setup()?;
+        *self = new_cfg;
+
+        let freqs = self.freqs();
+        for listener in listeners.iter_mut() {
+            listener.clocks_changed(&freqs);
+        }
+
+        Ok(freqs)
+    }
+}
+
+/// Switch the system clock to an internal oscillator, so the PLL can be reconfigured, and wait
+/// for the switch to complete. On H5 and H7, this uses the CSI (4Mhz), which is valid with any
+/// flash wait states and voltage scaling; on others, HSI16, or HSI on F3 (8Mhz). Flash wait states
+/// are raised first if HSI16 needs more at the current voltage range; `setup()` lowers them again.
+fn select_safe_sysclk() -> Result<(), RccError> {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    cfg_if::cfg_if! {
+        if #[cfg(any(feature = "h5", feature = "h7"))] {
+            let src = InputSrc::Csi;
+
+            rcc.cr.modify(|_, w| w.csion().set_bit());
+            let mut i = 0;
+            while rcc.cr.read().csirdy().bit_is_clear() {
+                i += 1;
+                if i >= MAX_ITERS {
+                    return Err(RccError::Hardware);
+                }
+            }
+        } else {
+            let src = InputSrc::Hsi;
+
+            rcc.cr.modify(|_, w| w.hsion().set_bit());
+            let mut i = 0;
+            while rcc.cr.read().hsirdy().bit_is_clear() {
+                i += 1;
+                if i >= MAX_ITERS {
+                    return Err(RccError::Hardware);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(any(feature = "f3", feature = "f4", feature = "h5", feature = "h7")))]
+    {
+        let flash = unsafe { &(*pac::FLASH::ptr()) };
+        let wait_states = power::voltage_range().wait_states(16_000_000);
+        if flash.acr.read().latency().bits() < wait_states {
+            flash
+                .acr
+                .modify(|_, w| unsafe { w.latency().bits(wait_states) });
+        }
+    }
+
+    rcc.cfgr.modify(|_, w| unsafe { w.sw().bits(src.bits()) });
+
+    let mut i = 0;
+    while rcc.cfgr.read().sws().bits() != src.bits() {
+        i += 1;
+        if i >= MAX_ITERS {
+            return Err(RccError::Hardware);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// An oscillator failure, detected by the clock security system.
+pub enum CssEvent {
+    /// The HSE failed. The system clock has been switched to an internal oscillator, and the HSE
+    /// and PLL (if driven by the HSE) have been disabled by hardware.
+    Hse,
+    /// The LSE failed. The LSE remains selected as the RTC clock, if it was, so select the LSI
+    /// to keep the RTC running.
+    Lse,
+}
+
+/// Enable the clock security system for the HSE. If the HSE fails, hardware switches the system
+/// clock to the HSI (or MSI, on some families, depending on the stop wakeup clock setting), and
+/// triggers the NMI. Run `handle_css()` in the NMI handler. This has the same effect as setting
+/// `Clocks::security_system`. Sets RCC_CR register, CSSON field.
+pub fn enable_css() {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    #[cfg(any(feature = "h5", feature = "h7"))]
+    rcc.cr.modify(|_, w| w.hsecsson().set_bit());
+    #[cfg(not(any(feature = "h5", feature = "h7")))]
+    rcc.cr.modify(|_, w| w.csson().set_bit());
+}
+
+#[cfg(not(any(feature = "f3", feature = "f4")))]
+/// Enable the clock security system for the LSE. The LSE must be enabled and ready, and backup
+/// domain write access enabled. On failure, the CSS LSE interrupt is triggered; run `handle_css()`
+/// in its handler. (On G0, the failure is only reported by the flag, and by the RTC and TAMP
+/// peripherals.) Sets RCC_BDCR register, LSECSSON field, and RCC_CIER, LSECSSIE.
+pub fn enable_lse_css() {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    rcc.bdcr.modify(|_, w| w.lsecsson().set_bit());
+    #[cfg(not(feature = "g0"))]
+    rcc.cier.modify(|_, w| w.lsecssie().set_bit());
+}
+
+/// Check for, and clear a clock security system failure flag. Returns the oscillator that failed,
+/// if any. `handle_css()` calls this.
+pub fn css_event() -> Option<CssEvent> {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    cfg_if::cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            if rcc.cir.read().cssf().bit_is_set() {
+                rcc.cir.modify(|_, w| w.cssc().set_bit());
+                return Some(CssEvent::Hse);
+            }
+        } else {
+            let cifr = rcc.cifr.read();
+
+            #[cfg(any(feature = "h5", feature = "h7", feature = "wb"))]
+            let hse_failed = cifr.hsecssf().bit_is_set();
+            #[cfg(not(any(feature = "h5", feature = "h7", feature = "wb")))]
+            let hse_failed = cifr.cssf().bit_is_set();
+
+            if hse_failed {
+                #[cfg(any(feature = "h5", feature = "h7", feature = "wb"))]
+                rcc.cicr.write(|w| w.hsecssc().set_bit());
+                #[cfg(not(any(feature = "h5", feature = "h7", feature = "wb")))]
+                rcc.cicr.write(|w| w.cssc().set_bit());
+                return Some(CssEvent::Hse);
+            }
+
+            if cifr.lsecssf().bit_is_set() {
+                rcc.cicr.write(|w| w.lsecssc().set_bit());
+                return Some(CssEvent::Lse);
+            }
+        }
+    }
+
+    None
+}
+
+/// Handle a clock security system event; run this in the NMI handler, and the CSS LSE interrupt
+/// handler if using LSE CSS. Clears the failure flag, and returns the oscillator that failed. On
+/// HSE failure, re-selects the input source using `fallback`'s `reselect_input()`, eg to run the
+/// PLL from the HSI instead. `fallback` must not use the HSE.
+///
+/// Example:
+/// ```rust
+/// #[exception]
+/// unsafe fn NonMaskableInt() {
+///     let fallback = Clocks {
+///         input_src: InputSrc::Pll(PllSrc::Hsi),
+///         ..Default::default()
+///     };
+///     if let Ok(Some(CssEvent::Hse)) = clocks::handle_css(&fallback) {
+///         // Log the failure, and update peripheral clocks, eg with `Clocks::freqs()`.
+///     }
+/// }
+/// ```
+pub fn handle_css(fallback: &Clocks) -> Result<Option<CssEvent>, RccError> {
+    let event = css_event();
+
+    if let Some(CssEvent::Hse) = event {
+        cfg_if::cfg_if! {
+            if #[cfg(any(feature = "f3", feature = "f4"))] {
+                fallback.reselect_input();
+            } else {
+                fallback.reselect_input()?;
+            }
+        }
+    }
+
+    Ok(event)
+}
+
+#[cfg(not(any(feature = "g0", feature = "wl")))]
+/// USB full-speed requires a 48Mhz clock, within ±0.25% (2,500ppm). See USB 2.0 spec, section 7.1.11.
+pub(crate) fn validate_usb_speed(freq: u32) -> Result<(), RccError> {
+    const USB_FREQ: u32 = 48_000_000;
+    const TOLERANCE: u32 = USB_FREQ / 400;
+
+    if freq.abs_diff(USB_FREQ) > TOLERANCE {
+        return Err(RccError::Speed);
+    }
+    Ok(())
+}
+
 // #[derive(Clone, Copy)]
 // #[repr(u8)]
 // pub enum ClocksValid {
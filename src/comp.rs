@@ -24,6 +24,8 @@ use crate::pac::comp1::{CFGR1, CFGR2};
 // Config enums
 /// Comparator power mode
 #[cfg(any(feature = "l4"))]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PowerMode {
     /// High speed/full power (Lowest propagation delay).
     HighSpeed = 0x00000000,
@@ -35,7 +37,8 @@ pub enum PowerMode {
 
 /// Comparator input plus (Non-inverting Input)
 #[cfg(any(feature = "g473", feature = "h7"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 // STM32G4 reference manual section 24.3.2 table 196
 pub enum NonInvertingInput {
@@ -44,6 +47,8 @@ pub enum NonInvertingInput {
 }
 
 #[cfg(any(feature = "l4"))]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum NonInvertingInput {
     /// From the first GPIO pin connected to the comparator.
     ///
@@ -64,7 +69,8 @@ pub enum NonInvertingInput {
 
 /// Comparator input minus (Inverted Input)
 #[cfg(any(feature = "g473", feature = "h7", feature = "l4"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 // STM32G4 reference manual section 24.3.2 table 197
 pub enum InvertingInput {
@@ -80,7 +86,8 @@ pub enum InvertingInput {
 
 /// Comparator hysterisis
 #[cfg(any(feature = "g473"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Hysterisis {
     None = 0b000,
     TenMilliVolt = 0b001,
@@ -93,6 +100,8 @@ pub enum Hysterisis {
 }
 
 #[cfg(any(feature = "l4", feature = "h7"))]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Hysterisis {
     /// No Hysterisis.
     NoHysterisis = 0b00,
@@ -117,13 +126,16 @@ pub enum Hysterisis {
 /// [NonInvertingInput] has higher voltage than [InvertingInput].
 
 #[cfg(any(feature = "g473", feature = "h7"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum OutputPolarity {
     NotInverted = 0b0,
     Inverted = 0b1,
 }
 
 #[cfg(any(feature = "l4"))]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum OutputPolarity {
     /// Comparator output will not be inverted.
     NotInverted = 0x00000000,
@@ -155,7 +167,8 @@ pub enum CompDevice {
 /// Initial configuration data for the comparator peripheral.
 
 #[cfg(any(feature = "g473"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct CompConfig {
     pub inpsel: NonInvertingInput,
     pub inmsel: InvertingInput,
@@ -164,6 +177,8 @@ pub struct CompConfig {
 }
 
 #[cfg(any(feature = "l4"))]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct CompConfig {
     /// Comparator power mode.
     pub pwrmode: PowerMode,
@@ -0,0 +1,371 @@
+//! A lightweight command console, for bring-up and production test. Parses lines of input into
+//! whitespace-separated arguments, and dispatches them to commands you register. Runs over the UART
+//! driver, or RTT with the `console_rtt` feature; implement `ConsoleIo` to use another transport.
+//!
+//! Built-in commands provide HAL introspection:
+//! - `help`: List commands.
+//! - `peek <addr> [count]`: Read `count` 32-bit words starting at `addr`, eg to dump peripheral registers.
+//! - `poke <addr> <value>`: Write a 32-bit word.
+//! - `clocks`: Print clock speeds, if the console was given the clock config with `set_clocks()`.
+//! - `gpio <port> <pin> [high|low]`: Read a pin's input level, or set its output level.
+//!
+//! Numbers may be decimal, or hex with a `0x` prefix.
+//!
+//! Example:
+//! ```rust
+//! fn cmd_adc(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+//!     let ch: u8 = args.get(0).ok_or(CommandError::InvalidArgs)?.parse().map_err(|_| CommandError::InvalidArgs)?;
+//!     writeln!(out, "{}", read_adc(ch)).ok();
+//!     Ok(())
+//! }
+//!
+//! static COMMANDS: [Command; 1] = [Command { name: "adc", help: "adc <ch>: Read an ADC channel", handler: cmd_adc }];
+//!
+//! let mut console: Console<_, 64> = Console::new(uart, &COMMANDS);
+//! console.set_clocks(&clock_cfg);
+//!
+//! loop {
+//!     console.poll();
+//! }
+//! ```
+
+use core::fmt::{self, Write};
+
+use crate::{
+    clocks::Clocks,
+    gpio::{self, PinState, Port},
+    usart::Usart,
+};
+
+/// The maximum number of arguments passed to a command, not including its name.
+pub const MAX_ARGS: usize = 8;
+
+/// A byte-oriented transport for the console.
+pub trait ConsoleIo {
+    /// Read a received byte, if available. Must not block.
+    fn read_byte(&mut self) -> Option<u8>;
+    /// Write bytes. May block until they're sent, or queued.
+    fn write_bytes(&mut self, data: &[u8]);
+}
+
+impl<R> ConsoleIo for Usart<R>
+where
+    R: core::ops::Deref<Target = crate::pac::usart1::RegisterBlock>
+        + crate::util::RccPeriph
+        + crate::util::BaudPeriph,
+{
+    fn read_byte(&mut self) -> Option<u8> {
+        if self.rx_ready() {
+            Some(self.read_one())
+        } else {
+            None
+        }
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) {
+        self.write(data).ok();
+    }
+}
+
+#[cfg(feature = "console_rtt")]
+/// An RTT up (target to host) and down (host to target) channel pair, eg from `rtt_init!`.
+pub struct RttIo {
+    pub up: rtt_target::UpChannel,
+    pub down: rtt_target::DownChannel,
+}
+
+#[cfg(feature = "console_rtt")]
+impl ConsoleIo for RttIo {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        if self.down.read(&mut byte) == 1 {
+            Some(byte[0])
+        } else {
+            None
+        }
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) {
+        let mut data = data;
+        while !data.is_empty() {
+            let written = self.up.write(data);
+            data = &data[written..];
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Errors returned by command handlers.
+pub enum CommandError {
+    /// Missing, extra, or unparsable arguments.
+    InvalidArgs,
+    /// The command ran, but the operation failed.
+    Failed,
+}
+
+/// A command, registered with the console.
+pub struct Command {
+    /// The name typed to run the command.
+    pub name: &'static str,
+    /// A one-line description, shown by `help`.
+    pub help: &'static str,
+    /// Runs the command. `args` doesn't include the command's name.
+    pub handler: fn(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError>,
+}
+
+/// Wraps a transport for `core::fmt::Write`, translating `\n` to `\r\n` for terminals.
+struct Output<'a, IO>(&'a mut IO);
+
+impl<IO: ConsoleIo> Write for Output<'_, IO> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_bytes(b"\r\n");
+            }
+            self.0.write_bytes(part.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// A command console, with a line buffer of `N` bytes.
+pub struct Console<'a, IO, const N: usize> {
+    pub io: IO,
+    commands: &'a [Command],
+    clocks: Option<&'a Clocks>,
+    line: [u8; N],
+    len: usize,
+    /// Set if the last byte received was `\r`, so the `\n` of a CRLF line ending is skipped.
+    after_cr: bool,
+    /// Printed before each line of input.
+    pub prompt: &'static str,
+    /// If true, received characters are echoed back. Defaults to true.
+    pub echo: bool,
+}
+
+impl<'a, IO: ConsoleIo, const N: usize> Console<'a, IO, N> {
+    /// Create a console on a transport, with a set of commands. These are in addition to the built-in
+    /// commands.
+    pub fn new(io: IO, commands: &'a [Command]) -> Self {
+        Self {
+            io,
+            commands,
+            clocks: None,
+            line: [0; N],
+            len: 0,
+            after_cr: false,
+            prompt: "> ",
+            echo: true,
+        }
+    }
+
+    /// Provide the clock configuration, for the `clocks` command.
+    pub fn set_clocks(&mut self, clocks: &'a Clocks) {
+        self.clocks = Some(clocks);
+    }
+
+    /// Process received input, running a command when a line is complete. Doesn't block, other
+    /// than for writing output. Run this from your main loop, or when data is received. Lines may
+    /// end with CR, LF, or CRLF.
+    pub fn poll(&mut self) {
+        while let Some(byte) = self.io.read_byte() {
+            let after_cr = self.after_cr;
+            self.after_cr = byte == b'\r';
+
+            match byte {
+                // The second half of a CRLF line ending.
+                b'\n' if after_cr => (),
+                b'\r' | b'\n' => {
+                    self.io.write_bytes(b"\r\n");
+                    if self.len > 0 {
+                        self.execute();
+                        self.len = 0;
+                    }
+                    self.io.write_bytes(self.prompt.as_bytes());
+                }
+                // Backspace, or delete.
+                0x08 | 0x7f if self.len > 0 => {
+                    self.len -= 1;
+                    if self.echo {
+                        self.io.write_bytes(b"\x08 \x08");
+                    }
+                }
+                // Ignore other control characters, and input beyond the line buffer's size.
+                0x20..=0x7e if self.len < N => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                    if self.echo {
+                        self.io.write_bytes(&[byte]);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Parse and run the line in the buffer.
+    fn execute(&mut self) {
+        // We only store printable ASCII, so this is valid UTF-8.
+        let line = core::str::from_utf8(&self.line[..self.len]).unwrap_or("");
+
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(n) => n,
+            None => return,
+        };
+
+        let mut args = [""; MAX_ARGS];
+        let mut num_args = 0;
+        for word in words {
+            if num_args == MAX_ARGS {
+                writeln!(Output(&mut self.io), "Too many arguments").ok();
+                return;
+            }
+            args[num_args] = word;
+            num_args += 1;
+        }
+        let args = &args[..num_args];
+
+        let mut out = Output(&mut self.io);
+
+        let result = match name {
+            "help" => {
+                print_help(&mut out, self.commands);
+                Ok(())
+            }
+            "peek" => cmd_peek(args, &mut out),
+            "poke" => cmd_poke(args, &mut out),
+            "clocks" => match self.clocks {
+                Some(clocks) => {
+                    print_clocks(&mut out, clocks);
+                    Ok(())
+                }
+                None => Err(CommandError::Failed),
+            },
+            "gpio" => cmd_gpio(args, &mut out),
+            _ => match self.commands.iter().find(|c| c.name == name) {
+                Some(command) => (command.handler)(args, &mut out),
+                None => {
+                    writeln!(out, "Unknown command: {}. Type `help` for a list.", name).ok();
+                    return;
+                }
+            },
+        };
+
+        match result {
+            Ok(()) => (),
+            Err(CommandError::InvalidArgs) => {
+                writeln!(out, "Invalid arguments").ok();
+            }
+            Err(CommandError::Failed) => {
+                writeln!(out, "Failed").ok();
+            }
+        }
+    }
+}
+
+/// Parse a decimal, or `0x`-prefixed hex number.
+pub fn parse_u32(s: &str) -> Result<u32, CommandError> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| CommandError::InvalidArgs)
+}
+
+fn print_help(out: &mut dyn Write, commands: &[Command]) {
+    writeln!(out, "help: List commands").ok();
+    writeln!(out, "peek <addr> [count]: Read 32-bit words").ok();
+    writeln!(out, "poke <addr> <value>: Write a 32-bit word").ok();
+    writeln!(out, "clocks: Print clock speeds").ok();
+    writeln!(out, "gpio <port> <pin> [high|low]: Read or set a pin").ok();
+
+    for command in commands {
+        writeln!(out, "{}: {}", command.name, command.help).ok();
+    }
+}
+
+/// Print the main clock speeds, in Hz.
+pub fn print_clocks(out: &mut dyn Write, clocks: &Clocks) {
+    writeln!(out, "sysclk: {}", clocks.sysclk()).ok();
+    writeln!(out, "hclk: {}", clocks.hclk()).ok();
+    writeln!(out, "apb1: {}", clocks.apb1()).ok();
+    writeln!(out, "apb1 timer: {}", clocks.apb1_timer()).ok();
+    writeln!(out, "apb2: {}", clocks.apb2()).ok();
+    writeln!(out, "apb2 timer: {}", clocks.apb2_timer()).ok();
+    writeln!(out, "systick: {}", clocks.systick()).ok();
+}
+
+fn cmd_peek(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let addr = parse_u32(args.first().ok_or(CommandError::InvalidArgs)?)?;
+    let count = match args.get(1) {
+        Some(c) => parse_u32(c)?,
+        None => 1,
+    };
+
+    if addr % 4 != 0 || args.len() > 2 {
+        return Err(CommandError::InvalidArgs);
+    }
+
+    for i in 0..count {
+        let a = addr.wrapping_add(i * 4);
+        // Reading an invalid address triggers a fault; this is a debug tool.
+        let val = unsafe { core::ptr::read_volatile(a as *const u32) };
+        writeln!(out, "{:#010x}: {:#010x}", a, val).ok();
+    }
+
+    Ok(())
+}
+
+fn cmd_poke(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if args.len() != 2 {
+        return Err(CommandError::InvalidArgs);
+    }
+    let addr = parse_u32(args[0])?;
+    let val = parse_u32(args[1])?;
+
+    if addr % 4 != 0 {
+        return Err(CommandError::InvalidArgs);
+    }
+
+    unsafe { core::ptr::write_volatile(addr as *mut u32, val) };
+    writeln!(out, "{:#010x} <- {:#010x}", addr, val).ok();
+
+    Ok(())
+}
+
+/// Read, or set a pin. Setting only affects pins that are already configured as outputs.
+fn cmd_gpio(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if args.len() < 2 || args.len() > 3 {
+        return Err(CommandError::InvalidArgs);
+    }
+
+    let mut letter = args[0].chars();
+    let port = match (letter.next(), letter.next()) {
+        (Some(l), None) => Port::from_letter(l).ok_or(CommandError::InvalidArgs)?,
+        _ => return Err(CommandError::InvalidArgs),
+    };
+
+    let pin = parse_u32(args[1])?;
+    if pin > 15 {
+        return Err(CommandError::InvalidArgs);
+    }
+    let pin = pin as u8;
+
+    match args.get(2) {
+        Some(&"high") | Some(&"1") => gpio::set_state(port, pin, PinState::High),
+        Some(&"low") | Some(&"0") => gpio::set_state(port, pin, PinState::Low),
+        Some(_) => return Err(CommandError::InvalidArgs),
+        None => {
+            let level = if gpio::is_high(port, pin) {
+                "high"
+            } else {
+                "low"
+            };
+            writeln!(out, "{}", level).ok();
+        }
+    }
+
+    Ok(())
+}
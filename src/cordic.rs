@@ -0,0 +1,450 @@
+//! Support for the CORDIC co-processor, on G4 and H723/H725/H730/H733/H735. This computes
+//! trigonometric, hyperbolic, logarithm, and square root functions in fixed point, in a few
+//! cycles; eg sine and cosine for field-oriented motor control, at high loop rates.
+//!
+//! Arguments and results are q1.31 or q1.15 fixed point, in the range -1 to 1. Angles are in units
+//! of π radians, ie -1 is -π, and 1 is π. Use the `to_q31`, `from_q31` etc functions to convert.
+//! In q1.15 mode, each 32-bit word holds two values; the first in the low half.
+//!
+//! Writing arguments, then reading results is zero-overhead: The read stalls the bus until the
+//! result is ready, so there's no need to poll. Use DMA for streaming many arguments.
+//!
+//! Example:
+//! ```rust
+//! let mut cordic = Cordic::new(dp.CORDIC, Default::default());
+//!
+//! // Within a FOC loop, with the electrical angle in radians:
+//! let (cos, sin) = cordic.sin_cos(angle);
+//!
+//! // Or, in fixed point:
+//! cordic.write_arg(cordic::to_q31(angle / PI));
+//! let cos = cordic.read_result();
+//! let sin = cordic.read_result();
+//! ```
+
+use core::f32::consts::PI;
+
+use cfg_if::cfg_if;
+use num_traits::float::FloatCore; // To round floats.
+
+use crate::{
+    dma::{self, ChannelCfg, DmaChannel},
+    pac::{CORDIC, DMA1, RCC},
+    util::rcc_en_reset,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// CORDIC errors.
+pub enum CordicError {
+    /// An argument is outside the range supported by the function.
+    OutOfRange,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// The function to compute. Sets CSR, FUNC. Arguments and results are listed in order.
+pub enum Function {
+    /// Arguments: angle, modulus. Results: modulus * cos(angle), modulus * sin(angle).
+    Cosine = 0,
+    /// Arguments: angle, modulus. Results: modulus * sin(angle), modulus * cos(angle).
+    Sine = 1,
+    /// Arguments: x, y. Results: atan2(y, x), modulus.
+    Phase = 2,
+    /// Arguments: x, y. Results: modulus, atan2(y, x).
+    Modulus = 3,
+    /// Argument: x. Result: atan(x).
+    Arctangent = 4,
+    /// Argument: x. Results: cosh(x), sinh(x).
+    HyperbolicCosine = 5,
+    /// Argument: x. Results: sinh(x), cosh(x).
+    HyperbolicSine = 6,
+    /// Argument: x. Result: atanh(x).
+    HyperbolicArctangent = 7,
+    /// Argument: x. Result: ln(x).
+    NaturalLog = 8,
+    /// Argument: x. Result: sqrt(x).
+    SquareRoot = 9,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// The fixed-point format of arguments or results. Sets CSR, ARGSIZE and RESSIZE.
+pub enum DataSize {
+    /// One value per 32-bit word.
+    Q31 = 0,
+    /// Two values per 32-bit word.
+    Q15 = 1,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// The number of arguments, or results, per calculation. Sets CSR, NARGS and NRES. In q1.15 mode,
+/// both are packed into a single word, so use `One`.
+pub enum NumWords {
+    One = 0,
+    Two = 1,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// CORDIC configuration. Can be used with `Default::default()`, which computes cosine and sine,
+/// in q1.31, from a single angle argument.
+pub struct CordicConfig {
+    pub function: Function,
+    /// The number of iterations, divided by 4; 1 - 15. More is more precise, but slower. Defaults
+    /// to 6, which is close to full q1.31 precision for most functions.
+    pub precision: u8,
+    /// Scaling factor, 0 - 7; arguments are multiplied by 2^-scale, and results by 2^scale.
+    /// Required for arguments outside the default range of some functions. (See the RM.) Defaults
+    /// to 0.
+    pub scale: u8,
+    /// Defaults to q1.31.
+    pub arg_size: DataSize,
+    /// Defaults to q1.31.
+    pub res_size: DataSize,
+    /// If `One` when the function takes two arguments, the second keeps its previous value. This
+    /// is 1 (0x7fff_ffff) after reset. Defaults to `One`.
+    pub num_args: NumWords,
+    /// Defaults to `Two`.
+    pub num_results: NumWords,
+}
+
+impl Default for CordicConfig {
+    fn default() -> Self {
+        Self {
+            function: Function::Cosine,
+            precision: 6,
+            scale: 0,
+            arg_size: DataSize::Q31,
+            res_size: DataSize::Q31,
+            num_args: NumWords::One,
+            num_results: NumWords::Two,
+        }
+    }
+}
+
+/// Represents a CORDIC co-processor.
+pub struct Cordic {
+    pub regs: CORDIC,
+    pub cfg: CordicConfig,
+}
+
+impl Cordic {
+    /// Initialize the CORDIC peripheral, including enabling and resetting its RCC peripheral clock.
+    pub fn new(regs: CORDIC, cfg: CordicConfig) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+
+        cfg_if! {
+            if #[cfg(feature = "g4")] {
+                rcc_en_reset!(ahb1, cordic, rcc);
+            } else {
+                rcc_en_reset!(ahb2, cordic, rcc);
+            }
+        }
+
+        let mut result = Self { regs, cfg };
+        result.set_config(result.cfg.clone());
+
+        result
+    }
+
+    /// Change the configuration. Takes effect on the next argument written.
+    pub fn set_config(&mut self, cfg: CordicConfig) {
+        assert!(
+            cfg.precision >= 1 && cfg.precision <= 15,
+            "Precision must be 1 - 15."
+        );
+        assert!(cfg.scale <= 7, "Scale must be 0 - 7.");
+
+        self.regs.csr.modify(|_, w| unsafe {
+            w.func().bits(cfg.function as u8);
+            w.precision().bits(cfg.precision);
+            w.scale().bits(cfg.scale);
+            w.argsize().bit(cfg.arg_size as u8 != 0);
+            w.ressize().bit(cfg.res_size as u8 != 0);
+            w.nargs().bit(cfg.num_args as u8 != 0);
+            w.nres().bit(cfg.num_results as u8 != 0)
+        });
+
+        self.cfg = cfg;
+    }
+
+    /// Write an argument. The calculation starts once all arguments set by `num_args` are written.
+    pub fn write_arg(&mut self, arg: u32) {
+        self.regs.wdata.write(|w| unsafe { w.arg().bits(arg) });
+    }
+
+    /// Read a result. If the calculation is still in progress, this stalls until it's complete.
+    pub fn read_result(&mut self) -> u32 {
+        self.regs.rdata.read().bits()
+    }
+
+    /// Check if a result is ready to read. (CSR, RRDY)
+    pub fn result_ready(&self) -> bool {
+        self.regs.csr.read().rrdy().bit_is_set()
+    }
+
+    /// Run a calculation for each set of arguments, in fixed point, with the current
+    /// configuration. `args` holds `num_args` words per calculation, and `results` receives
+    /// `num_results` words per calculation.
+    pub fn run(&mut self, args: &[u32], results: &mut [u32]) {
+        let num_args = self.cfg.num_args as usize + 1;
+        let num_results = self.cfg.num_results as usize + 1;
+
+        for (arg, result) in args
+            .chunks_exact(num_args)
+            .zip(results.chunks_exact_mut(num_results))
+        {
+            for a in arg {
+                self.write_arg(*a);
+            }
+            for r in result {
+                *r = self.read_result();
+            }
+        }
+    }
+
+    /// Reconfigure for a q1.31 calculation, if required, keeping the current precision.
+    fn ensure_q31(
+        &mut self,
+        function: Function,
+        num_args: NumWords,
+        num_results: NumWords,
+        scale: u8,
+    ) {
+        let cfg = &self.cfg;
+        if cfg.function == function
+            && cfg.num_args == num_args
+            && cfg.num_results == num_results
+            && cfg.scale == scale
+            && cfg.arg_size == DataSize::Q31
+            && cfg.res_size == DataSize::Q31
+        {
+            return;
+        }
+
+        self.set_config(CordicConfig {
+            function,
+            precision: self.cfg.precision,
+            scale,
+            arg_size: DataSize::Q31,
+            res_size: DataSize::Q31,
+            num_args,
+            num_results,
+        });
+    }
+
+    /// Compute the cosine and sine of an angle, in radians. Returns `(cos, sin)`. Reconfigures
+    /// the peripheral if required.
+    pub fn sin_cos(&mut self, angle: f32) -> (f32, f32) {
+        self.ensure_q31(Function::Cosine, NumWords::Two, NumWords::Two, 0);
+
+        // Wrap to -π to π.
+        let angle = angle - 2. * PI * ((angle + PI) / (2. * PI)).floor();
+
+        self.write_arg(to_q31(angle / PI));
+        self.write_arg(to_q31(1.));
+
+        let cos = from_q31(self.read_result());
+        let sin = from_q31(self.read_result());
+        (cos, sin)
+    }
+
+    /// Compute atan2(y, x), in radians. Reconfigures the peripheral if required.
+    pub fn atan2(&mut self, y: f32, x: f32) -> f32 {
+        self.ensure_q31(Function::Phase, NumWords::Two, NumWords::Two, 0);
+
+        // The phase doesn't depend on scale; normalize into the argument range.
+        let max = x.abs().max(y.abs());
+        if max == 0. {
+            return 0.;
+        }
+
+        self.write_arg(to_q31(x / max));
+        self.write_arg(to_q31(y / max));
+
+        let phase = from_q31(self.read_result());
+        self.read_result(); // Modulus; read to clear RRDY.
+
+        phase * PI
+    }
+
+    /// Compute a square root. `x` must be 0.027 - 2.34. Reconfigures the peripheral if required.
+    pub fn sqrt(&mut self, x: f32) -> Result<f32, CordicError> {
+        // RM: Input range for each scale factor.
+        let scale = if (0.027..0.75).contains(&x) {
+            0
+        } else if (0.75..1.75).contains(&x) {
+            1
+        } else if (1.75..2.34).contains(&x) {
+            2
+        } else {
+            return Err(CordicError::OutOfRange);
+        };
+
+        self.ensure_q31(Function::SquareRoot, NumWords::One, NumWords::One, scale);
+
+        let factor = (1 << scale) as f32;
+        self.write_arg(to_q31(x / factor));
+
+        Ok(from_q31(self.read_result()) * factor)
+    }
+
+    /// Enable the interrupt that fires when a result is ready.
+    pub fn enable_interrupt(&mut self) {
+        self.regs.csr.modify(|_, w| w.ien().set_bit());
+    }
+
+    /// Disable the result ready interrupt.
+    pub fn disable_interrupt(&mut self) {
+        self.regs.csr.modify(|_, w| w.ien().clear_bit());
+    }
+
+    /// Stream arguments to the CORDIC using DMA. Use with `read_dma` on a second channel to collect
+    /// the results. `args` is as in `run`. Set the channel's input to `DmaInput::CordicWrite`
+    /// with `dma::mux()`.
+    ///
+    /// # Safety
+    /// `args` must remain valid, and not be moved, until the transfer is complete.
+    pub unsafe fn write_dma(
+        &mut self,
+        args: &[u32],
+        channel: DmaChannel,
+        channel_cfg: ChannelCfg,
+        dma_periph: dma::DmaPeriph,
+    ) {
+        let (ptr, len) = (args.as_ptr(), args.len());
+
+        self.regs.csr.modify(|_, w| w.dmawen().set_bit());
+
+        let periph_addr = &self.regs.wdata as *const _ as u32;
+
+        cfg_dma(
+            channel,
+            periph_addr,
+            ptr as u32,
+            len,
+            dma::Direction::ReadFromMem,
+            channel_cfg,
+            dma_periph,
+        );
+    }
+
+    /// Stream results from the CORDIC using DMA; one DMA transfer per result word. Set the
+    /// channel's input to `DmaInput::CordicRead` with `dma::mux()`.
+    ///
+    /// # Safety
+    /// `results` must remain valid, and not be accessed, until the transfer is complete.
+    pub unsafe fn read_dma(
+        &mut self,
+        results: &mut [u32],
+        channel: DmaChannel,
+        channel_cfg: ChannelCfg,
+        dma_periph: dma::DmaPeriph,
+    ) {
+        let (ptr, len) = (results.as_mut_ptr(), results.len());
+
+        self.regs.csr.modify(|_, w| w.dmaren().set_bit());
+
+        let periph_addr = &self.regs.rdata as *const _ as u32;
+
+        cfg_dma(
+            channel,
+            periph_addr,
+            ptr as u32,
+            len,
+            dma::Direction::ReadFromPeriph,
+            channel_cfg,
+            dma_periph,
+        );
+    }
+
+    /// Disable DMA requests, eg once a transfer is complete.
+    pub fn stop_dma(&mut self) {
+        self.regs.csr.modify(|_, w| {
+            w.dmawen().clear_bit();
+            w.dmaren().clear_bit()
+        });
+    }
+}
+
+fn cfg_dma(
+    channel: DmaChannel,
+    periph_addr: u32,
+    mem_addr: u32,
+    len: usize,
+    direction: dma::Direction,
+    channel_cfg: ChannelCfg,
+    dma_periph: dma::DmaPeriph,
+) {
+    #[cfg(feature = "h7")]
+    let len = len as u32;
+    #[cfg(not(feature = "h7"))]
+    let len = len as u16;
+
+    match dma_periph {
+        dma::DmaPeriph::Dma1 => {
+            let mut regs = unsafe { &(*DMA1::ptr()) };
+            dma::cfg_channel(
+                &mut regs,
+                channel,
+                periph_addr,
+                mem_addr,
+                len,
+                direction,
+                dma::DataSize::S32,
+                dma::DataSize::S32,
+                channel_cfg,
+            );
+        }
+        dma::DmaPeriph::Dma2 => {
+            let mut regs = unsafe { &(*crate::pac::DMA2::ptr()) };
+            dma::cfg_channel(
+                &mut regs,
+                channel,
+                periph_addr,
+                mem_addr,
+                len,
+                direction,
+                dma::DataSize::S32,
+                dma::DataSize::S32,
+                channel_cfg,
+            );
+        }
+    }
+}
+
+/// Convert a value from -1 to 1 to q1.31 fixed point. Values outside the range saturate.
+pub fn to_q31(val: f32) -> u32 {
+    // The float to int cast saturates.
+    ((val * 2_147_483_648.) as i32) as u32
+}
+
+/// Convert a q1.31 fixed point value to a float.
+pub fn from_q31(val: u32) -> f32 {
+    val as i32 as f32 / 2_147_483_648.
+}
+
+/// Convert a value from -1 to 1 to q1.15 fixed point. Values outside the range saturate.
+pub fn to_q15(val: f32) -> u16 {
+    ((val * 32_768.) as i16) as u16
+}
+
+/// Convert a q1.15 fixed point value to a float.
+pub fn from_q15(val: u16) -> f32 {
+    val as i16 as f32 / 32_768.
+}
+
+/// Pack two values into a word, for q1.15 arguments. `first` is in the low half.
+pub fn pack_q15(first: f32, second: f32) -> u32 {
+    to_q15(first) as u32 | ((to_q15(second) as u32) << 16)
+}
+
+/// Unpack a word of q1.15 results. Returns `(first, second)`.
+pub fn unpack_q15(val: u32) -> (f32, f32) {
+    (from_q15(val as u16), from_q15((val >> 16) as u16))
+}
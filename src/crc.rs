@@ -88,26 +88,27 @@ impl Crc {
     /// Write data to the CRC unit. Note that CRC calculation works
     /// faster if more data is given at once.
     pub fn update(&mut self, data: &[u8]) {
+        // The PACs differ in how they expose the 8, 16, and 32-bit views of DR, so we write it
+        // directly. DR is at offset 0.
+        let dr = CRC::ptr() as *mut u32;
+
         // write 4 bytes at once, then 2, then 1, as appropriate
         // in the case of a single large slice this improves speed by >3x
         let mut words = data.chunks_exact(4);
         for word in words.by_ref() {
             let word = u32::from_be_bytes(word.try_into().unwrap());
-            // todo: Put back once PAC settles. Currently causing error on H7
-            // self.regs.dr_mut().write(|w| w.dr().bits(word));
+            unsafe { core::ptr::write_volatile(dr, word) };
         }
 
         // there will be at most 3 bytes remaining, so 1 half-word and 1 byte
         let mut half_word = words.remainder().chunks_exact(2);
         if let Some(half_word) = half_word.next() {
-            let _half_word = u16::from_be_bytes(half_word.try_into().unwrap());
-            // todo: Put back once PAC settles. Currently causing error on H7
-            // self.regs.dr16_mut().write(|w| w.dr16().bits(half_word));
+            let half_word = u16::from_be_bytes(half_word.try_into().unwrap());
+            unsafe { core::ptr::write_volatile(dr as *mut u16, half_word) };
         }
 
         if let Some(byte) = half_word.remainder().first() {
-            // todo: Put back once PAC settles. Currently causing error on H7
-            // self.regs.dr8_mut().write(|w| w.dr8().bits(*byte));
+            unsafe { core::ptr::write_volatile(dr as *mut u8, *byte) };
         }
     }
 
@@ -222,6 +223,7 @@ mod macros {
 /// A polynomial being even means that the least significant bit is `0`
 /// in the polynomial's normal representation.
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Polynomial(Poly);
 
 impl Polynomial {
@@ -314,6 +316,7 @@ impl Default for Polynomial {
 
 /// Errors generated when trying to create invalid polynomials.
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PolynomialError {
     /// Tried to create an even polynomial.
     /// The hardware CRC unit only supports odd polynomials.
@@ -336,6 +339,7 @@ impl fmt::Display for PolynomialError {
 
 /// Internal representation of a polynomial.
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 enum Poly {
     /// 7-bit polynomial
     B7(u8),
@@ -353,6 +357,7 @@ enum Poly {
 /// 'reflection' it most likely wants [`BitReversal::Byte`] and output reversal
 /// enabled.
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum BitReversal {
     /// Each input byte has its bits reversed. `0x1A2B3C4D` becomes `0x58D43CB2`.
@@ -365,6 +370,7 @@ pub enum BitReversal {
 
 /// CRC unit configuration.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Config {
     poly: Polynomial,
     initial: u32,
@@ -0,0 +1,929 @@
+//! Hardware AES acceleration, using the AES peripheral (L4, L5, G0, G4, WB and WL), or the CRYP
+//! peripheral (H7 and F4). Supports ECB, CBC and CTR modes, and GCM authenticated encryption, with
+//! 128 or 256-bit keys. Blocks can be processed by the CPU, or fed by DMA.
+//!
+//! With the `rustcrypto` feature, `AesAdapter` implements the RustCrypto `BlockEncrypt`,
+//! `BlockDecrypt`, and `AeadInPlace` (AES-GCM) traits, so existing crypto stacks can use the
+//! hardware.
+//!
+//! Example:
+//! ```rust
+//! let mut aes = Aes::new(dp.AES);
+//! aes.load_key(&KEY).unwrap();
+//!
+//! let mut buf = *b"sixteen byte msg";
+//! aes.encrypt(Mode::Cbc { iv: IV }, &mut buf).unwrap();
+//! aes.decrypt(Mode::Cbc { iv: IV }, &mut buf).unwrap();
+//!
+//! let tag = aes.encrypt_gcm(&NONCE, b"header", &mut buf).unwrap();
+//! aes.decrypt_gcm(&NONCE, b"header", &mut buf, &tag).unwrap();
+//! ```
+//!
+//! Data is processed as bytes, in the standard (big endian) AES order. GCM encryption of a payload
+//! whose length isn't a multiple of 16 bytes isn't supported on L4 and F4, which can't mark the last
+//! block as partial.
+
+#[cfg(feature = "rustcrypto")]
+use core::cell::RefCell;
+
+use cfg_if::cfg_if;
+
+#[cfg(not(feature = "f4"))]
+use crate::dma::{self, ChannelCfg, DataSize, Direction, DmaChannel, DmaPeriph, Priority};
+use crate::{pac::RCC, util::rcc_en_reset, MAX_ITERS};
+
+cfg_if! {
+    if #[cfg(any(feature = "h7", feature = "f4"))] {
+        use crate::pac::CRYP as AES;
+    } else if #[cfg(feature = "wb")] {
+        use crate::pac::AES1 as AES;
+    } else {
+        use crate::pac::AES;
+    }
+}
+
+#[cfg(not(feature = "f4"))]
+cfg_if! {
+    if #[cfg(all(feature = "g0", not(any(feature = "g0b1", feature = "g0c1"))))] {
+        use crate::pac::DMA as DMA1;
+    } else {
+        use crate::pac::DMA1;
+    }
+}
+
+// Register offsets and bits. We use these instead of the PAC, since field names vary between
+// families, and between the AES and CRYP peripherals.
+const CR: usize = 0x00;
+const SR: usize = 0x04;
+
+cfg_if! {
+    if #[cfg(any(feature = "h7", feature = "f4"))] {
+        const DIN: usize = 0x08;
+        const DOUT: usize = 0x0c;
+        #[cfg(not(feature = "f4"))]
+        const DMACR: usize = 0x10;
+        /// K0LR. 128-bit keys start at K2LR.
+        const KEYR: usize = 0x20;
+        /// IV0LR.
+        const IVR: usize = 0x40;
+
+        const CR_ALGODIR: u32 = 1 << 2;
+        const CR_ALGOMODE_SHIFT: u32 = 3;
+        /// ALGOMODE[3] is separate from the other 3 bits.
+        const CR_ALGOMODE3: u32 = 1 << 19;
+        const CR_DATATYPE_BYTE: u32 = 0b10 << 6;
+        const CR_KEYSIZE_256: u32 = 0b10 << 8;
+        const CR_FFLUSH: u32 = 1 << 14;
+        const CR_EN: u32 = 1 << 15;
+        const CR_GCMPH_SHIFT: u32 = 16;
+
+        const SR_IFEM: u32 = 1 << 0;
+        const SR_IFNF: u32 = 1 << 1;
+        const SR_OFNE: u32 = 1 << 2;
+        const SR_BUSY: u32 = 1 << 4;
+
+        #[cfg(not(feature = "f4"))]
+        const DMACR_DIEN: u32 = 1 << 0;
+        #[cfg(not(feature = "f4"))]
+        const DMACR_DOEN: u32 = 1 << 1;
+
+        const ALGO_KEY_PREP: u32 = 0b111;
+    } else {
+        const DIN: usize = 0x08;
+        const DOUT: usize = 0x0c;
+        /// KEYR0. KEYR4 - 7, for 256-bit keys, are at 0x30.
+        const KEYR: usize = 0x10;
+        const KEYR4: usize = 0x30;
+        /// IVR0.
+        const IVR: usize = 0x20;
+
+        const CR_EN: u32 = 1 << 0;
+        const CR_DATATYPE_BYTE: u32 = 0b10 << 1;
+        const CR_MODE_SHIFT: u32 = 3;
+        const CR_CHMOD_SHIFT: u32 = 5;
+        /// CHMOD[2] is separate from the other 2 bits.
+        const CR_CHMOD2: u32 = 1 << 16;
+        const CR_CCFC: u32 = 1 << 7;
+        const CR_ERRC: u32 = 1 << 8;
+        const CR_DMAINEN: u32 = 1 << 11;
+        const CR_DMAOUTEN: u32 = 1 << 12;
+        const CR_GCMPH_SHIFT: u32 = 13;
+        const CR_KEYSIZE_256: u32 = 1 << 18;
+
+        const SR_CCF: u32 = 1 << 0;
+        const SR_RDERR: u32 = 1 << 1;
+        const SR_WRERR: u32 = 1 << 2;
+
+        const MODE_ENCRYPT: u32 = 0b00;
+        const MODE_KEY_DERIVATION: u32 = 0b01;
+        const MODE_DECRYPT: u32 = 0b10;
+    }
+}
+
+/// The number of padding bytes in the last block, for GCM encryption. Not available on L4 and F4.
+#[cfg(not(any(feature = "l4", feature = "f4")))]
+const CR_NPBLB_SHIFT: u32 = 20;
+
+const GCM_PHASE_INIT: u32 = 0b00;
+const GCM_PHASE_HEADER: u32 = 0b01;
+const GCM_PHASE_PAYLOAD: u32 = 0b10;
+const GCM_PHASE_FINAL: u32 = 0b11;
+
+pub const BLOCK_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// AES errors.
+pub enum CryptoError {
+    /// The key isn't 16 or 32 bytes long, or hasn't been loaded.
+    KeyLen,
+    /// The data length isn't a multiple of the block size, in a mode that requires it, or input
+    /// and output lengths don't match.
+    Length,
+    /// GCM encryption with a partial last block isn't supported on this MCU.
+    PartialBlock,
+    /// A read or write error was flagged by the peripheral.
+    Hardware,
+    /// Timed out waiting for the peripheral.
+    Timeout,
+    /// The GCM tag doesn't match; the data isn't authentic. The output is zeroed.
+    TagMismatch,
+}
+
+#[derive(Clone, Copy)]
+/// A block cipher mode, and its initialization vector where applicable.
+pub enum Mode {
+    /// Electronic codebook. Each block is encrypted independently; avoid this for data longer than
+    /// a block, unless you know you need it.
+    Ecb,
+    /// Cipher block chaining.
+    Cbc { iv: [u8; 16] },
+    /// Counter mode. `iv` is the initial counter block; the hardware increments its last 32 bits.
+    /// Data may be any length.
+    Ctr { iv: [u8; 16] },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// Chaining modes, as configured in the peripheral.
+enum Chain {
+    Ecb,
+    Cbc,
+    Ctr,
+    Gcm,
+}
+
+impl Mode {
+    fn chain(&self) -> Chain {
+        match self {
+            Self::Ecb => Chain::Ecb,
+            Self::Cbc { .. } => Chain::Cbc,
+            Self::Ctr { .. } => Chain::Ctr,
+        }
+    }
+
+    fn iv(&self) -> [u32; 4] {
+        match self {
+            Self::Ecb => [0; 4],
+            Self::Cbc { iv } | Self::Ctr { iv } => words_be(iv),
+        }
+    }
+}
+
+/// Split bytes into big endian words.
+fn words_be<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut result = [0; N];
+    for (word, chunk) in result.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    result
+}
+
+/// Represents an AES (or CRYP) peripheral.
+pub struct Aes {
+    pub regs: AES,
+    /// The loaded key, as big endian words.
+    key: [u32; 8],
+    /// The key length, in words; 0 if no key is loaded.
+    key_words: usize,
+}
+
+impl Aes {
+    /// Initialize the AES peripheral, including enabling and resetting its RCC peripheral clock.
+    pub fn new(regs: AES) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                rcc_en_reset!(ahb2, cryp, rcc);
+            } else if #[cfg(feature = "h7")] {
+                rcc_en_reset!(ahb2, crypt, rcc);
+            } else if #[cfg(feature = "wb")] {
+                rcc_en_reset!(ahb2, aes1, rcc);
+            } else if #[cfg(feature = "wl")] {
+                rcc_en_reset!(ahb3, aes, rcc);
+            } else if #[cfg(feature = "g0")] {
+                rcc_en_reset!(ahb1, aes, rcc);
+            } else {
+                rcc_en_reset!(ahb2, aes, rcc);
+            }
+        }
+
+        Self {
+            regs,
+            key: [0; 8],
+            key_words: 0,
+        }
+    }
+
+    /// Load a 128-bit (16 byte) or 256-bit (32 byte) key. It's written to the peripheral at the
+    /// start of each operation.
+    pub fn load_key(&mut self, key: &[u8]) -> Result<(), CryptoError> {
+        match key.len() {
+            16 => self.key[..4].copy_from_slice(&words_be::<4>(key)),
+            32 => self.key = words_be(key),
+            _ => return Err(CryptoError::KeyLen),
+        }
+        self.key_words = key.len() / 4;
+
+        Ok(())
+    }
+
+    /// Encrypt data in place. In ECB and CBC modes, the length must be a multiple of 16 bytes.
+    pub fn encrypt(&mut self, mode: Mode, buf: &mut [u8]) -> Result<(), CryptoError> {
+        self.process(mode, buf, false)
+    }
+
+    /// Decrypt data in place. In ECB and CBC modes, the length must be a multiple of 16 bytes.
+    pub fn decrypt(&mut self, mode: Mode, buf: &mut [u8]) -> Result<(), CryptoError> {
+        self.process(mode, buf, true)
+    }
+
+    fn process(&mut self, mode: Mode, buf: &mut [u8], decrypt: bool) -> Result<(), CryptoError> {
+        if mode.chain() != Chain::Ctr && !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(CryptoError::Length);
+        }
+
+        self.configure(mode.chain(), decrypt, &mode.iv())?;
+        self.enable();
+
+        let result = self.process_blocks(buf);
+        self.disable();
+        result
+    }
+
+    /// Process blocks with the peripheral configured and enabled. Pads a partial last block with
+    /// zeros.
+    fn process_blocks(&mut self, buf: &mut [u8]) -> Result<(), CryptoError> {
+        for chunk in buf.chunks_mut(BLOCK_SIZE) {
+            let mut block = [0; BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+
+            let out = self.process_block(&block)?;
+            chunk.copy_from_slice(&out[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    /// Encrypt data in place using GCM, and return the authentication tag. `iv` is the 96-bit
+    /// nonce; never reuse one with the same key. `aad` is additional data that's authenticated, but
+    /// not encrypted.
+    pub fn encrypt_gcm(
+        &mut self,
+        iv: &[u8; 12],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; 16], CryptoError> {
+        self.gcm(iv, aad, buf, false)
+    }
+
+    /// Decrypt data in place using GCM, and check its authentication tag. If the tag doesn't
+    /// match, the output is zeroed, and `CryptoError::TagMismatch` is returned.
+    pub fn decrypt_gcm(
+        &mut self,
+        iv: &[u8; 12],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8; 16],
+    ) -> Result<(), CryptoError> {
+        let computed = self.gcm(iv, aad, buf, true)?;
+
+        // Compare in constant time.
+        let diff = computed
+            .iter()
+            .zip(tag.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+
+        if diff != 0 {
+            buf.fill(0);
+            return Err(CryptoError::TagMismatch);
+        }
+        Ok(())
+    }
+
+    fn gcm(
+        &mut self,
+        iv: &[u8; 12],
+        aad: &[u8],
+        buf: &mut [u8],
+        decrypt: bool,
+    ) -> Result<[u8; 16], CryptoError> {
+        #[cfg(any(feature = "l4", feature = "f4"))]
+        if !decrypt && !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(CryptoError::PartialBlock);
+        }
+
+        // The last word is the initial counter value: 1 is used for the tag, and 2 for the first
+        // payload block.
+        let words = words_be::<3>(iv);
+        self.configure(Chain::Gcm, decrypt, &[words[0], words[1], words[2], 2])?;
+
+        let result = self.gcm_phases(aad, buf, decrypt);
+        self.disable();
+        result
+    }
+
+    fn gcm_phases(
+        &mut self,
+        aad: &[u8],
+        buf: &mut [u8],
+        decrypt: bool,
+    ) -> Result<[u8; 16], CryptoError> {
+        // Init phase: The peripheral computes the hash subkey.
+        self.enable();
+        self.wait_gcm_init()?;
+
+        if !aad.is_empty() {
+            self.set_gcm_phase(GCM_PHASE_HEADER)?;
+            for chunk in aad.chunks(BLOCK_SIZE) {
+                let mut block = [0; BLOCK_SIZE];
+                block[..chunk.len()].copy_from_slice(chunk);
+                self.write_header_block(&block)?;
+            }
+        }
+
+        if !buf.is_empty() {
+            self.set_gcm_phase(GCM_PHASE_PAYLOAD)?;
+
+            let partial = buf.len() % BLOCK_SIZE;
+            if partial != 0 && !decrypt {
+                // Mark the padding, so it's excluded from the tag. (Decryption authenticates the
+                // zero-padded ciphertext, so doesn't need this)
+                #[cfg(not(any(feature = "l4", feature = "f4")))]
+                self.modify_reg(CR, |r| {
+                    r | ((BLOCK_SIZE - partial) as u32) << CR_NPBLB_SHIFT
+                });
+            }
+
+            self.process_blocks(buf)?;
+        }
+
+        self.set_gcm_phase(GCM_PHASE_FINAL)?;
+
+        // Lengths of the additional data, and payload, in bits, as 64-bit integers. These
+        // aren't byte-swapped by the DATATYPE setting, so swap them here.
+        let aad_bits = aad.len() as u64 * 8;
+        let payload_bits = buf.len() as u64 * 8;
+
+        let lens = [
+            ((aad_bits >> 32) as u32).swap_bytes(),
+            (aad_bits as u32).swap_bytes(),
+            ((payload_bits >> 32) as u32).swap_bytes(),
+            (payload_bits as u32).swap_bytes(),
+        ];
+
+        let mut block = [0; BLOCK_SIZE];
+        for (chunk, len) in block.chunks_exact_mut(4).zip(lens) {
+            chunk.copy_from_slice(&len.to_le_bytes());
+        }
+
+        self.process_block(&block)
+    }
+
+    #[cfg(not(feature = "f4"))]
+    /// Encrypt data using DMA, from `input` to `output`, which must be the same length; a multiple of
+    /// 16 bytes. Configure the DMA mux (or channel select) for the peripheral's input and output
+    /// requests first. Run `stop_dma()` once the output channel's transfer completes.
+    ///
+    /// # Safety
+    /// `input` and `output` must remain valid while the transfer runs.
+    pub unsafe fn encrypt_dma(
+        &mut self,
+        mode: Mode,
+        input: &[u8],
+        output: &mut [u8],
+        in_channel: DmaChannel,
+        out_channel: DmaChannel,
+        dma_periph: DmaPeriph,
+    ) -> Result<(), CryptoError> {
+        self.process_dma(
+            mode,
+            input,
+            output,
+            (in_channel, out_channel),
+            dma_periph,
+            false,
+        )
+    }
+
+    #[cfg(not(feature = "f4"))]
+    /// Decrypt data using DMA. See `encrypt_dma()` for details.
+    ///
+    /// # Safety
+    /// `input` and `output` must remain valid while the transfer runs.
+    pub unsafe fn decrypt_dma(
+        &mut self,
+        mode: Mode,
+        input: &[u8],
+        output: &mut [u8],
+        in_channel: DmaChannel,
+        out_channel: DmaChannel,
+        dma_periph: DmaPeriph,
+    ) -> Result<(), CryptoError> {
+        self.process_dma(
+            mode,
+            input,
+            output,
+            (in_channel, out_channel),
+            dma_periph,
+            true,
+        )
+    }
+
+    #[cfg(not(feature = "f4"))]
+    unsafe fn process_dma(
+        &mut self,
+        mode: Mode,
+        input: &[u8],
+        output: &mut [u8],
+        channels: (DmaChannel, DmaChannel),
+        dma_periph: DmaPeriph,
+        decrypt: bool,
+    ) -> Result<(), CryptoError> {
+        if input.len() != output.len() || !input.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(CryptoError::Length);
+        }
+
+        self.configure(mode.chain(), decrypt, &mode.iv())?;
+
+        #[cfg(feature = "h7")]
+        let num_data = (input.len() / 4) as u32;
+        #[cfg(not(feature = "h7"))]
+        let num_data = (input.len() / 4) as u16;
+
+        // Read output at a higher priority than input, so the output FIFO doesn't stall the
+        // peripheral.
+        let transfers = [
+            (
+                channels.1,
+                DOUT,
+                output.as_mut_ptr() as u32,
+                Direction::ReadFromPeriph,
+                Priority::High,
+            ),
+            (
+                channels.0,
+                DIN,
+                input.as_ptr() as u32,
+                Direction::ReadFromMem,
+                Priority::Medium,
+            ),
+        ];
+
+        for (channel, reg, mem_addr, direction, priority) in transfers {
+            let cfg = ChannelCfg {
+                priority,
+                ..Default::default()
+            };
+
+            match dma_periph {
+                DmaPeriph::Dma1 => {
+                    let mut regs = &(*DMA1::ptr());
+                    dma::cfg_channel(
+                        &mut regs,
+                        channel,
+                        self.reg(reg) as u32,
+                        mem_addr,
+                        num_data,
+                        direction,
+                        DataSize::S32,
+                        DataSize::S32,
+                        cfg,
+                    );
+                }
+                #[cfg(not(any(
+                    feature = "f3x4",
+                    all(feature = "g0", not(any(feature = "g0b1", feature = "g0c1"))),
+                    feature = "wb",
+                )))]
+                DmaPeriph::Dma2 => {
+                    let mut regs = &(*crate::pac::DMA2::ptr());
+                    dma::cfg_channel(
+                        &mut regs,
+                        channel,
+                        self.reg(reg) as u32,
+                        mem_addr,
+                        num_data,
+                        direction,
+                        DataSize::S32,
+                        DataSize::S32,
+                        cfg,
+                    );
+                }
+            }
+        }
+
+        cfg_if! {
+            if #[cfg(feature = "h7")] {
+                self.write_reg(DMACR, DMACR_DIEN | DMACR_DOEN);
+            } else {
+                self.modify_reg(CR, |r| r | CR_DMAINEN | CR_DMAOUTEN);
+            }
+        }
+
+        self.enable();
+        Ok(())
+    }
+
+    #[cfg(not(feature = "f4"))]
+    /// Stop a DMA transfer started with `encrypt_dma()` or `decrypt_dma()`, and disable the
+    /// peripheral.
+    pub fn stop_dma(
+        &mut self,
+        in_channel: DmaChannel,
+        out_channel: DmaChannel,
+        dma_periph: DmaPeriph,
+    ) {
+        dma::stop(dma_periph, in_channel);
+        dma::stop(dma_periph, out_channel);
+
+        cfg_if! {
+            if #[cfg(feature = "h7")] {
+                self.write_reg(DMACR, 0);
+            } else {
+                self.modify_reg(CR, |r| r & !(CR_DMAINEN | CR_DMAOUTEN));
+            }
+        }
+
+        self.disable();
+    }
+
+    /// Set up the peripheral for an operation, including writing the key and IV. Prepares the
+    /// decryption key for ECB and CBC decryption. Leaves the peripheral disabled.
+    fn configure(&mut self, chain: Chain, decrypt: bool, iv: &[u32; 4]) -> Result<(), CryptoError> {
+        if self.key_words == 0 {
+            return Err(CryptoError::KeyLen);
+        }
+        let key_256 = self.key_words == 8;
+
+        self.disable();
+
+        cfg_if! {
+            if #[cfg(any(feature = "h7", feature = "f4"))] {
+                self.write_reg(CR, CR_FFLUSH);
+
+                let algo = match chain {
+                    Chain::Ecb => 0b100,
+                    Chain::Cbc => 0b101,
+                    Chain::Ctr => 0b110,
+                    Chain::Gcm => 0b1000,
+                };
+
+                let mut cr = CR_DATATYPE_BYTE | algo_bits(algo);
+                if key_256 {
+                    cr |= CR_KEYSIZE_256;
+                }
+                if decrypt {
+                    cr |= CR_ALGODIR;
+                }
+
+                // Keys are right-aligned in the key registers; 128-bit keys start at K2LR.
+                let key = self.key;
+                for (i, word) in key[..self.key_words].iter().enumerate() {
+                    self.write_reg(KEYR + (8 - self.key_words + i) * 4, *word);
+                }
+
+                if decrypt && matches!(chain, Chain::Ecb | Chain::Cbc) {
+                    // Key preparation; the peripheral disables itself when done.
+                    self.write_reg(CR, (cr & !algo_bits(0b1111)) | algo_bits(ALGO_KEY_PREP) | CR_EN);
+                    self.wait(|s| s.read_reg(SR) & SR_BUSY == 0)?;
+                    self.disable();
+                }
+
+                self.write_reg(CR, cr | (GCM_PHASE_INIT << CR_GCMPH_SHIFT));
+
+                // IV0LR, IV0RR, IV1LR, IV1RR.
+                for (i, word) in iv.iter().enumerate() {
+                    self.write_reg(IVR + i * 4, *word);
+                }
+            } else {
+                let chmod = match chain {
+                    Chain::Ecb => 0b000,
+                    Chain::Cbc => 0b001,
+                    Chain::Ctr => 0b010,
+                    Chain::Gcm => 0b011,
+                };
+
+                let mut cr = CR_DATATYPE_BYTE
+                    | ((chmod & 0b11) << CR_CHMOD_SHIFT)
+                    | (GCM_PHASE_INIT << CR_GCMPH_SHIFT);
+                if chmod & 0b100 != 0 {
+                    cr |= CR_CHMOD2;
+                }
+                if key_256 {
+                    cr |= CR_KEYSIZE_256;
+                }
+
+                // Clear any error flags, and the computation complete flag from a previous operation.
+                self.write_reg(CR, cr | CR_CCFC | CR_ERRC);
+
+                // KEYR3 holds the most significant word of the key; KEYR7 for 256-bit keys.
+                let key = self.key;
+                let (high, low) = key[..self.key_words].split_at(self.key_words - 4);
+                for (i, word) in low.iter().rev().enumerate() {
+                    self.write_reg(KEYR + i * 4, *word);
+                }
+                for (i, word) in high.iter().rev().enumerate() {
+                    self.write_reg(KEYR4 + i * 4, *word);
+                }
+
+                let mode = if decrypt && matches!(chain, Chain::Ecb | Chain::Cbc) {
+                    // Derive the decryption key from the encryption key.
+                    self.write_reg(CR, cr | (MODE_KEY_DERIVATION << CR_MODE_SHIFT) | CR_EN);
+                    self.wait(|s| s.read_reg(SR) & SR_CCF != 0)?;
+                    self.modify_reg(CR, |r| r | CR_CCFC);
+                    self.disable();
+
+                    MODE_DECRYPT
+                } else if decrypt && chain == Chain::Gcm {
+                    MODE_DECRYPT
+                } else {
+                    // CTR decryption is the same as encryption.
+                    MODE_ENCRYPT
+                };
+
+                self.write_reg(CR, cr | (mode << CR_MODE_SHIFT));
+
+                // IVR3 holds the most significant word.
+                for (i, word) in iv.iter().rev().enumerate() {
+                    self.write_reg(IVR + i * 4, *word);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn enable(&mut self) {
+        self.modify_reg(CR, |r| r | CR_EN);
+    }
+
+    fn disable(&mut self) {
+        self.modify_reg(CR, |r| r & !CR_EN);
+    }
+
+    /// Write a block, and read the result, with the peripheral enabled.
+    fn process_block(&mut self, block: &[u8; 16]) -> Result<[u8; 16], CryptoError> {
+        // With the DATATYPE set to bytes, the peripheral swaps each word into big endian order.
+        for chunk in block.chunks_exact(4) {
+            #[cfg(any(feature = "h7", feature = "f4"))]
+            self.wait(|s| s.read_reg(SR) & SR_IFNF != 0)?;
+            self.write_reg(DIN, u32::from_le_bytes(chunk.try_into().unwrap()));
+        }
+
+        #[cfg(not(any(feature = "h7", feature = "f4")))]
+        self.wait_ccf()?;
+
+        let mut result = [0; 16];
+        for chunk in result.chunks_exact_mut(4) {
+            #[cfg(any(feature = "h7", feature = "f4"))]
+            self.wait(|s| s.read_reg(SR) & SR_OFNE != 0)?;
+            chunk.copy_from_slice(&self.read_reg(DOUT).to_le_bytes());
+        }
+
+        #[cfg(not(any(feature = "h7", feature = "f4")))]
+        self.modify_reg(CR, |r| r | CR_CCFC);
+
+        Ok(result)
+    }
+
+    /// Write a GCM header block. There is no output.
+    fn write_header_block(&mut self, block: &[u8; 16]) -> Result<(), CryptoError> {
+        for chunk in block.chunks_exact(4) {
+            #[cfg(any(feature = "h7", feature = "f4"))]
+            self.wait(|s| s.read_reg(SR) & SR_IFNF != 0)?;
+            self.write_reg(DIN, u32::from_le_bytes(chunk.try_into().unwrap()));
+        }
+
+        #[cfg(not(any(feature = "h7", feature = "f4")))]
+        {
+            self.wait_ccf()?;
+            self.modify_reg(CR, |r| r | CR_CCFC);
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the GCM init phase, which computes the hash subkey, to complete.
+    fn wait_gcm_init(&mut self) -> Result<(), CryptoError> {
+        cfg_if! {
+            if #[cfg(any(feature = "h7", feature = "f4"))] {
+                // The peripheral disables itself when done.
+                self.wait(|s| s.read_reg(CR) & CR_EN == 0)
+            } else {
+                self.wait_ccf()?;
+                self.modify_reg(CR, |r| r | CR_CCFC);
+                Ok(())
+            }
+        }
+    }
+
+    /// Move to a new GCM phase, and enable the peripheral.
+    fn set_gcm_phase(&mut self, phase: u32) -> Result<(), CryptoError> {
+        #[cfg(any(feature = "h7", feature = "f4"))]
+        {
+            // Let the previous phase finish, and disable the peripheral to change phase.
+            self.wait(|s| s.read_reg(SR) & (SR_IFEM | SR_BUSY) == SR_IFEM)?;
+            self.disable();
+
+            if phase == GCM_PHASE_FINAL {
+                // ALGODIR must be 0 in the final phase, for both encryption and decryption.
+                self.modify_reg(CR, |r| r & !CR_ALGODIR);
+            }
+        }
+
+        self.modify_reg(CR, |r| {
+            (r & !(0b11 << CR_GCMPH_SHIFT)) | (phase << CR_GCMPH_SHIFT) | CR_EN
+        });
+
+        Ok(())
+    }
+
+    #[cfg(not(any(feature = "h7", feature = "f4")))]
+    /// Wait for the computation complete flag, checking for read and write errors.
+    fn wait_ccf(&mut self) -> Result<(), CryptoError> {
+        let mut i = 0;
+        loop {
+            let sr = self.read_reg(SR);
+            if sr & (SR_RDERR | SR_WRERR) != 0 {
+                self.modify_reg(CR, |r| r | CR_ERRC);
+                return Err(CryptoError::Hardware);
+            }
+            if sr & SR_CCF != 0 {
+                return Ok(());
+            }
+
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(CryptoError::Timeout);
+            }
+        }
+    }
+
+    fn wait(&self, done: impl Fn(&Self) -> bool) -> Result<(), CryptoError> {
+        let mut i = 0;
+        while !done(self) {
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(CryptoError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        unsafe { (&*self.regs as *const _ as *mut u8).add(offset) as *mut u32 }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.reg(offset)) }
+    }
+
+    fn write_reg(&mut self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(offset), val) }
+    }
+
+    fn modify_reg(&mut self, offset: usize, f: impl FnOnce(u32) -> u32) {
+        let val = self.read_reg(offset);
+        self.write_reg(offset, f(val));
+    }
+}
+
+#[cfg(any(feature = "h7", feature = "f4"))]
+/// Place an ALGOMODE value in its CR bits.
+fn algo_bits(algo: u32) -> u32 {
+    ((algo & 0b111) << CR_ALGOMODE_SHIFT) | if algo & 0b1000 != 0 { CR_ALGOMODE3 } else { 0 }
+}
+
+#[cfg(feature = "rustcrypto")]
+/// Adapts `Aes` to the RustCrypto traits: `BlockEncrypt` and `BlockDecrypt` (AES, with the loaded
+/// key), and `AeadInPlace` (AES-GCM). Load the key before creating this. Methods panic if the
+/// hardware fails, except the AEAD ones, which return an error.
+pub struct AesAdapter<'a> {
+    aes: RefCell<&'a mut Aes>,
+}
+
+#[cfg(feature = "rustcrypto")]
+impl<'a> AesAdapter<'a> {
+    pub fn new(aes: &'a mut Aes) -> Self {
+        Self {
+            aes: RefCell::new(aes),
+        }
+    }
+}
+
+#[cfg(feature = "rustcrypto")]
+mod rustcrypto {
+    use aead::{AeadCore, AeadInPlace, Nonce, Tag};
+    use cipher::{
+        consts::{U0, U1, U12, U16},
+        generic_array::GenericArray,
+        inout::InOut,
+        Block, BlockBackend, BlockCipher, BlockClosure, BlockDecrypt, BlockEncrypt, BlockSizeUser,
+        ParBlocksSizeUser,
+    };
+
+    use super::{Aes, AesAdapter, Chain};
+
+    /// Processes blocks with the peripheral configured for ECB.
+    struct Backend<'b>(&'b mut Aes);
+
+    impl BlockSizeUser for Backend<'_> {
+        type BlockSize = U16;
+    }
+
+    impl ParBlocksSizeUser for Backend<'_> {
+        type ParBlocksSize = U1;
+    }
+
+    impl BlockBackend for Backend<'_> {
+        fn proc_block(&mut self, mut block: InOut<'_, '_, Block<Self>>) {
+            let input: [u8; 16] = block.clone_in().into();
+            let output = self.0.process_block(&input).unwrap();
+            block.get_out().copy_from_slice(&output);
+        }
+    }
+
+    impl AesAdapter<'_> {
+        fn with_backend(&self, decrypt: bool, f: impl BlockClosure<BlockSize = U16>) {
+            let mut aes = self.aes.borrow_mut();
+            aes.configure(Chain::Ecb, decrypt, &[0; 4]).unwrap();
+            aes.enable();
+            f.call(&mut Backend(&mut aes));
+            aes.disable();
+        }
+    }
+
+    impl BlockSizeUser for AesAdapter<'_> {
+        type BlockSize = U16;
+    }
+
+    impl BlockCipher for AesAdapter<'_> {}
+
+    impl BlockEncrypt for AesAdapter<'_> {
+        fn encrypt_with_backend(&self, f: impl BlockClosure<BlockSize = Self::BlockSize>) {
+            self.with_backend(false, f);
+        }
+    }
+
+    impl BlockDecrypt for AesAdapter<'_> {
+        fn decrypt_with_backend(&self, f: impl BlockClosure<BlockSize = Self::BlockSize>) {
+            self.with_backend(true, f);
+        }
+    }
+
+    impl AeadCore for AesAdapter<'_> {
+        type NonceSize = U12;
+        type TagSize = U16;
+        type CiphertextOverhead = U0;
+    }
+
+    impl AeadInPlace for AesAdapter<'_> {
+        fn encrypt_in_place_detached(
+            &self,
+            nonce: &Nonce<Self>,
+            associated_data: &[u8],
+            buffer: &mut [u8],
+        ) -> aead::Result<Tag<Self>> {
+            let tag = self
+                .aes
+                .borrow_mut()
+                .encrypt_gcm(&(*nonce).into(), associated_data, buffer)
+                .map_err(|_| aead::Error)?;
+
+            Ok(GenericArray::from(tag))
+        }
+
+        fn decrypt_in_place_detached(
+            &self,
+            nonce: &Nonce<Self>,
+            associated_data: &[u8],
+            buffer: &mut [u8],
+            tag: &Tag<Self>,
+        ) -> aead::Result<()> {
+            self.aes
+                .borrow_mut()
+                .decrypt_gcm(&(*nonce).into(), associated_data, buffer, &(*tag).into())
+                .map_err(|_| aead::Error)
+        }
+    }
+}
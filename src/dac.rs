@@ -20,14 +20,18 @@ cfg_if! {
 
 #[cfg(any(feature = "f3", feature = "l4"))]
 use crate::dma::DmaInput;
-#[cfg(not(any(feature = "f4", feature = "l552")))]
-use crate::dma::{self, ChannelCfg, DmaChannel};
 #[cfg(feature = "g0")]
 use crate::pac::DMA as DMA1;
 #[cfg(not(feature = "g0"))]
 use crate::pac::DMA1;
+#[cfg(not(any(feature = "f4", feature = "l552")))]
+use crate::{
+    dma::{self, ChannelCfg, DmaChannel},
+    dma_route,
+};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Sets the DAC_MCR register, Mode1 and Mode2 fields.
 pub enum DacMode {
@@ -53,7 +57,8 @@ pub enum DacMode {
 
 use cfg_if::cfg_if;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Select the channel to output to. Most MCUs only use 2 channels.
 pub enum DacChannel {
     C1,
@@ -61,7 +66,8 @@ pub enum DacChannel {
     C2,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Three options are available to set DAC precision. Sets the DHR8R1 etc register contents.
 pub enum DacBits {
     /// Eight bit precision, right-aligned.
@@ -72,7 +78,8 @@ pub enum DacBits {
     TwelveR,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 #[cfg(not(any(feature = "h7", feature = "g4")))]
 /// Select a trigger, used by some features. Sets DAC_CR, TSEL1 and TSEL2 fields, for Channel 1
@@ -96,7 +103,8 @@ pub enum Trigger {
     Swtrig = 0b111,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 #[cfg(feature = "g4")]
 /// Trigger selection on G4, used by TSELx and Sawtooth generation ST[INC|RST]TRIGSELx
@@ -147,7 +155,8 @@ pub enum WaveGeneration {
     Sawtooth = 0b11,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 #[cfg(any(feature = "g4"))]
 pub enum SawtoothDirection {
@@ -155,7 +164,8 @@ pub enum SawtoothDirection {
     Rising = 0b1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg(any(feature = "g4"))]
 /// Sawtooth Generation configuration for the generate_sawtooth() method
 pub struct SawtoothConfig {
@@ -179,7 +189,8 @@ pub struct SawtoothConfig {
     pub direction: SawtoothDirection,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 #[cfg(feature = "h7")]
 /// Select a trigger, used by some features. Sets DAC_CR, TSEL1 and TSEL2 fields, for Channel 1
@@ -215,7 +226,8 @@ pub enum Trigger {
     Exti9 = 13,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 #[cfg(feature = "g4")]
 /// High frequency interface mode selection.
@@ -230,7 +242,8 @@ pub enum HighFrequencyMode {
     High = 0b10,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DacConfig {
     /// Mode: Ie buffer enabled or not, and connected to internal, external, or both. Defaults
     /// to external only, buffer enabled.
@@ -476,19 +489,7 @@ where
         // When an external trigger (but not a software trigger) occurs while the DMAENx bit is set, the
         // value of the DAC_DHRx register is transferred into the DAC_DORx register when the
         // transfer is complete, and a DMA request is generated.
-        #[cfg(feature = "g4")]
-        match dac_channel {
-            DacChannel::C1 => self.regs.dac_cr.modify(|_, w| w.dmaen1().set_bit()),
-            #[cfg(not(feature = "wl"))]
-            DacChannel::C2 => self.regs.dac_cr.modify(|_, w| w.dmaen2().set_bit()),
-        }
-
-        #[cfg(not(feature = "g4"))]
-        match dac_channel {
-            DacChannel::C1 => self.regs.cr.modify(|_, w| w.dmaen1().set_bit()),
-            #[cfg(not(feature = "wl"))]
-            DacChannel::C2 => self.regs.cr.modify(|_, w| w.dmaen2().set_bit()),
-        }
+        self.enable_dma_request(dac_channel);
 
         // In dual mode, if both DMAENx bits are set, two DMA requests are generated. If only one
         // DMA request is needed, only the corresponding DMAENx bit must be set. In this way, the
@@ -513,35 +514,7 @@ where
         // For each DAC channelx, an interrupt is also generated if its corresponding DMAUDRIEx bit
         // in the DAC_CR register is enabled.
 
-        #[cfg(feature = "g4")]
-        let periph_addr = match dac_channel {
-            DacChannel::C1 => match &self.cfg.bits {
-                DacBits::EightR => &self.regs.dac_dhr8r1 as *const _ as u32,
-                DacBits::TwelveL => &self.regs.dac_dhr12l1 as *const _ as u32,
-                DacBits::TwelveR => &self.regs.dac_dhr12r1 as *const _ as u32,
-            },
-            #[cfg(not(feature = "wl"))]
-            DacChannel::C2 => match &self.cfg.bits {
-                DacBits::EightR => &self.regs.dac_dhr8r2 as *const _ as u32,
-                DacBits::TwelveL => &self.regs.dac_dhr12l2 as *const _ as u32,
-                DacBits::TwelveR => &self.regs.dac_dhr12r2 as *const _ as u32,
-            },
-        };
-
-        #[cfg(not(feature = "g4"))]
-        let periph_addr = match dac_channel {
-            DacChannel::C1 => match &self.cfg.bits {
-                DacBits::EightR => &self.regs.dhr8r1 as *const _ as u32,
-                DacBits::TwelveL => &self.regs.dhr12l1 as *const _ as u32,
-                DacBits::TwelveR => &self.regs.dhr12r1 as *const _ as u32,
-            },
-            #[cfg(not(feature = "wl"))]
-            DacChannel::C2 => match &self.cfg.bits {
-                DacBits::EightR => &self.regs.dhr8r2 as *const _ as u32,
-                DacBits::TwelveL => &self.regs.dhr12l2 as *const _ as u32,
-                DacBits::TwelveR => &self.regs.dhr12r2 as *const _ as u32,
-            },
-        };
+        let periph_addr = self.data_reg_addr(dac_channel);
 
         #[cfg(feature = "h7")]
         let len = len as u32;
@@ -581,6 +554,72 @@ where
         }
     }
 
+    #[cfg(not(any(feature = "f4", feature = "l552")))]
+    /// Enable DMA requests on each trigger, and return the data register, for use as the destination
+    /// of a DMA route. (See the `dma_route` module). `request` is this channel's DMA input. Set the
+    /// trigger, and enable the channel separately.
+    pub fn dma_sink(
+        &mut self,
+        dac_channel: DacChannel,
+        request: dma::DmaInput,
+    ) -> dma_route::Endpoint<u16> {
+        self.enable_dma_request(dac_channel);
+        dma_route::Endpoint::register(self.data_reg_addr(dac_channel), request)
+    }
+
+    #[cfg(not(any(feature = "f4", feature = "l552")))]
+    /// Set the DMAEN bit for a channel.
+    fn enable_dma_request(&mut self, dac_channel: DacChannel) {
+        #[cfg(feature = "g4")]
+        match dac_channel {
+            DacChannel::C1 => self.regs.dac_cr.modify(|_, w| w.dmaen1().set_bit()),
+            #[cfg(not(feature = "wl"))]
+            DacChannel::C2 => self.regs.dac_cr.modify(|_, w| w.dmaen2().set_bit()),
+        }
+
+        #[cfg(not(feature = "g4"))]
+        match dac_channel {
+            DacChannel::C1 => self.regs.cr.modify(|_, w| w.dmaen1().set_bit()),
+            #[cfg(not(feature = "wl"))]
+            DacChannel::C2 => self.regs.cr.modify(|_, w| w.dmaen2().set_bit()),
+        }
+    }
+
+    #[cfg(not(any(feature = "f4", feature = "l552")))]
+    /// The address of a channel's data holding register, for the configured precision.
+    fn data_reg_addr(&self, dac_channel: DacChannel) -> u32 {
+        cfg_if! {
+            if #[cfg(feature = "g4")] {
+                match dac_channel {
+                    DacChannel::C1 => match &self.cfg.bits {
+                        DacBits::EightR => &self.regs.dac_dhr8r1 as *const _ as u32,
+                        DacBits::TwelveL => &self.regs.dac_dhr12l1 as *const _ as u32,
+                        DacBits::TwelveR => &self.regs.dac_dhr12r1 as *const _ as u32,
+                    },
+                    DacChannel::C2 => match &self.cfg.bits {
+                        DacBits::EightR => &self.regs.dac_dhr8r2 as *const _ as u32,
+                        DacBits::TwelveL => &self.regs.dac_dhr12l2 as *const _ as u32,
+                        DacBits::TwelveR => &self.regs.dac_dhr12r2 as *const _ as u32,
+                    },
+                }
+            } else {
+                match dac_channel {
+                    DacChannel::C1 => match &self.cfg.bits {
+                        DacBits::EightR => &self.regs.dhr8r1 as *const _ as u32,
+                        DacBits::TwelveL => &self.regs.dhr12l1 as *const _ as u32,
+                        DacBits::TwelveR => &self.regs.dhr12r1 as *const _ as u32,
+                    },
+                    #[cfg(not(feature = "wl"))]
+                    DacChannel::C2 => match &self.cfg.bits {
+                        DacBits::EightR => &self.regs.dhr8r2 as *const _ as u32,
+                        DacBits::TwelveL => &self.regs.dhr12l2 as *const _ as u32,
+                        DacBits::TwelveR => &self.regs.dhr12r2 as *const _ as u32,
+                    },
+                }
+            }
+        }
+    }
+
     /// Set the DAC output voltage.
     pub fn write_voltage(&mut self, channel: DacChannel, volts: f32) {
         let max_word = match self.cfg.bits {
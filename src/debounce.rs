@@ -0,0 +1,289 @@
+//! Debouncing for external trigger inputs, eg ADC or timer triggers from noisy industrial lines, where
+//! glitches or contact bounce would otherwise cause double conversions.
+//!
+//! In hardware: A general-purpose or advanced timer receives the trigger on its CH1, CH2, or ETR pin.
+//! The timer's digital input filter rejects pulses shorter than its window. Each filtered edge starts
+//! the timer in one-pulse mode, and its TRGO output goes high while it runs. Edges that arrive while
+//! it's running are ignored, so the timer's period acts as a hold-off time. Trigger the ADC (or another
+//! timer) from this timer's TRGO, on the rising edge.
+//!
+//! In software: `Debouncer` filters a periodically sampled input, and `HoldOff` rejects
+//! triggers that arrive too soon after the previous one; eg for an EXTI line.
+//!
+//! Example, with a 1ms hold-off, and a filter rejecting pulses shorter than 2us:
+//! ```rust
+//! let mut timer = Timer::new_tim3(dp.TIM3, 1_000., Default::default(), &clock_cfg);
+//!
+//! let cfg = DebounceConfig {
+//!     input: TriggerInput::Ch1,
+//!     filter: InputFilter::for_duration(clock_cfg.apb1_timer(), 2_000),
+//!     edge: Edge::Rising,
+//! };
+//! debounce::configure(&mut timer, &cfg);
+//!
+//! adc.set_trigger(adc::Trigger::Tim3Trgo, TriggerEdge::HardwareRising);
+//! ```
+
+use core::ops::Deref;
+
+use crate::timer::Timer;
+
+// Register offsets. The slave mode controller, and input filter fields are the same on all
+// general-purpose and advanced timers, but their PAC field names vary between timers and families.
+const CR1: usize = 0x00;
+const CR2: usize = 0x04;
+const SMCR: usize = 0x08;
+const CCMR1: usize = 0x18;
+const CCER: usize = 0x20;
+
+const CR1_CEN: u32 = 1 << 0;
+const CR1_OPM: u32 = 1 << 3;
+/// CR2, MMS field: The counter enable signal is used as TRGO.
+const MMS_ENABLE: u32 = 0b001 << 4;
+/// SMCR, SMS field: Trigger mode. The counter starts on the trigger's rising edge.
+const SMS_TRIGGER: u32 = 0b110;
+
+/// Implemented for timers with a slave mode controller, filtered inputs, and an ETR input; ie
+/// TIM1 - TIM5, TIM8, and TIM20, where available.
+pub trait TriggerTimer {}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// The timer input the trigger is connected to.
+pub enum TriggerInput {
+    /// Channel 1 (TI1FP1).
+    Ch1,
+    /// Channel 2 (TI2FP2).
+    Ch2,
+    /// The external trigger input (ETRF).
+    Etr,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// The trigger's active edge.
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// Digital input filter: An edge is only accepted after N consecutive samples at the new level, at
+/// the sampling frequency listed. Sets the CCMR1 register, ICxF field, or the SMCR register, ETF
+/// field. This assumes CR1 CKD = 0, so fDTS is the timer clock.
+pub enum InputFilter {
+    /// No filter; sampled at the timer clock.
+    None = 0b0000,
+    Clk2 = 0b0001,
+    Clk4 = 0b0010,
+    Clk8 = 0b0011,
+    Div2N6 = 0b0100,
+    Div2N8 = 0b0101,
+    Div4N6 = 0b0110,
+    Div4N8 = 0b0111,
+    Div8N6 = 0b1000,
+    Div8N8 = 0b1001,
+    Div16N5 = 0b1010,
+    Div16N6 = 0b1011,
+    Div16N8 = 0b1100,
+    Div32N5 = 0b1101,
+    Div32N6 = 0b1110,
+    Div32N8 = 0b1111,
+}
+
+/// Filter settings, ordered by window length.
+const FILTERS: [InputFilter; 16] = [
+    InputFilter::None,
+    InputFilter::Clk2,
+    InputFilter::Clk4,
+    InputFilter::Clk8,
+    InputFilter::Div2N6,
+    InputFilter::Div2N8,
+    InputFilter::Div4N6,
+    InputFilter::Div4N8,
+    InputFilter::Div8N6,
+    InputFilter::Div8N8,
+    InputFilter::Div16N5,
+    InputFilter::Div16N6,
+    InputFilter::Div16N8,
+    InputFilter::Div32N5,
+    InputFilter::Div32N6,
+    InputFilter::Div32N8,
+];
+
+impl InputFilter {
+    /// The shortest pulse the filter accepts, in timer clock cycles.
+    pub fn window_cycles(&self) -> u32 {
+        match self {
+            Self::None => 1,
+            Self::Clk2 => 2,
+            Self::Clk4 => 4,
+            Self::Clk8 => 8,
+            Self::Div2N6 => 12,
+            Self::Div2N8 => 16,
+            Self::Div4N6 => 24,
+            Self::Div4N8 => 32,
+            Self::Div8N6 => 48,
+            Self::Div8N8 => 64,
+            Self::Div16N5 => 80,
+            Self::Div16N6 => 96,
+            Self::Div16N8 => 128,
+            Self::Div32N5 => 160,
+            Self::Div32N6 => 192,
+            Self::Div32N8 => 256,
+        }
+    }
+
+    /// Select the shortest filter that rejects pulses shorter than `ns` nanoseconds, for a timer
+    /// clock `timer_clock` in Hz. Returns the longest filter if none are long enough; this is 256
+    /// timer clock cycles.
+    pub fn for_duration(timer_clock: u32, ns: u32) -> Self {
+        let cycles = (ns as u64 * timer_clock as u64).div_ceil(1_000_000_000) as u32;
+
+        FILTERS
+            .into_iter()
+            .find(|f| f.window_cycles() >= cycles)
+            .unwrap_or(Self::Div32N8)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Hardware trigger debounce configuration.
+pub struct DebounceConfig {
+    pub input: TriggerInput,
+    pub filter: InputFilter,
+    pub edge: Edge,
+}
+
+/// Configure a timer as a debounced trigger source. The timer's period sets the hold-off time;
+/// set it when creating the timer, or with `set_period()`. This stops the timer, and puts it in
+/// one-pulse mode, where it waits for the trigger. Other channels remain available; eg a PWM output
+/// on CH3 pulses once for each accepted trigger.
+pub fn configure<R>(timer: &mut Timer<R>, cfg: &DebounceConfig)
+where
+    R: Deref + TriggerTimer,
+{
+    let regs = &*timer.regs as *const _ as *mut u8;
+    let reg = |offset: usize| unsafe { regs.add(offset) as *mut u32 };
+    let read = |offset| unsafe { core::ptr::read_volatile(reg(offset)) };
+    let write = |offset, val| unsafe { core::ptr::write_volatile(reg(offset), val) };
+
+    write(CR1, (read(CR1) & !CR1_CEN) | CR1_OPM);
+
+    let filter = cfg.filter as u32;
+    let falling = cfg.edge == Edge::Falling;
+
+    // TS field: 0b101 for TI1FP1, 0b110 for TI2FP2, and 0b111 for ETRF.
+    let ts = match cfg.input {
+        TriggerInput::Ch1 => {
+            // CC1S = 01: Input, mapped on TI1. IC1F, in bits 4:7.
+            write(CCMR1, (read(CCMR1) & !0xff) | 0b01 | (filter << 4));
+            // CC1P (bit 1) selects falling edges; CC1NP (bit 3) must be clear.
+            write(CCER, (read(CCER) & !0b1010) | ((falling as u32) << 1));
+            0b101
+        }
+        TriggerInput::Ch2 => {
+            // CC2S = 01: Input, mapped on TI2. IC2F, in bits 12:15.
+            write(
+                CCMR1,
+                (read(CCMR1) & !0xff00) | (0b01 << 8) | (filter << 12),
+            );
+            // CC2P is bit 5, and CC2NP is bit 7.
+            write(CCER, (read(CCER) & !0b1010_0000) | ((falling as u32) << 5));
+            0b110
+        }
+        TriggerInput::Etr => 0b111,
+    };
+
+    // SMCR: Clear SMS (bits 0:2, and 16), TS (bits 4:6, and 20:21), and the ETR fields (bits 8:15).
+    // For ETR, set the ETF field (bits 8:11), and ETP (bit 15) for falling edges. The ETR
+    // prescaler (ETPS) is left off.
+    let mut smcr = read(SMCR) & !(0x0031_ff77);
+    smcr |= SMS_TRIGGER | (ts << 4);
+    if cfg.input == TriggerInput::Etr {
+        smcr |= (filter << 8) | ((falling as u32) << 15);
+    }
+    write(SMCR, smcr);
+
+    // MMS, in bits 4:6.
+    write(CR2, (read(CR2) & !(0b111 << 4)) | MMS_ENABLE);
+}
+
+/// A software debouncer, for a digital input sampled at a regular interval; eg from a timer
+/// interrupt. The output only changes once the input has been stable for `threshold` samples.
+pub struct Debouncer {
+    threshold: u8,
+    count: u8,
+    state: bool,
+}
+
+impl Debouncer {
+    /// Create a debouncer, with an initial state, and the number of consecutive samples required to
+    /// accept a change.
+    pub const fn new(initial: bool, threshold: u8) -> Self {
+        Self {
+            threshold,
+            count: 0,
+            state: initial,
+        }
+    }
+
+    /// Update with a new sample. Returns the edge, if the debounced state changed.
+    pub fn update(&mut self, sample: bool) -> Option<Edge> {
+        if sample == self.state {
+            self.count = 0;
+            return None;
+        }
+
+        self.count += 1;
+        if self.count < self.threshold {
+            return None;
+        }
+
+        self.count = 0;
+        self.state = sample;
+        Some(if sample { Edge::Rising } else { Edge::Falling })
+    }
+
+    /// The debounced state.
+    pub fn state(&self) -> bool {
+        self.state
+    }
+}
+
+/// Rejects triggers that arrive within a hold-off time of the last accepted one; eg in an EXTI
+/// interrupt handler that starts an ADC conversion. Times are in ticks of any free-running counter,
+/// eg a timer's count, or the DWT cycle counter, that wraps at `u32::MAX`.
+pub struct HoldOff {
+    hold_off: u32,
+    last: Option<u32>,
+}
+
+impl HoldOff {
+    /// Create a hold-off filter, with the minimum time between accepted triggers, in ticks.
+    pub const fn new(hold_off: u32) -> Self {
+        Self {
+            hold_off,
+            last: None,
+        }
+    }
+
+    /// Call on each trigger, with the current time; returns true if it should be accepted.
+    pub fn accept(&mut self, now: u32) -> bool {
+        if let Some(last) = self.last {
+            if now.wrapping_sub(last) < self.hold_off {
+                return false;
+            }
+        }
+        self.last = Some(now);
+        true
+    }
+
+    /// Accept the next trigger, regardless of when it arrives.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
@@ -51,7 +51,8 @@ pub enum DfsdmChannel {
     C7 = 7,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Sinc filter order. Sets FLTxFCR register, FORD field.
 pub enum FilterOrder {
@@ -69,7 +70,8 @@ pub enum FilterOrder {
     Sinc5 = 5,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Toggle clock clourse between system and audio clocks. Sets CH0CFGR1 register, CKOUTSRC field.
 pub enum DfsdmClockSrc {
@@ -80,7 +82,8 @@ pub enum DfsdmClockSrc {
     AudioClk = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// SPI clock select for a given channel.
 pub enum SpiClock {
@@ -94,7 +97,8 @@ pub enum SpiClock {
     InternalRising = 3,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// The type of DFSDM interrupt to configure. Reference Section 30.5 of the H742 RM.
 /// Enabled in FLTxCR2. register. Monitor in FLTxISR register. Cleared by writing to the
 /// FLTxICR register, for most.
@@ -115,7 +119,8 @@ pub enum DfsdmInterrupt {
     ChannelClockAbsense,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Set conversions to regular, continous, or contiuous fast mode. Sets FLTxCR1 register, RCONT
 /// and FAST fields.
 pub enum Continuous {
@@ -135,6 +140,8 @@ pub enum Continuous {
 /// Configuration for the DFSDM peripheral.
 /// All operations in the DFSDM peripheral are in signed format (filtering, integration, offset
 /// correction, right bit shift).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DfsdmConfig {
     /// Set the clock source to Sysclk, or Audio clock. Audio clock appears to be the SAI clock source,
     /// at least on H7. (?)
@@ -48,7 +48,8 @@ use paste::paste;
 
 // todo: Several sections of this are only correct for DMA1.
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DmaPeriph {
     Dma1,
     #[cfg(not(any(
@@ -136,6 +137,10 @@ pub enum DmaInput {
     Tim4Up = 71,
     Sai1A = 108,
     Sai1B = 109,
+    FmacRead = 110,
+    FmacWrite = 111,
+    CordicRead = 112,
+    CordicWrite = 113,
     // todo: These SAI2 values are bogus; can't find on G4 DMA mux.
     Sai2A = 203,
     Sai2B = 204,
@@ -237,6 +242,10 @@ pub enum DmaInput {
     Uart9Tx = 117,
     Uart10Rx = 118,
     Uart10Tx = 119,
+    FmacRead = 121,
+    FmacWrite = 122,
+    CordicRead = 123,
+    CordicWrite = 124,
 }
 
 #[derive(Copy, Clone)]
@@ -374,7 +383,8 @@ pub enum Priority {
     VeryHigh = 0b11,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Represents a DMA channel to select, eg when configuring for use with a peripheral.
 /// u8 representation is used to index registers on H7 PAC (And hopefully on future PACs if they
@@ -429,7 +439,8 @@ pub enum IncrMode {
     Enabled = 1,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Peripheral and memory increment mode. (CCR PSIZE and MSIZE bits)
 /// Can only be set when channel is disabled.
@@ -439,7 +450,8 @@ pub enum DataSize {
     S32 = 0b10,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Interrupt type. Set in CCR using TEIE, HTIE, and TCIE bits.
 /// Can only be set when channel is disabled.
 pub enum DmaInterrupt {
@@ -1877,6 +1889,61 @@ pub fn clear_interrupt(periph: DmaPeriph, channel: DmaChannel, interrupt: DmaInt
     }
 }
 
+#[cfg(not(any(feature = "h7", feature = "g0")))]
+fn transfer_flags_internal<D>(regs: &D, channel: DmaChannel) -> (bool, bool)
+where
+    D: Deref<Target = dma1::RegisterBlock>,
+{
+    let isr_val = regs.isr.read();
+    match channel {
+        DmaChannel::C1 => (isr_val.htif1().bit_is_set(), isr_val.tcif1().bit_is_set()),
+        DmaChannel::C2 => (isr_val.htif2().bit_is_set(), isr_val.tcif2().bit_is_set()),
+        DmaChannel::C3 => (isr_val.htif3().bit_is_set(), isr_val.tcif3().bit_is_set()),
+        DmaChannel::C4 => (isr_val.htif4().bit_is_set(), isr_val.tcif4().bit_is_set()),
+        DmaChannel::C5 => (isr_val.htif5().bit_is_set(), isr_val.tcif5().bit_is_set()),
+        DmaChannel::C6 => (isr_val.htif6().bit_is_set(), isr_val.tcif6().bit_is_set()),
+        DmaChannel::C7 => (isr_val.htif7().bit_is_set(), isr_val.tcif7().bit_is_set()),
+        #[cfg(any(feature = "l5", feature = "g4"))]
+        DmaChannel::C8 => (isr_val.htif8().bit_is_set(), isr_val.tcif8().bit_is_set()),
+    }
+}
+
+#[cfg(feature = "h7")]
+fn transfer_flags_internal<D>(regs: &D, channel: DmaChannel) -> (bool, bool)
+where
+    D: Deref<Target = dma1::RegisterBlock>,
+{
+    let lisr = regs.lisr.read();
+    let hisr = regs.hisr.read();
+    match channel {
+        DmaChannel::C0 => (lisr.htif0().bit_is_set(), lisr.tcif0().bit_is_set()),
+        DmaChannel::C1 => (lisr.htif1().bit_is_set(), lisr.tcif1().bit_is_set()),
+        DmaChannel::C2 => (lisr.htif2().bit_is_set(), lisr.tcif2().bit_is_set()),
+        DmaChannel::C3 => (lisr.htif3().bit_is_set(), lisr.tcif3().bit_is_set()),
+        DmaChannel::C4 => (hisr.htif4().bit_is_set(), hisr.tcif4().bit_is_set()),
+        DmaChannel::C5 => (hisr.htif5().bit_is_set(), hisr.tcif5().bit_is_set()),
+        DmaChannel::C6 => (hisr.htif6().bit_is_set(), hisr.tcif6().bit_is_set()),
+        DmaChannel::C7 => (hisr.htif7().bit_is_set(), hisr.tcif7().bit_is_set()),
+    }
+}
+
+// todo: G0 removed from this fn due to a bug introduced in PAC 0.13, as with `transfer_is_complete`.
+#[cfg(not(feature = "g0"))]
+/// Read a channel's half transfer, and transfer complete flags, as `(half, complete)`.
+pub fn transfer_flags(periph: DmaPeriph, channel: DmaChannel) -> (bool, bool) {
+    match periph {
+        DmaPeriph::Dma1 => {
+            let regs = unsafe { &(*DMA1::ptr()) };
+            transfer_flags_internal(&regs, channel)
+        }
+        #[cfg(not(any(feature = "f3x4", feature = "wb")))]
+        DmaPeriph::Dma2 => {
+            let regs = unsafe { &(*pac::DMA2::ptr()) };
+            transfer_flags_internal(&regs, channel)
+        }
+    }
+}
+
 #[cfg(any(
     feature = "l5",
     feature = "g0",
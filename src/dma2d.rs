@@ -0,0 +1,304 @@
+//! Support for the Chrom-ART Accelerator (DMA2D): A DMA dedicated to image manipulation, eg
+//! filling rectangles with a color, copying (blitting) rectangular regions between
+//! framebuffers with pixel format conversion, and alpha blending. Available on F427, F429, F469, and H7.
+//!
+//! Transfers started by this module are non-blocking. Use `wait()` to block until complete, or enable
+//! the `TransferComplete` interrupt.
+//!
+//! Note that on H7, the DMA2D can't access DTCM, so place framebuffers and source images in AXI SRAM,
+//! or external SDRAM. If the D-cache is enabled, clean it for source buffers before starting
+//! a transfer.
+
+use cfg_if::cfg_if;
+
+use crate::{
+    pac::{DMA2D, RCC},
+    util::rcc_en_reset,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// Color format of the output, and of the foreground and background inputs. Sets the
+/// OPFCCR, FGPFCCR, and BGPFCCR registers, CM fields.
+pub enum ColorMode {
+    Argb8888 = 0b000,
+    Rgb888 = 0b001,
+    Rgb565 = 0b010,
+    Argb1555 = 0b011,
+    Argb4444 = 0b100,
+}
+
+impl ColorMode {
+    /// The number of bytes used to store each pixel.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            Self::Argb8888 => 4,
+            Self::Rgb888 => 3,
+            _ => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// DMA2D transfer mode. Sets the CR register, MODE field.
+pub enum TransferMode {
+    /// Memory-to-memory, with the foreground fetch only.
+    MemToMem = 0b00,
+    /// Memory-to-memory, with pixel format conversion from the foreground format to the output format.
+    MemToMemPfc = 0b01,
+    /// Memory-to-memory, blending the foreground and background.
+    MemToMemBlend = 0b10,
+    /// Register-to-memory: Fill the output with the color in OCOLR.
+    RegToMem = 0b11,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// How an input's alpha is modified before blending. Sets the FGPFCCR and BGPFCCR registers,
+/// AM fields.
+pub enum AlphaMode {
+    /// Use each pixel's alpha.
+    NoModify = 0b00,
+    /// Replace each pixel's alpha with the constant alpha.
+    Replace = 0b01,
+    /// Multiply each pixel's alpha by the constant alpha.
+    Multiply = 0b10,
+}
+
+#[derive(Clone, Copy)]
+/// A foreground or background input to `blend()`.
+pub struct BlendInput {
+    /// The address of the top-left pixel.
+    pub addr: *const u8,
+    /// The number of pixels skipped after each line; eg the image width minus the blended width.
+    pub line_offset: u16,
+    pub color_mode: ColorMode,
+    pub alpha_mode: AlphaMode,
+    /// The constant alpha, used with `AlphaMode::Replace`, and `AlphaMode::Multiply`. 255 is opaque.
+    pub alpha: u8,
+}
+
+#[derive(Clone, Copy)]
+/// The output of a transfer, eg a region of a framebuffer.
+pub struct Dma2dOutput {
+    /// The address of the top-left pixel.
+    pub addr: *mut u8,
+    /// The number of pixels skipped after each line; eg the framebuffer width minus the output width.
+    pub line_offset: u16,
+    pub color_mode: ColorMode,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// DMA2D interrupts. Set in the CR register; cleared in the IFCR register.
+pub enum Dma2dInterrupt {
+    TransferComplete,
+    TransferError,
+    TransferWatermark,
+    ClutAccessError,
+    ClutTransferComplete,
+    ConfigurationError,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// DMA2D errors.
+pub enum Dma2dError {
+    /// A transfer error occured, eg the DMA2D accessed an invalid address.
+    Transfer,
+    /// The transfer was configured incorrectly.
+    Configuration,
+}
+
+/// Represents a Chrom-ART Accelerator (DMA2D) peripheral.
+pub struct Dma2d {
+    pub regs: DMA2D,
+}
+
+impl Dma2d {
+    /// Initialize the DMA2D peripheral, including enabling and resetting its RCC peripheral clock.
+    pub fn new(regs: DMA2D) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                rcc_en_reset!(ahb1, dma2d, rcc);
+            } else { // H7
+                rcc_en_reset!(ahb3, dma2d, rcc);
+            }
+        }
+
+        Self { regs }
+    }
+
+    /// Fill a `width` by `height` rectangle of `dest` with a single color. `color` is in the output
+    /// color format, eg `0x_ffff` for white in RGB565.
+    pub fn fill(&mut self, dest: &Dma2dOutput, width: u16, height: u16, color: u32) {
+        self.wait_idle();
+
+        self.regs
+            .opfccr
+            .write(|w| unsafe { w.cm().bits(dest.color_mode as u8) });
+        self.regs.ocolr.write(|w| unsafe { w.bits(color) });
+
+        self.set_output(dest, width, height);
+        self.start(TransferMode::RegToMem);
+    }
+
+    /// Copy a rectangle of pixels from one location to another, eg from an image into a framebuffer.
+    /// If `src_mode` and the output color mode differ, the pixel format is converted during the copy.
+    /// `src_offset` is the number of pixels skipped after each source line, as in `Dma2dOutput`.
+    pub fn copy(
+        &mut self,
+        src: *const u8,
+        src_offset: u16,
+        src_mode: ColorMode,
+        dest: &Dma2dOutput,
+        width: u16,
+        height: u16,
+    ) {
+        self.wait_idle();
+
+        self.regs.fgmar.write(|w| unsafe { w.bits(src as u32) });
+        self.regs.fgor.write(|w| unsafe { w.lo().bits(src_offset) });
+        self.regs
+            .fgpfccr
+            .write(|w| unsafe { w.cm().bits(src_mode as u8) });
+        self.regs
+            .opfccr
+            .write(|w| unsafe { w.cm().bits(dest.color_mode as u8) });
+
+        self.set_output(dest, width, height);
+
+        let mode = if src_mode == dest.color_mode {
+            TransferMode::MemToMem
+        } else {
+            TransferMode::MemToMemPfc
+        };
+        self.start(mode);
+    }
+
+    /// Blend a foreground image over a background image, and write the result to `dest`. The output
+    /// may be the same as the background, eg to draw a translucent image onto a framebuffer.
+    pub fn blend(
+        &mut self,
+        fg: &BlendInput,
+        bg: &BlendInput,
+        dest: &Dma2dOutput,
+        width: u16,
+        height: u16,
+    ) {
+        self.wait_idle();
+
+        self.regs.fgmar.write(|w| unsafe { w.bits(fg.addr as u32) });
+        self.regs
+            .fgor
+            .write(|w| unsafe { w.lo().bits(fg.line_offset) });
+        self.regs.fgpfccr.write(|w| unsafe {
+            w.cm().bits(fg.color_mode as u8);
+            w.am().bits(fg.alpha_mode as u8);
+            w.alpha().bits(fg.alpha)
+        });
+
+        self.regs.bgmar.write(|w| unsafe { w.bits(bg.addr as u32) });
+        self.regs
+            .bgor
+            .write(|w| unsafe { w.lo().bits(bg.line_offset) });
+        self.regs.bgpfccr.write(|w| unsafe {
+            w.cm().bits(bg.color_mode as u8);
+            w.am().bits(bg.alpha_mode as u8);
+            w.alpha().bits(bg.alpha)
+        });
+
+        self.regs
+            .opfccr
+            .write(|w| unsafe { w.cm().bits(dest.color_mode as u8) });
+
+        self.set_output(dest, width, height);
+        self.start(TransferMode::MemToMemBlend);
+    }
+
+    /// Set the output address, size, and line offset.
+    fn set_output(&mut self, dest: &Dma2dOutput, width: u16, height: u16) {
+        self.regs
+            .omar
+            .write(|w| unsafe { w.bits(dest.addr as u32) });
+        self.regs
+            .oor
+            .write(|w| unsafe { w.lo().bits(dest.line_offset) });
+        self.regs.nlr.write(|w| unsafe {
+            w.pl().bits(width);
+            w.nl().bits(height)
+        });
+    }
+
+    /// Start a transfer, using the mode specified.
+    fn start(&mut self, mode: TransferMode) {
+        self.regs.ifcr.write(|w| {
+            w.ctcif().set_bit();
+            w.cteif().set_bit();
+            w.cceif().set_bit()
+        });
+
+        self.regs.cr.modify(|_, w| unsafe {
+            w.mode().bits(mode as u8);
+            w.start().set_bit()
+        });
+    }
+
+    /// Returns true if a transfer is in progress.
+    pub fn is_busy(&self) -> bool {
+        self.regs.cr.read().start().bit_is_set()
+    }
+
+    /// Block until the current transfer is complete, returning an error if one occured.
+    pub fn wait(&mut self) -> Result<(), Dma2dError> {
+        self.wait_idle();
+
+        let isr = self.regs.isr.read();
+        if isr.teif().bit_is_set() {
+            self.clear_interrupt(Dma2dInterrupt::TransferError);
+            return Err(Dma2dError::Transfer);
+        }
+        if isr.ceif().bit_is_set() {
+            self.clear_interrupt(Dma2dInterrupt::ConfigurationError);
+            return Err(Dma2dError::Configuration);
+        }
+
+        Ok(())
+    }
+
+    fn wait_idle(&self) {
+        while self.is_busy() {}
+    }
+
+    /// Abort the current transfer.
+    pub fn abort(&mut self) {
+        self.regs.cr.modify(|_, w| w.abort().set_bit());
+        self.wait_idle();
+    }
+
+    /// Enable an interrupt.
+    pub fn enable_interrupt(&mut self, interrupt: Dma2dInterrupt) {
+        self.regs.cr.modify(|_, w| match interrupt {
+            Dma2dInterrupt::TransferComplete => w.tcie().set_bit(),
+            Dma2dInterrupt::TransferError => w.teie().set_bit(),
+            Dma2dInterrupt::TransferWatermark => w.twie().set_bit(),
+            Dma2dInterrupt::ClutAccessError => w.caeie().set_bit(),
+            Dma2dInterrupt::ClutTransferComplete => w.ctcie().set_bit(),
+            Dma2dInterrupt::ConfigurationError => w.ceie().set_bit(),
+        });
+    }
+
+    /// Clear an interrupt flag.
+    pub fn clear_interrupt(&mut self, interrupt: Dma2dInterrupt) {
+        self.regs.ifcr.write(|w| match interrupt {
+            Dma2dInterrupt::TransferComplete => w.ctcif().set_bit(),
+            Dma2dInterrupt::TransferError => w.cteif().set_bit(),
+            Dma2dInterrupt::TransferWatermark => w.ctwif().set_bit(),
+            Dma2dInterrupt::ClutAccessError => w.caecif().set_bit(),
+            Dma2dInterrupt::ClutTransferComplete => w.cctcif().set_bit(),
+            Dma2dInterrupt::ConfigurationError => w.cceif().set_bit(),
+        });
+    }
+}
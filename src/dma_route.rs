@@ -0,0 +1,398 @@
+//! Canned DMA routes between peripherals, eg ADC to DAC passthrough, a UART to UART bridge, or
+//! timer captures collected in memory, then sent over SPI.
+//!
+//! A route is a single DMA channel moving data from a source `Endpoint` to a destination one. Each
+//! endpoint is typed by the word it holds (`u8`, `u16` or `u32`), which sets the channel's data
+//! sizes. One endpoint's DMA request paces the transfer: the source's when it produces data (eg an
+//! ADC conversion, or a received byte), or the destination's when it's ready to accept it (eg SPI
+//! TXE). The route handles the request routing (DMAMUX, or L4's channel select), and the channel
+//! configuration.
+//!
+//! Peripheral endpoints are created by their modules, eg `Adc::dma_source()`, `Dac::dma_sink()`,
+//! `Usart::dma_source()`, and `Spi::dma_sink()`; these also enable the peripheral's DMA requests.
+//! Memory endpoints are created with `Endpoint::buffer()`.
+//!
+//! Example, bridging USART1's RX to USART2's TX:
+//! ```rust
+//! let rx = uart1.dma_source(DmaInput::Usart1Rx);
+//! let tx = uart2.dma_sink(DmaInput::Usart2Tx);
+//!
+//! let mut bridge = Route::uart_bridge(rx, tx);
+//! unsafe { bridge.start(DmaPeriph::Dma1, DmaChannel::C1).unwrap() };
+//! ```
+//!
+//! Note that on F3 and L4, the channel is fixed by the pacing request, and the `channel` argument
+//! is unused. On G4 and WB, run `dma::enable_mux1()` first.
+//!
+//! Each route's channel has its transfer complete interrupt enabled; for continuous peripheral to
+//! peripheral routes, leave its interrupt line masked.
+
+use core::marker::PhantomData;
+
+use cfg_if::cfg_if;
+
+use crate::{
+    dma::{
+        self, ChannelCfg, Circular, DataSize, Direction, DmaChannel, DmaInput, DmaInterrupt,
+        DmaPeriph, IncrMode, Priority,
+    },
+    timer::{TimChannel, Timer},
+};
+
+cfg_if! {
+    if #[cfg(all(feature = "g0", not(any(feature = "g0b1", feature = "g0c1"))))] {
+        use crate::pac::DMA as DMA1;
+    } else {
+        use crate::pac::DMA1;
+    }
+}
+
+// Timer register offsets. These are the same on all families, and we use them directly since the
+// PAC field names vary.
+const TIM_DIER: usize = 0x0c;
+const TIM_CCR1: usize = 0x34;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Route errors.
+pub enum RouteError {
+    /// The endpoint that paces the route has no DMA request; eg it's a memory buffer.
+    NoRequest,
+}
+
+/// A word size DMA can transfer. Implemented for `u8`, `u16`, and `u32`.
+pub trait Word: Copy {
+    const SIZE: DataSize;
+}
+
+impl Word for u8 {
+    const SIZE: DataSize = DataSize::S8;
+}
+
+impl Word for u16 {
+    const SIZE: DataSize = DataSize::S16;
+}
+
+impl Word for u32 {
+    const SIZE: DataSize = DataSize::S32;
+}
+
+#[derive(Clone, Copy)]
+/// One end of a route: A peripheral data register, or a memory buffer, holding words of type `W`.
+pub struct Endpoint<W> {
+    addr: u32,
+    incr: IncrMode,
+    /// The buffer length, in words; `None` for registers.
+    len: Option<usize>,
+    request: Option<DmaInput>,
+    _word: PhantomData<W>,
+}
+
+impl<W: Word> Endpoint<W> {
+    /// A peripheral register at `addr`, whose peripheral raises `request` when it has data, or can
+    /// accept it. The peripheral's DMA request must be enabled separately. Prefer the peripheral
+    /// module constructors, eg `Usart::dma_source()`, where available.
+    pub fn register(addr: u32, request: DmaInput) -> Self {
+        Self {
+            addr,
+            incr: IncrMode::Disabled,
+            len: None,
+            request: Some(request),
+            _word: PhantomData,
+        }
+    }
+
+    /// A memory buffer. The transfer steps through it, one word per request.
+    pub fn buffer(buf: &mut [W]) -> Self {
+        Self {
+            addr: buf.as_mut_ptr() as u32,
+            incr: IncrMode::Enabled,
+            len: Some(buf.len()),
+            request: None,
+            _word: PhantomData,
+        }
+    }
+
+    /// The address DMA reads or writes.
+    pub fn addr(&self) -> u32 {
+        self.addr
+    }
+}
+
+/// Set up a timer channel's captures as a route source, in words of 16 bits. This enables the
+/// channel's capture/compare DMA request. The timer channel must be configured for input capture
+/// separately. `request` is the timer channel's DMA input; eg `DmaInput::Tim2Ch1`.
+pub fn timer_capture<TIM>(
+    timer: &mut Timer<TIM>,
+    channel: TimChannel,
+    request: DmaInput,
+) -> Endpoint<u16>
+where
+    TIM: core::ops::Deref,
+{
+    let base = &*timer.regs as *const _ as *const u8 as *mut u32;
+    let ch = channel as usize;
+
+    unsafe {
+        // CCxDE is bit 9, for channel 1.
+        let dier = base.add(TIM_DIER / 4);
+        dier.write_volatile(dier.read_volatile() | (1 << (9 + ch)));
+
+        Endpoint::register(base.add(TIM_CCR1 / 4 + ch) as u32, request)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// Which endpoint's DMA request paces the route.
+pub enum Pacing {
+    /// Transfer when the source has data; eg an ADC conversion completes, or a byte is received.
+    Source,
+    /// Transfer when the destination can accept data; eg a transmit register is empty.
+    Destination,
+}
+
+/// A single DMA channel, moving words from `src` to `dst`.
+pub struct Route<S, D> {
+    pub src: Endpoint<S>,
+    pub dst: Endpoint<D>,
+    /// Which endpoint's request paces the transfer.
+    pub pacing: Pacing,
+    /// The number of words to transfer; for circular routes, per cycle.
+    pub len: usize,
+    pub circular: Circular,
+    pub priority: Priority,
+    dma_periph: DmaPeriph,
+    channel: DmaChannel,
+}
+
+impl<S: Word, D: Word> Route<S, D> {
+    /// Create a route. The length is that of the shorter buffer, or 1 word if both endpoints are
+    /// registers. Routes between two registers are circular; others aren't. Priority is medium.
+    /// Adjust the public fields to change these before starting.
+    ///
+    /// Note that on H7, channels run in direct mode, so words are moved at the paced side's size.
+    /// Use the same word size at both ends there.
+    pub fn new(src: Endpoint<S>, dst: Endpoint<D>, pacing: Pacing) -> Self {
+        let (len, circular) = match (src.len, dst.len) {
+            (Some(a), Some(b)) => (a.min(b), Circular::Disabled),
+            (Some(a), None) | (None, Some(a)) => (a, Circular::Disabled),
+            (None, None) => (1, Circular::Enabled),
+        };
+
+        Self {
+            src,
+            dst,
+            pacing,
+            len,
+            circular,
+            priority: Priority::Medium,
+            dma_periph: DmaPeriph::Dma1,
+            channel: DmaChannel::C1,
+        }
+    }
+
+    /// Route the pacing request to a DMA channel, and start it. Returns the channel used, which is
+    /// fixed by the request on F3 and L4.
+    ///
+    /// # Safety
+    /// Buffer endpoints must remain valid while the transfer runs.
+    #[cfg_attr(any(feature = "f3", feature = "l4"), allow(unused_variables))]
+    pub unsafe fn start(
+        &mut self,
+        dma_periph: DmaPeriph,
+        channel: DmaChannel,
+    ) -> Result<DmaChannel, RouteError> {
+        let request = match self.pacing {
+            Pacing::Source => self.src.request,
+            Pacing::Destination => self.dst.request,
+        }
+        .ok_or(RouteError::NoRequest)?;
+
+        // F3 and L4 channels are hard-wired to their requests. L4 also needs the channel select set.
+        #[cfg(any(feature = "f3", feature = "l4"))]
+        let channel = request.dma1_channel();
+
+        #[cfg(feature = "l4")]
+        match dma_periph {
+            DmaPeriph::Dma1 => dma::channel_select(&mut &(*DMA1::ptr()), request),
+            DmaPeriph::Dma2 => dma::channel_select(&mut &(*crate::pac::DMA2::ptr()), request),
+        }
+
+        #[cfg(not(any(feature = "f3", feature = "l4")))]
+        dma::mux(dma_periph, channel, request);
+
+        // DMA reads from the peripheral side, and writes to the memory side, when the direction is
+        // `ReadFromPeriph`. Put the pacing endpoint on the peripheral side.
+        let (direction, periph, mem, periph_size, mem_size) = match self.pacing {
+            Pacing::Source => (
+                Direction::ReadFromPeriph,
+                (self.src.addr, self.src.incr),
+                (self.dst.addr, self.dst.incr),
+                S::SIZE,
+                D::SIZE,
+            ),
+            Pacing::Destination => (
+                Direction::ReadFromMem,
+                (self.dst.addr, self.dst.incr),
+                (self.src.addr, self.src.incr),
+                D::SIZE,
+                S::SIZE,
+            ),
+        };
+
+        let channel_cfg = ChannelCfg {
+            priority: self.priority,
+            circular: self.circular,
+            periph_incr: periph.1,
+            mem_incr: mem.1,
+        };
+
+        #[cfg(feature = "h7")]
+        let num_data = self.len as u32;
+        #[cfg(not(feature = "h7"))]
+        let num_data = self.len as u16;
+
+        match dma_periph {
+            DmaPeriph::Dma1 => {
+                let mut regs = &(*DMA1::ptr());
+                dma::cfg_channel(
+                    &mut regs,
+                    channel,
+                    periph.0,
+                    mem.0,
+                    num_data,
+                    direction,
+                    periph_size,
+                    mem_size,
+                    channel_cfg,
+                );
+            }
+            #[cfg(not(any(
+                feature = "f3x4",
+                all(feature = "g0", not(any(feature = "g0b1", feature = "g0c1"))),
+                feature = "wb",
+            )))]
+            DmaPeriph::Dma2 => {
+                let mut regs = &(*crate::pac::DMA2::ptr());
+                dma::cfg_channel(
+                    &mut regs,
+                    channel,
+                    periph.0,
+                    mem.0,
+                    num_data,
+                    direction,
+                    periph_size,
+                    mem_size,
+                    channel_cfg,
+                );
+            }
+        }
+
+        self.dma_periph = dma_periph;
+        self.channel = channel;
+
+        Ok(channel)
+    }
+
+    /// Stop the route's DMA channel.
+    pub fn stop(&mut self) {
+        dma::stop(self.dma_periph, self.channel);
+    }
+
+    /// The DMA channel used by the most recent `start()`.
+    pub fn channel(&self) -> DmaChannel {
+        self.channel
+    }
+}
+
+impl Route<u16, u16> {
+    /// ADC to DAC passthrough: Each conversion is written to the DAC's holding register, with no CPU
+    /// involvement. Set the ADC to convert continuously, or on a trigger, and the DAC to update on
+    /// the same trigger if its output timing matters. High priority, so a conversion isn't missed.
+    pub fn adc_to_dac(adc: Endpoint<u16>, dac: Endpoint<u16>) -> Self {
+        let mut result = Self::new(adc, dac, Pacing::Source);
+        result.priority = Priority::High;
+        result
+    }
+}
+
+impl Route<u8, u8> {
+    /// UART to UART bridge: Each byte received on one is written to the other's transmit register.
+    /// The transmitting UART's baud rate must be at least that of the receiving one. Set up one
+    /// route per direction for a bidirectional bridge. High priority, to prevent overruns.
+    pub fn uart_bridge(rx: Endpoint<u8>, tx: Endpoint<u8>) -> Self {
+        let mut result = Self::new(rx, tx, Pacing::Source);
+        result.priority = Priority::High;
+        result
+    }
+}
+
+/// Two routes through a memory buffer: The first fills it, and the second empties it, once the
+/// first completes. The buffer's word type links the stages.
+pub struct Pipeline<A, B, C> {
+    pub first: Route<A, B>,
+    pub second: Route<B, C>,
+    dma_periph: DmaPeriph,
+    second_channel: DmaChannel,
+}
+
+impl<A: Word, B: Word, C: Word> Pipeline<A, B, C> {
+    pub fn new(first: Route<A, B>, second: Route<B, C>) -> Self {
+        Self {
+            first,
+            second,
+            dma_periph: DmaPeriph::Dma1,
+            second_channel: DmaChannel::C1,
+        }
+    }
+
+    /// Start the first stage. Unmask the first channel's interrupt line, and run `service()` from
+    /// its handler. Returns the first stage's channel. `channels` is unused on F3 and L4, where the
+    /// requests set the channels.
+    ///
+    /// # Safety
+    /// The buffer must remain valid while the pipeline runs.
+    pub unsafe fn start(
+        &mut self,
+        dma_periph: DmaPeriph,
+        channels: (DmaChannel, DmaChannel),
+    ) -> Result<DmaChannel, RouteError> {
+        self.dma_periph = dma_periph;
+        self.second_channel = channels.1;
+
+        self.first.start(dma_periph, channels.0)
+    }
+
+    /// Start the second stage, once the first has filled the buffer. Run this from the first
+    /// channel's interrupt handler; this clears its transfer complete flag. Run `start()` again to
+    /// collect the next buffer's worth, after the second stage completes.
+    pub fn service(&mut self) -> Result<DmaChannel, RouteError> {
+        dma::clear_interrupt(
+            self.dma_periph,
+            self.first.channel(),
+            DmaInterrupt::TransferComplete,
+        );
+        self.first.stop();
+
+        unsafe { self.second.start(self.dma_periph, self.second_channel) }
+    }
+
+    /// Stop both stages.
+    pub fn stop(&mut self) {
+        self.first.stop();
+        self.second.stop();
+    }
+}
+
+impl<W: Word> Pipeline<u16, u16, W> {
+    /// Timer captures to memory, then to SPI: Collects `buf.len()` captures, then sends them over
+    /// SPI. Use a `u16` SPI endpoint for 16-bit frames, or `u8` to send the low byte of each.
+    pub fn capture_to_spi(capture: Endpoint<u16>, buf: &mut [u16], spi: Endpoint<W>) -> Self {
+        let buf = Endpoint::buffer(buf);
+
+        let mut first = Route::new(capture, buf, Pacing::Source);
+        first.priority = Priority::High;
+
+        Self::new(first, Route::new(buf, spi, Pacing::Destination))
+    }
+}
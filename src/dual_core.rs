@@ -0,0 +1,182 @@
+//! Dual-core support for STM32H745, H747, H755, and H757, which have a Cortex-M7 (CPU1) and a
+//! Cortex-M4 (CPU2) core. Build firmware for each core separately, using the `h747cm7` and
+//! `h747cm4` features. This module handles holding one core until the other has configured
+//! clocks and shared peripherals, and allocating peripherals to a core. Use the `hsem` module to
+//! synchronize access to anything shared afterwards.
+//!
+//! Peripheral allocation matters for low power modes: A domain only enters Stop when neither core
+//! has peripherals in it allocated. The `rcc_en_reset` style enabling used by the rest of this HAL
+//! enables peripherals through the common RCC registers, which allocate them to the core that
+//! performs the write.
+//!
+//! Example, on the M7 core, with the M4 core booting at the same time as the M7 (the default option
+//! bytes):
+//! ```rust
+//! let mut hsem = Hsem::new(dp.HSEM);
+//!
+//! // Wait for the M4 to enter Stop mode, in `wait_for_release`.
+//! dual_core::wait_for_cm4_stop().unwrap();
+//!
+//! clock_cfg.setup().unwrap();
+//! // ... Set up shared peripherals
+//!
+//! dual_core::release_core(&mut hsem, BOOT_SEM);
+//! ```
+//!
+//! And on the M4 core:
+//! ```rust
+//! let mut hsem = Hsem::new(dp.HSEM);
+//! dual_core::wait_for_release(&mut hsem, BOOT_SEM);
+//! ```
+
+use cortex_m::{asm, interrupt::InterruptNumber, peripheral::NVIC};
+
+use crate::{
+    hsem::{Core, Hsem},
+    pac::RCC,
+    MAX_ITERS,
+};
+
+// RCC per-core enable registers; CPU1's start at 0x134, and CPU2's at 0x194.
+const C1_ENR_OFFSET: usize = 0x134;
+const C2_ENR_OFFSET: usize = 0x194;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Dual-core errors.
+pub enum DualCoreError {
+    /// Timed out waiting for the other core.
+    Timeout,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+/// A peripheral bus, for per-core peripheral allocation. The value is the offset of its
+/// RCC_Cx_xxxENR register, from the first.
+pub enum Bus {
+    Ahb3 = 0x00,
+    Ahb1 = 0x04,
+    Ahb2 = 0x08,
+    Ahb4 = 0x0c,
+    Apb3 = 0x10,
+    Apb1L = 0x14,
+    Apb1H = 0x18,
+    Apb2 = 0x1c,
+    Apb4 = 0x20,
+}
+
+/// Force the other core to boot, regardless of the BCM4 or BCM7 option bytes. Sets RCC_GCR,
+/// BOOT_C2 (from the M7) or BOOT_C1 (from the M4).
+pub fn boot_other_core() {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    #[cfg(feature = "h747cm7")]
+    rcc.gcr.modify(|_, w| w.boot_c2().set_bit());
+    #[cfg(feature = "h747cm4")]
+    rcc.gcr.modify(|_, w| w.boot_c1().set_bit());
+}
+
+/// Run on the M7: Wait until the M4 is held in Stop mode, ie by `wait_for_release`, indicated by
+/// the D2 domain clock stopping. Required before changing the clock configuration.
+pub fn wait_for_cm4_stop() -> Result<(), DualCoreError> {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    let mut i = 0;
+    while rcc.cr.read().d2ckrdy().bit_is_set() {
+        i += 1;
+        if i >= MAX_ITERS {
+            return Err(DualCoreError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Release the other core from `wait_for_release`, by locking and unlocking a semaphore. The
+/// other core must have started waiting first.
+pub fn release_core(hsem: &mut Hsem, semaphore_num: u8) {
+    let core = Core::current();
+    // The lock can only fail if the other core holds this semaphore, which it shouldn't.
+    while hsem.lock_1_step(core, semaphore_num).is_err() {}
+    hsem.unlock(core, semaphore_num, 0);
+}
+
+/// Hold this core in Stop mode until the other core calls `release_core` with the same semaphore.
+/// The lower-power domain of this core (eg D2 for the M4) stops while waiting, as long as
+/// PWR_CPUxCR is at its reset configuration.
+pub fn wait_for_release(hsem: &mut Hsem, semaphore_num: u8) {
+    let core = Core::current();
+    let mut scb = unsafe { cortex_m::Peripherals::steal().SCB };
+
+    hsem.clear_interrupt(core, semaphore_num);
+    hsem.enable_interrupt(core, semaphore_num);
+
+    // Wake up from WFE on the semaphore interrupt, without needing it enabled in the NVIC.
+    unsafe { scb.scr.modify(|r| r | SCB_SCR_SEVONPEND) };
+    scb.set_sleepdeep();
+
+    while !hsem.interrupt_flag(core, semaphore_num, true) {
+        asm::wfe();
+    }
+
+    scb.clear_sleepdeep();
+    unsafe { scb.scr.modify(|r| r & !SCB_SCR_SEVONPEND) };
+
+    hsem.disable_interrupt(core, semaphore_num);
+    hsem.clear_interrupt(core, semaphore_num);
+    // The wakeup leaves the interrupt pending in the NVIC; clear it so that unmasking it later
+    // doesn't fire a spurious interrupt.
+    NVIC::unpend(HsemIrq);
+}
+
+/// This core's HSEM interrupt: HSEM1 (125) for CPU1, and HSEM2 (126) for CPU2. We use the number
+/// directly, since the CM4 PAC doesn't include HSEM2.
+#[derive(Clone, Copy)]
+struct HsemIrq;
+
+unsafe impl InterruptNumber for HsemIrq {
+    fn number(self) -> u16 {
+        if cfg!(feature = "h747cm4") {
+            126
+        } else {
+            125
+        }
+    }
+}
+
+/// SCB_SCR, SEVONPEND bit: Pending interrupts, including disabled ones, wake the core from WFE.
+const SCB_SCR_SEVONPEND: u32 = 1 << 4;
+
+/// Allocate a peripheral to a core, or deallocate it, using the RCC_C1 or RCC_C2 enable registers.
+/// `bit` is the peripheral's enable bit in that bus's register; it's the same as in the common
+/// enable registers. (eg 4 for USART2 on `Apb1L`.) A peripheral is clocked as long as either core
+/// has it allocated.
+pub fn allocate(core: Core, bus: Bus, bit: u8, allocated: bool) {
+    let offset = match core {
+        Core::C1 => C1_ENR_OFFSET,
+        Core::C2 => C2_ENR_OFFSET,
+    } + bus as usize;
+
+    unsafe {
+        let reg = (RCC::ptr() as *mut u8).add(offset) as *mut u32;
+        let val = core::ptr::read_volatile(reg);
+
+        let val = if allocated {
+            val | (1 << bit)
+        } else {
+            val & !(1 << bit)
+        };
+        core::ptr::write_volatile(reg, val);
+    }
+}
+
+/// Check if a peripheral is allocated to a core.
+pub fn is_allocated(core: Core, bus: Bus, bit: u8) -> bool {
+    let offset = match core {
+        Core::C1 => C1_ENR_OFFSET,
+        Core::C2 => C2_ENR_OFFSET,
+    } + bus as usize;
+
+    let val =
+        unsafe { core::ptr::read_volatile((RCC::ptr() as *const u8).add(offset) as *const u32) };
+    val & (1 << bit) != 0
+}
@@ -19,6 +19,8 @@ use crate::{
 };
 
 /// Configuration data for Ethernet
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct EthConfig {}
 
 // impl Default for EthConfig {
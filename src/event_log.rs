@@ -0,0 +1,163 @@
+//! A small ring log of timestamped event codes, stored in the RTC backup registers. (TAMP
+//! backup registers on G0, G4, L5, and WL). The backup domain is retained in Standby mode and across
+//! resets, so this can be used to record events (errors, state transitions etc) that are drained,
+//! decoded, and reported after the next boot, eg for field diagnostics.
+//!
+//! The log uses a contiguous block of backup registers: One for a header containing a validity marker,
+//! the write position, and entry count, followed by one register per entry. You may use the remaining
+//! backup registers for other purposes.
+//!
+//! `push()` runs in a critical section, so it can be called from interrupt handlers.
+//!
+//! Example:
+//! ```rust
+//! static LOG: EventLog = EventLog::new(0, 8);
+//!
+//! // At boot, after setting up the RTC (Which enables backup domain access):
+//! LOG.init();
+//! LOG.drain(|entry| defmt::println!("Event {} at {}", entry.code, entry.timestamp));
+//!
+//! // Later, eg in an interrupt handler:
+//! LOG.push(EVENT_OVERCURRENT, uptime_s);
+//! ```
+
+use cortex_m::interrupt;
+
+use crate::rtc::{backup_read, backup_reg_count, backup_write};
+
+/// Stored in the upper 16 bits of the header register, to identify a valid log after power loss
+/// of the backup domain, or on first use.
+const MARKER: u32 = 0xe10c;
+
+/// Entries store 24 bits of timestamp.
+const TIMESTAMP_MASK: u32 = 0x00ff_ffff;
+
+/// A single log entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogEntry {
+    /// An application-defined event code.
+    pub code: u8,
+    /// An application-defined timestamp, eg seconds since boot, or from the RTC. Only the lower 24 bits
+    /// are retained.
+    pub timestamp: u32,
+}
+
+impl LogEntry {
+    fn to_bits(self) -> u32 {
+        ((self.code as u32) << 24) | (self.timestamp & TIMESTAMP_MASK)
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        Self {
+            code: (bits >> 24) as u8,
+            timestamp: bits & TIMESTAMP_MASK,
+        }
+    }
+}
+
+/// A ring log in backup registers. This struct doesn't hold state; all state is stored in the
+/// backup registers, so it may be placed in a `static`.
+pub struct EventLog {
+    /// The backup register holding the header. Entries use the registers directly following it.
+    first_reg: usize,
+    /// Maximum number of entries stored. When full, the oldest entry is overwritten.
+    capacity: usize,
+}
+
+impl EventLog {
+    /// Create a log using backup registers `first_reg` through `first_reg + capacity`, inclusive.
+    /// `capacity` is limited to 255 entries, and by the number of backup registers on your MCU.
+    pub const fn new(first_reg: usize, capacity: usize) -> Self {
+        assert!(capacity > 0 && capacity <= 255);
+        Self {
+            first_reg,
+            capacity,
+        }
+    }
+
+    /// Validate the log header, clearing the log if it's not valid, eg after the backup domain
+    /// lost power. Run this at boot, after the RTC is set up.
+    pub fn init(&self) {
+        if self.first_reg + self.capacity >= backup_reg_count() {
+            panic!("Event log exceeds the number of backup registers.");
+        }
+
+        let header = backup_read(self.first_reg);
+        let (head, count) = header_fields(header);
+
+        if header >> 16 != MARKER || head >= self.capacity || count > self.capacity {
+            self.clear();
+        }
+    }
+
+    /// Append an entry, overwriting the oldest one if the log is full. This may be called
+    /// from interrupt handlers.
+    pub fn push(&self, code: u8, timestamp: u32) {
+        let entry = LogEntry { code, timestamp };
+
+        interrupt::free(|_| {
+            let (head, count) = header_fields(backup_read(self.first_reg));
+
+            backup_write(self.first_reg + 1 + head, entry.to_bits());
+
+            let head = (head + 1) % self.capacity;
+            let count = (count + 1).min(self.capacity);
+            self.write_header(head, count);
+        });
+    }
+
+    /// Remove and return the oldest entry, if available.
+    pub fn pop(&self) -> Option<LogEntry> {
+        interrupt::free(|_| {
+            let (head, count) = header_fields(backup_read(self.first_reg));
+            if count == 0 {
+                return None;
+            }
+
+            let oldest = (head + self.capacity - count) % self.capacity;
+            let entry = LogEntry::from_bits(backup_read(self.first_reg + 1 + oldest));
+
+            self.write_header(head, count - 1);
+            Some(entry)
+        })
+    }
+
+    /// Remove all entries, calling `f` on each, from oldest to newest.
+    pub fn drain<F: FnMut(LogEntry)>(&self, mut f: F) {
+        while let Some(entry) = self.pop() {
+            f(entry);
+        }
+    }
+
+    /// The number of entries currently stored.
+    pub fn len(&self) -> usize {
+        header_fields(backup_read(self.first_reg)).1
+    }
+
+    /// Returns true if no entries are stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all entries.
+    pub fn clear(&self) {
+        interrupt::free(|_| {
+            for i in 0..self.capacity {
+                backup_write(self.first_reg + 1 + i, 0);
+            }
+            self.write_header(0, 0);
+        });
+    }
+
+    fn write_header(&self, head: usize, count: usize) {
+        backup_write(
+            self.first_reg,
+            (MARKER << 16) | ((head as u32) << 8) | count as u32,
+        );
+    }
+}
+
+/// Returns (head, count) from a header register value.
+fn header_fields(header: u32) -> (usize, usize) {
+    (((header >> 8) & 0xff) as usize, (header & 0xff) as usize)
+}
@@ -0,0 +1,258 @@
+//! Configure EXTI (Extended interrupts and events controller) lines: Trigger edges, masking, GPIO
+//! port selection, NVIC enabling, and pending flags. This abstracts over the EXTI register layouts,
+//! which vary across families: F3, F4, L4, and G4 have a single pending register per bank, and select
+//! GPIO ports in SYSCFG. L5, G0, and H5 have separate rising and falling edge pending registers, and
+//! select GPIO ports in EXTI. WB, WL, and H7 have per-core mask and pending registers; we use the
+//! ones for the core this is compiled for.
+//!
+//! GPIO pins usually configure this with `Pin::enable_interrupt()`. Use this module directly for
+//! internal lines, eg the PVD, or COMP outputs. Lines 0 - 63 are supported. (Direct lines don't
+//! have trigger selection, or pending flags.)
+//!
+//! Example:
+//! ```rust
+//! let mut button = Pin::new(Port::C, 13, PinMode::Input);
+//! button.enable_interrupt(Edge::Falling);
+//!
+//! #[interrupt]
+//! fn EXTI15_10() {
+//!     if exti::is_pending(13) {
+//!         exti::clear_pending(13);
+//!     }
+//! }
+//! ```
+
+use core::ptr::{read_volatile, write_volatile};
+
+use cfg_if::cfg_if;
+use cortex_m::peripheral::NVIC;
+
+#[cfg(not(any(feature = "l5", feature = "g0", feature = "h5")))]
+use crate::pac::SYSCFG;
+use crate::{
+    gpio::{Edge, Port},
+    pac::{self, EXTI},
+};
+
+// Register offsets, for lines 0 - 31, and 32 - 63. We use offsets, since PAC field names vary
+// between variants. Lines 32 and up are direct lines on G0, except on G0B1 and G0C1.
+cfg_if! {
+    if #[cfg(any(feature = "f373", feature = "f4"))] {
+        pub(crate) const EXTI_IMR: &[usize] = &[0x00];
+        const EXTI_RTSR: &[usize] = &[0x08];
+        const EXTI_FTSR: &[usize] = &[0x0c];
+        pub(crate) const EXTI_PR: &[usize] = &[0x14];
+        pub(crate) const EXTI_FPR: &[usize] = &[];
+    } else if #[cfg(any(feature = "f3", feature = "l4", feature = "g4"))] {
+        pub(crate) const EXTI_IMR: &[usize] = &[0x00, 0x20];
+        const EXTI_RTSR: &[usize] = &[0x08, 0x28];
+        const EXTI_FTSR: &[usize] = &[0x0c, 0x2c];
+        pub(crate) const EXTI_PR: &[usize] = &[0x14, 0x34];
+        pub(crate) const EXTI_FPR: &[usize] = &[];
+    } else if #[cfg(any(feature = "wb", feature = "wl"))] {
+        pub(crate) const EXTI_IMR: &[usize] = &[0x80, 0x90];
+        const EXTI_RTSR: &[usize] = &[0x00, 0x20];
+        const EXTI_FTSR: &[usize] = &[0x04, 0x24];
+        pub(crate) const EXTI_PR: &[usize] = &[0x0c, 0x2c];
+        pub(crate) const EXTI_FPR: &[usize] = &[];
+    } else if #[cfg(any(feature = "l5", feature = "h5"))] {
+        // Separate rising and falling edge pending registers.
+        pub(crate) const EXTI_IMR: &[usize] = &[0x80, 0x90];
+        const EXTI_RTSR: &[usize] = &[0x00, 0x20];
+        const EXTI_FTSR: &[usize] = &[0x04, 0x24];
+        pub(crate) const EXTI_PR: &[usize] = &[0x0c, 0x2c];
+        pub(crate) const EXTI_FPR: &[usize] = &[0x10, 0x30];
+    } else if #[cfg(any(feature = "g0b1", feature = "g0c1"))] {
+        pub(crate) const EXTI_IMR: &[usize] = &[0x80, 0x90];
+        const EXTI_RTSR: &[usize] = &[0x00, 0x28];
+        const EXTI_FTSR: &[usize] = &[0x04, 0x2c];
+        pub(crate) const EXTI_PR: &[usize] = &[0x0c, 0x34];
+        pub(crate) const EXTI_FPR: &[usize] = &[0x10, 0x38];
+    } else if #[cfg(feature = "g0")] {
+        pub(crate) const EXTI_IMR: &[usize] = &[0x80, 0x90];
+        const EXTI_RTSR: &[usize] = &[0x00];
+        const EXTI_FTSR: &[usize] = &[0x04];
+        pub(crate) const EXTI_PR: &[usize] = &[0x0c];
+        pub(crate) const EXTI_FPR: &[usize] = &[0x10];
+    } else if #[cfg(feature = "h747cm4")] {
+        // CPU2 registers.
+        pub(crate) const EXTI_IMR: &[usize] = &[0xc0, 0xd0];
+        const EXTI_RTSR: &[usize] = &[0x00, 0x20];
+        const EXTI_FTSR: &[usize] = &[0x04, 0x24];
+        pub(crate) const EXTI_PR: &[usize] = &[0xc8, 0xd8];
+        pub(crate) const EXTI_FPR: &[usize] = &[];
+    } else { // H7
+        pub(crate) const EXTI_IMR: &[usize] = &[0x80, 0x90];
+        const EXTI_RTSR: &[usize] = &[0x00, 0x20];
+        const EXTI_FTSR: &[usize] = &[0x04, 0x24];
+        pub(crate) const EXTI_PR: &[usize] = &[0x88, 0x98];
+        pub(crate) const EXTI_FPR: &[usize] = &[];
+    }
+}
+
+// GPIO port selection: 4 registers, each covering 4 lines. Fields are 8 bits wide in EXTI_EXTICRx,
+// and 4 bits wide in SYSCFG_EXTICRx.
+cfg_if! {
+    if #[cfg(any(feature = "l5", feature = "g0", feature = "h5"))] {
+        const EXTICR: usize = 0x60;
+        const EXTICR_FIELD_WIDTH: u8 = 8;
+    } else {
+        const EXTICR: usize = 0x08;
+        const EXTICR_FIELD_WIDTH: u8 = 4;
+    }
+}
+
+fn reg(base: *const u8, offset: usize) -> *mut u32 {
+    unsafe { base.add(offset) as *mut u32 }
+}
+
+fn exti() -> *const u8 {
+    EXTI::ptr() as *const u8
+}
+
+/// Set or clear a line's bit, in one of a set of per-bank registers.
+fn set_line_bit(offsets: &[usize], line: u8, value: bool) {
+    if let Some(offset) = offsets.get(line as usize / 32) {
+        let r = reg(exti(), *offset);
+        let bit = 1 << (line % 32);
+
+        unsafe {
+            let val = read_volatile(r);
+            write_volatile(r, if value { val | bit } else { val & !bit });
+        }
+    }
+}
+
+/// Select the edges a line triggers on. Sets EXTI_RTSR and EXTI_FTSR registers.
+pub fn set_edge(line: u8, edge: Edge) {
+    let (rising, falling) = match edge {
+        Edge::Rising => (true, false),
+        Edge::Falling => (false, true),
+        Edge::Either => (true, true),
+    };
+
+    set_line_bit(EXTI_RTSR, line, rising);
+    set_line_bit(EXTI_FTSR, line, falling);
+}
+
+/// Select a line's trigger edges, and unmask it as an interrupt. This doesn't unmask it in the
+/// NVIC. Sets EXTI_RTSR, EXTI_FTSR, and EXTI_IMR registers.
+pub fn enable(line: u8, edge: Edge) {
+    set_edge(line, edge);
+    set_line_bit(EXTI_IMR, line, true);
+}
+
+/// Mask a line, and disable its triggers.
+pub fn disable(line: u8) {
+    set_line_bit(EXTI_IMR, line, false);
+    set_line_bit(EXTI_RTSR, line, false);
+    set_line_bit(EXTI_FTSR, line, false);
+}
+
+/// Select which GPIO port drives a line from 0 - 15. Sets SYSCFG_EXTICRx register (EXTI_EXTICRx
+/// on L5, G0, and H5).
+pub fn select_port(line: u8, port: Port) {
+    assert!(line <= 15, "GPIO EXTI lines must be 0 - 15.");
+
+    cfg_if! {
+        if #[cfg(any(feature = "l5", feature = "g0", feature = "h5"))] {
+            let base = exti();
+        } else {
+            let base = SYSCFG::ptr() as *const u8;
+        }
+    }
+
+    let r = reg(base, EXTICR + (line as usize / 4) * 4);
+    let shift = (line % 4) * EXTICR_FIELD_WIDTH;
+    let mask = ((1 << EXTICR_FIELD_WIDTH) - 1) << shift;
+
+    unsafe {
+        let val = read_volatile(r);
+        write_volatile(r, (val & !mask) | ((port.cr_val() as u32) << shift));
+    }
+}
+
+/// Check if a line's pending flag is set. On L5, G0, and H5, this checks both the rising and
+/// falling edge flags.
+pub fn is_pending(line: u8) -> bool {
+    let i = line as usize / 32;
+    let bit = 1 << (line % 32);
+
+    EXTI_PR
+        .get(i)
+        .into_iter()
+        .chain(EXTI_FPR.get(i))
+        .any(|offset| unsafe { read_volatile(reg(exti(), *offset)) } & bit != 0)
+}
+
+/// Clear a line's pending flag, eg in its interrupt handler, to prevent it from continuously
+/// firing. Sets EXTI_PR register (EXTI_RPR and EXTI_FPR on L5, G0, and H5).
+pub fn clear_pending(line: u8) {
+    let i = line as usize / 32;
+    let bit = 1 << (line % 32);
+
+    // These registers are write 1 to clear.
+    for offset in EXTI_PR.get(i).into_iter().chain(EXTI_FPR.get(i)) {
+        unsafe { write_volatile(reg(exti(), *offset), bit) };
+    }
+}
+
+/// The NVIC interrupt that handles a GPIO EXTI line, from 0 - 15. On most families, lines 5 - 9,
+/// and 10 - 15 share interrupts; check which lines are pending in their handlers.
+pub fn gpio_interrupt(line: u8) -> pac::Interrupt {
+    cfg_if! {
+        if #[cfg(feature = "g0")] {
+            match line {
+                0 | 1 => pac::Interrupt::EXTI0_1,
+                2 | 3 => pac::Interrupt::EXTI2_3,
+                4..=15 => pac::Interrupt::EXTI4_15,
+                _ => panic!("GPIO EXTI lines must be 0 - 15."),
+            }
+        } else if #[cfg(any(feature = "l5", feature = "h5"))] {
+            match line {
+                0 => pac::Interrupt::EXTI0,
+                1 => pac::Interrupt::EXTI1,
+                2 => pac::Interrupt::EXTI2,
+                3 => pac::Interrupt::EXTI3,
+                4 => pac::Interrupt::EXTI4,
+                5 => pac::Interrupt::EXTI5,
+                6 => pac::Interrupt::EXTI6,
+                7 => pac::Interrupt::EXTI7,
+                8 => pac::Interrupt::EXTI8,
+                9 => pac::Interrupt::EXTI9,
+                10 => pac::Interrupt::EXTI10,
+                11 => pac::Interrupt::EXTI11,
+                12 => pac::Interrupt::EXTI12,
+                13 => pac::Interrupt::EXTI13,
+                14 => pac::Interrupt::EXTI14,
+                15 => pac::Interrupt::EXTI15,
+                _ => panic!("GPIO EXTI lines must be 0 - 15."),
+            }
+        } else {
+            match line {
+                0 => pac::Interrupt::EXTI0,
+                1 => pac::Interrupt::EXTI1,
+                #[cfg(feature = "f373")]
+                2 => pac::Interrupt::EXTI2_TS,
+                #[cfg(all(feature = "f3", not(feature = "f373")))]
+                2 => pac::Interrupt::EXTI2_TSC,
+                #[cfg(not(feature = "f3"))]
+                2 => pac::Interrupt::EXTI2,
+                3 => pac::Interrupt::EXTI3,
+                4 => pac::Interrupt::EXTI4,
+                #[cfg(feature = "f373")]
+                5..=9 => pac::Interrupt::EXTI5_9,
+                #[cfg(not(feature = "f373"))]
+                5..=9 => pac::Interrupt::EXTI9_5,
+                10..=15 => pac::Interrupt::EXTI15_10,
+                _ => panic!("GPIO EXTI lines must be 0 - 15."),
+            }
+        }
+    }
+}
+
+/// Unmask the NVIC interrupt that handles a GPIO EXTI line. Like `NVIC::unmask()`, this can break
+/// mask-based critical sections; don't run it inside one.
+pub fn unmask_gpio_interrupt(line: u8) {
+    unsafe { NVIC::unmask(gpio_interrupt(line)) };
+}
@@ -0,0 +1,473 @@
+//! EEPROM emulation: Wear-leveled key-value storage on two pages of internal flash, eg for
+//! configuration data and calibration values.
+//!
+//! Records are appended to the active page, so updating a value doesn't erase anything. When the
+//! active page is full, the latest value of each key is copied to the other page, which then becomes
+//! active, and the old page is erased. Each page is erased once per fill, instead of once per write.
+//!
+//! Each page starts with a header, containing a sequence number. It's written after compaction
+//! completes, so an interrupted compaction (eg from a power loss) leaves the old page in use. Each
+//! record contains a checksum, so a record interrupted while writing is discarded. Flash units are
+//! only written once between erases, as required by families with flash ECC.
+//!
+//! Example:
+//! ```rust
+//! let flash = Flash::new(dp.FLASH);
+//! // Use the last two pages of a 512Kb G4 in dual-bank mode.
+//! let mut eeprom = Eeprom::new(flash, [(Bank::B2, 126), (Bank::B2, 127)]).unwrap();
+//!
+//! const KEY_CAL_OFFSET: u16 = 1;
+//!
+//! eeprom.put(KEY_CAL_OFFSET, &1.23_f32).unwrap();
+//! let offset: Option<f32> = eeprom.get(KEY_CAL_OFFSET).unwrap();
+//! ```
+//!
+//! The pages must not contain program memory. On F4 and H7, these are sectors; eg with 128Kb
+//! sectors, compaction is rare, but each erase takes longer.
+
+use super::{Bank, Error, Flash, WRITE_SIZE};
+
+/// The maximum record value size, in bytes.
+pub const MAX_VALUE_SIZE: usize = 64;
+
+/// Page header, and record header size, before padding to `WRITE_SIZE`.
+const HEADER_LEN: usize = 8;
+const PAGE_HEADER_SIZE: usize = HEADER_LEN.div_ceil(WRITE_SIZE) * WRITE_SIZE;
+const MAX_RECORD_SIZE: usize = (HEADER_LEN + MAX_VALUE_SIZE).div_ceil(WRITE_SIZE) * WRITE_SIZE;
+
+/// Identifies a valid page. "EEPR".
+const PAGE_MAGIC: u32 = 0x5250_4545;
+
+/// Erased keys mark the end of the record log.
+const KEY_ERASED: u16 = 0xffff;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Possible error states for EEPROM emulation.
+pub enum EepromError {
+    /// An error from the flash driver.
+    Flash(Error),
+    /// There's no space left, even after compacting.
+    Full,
+    /// Key `0xFFFF` is reserved.
+    InvalidKey,
+    /// The value is larger than `MAX_VALUE_SIZE`, or the buffer passed to `get_bytes` is too
+    /// small for the stored value.
+    Size,
+}
+
+impl From<Error> for EepromError {
+    fn from(e: Error) -> Self {
+        Self::Flash(e)
+    }
+}
+
+/// A value that can be stored as a record. Implemented for integers, floats, `bool`, and byte arrays.
+/// `SIZE` must be at most `MAX_VALUE_SIZE`.
+pub trait Value: Sized {
+    const SIZE: usize;
+    /// Serialize into `buf`, which is `SIZE` bytes long.
+    fn to_bytes(&self, buf: &mut [u8]);
+    /// Deserialize from `buf`, which is `SIZE` bytes long.
+    fn from_bytes(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_value {
+    ($($t:ty),+) => {
+        $(
+            impl Value for $t {
+                const SIZE: usize = core::mem::size_of::<$t>();
+
+                fn to_bytes(&self, buf: &mut [u8]) {
+                    buf.copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn from_bytes(buf: &[u8]) -> Self {
+                    Self::from_le_bytes(buf.try_into().unwrap())
+                }
+            }
+        )+
+    };
+}
+
+impl_value!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Value for bool {
+    const SIZE: usize = 1;
+
+    fn to_bytes(&self, buf: &mut [u8]) {
+        buf[0] = *self as u8;
+    }
+
+    fn from_bytes(buf: &[u8]) -> Self {
+        buf[0] != 0
+    }
+}
+
+impl<const N: usize> Value for [u8; N] {
+    const SIZE: usize = N;
+
+    fn to_bytes(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(self);
+    }
+
+    fn from_bytes(buf: &[u8]) -> Self {
+        buf.try_into().unwrap()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Page {
+    bank: Bank,
+    number: usize,
+    address: usize,
+    size: usize,
+}
+
+/// A record header, read from flash.
+enum Slot {
+    /// A record with a valid checksum.
+    Valid { key: u16, len: usize },
+    /// Erased flash; the end of the log.
+    Erased,
+    /// An interrupted write.
+    Corrupt,
+}
+
+/// The size of a record in flash, including its header, and padding.
+fn record_size(len: usize) -> usize {
+    (HEADER_LEN + len).div_ceil(WRITE_SIZE) * WRITE_SIZE
+}
+
+/// FNV-1a hash of a record's key, length, and value.
+fn checksum(header: &[u8], value: &[u8]) -> u32 {
+    let mut hash = 0x811c_9dc5_u32;
+    for byte in header.iter().chain(value) {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+fn read_bytes(address: usize, buf: &mut [u8]) {
+    let ptr = address as *const u8;
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile(ptr.add(i)) };
+    }
+}
+
+fn read_u32(address: usize) -> u32 {
+    let mut buf = [0; 4];
+    read_bytes(address, &mut buf);
+    u32::from_le_bytes(buf)
+}
+
+/// Emulated EEPROM, on two flash pages.
+pub struct Eeprom {
+    pub flash: Flash,
+    pages: [Page; 2],
+    /// The index of the active page in `pages`.
+    active: usize,
+    /// The offset of the next free record in the active page.
+    next: usize,
+    seq: u32,
+    /// Set if the log ends in a corrupt record, so must be compacted before writing.
+    dirty: bool,
+}
+
+impl Eeprom {
+    /// Set up EEPROM emulation on two pages (sectors on F4 and H7), specified by bank and page
+    /// number. Loads existing data if present; otherwise, formats the pages.
+    pub fn new(flash: Flash, pages: [(Bank, usize); 2]) -> Result<Self, EepromError> {
+        let pages = pages.map(|(bank, number)| {
+            let address = flash.page_address(bank, number);
+            Page {
+                bank,
+                number,
+                address,
+                size: flash.page_address(bank, number + 1) - address,
+            }
+        });
+
+        let mut result = Self {
+            flash,
+            pages,
+            active: 0,
+            next: PAGE_HEADER_SIZE,
+            seq: 0,
+            dirty: false,
+        };
+
+        let seqs = pages.map(|p| {
+            if read_u32(p.address) == PAGE_MAGIC {
+                Some(read_u32(p.address + 4))
+            } else {
+                None
+            }
+        });
+
+        match seqs {
+            [None, None] => {
+                result.format()?;
+                return Ok(result);
+            }
+            [Some(seq), None] => result.seq = seq,
+            [None, Some(seq)] => {
+                result.active = 1;
+                result.seq = seq;
+            }
+            [Some(a), Some(b)] => {
+                // Both are valid if compaction was interrupted before erasing the old page. Use the
+                // newer one.
+                if (b.wrapping_sub(a) as i32) > 0 {
+                    result.active = 1;
+                    result.seq = b;
+                } else {
+                    result.seq = a;
+                }
+            }
+        }
+
+        // Find the end of the log.
+        loop {
+            match result.slot(result.next) {
+                Slot::Valid { len, .. } => result.next += record_size(len),
+                Slot::Erased => break,
+                Slot::Corrupt => {
+                    result.dirty = true;
+                    break;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Erase both pages, deleting all records.
+    pub fn format(&mut self) -> Result<(), EepromError> {
+        for page in self.pages {
+            self.flash.erase_page(page.bank, page.number)?;
+        }
+
+        self.active = 0;
+        self.seq = 1;
+        self.write_header(self.pages[0])?;
+        self.next = PAGE_HEADER_SIZE;
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    fn write_header(&mut self, page: Page) -> Result<(), EepromError> {
+        let mut header = [0xff; PAGE_HEADER_SIZE];
+        header[0..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&self.seq.to_le_bytes());
+
+        self.flash.write(page.address, &header)?;
+        Ok(())
+    }
+
+    /// Read the record header at an offset in the active page.
+    fn slot(&self, offset: usize) -> Slot {
+        let page = self.pages[self.active];
+        if offset + HEADER_LEN > page.size {
+            return Slot::Erased;
+        }
+
+        let mut header = [0; HEADER_LEN];
+        read_bytes(page.address + offset, &mut header);
+
+        let key = u16::from_le_bytes([header[0], header[1]]);
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let check = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        if header.iter().all(|b| *b == 0xff) {
+            return Slot::Erased;
+        }
+
+        if key == KEY_ERASED || len > MAX_VALUE_SIZE || offset + record_size(len) > page.size {
+            return Slot::Corrupt;
+        }
+
+        let mut value = [0; MAX_VALUE_SIZE];
+        read_bytes(page.address + offset + HEADER_LEN, &mut value[..len]);
+
+        if checksum(&header[..4], &value[..len]) == check {
+            Slot::Valid { key, len }
+        } else {
+            Slot::Corrupt
+        }
+    }
+
+    /// Find the offset, and length of the latest record for a key, starting at `from`.
+    fn find(&self, key: u16, from: usize) -> Option<(usize, usize)> {
+        let mut result = None;
+        let mut offset = from;
+
+        while offset < self.next {
+            match self.slot(offset) {
+                Slot::Valid { key: k, len } => {
+                    if k == key {
+                        result = Some((offset, len));
+                    }
+                    offset += record_size(len);
+                }
+                _ => break,
+            }
+        }
+
+        result
+    }
+
+    /// Read a value into `buf`. Returns its length, or `None` if the key isn't present.
+    pub fn get_bytes(&self, key: u16, buf: &mut [u8]) -> Result<Option<usize>, EepromError> {
+        if key == KEY_ERASED {
+            return Err(EepromError::InvalidKey);
+        }
+
+        let (offset, len) = match self.find(key, PAGE_HEADER_SIZE) {
+            // A zero-length record marks a removed key.
+            Some((_, 0)) | None => return Ok(None),
+            Some(r) => r,
+        };
+
+        if buf.len() < len {
+            return Err(EepromError::Size);
+        }
+
+        let address = self.pages[self.active].address + offset + HEADER_LEN;
+        read_bytes(address, &mut buf[..len]);
+
+        Ok(Some(len))
+    }
+
+    /// Store a value. This doesn't write anything if the stored value is already the same. If the
+    /// active page is full, this compacts the records first.
+    pub fn put_bytes(&mut self, key: u16, value: &[u8]) -> Result<(), EepromError> {
+        if key == KEY_ERASED {
+            return Err(EepromError::InvalidKey);
+        }
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(EepromError::Size);
+        }
+
+        if !value.is_empty() {
+            let mut current = [0; MAX_VALUE_SIZE];
+            if let Ok(Some(len)) = self.get_bytes(key, &mut current) {
+                if current[..len] == *value {
+                    return Ok(());
+                }
+            }
+        }
+
+        let size = record_size(value.len());
+
+        if self.dirty || self.next + size > self.pages[self.active].size {
+            self.compact()?;
+
+            if self.next + size > self.pages[self.active].size {
+                return Err(EepromError::Full);
+            }
+        }
+
+        let mut record = [0xff; MAX_RECORD_SIZE];
+        record[0..2].copy_from_slice(&key.to_le_bytes());
+        record[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        let check = checksum(&record[..4], value);
+        record[4..8].copy_from_slice(&check.to_le_bytes());
+        record[HEADER_LEN..HEADER_LEN + value.len()].copy_from_slice(value);
+
+        let address = self.pages[self.active].address + self.next;
+
+        if let Err(e) = self.flash.write(address, &record[..size]) {
+            // The area may be partially written; compact before the next write.
+            self.dirty = true;
+            return Err(e.into());
+        }
+        self.next += size;
+
+        Ok(())
+    }
+
+    /// Remove a key.
+    pub fn remove(&mut self, key: u16) -> Result<(), EepromError> {
+        let mut buf = [0; MAX_VALUE_SIZE];
+        if self.get_bytes(key, &mut buf)?.is_none() {
+            return Ok(());
+        }
+
+        self.put_bytes(key, &[])
+    }
+
+    /// Read a typed value. Returns `None` if the key isn't present, and `EepromError::Size` if the
+    /// stored value isn't the type's size.
+    pub fn get<T: Value>(&self, key: u16) -> Result<Option<T>, EepromError> {
+        let mut buf = [0; MAX_VALUE_SIZE];
+
+        match self.get_bytes(key, &mut buf)? {
+            Some(len) if len == T::SIZE => Ok(Some(T::from_bytes(&buf[..len]))),
+            Some(_) => Err(EepromError::Size),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a typed value.
+    pub fn put<T: Value>(&mut self, key: u16, value: &T) -> Result<(), EepromError> {
+        if T::SIZE > MAX_VALUE_SIZE {
+            return Err(EepromError::Size);
+        }
+
+        let mut buf = [0; MAX_VALUE_SIZE];
+        value.to_bytes(&mut buf[..T::SIZE]);
+        self.put_bytes(key, &buf[..T::SIZE])
+    }
+
+    /// Copy the latest value of each key to the other page, make it active, and erase the old
+    /// page. This runs automatically when the active page is full.
+    pub fn compact(&mut self) -> Result<(), EepromError> {
+        let src = self.pages[self.active];
+        let dest = self.pages[1 - self.active];
+
+        self.flash.erase_page(dest.bank, dest.number)?;
+
+        let mut offset = PAGE_HEADER_SIZE;
+        let mut dest_offset = PAGE_HEADER_SIZE;
+        let mut record = [0; MAX_RECORD_SIZE];
+
+        while offset < self.next {
+            let (key, len) = match self.slot(offset) {
+                Slot::Valid { key, len } => (key, len),
+                _ => break,
+            };
+            let size = record_size(len);
+
+            // Only copy the latest record for each key, and skip removed keys.
+            let superseded = self.find(key, offset + size).is_some();
+            if !superseded && len > 0 {
+                read_bytes(src.address + offset, &mut record[..size]);
+                self.flash
+                    .write(dest.address + dest_offset, &record[..size])?;
+                dest_offset += size;
+            }
+
+            offset += size;
+        }
+
+        // Write the header last, so an interrupted compaction leaves the old page active.
+        self.seq = self.seq.wrapping_add(1);
+        self.write_header(dest)?;
+
+        self.active = 1 - self.active;
+        self.next = dest_offset;
+        self.dirty = false;
+
+        self.flash.erase_page(src.bank, src.number)?;
+
+        Ok(())
+    }
+
+    /// Release the flash peripheral.
+    pub fn free(self) -> Flash {
+        self.flash
+    }
+}
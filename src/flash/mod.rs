@@ -5,6 +5,14 @@
 //! STM32 variant for page [sector] size, and number of pages [sectors] available.
 //! Make sure not to write to a page your MCU doesn't have, or that includes your
 //! program's memory.
+//!
+//! With the `embedded_storage` feature, `Flash` implements `embedded-storage`'s `NorFlash` trait, for
+//! use with crates like `sequential-storage`, and bootloaders. Offsets are from the start of flash
+//! memory, and span both banks on dual-bank variants. Not implemented on F4, which has sectors of
+//! different sizes, or on L5.
+//!
+//! See the `options` module for option bytes, including readout protection, and the `eeprom`
+//! module for wear-leveled key-value storage.
 
 use cfg_if::cfg_if;
 
@@ -23,6 +31,12 @@ cfg_if! {
     } else if #[cfg(feature = "h7")]{
         const SECTOR_SIZE: usize = 0x2_0000;
         const BANK2_START_ADDR: usize = 0x0810_0000;
+    } else if #[cfg(feature = "f4")]{
+        // Sectors are 16Kb, 64Kb, or 128Kb; see `page_to_address`. Bank 2 is for F42x and F43x
+        // variants with 2Mb of flash.
+        const BANK2_START_ADDR: usize = 0x0810_0000;
+    } else if #[cfg(feature = "wb")]{
+        const PAGE_SIZE: usize = 4_096;
     } else {
         const PAGE_SIZE: usize = 2_048;
         #[allow(dead_code)]  // bank arg on single-bank MCUs.
@@ -40,6 +54,28 @@ cfg_if! {
     }
 }
 
+// F4 sectors have different sizes, and H7B3 sectors can't be addressed with the PAC's `SNB` field.
+#[cfg(all(
+    feature = "embedded_storage",
+    not(any(
+        feature = "f4",
+        feature = "l5",
+        feature = "h5",
+        feature = "h7b3",
+        feature = "h747cm4",
+        feature = "h747cm7"
+    ))
+))]
+mod nor_flash;
+
+#[cfg(not(any(feature = "l5", feature = "h5")))]
+pub mod eeprom;
+
+// F3 option bytes are programmed like flash memory; L5 option registers are split by security
+// state.
+#[cfg(not(any(feature = "f3", feature = "l5", feature = "h5")))]
+pub mod options;
+
 pub struct Flash {
     pub regs: FLASH,
     #[cfg(any(
@@ -79,21 +115,7 @@ impl Flash {
         // 2. The embedded Flash memory effectively executes the read operation from the read
         // command queue buffer as soon as the non-volatile memory is ready and the previously
         // requested operations on this specific bank have been served.
-        cfg_if! {
-            if #[cfg(any(
-                feature = "g473",
-                feature = "g474",
-                feature = "g483",
-                feature = "g484",
-                feature = "l5",
-            ))] {
-                let mut addr = page_to_address(self.dual_bank, bank, page) as *mut u32;
-            } else if #[cfg(feature = "h7")]{
-                let mut addr = page_to_address(bank, page) as *mut u32;
-            } else {
-                let mut addr = page_to_address(page) as *mut u32;
-            }
-        }
+        let mut addr = self.page_address(bank, page) as *mut u32;
 
         unsafe {
             // Offset it by the start position
@@ -114,11 +136,112 @@ impl Flash {
             }
         }
     }
+
+    /// The address of the start of a page (sector on F4 and H7).
+    #[allow(unused_variables)] // bank arg on single-bank MCUs.
+    fn page_address(&self, bank: Bank, page: usize) -> usize {
+        cfg_if! {
+            if #[cfg(any(
+                feature = "g473",
+                feature = "g474",
+                feature = "g483",
+                feature = "g484",
+                feature = "l5",
+            ))] {
+                page_to_address(self.dual_bank, bank, page)
+            } else if #[cfg(any(feature = "f4", feature = "l4x5", feature = "l4x6", feature = "h7"))]{
+                page_to_address(bank, page)
+            } else {
+                page_to_address(page)
+            }
+        }
+    }
+
+    #[cfg(not(any(feature = "l5", feature = "h5")))]
+    /// Find the bank, page number (sector on F4 and H7), and page size containing an address.
+    pub(crate) fn page_at(&self, address: usize) -> (Bank, usize, usize) {
+        cfg_if! {
+            if #[cfg(any(feature = "g473", feature = "g474", feature = "g483", feature = "g484"))] {
+                if self.dual_bank == DualBank::Single {
+                    let page = (address - BANK1_START_ADDR) / PAGE_SIZE_SINGLE_BANK;
+                    (Bank::B1, page, PAGE_SIZE_SINGLE_BANK)
+                } else if address >= BANK2_START_ADDR {
+                    let page = (address - BANK2_START_ADDR) / PAGE_SIZE_DUAL_BANK;
+                    (Bank::B2, page, PAGE_SIZE_DUAL_BANK)
+                } else {
+                    let page = (address - BANK1_START_ADDR) / PAGE_SIZE_DUAL_BANK;
+                    (Bank::B1, page, PAGE_SIZE_DUAL_BANK)
+                }
+            } else if #[cfg(any(feature = "h747cm4", feature = "h747cm7"))] {
+                (Bank::B1, (address - BANK1_START_ADDR) / SECTOR_SIZE, SECTOR_SIZE)
+            } else if #[cfg(feature = "h7")] {
+                if address >= BANK2_START_ADDR {
+                    (Bank::B2, (address - BANK2_START_ADDR) / SECTOR_SIZE, SECTOR_SIZE)
+                } else {
+                    (Bank::B1, (address - BANK1_START_ADDR) / SECTOR_SIZE, SECTOR_SIZE)
+                }
+            } else if #[cfg(feature = "f4")] {
+                let (bank, offset) = if address >= BANK2_START_ADDR {
+                    (Bank::B2, address - BANK2_START_ADDR)
+                } else {
+                    (Bank::B1, address - BANK1_START_ADDR)
+                };
+
+                match offset {
+                    0..=0xffff => (bank, offset / 0x4000, 0x4000),
+                    0x1_0000..=0x1_ffff => (bank, 4, 0x1_0000),
+                    _ => (bank, 4 + offset / 0x2_0000, 0x2_0000),
+                }
+            } else if #[cfg(any(feature = "l4x5", feature = "l4x6"))] {
+                let bank_size = flash_size() / 2;
+                let offset = address - BANK1_START_ADDR;
+
+                if offset >= bank_size {
+                    (Bank::B2, (offset - bank_size) / PAGE_SIZE, PAGE_SIZE)
+                } else {
+                    (Bank::B1, offset / PAGE_SIZE, PAGE_SIZE)
+                }
+            } else {
+                (Bank::B1, (address - BANK1_START_ADDR) / PAGE_SIZE, PAGE_SIZE)
+            }
+        }
+    }
+
+    /// The size of the flash memory, in bytes; read from the flash size data register.
+    pub fn size(&self) -> usize {
+        flash_size()
+    }
+}
+
+/// Read the flash size data register, which contains the size in Kb. This is in the
+/// device's system memory, at an address that varies by family.
+fn flash_size() -> usize {
+    cfg_if! {
+        if #[cfg(feature = "f3")] {
+            let addr = 0x1FFF_F7CC;
+        } else if #[cfg(feature = "f4")] {
+            let addr = 0x1FFF_7A22;
+        } else if #[cfg(feature = "l5")] {
+            let addr = 0x0BFA_05E0;
+        } else if #[cfg(any(feature = "h5", feature = "h7b3"))] {
+            let addr = 0x08FF_F80C;
+        } else if #[cfg(feature = "h7")] {
+            let addr = 0x1FF1_E880;
+        } else {
+            let addr = 0x1FFF_75E0;
+        }
+    }
+
+    let kb = unsafe { core::ptr::read_volatile(addr as *const u16) };
+    kb as usize * 1_024
 }
 
 /// Calculate the address of the start of a given page. Each page is 2,048 Kb for non-H7.
 /// For H7, sectors are 128Kb, with 8 sectors per bank.
 #[cfg(not(any(
+    feature = "f4",
+    feature = "l4x5",
+    feature = "l4x6",
     feature = "g473",
     feature = "g474",
     feature = "g483",
@@ -131,6 +254,36 @@ fn page_to_address(page: usize) -> usize {
     BANK1_START_ADDR + page * PAGE_SIZE
 }
 
+#[cfg(any(feature = "l4x5", feature = "l4x6"))]
+/// Calculate the address of the start of a given page. Pages are 2Kb. Bank 2 starts halfway
+/// through flash memory; eg at 0x0808_0000 on 1Mb variants.
+fn page_to_address(bank: Bank, page: usize) -> usize {
+    let starting_pt = match bank {
+        Bank::B1 => BANK1_START_ADDR,
+        Bank::B2 => BANK1_START_ADDR + flash_size() / 2,
+    };
+
+    starting_pt + page * PAGE_SIZE
+}
+
+#[cfg(feature = "f4")]
+/// Calculate the address of the start of a given sector. Sectors 0-3 are 16Kb, sector 4 is 64Kb,
+/// and sectors 5-11 are 128Kb. Dual-bank variants repeat this layout in bank 2.
+fn page_to_address(bank: Bank, sector: usize) -> usize {
+    let starting_pt = match bank {
+        Bank::B1 => BANK1_START_ADDR,
+        Bank::B2 => BANK2_START_ADDR,
+    };
+
+    let offset = match sector {
+        0..=3 => sector * 0x4000,
+        4 => 0x1_0000,
+        _ => (sector - 4) * 0x2_0000,
+    };
+
+    starting_pt + offset
+}
+
 #[cfg(any(
     feature = "g473",
     feature = "g474",
@@ -5,15 +5,60 @@ use core;
 
 use cfg_if::cfg_if;
 
+#[cfg(all(feature = "h7", not(any(feature = "h747cm4", feature = "h747cm7"))))]
+use super::BANK2_START_ADDR;
 use super::{page_to_address, Flash};
 #[cfg(feature = "h7")]
 use crate::pac::flash::BANK;
+#[cfg(not(feature = "h7"))]
 use crate::pac::FLASH;
 
 const FLASH_KEY1: u32 = 0x4567_0123;
 const FLASH_KEY2: u32 = 0xCDEF_89AB;
-// const FLASH_OPT_KEY1: u32 = 0x0819_2A3B;
-// const FLASH_OPT_KEY2: u32 = 0x4C5D_6E7F;
+
+cfg_if! {
+    if #[cfg(feature = "f3")] {
+        /// The number of bytes programmed at once: A half-word.
+        pub const WRITE_SIZE: usize = 2;
+    } else if #[cfg(feature = "f4")] {
+        /// The number of bytes programmed at once: A word. (x32 parallelism, which requires a
+        /// supply voltage of 2.7 - 3.6V)
+        pub const WRITE_SIZE: usize = 4;
+    } else if #[cfg(feature = "h7b3")] {
+        /// The number of bytes programmed at once: A 128-bit flash word.
+        pub const WRITE_SIZE: usize = 16;
+    } else if #[cfg(feature = "h7")] {
+        /// The number of bytes programmed at once: A 256-bit flash word.
+        pub const WRITE_SIZE: usize = 32;
+    } else {
+        /// The number of bytes programmed at once: A double word.
+        pub const WRITE_SIZE: usize = 8;
+    }
+}
+
+// Error flags in the `SR` register. We use masks, since the PAC field names vary by family.
+cfg_if! {
+    if #[cfg(feature = "f3")] {
+        // PGERR, WRPRTERR
+        pub(super) const SR_ERRORS: u32 = 0x14;
+    } else if #[cfg(feature = "f4")] {
+        // OPERR, WRPERR, PGAERR, PGPERR, PGSERR
+        pub(super) const SR_ERRORS: u32 = 0xf2;
+    } else if #[cfg(feature = "h7")] {
+        // WRPERR, PGSERR, STRBERR, INCERR, OPERR
+        pub(super) const SR_ERRORS: u32 = 0x6e_0000;
+    } else {
+        // OPERR, PROGERR, WRPERR, PGAERR, SIZERR, PGSERR, MISERR, FASTERR
+        pub(super) const SR_ERRORS: u32 = 0x3fa;
+    }
+}
+
+/// The register block containing the `KEYR`, `CR`, and `SR` registers. On H7, each bank has its
+/// own.
+#[cfg(not(feature = "h7"))]
+type BankRegs = FLASH;
+#[cfg(feature = "h7")]
+type BankRegs = BANK;
 
 #[derive(Clone, Copy, PartialEq)]
 /// Set dual bank mode (DBANK option bit). Eg G4
@@ -32,7 +77,8 @@ pub enum Bank {
     B2 = 1,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Possible error states for flash operations.
 pub enum Error {
     /// Flash controller is not done yet
@@ -45,10 +91,10 @@ pub enum Error {
     PageOutOfRange,
     /// (Legal) command failed
     Failure,
+    /// The address isn't aligned to `WRITE_SIZE`, or an erase range isn't aligned to pages.
+    Alignment,
 }
 
-// todo: Bank 2 support on H7 and others.
-
 #[cfg(not(feature = "h7"))]
 /// Check and clear all non-secure error programming flags due to a previous
 /// programming. If not, PGSERR is set.
@@ -159,69 +205,115 @@ fn clear_error_flags(regs: &BANK) {
     }
 }
 
+/// Unlock a bank's `CR` register. See `Flash::unlock`.
+fn unlock_bank(regs: &BankRegs) -> Result<(), Error> {
+    if regs.cr.read().lock().bit_is_clear() {
+        return Ok(());
+    }
+
+    // The following sequence is used to unlock this register:
+    // 1. Write KEY1 = 0x45670123 in the Flash key register (FLASH_KEYR)
+    // 2. Write KEY2 = 0xCDEF89AB in the FLASH_KEYR register.
+    regs.keyr.write(|w| unsafe { w.bits(FLASH_KEY1) });
+    regs.keyr.write(|w| unsafe { w.bits(FLASH_KEY2) });
+
+    if regs.cr.read().lock().bit_is_clear() {
+        Ok(())
+    } else {
+        Err(Error::Failure)
+    }
+}
+
+fn lock_bank(regs: &BankRegs) {
+    // The FLASH_CR register cannot be written when the BSY bit in the Flash status register
+    // (FLASH_SR) is set. Any attempt to write to it with the BSY bit set causes the AHB bus to
+    // stall until the BSY bit is cleared.
+    while regs.sr.read().bsy().bit_is_set() {}
+    regs.cr.modify(|_, w| w.lock().set_bit());
+}
+
+/// Wait for an erase or program operation to complete, then check for errors. Clears the EOP flag.
+/// Error flags are left set; they're cleared at the start of the next operation.
+fn wait_ready(regs: &BankRegs) -> Result<(), Error> {
+    // (H7): Wait until the QW1/2 bit is cleared in the corresponding FLASH_SR1/2 register.
+    #[cfg(feature = "h7")]
+    while regs.sr.read().qw().bit_is_set() {}
+
+    while regs.sr.read().bsy().bit_is_set() {}
+
+    let sr = regs.sr.read();
+
+    // On F3 and F4, EOP is set when the operation succeeds. On others, only if the EOPIE
+    // interrupt is enabled.
+    if sr.eop().bit_is_set() {
+        #[cfg(not(feature = "h7"))]
+        regs.sr.write(|w| w.eop().set_bit());
+        #[cfg(feature = "h7")]
+        regs.ccr.write(|w| w.clr_eop().set_bit());
+    }
+
+    if sr.bits() & SR_ERRORS != 0 {
+        Err(Error::Illegal)
+    } else {
+        Ok(())
+    }
+}
+
+/// Write one `WRITE_SIZE` unit. Must be aligned to `WRITE_SIZE`.
+fn program_word(address: usize, data: &[u8; WRITE_SIZE]) {
+    cfg_if! {
+        if #[cfg(feature = "f3")] {
+            // F3 RM: "The Flash memory can be programmed 16 bits at a time."
+            unsafe { core::ptr::write_volatile(address as *mut u16, u16::from_le_bytes(*data)) };
+        } else {
+            let address = address as *mut u32;
+
+            for (i, bytes) in data.chunks_exact(4).enumerate() {
+                let word = u32::from_le_bytes(bytes.try_into().unwrap());
+                unsafe { core::ptr::write_volatile(address.add(i), word) };
+            }
+        }
+    }
+
+    // (H7): Make sure the flash word has left the CPU's write buffer before waiting on QW.
+    #[cfg(feature = "h7")]
+    cortex_m::asm::dsb();
+}
+
 impl Flash {
     /// Unlock the flash memory, allowing writes. See L4 Reference manual, section 3.3.5.
     /// (G4 RM section 5.3.5)
     /// "After reset, write is not allowed in the Flash control register (FLASH_CR) to protect the
     /// Flash memory against possible unwanted operations due, for example, to electric
     /// disturbances."
+    /// On dual-bank H7 variants, this unlocks both banks.
     pub fn unlock(&mut self) -> Result<(), Error> {
         #[cfg(not(feature = "h7"))]
-        let regs = &self.regs;
-        #[cfg(feature = "h7")]
-        let regs = self.regs.bank1();
+        unlock_bank(&self.regs)?;
 
-        if regs.cr.read().lock().bit_is_clear() {
-            return Ok(());
-        }
-
-        // The following sequence is used to unlock this register:
-        // 1. Write KEY1 = 0x45670123 in the Flash key register (FLASH_KEYR)
-        // 2. Write KEY2 = 0xCDEF89AB in the FLASH_KEYR register.
-        regs.keyr.write(|w| unsafe { w.bits(FLASH_KEY1) });
-        regs.keyr.write(|w| unsafe { w.bits(FLASH_KEY2) });
+        #[cfg(feature = "h7")]
+        unlock_bank(self.regs.bank1())?;
+        #[cfg(all(
+            feature = "h7",
+            not(any(feature = "h735", feature = "h747cm4", feature = "h747cm7"))
+        ))]
+        unlock_bank(self.regs.bank2())?;
 
-        if regs.cr.read().lock().bit_is_clear() {
-            Ok(())
-        } else {
-            Err(Error::Failure)
-        }
+        Ok(())
     }
 
-    // /// Unlock the FLASH_OPTCR register, for writing option bits.
-    // pub fn unlock_options(&mut self) -> Result<(), Error> {
-    //     #[cfg(not(feature = "h7"))]
-    //     let regs = &self.regs;
-    //     #[cfg(feature = "h7")]
-    //     let regs = self.regs.bank1();
-    //
-    //     if regs.cr.read().lock().bit_is_clear() {
-    //         return Ok(());
-    //     }
-    //
-    //     regs.optkeyr.write(|w| unsafe { w.bits(FLASH_OPT_KEY1) });
-    //     regs.optkeyr.write(|w| unsafe { w.bits(FLASH_OPT_KEY2) });
-    //
-    //     if regs.cr.read().lock().bit_is_clear() {
-    //         Ok(())
-    //     } else {
-    //         Err(Error::Failure)
-    //     }
-    // }
-
+    /// Lock the flash memory, preventing writes. On dual-bank H7 variants, this locks both banks.
     pub fn lock(&mut self) {
-        // The FLASH_CR register cannot be written when the BSY bit in the Flash status register
-        // (FLASH_SR) is set. Any attempt to write to it with the BSY bit set causes the AHB bus to
-        // stall until the BSY bit is cleared.
-        // todo: bank
-
         #[cfg(not(feature = "h7"))]
-        let regs = &self.regs;
-        #[cfg(feature = "h7")]
-        let regs = &self.regs.bank1();
+        lock_bank(&self.regs);
 
-        while regs.sr.read().bsy().bit_is_set() {}
-        regs.cr.modify(|_, w| w.lock().set_bit());
+        #[cfg(feature = "h7")]
+        lock_bank(self.regs.bank1());
+        #[cfg(all(
+            feature = "h7",
+            not(any(feature = "h735", feature = "h747cm4", feature = "h747cm7"))
+        ))]
+        lock_bank(self.regs.bank2());
     }
 
     #[cfg(not(feature = "h7"))]
@@ -231,6 +323,8 @@ impl Flash {
     /// "Programming in a previously programmed address is not allowed except if the data to write
     /// is full zero, and any attempt will set PROGERR flag in the Flash status register
     /// (FLASH_SR)."
+    /// On F4, `page` is the sector number within the bank. Bank 2 is only available on F42x and
+    /// F43x variants with 2Mb of flash.
     pub fn erase_page(&mut self, bank: Bank, page: usize) -> Result<(), Error> {
         self.unlock()?;
         let regs = &self.regs;
@@ -254,16 +348,18 @@ impl Flash {
                 // Set the PER bit in the FLASH_CR register
                 regs.cr.modify(|_, w| w.per().set_bit());
 
-                // Program the FLASH_CR register
-                // regs.ar.modify(|_, w| w.far().bits(page as u8));
-                regs.ar.write(|w| unsafe { w.bits(page as u32) }); // todo: Is this right?
+                // Program the FLASH_AR register to select a page to erase. This takes an address
+                // within the page, vice a page number.
+                regs.ar.write(|w| unsafe { w.bits(page_to_address(page) as u32) });
             } else if #[cfg(feature = "f4")] {
                 // Set the SER bit and select the sector out of the 12 sectors (for STM32F405xx/07xx and
                 // STM32F415xx/17xx) and out of 24 (for STM32F42xxx and STM32F43xxx) in the main
-                // memory block you wish to erase (SNB) in the FLASH_CR register
+                // memory block you wish to erase (SNB) in the FLASH_CR register. Sectors 12 - 23,
+                // in bank 2, are selected by setting bit 4.
                 regs.cr.modify(|_, w| unsafe {
+                    w.psize().psize32();
                     w.ser().set_bit();
-                    w.snb().bits(page as u8) // todo: Probably not right?
+                    w.snb().bits(((bank as u8) << 4) | page as u8)
                 });
             } else if #[cfg(any(feature = "g473", feature = "g474", feature = "g483", feature = "g484"))] {
                 // 3. (G4 dual-bank devices: In dual bank mode (DBANK option bit is set), set the PER bit and
@@ -273,17 +369,26 @@ impl Flash {
                 // select the page to erase (PNB). The BKER bit in the Flash control register
                 // (FLASH_CR) must be kept cleared)
                 if self.dual_bank == DualBank::Dual {
-                     regs.cr.modify(|_, w| unsafe {
-                        // w.bker().bits(bank as u8); // todo: PAC error
+                     regs.cr.modify(|r, w| unsafe {
+                        // BKER is bit 11; it's missing from the PAC.
+                        w.bits((r.bits() & !(1 << 11)) | ((bank as u32) << 11));
                         w.pnb().bits(page as u8);
                         w.per().set_bit()
                     });
                 } else {
-                     regs.cr.modify(|_, w| unsafe {
+                     regs.cr.modify(|r, w| unsafe {
+                        w.bits(r.bits() & !(1 << 11));
                         w.pnb().bits(page as u8);
                         w.per().set_bit()
                     });
                 }
+            } else if #[cfg(feature = "l4")] {
+                // L4 dual-bank variants (eg L4x5 and L4x6) have up to 256 pages per bank.
+                regs.cr.modify(|_, w| unsafe {
+                    w.bker().bit(bank as u8 != 0);
+                    w.pnb().bits(page as u8);
+                    w.per().set_bit()
+                });
             } else {
                  regs.cr.modify(|_, w| unsafe {
                     w.pnb().bits(page as u8);
@@ -302,16 +407,10 @@ impl Flash {
         }
 
         // 5. Wait for the BSY bit to be cleared in the FLASH_SR register.
-        while regs.sr.read().bsy().bit_is_set() {}
+        // (F3, F4): Check the EOP flag in the FLASH_SR register (it is set when the erase
+        // operation has succeeded), and then clear it by software.
+        let result = wait_ready(regs);
 
-        cfg_if! {
-            if #[cfg(any(feature = "f3", feature = "f4"))] {
-                // Check the EOP flag in the FLASH_SR register (it is set when the erase operation has
-                // succeeded), and then clear it by software.
-                while regs.sr.read().eop().bit_is_clear() {}
-                regs.sr.modify(|_, w| w.eop().set_bit());
-            }
-        }
         #[cfg(not(feature = "f4"))]
         regs.cr.modify(|_, w| w.per().clear_bit());
         #[cfg(feature = "f4")]
@@ -319,7 +418,7 @@ impl Flash {
 
         self.lock();
 
-        Ok(())
+        result
     }
 
     #[cfg(feature = "h7")]
@@ -328,6 +427,9 @@ impl Flash {
     /// families, but has a different name "sector" vice "page", and the RM instructions
     /// are phrased differently.
     pub fn erase_page(&mut self, bank: Bank, sector: usize) -> Result<(), Error> {
+        // 2.Unlock the FLASH_CR1/2 register, as described in Section 4.5.1: FLASH configuration
+        // protection (only if register is not already unlocked).
+        // (Out of order due to borrow-checker issue)
         self.unlock()?;
 
         let regs = &match bank {
@@ -337,35 +439,39 @@ impl Flash {
             Bank::B2 => self.regs.bank2(),
         };
 
+        if regs.sr.read().qw().bit_is_set() {
+            self.lock();
+            return Err(Error::Busy);
+        }
+
         // To erase a 128-Kbyte user sector, proceed as follows:
         // 1. Check and clear (optional) all the error flags due to previous programming/erase
         // operation. Refer to Section 4.7: FLASH error management for details.
-        clear_error_flags(&regs);
-
-        // 2.Unlock the FLASH_CR1/2 register, as described in Section 4.5.1: FLASH configuration
-        // protection (only if register is not already unlocked).
-        // self.unlock()?; // (Above; out of order due to borrow-checker issue)
+        clear_error_flags(regs);
 
         // 3. Set the SER1/2 bit and SNB1/2 bitfield in the corresponding FLASH_CR1/2 register.
         // SER1/2 indicates a sector erase operation, while SNB1/2 contains the target sector
         // number.
         regs.cr.modify(|_, w| unsafe {
             w.ser().set_bit();
-            w.snb().bits(sector as u8) // todo: Probably not right?
+            w.snb().bits(sector as u8)
         });
 
         // 4. Set the START1/2 bit in the FLASH_CR1/2 register.
         regs.cr.modify(|_, w| w.start().set_bit());
 
         // 5. Wait for the QW1/2 bit to be cleared in the corresponding FLASH_SR1/2 register.
-        while regs.sr.read().qw().bit_is_set() {}
+        let result = wait_ready(regs);
+
+        regs.cr.modify(|_, w| w.ser().clear_bit());
 
         self.lock();
 
-        Ok(())
+        result
     }
 
     /// Erase one or both banks. Called "Mass erase" on single-bank variants like G4.
+    #[allow(unused_variables)] // bank arg on single-bank MCUs.
     pub fn erase_bank(&mut self, bank: Bank) -> Result<(), Error> {
         // todo: DRY
         // (H7): 2. Unlock the FLASH_CR1/2 register, as described in Section 4.5.1: FLASH configuration
@@ -407,43 +513,49 @@ impl Flash {
             } else if #[cfg(feature = "h7")] {
                 // 3. Set the BER1/2 bit in the FLASH_CR1/2 register corresponding to the targeted bank.
                 regs.cr.modify(|_, w| w.ber().set_bit());
-            } else { // L4, G4
+            } else if #[cfg(feature = "l4")] {
+                match bank {
+                    Bank::B1 => regs.cr.modify(|_, w| w.mer1().set_bit()),
+                    Bank::B2 => regs.cr.modify(|_, w| w.mer2().set_bit()),
+                }
+            } else { // G4
                 match bank {
-                    Bank::B1 => {
-                        regs.cr.modify(|_, w| w.mer1().set_bit());
-                    }
-                    Bank::B2 => {
-                        // todo: Other families that have dual bank too!
-                        // #[cfg(any(feature = "g473", feature = "g474", feature = "g483", feature = "g484"))]
-                        // regs.cr.modify(|_, w| w.mer2().set_bit()); // todo PAC error
-                    }
+                    Bank::B1 => regs.cr.modify(|_, w| w.mer1().set_bit()),
+                    // MER2 is bit 15; it's missing from the PAC.
+                    Bank::B2 => regs.cr.modify(|r, w| unsafe { w.bits(r.bits() | (1 << 15)) }),
                 }
             }
         }
 
         // 4. Set the STRT bit in the FLASH_CR register.
         cfg_if! {
-             if #[cfg(feature = "h7")] {
-                // 4. Set the START bit in the FLASH_CR1/2 register to start the bank erase operation. Then
-                // wait until the QW1/2 bit is cleared in the corresponding FLASH_SR1/2 register.
+             if #[cfg(any(feature = "l4", feature = "h7"))] {
+                // (H7) 4. Set the START bit in the FLASH_CR1/2 register to start the bank erase
+                // operation. Then wait until the QW1/2 bit is cleared in the corresponding
+                // FLASH_SR1/2 register.
                 regs.cr.modify(|_, w| w.start().set_bit());
-                while regs.sr.read().qw().bit_is_set() {}
-            } else if #[cfg(feature = "l4")] {
-                regs.cr.modify( | _, w | w.start().set_bit());
             } else {
                 regs.cr.modify(|_, w| w.strt().set_bit());
             }
         }
 
         // 5. Wait for the BSY bit to be cleared in the FLASH_SR register.
-        while regs.sr.read().bsy().bit_is_set() {}
+        let result = wait_ready(regs);
 
         // (Some RMs describe this procedure, to clear mer, with ambiguity of if it's required)
         cfg_if! {
             if #[cfg(feature = "h7")] {
                 regs.cr.modify(|_, w| w.ber().clear_bit());
-            } else if #[cfg(any(feature = "l4", feature = "g4"))] {
-                regs.cr.modify(|_, w| w.mer1().clear_bit());
+            } else if #[cfg(feature = "l4")] {
+                regs.cr.modify(|_, w| {
+                    w.mer1().clear_bit();
+                    w.mer2().clear_bit()
+                });
+            } else if #[cfg(feature = "g4")] {
+                regs.cr.modify(|r, w| unsafe {
+                    w.bits(r.bits() & !(1 << 15));
+                    w.mer1().clear_bit()
+                });
             } else {
                 regs.cr.modify(|_, w| w.mer().clear_bit());
             }
@@ -451,17 +563,34 @@ impl Flash {
 
         self.lock();
 
-        Ok(())
+        result
     }
 
-    // todo: For multibank variants, accept a bank argument.
-    /// Write the contents of a page. Must be erased first. See L4 RM, section 3.3.7.
+    /// Write the contents of a page (sector on H7). Must be erased first. See `write`.
     /// Make sure the page is one your MCU has, and isn't being used for the program itself.
-    #[cfg(not(feature = "h7"))]
     #[allow(unused_variables)] // bank arg on single-bank MCUs.
     pub fn write_page(&mut self, bank: Bank, page: usize, data: &[u8]) -> Result<(), Error> {
-        // todo: Consider a u8-based approach.
-        // todo: DRY from `erase_page`.
+        cfg_if! {
+             if #[cfg(any(feature = "g473", feature = "g474", feature = "g483", feature = "g484"))] {
+                let address = page_to_address(self.dual_bank, bank, page);
+            } else if #[cfg(any(feature = "f4", feature = "l4x5", feature = "l4x6", feature = "h7"))] {
+                let address = page_to_address(bank, page);
+            } else {
+                let address = page_to_address(page);
+            }
+        }
+
+        self.write(address, data)
+    }
+
+    /// Write data to flash memory, starting at `address`. Must be erased first. See L4 RM,
+    /// section 3.3.7. `address` must be aligned to `WRITE_SIZE`; if `data`'s length isn't a
+    /// multiple of it, the last write is padded with 0xff.
+    #[cfg(not(feature = "h7"))]
+    pub fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+        if !address.is_multiple_of(WRITE_SIZE) {
+            return Err(Error::Alignment);
+        }
 
         self.unlock()?;
 
@@ -480,63 +609,34 @@ impl Flash {
         // PGSERR is set.
         clear_error_flags(regs);
 
+        // (F4): Set the program size; this must match the size of the writes.
+        #[cfg(feature = "f4")]
+        regs.cr.modify(|_, w| w.psize().psize32());
+
         // 3. Set the PG bit in the Flash control register (FLASH_CR).
         regs.cr.modify(|_, w| w.pg().set_bit());
 
         // 4. Perform the data write operation at the desired memory address, inside main memory
-        // block or OTP area. Only double word can be programmed.
-
-        cfg_if! {
-             if #[cfg(any(feature = "g473", feature = "g474", feature = "g483", feature = "g484"))] {
-                let mut address = page_to_address(self.dual_bank, bank, page) as *mut u32;
-            } else {
-                let mut address = page_to_address(page) as *mut u32;
-            }
-        }
-
-        // Map our 8-bit data input API to the 64-bit write API. (Used by all variants, even though
-        // they have different read sizes.)
-        // "The Flash memory is programmed 72 bits at a time (64 bits + 8 bits ECC)"
+        // block or OTP area.
         // G4 RM: "It is only possible to program double word (2 x 32-bit data). •
         // Any attempt to write byte or half-word sets SIZERR flag in the FLASH_SR register.
         // Any attempt to write a double word which is not aligned with a double word address
         // sets PGAERR flag in the FLASH_SR register."
+        let mut result = Ok(());
 
-        for chunk in data.chunks(8) {
-            // 8 bytes is 64 bits. (Double word)
-            // Pad to 64 bits if required, ie on the last word.
-            let mut padded = [0xff; 8];
+        for (i, chunk) in data.chunks(WRITE_SIZE).enumerate() {
+            // Pad if required, ie on the last word. 0xff due to the default value of erased pages.
+            let mut padded = [0xff; WRITE_SIZE];
+            padded[..chunk.len()].copy_from_slice(chunk);
 
-            let (word1, word2) = if chunk.len() < 8 {
-                // 0xff due to the default value of erased pages.
-                padded[0..chunk.len()].clone_from_slice(&chunk);
+            program_word(address + i * WRITE_SIZE, &padded);
 
-                (
-                    u32::from_le_bytes(padded[0..4].try_into().unwrap()),
-                    u32::from_le_bytes(padded[4..8].try_into().unwrap()),
-                )
-            } else {
-                (
-                    u32::from_le_bytes(chunk[0..4].try_into().unwrap()),
-                    u32::from_le_bytes(chunk[4..8].try_into().unwrap()),
-                )
-            };
-
-            unsafe {
-                // Write a first word in an address aligned with double word
-                core::ptr::write_volatile(address, word1);
-                address = address.add(1);
-                // Write the second word
-                core::ptr::write_volatile(address, word2);
-                address = address.add(1);
-            }
             // 5. Wait until the BSY bit is cleared in the FLASH_SR register.
-            while regs.sr.read().bsy().bit_is_set() {}
-
             // 6. Check that EOP flag is set in the FLASH_SR register (meaning that the programming
             // operation has succeed), and clear it by software.
-            if regs.sr.read().eop().bit_is_set() {
-                regs.sr.modify(|_, w| w.eop().set_bit()); // Clear
+            result = wait_ready(regs);
+            if result.is_err() {
+                break;
             }
         }
 
@@ -546,19 +646,39 @@ impl Flash {
 
         self.lock();
 
-        Ok(())
+        result
     }
 
-    /// Write the contents of a sector. Must be erased first. See H742 or H723-35 RM, section 4.3.9.
-    /// Make sure the sector is one your MCU has, and isn't being used for the program itself. Writes
-    /// a byte array, 256 bits at a time.
+    /// Write data to flash memory, starting at `address`. Must be erased first. See H742 or
+    /// H723-35 RM, section 4.3.9. Writes 256 bits at a time (128 on H7B3). `address` must be aligned
+    /// to `WRITE_SIZE`; if `data`'s length isn't a multiple of it, the last write is padded with
+    /// 0xff. Writes to bank 2 on dual-bank variants if `address` is in it.
     #[cfg(feature = "h7")]
-    pub fn write_page(&mut self, bank: Bank, sector: usize, data: &[u8]) -> Result<(), Error> {
+    pub fn write(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+        if !address.is_multiple_of(WRITE_SIZE) {
+            return Err(Error::Alignment);
+        }
+
         // 1. Unlock the FLASH_CR1/2 register, as described in Section 4.5.1: FLASH configuration
         // protection (only if register is not already unlocked).
         self.unlock()?;
 
-        let regs = &self.regs.bank1(); // todo: Bank 2 support.
+        // todo: PAC bank 2 error
+        #[cfg(not(any(feature = "h747cm4", feature = "h747cm7")))]
+        let regs = if address >= BANK2_START_ADDR {
+            self.regs.bank2()
+        } else {
+            self.regs.bank1()
+        };
+        #[cfg(any(feature = "h747cm4", feature = "h747cm7"))]
+        let regs = self.regs.bank1();
+
+        if regs.sr.read().qw().bit_is_set() {
+            self.lock();
+            return Err(Error::Busy);
+        }
+
+        clear_error_flags(regs);
 
         // 2. Enable write operations by setting the PG1/2 bit in the FLASH_CR1/2 register.
         regs.cr.modify(|_, w| w.pg().set_bit());
@@ -567,33 +687,30 @@ impl Flash {
 
         // 4. Write one Flash-word corresponding to 32-byte data starting at a 32-byte aligned
         // address.
-        let mut address = page_to_address(bank, sector) as *mut u32;
-
         // Note that the key element separating each 256-bit writes is wating until the `qw` bit
         // is cleared.
-        for chunk in data.chunks(32) {
-            // We use 8 pointer-sized (32-bit) words to meet our full 32-byte (256-bit) write.
-            // Pad to 256 bits if required, ie on the last word.
-            let mut padded = [0xff; 32];
+        let mut result = Ok(());
 
-            for i in 0..8 {
-                // 0xff due to the default value of erased pages.
-                padded[0..chunk.len()].clone_from_slice(&chunk);
+        for (i, chunk) in data.chunks(WRITE_SIZE).enumerate() {
+            // Pad to 256 bits if required, ie on the last word.
+            // 0xff due to the default value of erased pages.
+            let mut padded = [0xff; WRITE_SIZE];
+            padded[..chunk.len()].copy_from_slice(chunk);
 
-                let word = u32::from_le_bytes(padded[i * 4..i * 4 + 4].try_into().unwrap());
-                unsafe {
-                    core::ptr::write_volatile(address, word);
-                    address = address.add(1);
-                }
-            }
+            program_word(address + i * WRITE_SIZE, &padded);
 
             // 5. Check that QW has been raised and wait until it is reset to 0.
-            while regs.sr.read().qw().bit_is_set() {}
+            result = wait_ready(regs);
+            if result.is_err() {
+                break;
+            }
         }
 
+        regs.cr.modify(|_, w| w.pg().clear_bit());
+
         self.lock();
 
-        Ok(())
+        result
     }
 
     /// Erase a page, then write to it.
@@ -0,0 +1,94 @@
+//! Implements the `embedded-storage` `NorFlash` traits. Offsets are relative to the start of flash
+//! memory. Erases use the page size (sector size on H7); on G4 variants with a DBANK option, this is
+//! the single-bank page size, which spans two pages in dual-bank mode.
+
+use cfg_if::cfg_if;
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashError, NorFlashErrorKind,
+    ReadNorFlash,
+};
+
+use super::{Error, Flash, BANK1_START_ADDR, WRITE_SIZE};
+
+cfg_if! {
+    if #[cfg(any(feature = "g473", feature = "g474", feature = "g483", feature = "g484"))] {
+        use super::PAGE_SIZE_SINGLE_BANK;
+        const ERASE_SIZE: usize = PAGE_SIZE_SINGLE_BANK;
+    } else if #[cfg(feature = "h7")] {
+        use super::SECTOR_SIZE;
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+    } else {
+        use super::PAGE_SIZE;
+        const ERASE_SIZE: usize = PAGE_SIZE;
+    }
+}
+
+impl From<NorFlashErrorKind> for Error {
+    fn from(kind: NorFlashErrorKind) -> Self {
+        match kind {
+            NorFlashErrorKind::NotAligned => Self::Alignment,
+            NorFlashErrorKind::OutOfBounds => Self::PageOutOfRange,
+            _ => Self::Failure,
+        }
+    }
+}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::Alignment => NorFlashErrorKind::NotAligned,
+            Self::PageOutOfRange => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+
+        let address = (BANK1_START_ADDR + offset as usize) as *const u8;
+
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = unsafe { core::ptr::read_volatile(address.add(i)) };
+        }
+
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.size()
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = WRITE_SIZE;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+
+        let mut address = BANK1_START_ADDR + from as usize;
+        let end = BANK1_START_ADDR + to as usize;
+
+        while address < end {
+            let (bank, page, page_size) = self.page_at(address);
+            self.erase_page(bank, page)?;
+            address += page_size;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+
+        Flash::write(self, BANK1_START_ADDR + offset as usize, bytes)
+    }
+}
@@ -0,0 +1,683 @@
+//! Read and program option bytes: Readout protection (RDP) level, brown-out reset (BOR) level, write
+//! protection (WRP), boot configuration, and dual-bank swap. Use this for production provisioning, eg
+//! to set RDP level 1 from firmware on first boot.
+//!
+//! Modify a copy read with `Flash::read_options()`, then write it with
+//! `Flash::program_options()`. Programmed values don't take effect until they're loaded with
+//! `Flash::launch_options()`, which resets the MCU.
+//!
+//! **Warning**: Option bytes can permanently disable your MCU, or make it unreachable from a debugger:
+//! - RDP level 2 is permanent. It disables debug access, and the option bytes can never be changed
+//!   again, including by this module.
+//! - Changing RDP from level 1 to level 0 mass-erases flash memory, including the running program.
+//! - Setting the boot configuration incorrectly (eg a H7 boot address with no valid program) may
+//!   prevent the MCU from starting, until it's recovered with a debugger under reset.
+//!
+//! Example, setting RDP level 1 if it isn't already:
+//! ```rust
+//! let mut flash = Flash::new(dp.FLASH);
+//! let mut options = flash.read_options();
+//!
+//! if options.rdp() == RdpLevel::L0 {
+//!     options.set_rdp(RdpLevel::L1);
+//!     flash.program_options(&options).ok();
+//!     flash.launch_options().ok(); // Resets the MCU.
+//! }
+//! ```
+//!
+//! Not available on F3, where option bytes are programmed like flash memory, or on L5.
+
+use cfg_if::cfg_if;
+
+#[cfg(not(feature = "h7"))]
+use super::non_trustzone::SR_ERRORS;
+use super::{Bank, Error, Flash};
+
+const FLASH_OPT_KEY1: u32 = 0x0819_2A3B;
+const FLASH_OPT_KEY2: u32 = 0x4C5D_6E7F;
+
+// Register offsets and bits. We use these instead of the PAC, since option register and field
+// names vary between families and variants.
+cfg_if! {
+    if #[cfg(feature = "f4")] {
+        const OPTKEYR: usize = 0x08;
+        const SR: usize = 0x0c;
+        /// Option control register. On F4, this holds both the control bits, and option values.
+        const OPTCR: usize = 0x14;
+        const OPTCR_OPTLOCK: u32 = 1 << 0;
+        const OPTCR_OPTSTRT: u32 = 1 << 1;
+        const SR_BSY: u32 = 1 << 16;
+
+        const RDP_SHIFT: u32 = 8;
+        const BOR_SHIFT: u32 = 2;
+
+        cfg_if! {
+            if #[cfg(any(feature = "f427", feature = "f429", feature = "f469"))] {
+                /// `FLASH_OPTCR1`; bank 2 nWRP. Bank 1's nWRP field is in `FLASH_OPTCR`.
+                const WRP_REGS: [usize; 1] = [0x18];
+                const BFB2: u32 = 1 << 4;
+            } else {
+                const WRP_REGS: [usize; 0] = [];
+            }
+        }
+        const WRP_SECTOR_MASK: u32 = 0xfff;
+    } else if #[cfg(feature = "h7")] {
+        const OPTKEYR: usize = 0x08;
+        const OPTCR: usize = 0x18;
+        const OPTSR_CUR: usize = 0x1c;
+        const OPTSR_PRG: usize = 0x20;
+        const OPTCCR: usize = 0x24;
+        const BOOT_CURR: usize = 0x40;
+        const BOOT_PRGR: usize = 0x44;
+
+        const OPTCR_OPTLOCK: u32 = 1 << 0;
+        const OPTCR_OPTSTART: u32 = 1 << 1;
+        const OPTSR_OPT_BUSY: u32 = 1 << 0;
+        const OPTSR_OPTCHANGEERR: u32 = 1 << 30;
+        /// Status bits in `OPTSR_CUR` that are reserved in `OPTSR_PRG`.
+        const OPTSR_STATUS: u32 = OPTSR_OPT_BUSY | OPTSR_OPTCHANGEERR;
+
+        const RDP_SHIFT: u32 = 8;
+        const BOR_SHIFT: u32 = 2;
+
+        cfg_if! {
+            if #[cfg(feature = "h735")] {
+                /// (Current, program) `FLASH_WPSN` registers, for each bank.
+                const WRP_REGS: [(usize, usize); 1] = [(0x38, 0x3c)];
+            } else {
+                const WRP_REGS: [(usize, usize); 2] = [(0x38, 0x3c), (0x138, 0x13c)];
+                const BFB2: u32 = 1 << 31;
+            }
+        }
+
+        cfg_if! {
+            if #[cfg(feature = "h7b3")] {
+                // One bit per group of 4 sectors.
+                const WRP_SECTOR_MASK: u32 = 0xffff_ffff;
+            } else {
+                const WRP_SECTOR_MASK: u32 = 0xff;
+            }
+        }
+    } else {
+        const OPTKEYR: usize = 0x0c;
+        const SR: usize = 0x10;
+        const CR: usize = 0x14;
+        const OPTR: usize = 0x20;
+
+        const SR_BSY: u32 = 1 << 16;
+        const SR_OPTVERR: u32 = 1 << 15;
+        const CR_OPTSTRT: u32 = 1 << 17;
+        const CR_OBL_LAUNCH: u32 = 1 << 27;
+        const CR_OPTLOCK: u32 = 1 << 30;
+
+        const RDP_SHIFT: u32 = 0;
+
+        cfg_if! {
+            if #[cfg(any(feature = "l4x5", feature = "l4x6", feature = "g473", feature = "g474", feature = "g483", feature = "g484"))] {
+                /// `FLASH_WRP1AR`, `FLASH_WRP1BR`, `FLASH_WRP2AR`, and `FLASH_WRP2BR`.
+                const WRP_REGS: [usize; 4] = [0x2c, 0x30, 0x4c, 0x50];
+                const BFB2: u32 = 1 << 20;
+            } else if #[cfg(any(feature = "g0b1", feature = "g0c1"))] {
+                const WRP_REGS: [usize; 4] = [0x2c, 0x30, 0x4c, 0x50];
+            } else {
+                /// `FLASH_WRP1AR`, and `FLASH_WRP1BR`.
+                const WRP_REGS: [usize; 2] = [0x2c, 0x30];
+            }
+        }
+
+        // The width of the WRP area start and end page fields.
+        cfg_if! {
+            if #[cfg(any(feature = "g0b1", feature = "g0c1", feature = "g4"))] {
+                const WRP_PAGE_MASK: u32 = 0x7f;
+            } else if #[cfg(feature = "g0")] {
+                const WRP_PAGE_MASK: u32 = 0x3f;
+            } else {
+                const WRP_PAGE_MASK: u32 = 0xff;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Readout protection level.
+pub enum RdpLevel {
+    /// No protection.
+    L0,
+    /// Flash memory can't be read by a debugger, or when booting from RAM or system memory.
+    /// Returning to level 0 mass-erases flash memory.
+    L1,
+    /// **Permanent**. As level 1, and debug access and option byte changes are disabled.
+    L2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Brown-out reset threshold. See the datasheet for voltages. L4, G4, WB, and WL support
+/// levels 0 - 4, and can't disable BOR. G0 supports off, and levels 0 - 3. F4 and H7 support off,
+/// and levels 1 - 3.
+pub enum BorLevel {
+    Off,
+    Level0,
+    Level1,
+    Level2,
+    Level3,
+    Level4,
+}
+
+#[cfg(not(any(feature = "f4", feature = "h7", feature = "l4x5", feature = "l4x6")))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Selects what sets BOOT0, used to select the boot memory.
+pub enum Boot0Source {
+    /// The BOOT0 pin.
+    Pin,
+    /// The nBOOT0 option bit. The value here is the resulting BOOT0 state; `false` boots from
+    /// flash memory.
+    OptionBit(bool),
+}
+
+#[cfg(not(any(feature = "f4", feature = "h7")))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Each bank has two write protection areas.
+pub enum WrpArea {
+    A,
+    B,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Option byte values. Read these with `Flash::read_options()`, and write them with
+/// `Flash::program_options()`. Fields are raw register values; use the methods to read and modify
+/// them.
+pub struct OptionBytes {
+    /// `FLASH_OPTR` on most families, `FLASH_OPTCR` on F4, and `FLASH_OPTSR` on H7.
+    pub user: u32,
+    /// Write protection: `FLASH_WRPxyR` on most families, `FLASH_OPTCR1` on F4 dual-bank variants,
+    /// and `FLASH_WPSNx` on H7.
+    pub wrp: [u32; WRP_REGS.len()],
+    #[cfg(feature = "h7")]
+    /// `FLASH_BOOT`; boot addresses.
+    pub boot: u32,
+}
+
+impl OptionBytes {
+    /// The readout protection level.
+    pub fn rdp(&self) -> RdpLevel {
+        match (self.user >> RDP_SHIFT) & 0xff {
+            0xaa => RdpLevel::L0,
+            0xcc => RdpLevel::L2,
+            _ => RdpLevel::L1,
+        }
+    }
+
+    /// Set the readout protection level. **Warning**: Level 2 is permanent. Changing from level 1 to
+    /// level 0 mass-erases flash memory.
+    pub fn set_rdp(&mut self, level: RdpLevel) {
+        let val = match level {
+            RdpLevel::L0 => 0xaa,
+            RdpLevel::L1 => 0xbb,
+            RdpLevel::L2 => 0xcc,
+        };
+        self.user = (self.user & !(0xff << RDP_SHIFT)) | (val << RDP_SHIFT);
+    }
+
+    /// The brown-out reset threshold.
+    pub fn bor_level(&self) -> BorLevel {
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                match (self.user >> BOR_SHIFT) & 0b11 {
+                    0b11 => BorLevel::Off,
+                    0b10 => BorLevel::Level1,
+                    0b01 => BorLevel::Level2,
+                    _ => BorLevel::Level3,
+                }
+            } else if #[cfg(feature = "h7")] {
+                match (self.user >> BOR_SHIFT) & 0b11 {
+                    0b00 => BorLevel::Off,
+                    0b01 => BorLevel::Level1,
+                    0b10 => BorLevel::Level2,
+                    _ => BorLevel::Level3,
+                }
+            } else if #[cfg(feature = "g0")] {
+                // BOREN, and BORF_LEV. (The rising threshold, BORR_LEV, is set to match.)
+                if self.user & (1 << 8) == 0 {
+                    return BorLevel::Off;
+                }
+                match (self.user >> 9) & 0b11 {
+                    0 => BorLevel::Level0,
+                    1 => BorLevel::Level1,
+                    2 => BorLevel::Level2,
+                    _ => BorLevel::Level3,
+                }
+            } else {
+                match (self.user >> 8) & 0b111 {
+                    0 => BorLevel::Level0,
+                    1 => BorLevel::Level1,
+                    2 => BorLevel::Level2,
+                    3 => BorLevel::Level3,
+                    _ => BorLevel::Level4,
+                }
+            }
+        }
+    }
+
+    /// Set the brown-out reset threshold. Returns `Error::Illegal` if the level isn't supported on
+    /// this family.
+    pub fn set_bor_level(&mut self, level: BorLevel) -> Result<(), Error> {
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                let val = match level {
+                    BorLevel::Off => 0b11,
+                    BorLevel::Level1 => 0b10,
+                    BorLevel::Level2 => 0b01,
+                    BorLevel::Level3 => 0b00,
+                    _ => return Err(Error::Illegal),
+                };
+                self.user = (self.user & !(0b11 << BOR_SHIFT)) | (val << BOR_SHIFT);
+            } else if #[cfg(feature = "h7")] {
+                let val = match level {
+                    BorLevel::Off => 0b00,
+                    BorLevel::Level1 => 0b01,
+                    BorLevel::Level2 => 0b10,
+                    BorLevel::Level3 => 0b11,
+                    _ => return Err(Error::Illegal),
+                };
+                self.user = (self.user & !(0b11 << BOR_SHIFT)) | (val << BOR_SHIFT);
+            } else if #[cfg(feature = "g0")] {
+                // BOREN in bit 8, BORF_LEV in bits 9:10, and BORR_LEV in bits 11:12. The rising threshold
+                // is set to the same level as the falling one.
+                let val = match level {
+                    BorLevel::Off => 0,
+                    BorLevel::Level0 => 1,
+                    BorLevel::Level1 => 1 | (1 << 1) | (1 << 3),
+                    BorLevel::Level2 => 1 | (2 << 1) | (2 << 3),
+                    BorLevel::Level3 => 1 | (3 << 1) | (3 << 3),
+                    _ => return Err(Error::Illegal),
+                };
+                self.user = (self.user & !(0b11111 << 8)) | (val << 8);
+            } else {
+                let val = match level {
+                    BorLevel::Level0 => 0,
+                    BorLevel::Level1 => 1,
+                    BorLevel::Level2 => 2,
+                    BorLevel::Level3 => 3,
+                    BorLevel::Level4 => 4,
+                    _ => return Err(Error::Illegal),
+                };
+                self.user = (self.user & !(0b111 << 8)) | (val << 8);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(feature = "f4", feature = "h7", feature = "l4x5", feature = "l4x6")))]
+    /// The BOOT0 source: The pin, or the nBOOT0 option bit.
+    pub fn boot0(&self) -> Boot0Source {
+        // On G0, nBOOT_SEL (bit 24) is set to use the option bit, and nBOOT0 is bit 26. On others,
+        // nSWBOOT0 (bit 26) is cleared to use the option bit, and nBOOT0 is bit 27.
+        cfg_if! {
+            if #[cfg(feature = "g0")] {
+                let use_option = self.user & (1 << 24) != 0;
+                let n_boot0 = self.user & (1 << 26) != 0;
+            } else {
+                let use_option = self.user & (1 << 26) == 0;
+                let n_boot0 = self.user & (1 << 27) != 0;
+            }
+        }
+
+        if use_option {
+            Boot0Source::OptionBit(!n_boot0)
+        } else {
+            Boot0Source::Pin
+        }
+    }
+
+    #[cfg(not(any(feature = "f4", feature = "h7", feature = "l4x5", feature = "l4x6")))]
+    /// Set the BOOT0 source. **Warning**: Setting BOOT0 high with the option bit boots from system
+    /// memory or SRAM, not your program, regardless of the pin.
+    pub fn set_boot0(&mut self, source: Boot0Source) {
+        cfg_if! {
+            if #[cfg(feature = "g0")] {
+                let (sel_bit, boot0_bit) = (24, 26);
+            } else {
+                let (sel_bit, boot0_bit) = (26, 27);
+            }
+        }
+
+        match source {
+            Boot0Source::Pin => {
+                #[cfg(feature = "g0")]
+                {
+                    self.user &= !(1 << sel_bit);
+                }
+                #[cfg(not(feature = "g0"))]
+                {
+                    self.user |= 1 << sel_bit;
+                }
+            }
+            Boot0Source::OptionBit(boot0) => {
+                #[cfg(feature = "g0")]
+                {
+                    self.user |= 1 << sel_bit;
+                }
+                #[cfg(not(feature = "g0"))]
+                {
+                    self.user &= !(1 << sel_bit);
+                }
+                if boot0 {
+                    self.user &= !(1 << boot0_bit);
+                } else {
+                    self.user |= 1 << boot0_bit;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "h7")]
+    /// The boot address, for BOOT0 pin state `boot0`.
+    pub fn boot_address(&self, boot0: bool) -> u32 {
+        let shift = if boot0 { 16 } else { 0 };
+        ((self.boot >> shift) & 0xffff) << 16
+    }
+
+    #[cfg(feature = "h7")]
+    /// Set the boot address, for BOOT0 pin state `boot0`. The address's lower 16 bits are ignored.
+    /// **Warning**: If there's no valid program at this address, the MCU won't start.
+    pub fn set_boot_address(&mut self, boot0: bool, address: u32) {
+        let shift = if boot0 { 16 } else { 0 };
+        self.boot = (self.boot & !(0xffff << shift)) | ((address >> 16) << shift);
+    }
+
+    #[cfg(any(
+        feature = "l4x5",
+        feature = "l4x6",
+        feature = "g473",
+        feature = "g474",
+        feature = "g483",
+        feature = "g484",
+        feature = "f427",
+        feature = "f429",
+        feature = "f469",
+        all(feature = "h7", not(feature = "h735"))
+    ))]
+    /// Returns true if the banks are swapped; ie booting from bank 2. (BFB2, or SWAP_BANK_OPT on H7)
+    pub fn bank_swap(&self) -> bool {
+        self.user & BFB2 != 0
+    }
+
+    #[cfg(any(
+        feature = "l4x5",
+        feature = "l4x6",
+        feature = "g473",
+        feature = "g474",
+        feature = "g483",
+        feature = "g484",
+        feature = "f427",
+        feature = "f429",
+        feature = "f469",
+        all(feature = "h7", not(feature = "h735"))
+    ))]
+    /// Set bank swapping; eg to boot a firmware update written to bank 2. **Warning**: Bank 2 must
+    /// contain a valid program.
+    pub fn set_bank_swap(&mut self, swap: bool) {
+        if swap {
+            self.user |= BFB2;
+        } else {
+            self.user &= !BFB2;
+        }
+    }
+
+    #[cfg(not(any(feature = "f4", feature = "h7")))]
+    /// A write protection area's (start, end) pages, inclusive, relative to its bank. Returns `None`
+    /// if the area is disabled, or doesn't exist on this variant.
+    pub fn wrp(&self, bank: Bank, area: WrpArea) -> Option<(u8, u8)> {
+        let reg = *self.wrp.get(wrp_index(bank, area))?;
+        let start = reg & WRP_PAGE_MASK;
+        let end = (reg >> 16) & WRP_PAGE_MASK;
+
+        if start > end {
+            None
+        } else {
+            Some((start as u8, end as u8))
+        }
+    }
+
+    #[cfg(not(any(feature = "f4", feature = "h7")))]
+    /// Set a write protection area's (start, end) pages, inclusive, relative to its bank. Use
+    /// `None` to disable the area. Returns `Error::Illegal` if the area doesn't exist on this
+    /// variant, or the pages are out of range.
+    pub fn set_wrp(
+        &mut self,
+        bank: Bank,
+        area: WrpArea,
+        pages: Option<(u8, u8)>,
+    ) -> Result<(), Error> {
+        // An area is disabled when its start page is after its end page.
+        let (start, end) = pages.unwrap_or((WRP_PAGE_MASK as u8, 0));
+
+        if start as u32 > WRP_PAGE_MASK || end as u32 > WRP_PAGE_MASK {
+            return Err(Error::Illegal);
+        }
+
+        let reg = self
+            .wrp
+            .get_mut(wrp_index(bank, area))
+            .ok_or(Error::Illegal)?;
+
+        let mask = WRP_PAGE_MASK | (WRP_PAGE_MASK << 16);
+        *reg = (*reg & !mask) | start as u32 | ((end as u32) << 16);
+
+        Ok(())
+    }
+
+    #[cfg(any(feature = "f4", feature = "h7"))]
+    /// Write-protected sectors in a bank; a bit set for each protected sector. (On H7B3, each bit is
+    /// a group of 4 sectors.) Returns 0 for a bank this variant doesn't have.
+    pub fn wrp_sectors(&self, bank: Bank) -> u32 {
+        // Option bits are active low: Cleared for protected sectors.
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                let n_wrp = match bank {
+                    Bank::B1 => Some(self.user >> 16),
+                    Bank::B2 => self.wrp.first().map(|r| r >> 16),
+                };
+            } else {
+                let n_wrp = self.wrp.get(bank as usize).copied();
+            }
+        }
+
+        n_wrp.map(|r| !r & WRP_SECTOR_MASK).unwrap_or(0)
+    }
+
+    #[cfg(any(feature = "f4", feature = "h7"))]
+    /// Set write-protected sectors in a bank; a bit set for each sector to protect. Returns
+    /// `Error::Illegal` for a bank this variant doesn't have.
+    pub fn set_wrp_sectors(&mut self, bank: Bank, sectors: u32) -> Result<(), Error> {
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                let (reg, shift) = match bank {
+                    Bank::B1 => (&mut self.user, 16),
+                    Bank::B2 => (self.wrp.first_mut().ok_or(Error::Illegal)?, 16),
+                };
+            } else {
+                let (reg, shift) = (self.wrp.get_mut(bank as usize).ok_or(Error::Illegal)?, 0);
+            }
+        }
+
+        let mask = WRP_SECTOR_MASK << shift;
+        *reg = (*reg & !mask) | ((!sectors << shift) & mask);
+        Ok(())
+    }
+}
+
+#[cfg(not(any(feature = "f4", feature = "h7")))]
+/// The index of an area in `OptionBytes::wrp`. On single-bank variants, bank 2 indices are out of
+/// range.
+fn wrp_index(bank: Bank, area: WrpArea) -> usize {
+    bank as usize * 2 + area as usize
+}
+
+impl Flash {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        unsafe { (&*self.regs as *const _ as *mut u8).add(offset) as *mut u32 }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.reg(offset)) }
+    }
+
+    fn write_reg(&mut self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(offset), val) }
+    }
+
+    /// Read the option bytes currently in effect.
+    pub fn read_options(&self) -> OptionBytes {
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                OptionBytes {
+                    user: self.read_reg(OPTCR) & !(OPTCR_OPTLOCK | OPTCR_OPTSTRT),
+                    wrp: WRP_REGS.map(|r| self.read_reg(r)),
+                }
+            } else if #[cfg(feature = "h7")] {
+                OptionBytes {
+                    user: self.read_reg(OPTSR_CUR) & !OPTSR_STATUS,
+                    wrp: WRP_REGS.map(|(cur, _)| self.read_reg(cur)),
+                    boot: self.read_reg(BOOT_CURR),
+                }
+            } else {
+                OptionBytes {
+                    user: self.read_reg(OPTR),
+                    wrp: WRP_REGS.map(|r| self.read_reg(r)),
+                }
+            }
+        }
+    }
+
+    /// Unlock the option registers, for programming. `program_options()` and `launch_options()`
+    /// do this automatically. On families other than F4 and H7, this also unlocks the `CR` register,
+    /// which must be unlocked first.
+    pub fn unlock_options(&mut self) -> Result<(), Error> {
+        cfg_if! {
+            if #[cfg(any(feature = "f4", feature = "h7"))] {
+                let (cr, lock) = (OPTCR, OPTCR_OPTLOCK);
+            } else {
+                self.unlock()?;
+                let (cr, lock) = (CR, CR_OPTLOCK);
+            }
+        }
+
+        if self.read_reg(cr) & lock == 0 {
+            return Ok(());
+        }
+
+        self.write_reg(OPTKEYR, FLASH_OPT_KEY1);
+        self.write_reg(OPTKEYR, FLASH_OPT_KEY2);
+
+        if self.read_reg(cr) & lock == 0 {
+            Ok(())
+        } else {
+            Err(Error::Failure)
+        }
+    }
+
+    /// Lock the option registers.
+    pub fn lock_options(&mut self) {
+        cfg_if! {
+            if #[cfg(any(feature = "f4", feature = "h7"))] {
+                let (cr, lock) = (OPTCR, OPTCR_OPTLOCK);
+            } else {
+                let (cr, lock) = (CR, CR_OPTLOCK);
+            }
+        }
+
+        let val = self.read_reg(cr);
+        self.write_reg(cr, val | lock);
+    }
+
+    /// Program option bytes. These take effect after `launch_options()`, or a power-on reset.
+    /// Locks the option registers when complete.
+    ///
+    /// **Warning**: Programming RDP level 2 is permanent. Changing RDP from level 1 to level 0
+    /// mass-erases flash memory, including the running program; this function won't return.
+    pub fn program_options(&mut self, options: &OptionBytes) -> Result<(), Error> {
+        self.unlock_options()?;
+
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                while self.read_reg(SR) & SR_BSY != 0 {}
+                self.write_reg(SR, SR_ERRORS);
+
+                for (reg, val) in WRP_REGS.iter().zip(options.wrp) {
+                    self.write_reg(*reg, val);
+                }
+                // Keep OPTLOCK and OPTSTRT clear; start programming as a separate write.
+                let user = options.user & !(OPTCR_OPTLOCK | OPTCR_OPTSTRT);
+                self.write_reg(OPTCR, user);
+                self.write_reg(OPTCR, user | OPTCR_OPTSTRT);
+
+                while self.read_reg(SR) & SR_BSY != 0 {}
+                let result = if self.read_reg(SR) & SR_ERRORS != 0 {
+                    Err(Error::Illegal)
+                } else {
+                    Ok(())
+                };
+            } else if #[cfg(feature = "h7")] {
+                while self.read_reg(OPTSR_CUR) & OPTSR_OPT_BUSY != 0 {}
+                self.write_reg(OPTCCR, OPTSR_OPTCHANGEERR);
+
+                self.write_reg(OPTSR_PRG, options.user & !OPTSR_STATUS);
+                for ((_, prg), val) in WRP_REGS.iter().zip(options.wrp) {
+                    self.write_reg(*prg, val);
+                }
+                self.write_reg(BOOT_PRGR, options.boot);
+
+                let cr = self.read_reg(OPTCR);
+                self.write_reg(OPTCR, cr | OPTCR_OPTSTART);
+
+                while self.read_reg(OPTSR_CUR) & OPTSR_OPT_BUSY != 0 {}
+                let result = if self.read_reg(OPTSR_CUR) & OPTSR_OPTCHANGEERR != 0 {
+                    self.write_reg(OPTCCR, OPTSR_OPTCHANGEERR);
+                    Err(Error::Illegal)
+                } else {
+                    Ok(())
+                };
+            } else {
+                while self.read_reg(SR) & SR_BSY != 0 {}
+                self.write_reg(SR, SR_ERRORS | SR_OPTVERR);
+
+                self.write_reg(OPTR, options.user);
+                for (reg, val) in WRP_REGS.iter().zip(options.wrp) {
+                    self.write_reg(*reg, val);
+                }
+
+                let cr = self.read_reg(CR);
+                self.write_reg(CR, cr | CR_OPTSTRT);
+
+                while self.read_reg(SR) & SR_BSY != 0 {}
+                let result = if self.read_reg(SR) & (SR_ERRORS | SR_OPTVERR) != 0 {
+                    Err(Error::Illegal)
+                } else {
+                    Ok(())
+                };
+            }
+        }
+
+        self.lock_options();
+        result
+    }
+
+    /// Load programmed option bytes. This resets the MCU, so doesn't return unless there's an error.
+    /// (On F4 and H7, this uses a system reset; on others, it sets OBL_LAUNCH.)
+    pub fn launch_options(&mut self) -> Result<(), Error> {
+        cfg_if! {
+            if #[cfg(any(feature = "f4", feature = "h7"))] {
+                cortex_m::peripheral::SCB::sys_reset();
+            } else {
+                self.unlock_options()?;
+
+                let cr = self.read_reg(CR);
+                self.write_reg(CR, cr | CR_OBL_LAUNCH);
+
+                // The reset is immediate; we shouldn't reach this.
+                Err(Error::Failure)
+            }
+        }
+    }
+}
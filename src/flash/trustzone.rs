@@ -32,7 +32,8 @@ pub enum Bank {
     B2 = 1,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Possible error states for flash operations.
 pub enum Error {
     /// Flash controller is not done yet
@@ -360,6 +361,15 @@ impl Flash {
                         )
                     };
 
+                    unsafe {
+                        // Write a first word in an address aligned with double word
+                        core::ptr::write_volatile(address, word1);
+                        address = address.add(1);
+                        // Write the second word
+                        core::ptr::write_volatile(address, word2);
+                        address = address.add(1);
+                    }
+
                     // 5. Wait until the BSY bit is cleared in the FLASH_NSSR register.
                     while self.regs.nssr.read().nsbsy().bit_is_set() {}
 
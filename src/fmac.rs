@@ -1,28 +1,550 @@
-//! This module supports the Filter Match ACcelerator (FMAC) peripheral, which
-//! allows for hardware processing of digital filters such as FIR and IIR.
+//! This module supports the Filter Math ACcelerator (FMAC) peripheral, which
+//! allows for hardware processing of digital filters such as FIR and IIR. Available on G4, and
+//! H723/H725/H730/H733/H735.
+//!
+//! The FMAC operates on q1.15 fixed point values, in a 256-word local memory. This holds three
+//! circular buffers: X1 (input samples), X2 (coefficients), and Y (output samples). The layout is
+//! set up automatically from the filter, and the `headroom` config field.
+//!
+//! Once started, the filter runs whenever there are new input samples, and room for results.
+//! Feed it by polling (`run`), interrupts (`Write` and `Read`, which fire based on the
+//! watermarks), or DMA, so filters can run entirely off-CPU.
+//!
+//! Example, running a biquad low-pass filter:
+//! ```rust
+//! let mut fmac = Fmac::new(dp.FMAC, Default::default());
+//!
+//! // q1.15 coefficients. Feedback coefficients are negated, compared to the usual form.
+//! let b = [2_980, 5_960, 2_980];
+//! let a = [18_904, -6_074];
+//!
+//! // A gain of 2^1, since the coefficients were scaled by 1/2 to fit in q1.15.
+//! fmac.set_iir(&b, &a, 1).unwrap();
+//! fmac.start();
+//!
+//! fmac.run(&input, &mut output).unwrap();
+//! ```
 
-// todo: Is this fixed point only?
+use cfg_if::cfg_if;
 
-use crate::pac::{FMAC};
+use crate::{
+    dma::{self, ChannelCfg, DmaChannel},
+    pac::{DMA1, FMAC, RCC},
+    util::rcc_en_reset,
+    MAX_ITERS,
+};
 
+/// The size of the FMAC local memory, in 16-bit words.
+pub const MEM_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// FMAC errors.
+pub enum FmacError {
+    /// The filter and headroom don't fit in local memory, or are longer than the function
+    /// supports.
+    BufferSize,
+    /// A value was written with X1 full. (OVFL)
+    Overflow,
+    /// A value was read with Y empty. (UNFL)
+    Underflow,
+    /// An output saturated, with clipping disabled. (SAT)
+    Saturation,
+    /// Timed out waiting for the peripheral.
+    Timeout,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+/// The FMAC function. Sets PARAM, FUNC.
+enum Function {
+    LoadX1 = 1,
+    LoadX2 = 2,
+    LoadY = 3,
+    Convolution = 8,
+    IirDirectForm1 = 9,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// Buffer watermarks. For X1: the write interrupt and DMA request fire while at least this many
+/// spaces are free. For Y: the read interrupt and DMA request fire while at least this many
+/// samples are unread. Sets X1BUFCFG, FULL_WM and YBUFCFG, EMPTY_WM.
+pub enum Watermark {
+    W1 = 0b00,
+    W2 = 0b01,
+    W4 = 0b10,
+    W8 = 0b11,
+}
+
+impl Watermark {
+    fn count(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// FMAC interrupts. Sets CR.
+pub enum FmacInterrupt {
+    /// X1 has room for more input samples, per the X1 watermark. (WIEN)
+    Write,
+    /// Y has output samples to read, per the Y watermark. (RIEN)
+    Read,
+    /// A value was written with X1 full. (OVFLIEN)
+    Overflow,
+    /// A value was read with Y empty. (UNFLIEN)
+    Underflow,
+    /// An output saturated. (SATIEN)
+    Saturation,
+}
+
+#[derive(Clone, Copy, Default)]
+/// The local memory layout. Bases and sizes are in 16-bit words.
+pub struct BufferLayout {
+    pub x1_base: u8,
+    pub x1_size: u8,
+    pub x2_base: u8,
+    pub x2_size: u8,
+    pub y_base: u8,
+    pub y_size: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// FMAC configuration. Can be used with `Default::default()`.
+pub struct FmacConfig {
+    /// X1 watermark. Defaults to 1.
+    pub x1_watermark: Watermark,
+    /// Y watermark. Defaults to 1.
+    pub y_watermark: Watermark,
+    /// Extra space in the X1 and Y buffers, beyond what the filter requires, in samples. More
+    /// allows more samples to be written, or read, per interrupt or DMA burst. Must be at least
+    /// the watermarks. Defaults to 4.
+    pub headroom: u8,
+    /// Saturate outputs, instead of wrapping. Defaults to enabled.
+    pub clip: bool,
+}
+
+impl Default for FmacConfig {
+    fn default() -> Self {
+        Self {
+            x1_watermark: Watermark::W1,
+            y_watermark: Watermark::W1,
+            headroom: 4,
+            clip: true,
+        }
+    }
+}
+
+/// Represents a Filter Math Accelerator (FMAC) peripheral.
 pub struct Fmac {
     pub regs: FMAC,
+    pub cfg: FmacConfig,
+    pub layout: BufferLayout,
 }
 
 impl Fmac {
-    /// Create a struct used to perform operations on Flash.
-    pub fn new(regs: FMAC) -> Self {
-        // todo: Implement and configure dual bank mode.
-        Self { regs }
+    /// Initialize the FMAC peripheral, including enabling and resetting its RCC peripheral clock.
+    pub fn new(regs: FMAC, cfg: FmacConfig) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+
+        cfg_if! {
+            if #[cfg(feature = "g4")] {
+                rcc_en_reset!(ahb1, fmac, rcc);
+            } else {
+                rcc_en_reset!(ahb2, fmac, rcc);
+            }
+        }
+
+        regs.cr.modify(|_, w| w.clipen().bit(cfg.clip));
+
+        Self {
+            regs,
+            cfg,
+            layout: Default::default(),
+        }
     }
 
-    /// Set up a Finite Impulse Response (FIR) filter.
-    fn run_fir(&mut self, coeffs: &[f32], data: &mut [f32]) {
+    /// Set up a Finite Impulse Response (FIR) filter, with q1.15 coefficients, and a gain of
+    /// 2^`gain`; 0 - 7. Loads the coefficients, and sets up the buffers. Run `start` afterwards.
+    pub fn set_fir(&mut self, coeffs: &[i16], gain: u8) -> Result<(), FmacError> {
+        // RM: P (the number of coefficients) is 2 - 127 for convolution.
+        if coeffs.len() < 2 || coeffs.len() > 127 {
+            return Err(FmacError::BufferSize);
+        }
+        let num_coeffs = coeffs.len() as u8;
 
+        // X1 holds the last `num_coeffs` inputs.
+        self.set_layout(num_coeffs, num_coeffs, 0)?;
+        self.load(Function::LoadX2, coeffs, 0)?;
+
+        self.set_param(Function::Convolution, num_coeffs, 0, gain);
+        Ok(())
+    }
+
+    /// Set up an Infinite Impulse Response (IIR) filter, using direct form 1, with a gain of
+    /// 2^`gain`; 0 - 7. `b` are the feed-forward coefficients, and `a` the feedback ones,
+    /// excluding a0, in q1.15. Feedback coefficients are the negation of the usual form:
+    /// y[n] = 2^gain * (Σ b[k] x[n - k] + Σ a[k] y[n - k]). Eg for a biquad, `b` has 3 values,
+    /// and `a` 2. Run `start` afterwards.
+    pub fn set_iir(&mut self, b: &[i16], a: &[i16], gain: u8) -> Result<(), FmacError> {
+        // RM: P is 2 - 64, and Q is 1 - P - 1 for IIR.
+        if b.len() < 2 || b.len() > 64 || a.is_empty() || a.len() >= b.len() {
+            return Err(FmacError::BufferSize);
+        }
+        let (p, q) = (b.len() as u8, a.len() as u8);
+
+        // X1 holds the last `p` inputs, and Y the last `q` outputs.
+        self.set_layout(p + q, p, q)?;
+
+        // Coefficients are loaded with one operation: b, then a.
+        self.regs.param.write(|w| unsafe {
+            w.func().bits(Function::LoadX2 as u8);
+            w.p().bits(p);
+            w.q().bits(q);
+            w.start().set_bit()
+        });
+        for val in b.iter().chain(a.iter()) {
+            self.write_unchecked(*val);
+        }
+        wait(|| self.regs.param.read().start().bit_is_clear())?;
+
+        self.set_param(Function::IirDirectForm1, p, q, gain);
+        Ok(())
+    }
+
+    /// Set the local memory layout: X2, then X1, then Y, from the start of memory.
+    fn set_layout(&mut self, x2_size: u8, x1_len: u8, y_len: u8) -> Result<(), FmacError> {
+        let headroom = self
+            .cfg
+            .headroom
+            .max(self.cfg.x1_watermark.count())
+            .max(self.cfg.y_watermark.count());
+
+        let x1_size = x1_len as usize + headroom as usize;
+        let y_size = y_len as usize + headroom as usize;
+
+        if x2_size as usize + x1_size + y_size > MEM_SIZE {
+            return Err(FmacError::BufferSize);
+        }
+
+        let layout = BufferLayout {
+            x2_base: 0,
+            x2_size,
+            x1_base: x2_size,
+            x1_size: x1_size as u8,
+            y_base: x2_size + x1_size as u8,
+            y_size: y_size as u8,
+        };
+
+        self.regs.x2bufcfg.write(|w| unsafe {
+            w.x2_base().bits(layout.x2_base);
+            w.x2_buf_size().bits(layout.x2_size)
+        });
+        self.regs.x1bufcfg.write(|w| unsafe {
+            w.x1_base().bits(layout.x1_base);
+            w.x1_buf_size().bits(layout.x1_size);
+            w.full_wm().bits(self.cfg.x1_watermark as u8)
+        });
+        self.regs.ybufcfg.write(|w| unsafe {
+            w.y_base().bits(layout.y_base);
+            w.y_buf_size().bits(layout.y_size);
+            w.empty_wm().bits(self.cfg.y_watermark as u8)
+        });
+
+        self.layout = layout;
+        Ok(())
+    }
+
+    /// Preload a buffer, using one of the load functions.
+    fn load(&mut self, function: Function, vals: &[i16], q: u8) -> Result<(), FmacError> {
+        self.regs.param.write(|w| unsafe {
+            w.func().bits(function as u8);
+            w.p().bits(vals.len() as u8);
+            w.q().bits(q);
+            w.start().set_bit()
+        });
+        for val in vals {
+            self.write_unchecked(*val);
+        }
+        // START is cleared by hardware once all values are written.
+        wait(|| self.regs.param.read().start().bit_is_clear())
     }
 
-    /// Set up an Infinite Impulse Response (IIR) filter.
-    fn run_iir(&mut self, coeffs: &[f32], data: &mut [f32]) {
+    /// Preload the X1 buffer with initial input samples, eg from a previous block. Run after
+    /// `set_fir` or `set_iir`, and before `start`.
+    pub fn preload_x1(&mut self, vals: &[i16]) -> Result<(), FmacError> {
+        if vals.len() > self.layout.x1_size as usize {
+            return Err(FmacError::BufferSize);
+        }
+        let param = self.regs.param.read().bits();
+        self.load(Function::LoadX1, vals, 0)?;
+        self.regs.param.write(|w| unsafe { w.bits(param) });
+        Ok(())
+    }
 
+    /// Preload the Y buffer with initial output samples; the IIR filter state. Run after
+    /// `set_iir`, and before `start`.
+    pub fn preload_y(&mut self, vals: &[i16]) -> Result<(), FmacError> {
+        if vals.len() > self.layout.y_size as usize {
+            return Err(FmacError::BufferSize);
+        }
+        let param = self.regs.param.read().bits();
+        self.load(Function::LoadY, vals, 0)?;
+        self.regs.param.write(|w| unsafe { w.bits(param) });
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Set the filter function, without starting it.
+    fn set_param(&mut self, function: Function, p: u8, q: u8, gain: u8) {
+        assert!(gain <= 7, "Gain must be 0 - 7.");
+
+        self.regs.param.write(|w| unsafe {
+            w.func().bits(function as u8);
+            w.p().bits(p);
+            w.q().bits(q);
+            w.r().bits(gain)
+        });
+    }
+
+    /// Start the filter set up with `set_fir` or `set_iir`. It processes input samples as they're
+    /// written.
+    pub fn start(&mut self) {
+        self.regs.param.modify(|_, w| w.start().set_bit());
+    }
+
+    /// Stop the filter. The buffers keep their contents.
+    pub fn stop(&mut self) {
+        self.regs.param.modify(|_, w| w.start().clear_bit());
+    }
+
+    /// Reset the peripheral's read and write pointers, and disable the filter. Buffer
+    /// configuration and contents are kept, but the filter must be set up again.
+    pub fn reset(&mut self) -> Result<(), FmacError> {
+        self.regs.cr.modify(|_, w| w.reset().set_bit());
+        wait(|| self.regs.cr.read().reset().bit_is_clear())
+    }
+
+    fn write_unchecked(&mut self, val: i16) {
+        self.regs
+            .wdata
+            .write(|w| unsafe { w.wdata().bits(val as u16) });
+    }
+
+    /// Write an input sample. Returns an error if X1 is full.
+    pub fn write(&mut self, val: i16) -> Result<(), FmacError> {
+        if self.regs.sr.read().x1full().bit_is_set() {
+            return Err(FmacError::Overflow);
+        }
+        self.write_unchecked(val);
+        Ok(())
+    }
+
+    /// Read an output sample, if one is available.
+    pub fn read(&mut self) -> Option<i16> {
+        if self.regs.sr.read().yempty().bit_is_set() {
+            return None;
+        }
+        Some(self.regs.rdata.read().bits() as i16)
+    }
+
+    /// Filter a block of samples, by polling. `output` must be the same length as `input`.
+    pub fn run(&mut self, input: &[i16], output: &mut [i16]) -> Result<(), FmacError> {
+        let (mut i_in, mut i_out) = (0, 0);
+        let mut idle = 0;
+
+        while i_out < output.len() {
+            let sr = self.regs.sr.read();
+            if sr.sat().bit_is_set() {
+                return Err(FmacError::Saturation);
+            }
+
+            let mut progress = false;
+
+            if i_in < input.len() && sr.x1full().bit_is_clear() {
+                self.write_unchecked(input[i_in]);
+                i_in += 1;
+                progress = true;
+            }
+
+            if sr.yempty().bit_is_clear() {
+                output[i_out] = self.regs.rdata.read().bits() as i16;
+                i_out += 1;
+                progress = true;
+            }
+
+            if progress {
+                idle = 0;
+            } else {
+                idle += 1;
+                if idle >= MAX_ITERS {
+                    return Err(FmacError::Timeout);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check for, and clear, an error flag. Overflow and underflow flags are cleared by reset.
+    pub fn check_errors(&mut self) -> Result<(), FmacError> {
+        let sr = self.regs.sr.read();
+        if sr.ovfl().bit_is_set() {
+            Err(FmacError::Overflow)
+        } else if sr.unfl().bit_is_set() {
+            Err(FmacError::Underflow)
+        } else if sr.sat().bit_is_set() {
+            Err(FmacError::Saturation)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Enable a specific type of FMAC interrupt.
+    pub fn enable_interrupt(&mut self, interrupt: FmacInterrupt) {
+        self.regs.cr.modify(|_, w| match interrupt {
+            FmacInterrupt::Write => w.wien().set_bit(),
+            FmacInterrupt::Read => w.rien().set_bit(),
+            FmacInterrupt::Overflow => w.ovflien().set_bit(),
+            FmacInterrupt::Underflow => w.unflien().set_bit(),
+            FmacInterrupt::Saturation => w.satien().set_bit(),
+        });
+    }
+
+    /// Disable a specific type of FMAC interrupt.
+    pub fn disable_interrupt(&mut self, interrupt: FmacInterrupt) {
+        self.regs.cr.modify(|_, w| match interrupt {
+            FmacInterrupt::Write => w.wien().clear_bit(),
+            FmacInterrupt::Read => w.rien().clear_bit(),
+            FmacInterrupt::Overflow => w.ovflien().clear_bit(),
+            FmacInterrupt::Underflow => w.unflien().clear_bit(),
+            FmacInterrupt::Saturation => w.satien().clear_bit(),
+        });
+    }
+
+    /// Stream input samples to the FMAC using DMA, paced by the X1 watermark. Use with `read_dma`
+    /// on a second channel to collect the output. Set the channel's input to
+    /// `DmaInput::FmacWrite` with `dma::mux()`.
+    ///
+    /// # Safety
+    /// `input` must remain valid, and not be moved, until the transfer is complete.
+    pub unsafe fn write_dma(
+        &mut self,
+        input: &[i16],
+        channel: DmaChannel,
+        channel_cfg: ChannelCfg,
+        dma_periph: dma::DmaPeriph,
+    ) {
+        let (ptr, len) = (input.as_ptr(), input.len());
+
+        self.regs.cr.modify(|_, w| w.dmawen().set_bit());
+
+        let periph_addr = &self.regs.wdata as *const _ as u32;
+
+        cfg_dma(
+            channel,
+            periph_addr,
+            ptr as u32,
+            len,
+            dma::Direction::ReadFromMem,
+            channel_cfg,
+            dma_periph,
+        );
+    }
+
+    /// Stream output samples from the FMAC using DMA, paced by the Y watermark. Set the
+    /// channel's input to `DmaInput::FmacRead` with `dma::mux()`.
+    ///
+    /// # Safety
+    /// `output` must remain valid, and not be accessed, until the transfer is complete.
+    pub unsafe fn read_dma(
+        &mut self,
+        output: &mut [i16],
+        channel: DmaChannel,
+        channel_cfg: ChannelCfg,
+        dma_periph: dma::DmaPeriph,
+    ) {
+        let (ptr, len) = (output.as_mut_ptr(), output.len());
+
+        self.regs.cr.modify(|_, w| w.dmaren().set_bit());
+
+        let periph_addr = &self.regs.rdata as *const _ as u32;
+
+        cfg_dma(
+            channel,
+            periph_addr,
+            ptr as u32,
+            len,
+            dma::Direction::ReadFromPeriph,
+            channel_cfg,
+            dma_periph,
+        );
+    }
+
+    /// Disable DMA requests, eg once a transfer is complete.
+    pub fn stop_dma(&mut self) {
+        self.regs.cr.modify(|_, w| {
+            w.dmawen().clear_bit();
+            w.dmaren().clear_bit()
+        });
+    }
+}
+
+fn cfg_dma(
+    channel: DmaChannel,
+    periph_addr: u32,
+    mem_addr: u32,
+    len: usize,
+    direction: dma::Direction,
+    channel_cfg: ChannelCfg,
+    dma_periph: dma::DmaPeriph,
+) {
+    #[cfg(feature = "h7")]
+    let len = len as u32;
+    #[cfg(not(feature = "h7"))]
+    let len = len as u16;
+
+    match dma_periph {
+        dma::DmaPeriph::Dma1 => {
+            let mut regs = unsafe { &(*DMA1::ptr()) };
+            dma::cfg_channel(
+                &mut regs,
+                channel,
+                periph_addr,
+                mem_addr,
+                len,
+                direction,
+                dma::DataSize::S16,
+                dma::DataSize::S16,
+                channel_cfg,
+            );
+        }
+        dma::DmaPeriph::Dma2 => {
+            let mut regs = unsafe { &(*crate::pac::DMA2::ptr()) };
+            dma::cfg_channel(
+                &mut regs,
+                channel,
+                periph_addr,
+                mem_addr,
+                len,
+                direction,
+                dma::DataSize::S16,
+                dma::DataSize::S16,
+                channel_cfg,
+            );
+        }
+    }
+}
+
+fn wait(done: impl Fn() -> bool) -> Result<(), FmacError> {
+    let mut i = 0;
+    while !done() {
+        i += 1;
+        if i >= MAX_ITERS {
+            return Err(FmacError::Timeout);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,569 @@
+//! Support for the Flexible Memory Controller (FMC): External NOR Flash, SRAM, and PSRAM on the
+//! NOR/SRAM banks, and SDRAM. Timing is set from memory datasheet parameters in nanoseconds, using
+//! the current HCLK. Each init function returns the memory-mapped region; eg for use as an
+//! external framebuffer.
+//!
+//! Available on F427, F429, F446, F469, and H7. Set up the address, data, and control pins in
+//! alternate function mode; AF12 on most variants, with a very high output speed.
+//!
+//! Example, for an IS42S16400J 16-bit SDRAM, as on the F429 discovery board:
+//! ```rust
+//! let mut fmc = Fmc::new(dp.FMC);
+//!
+//! let cfg = SdramConfig {
+//!     bank: SdramBank::B2,
+//!     column_bits: 8,
+//!     row_bits: 12,
+//!     data_width: MemWidth::W16,
+//!     cas_latency: 3,
+//!     ..Default::default()
+//! };
+//!
+//! let sdram = fmc.init_sdram(&cfg, &clock_cfg)?;
+//! let fb: &'static mut [u16] = unsafe { sdram.as_slice() };
+//! ```
+//!
+//! On H7, the default memory attributes for the SDRAM region (0xC000_0000 - 0xDFFF_FFFF) are
+//! Device, which doesn't allow unaligned access. Configure it as Normal memory using the MPU, before
+//! using it for general data.
+
+use core::slice;
+
+use cortex_m::asm;
+
+use crate::{
+    clocks::Clocks,
+    pac::{FMC, RCC},
+    util::rcc_en_reset,
+};
+
+#[cfg(feature = "f4")]
+use crate::MAX_ITERS;
+
+// Register offsets. We access registers directly, since SDRAM registers are grouped differently
+// between the F4 and H7 PACs. See F429 RM, section 37.8, and H743 RM, section 22.9.
+const BCR1: usize = 0x00;
+const SDCR1: usize = 0x140;
+const SDTR1: usize = 0x148;
+const SDCMR: usize = 0x150;
+const SDRTR: usize = 0x154;
+const SDSR: usize = 0x158;
+
+/// BCRx: Bit 7 is reserved, and must be kept at its reset value of 1.
+const BCR_RESERVED: u32 = 1 << 7;
+const BCR_MBKEN: u32 = 1 << 0;
+const BCR_MUXEN: u32 = 1 << 1;
+const BCR_FACCEN: u32 = 1 << 6;
+const BCR_WREN: u32 = 1 << 12;
+#[cfg(feature = "h7")]
+/// Bit 31 of BCR1 enables the whole FMC, on H7.
+const BCR1_FMCEN: u32 = 1 << 31;
+
+#[cfg(feature = "f4")]
+const SDSR_BUSY: u32 = 1 << 5;
+
+/// The base address of the NOR/SRAM banks. Each of the 4 sub-banks is 64MB.
+const NOR_SRAM_BASE: usize = 0x6000_0000;
+const NOR_SRAM_BANK_SIZE: usize = 0x0400_0000;
+
+/// The base addresses of SDRAM banks 1 and 2, with the default mapping.
+const SDRAM_BASE: [usize; 2] = [0xc000_0000, 0xd000_0000];
+
+/// The number of auto-refresh commands issued during SDRAM initialization.
+const SDRAM_INIT_REFRESHES: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// FMC errors.
+pub enum FmcError {
+    /// A timing parameter doesn't fit in its register field, at the current clock speed.
+    Timing,
+    /// A configuration value is out of range; eg the number of SDRAM rows.
+    Config,
+    /// The SDRAM controller didn't accept a command in time.
+    Hardware,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// Memory data bus width. Sets the BCRx, and SDCRx registers, MWID fields.
+pub enum MemWidth {
+    W8 = 0b00,
+    W16 = 0b01,
+    W32 = 0b10,
+}
+
+impl MemWidth {
+    fn bytes(&self) -> usize {
+        match self {
+            Self::W8 => 1,
+            Self::W16 => 2,
+            Self::W32 => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// A NOR/SRAM sub-bank, selected by the NE1 - NE4 chip select pins.
+pub enum NorSramBank {
+    /// 0x6000_0000
+    B1 = 0,
+    /// 0x6400_0000
+    B2 = 1,
+    /// 0x6800_0000
+    B3 = 2,
+    /// 0x6c00_0000
+    B4 = 3,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// NOR/SRAM memory type. Sets the BCRx register, MTYP field.
+pub enum MemType {
+    Sram = 0b00,
+    Psram = 0b01,
+    NorFlash = 0b10,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Asynchronous NOR/SRAM timing, in nanoseconds; from the memory's datasheet.
+pub struct NorSramTiming {
+    /// Address setup time. Sets the BTRx register, ADDSET field.
+    pub address_setup_ns: u32,
+    /// Address hold time; used with a multiplexed address/data bus. Sets the ADDHLD field.
+    pub address_hold_ns: u32,
+    /// Data setup time; ie the read or write strobe width. Sets the DATAST field.
+    pub data_setup_ns: u32,
+    /// The delay between consecutive accesses; eg to allow the memory to release the data bus.
+    /// Sets the BUSTURN field.
+    pub bus_turnaround_ns: u32,
+}
+
+impl Default for NorSramTiming {
+    fn default() -> Self {
+        Self {
+            address_setup_ns: 10,
+            address_hold_ns: 10,
+            data_setup_ns: 60,
+            bus_turnaround_ns: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// NOR Flash, SRAM, or PSRAM configuration. Uses asynchronous access, in mode 1.
+pub struct NorSramConfig {
+    pub bank: NorSramBank,
+    pub mem_type: MemType,
+    pub data_width: MemWidth,
+    /// Address and data are multiplexed on the data pins, using the NL (NADV) pin. Defaults to false.
+    pub mux_addr_data: bool,
+    /// Allow writes. Defaults to true.
+    pub write_enable: bool,
+    pub timing: NorSramTiming,
+}
+
+impl Default for NorSramConfig {
+    fn default() -> Self {
+        Self {
+            bank: NorSramBank::B1,
+            mem_type: MemType::Sram,
+            data_width: MemWidth::W16,
+            mux_addr_data: false,
+            write_enable: true,
+            timing: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// An SDRAM bank, selected by the SDNE0 and SDCKE0, or SDNE1 and SDCKE1 pins.
+pub enum SdramBank {
+    /// 0xc000_0000
+    B1 = 0,
+    /// 0xd000_0000
+    B2 = 1,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// The SDRAM clock, as a division of HCLK. Sets the SDCR1 register, SDCLK field.
+pub enum SdClockDiv {
+    Div2 = 0b10,
+    Div3 = 0b11,
+}
+
+impl SdClockDiv {
+    fn value(&self) -> u32 {
+        match self {
+            Self::Div2 => 2,
+            Self::Div3 => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// SDRAM timing, from the memory's datasheet. Times are in nanoseconds, unless specified as clock
+/// cycles.
+pub struct SdramTiming {
+    /// Load Mode Register to Active delay, in clock cycles. Sets the SDTRx register, TMRD field.
+    pub t_mrd_clks: u8,
+    /// Exit self-refresh delay. Sets the TXSR field.
+    pub t_xsr_ns: u32,
+    /// Row active time; the minimum active to precharge time. Sets the TRAS field.
+    pub t_ras_ns: u32,
+    /// Row cycle delay. Sets the TRC field.
+    pub t_rc_ns: u32,
+    /// Write recovery time, in clock cycles. Sets the TWR field.
+    pub t_wr_clks: u8,
+    /// Row precharge delay. Sets the TRP field.
+    pub t_rp_ns: u32,
+    /// Row to column delay. Sets the TRCD field.
+    pub t_rcd_ns: u32,
+    /// The period in which all rows must be refreshed, in milliseconds. Usually 64.
+    pub refresh_period_ms: u32,
+}
+
+impl Default for SdramTiming {
+    /// Timing for IS42S16400J-7 SDRAM; typical of 143Mhz parts.
+    fn default() -> Self {
+        Self {
+            t_mrd_clks: 2,
+            t_xsr_ns: 70,
+            t_ras_ns: 42,
+            t_rc_ns: 63,
+            t_wr_clks: 2,
+            t_rp_ns: 15,
+            t_rcd_ns: 15,
+            refresh_period_ms: 64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// SDRAM configuration. Note that the SDRAM clock, read burst, and read pipe settings are shared by
+/// both banks, so if using both, initialize them with the same values.
+pub struct SdramConfig {
+    pub bank: SdramBank,
+    /// The number of column address bits; 8 - 11.
+    pub column_bits: u8,
+    /// The number of row address bits; 11 - 13.
+    pub row_bits: u8,
+    pub data_width: MemWidth,
+    /// True for 4 internal banks, or false for 2. Defaults to true.
+    pub four_internal_banks: bool,
+    /// CAS latency, in clock cycles; 1 - 3.
+    pub cas_latency: u8,
+    pub clock_div: SdClockDiv,
+    /// Anticipate the next read commands during bursts. Defaults to true.
+    pub read_burst: bool,
+    /// Delay for reading data after CAS latency, in HCLK cycles; 0 - 2. Defaults to 0 (Note that
+    /// ST's examples use 1 at high clock speeds).
+    pub read_pipe_delay: u8,
+    pub timing: SdramTiming,
+}
+
+impl Default for SdramConfig {
+    fn default() -> Self {
+        Self {
+            bank: SdramBank::B1,
+            column_bits: 8,
+            row_bits: 12,
+            data_width: MemWidth::W16,
+            four_internal_banks: true,
+            cas_latency: 3,
+            clock_div: SdClockDiv::Div2,
+            read_burst: true,
+            read_pipe_delay: 0,
+            timing: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// SDRAM commands. Sets the SDCMR register, MODE field.
+enum SdramCommand {
+    ClockEnable = 0b001,
+    PrechargeAll = 0b010,
+    AutoRefresh = 0b011,
+    LoadModeRegister = 0b100,
+}
+
+#[derive(Clone, Copy)]
+/// A memory-mapped external memory region.
+pub struct MemRegion {
+    pub ptr: *mut u8,
+    /// The region's size, in bytes. For NOR/SRAM banks, this is the size of the address window;
+    /// the memory itself may be smaller.
+    pub len: usize,
+}
+
+impl MemRegion {
+    /// Access the region as a slice of `T`; eg `u16` for an RGB565 framebuffer.
+    ///
+    /// # Safety
+    /// Only create one slice per region; this doesn't check aliasing. The memory must have been
+    /// initialized by `Fmc`, and supports accesses of `T`'s size.
+    pub unsafe fn as_slice<T>(&self) -> &'static mut [T] {
+        slice::from_raw_parts_mut(self.ptr as *mut T, self.len / core::mem::size_of::<T>())
+    }
+}
+
+/// Convert a time in nanoseconds to clock cycles, rounding up.
+fn ns_to_clks(ns: u32, freq: u32) -> u32 {
+    ((ns as u64 * freq as u64).div_ceil(1_000_000_000)) as u32
+}
+
+/// Check a value fits in a register field, returning it.
+fn check(val: u32, min: u32, max: u32) -> Result<u32, FmcError> {
+    if val > max {
+        Err(FmcError::Timing)
+    } else {
+        Ok(val.max(min))
+    }
+}
+
+/// Represents the Flexible Memory Controller.
+pub struct Fmc {
+    pub regs: FMC,
+}
+
+impl Fmc {
+    /// Initialize the FMC peripheral, including enabling and resetting its RCC peripheral clock.
+    pub fn new(regs: FMC) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+        rcc_en_reset!(ahb3, fmc, rcc);
+
+        Self { regs }
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        unsafe { (FMC::ptr() as *mut u8).add(offset) as *mut u32 }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.reg(offset)) }
+    }
+
+    fn write_reg(&self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(offset), val) }
+    }
+
+    #[cfg(feature = "h7")]
+    /// Enable the FMC, once banks are configured. This is shared by all banks.
+    fn enable(&mut self) {
+        self.write_reg(BCR1, self.read_reg(BCR1) | BCR1_FMCEN);
+    }
+
+    /// Configure a NOR/SRAM bank for asynchronous access, and enable it. On H7, this assumes the
+    /// FMC kernel clock is HCLK; the default.
+    pub fn init_nor_sram(
+        &mut self,
+        cfg: &NorSramConfig,
+        clocks: &Clocks,
+    ) -> Result<MemRegion, FmcError> {
+        let hclk = clocks.hclk();
+        let t = &cfg.timing;
+
+        // F429 RM, section 37.5.4: NOR Flash/PSRAM controller timing diagrams, mode 1.
+        let addset = check(ns_to_clks(t.address_setup_ns, hclk), 0, 15)?;
+        let addhld = check(ns_to_clks(t.address_hold_ns, hclk), 1, 15)?;
+        let datast = check(ns_to_clks(t.data_setup_ns, hclk), 1, 255)?;
+        let busturn = check(ns_to_clks(t.bus_turnaround_ns, hclk), 0, 15)?;
+
+        let bank = cfg.bank as usize;
+        let bcr = BCR1 + bank * 8;
+        let btr = bcr + 4;
+
+        // CLKDIV and DATLAT are unused for asynchronous access, but must be non-zero.
+        self.write_reg(
+            btr,
+            addset | (addhld << 4) | (datast << 8) | (busturn << 16) | (0xf << 20) | (0xf << 24),
+        );
+
+        // Preserve bits only set in BCR1, which are shared by all banks; eg FMCEN on H7.
+        let shared = if bank == 0 {
+            self.read_reg(BCR1) & 0xff00_0000
+        } else {
+            0
+        };
+
+        let mut val = shared
+            | BCR_RESERVED
+            | BCR_MBKEN
+            | ((cfg.mem_type as u32) << 2)
+            | ((cfg.data_width as u32) << 4);
+        if cfg.mux_addr_data {
+            val |= BCR_MUXEN;
+        }
+        if cfg.mem_type == MemType::NorFlash {
+            val |= BCR_FACCEN;
+        }
+        if cfg.write_enable {
+            val |= BCR_WREN;
+        }
+        self.write_reg(bcr, val);
+
+        #[cfg(feature = "h7")]
+        self.enable();
+
+        Ok(MemRegion {
+            ptr: (NOR_SRAM_BASE + bank * NOR_SRAM_BANK_SIZE) as *mut u8,
+            len: NOR_SRAM_BANK_SIZE,
+        })
+    }
+
+    /// Configure an SDRAM bank, and run the SDRAM initialization sequence: Clock enable, precharge
+    /// all, auto-refresh, and load mode register. Then, set the refresh rate. On H7, this assumes
+    /// the FMC kernel clock is HCLK; the default.
+    pub fn init_sdram(
+        &mut self,
+        cfg: &SdramConfig,
+        clocks: &Clocks,
+    ) -> Result<MemRegion, FmcError> {
+        if !(8..=11).contains(&cfg.column_bits)
+            || !(11..=13).contains(&cfg.row_bits)
+            || !(1..=3).contains(&cfg.cas_latency)
+            || cfg.read_pipe_delay > 2
+        {
+            return Err(FmcError::Config);
+        }
+
+        let hclk = clocks.hclk();
+        let sdclk = hclk / cfg.clock_div.value();
+        let t = &cfg.timing;
+
+        // Timing fields are the number of cycles, minus 1.
+        let clks = |ns| check(ns_to_clks(ns, sdclk), 1, 16);
+        let tmrd = check(t.t_mrd_clks as u32, 1, 16)?;
+        let txsr = clks(t.t_xsr_ns)?;
+        let tras = clks(t.t_ras_ns)?;
+        let trc = clks(t.t_rc_ns)?;
+        let trp = clks(t.t_rp_ns)?;
+        let trcd = clks(t.t_rcd_ns)?;
+        // F429 RM, section 37.8.4: TWR must be at least TRAS - TRCD, and TRC - TRCD - TRP.
+        let twr = check(t.t_wr_clks as u32, 1, 16)?
+            .max(tras.saturating_sub(trcd))
+            .max(trc.saturating_sub(trcd + trp));
+
+        let bank = cfg.bank as usize;
+
+        // SDCLK, RBURST, and RPIPE are only set in SDCR1, and TRC and TRP in SDTR1, for both banks.
+        let shared_cr = ((cfg.clock_div as u32) << 10)
+            | ((cfg.read_burst as u32) << 12)
+            | ((cfg.read_pipe_delay as u32) << 13);
+        let shared_tr = ((trc - 1) << 12) | ((trp - 1) << 20);
+
+        let sdcr = (cfg.column_bits as u32 - 8)
+            | ((cfg.row_bits as u32 - 11) << 2)
+            | ((cfg.data_width as u32) << 4)
+            | ((cfg.four_internal_banks as u32) << 6)
+            | ((cfg.cas_latency as u32) << 7);
+        let sdtr = (tmrd - 1)
+            | ((txsr - 1) << 4)
+            | ((tras - 1) << 8)
+            | ((twr - 1) << 16)
+            | ((trcd - 1) << 24);
+
+        if bank == 0 {
+            self.write_reg(SDCR1, sdcr | shared_cr);
+            self.write_reg(SDTR1, sdtr | shared_tr);
+        } else {
+            self.write_reg(SDCR1, (self.read_reg(SDCR1) & !(0x1f << 10)) | shared_cr);
+            self.write_reg(SDTR1, (self.read_reg(SDTR1) & !(0xf0f << 12)) | shared_tr);
+            self.write_reg(SDCR1 + 4, sdcr);
+            self.write_reg(SDTR1 + 4, sdtr);
+        }
+
+        #[cfg(feature = "h7")]
+        self.enable();
+
+        // F429 RM, section 37.7.3: SDRAM initialization.
+        self.sdram_command(cfg.bank, SdramCommand::ClockEnable, 0)?;
+        // Wait at least 100us, with the clock running. `delay` waits at least this many CPU cycles,
+        // and the CPU clock is at least HCLK.
+        asm::delay(hclk / 10_000);
+
+        self.sdram_command(cfg.bank, SdramCommand::PrechargeAll, 0)?;
+        self.sdram_command(cfg.bank, SdramCommand::AutoRefresh, 0)?;
+
+        // Mode register: Burst length 1, sequential, CAS latency, standard operation, and single
+        // location write access.
+        let mode = ((cfg.cas_latency as u32) << 4) | (1 << 9);
+        self.sdram_command(cfg.bank, SdramCommand::LoadModeRegister, mode)?;
+
+        // The refresh rate, in SDCLK cycles, with a margin of 20 cycles for refresh requests that
+        // occur during reads.
+        let refresh =
+            (sdclk as u64 * t.refresh_period_ms as u64 / (1_000 * (1u64 << cfg.row_bits))) as u32;
+        let count = check(refresh.saturating_sub(20), 41, 0x1fff)?;
+        self.write_reg(SDRTR, count << 1);
+
+        let banks = if cfg.four_internal_banks { 4 } else { 2 };
+        Ok(MemRegion {
+            ptr: SDRAM_BASE[bank] as *mut u8,
+            len: (1 << (cfg.column_bits + cfg.row_bits)) * banks * cfg.data_width.bytes(),
+        })
+    }
+
+    /// Send a command to an SDRAM bank.
+    fn sdram_command(
+        &mut self,
+        bank: SdramBank,
+        command: SdramCommand,
+        mode_reg: u32,
+    ) -> Result<(), FmcError> {
+        // CTB1 is bit 4, and CTB2 is bit 3.
+        let target = match bank {
+            SdramBank::B1 => 1 << 4,
+            SdramBank::B2 => 1 << 3,
+        };
+        let nrfs = if command == SdramCommand::AutoRefresh {
+            SDRAM_INIT_REFRESHES - 1
+        } else {
+            0
+        };
+
+        self.write_reg(
+            SDCMR,
+            command as u32 | target | (nrfs << 5) | (mode_reg << 9),
+        );
+
+        // H7 doesn't have a busy flag; commands are accepted immediately.
+        #[cfg(feature = "f4")]
+        {
+            let mut i = 0;
+            while self.read_reg(SDSR) & SDSR_BUSY != 0 {
+                i += 1;
+                if i >= MAX_ITERS {
+                    return Err(FmcError::Hardware);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if an SDRAM refresh error occurred, and clears the flag. This indicates the
+    /// refresh rate is too low for the memory access pattern.
+    pub fn sdram_refresh_error(&mut self) -> bool {
+        let error = self.read_reg(SDSR) & 1 != 0;
+        if error {
+            // CRE
+            self.write_reg(SDRTR, self.read_reg(SDRTR) | 1);
+        }
+        error
+    }
+}
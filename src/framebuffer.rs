@@ -0,0 +1,417 @@
+//! A double-buffered rendering helper for the LTDC display path. One framebuffer (the front buffer)
+//! is scanned out by the LTDC while you draw into the other (the back buffer), using the CPU, or
+//! the DMA2D for clears and blits. `swap()` schedules the back buffer to be displayed at the next
+//! vertical blanking period, so updates never tear.
+//!
+//! Frame pacing: After calling `swap()`, the back buffer is unavailable until the LTDC has reloaded
+//! its shadow registers; `back()` returns `None` until then. Call `poll_swap()` (eg from the LTDC
+//! `RegisterReload` interrupt handler, or your render loop), or `wait_for_swap()`, to complete the swap.
+//! This limits rendering to the display's refresh rate.
+//!
+//! With the `embedded_graphics` feature, `draw_target()` returns an `embedded-graphics` `DrawTarget`
+//! for the back buffer, for RGB565 or ARGB8888 framebuffers. Solid fills use the DMA2D, if passed.
+//!
+//! Example:
+//! ```rust
+//! let mut fbs = Framebuffers::new(&mut FB_A, &mut FB_B, 480, 272, ColorMode::Rgb565, Layer::L1);
+//! fbs.show_front(&mut ltdc);
+//!
+//! loop {
+//!     fbs.clear(&mut dma2d, 0x0000).ok();
+//!     draw_ui(fbs.back().unwrap()); // eg with an `embedded-graphics` or Slint target
+//!     fbs.swap(&mut ltdc);
+//!     fbs.wait_for_swap(&mut ltdc);
+//! }
+//! ```
+
+use core::mem;
+
+#[cfg(feature = "embedded_graphics")]
+use embedded_graphics_core::{
+    draw_target::DrawTarget,
+    geometry::{OriginDimensions, Size},
+    pixelcolor::{IntoStorage, PixelColor, Rgb565, Rgb888},
+    primitives::Rectangle,
+    Pixel,
+};
+
+use crate::{
+    dma2d::{ColorMode, Dma2d, Dma2dError, Dma2dOutput},
+    ltdc::{Layer, Ltdc},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Errors associated with framebuffer operations.
+pub enum FramebufferError {
+    /// A swap was requested, but the LTDC hasn't yet displayed the new front buffer, so the back buffer
+    /// may still be scanned out.
+    SwapPending,
+    /// The requested region doesn't fit in the framebuffer, or source image.
+    OutOfBounds,
+    Dma2d(Dma2dError),
+}
+
+impl From<Dma2dError> for FramebufferError {
+    fn from(e: Dma2dError) -> Self {
+        Self::Dma2d(e)
+    }
+}
+
+/// A pair of framebuffers for one LTDC layer. `P` is the pixel type; eg `u16` for RGB565, or
+/// `u32` for ARGB8888.
+pub struct Framebuffers<'a, P> {
+    bufs: [&'a mut [P]; 2],
+    /// Index in `bufs` of the buffer currently being displayed.
+    front: usize,
+    swap_pending: bool,
+    pub width: u16,
+    pub height: u16,
+    pub color_mode: ColorMode,
+    pub layer: Layer,
+}
+
+impl<'a, P: Copy> Framebuffers<'a, P> {
+    /// Create a framebuffer pair. Each buffer must hold at least `width` x `height` pixels, and the pixel
+    /// type must match `color_mode`'s size. This doesn't configure the LTDC; run `show_front()` once
+    /// the layer is set up.
+    pub fn new(
+        buf_a: &'a mut [P],
+        buf_b: &'a mut [P],
+        width: u16,
+        height: u16,
+        color_mode: ColorMode,
+        layer: Layer,
+    ) -> Self {
+        let len = width as usize * height as usize;
+        assert!(
+            buf_a.len() >= len && buf_b.len() >= len,
+            "Framebuffers must hold width x height pixels."
+        );
+        assert_eq!(
+            mem::size_of::<P>(),
+            color_mode.bytes_per_pixel(),
+            "Pixel type size must match the color mode."
+        );
+
+        Self {
+            bufs: [buf_a, buf_b],
+            front: 0,
+            swap_pending: false,
+            width,
+            height,
+            color_mode,
+            layer,
+        }
+    }
+
+    /// Point the LTDC layer at the front buffer immediately, eg during initialization.
+    pub fn show_front(&mut self, ltdc: &mut Ltdc) {
+        ltdc.set_framebuffer(self.layer, self.bufs[self.front].as_ptr() as u32);
+        ltdc.reload_now();
+    }
+
+    /// The buffer to draw into, or `None` if a swap is pending.
+    pub fn back(&mut self) -> Option<&mut [P]> {
+        if self.swap_pending {
+            return None;
+        }
+        Some(&mut *self.bufs[1 - self.front])
+    }
+
+    /// The buffer currently being displayed.
+    pub fn front(&self) -> &[P] {
+        &*self.bufs[self.front]
+    }
+
+    /// Returns true if a swap was requested, and hasn't completed.
+    pub fn swap_pending(&self) -> bool {
+        self.swap_pending
+    }
+
+    /// Fill the back buffer with a single color, using the DMA2D. `color` is in the buffers'
+    /// color format. Blocks until complete.
+    pub fn clear(&mut self, dma2d: &mut Dma2d, color: u32) -> Result<(), FramebufferError> {
+        self.fill_rect(dma2d, 0, 0, self.width, self.height, color)
+    }
+
+    /// Fill a rectangle in the back buffer with a single color, using the DMA2D. Blocks until complete.
+    pub fn fill_rect(
+        &mut self,
+        dma2d: &mut Dma2d,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        color: u32,
+    ) -> Result<(), FramebufferError> {
+        self.check_bounds(x, y, width, height)?;
+        let dest = Dma2dOutput {
+            addr: self.back_ptr(x, y)?,
+            line_offset: self.width - width,
+            color_mode: self.color_mode,
+        };
+
+        dma2d.fill(&dest, width, height, color);
+        Ok(dma2d.wait()?)
+    }
+
+    /// Copy an image of `src_width` pixels per line into the back buffer, with its top-left corner
+    /// at (`x`, `y`), using the DMA2D. `src` must use the same color format as the framebuffers.
+    /// Blocks until complete.
+    pub fn blit(
+        &mut self,
+        dma2d: &mut Dma2d,
+        src: &[P],
+        src_width: u16,
+        x: u16,
+        y: u16,
+    ) -> Result<(), FramebufferError> {
+        if src_width == 0 || !src.len().is_multiple_of(src_width as usize) {
+            return Err(FramebufferError::OutOfBounds);
+        }
+        let src_height = u16::try_from(src.len() / src_width as usize)
+            .map_err(|_| FramebufferError::OutOfBounds)?;
+        self.check_bounds(x, y, src_width, src_height)?;
+        let dest = Dma2dOutput {
+            addr: self.back_ptr(x, y)?,
+            line_offset: self.width - src_width,
+            color_mode: self.color_mode,
+        };
+
+        dma2d.copy(
+            src.as_ptr() as *const u8,
+            0,
+            self.color_mode,
+            &dest,
+            src_width,
+            src_height,
+        );
+        Ok(dma2d.wait()?)
+    }
+
+    /// Copy the front buffer to the back buffer, using the DMA2D. Use this before drawing if you
+    /// only update part of the screen each frame. Blocks until complete.
+    pub fn copy_front_to_back(&mut self, dma2d: &mut Dma2d) -> Result<(), FramebufferError> {
+        let dest = Dma2dOutput {
+            addr: self.back_ptr(0, 0)?,
+            line_offset: 0,
+            color_mode: self.color_mode,
+        };
+
+        dma2d.copy(
+            self.bufs[self.front].as_ptr() as *const u8,
+            0,
+            self.color_mode,
+            &dest,
+            self.width,
+            self.height,
+        );
+        Ok(dma2d.wait()?)
+    }
+
+    #[cfg(feature = "embedded_graphics")]
+    /// An `embedded-graphics` draw target for the back buffer, or `None` if a swap is pending. `C`
+    /// is the `embedded-graphics` color type matching the framebuffer's format; eg `Rgb565` for `u16`
+    /// pixels. If `dma2d` is passed, it's used for solid fills.
+    pub fn draw_target<'d, C>(
+        &mut self,
+        dma2d: Option<&'d mut Dma2d>,
+    ) -> Option<GraphicsTarget<'_, 'd, C>>
+    where
+        C: FramebufferColor<Pixel = P>,
+    {
+        let (width, height) = (self.width, self.height);
+        self.back()
+            .map(|buf| GraphicsTarget::new(buf, width, height, dma2d))
+    }
+
+    /// Schedule the back buffer to be displayed at the next vertical blanking period. Complete the swap
+    /// with `poll_swap()` or `wait_for_swap()`. Make sure any DMA2D transfers to the back buffer are
+    /// complete, and on H7, clean the D-cache if enabled, before running this.
+    pub fn swap(&mut self, ltdc: &mut Ltdc) {
+        if self.swap_pending {
+            return;
+        }
+        ltdc.set_framebuffer(self.layer, self.bufs[1 - self.front].as_ptr() as u32);
+        ltdc.reload_on_vblank();
+        self.swap_pending = true;
+    }
+
+    /// Complete a pending swap if the LTDC has reloaded. Returns true if no swap is pending after
+    /// running this, ie the back buffer is available to draw into.
+    pub fn poll_swap(&mut self, ltdc: &Ltdc) -> bool {
+        if self.swap_pending && !ltdc.reload_pending() {
+            self.front = 1 - self.front;
+            self.swap_pending = false;
+        }
+        !self.swap_pending
+    }
+
+    /// Block until a pending swap completes.
+    pub fn wait_for_swap(&mut self, ltdc: &Ltdc) {
+        while !self.poll_swap(ltdc) {}
+    }
+
+    fn check_bounds(
+        &self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<(), FramebufferError> {
+        if x as u32 + width as u32 > self.width as u32
+            || y as u32 + height as u32 > self.height as u32
+        {
+            return Err(FramebufferError::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    /// The address of a pixel in the back buffer.
+    fn back_ptr(&mut self, x: u16, y: u16) -> Result<*mut u8, FramebufferError> {
+        if self.swap_pending {
+            return Err(FramebufferError::SwapPending);
+        }
+        let i = y as usize * self.width as usize + x as usize;
+        Ok(self.bufs[1 - self.front][i..].as_mut_ptr() as *mut u8)
+    }
+}
+
+#[cfg(feature = "embedded_graphics")]
+/// An `embedded-graphics` color that can be stored in a framebuffer.
+pub trait FramebufferColor: PixelColor {
+    /// The framebuffer's pixel type.
+    type Pixel: Copy;
+    /// The matching DMA2D color mode.
+    const COLOR_MODE: ColorMode;
+    /// Convert to the pixel value stored in the framebuffer.
+    fn to_pixel(self) -> Self::Pixel;
+    /// Convert to the pixel value, as used by `Dma2d::fill()`.
+    fn to_raw(self) -> u32;
+}
+
+#[cfg(feature = "embedded_graphics")]
+impl FramebufferColor for Rgb565 {
+    type Pixel = u16;
+    const COLOR_MODE: ColorMode = ColorMode::Rgb565;
+
+    fn to_pixel(self) -> u16 {
+        self.into_storage()
+    }
+
+    fn to_raw(self) -> u32 {
+        self.into_storage() as u32
+    }
+}
+
+#[cfg(feature = "embedded_graphics")]
+/// Stored as ARGB8888, with an opaque alpha.
+impl FramebufferColor for Rgb888 {
+    type Pixel = u32;
+    const COLOR_MODE: ColorMode = ColorMode::Argb8888;
+
+    fn to_pixel(self) -> u32 {
+        0xff00_0000 | self.into_storage()
+    }
+
+    fn to_raw(self) -> u32 {
+        self.to_pixel()
+    }
+}
+
+#[cfg(feature = "embedded_graphics")]
+/// An `embedded-graphics` draw target over a framebuffer. Pixels are drawn by the CPU; solid fills,
+/// including `clear()`, use the DMA2D if available.
+pub struct GraphicsTarget<'a, 'd, C: FramebufferColor> {
+    buf: &'a mut [C::Pixel],
+    width: u16,
+    height: u16,
+    dma2d: Option<&'d mut Dma2d>,
+}
+
+#[cfg(feature = "embedded_graphics")]
+impl<'a, 'd, C: FramebufferColor> GraphicsTarget<'a, 'd, C> {
+    /// Create a draw target over a framebuffer of at least `width` x `height` pixels. Eg for a
+    /// single-buffered display; use `Framebuffers::draw_target()` when double buffering.
+    pub fn new(
+        buf: &'a mut [C::Pixel],
+        width: u16,
+        height: u16,
+        dma2d: Option<&'d mut Dma2d>,
+    ) -> Self {
+        assert!(
+            buf.len() >= width as usize * height as usize,
+            "Framebuffers must hold width x height pixels."
+        );
+
+        Self {
+            buf,
+            width,
+            height,
+            dma2d,
+        }
+    }
+}
+
+#[cfg(feature = "embedded_graphics")]
+impl<C: FramebufferColor> OriginDimensions for GraphicsTarget<'_, '_, C> {
+    fn size(&self) -> Size {
+        Size::new(self.width as u32, self.height as u32)
+    }
+}
+
+#[cfg(feature = "embedded_graphics")]
+impl<C: FramebufferColor> DrawTarget for GraphicsTarget<'_, '_, C> {
+    type Color = C;
+    type Error = FramebufferError;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            // Pixels outside the framebuffer are discarded, as `DrawTarget` requires.
+            if point.x >= 0
+                && point.y >= 0
+                && (point.x as u32) < self.width as u32
+                && (point.y as u32) < self.height as u32
+            {
+                let i = point.y as usize * self.width as usize + point.x as usize;
+                self.buf[i] = color.to_pixel();
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&Rectangle::new(Default::default(), self.size()));
+        if area.size.width == 0 || area.size.height == 0 {
+            return Ok(());
+        }
+
+        let x = area.top_left.x as usize;
+        let y = area.top_left.y as usize;
+        let (w, h) = (area.size.width as usize, area.size.height as usize);
+        let start = y * self.width as usize + x;
+
+        match self.dma2d.as_deref_mut() {
+            Some(dma2d) => {
+                let dest = Dma2dOutput {
+                    addr: self.buf[start..].as_mut_ptr() as *mut u8,
+                    line_offset: self.width - w as u16,
+                    color_mode: C::COLOR_MODE,
+                };
+                dma2d.fill(&dest, w as u16, h as u16, color.to_raw());
+                Ok(dma2d.wait()?)
+            }
+            None => {
+                let pixel = color.to_pixel();
+                for line in self.buf[start..].chunks_mut(self.width as usize).take(h) {
+                    line[..w].fill(pixel);
+                }
+                Ok(())
+            }
+        }
+    }
+}
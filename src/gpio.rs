@@ -11,13 +11,26 @@
 
 #[cfg(feature = "embedded_hal")]
 use core::convert::Infallible;
-
+#[cfg(feature = "async")]
+use core::{
+    cell::RefCell,
+    future::Future,
+    task::{Context, Poll, Waker},
+};
+
+#[cfg(feature = "async")]
+use cortex_m::interrupt::{self, Mutex};
 #[cfg(feature = "embedded_hal")]
 use embedded_hal::digital::{ErrorType, InputPin, OutputPin, StatefulOutputPin};
+#[cfg(feature = "async")]
+use embedded_hal_async::digital::Wait;
 
-use crate::pac::{self, EXTI, RCC};
 #[cfg(not(feature = "h7"))]
 use crate::util::rcc_en_reset;
+use crate::{
+    exti,
+    pac::{self, RCC},
+};
 
 // #[cfg(not(any(
 //     // feature = "g0",
@@ -194,7 +207,7 @@ pub enum Port {
 
 impl Port {
     /// See F303 RM section 12.1.3: each reg has an associated value
-    fn cr_val(&self) -> u8 {
+    pub(crate) fn cr_val(&self) -> u8 {
         match self {
             Self::A => 0,
             Self::B => 1,
@@ -259,9 +272,150 @@ impl Port {
             Self::I => 8,
         }
     }
+
+    /// Look up a port by its letter, eg `'A'`, or `'a'`. Returns `None` if the port isn't
+    /// available on this MCU.
+    pub fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            'A' | 'a' => Some(Self::A),
+            'B' | 'b' => Some(Self::B),
+            #[cfg(not(feature = "wl"))]
+            'C' | 'c' => Some(Self::C),
+            #[cfg(not(any(feature = "f410", feature = "wl")))]
+            'D' | 'd' => Some(Self::D),
+            #[cfg(not(any(
+                feature = "f301",
+                feature = "f3x4",
+                feature = "f410",
+                feature = "g0",
+                feature = "wb",
+                feature = "wl"
+            )))]
+            'E' | 'e' => Some(Self::E),
+            #[cfg(not(any(
+                feature = "f401",
+                feature = "f410",
+                feature = "f411",
+                feature = "l4x1",
+                feature = "l4x2",
+                feature = "l412",
+                feature = "l4x3",
+                feature = "wb",
+                feature = "wl"
+            )))]
+            'F' | 'f' => Some(Self::F),
+            #[cfg(not(any(
+                feature = "f373",
+                feature = "f301",
+                feature = "f3x4",
+                feature = "f401",
+                feature = "f410",
+                feature = "f411",
+                feature = "l4x1",
+                feature = "l4x2",
+                feature = "l412",
+                feature = "l4x3",
+                feature = "g0",
+                feature = "wb",
+                feature = "wl"
+            )))]
+            'G' | 'g' => Some(Self::G),
+            #[cfg(not(any(
+                feature = "f373",
+                feature = "f301",
+                feature = "f3x4",
+                feature = "f410",
+                feature = "l4x1",
+                feature = "l4x2",
+                feature = "l412",
+                feature = "l4x3",
+                feature = "g0",
+                feature = "g4",
+                feature = "wb",
+                feature = "wl"
+            )))]
+            'H' | 'h' => Some(Self::H),
+            #[cfg(any(feature = "h747cm4", feature = "h747cm7", feature = "l4x6",))]
+            'I' | 'i' => Some(Self::I),
+            _ => None,
+        }
+    }
+
+    // The multi-pin operations below take a 16-bit mask, or field, with one bit per pin. They
+    // don't configure pins, or enable the port's clock; set up each pin with `Pin::new()` first.
+
+    /// Set the output voltage of multiple pins to high, with a single write. Bits set in `mask`
+    /// select pins; eg `0b1010` for pins 1 and 3. Sets the `BSRR` register. Atomic.
+    pub fn set_high(&self, mask: u16) {
+        self.set_reset(mask, 0);
+    }
+
+    /// Set the output voltage of multiple pins to low, with a single write. Sets the `BSRR`
+    /// register. Atomic.
+    pub fn set_low(&self, mask: u16) {
+        self.set_reset(0, mask);
+    }
+
+    /// Set the pins in `high` to high, and the pins in `low` to low, with a single write. If a
+    /// pin is in both, it's set high. Sets the `BSRR` register. Atomic.
+    pub fn set_reset(&self, high: u16, low: u16) {
+        unsafe {
+            (*regs(*self))
+                .bsrr
+                .write(|w| w.bits(high as u32 | ((low as u32) << 16)));
+        }
+    }
+
+    /// Set the output states of the pins selected by `mask` to the corresponding bits of
+    /// `value`. Other pins aren't affected. Sets the `BSRR` register. Atomic.
+    pub fn write_masked(&self, mask: u16, value: u16) {
+        self.set_reset(value & mask, !value & mask);
+    }
+
+    /// Toggle the output voltage of multiple pins. Reads the `ODR` register, then sets the `BSRR`
+    /// register. Other pins aren't affected, but this isn't atomic with respect to other writes
+    /// to these pins between the read, and write.
+    pub fn toggle(&self, mask: u16) {
+        let odr = self.read_output();
+        self.set_reset(!odr & mask, odr & mask);
+    }
+
+    /// Write a `width`-bit value to a contiguous group of pins starting at `shift`; eg 8 pins
+    /// driving a parallel display bus. Bits of `value` above `width` are ignored. Sets the `BSRR`
+    /// register. Atomic.
+    pub fn write_field(&self, shift: u8, width: u8, value: u16) {
+        self.write_masked(field_mask(shift, width), value << shift);
+    }
+
+    /// Read a `width`-bit value from a contiguous group of pins starting at `shift`. Reads the
+    /// `IDR` register.
+    pub fn read_field(&self, shift: u8, width: u8) -> u16 {
+        (self.read() & field_mask(shift, width)) >> shift
+    }
+
+    /// Read the input state of all pins on this port, with a single read. Bit 0 is pin 0. Reads
+    /// the `IDR` register.
+    pub fn read(&self) -> u16 {
+        unsafe { (*regs(*self)).idr.read().bits() as u16 }
+    }
+
+    /// Read the output state of all pins on this port, as last written. Reads the `ODR` register.
+    pub fn read_output(&self) -> u16 {
+        unsafe { (*regs(*self)).odr.read().bits() as u16 }
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// A mask covering `width` bits, starting at `shift`.
+fn field_mask(shift: u8, width: u8) -> u16 {
+    assert!(
+        width >= 1 && shift + width <= 16,
+        "Pin fields must fit within pins 0 - 15."
+    );
+    (((1u32 << width) - 1) << shift) as u16
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// The pulse edge used to trigger interrupts. Either rising, falling, or either.
 pub enum Edge {
     /// Interrupts trigger on rising pin edge.
@@ -345,139 +499,6 @@ macro_rules! set_state {
     }
 }
 
-// todo: Consolidate these exti macros
-
-// Reduce DRY for setting up interrupts.
-macro_rules! set_exti {
-    ($pin:expr, $rising:expr, $falling:expr, $val:expr, [$(($num:expr, $crnum:expr)),+]) => {
-        let exti = unsafe { &(*pac::EXTI::ptr()) };
-        let syscfg  = unsafe { &(*pac::SYSCFG::ptr()) };
-
-        paste! {
-            match $pin {
-                $(
-                    $num => {
-                    // todo: Core 2 interrupts for wb. (?)
-                        cfg_if! {
-                            if #[cfg(all(feature = "h7", not(any(feature = "h747cm4", feature = "h747cm7"))))] {
-                                exti.cpuimr1.modify(|_, w| w.[<mr $num>]().set_bit());
-                            } else if #[cfg(any(feature = "h747cm4", feature = "h747cm7"))] {
-                                exti.c1imr1.modify(|_, w| w.[<mr $num>]().set_bit());
-                            } else if #[cfg(any(feature = "g4", feature = "wb", feature = "wl"))] {
-                                exti.imr1.modify(|_, w| w.[<im $num>]().set_bit());
-                            } else {
-                                exti.imr1.modify(|_, w| w.[<mr $num>]().set_bit());
-                            }
-                        }
-
-                        cfg_if! {
-                            if #[cfg(any(feature = "g4", feature = "wb", feature = "wl"))] {
-                                exti.rtsr1.modify(|_, w| w.[<rt $num>]().bit($rising));
-                                exti.ftsr1.modify(|_, w| w.[<ft $num>]().bit($falling));
-                            // } else if #[cfg(any(feature = "wb", feature = "wl"))] {
-                            //     // todo: Missing in PAC, so we read+write. https://github.com/stm32-rs/stm32-rs/issues/570
-                            //     let val_r =  $exti.rtsr1.read().bits();
-                            //     $exti.rtsr1.write(|w| unsafe { w.bits(val_r | (1 << $num)) });
-                            //     let val_f =  $exti.ftsr1.read().bits();
-                            //     $exti.ftsr1.write(|w| unsafe { w.bits(val_f | (1 << $num)) });
-                            //     // todo: Core 2 interrupts.
-                            } else {
-                                exti.rtsr1.modify(|_, w| w.[<tr $num>]().bit($rising));
-                                exti.ftsr1.modify(|_, w| w.[<tr $num>]().bit($falling));
-                            }
-                        }
-                        syscfg
-                            .[<exticr $crnum>]
-                            .modify(|_, w| unsafe { w.[<exti $num>]().bits($val) });
-                    }
-                )+
-                _ => panic!("GPIO pins must be 0 - 15."),
-            }
-        }
-    }
-}
-
-#[cfg(feature = "f4")]
-// Similar to `set_exti`, but with reg names sans `1`.
-macro_rules! set_exti_f4 {
-    ($pin:expr, $rising:expr, $falling:expr, $val:expr, [$(($num:expr, $crnum:expr)),+]) => {
-        let exti = unsafe { &(*pac::EXTI::ptr()) };
-        let syscfg  = unsafe { &(*pac::SYSCFG::ptr()) };
-
-        paste! {
-            match $pin {
-                $(
-                    $num => {
-                        exti.imr.modify(|_, w| w.[<mr $num>]().unmasked());
-                        exti.rtsr.modify(|_, w| w.[<tr $num>]().bit($rising));
-                        exti.ftsr.modify(|_, w| w.[<tr $num>]().bit($falling));
-                        syscfg
-                            .[<exticr $crnum>]
-                            .modify(|_, w| unsafe { w.[<exti $num>]().bits($val) });
-                    }
-                )+
-                _ => panic!("GPIO pins must be 0 - 15."),
-            }
-        }
-    }
-}
-
-#[cfg(any(feature = "l5", feature = "h5"))]
-// For L5 See `set_exti!`. Different method naming pattern for exticr.
-macro_rules! set_exti_l5 {
-    ($pin:expr, $rising:expr, $falling:expr, $val:expr, [$(($num:expr, $crnum:expr, $num2:expr)),+]) => {
-        let exti = unsafe { &(*pac::EXTI::ptr()) };
-
-        paste! {
-            match $pin {
-                $(
-                    $num => {
-                        exti.imr1.modify(|_, w| w.[<im $num>]().set_bit());  // unmask
-                        exti.rtsr1.modify(|_, w| w.[<rt $num>]().bit($rising));  // Rising trigger
-                        exti.ftsr1.modify(|_, w| w.[<ft $num>]().bit($falling));   // Falling trigger
-
-                        #[cfg(feature = "l5")]
-                        exti
-                            .[<exticr $crnum>]
-                            .modify(|_, w| unsafe { w.[<exti $num2>]().bits($val) });
-
-                        #[cfg(feature = "h5")]
-                        exti
-                            .[<exticr $crnum>]
-                            .modify(|_, w| unsafe { w.[<exti $num>]().bits($val) });
-                    }
-                )+
-                _ => panic!("GPIO pins must be 0 - 15."),
-            }
-        }
-    }
-}
-
-#[cfg(feature = "g0")]
-// For G0. See `set_exti!`. Todo? Reduce DRY.
-macro_rules! set_exti_g0 {
-    ($pin:expr, $rising:expr, $falling:expr, $val:expr, [$(($num:expr, $crnum:expr, $num2:expr)),+]) => {
-        let exti = unsafe { &(*pac::EXTI::ptr()) };
-
-        paste! {
-            match $pin {
-                $(
-                    $num => {
-                        exti.imr1.modify(|_, w| w.[<im $num>]().set_bit());  // unmask
-                        exti.rtsr1.modify(|_, w| w.[<tr $num>]().bit($rising));  // Rising trigger
-                        // This field name is probably a PAC error.
-                        exti.ftsr1.modify(|_, w| w.[<tr $num>]().bit($falling));   // Falling trigger
-                        exti
-                            .[<exticr $crnum>]
-                            .modify(|_, w| unsafe { w.[<exti $num2>]().bits($val) });
-                    }
-                )+
-                _ => panic!("GPIO pins must be 0 - 15."),
-            }
-        }
-    }
-}
-
 #[derive(Clone)]
 /// Represents a single GPIO pin. Allows configuration, and reading/setting state.
 pub struct Pin {
@@ -873,6 +894,28 @@ impl Pin {
         );
     }
 
+    #[cfg(feature = "l5")]
+    /// Set the pin as secure, or non-secure. Secure pins can only be accessed by the secure image;
+    /// enable the `trustzone_secure` feature when building it. Must be run from the secure image.
+    /// Sets the `SECCFGR` register, `SEC` field.
+    pub fn set_security(&mut self, secure: bool) {
+        // SECCFGR is only writable through the secure alias.
+        let regs = (self.regs() as usize | crate::security::SECURE_ALIAS_OFFSET)
+            as *const pac::gpioa::RegisterBlock;
+
+        // Note: The PAC doesn't allow reading this register.
+        unsafe {
+            let reg = (*regs).seccfgr.as_ptr();
+            let orig = core::ptr::read_volatile(reg);
+            let val = if secure {
+                orig | (1 << self.pin)
+            } else {
+                orig & !(1 << self.pin)
+            };
+            core::ptr::write_volatile(reg, val);
+        }
+    }
+
     /// Set output speed to Low, Medium, or High. Sets the `OSPEEDR` register.
     pub fn output_speed(&mut self, value: OutputSpeed) {
         #[cfg(not(feature = "h5"))] // todo: Probably needs a PAC fix for H5.
@@ -979,44 +1022,25 @@ impl Pin {
         }
     }
 
-    #[cfg(not(any(feature = "f373", feature = "wl")))]
-    /// Configure this pin as an interrupt source. Set the edge as Rising or Falling.
+    /// Configure this pin as an interrupt source. Set the edge as Rising, Falling, or Either.
+    /// This selects the pin's port for its EXTI line, unmasks the line, and unmasks its interrupt
+    /// in the NVIC; eg `EXTI15_10` for pin 13. Clear the interrupt in its handler with
+    /// `clear_interrupt()`.
     pub fn enable_interrupt(&mut self, edge: Edge) {
-        let rising = match edge {
-            Edge::Falling => false,
-            _ => true, // rising or either.
-        };
+        exti::select_port(self.pin, self.port);
+        exti::enable(self.pin, edge);
+        exti::unmask_gpio_interrupt(self.pin);
+    }
 
-        let falling = match edge {
-            Edge::Rising => false,
-            _ => true, // falling or either.
-        };
+    /// Stop this pin from triggering interrupts, by masking its EXTI line. Its NVIC interrupt is
+    /// left unmasked, since other lines may share it.
+    pub fn disable_interrupt(&mut self) {
+        exti::disable(self.pin);
+    }
 
-        cfg_if! {
-            if #[cfg(feature = "g0")] {
-                set_exti_g0!(self.pin, rising, falling, self.port.cr_val(), [(0, 1, 0_7), (1, 1, 0_7), (2, 1, 0_7),
-                    (3, 1, 0_7), (4, 2, 0_7), (5, 2, 0_7), (6, 2, 0_7), (7, 2, 0_7), (8, 3, 8_15),
-                    (9, 3, 8_15), (10, 3, 8_15), (11, 3, 8_15), (12, 4, 8_15),
-                    (13, 4, 8_15), (14, 4, 8_15), (15, 4, 8_15)]
-                );
-            } else if #[cfg(any(feature = "l5", feature = "h5"))] {
-                set_exti_l5!(self.pin, rising, falling, self.port.cr_val(), [(0, 1, 0_7), (1, 1, 0_7), (2, 1, 0_7),
-                    (3, 1, 0_7), (4, 2, 0_7), (5, 2, 0_7), (6, 2, 0_7), (7, 2, 0_7), (8, 3, 8_15),
-                    (9, 3, 8_15), (10, 3, 8_15), (11, 3, 8_15), (12, 4, 8_15),
-                    (13, 4, 8_15), (14, 4, 8_15), (15, 4, 8_15)]
-                );
-            } else if #[cfg(feature = "f4")] {
-                set_exti_f4!(self.pin, rising, falling, self.port.cr_val(), [(0, 1), (1, 1), (2, 1),
-                        (3, 1), (4, 2), (5, 2), (6, 2), (7, 2), (8, 3), (9, 3), (10, 3), (11, 3), (12, 4),
-                        (13, 4), (14, 4), (15, 4)]
-                );
-            } else {
-                set_exti!(self.pin, rising, falling, self.port.cr_val(), [(0, 1), (1, 1), (2, 1),
-                    (3, 1), (4, 2), (5, 2), (6, 2), (7, 2), (8, 3), (9, 3), (10, 3), (11, 3), (12, 4),
-                    (13, 4), (14, 4), (15, 4)]
-                );
-            }
-        }
+    /// Clear this pin's EXTI pending flag, eg in its interrupt handler.
+    pub fn clear_interrupt(&mut self) {
+        exti::clear_pending(self.pin);
     }
 
     #[cfg(feature = "l4x6")]
@@ -1120,6 +1144,158 @@ impl StatefulOutputPin for Pin {
     }
 }
 
+#[cfg(feature = "async")]
+/// State for GPIO EXTI lines 0 - 15 awaited with `Wait`: Lines that are armed, lines whose edge
+/// has occurred, and the waker for each line's task.
+struct AsyncLines {
+    armed: u16,
+    fired: u16,
+    wakers: [Option<Waker>; 16],
+}
+
+#[cfg(feature = "async")]
+static ASYNC_LINES: Mutex<RefCell<AsyncLines>> = Mutex::new(RefCell::new(AsyncLines {
+    armed: 0,
+    fired: 0,
+    wakers: [const { None }; 16],
+}));
+
+#[cfg(feature = "async")]
+/// Wake tasks awaiting pins with `Wait` methods, eg `wait_for_rising_edge().await`. Call this
+/// from the handler of each EXTI interrupt whose lines are awaited, eg `EXTI15_10`. For armed lines
+/// that are pending, this masks the line, clears its pending flag, and wakes its task. It ignores
+/// other lines, so handlers can still check and clear them as usual.
+pub fn on_exti_interrupt() {
+    interrupt::free(|cs| {
+        let mut lines = ASYNC_LINES.borrow(cs).borrow_mut();
+
+        for line in 0..16 {
+            let bit = 1 << line;
+            if lines.armed & bit == 0 || !exti::is_pending(line) {
+                continue;
+            }
+
+            exti::disable(line);
+            exti::clear_pending(line);
+
+            lines.armed &= !bit;
+            lines.fired |= bit;
+
+            if let Some(waker) = lines.wakers[line as usize].take() {
+                waker.wake();
+            }
+        }
+    });
+}
+
+#[cfg(feature = "async")]
+/// Completes when an armed EXTI line's edge occurs. Dropping it disarms the line.
+struct ExtiFuture {
+    line: u8,
+}
+
+#[cfg(feature = "async")]
+impl ExtiFuture {
+    /// Arm a pin's EXTI line for an edge. This happens before polling, so edges that occur
+    /// while checking the pin's level aren't missed.
+    fn new(pin: &mut Pin, edge: Edge) -> Self {
+        let bit = 1 << pin.pin;
+
+        interrupt::free(|cs| {
+            let mut lines = ASYNC_LINES.borrow(cs).borrow_mut();
+            lines.armed |= bit;
+            lines.fired &= !bit;
+        });
+
+        // Unmasking the NVIC interrupt can't be done in a critical section.
+        exti::clear_pending(pin.pin);
+        pin.enable_interrupt(edge);
+
+        Self { line: pin.pin }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Future for ExtiFuture {
+    type Output = ();
+
+    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let bit = 1 << self.line;
+
+        interrupt::free(|cs| {
+            let mut lines = ASYNC_LINES.borrow(cs).borrow_mut();
+
+            if lines.fired & bit != 0 {
+                lines.fired &= !bit;
+                return Poll::Ready(());
+            }
+
+            let waker = &mut lines.wakers[self.line as usize];
+            if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                *waker = Some(cx.waker().clone());
+            }
+
+            Poll::Pending
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for ExtiFuture {
+    fn drop(&mut self) {
+        let bit = 1 << self.line;
+
+        interrupt::free(|cs| {
+            let mut lines = ASYNC_LINES.borrow(cs).borrow_mut();
+
+            if lines.armed & bit != 0 {
+                exti::disable(self.line);
+            }
+
+            lines.armed &= !bit;
+            lines.fired &= !bit;
+            lines.wakers[self.line as usize] = None;
+        });
+    }
+}
+
+#[cfg(feature = "async")]
+/// Await pin levels and edges, using the pin's EXTI line. This configures the line as in
+/// `enable_interrupt()`, and masks it again when done. Call `on_exti_interrupt()` from its EXTI
+/// interrupt handler. Only one task can await each line (ie pin number) at a time.
+impl Wait for Pin {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        let edge = ExtiFuture::new(self, Edge::Rising);
+        if !Pin::is_high(self) {
+            edge.await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        let edge = ExtiFuture::new(self, Edge::Falling);
+        if !Pin::is_low(self) {
+            edge.await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        ExtiFuture::new(self, Edge::Rising).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        ExtiFuture::new(self, Edge::Falling).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        ExtiFuture::new(self, Edge::Either).await;
+        Ok(())
+    }
+}
+
 /// Check if a pin's input voltage is high. Reads from the `IDR` register.
 /// Does not require a `Pin` struct.
 pub fn is_high(port: Port, pin: u8) -> bool {
@@ -1165,172 +1341,29 @@ pub fn set_state(port: Port, pin: u8, value: PinState) {
     );
 }
 
-/// Clear an EXTI interrupt, lines 0 - 15. Note that this function currently doesn't support
-/// higher extis, but will work for all GPIO interrupts.
+/// Clear an EXTI interrupt's pending flag. Works for lines 0 - 63, including GPIO interrupts. This
+/// is equivalent to `exti::clear_pending()`.
 pub fn clear_exti_interrupt(line: u8) {
-    // todo: Macro to avoid DRY?
-    unsafe {
-        cfg_if! {
-            if #[cfg(any(feature = "h747cm4", feature = "h747cm7"))] {
-                (*EXTI::ptr()).c1pr1.modify(|_, w| {
-                    match line {
-                        0 => w.pr0().set_bit(),
-                        1 => w.pr1().set_bit(),
-                        2 => w.pr2().set_bit(),
-                        3 => w.pr3().set_bit(),
-                        4 => w.pr4().set_bit(),
-                        5 => w.pr5().set_bit(),
-                        6 => w.pr6().set_bit(),
-                        7 => w.pr7().set_bit(),
-                        8 => w.pr8().set_bit(),
-                        9 => w.pr9().set_bit(),
-                        10 => w.pr10().set_bit(),
-                        11 => w.pr11().set_bit(),
-                        12 => w.pr12().set_bit(),
-                        13 => w.pr13().set_bit(),
-                        14 => w.pr14().set_bit(),
-                        15 => w.pr15().set_bit(),
-                        _ => panic!(),
-                    }
-                });
-            } else if #[cfg(feature = "h7")] {
-                (*EXTI::ptr()).cpupr1.modify(|_, w| {
-                    match line {
-                        0 => w.pr0().set_bit(),
-                        1 => w.pr1().set_bit(),
-                        2 => w.pr2().set_bit(),
-                        3 => w.pr3().set_bit(),
-                        4 => w.pr4().set_bit(),
-                        5 => w.pr5().set_bit(),
-                        6 => w.pr6().set_bit(),
-                        7 => w.pr7().set_bit(),
-                        8 => w.pr8().set_bit(),
-                        9 => w.pr9().set_bit(),
-                        10 => w.pr10().set_bit(),
-                        11 => w.pr11().set_bit(),
-                        12 => w.pr12().set_bit(),
-                        13 => w.pr13().set_bit(),
-                        14 => w.pr14().set_bit(),
-                        15 => w.pr15().set_bit(),
-                        _ => panic!(),
-                    }
-                });
-            } else if #[cfg(any(feature = "l5", feature = "g0"))] {
-                (*EXTI::ptr()).rpr1.modify(|_, w| {
-                    match line {
-                        0 => w.rpif0().set_bit(),
-                        1 => w.rpif1().set_bit(),
-                        2 => w.rpif2().set_bit(),
-                        3 => w.rpif3().set_bit(),
-                        4 => w.rpif4().set_bit(),
-                        5 => w.rpif5().set_bit(),
-                        6 => w.rpif6().set_bit(),
-                        7 => w.rpif7().set_bit(),
-                        8 => w.rpif8().set_bit(),
-                        9 => w.rpif9().set_bit(),
-                        10 => w.rpif10().set_bit(),
-                        11 => w.rpif11().set_bit(),
-                        12 => w.rpif12().set_bit(),
-                        13 => w.rpif13().set_bit(),
-                        14 => w.rpif14().set_bit(),
-                        15 => w.rpif15().set_bit(),
-                        _ => panic!(),
-                    }
-                });
-            } else if #[cfg(any(feature = "f373", feature = "f4"))] {
-                (*EXTI::ptr()).pr.modify(|_, w| {
-                    match line {
-                        0 => w.pr0().set_bit(),
-                        1 => w.pr1().set_bit(),
-                        2 => w.pr2().set_bit(),
-                        3 => w.pr3().set_bit(),
-                        4 => w.pr4().set_bit(),
-                        5 => w.pr5().set_bit(),
-                        6 => w.pr6().set_bit(),
-                        7 => w.pr7().set_bit(),
-                        8 => w.pr8().set_bit(),
-                        9 => w.pr9().set_bit(),
-                        10 => w.pr10().set_bit(),
-                        11 => w.pr11().set_bit(),
-                        12 => w.pr12().set_bit(),
-                        13 => w.pr13().set_bit(),
-                        14 => w.pr14().set_bit(),
-                        15 => w.pr15().set_bit(),
-                        _ => panic!(),
-                    }
-                });
-            } else if #[cfg(any(feature = "f3", feature = "l4"))] {
-                (*EXTI::ptr()).pr1.modify(|_, w| {
-                    match line {
-                        0 => w.pr0().set_bit(),
-                        1 => w.pr1().set_bit(),
-                        2 => w.pr2().set_bit(),
-                        3 => w.pr3().set_bit(),
-                        4 => w.pr4().set_bit(),
-                        5 => w.pr5().set_bit(),
-                        6 => w.pr6().set_bit(),
-                        7 => w.pr7().set_bit(),
-                        8 => w.pr8().set_bit(),
-                        9 => w.pr9().set_bit(),
-                        10 => w.pr10().set_bit(),
-                        11 => w.pr11().set_bit(),
-                        12 => w.pr12().set_bit(),
-                        13 => w.pr13().set_bit(),
-                        14 => w.pr14().set_bit(),
-                        15 => w.pr15().set_bit(),
-                        _ => panic!(),
-                    }
-                });
-              } else if #[cfg(feature = "h5")] {
-                (*EXTI::ptr()).rpr1.modify(|_, w| {
-                    match line {
-                        0 => w.rpif0().set_bit(),
-                        1 => w.rpif1().set_bit(),
-                        2 => w.rpif2().set_bit(),
-                        3 => w.rpif3().set_bit(),
-                        4 => w.rpif4().set_bit(),
-                        5 => w.rpif5().set_bit(),
-                        6 => w.rpif6().set_bit(),
-                        7 => w.rpif7().set_bit(),
-                        8 => w.rpif8().set_bit(),
-                        9 => w.rpif9().set_bit(),
-                        10 => w.rpif10().set_bit(),
-                        11 => w.rpif11().set_bit(),
-                        12 => w.rpif12().set_bit(),
-                        13 => w.rpif13().set_bit(),
-                        14 => w.rpif14().set_bit(),
-                        15 => w.rpif15().set_bit(),
-                        _ => panic!(),
-                    }
-                });
-            } else { // eg G4
-                (*EXTI::ptr()).pr1.modify(|_, w| {
-                    match line {
-                        0 => w.pif0().set_bit(),
-                        1 => w.pif1().set_bit(),
-                        2 => w.pif2().set_bit(),
-                        3 => w.pif3().set_bit(),
-                        4 => w.pif4().set_bit(),
-                        5 => w.pif5().set_bit(),
-                        6 => w.pif6().set_bit(),
-                        7 => w.pif7().set_bit(),
-                        8 => w.pif8().set_bit(),
-                        9 => w.pif9().set_bit(),
-                        10 => w.pif10().set_bit(),
-                        11 => w.pif11().set_bit(),
-                        12 => w.pif12().set_bit(),
-                        13 => w.pif13().set_bit(),
-                        14 => w.pif14().set_bit(),
-                        15 => w.pif15().set_bit(),
-                        _ => panic!(),
-                    }
-                });
-            }
-        }
+    exti::clear_pending(line);
+}
+
+#[cfg(all(feature = "l5", feature = "trustzone_secure"))]
+/// Secure images use the secure aliases, so they can access both secure, and non-secure pins.
+pub(crate) const fn regs(port: Port) -> *const pac::gpioa::RegisterBlock {
+    match port {
+        Port::A => crate::pac::SEC_GPIOA::ptr(),
+        Port::B => crate::pac::SEC_GPIOB::ptr() as _,
+        Port::C => crate::pac::SEC_GPIOC::ptr() as _,
+        Port::D => crate::pac::SEC_GPIOD::ptr() as _,
+        Port::E => crate::pac::SEC_GPIOE::ptr() as _,
+        Port::F => crate::pac::SEC_GPIOF::ptr() as _,
+        Port::G => crate::pac::SEC_GPIOG::ptr() as _,
+        Port::H => crate::pac::SEC_GPIOH::ptr() as _,
     }
 }
 
-const fn regs(port: Port) -> *const pac::gpioa::RegisterBlock {
+#[cfg(not(all(feature = "l5", feature = "trustzone_secure")))]
+pub(crate) const fn regs(port: Port) -> *const pac::gpioa::RegisterBlock {
     // Note that we use this `const` fn and pointer casting since not all ports actually
     // deref to GPIOA in PAC.
     match port {
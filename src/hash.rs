@@ -0,0 +1,446 @@
+//! Hardware SHA-1, SHA-224, and SHA-256 hashing, and HMAC, using the HASH peripheral. Data can be
+//! fed in multiple parts, and an in-progress computation can be suspended, and resumed later, eg to
+//! hash a higher-priority message in between.
+//!
+//! With the `digest` feature, `Sha1`, `Sha224`, and `Sha256` implement the RustCrypto `Digest`
+//! trait, so they can be used with crates that verify signatures over a digest. Set up the
+//! peripheral with `Hash::new()` first.
+//!
+//! Example, verifying a firmware image in a bootloader:
+//! ```rust
+//! let mut hash = Hash::new(dp.HASH);
+//!
+//! let image = unsafe { core::slice::from_raw_parts(APP_ADDR as *const u8, APP_LEN) };
+//! let digest = hash.hash(Algorithm::Sha256, image).unwrap();
+//!
+//! if !digest.matches(&EXPECTED_DIGEST) {
+//!     // Don't boot
+//! }
+//!
+//! // Multi-part:
+//! hash.start(Algorithm::Sha256);
+//! hash.update(header).unwrap();
+//! hash.update(body).unwrap();
+//! let digest = hash.finish().unwrap();
+//!
+//! let mac = hash.hmac(Algorithm::Sha256, &KEY, message).unwrap();
+//! ```
+
+use cfg_if::cfg_if;
+
+#[cfg(not(feature = "f4"))]
+use crate::util::rcc_en_reset;
+use crate::{
+    pac::{hash, HASH, RCC},
+    MAX_ITERS,
+};
+
+/// The number of 32-bit words in a block.
+const BLOCK_WORDS: u8 = 16;
+
+/// Context swap registers used, when not in HMAC mode. (CSR0 - 37)
+const CSR_COUNT: usize = 38;
+/// Context swap registers used in HMAC mode. (CSR0 - 53)
+const CSR_COUNT_HMAC: usize = 54;
+
+/// HMAC keys longer than this are hashed by the peripheral first.
+const HMAC_LONG_KEY: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// HASH errors.
+pub enum HashError {
+    /// Timed out waiting for the peripheral.
+    Timeout,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// Hash algorithm. Sets the CR register, ALGO field.
+pub enum Algorithm {
+    Sha1,
+    Sha224,
+    Sha256,
+}
+
+impl Algorithm {
+    /// The digest length, in bytes.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            Self::Sha1 => 20,
+            Self::Sha224 => 28,
+            Self::Sha256 => 32,
+        }
+    }
+
+    /// (ALGO[1], ALGO[0])
+    fn bits(&self) -> (bool, bool) {
+        match self {
+            Self::Sha1 => (false, false),
+            Self::Sha224 => (true, false),
+            Self::Sha256 => (true, true),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+/// A computed digest. Its length depends on the algorithm.
+pub struct HashOutput {
+    bytes: [u8; 32],
+    len: usize,
+}
+
+impl HashOutput {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Compare against an expected digest, in constant time.
+    pub fn matches(&self, expected: &[u8]) -> bool {
+        if expected.len() != self.len {
+            return false;
+        }
+
+        self.as_bytes()
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+/// Tracks data not yet written to the peripheral, since it only accepts whole words.
+#[derive(Clone, Copy)]
+struct State {
+    algo: Algorithm,
+    hmac: bool,
+    /// Bytes of a partial word.
+    pending: [u8; 4],
+    pending_len: usize,
+    /// Words written in the current block.
+    block_words: u8,
+}
+
+impl State {
+    const fn new(algo: Algorithm) -> Self {
+        Self {
+            algo,
+            hmac: false,
+            pending: [0; 4],
+            pending_len: 0,
+            block_words: 0,
+        }
+    }
+}
+
+/// A saved computation, from `Hash::suspend()`.
+pub struct HashContext {
+    imr: u32,
+    str: u32,
+    cr: u32,
+    csr: [u32; CSR_COUNT_HMAC],
+    state: State,
+}
+
+/// Represents a HASH peripheral.
+pub struct Hash {
+    pub regs: HASH,
+    state: State,
+}
+
+impl Hash {
+    /// Initialize the HASH peripheral, including enabling and resetting its RCC peripheral clock.
+    pub fn new(regs: HASH) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                // The PAC misspells the reset field.
+                rcc.ahb2enr.modify(|_, w| w.hashen().set_bit());
+                rcc.ahb2rstr.modify(|_, w| w.hsahrst().set_bit());
+                rcc.ahb2rstr.modify(|_, w| w.hsahrst().clear_bit());
+            } else if #[cfg(feature = "l4")] {
+                rcc_en_reset!(ahb2, hash1, rcc);
+            } else {
+                rcc_en_reset!(ahb2, hash, rcc);
+            }
+        }
+
+        Self {
+            regs,
+            state: State::new(Algorithm::Sha256),
+        }
+    }
+
+    /// Start a new computation, discarding any in progress.
+    pub fn start(&mut self, algo: Algorithm) {
+        self.state = State::new(algo);
+        init(&self.regs, algo, false, false);
+    }
+
+    /// Add data to the computation.
+    pub fn update(&mut self, data: &[u8]) -> Result<(), HashError> {
+        update(&self.regs, &mut self.state, data)
+    }
+
+    /// Complete the computation, and read the digest.
+    pub fn finish(&mut self) -> Result<HashOutput, HashError> {
+        finish(&self.regs, &mut self.state)?;
+        Ok(read_digest(&self.regs, self.state.algo))
+    }
+
+    /// Hash data in one step.
+    pub fn hash(&mut self, algo: Algorithm, data: &[u8]) -> Result<HashOutput, HashError> {
+        self.start(algo);
+        self.update(data)?;
+        self.finish()
+    }
+
+    /// Start an HMAC computation, writing the key. Add the message with `update()`, then call
+    /// `finish_hmac()` with the same key.
+    pub fn start_hmac(&mut self, algo: Algorithm, key: &[u8]) -> Result<(), HashError> {
+        self.state = State::new(algo);
+        self.state.hmac = true;
+        init(&self.regs, algo, true, key.len() > HMAC_LONG_KEY);
+
+        // Inner hash: The key is processed separately from the message.
+        update(&self.regs, &mut self.state, key)?;
+        finish_phase(&self.regs, &mut self.state)?;
+        wait(|| self.regs.sr.read().busy().bit_is_clear())
+    }
+
+    /// Complete an HMAC computation, and read the result. `key` must be the key passed to
+    /// `start_hmac()`.
+    pub fn finish_hmac(&mut self, key: &[u8]) -> Result<HashOutput, HashError> {
+        finish_phase(&self.regs, &mut self.state)?;
+        wait(|| self.regs.sr.read().busy().bit_is_clear())?;
+
+        // Outer hash.
+        update(&self.regs, &mut self.state, key)?;
+        finish(&self.regs, &mut self.state)?;
+
+        Ok(read_digest(&self.regs, self.state.algo))
+    }
+
+    /// Compute an HMAC in one step.
+    pub fn hmac(
+        &mut self,
+        algo: Algorithm,
+        key: &[u8],
+        data: &[u8],
+    ) -> Result<HashOutput, HashError> {
+        self.start_hmac(algo, key)?;
+        self.update(data)?;
+        self.finish_hmac(key)
+    }
+
+    /// Save the computation in progress, so the peripheral can be used for something else. Resume it
+    /// with `resume()`. (RM: Context swapping)
+    pub fn suspend(&mut self) -> Result<HashContext, HashError> {
+        // Wait for the current block to be processed, and the FIFO to be ready for the next.
+        wait(|| {
+            let sr = self.regs.sr.read();
+            sr.dinis().bit_is_set() && sr.busy().bit_is_clear()
+        })?;
+
+        let mut csr = [0; CSR_COUNT_HMAC];
+        let count = if self.state.hmac {
+            CSR_COUNT_HMAC
+        } else {
+            CSR_COUNT
+        };
+        for (i, val) in csr.iter_mut().take(count).enumerate() {
+            *val = self.regs.csr[i].read().bits();
+        }
+
+        Ok(HashContext {
+            imr: self.regs.imr.read().bits(),
+            str: self.regs.str.read().bits(),
+            cr: self.regs.cr.read().bits(),
+            csr,
+            state: self.state,
+        })
+    }
+
+    /// Resume a computation saved with `suspend()`.
+    pub fn resume(&mut self, ctx: &HashContext) {
+        self.regs.imr.write(|w| unsafe { w.bits(ctx.imr) });
+        self.regs.str.write(|w| unsafe { w.bits(ctx.str) });
+        // Restore the configuration, and initialize before writing the context.
+        self.regs.cr.write(|w| unsafe { w.bits(ctx.cr) });
+        self.regs.cr.modify(|_, w| w.init().set_bit());
+
+        let count = if ctx.state.hmac {
+            CSR_COUNT_HMAC
+        } else {
+            CSR_COUNT
+        };
+        for (i, val) in ctx.csr.iter().take(count).enumerate() {
+            self.regs.csr[i].write(|w| unsafe { w.bits(*val) });
+        }
+
+        self.state = ctx.state;
+    }
+}
+
+/// Configure the algorithm and mode, and start a new computation.
+fn init(regs: &hash::RegisterBlock, algo: Algorithm, hmac: bool, long_key: bool) {
+    let (algo1, algo0) = algo.bits();
+
+    regs.cr.write(|w| unsafe {
+        // Byte data; the peripheral swaps bytes within words to big-endian order.
+        w.datatype().bits(0b10);
+        w.algo1().bit(algo1);
+        w.algo0().bit(algo0);
+        w.mode().bit(hmac);
+        w.lkey().bit(long_key);
+        w.init().set_bit()
+    });
+}
+
+fn update(regs: &hash::RegisterBlock, state: &mut State, mut data: &[u8]) -> Result<(), HashError> {
+    // Complete a partial word from the previous update.
+    if state.pending_len > 0 {
+        let n = (4 - state.pending_len).min(data.len());
+        state.pending[state.pending_len..state.pending_len + n].copy_from_slice(&data[..n]);
+        state.pending_len += n;
+        data = &data[n..];
+
+        if state.pending_len < 4 {
+            return Ok(());
+        }
+        write_word(regs, state, u32::from_le_bytes(state.pending))?;
+        state.pending_len = 0;
+    }
+
+    let mut words = data.chunks_exact(4);
+    for word in &mut words {
+        write_word(regs, state, u32::from_le_bytes(word.try_into().unwrap()))?;
+    }
+
+    let rem = words.remainder();
+    state.pending[..rem.len()].copy_from_slice(rem);
+    state.pending_len = rem.len();
+
+    Ok(())
+}
+
+fn write_word(regs: &hash::RegisterBlock, state: &mut State, word: u32) -> Result<(), HashError> {
+    // Once a block is written, let the peripheral process it before writing more.
+    if state.block_words == BLOCK_WORDS {
+        wait(|| regs.sr.read().busy().bit_is_clear())?;
+        state.block_words = 0;
+    }
+
+    regs.din.write(|w| unsafe { w.bits(word) });
+    state.block_words += 1;
+
+    Ok(())
+}
+
+/// Write the last partial word, set the number of valid bits in it, and start the final
+/// computation for the current message (or HMAC phase).
+fn finish_phase(regs: &hash::RegisterBlock, state: &mut State) -> Result<(), HashError> {
+    let valid_bits = (state.pending_len * 8) as u8;
+    if state.pending_len > 0 {
+        let mut word = [0; 4];
+        word[..state.pending_len].copy_from_slice(&state.pending[..state.pending_len]);
+        write_word(regs, state, u32::from_le_bytes(word))?;
+    }
+
+    regs.str.write(|w| unsafe { w.nblw().bits(valid_bits) });
+    regs.str.modify(|_, w| w.dcal().set_bit());
+
+    state.pending_len = 0;
+    state.block_words = 0;
+
+    Ok(())
+}
+
+fn finish(regs: &hash::RegisterBlock, state: &mut State) -> Result<(), HashError> {
+    finish_phase(regs, state)?;
+    wait(|| regs.sr.read().dcis().bit_is_set())
+}
+
+fn read_digest(regs: &hash::RegisterBlock, algo: Algorithm) -> HashOutput {
+    let len = algo.digest_len();
+    let mut bytes = [0; 32];
+
+    for (i, chunk) in bytes[..len].chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&regs.hash_hr[i].read().bits().to_be_bytes());
+    }
+
+    HashOutput { bytes, len }
+}
+
+fn wait(done: impl Fn() -> bool) -> Result<(), HashError> {
+    let mut i = 0;
+    while !done() {
+        i += 1;
+        if i >= MAX_ITERS {
+            return Err(HashError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "digest")]
+mod digest_impl {
+    use digest::{
+        consts::{U20, U28, U32},
+        FixedOutput, HashMarker, Output, OutputSizeUser, Reset, Update,
+    };
+
+    use super::{finish, init, read_digest, update, Algorithm, State};
+    use crate::pac::HASH;
+
+    macro_rules! hw_digest {
+        ($name:ident, $algo:ident, $size:ident, $doc:expr) => {
+            #[doc = $doc]
+            /// Uses the peripheral directly, so only one may be in use at a time.
+            pub struct $name {
+                state: State,
+            }
+
+            impl Default for $name {
+                fn default() -> Self {
+                    let state = State::new(Algorithm::$algo);
+                    init(unsafe { &*HASH::ptr() }, state.algo, false, false);
+                    Self { state }
+                }
+            }
+
+            impl HashMarker for $name {}
+
+            impl OutputSizeUser for $name {
+                type OutputSize = $size;
+            }
+
+            impl Update for $name {
+                fn update(&mut self, data: &[u8]) {
+                    update(unsafe { &*HASH::ptr() }, &mut self.state, data).unwrap();
+                }
+            }
+
+            impl FixedOutput for $name {
+                fn finalize_into(mut self, out: &mut Output<Self>) {
+                    let regs = unsafe { &*HASH::ptr() };
+                    finish(regs, &mut self.state).unwrap();
+                    out.copy_from_slice(read_digest(regs, self.state.algo).as_bytes());
+                }
+            }
+
+            impl Reset for $name {
+                fn reset(&mut self) {
+                    *self = Self::default();
+                }
+            }
+        };
+    }
+
+    hw_digest!(Sha1, Sha1, U20, "Hardware SHA-1.");
+    hw_digest!(Sha224, Sha224, U28, "Hardware SHA-224.");
+    hw_digest!(Sha256, Sha256, U32, "Hardware SHA-256.");
+}
+
+#[cfg(feature = "digest")]
+pub use digest_impl::{Sha1, Sha224, Sha256};
@@ -0,0 +1,470 @@
+//! Heap allocation from multiple memory regions, with allocations that can be restricted to
+//! DMA-accessible, or fast (TCM or CCM) memory. Enabled with the `heap` feature.
+//!
+//! Each region is a memory bank, or part of one, that the linker doesn't use; eg a static buffer
+//! placed in a bank's section, or a bank left out of `memory.x` entirely. `MemoryBank` constants
+//! describe the banks of variants where this is easy to get wrong, eg H7:
+//! - DTCM and ITCM are fast, but DMA1, DMA2, and BDMA can't access them. (MDMA can)
+//! - AXI SRAM, and D2 SRAM (SRAM1 - 3) are accessible by DMA1 and DMA2.
+//! - BDMA can only access D3 SRAM (SRAM4), and the backup SRAM.
+//! - AXI and D2 SRAM are cacheable by default; DMA buffers there need cache maintenance, or an MPU
+//!   region configured as non-cacheable.
+//!
+//! On F3 and F4, CCM SRAM is fast, but not accessible by DMA.
+//!
+//! Example:
+//! ```rust
+//! #[global_allocator]
+//! static HEAP: RegionHeap<2> = RegionHeap::new();
+//!
+//! // Linked to AXI SRAM, and DTCM respectively, in `memory.x`.
+//! #[link_section = ".axisram"]
+//! static mut AXI_HEAP: [MaybeUninit<u8>; 65_536] = [MaybeUninit::uninit(); 65_536];
+//! #[link_section = ".dtcm_heap"]
+//! static mut DTCM_HEAP: [MaybeUninit<u8>; 16_384] = [MaybeUninit::uninit(); 16_384];
+//!
+//! unsafe {
+//!     HEAP.add_region(&mut AXI_HEAP).unwrap();
+//!     HEAP.add_region(&mut DTCM_HEAP).unwrap();
+//! }
+//!
+//! // Using `alloc`, eg `Vec` and `Box`, uses non-TCM memory first.
+//! let mut samples = Vec::new();
+//!
+//! // A DMA buffer:
+//! let layout = Layout::from_size_align(1_024, 32).unwrap();
+//! let buf = HEAP.alloc_kind(layout, MemKind::DmaSafe).unwrap();
+//! ```
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::RefCell,
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
+};
+
+use cfg_if::cfg_if;
+use cortex_m::interrupt::{self, Mutex};
+use linked_list_allocator::Heap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// The kind of memory an allocation requires.
+pub enum MemKind {
+    /// Any region. Regions that aren't fast are used first, to leave fast memory for fast
+    /// allocations.
+    Any,
+    /// A region that DMA (eg DMA1 and DMA2 on H7) can access.
+    DmaSafe,
+    /// A fast (TCM or CCM) region.
+    Fast,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Describes a memory bank: its address range, and attributes.
+pub struct MemoryBank {
+    pub name: &'static str,
+    pub start: usize,
+    /// Size, in bytes.
+    pub size: usize,
+    /// Accessible by DMA. On H7, this refers to DMA1 and DMA2.
+    pub dma: bool,
+    /// Tightly-coupled, or core-coupled memory, with no wait states.
+    pub fast: bool,
+}
+
+impl MemoryBank {
+    /// Returns true if an address range is entirely within this bank.
+    pub fn contains(&self, start: usize, len: usize) -> bool {
+        start >= self.start && start + len <= self.start + self.size
+    }
+}
+
+#[cfg(feature = "h7")]
+/// H7 memory banks. Sizes are for the largest variant of each line; check your variant's RM.
+pub mod banks {
+    use cfg_if::cfg_if;
+
+    use super::MemoryBank;
+
+    pub const ITCM: MemoryBank = MemoryBank {
+        name: "ITCM",
+        start: 0x0000_0000,
+        size: 64 * 1_024,
+        dma: false,
+        fast: true,
+    };
+
+    pub const DTCM: MemoryBank = MemoryBank {
+        name: "DTCM",
+        start: 0x2000_0000,
+        size: 128 * 1_024,
+        dma: false,
+        fast: true,
+    };
+
+    cfg_if! {
+        if #[cfg(feature = "h735")] {
+            /// Includes 192Kb shared with ITCM, by default allocated to AXI SRAM.
+            pub const AXI_SRAM: MemoryBank = MemoryBank {
+                name: "AXI SRAM",
+                start: 0x2400_0000,
+                size: 320 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            pub const SRAM1: MemoryBank = MemoryBank {
+                name: "SRAM1",
+                start: 0x3000_0000,
+                size: 16 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            pub const SRAM2: MemoryBank = MemoryBank {
+                name: "SRAM2",
+                start: 0x3000_4000,
+                size: 16 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            /// D3 SRAM; accessible by BDMA.
+            pub const SRAM4: MemoryBank = MemoryBank {
+                name: "SRAM4",
+                start: 0x3800_0000,
+                size: 16 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            pub const BANKS: [MemoryBank; 7] = [ITCM, DTCM, AXI_SRAM, SRAM1, SRAM2, SRAM4, BACKUP_SRAM];
+        } else if #[cfg(feature = "h7b3")] {
+            /// AXI SRAM1 - 3.
+            pub const AXI_SRAM: MemoryBank = MemoryBank {
+                name: "AXI SRAM",
+                start: 0x2400_0000,
+                size: 1_024 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            /// AHB SRAM1.
+            pub const SRAM1: MemoryBank = MemoryBank {
+                name: "SRAM1",
+                start: 0x3000_0000,
+                size: 64 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            /// AHB SRAM2.
+            pub const SRAM2: MemoryBank = MemoryBank {
+                name: "SRAM2",
+                start: 0x3001_0000,
+                size: 64 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            /// SRD SRAM; accessible by BDMA2.
+            pub const SRAM4: MemoryBank = MemoryBank {
+                name: "SRD SRAM",
+                start: 0x3800_0000,
+                size: 32 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            pub const BANKS: [MemoryBank; 7] = [ITCM, DTCM, AXI_SRAM, SRAM1, SRAM2, SRAM4, BACKUP_SRAM];
+        } else {
+            pub const AXI_SRAM: MemoryBank = MemoryBank {
+                name: "AXI SRAM",
+                start: 0x2400_0000,
+                size: 512 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            /// D2 SRAM.
+            pub const SRAM1: MemoryBank = MemoryBank {
+                name: "SRAM1",
+                start: 0x3000_0000,
+                size: 128 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            /// D2 SRAM.
+            pub const SRAM2: MemoryBank = MemoryBank {
+                name: "SRAM2",
+                start: 0x3002_0000,
+                size: 128 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            /// D2 SRAM.
+            pub const SRAM3: MemoryBank = MemoryBank {
+                name: "SRAM3",
+                start: 0x3004_0000,
+                size: 32 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            /// D3 SRAM; accessible by BDMA.
+            pub const SRAM4: MemoryBank = MemoryBank {
+                name: "SRAM4",
+                start: 0x3800_0000,
+                size: 64 * 1_024,
+                dma: true,
+                fast: false,
+            };
+
+            pub const BANKS: [MemoryBank; 8] =
+                [ITCM, DTCM, AXI_SRAM, SRAM1, SRAM2, SRAM3, SRAM4, BACKUP_SRAM];
+        }
+    }
+
+    /// Retained in Standby and VBAT modes, if the backup regulator is enabled. Enable its clock
+    /// (`RCC_AHB4ENR`, `BKPRAMEN`), and write access (`PWR_CR1`, `DBP`), before using it.
+    pub const BACKUP_SRAM: MemoryBank = MemoryBank {
+        name: "Backup SRAM",
+        start: 0x3880_0000,
+        size: 4 * 1_024,
+        dma: true,
+        fast: false,
+    };
+}
+
+#[cfg(any(
+    feature = "f405",
+    feature = "f407",
+    feature = "f427",
+    feature = "f429",
+    feature = "f469"
+))]
+/// F4 memory banks that aren't in the main SRAM.
+pub mod banks {
+    use super::MemoryBank;
+
+    /// Not accessible by DMA.
+    pub const CCM: MemoryBank = MemoryBank {
+        name: "CCM SRAM",
+        start: 0x1000_0000,
+        size: 64 * 1_024,
+        dma: false,
+        fast: true,
+    };
+
+    /// Retained in Standby and VBAT modes, if the backup regulator is enabled. Enable its clock
+    /// (`RCC_AHB1ENR`, `BKPSRAMEN`), and write access (`PWR_CR`, `DBP`), before using it.
+    pub const BACKUP_SRAM: MemoryBank = MemoryBank {
+        name: "Backup SRAM",
+        start: 0x4002_4000,
+        size: 4 * 1_024,
+        dma: true,
+        fast: false,
+    };
+
+    pub const BANKS: [MemoryBank; 2] = [CCM, BACKUP_SRAM];
+}
+
+#[cfg(any(
+    feature = "h7",
+    feature = "f405",
+    feature = "f407",
+    feature = "f427",
+    feature = "f429",
+    feature = "f469"
+))]
+/// Find the known memory bank containing an address range, if any.
+pub fn bank_of(start: usize, len: usize) -> Option<MemoryBank> {
+    banks::BANKS.into_iter().find(|b| b.contains(start, len))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HeapError {
+    /// All region slots are in use.
+    Full,
+    /// The region is too small to hold the allocator's bookkeeping.
+    TooSmall,
+}
+
+/// A heap region, and its attributes.
+struct Region {
+    heap: Heap,
+    dma: bool,
+    fast: bool,
+}
+
+impl Region {
+    fn matches(&self, kind: MemKind) -> bool {
+        match kind {
+            MemKind::Any => true,
+            MemKind::DmaSafe => self.dma,
+            MemKind::Fast => self.fast,
+        }
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        let bottom = self.heap.bottom() as usize;
+        (bottom..bottom + self.heap.size()).contains(&(ptr as usize))
+    }
+}
+
+/// A heap spanning up to `N` memory regions. Place this in a static; use it as the global allocator
+/// with `#[global_allocator]`, and/or call `alloc_kind()` directly.
+pub struct RegionHeap<const N: usize> {
+    regions: Mutex<RefCell<[Option<Region>; N]>>,
+}
+
+impl<const N: usize> RegionHeap<N> {
+    pub const fn new() -> Self {
+        Self {
+            regions: Mutex::new(RefCell::new([const { None }; N])),
+        }
+    }
+
+    /// Add a region, from a static buffer. Its attributes are from the known memory bank containing
+    /// it; if it isn't in one (eg main SRAM on most families), it's treated as DMA-accessible, and
+    /// not fast. Use `add_region_raw()` to set them explicitly.
+    ///
+    /// # Safety
+    /// The buffer must not be used for anything else, for the life of the program.
+    pub unsafe fn add_region(&self, buf: &'static mut [MaybeUninit<u8>]) -> Result<(), HeapError> {
+        let start = buf.as_mut_ptr() as *mut u8;
+
+        cfg_if! {
+            if #[cfg(any(
+                feature = "h7",
+                feature = "f405",
+                feature = "f407",
+                feature = "f427",
+                feature = "f429",
+                feature = "f469"
+            ))] {
+                let (dma, fast) = match bank_of(start as usize, buf.len()) {
+                    Some(bank) => (bank.dma, bank.fast),
+                    None => (true, false),
+                };
+            } else {
+                let (dma, fast) = (true, false);
+            }
+        }
+
+        unsafe { self.add_region_raw(start, buf.len(), dma, fast) }
+    }
+
+    /// Add a region, from a start address, and size in bytes.
+    ///
+    /// # Safety
+    /// The memory must be valid, and not used for anything else, for the life of the program; eg
+    /// not in a region the linker places data or the stack in.
+    pub unsafe fn add_region_raw(
+        &self,
+        start: *mut u8,
+        size: usize,
+        dma: bool,
+        fast: bool,
+    ) -> Result<(), HeapError> {
+        // The allocator's bookkeeping takes up to 3 words, depending on alignment.
+        if size < 3 * core::mem::size_of::<usize>() {
+            return Err(HeapError::TooSmall);
+        }
+
+        interrupt::free(|cs| {
+            let mut regions = self.regions.borrow(cs).borrow_mut();
+            let slot = regions
+                .iter_mut()
+                .find(|r| r.is_none())
+                .ok_or(HeapError::Full)?;
+
+            let mut heap = Heap::empty();
+            unsafe { heap.init(start, size) };
+
+            *slot = Some(Region { heap, dma, fast });
+            Ok(())
+        })
+    }
+
+    /// Allocate memory of a given kind. Returns `None` if no suitable region has space.
+    pub fn alloc_kind(&self, layout: Layout, kind: MemKind) -> Option<NonNull<u8>> {
+        interrupt::free(|cs| {
+            let mut regions = self.regions.borrow(cs).borrow_mut();
+
+            // For `Any`, use regions that aren't fast first.
+            let passes: &[bool] = if kind == MemKind::Any {
+                &[false, true]
+            } else {
+                &[true]
+            };
+
+            for &allow_fast in passes {
+                for region in regions.iter_mut().flatten() {
+                    if !region.matches(kind) || (region.fast && !allow_fast) {
+                        continue;
+                    }
+                    if let Ok(ptr) = region.heap.allocate_first_fit(layout) {
+                        return Some(ptr);
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    /// Free memory allocated with `alloc_kind()`, or the global allocator.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by this heap, with the same layout.
+    pub unsafe fn free(&self, ptr: NonNull<u8>, layout: Layout) {
+        interrupt::free(|cs| {
+            let mut regions = self.regions.borrow(cs).borrow_mut();
+
+            if let Some(region) = regions
+                .iter_mut()
+                .flatten()
+                .find(|r| r.contains(ptr.as_ptr()))
+            {
+                unsafe { region.heap.deallocate(ptr, layout) };
+            }
+        })
+    }
+
+    /// The number of free bytes in regions that can serve allocations of a given kind. Due to
+    /// fragmentation, the largest possible allocation may be smaller.
+    pub fn free_bytes(&self, kind: MemKind) -> usize {
+        interrupt::free(|cs| {
+            self.regions
+                .borrow(cs)
+                .borrow()
+                .iter()
+                .flatten()
+                .filter(|r| r.matches(kind))
+                .map(|r| r.heap.free())
+                .sum()
+        })
+    }
+}
+
+impl<const N: usize> Default for RegionHeap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for RegionHeap<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_kind(layout, MemKind::Any)
+            .map_or(ptr::null_mut(), |p| p.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            unsafe { self.free(ptr, layout) };
+        }
+    }
+}
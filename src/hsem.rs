@@ -1,79 +1,145 @@
 //! Hardware semaphore (HSEM)
-//! Used on STM32WB to synchronize processes running on different cores.
+//! Used on STM32WB, and dual-core STM32H7 (H745, H747, H755, H757) to synchronize processes running
+//! on different cores. eg: to share a peripheral, or a memory region, between cores, or to signal
+//! the other core. (A semaphore unlock can raise an interrupt on the other core.)
+//!
+//! Example:
+//! ```rust
+//! let mut hsem = Hsem::new(dp.HSEM);
+//!
+//! // Guard access to a UART shared by both cores.
+//! while hsem.lock_1_step(Core::current(), UART_SEM).is_err() {}
+//! uart.write(b"hello");
+//! hsem.unlock(Core::current(), UART_SEM, 0);
+//! ```
 
-use paste::paste;
+use cfg_if::cfg_if;
 
-use crate::pac::{self, HSEM, RCC};
+use crate::pac::{HSEM, RCC};
 
-#[derive(Clone, Copy)]
-/// The core that's performing the requested operation. Core 1 is the M4 core, and Core 2 is the M0+ core.
+/// RM: The semaphore is locked. (HSEM_Rx register, LOCK field)
+const LOCK: u32 = 1 << 31;
+
+// Per-core interrupt registers. We use offsets directly, since the PAC only includes one set on H7.
+const C1IER: usize = 0x100;
+const C1ICR: usize = 0x104;
+const C1ISR: usize = 0x108;
+const C1MISR: usize = 0x10c;
+const C2IER: usize = 0x110;
+const C2ICR: usize = 0x114;
+const C2ISR: usize = 0x118;
+const C2MISR: usize = 0x11c;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// HSEM errors.
+pub enum HsemError {
+    /// The semaphore is locked by another core, or another process.
+    Locked,
+    /// Process ID 0 is reserved for 1-step locks.
+    ProcId,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// The core that's performing the requested operation. On WB, Core 1 is the M4 core, and Core 2 is
+/// the M0+ core. On H7, Core 1 is the M7 core, and Core 2 is the M4 core.
 pub enum Core {
     // todo: This is the same as ipcc::Core; DRY; keep in one place and import in the other/both?
     C1,
     C2,
 }
 
-pub struct Hsem {
-    regs: HSEM,
-}
+impl Core {
+    /// The core this firmware is compiled for.
+    pub const fn current() -> Self {
+        cfg_if! {
+            if #[cfg(feature = "h747cm4")] {
+                Self::C2
+            } else {
+                Self::C1
+            }
+        }
+    }
 
-// Helper, since we need to access one of to 31 similarly-named registers.
-macro_rules! set_register_sem {
-    ($semaphore_num:expr, $regs:expr, $core_id:expr, $proc_id:expr) => {
-        paste! {
-            $regs.[<r $semaphore_num>].modify(|_, w| {
-                w.procid().bits($proc_id);
-                w.coreid().bits($core_id);
-                w.lock().set_bit()
-            })
+    /// The AHB bus master ID, used as COREID in HSEM registers.
+    fn id(&self) -> u32 {
+        cfg_if! {
+            if #[cfg(feature = "wb")] {
+                match self {
+                    Self::C1 => 4,
+                    Self::C2 => 8,
+                }
+            } else {
+                match self {
+                    Self::C1 => 3,
+                    Self::C2 => 1,
+                }
+            }
         }
-    };
+    }
 }
 
 /// Represents an Hardware Semiphore (HSEM) peripheral.
+pub struct Hsem {
+    regs: HSEM,
+}
+
 impl Hsem {
+    /// Initialize the HSEM peripheral, including enabling and resetting its RCC peripheral clock.
+    /// On H7, the peripheral isn't reset, since the other core may already hold locks.
     pub fn new(regs: HSEM) -> Self {
-        let mut rcc = unsafe { &(*RCC::ptr()) };
+        let rcc = unsafe { &(*RCC::ptr()) };
 
-        rcc.ahb3enr.modify(|_, w| w.hsemen().set_bit());
-        rcc.ahb3rstr.modify(|_, w| w.hsemrst().set_bit());
-        rcc.ahb3rstr.modify(|_, w| w.hsemrst().clear_bit());
-
-        // todo: Why are these missing here and on IPCC `new`?
-        // rcc.ahb4enr.modify(|_, w| w.hsemen().set_bit());
-        // rcc.ahb4rstr.modify(|_, w| w.hsemrst().set_bit());
-        // rcc.ahb4rstr.modify(|_, w| w.hsemrst().clear_bit());
+        cfg_if! {
+            if #[cfg(feature = "wb")] {
+                rcc.ahb3enr.modify(|_, w| w.hsemen().set_bit());
+                rcc.ahb3rstr.modify(|_, w| w.hsemrst().set_bit());
+                rcc.ahb3rstr.modify(|_, w| w.hsemrst().clear_bit());
+            } else {
+                rcc.ahb4enr.modify(|_, w| w.hsemen().set_bit());
+            }
+        }
 
         Self { regs }
     }
 
     /// RM: The 2-step lock procedure consists in a write to lock the semaphore, followed by a read to
-    /// check if the lock has been successful, carried out from the HSEM_Rx register
-    pub fn lock_2_step(&mut self, core: Core, semaphore_num: u8) {
+    /// check if the lock has been successful, carried out from the HSEM_Rx register. `proc_id`
+    /// identifies the process on this core, eg an RTOS task; it can't be 0, since 1-step locks use
+    /// that. Returns `HsemError::ProcId` if it is.
+    pub fn lock_2_step(
+        &mut self,
+        core: Core,
+        semaphore_num: u8,
+        proc_id: u8,
+    ) -> Result<(), HsemError> {
         if semaphore_num > 31 {
             panic!("Semaphore number must be 0 - 31.")
         }
-
-        // todo: You need a macro to do this! Currently only works on semaphore 1.
-
-        let core_id = 0; // todo temp!
-        let proc_id = 0; // todo temp!
+        if proc_id == 0 {
+            return Err(HsemError::ProcId);
+        }
+        let val = LOCK | (core.id() << 8) | proc_id as u32;
 
         // * Write semaphore with PROCID and COREID, and LOCK = 1. The COREID data
-        // written by software must match the AHB bus master information. i.e. a AHB bus master
-        // set_register_sem!(semaphore_num, self.regs, core_id, proc_id);  // todo problem with macro syntax
-
-        // ID = 1writes data COREID = 1.
+        // written by software must match the AHB bus master information.
         // Lock is put in place when the semaphore is free at write time.
+        self.regs.r[semaphore_num as usize].write(|w| unsafe { w.bits(val) });
+
         // * Read-back the semaphore
         // The software checks the lock status, if PROCID and COREID match the written data,
         // then the lock is confirmed.
         // * Else retry (the semaphore has been locked by another process, AHB bus master ID).
+        if self.regs.r[semaphore_num as usize].read().bits() == val {
+            Ok(())
+        } else {
+            Err(HsemError::Locked)
+        }
     }
 
     /// RM: The 1-step procedure consists in a read to lock and check the semaphore in a single step,
     /// carried out from the HSEM_RLRx register.
-    pub fn lock_1_step(&mut self, core: Core, semaphore_num: u8) {
+    pub fn lock_1_step(&mut self, core: Core, semaphore_num: u8) -> Result<(), HsemError> {
         if semaphore_num > 31 {
             panic!("Semaphore number must be 0 - 31.")
         }
@@ -82,14 +148,15 @@ impl Hsem {
         // matches and PROCID is not 0, this means that another process from the same
         // COREID has locked the semaphore with a 2-step (write) procedure.
         // * Else retry (the semaphore has been locked by another process, AHB bus master ID).
-        // A semaphore can only be locked when it is free. When read locking a free semaphore,
-        // PROCID is 0. Read locking a locked semaphore returns the COREID and PROCID that
-        // locked it. All read locks, including the first one that locks the semaphore, return the COREID
-        // that locks or locked the semaphore.
+        if self.regs.rlr[semaphore_num as usize].read().bits() == LOCK | (core.id() << 8) {
+            Ok(())
+        } else {
+            Err(HsemError::Locked)
+        }
     }
 
-    /// Unlock a semaphore.
-    pub fn unlock(&self, core: Core, semaphore_num: u8) {
+    /// Unlock a semaphore. `proc_id` must match the one used to lock it; use 0 for 1-step locks.
+    pub fn unlock(&mut self, core: Core, semaphore_num: u8, proc_id: u8) {
         if semaphore_num > 31 {
             panic!("Semaphore number must be 0 - 31.")
         }
@@ -99,36 +166,76 @@ impl Hsem {
         // and LOCK = 0. When unlocked the semaphore, the COREID, and the PROCID are all 0.
         // When unlocked, an interrupt may be generated to signal the event. To this end, the
         // semaphore interrupt shall be enabled.
-        // The unlock procedure consists in a write to the semaphore HSEM_Rx register with
-        // matching COREID regardless on how the semaphore has been locked (1-step or 2-step).
-        //  Write semaphore with PROCID, COREID, and LOCK = 0
-        //  If the written data matches the semaphore PROCID and COREID and the AHB bus
+        //  If the written data matches the semaphore PROCID and COREID and the AHB bus
         // master ID , the semaphore is unlocked and an interrupt may be generated when
-        // enabled, else write is ignored, semaphore remains locked and no interrupt is generated
-        // (the semaphore is locked by another process, AHB bus master ID or the written data
-        // does not match the AHB bus master signaling).
+        // enabled, else write is ignored, semaphore remains locked and no interrupt is generated.
+        self.regs.r[semaphore_num as usize]
+            .write(|w| unsafe { w.bits((core.id() << 8) | proc_id as u32) });
+    }
+
+    /// Unlock all semaphores locked by a core, eg after restarting it. `key` must match the one set
+    /// with `set_clear_key`; it's 0 on reset.
+    pub fn unlock_all(&mut self, core: Core, key: u16) {
+        self.regs
+            .cr
+            .write(|w| unsafe { w.bits(((key as u32) << 16) | (core.id() << 8)) });
     }
 
-    /// Enable an interrupt.
+    /// Set the key used to unlock all of a core's semaphores, with `unlock_all`.
+    pub fn set_clear_key(&mut self, key: u16) {
+        self.regs
+            .keyr
+            .write(|w| unsafe { w.bits((key as u32) << 16) });
+    }
+
+    /// Check if a semaphore is locked, by any core.
+    pub fn is_locked(&self, semaphore_num: u8) -> bool {
+        if semaphore_num > 31 {
+            panic!("Semaphore number must be 0 - 31.")
+        }
+        self.regs.r[semaphore_num as usize].read().bits() & LOCK != 0
+    }
+
+    /// Enable an interrupt on a core, that fires when a semaphore is unlocked.
     pub fn enable_interrupt(&mut self, core: Core, semaphore_num: u8) {
         if semaphore_num > 31 {
             panic!("Semaphore number must be 0 - 31.")
         }
         // Cnier doesn't have individual fields
-        match core {
-            Core::C1 => {
-                let orig_value = self.regs.c1ier.read().bits();
-                self.regs
-                    .c1ier
-                    .write(|w| unsafe { w.bits(orig_value | (1 << semaphore_num)) });
-            }
-            Core::C2 => {
-                let orig_value = self.regs.c2ier.read().bits();
-                self.regs
-                    .c2ier
-                    .write(|w| unsafe { w.bits(orig_value | (1 << semaphore_num)) });
-            }
+        let ier = match core {
+            Core::C1 => C1IER,
+            Core::C2 => C2IER,
+        };
+        let orig_value = self.read_reg(ier);
+        self.write_reg(ier, orig_value | (1 << semaphore_num));
+    }
+
+    /// Disable an interrupt on a core.
+    pub fn disable_interrupt(&mut self, core: Core, semaphore_num: u8) {
+        if semaphore_num > 31 {
+            panic!("Semaphore number must be 0 - 31.")
         }
+        let ier = match core {
+            Core::C1 => C1IER,
+            Core::C2 => C2IER,
+        };
+        let orig_value = self.read_reg(ier);
+        self.write_reg(ier, orig_value & !(1 << semaphore_num));
+    }
+
+    /// Check if a semaphore was unlocked, since its flag was last cleared. If `masked`, only
+    /// report semaphores with the interrupt enabled on this core.
+    pub fn interrupt_flag(&self, core: Core, semaphore_num: u8, masked: bool) -> bool {
+        if semaphore_num > 31 {
+            panic!("Semaphore number must be 0 - 31.")
+        }
+        let isr = match (core, masked) {
+            (Core::C1, false) => C1ISR,
+            (Core::C1, true) => C1MISR,
+            (Core::C2, false) => C2ISR,
+            (Core::C2, true) => C2MISR,
+        };
+        self.read_reg(isr) & (1 << semaphore_num) != 0
     }
 
     /// Clear an interrupt flag - run this in the interrupt's handler to prevent
@@ -137,20 +244,22 @@ impl Hsem {
         if semaphore_num > 31 {
             panic!("Semaphore number must be 0 - 31.")
         }
-        // todo: Do we need to read, or can we just do a write of the relevant bit
-        match core {
-            Core::C1 => {
-                let orig_value = self.regs.c1icr.read().bits();
-                self.regs
-                    .c1icr
-                    .write(|w| unsafe { w.bits(orig_value | (1 << semaphore_num)) });
-            }
-            Core::C2 => {
-                let orig_value = self.regs.c2icr.read().bits();
-                self.regs
-                    .c2icr
-                    .write(|w| unsafe { w.bits(orig_value | (1 << semaphore_num)) });
-            }
-        }
+        let icr = match core {
+            Core::C1 => C1ICR,
+            Core::C2 => C2ICR,
+        };
+        self.write_reg(icr, 1 << semaphore_num);
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        unsafe { (&*self.regs as *const _ as *mut u8).add(offset) as *mut u32 }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.reg(offset)) }
+    }
+
+    fn write_reg(&mut self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(offset), val) }
     }
 }
@@ -2,10 +2,12 @@
 //! Provides APIs to configure, read, and write from
 //! I2C, with blocking, nonblocking, and DMA functionality.
 
-use core::ops::Deref;
+use core::{fmt, ops::Deref};
 
 // #[cfg(feature = "embedded_hal")]
 // use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+#[cfg(not(feature = "h5"))]
+use crate::af_pins::I2cPins;
 #[cfg(any(feature = "f3", feature = "l4"))]
 use crate::dma::DmaInput;
 #[cfg(not(any(feature = "l552", feature = "h5")))]
@@ -14,22 +16,23 @@ use crate::dma::{self, ChannelCfg, DmaChannel};
 use crate::pac::DMA as DMA1;
 #[cfg(not(any(feature = "g0", feature = "h5")))]
 use crate::pac::DMA1;
+#[cfg(feature = "defmt")]
+use crate::util::format_flags;
 use crate::{
-    clocks::Clocks,
+    clocks::{ClockFreqs, ClockListener},
+    instant::Timeout,
     pac::{self, RCC},
-    util::RccPeriph,
-    MAX_ITERS,
+    util::{fmt_flags, BaudPeriph, RccPeriph},
 };
 
 macro_rules! busy_wait {
     ($regs:expr, $flag:ident) => {
-        let mut i = 0;
+        let mut timeout = Timeout::new();
 
         loop {
             let isr = $regs.isr.read();
 
-            i += 1;
-            if i >= MAX_ITERS {
+            if timeout.expired() {
                 return Err(Error::Hardware);
             }
 
@@ -63,7 +66,8 @@ macro_rules! busy_wait {
 
 /// I2C error
 #[non_exhaustive]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
     /// Bus error
     Bus,
@@ -76,6 +80,48 @@ pub enum Error {
     // Timeout, // SMBUS mode only
     // Alert, // SMBUS mode only
     Hardware,
+    /// A configuration register didn't read back as written; eg the peripheral clock isn't
+    /// enabled.
+    VerifyFailed,
+}
+
+/// An I2C interrupt and status register (ISR) value, from `I2c::status()`. Its `Debug` and
+/// `defmt::Format` implementations list the flags that are set, eg "NACKF + STOPF", for logging.
+#[derive(Clone, Copy, PartialEq)]
+pub struct I2cStatus(pub u32);
+
+impl I2cStatus {
+    const FLAGS: &'static [(u8, &'static str)] = &[
+        (0, "TXE"),
+        (1, "TXIS"),
+        (2, "RXNE"),
+        (3, "ADDR"),
+        (4, "NACKF"),
+        (5, "STOPF"),
+        (6, "TC"),
+        (7, "TCR"),
+        (8, "BERR"),
+        (9, "ARLO"),
+        (10, "OVR"),
+        (11, "PECERR"),
+        (12, "TIMEOUT"),
+        (13, "ALERT"),
+        (15, "BUSY"),
+        (16, "DIR"),
+    ];
+}
+
+impl fmt::Debug for I2cStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_flags(self.0, Self::FLAGS, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for I2cStatus {
+    fn format(&self, f: defmt::Formatter) {
+        format_flags(self.0, Self::FLAGS, f)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -167,11 +213,12 @@ pub struct I2c<R> {
 
 impl<R> I2c<R>
 where
-    R: Deref<Target = pac::i2c1::RegisterBlock> + RccPeriph,
+    R: Deref<Target = pac::i2c1::RegisterBlock> + RccPeriph + BaudPeriph,
 {
     /// Initialize a I2C peripheral, including configuration register writes, and enabling and resetting
-    /// its RCC peripheral clock. `freq` is in Hz.
-    pub fn new(regs: R, cfg: I2cConfig, clocks: &Clocks) -> Self {
+    /// its RCC peripheral clock. `clocks` is the result of `Clocks::freeze()`, or `&clock_cfg`;
+    /// TIMINGR is calculated using the I2C kernel clock.
+    pub fn new(regs: R, cfg: I2cConfig, clocks: impl Into<ClockFreqs>) -> Self {
         let rcc = unsafe { &(*RCC::ptr()) };
         R::en_reset(rcc);
 
@@ -181,6 +228,50 @@ where
         // todo: Slave currently nonfunctional!
         // todo: Check out the RM recipes for slave transmitter and receiver.
 
+        Self::write_timing(&regs, cfg.speed, R::baud(&clocks.into()));
+
+        // Before enabling the I2C peripheral by setting the PE bit in I2C_CR1 register, the user must
+        // configure the noise filters, if needed. By default, an analog noise filter is present on the SDA
+        // and SCL inputs. This analog filter is compliant with the I2C specification which requires the
+        // suppression of spikes with a pulse width up to 50 ns in Fast-mode and Fast-mode Plus. The
+        // user can disable this analog filter by setting the ANFOFF bit, and/or select a digital filter by
+        // configuring the DNF[3:0] bit in the I2C_CR1 register.
+        // When the digital filter is enabled, the level of the SCL or the SDA line is internally changed
+        // only if it remains stable for more than DNF x I2CCLK periods. This allows spikes with a
+        // programmable length of 1 to 15 I2CCLK periods to be suppressed.
+        let (anf_bit, dnf_bits) = match cfg.noise_filter {
+            NoiseFilter::Analog => (false, 0),
+            NoiseFilter::Digital(filtering_len) => {
+                assert!(filtering_len <= 0b1111);
+                (true, filtering_len)
+            }
+            NoiseFilter::Disabled => (true, 0),
+        };
+
+        regs.cr1.modify(|_, w| unsafe {
+            w.anfoff().bit(anf_bit);
+            w.dnf().bits(dnf_bits)
+        });
+
+        if let I2cMode::Slave = cfg.mode {
+            regs.cr1.modify(|_, w| w.nostretch().bit(cfg.nostretch));
+        }
+
+        let mut result = Self { regs, cfg };
+
+        if result.cfg.smbus {
+            result.enable_smbus().ok();
+        }
+
+        // Enable the peripheral. Note that this must not overwrite the filter and PEC settings.
+        result.regs.cr1.modify(|_, w| w.pe().set_bit());
+
+        result
+    }
+
+    /// Write TIMINGR for a preset speed, from the I2C kernel clock, in Hz. The peripheral must be
+    /// disabled.
+    fn write_timing(regs: &R, speed: I2cSpeed, t_i2cclk: u32) {
         // RM: I2C timings:
         // The timings must be configured in order to guarantee a correct data hold and setup time,
         // used in master and slave modes. This is done by programming the PRESC[3:0],
@@ -189,7 +280,6 @@ where
         // programming the PRESC[3:0], SCLH[7:0] and SCLL[7:0] bits in the I2C_TIMINGR register
 
         // For these speed and frequency variables, we use the RM's conventions.
-        let t_i2cclk = clocks.apb1();
 
         // assert!(t_i2cclk < (t_low - f_f) / 4);
         // assert!(t_i2cclk < t_high);
@@ -201,7 +291,7 @@ where
         // values.
 
         // We use this constant in several calculations.
-        let presc_const = match cfg.speed {
+        let presc_const = match speed {
             I2cSpeed::Standard10K => 4_000_000,
             I2cSpeed::Standard100K => 4_000_000,
             I2cSpeed::Fast400K => 8_000_000,
@@ -223,7 +313,7 @@ where
         // Hit the target freq by setting up t_scll (Period of SCL low)
         // to be half the whole period. These constants
         // are from the tables.
-        let freq = match cfg.speed {
+        let freq = match speed {
             I2cSpeed::Standard10K => 10_000,
             I2cSpeed::Standard100K => 100_000,
             I2cSpeed::Fast400K => 400_000,
@@ -246,7 +336,7 @@ where
         // 10K. THis may be due to delays
         // involved. The ratio is different for Fast-mode and Fast-mode+.
         // todo: Come back to this. How should we set this?
-        let sclh_val = match cfg.speed {
+        let sclh_val = match speed {
             I2cSpeed::Standard10K => scll_val - 4,
             I2cSpeed::Standard100K => scll_val - 4,
             I2cSpeed::Fast400K => scll_val * 4 / 10,
@@ -282,7 +372,7 @@ where
         // tSDADEL= SDADEL x tPRESC
         // Note: SDADEL is used to generate tHD:DAT timing
         // Sets TIMINGR reg, SDADEL field.
-        let sdadel = match cfg.speed {
+        let sdadel = match speed {
             I2cSpeed::Standard10K => 0x2,
             I2cSpeed::Standard100K => 0x2,
             I2cSpeed::Fast400K => 0x3,
@@ -296,7 +386,7 @@ where
         // tSCLDEL = (SCLDEL+1) x tPRESC
         // Note: tSCLDEL is used to generate tSU:DAT timing
         // Sets TIMINGR reg, SCLDEL field.
-        let scldel = match cfg.speed {
+        let scldel = match speed {
             I2cSpeed::Standard10K => 0x4,
             I2cSpeed::Standard100K => 0x4,
             I2cSpeed::Fast400K => 0x3,
@@ -319,44 +409,75 @@ where
             w.sclh().bits(sclh as u8);
             w.scll().bits(scll as u8)
         });
+    }
 
-        // Before enabling the I2C peripheral by setting the PE bit in I2C_CR1 register, the user must
-        // configure the noise filters, if needed. By default, an analog noise filter is present on the SDA
-        // and SCL inputs. This analog filter is compliant with the I2C specification which requires the
-        // suppression of spikes with a pulse width up to 50 ns in Fast-mode and Fast-mode Plus. The
-        // user can disable this analog filter by setting the ANFOFF bit, and/or select a digital filter by
-        // configuring the DNF[3:0] bit in the I2C_CR1 register.
-        // When the digital filter is enabled, the level of the SCL or the SDA line is internally changed
-        // only if it remains stable for more than DNF x I2CCLK periods. This allows spikes with a
-        // programmable length of 1 to 15 I2CCLK periods to be suppressed.
-        let (anf_bit, dnf_bits) = match cfg.noise_filter {
+    /// Recalculate TIMINGR using new clock frequencies, eg after changing clocks. Disables the
+    /// peripheral while writing, then re-enables it if it was enabled.
+    pub fn set_timing(&mut self, clocks: impl Into<ClockFreqs>) {
+        let originally_enabled = self.regs.cr1.read().pe().bit_is_set();
+        self.regs.cr1.modify(|_, w| w.pe().clear_bit());
+
+        Self::write_timing(&self.regs, self.cfg.speed, R::baud(&clocks.into()));
+
+        if originally_enabled {
+            self.regs.cr1.modify(|_, w| w.pe().set_bit());
+        }
+    }
+
+    /// Initialize an I2C peripheral as with `new()`, then check that its configuration registers
+    /// read back as written. Returns `Error::VerifyFailed` if they don't.
+    pub fn try_new(regs: R, cfg: I2cConfig, clocks: impl Into<ClockFreqs>) -> Result<Self, Error> {
+        let result = Self::new(regs, cfg, clocks);
+        result.verify_config()?;
+        Ok(result)
+    }
+
+    /// Initialize an I2C peripheral as with `new()`, after setting its SCL and SDA pins to their
+    /// alternate functions, with open drain outputs. The pins are checked against this peripheral's
+    /// AF table at compile time; eg `(PB6, PB7)` for I2C1. See the `af_pins` module.
+    #[cfg(not(feature = "h5"))]
+    pub fn new_with_pins(
+        regs: R,
+        pins: impl I2cPins<R>,
+        cfg: I2cConfig,
+        clocks: impl Into<ClockFreqs>,
+    ) -> Self {
+        pins.connect();
+        Self::new(regs, cfg, clocks)
+    }
+
+    /// Read the interrupt and status register (ISR), eg to log it after an error.
+    pub fn status(&self) -> I2cStatus {
+        I2cStatus(self.regs.isr.read().bits())
+    }
+
+    /// Read back the configuration registers set by `new()`, and compare them to the stored
+    /// configuration. This can be run periodically, eg as part of a self-test.
+    pub fn verify_config(&self) -> Result<(), Error> {
+        let cr1 = self.regs.cr1.read();
+
+        let (anf_bit, dnf_bits) = match self.cfg.noise_filter {
             NoiseFilter::Analog => (false, 0),
-            NoiseFilter::Digital(filtering_len) => {
-                assert!(filtering_len <= 0b1111);
-                (true, filtering_len)
-            }
+            NoiseFilter::Digital(filtering_len) => (true, filtering_len),
             NoiseFilter::Disabled => (true, 0),
         };
 
-        regs.cr1.modify(|_, w| unsafe {
-            w.anfoff().bit(anf_bit);
-            w.dnf().bits(dnf_bits)
-        });
+        let mut ok = cr1.pe().bit_is_set()
+            && cr1.anfoff().bit_is_set() == anf_bit
+            && cr1.dnf().bits() == dnf_bits
+            && cr1.pecen().bit_is_set() == self.cfg.smbus
+            // SCLL and SCLH are always non-zero after `new()`.
+            && self.regs.timingr.read().bits() != 0;
 
-        if let I2cMode::Slave = cfg.mode {
-            regs.cr1.modify(|_, w| w.nostretch().bit(cfg.nostretch));
+        if let I2cMode::Slave = self.cfg.mode {
+            ok &= cr1.nostretch().bit_is_set() == self.cfg.nostretch;
         }
 
-        let mut result = Self { regs, cfg };
-
-        if result.cfg.smbus {
-            result.enable_smbus().ok();
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::VerifyFailed)
         }
-
-        // Enable the peripheral
-        result.regs.cr1.write(|w| w.pe().set_bit());
-
-        result
     }
 
     /// Enable SMBus support. See L44 RM, section 37.4.11: SMBus initialization
@@ -376,10 +497,9 @@ where
         if originally_enabled {
             self.regs.cr1.modify(|_, w| w.pe().clear_bit());
 
-            let mut i = 0;
+            let mut timeout = Timeout::new();
             while self.regs.cr1.read().pe().bit_is_set() {
-                i += 1;
-                if i >= MAX_ITERS {
+                if timeout.expired() {
                     return Err(Error::Hardware);
                 }
             }
@@ -405,10 +525,9 @@ where
         // automatically. This could be up to 50% of a bus
         // cycle (ie. up to 0.5/freq)
 
-        let mut i = 0;
+        let mut timeout = Timeout::new();
         while self.regs.cr2.read().start().bit_is_set() {
-            i += 1;
-            if i >= MAX_ITERS {
+            if timeout.expired() {
                 return Err(Error::Hardware);
             }
         }
@@ -433,10 +552,9 @@ where
         // Wait for any previous address sequence to end
         // automatically. This could be up to 50% of a bus
         // cycle (ie. up to 0.5/freq)
-        let mut i = 0;
+        let mut timeout = Timeout::new();
         while self.regs.cr2.read().start().bit_is_set() {
-            i += 1;
-            if i >= MAX_ITERS {
+            if timeout.expired() {
                 return Err(Error::Hardware);
             }
         }
@@ -461,10 +579,9 @@ where
         // Wait for any previous address sequence to end
         // automatically. This could be up to 50% of a bus
         // cycle (ie. up to 0.5/freq)
-        let mut i = 0;
+        let mut timeout = Timeout::new();
         while self.regs.cr2.read().start().bit_is_set() {
-            i += 1;
-            if i >= MAX_ITERS {
+            if timeout.expired() {
                 return Err(Error::Hardware);
             }
         }
@@ -735,6 +852,16 @@ where
         unsafe { self.regs.isr.read().bits() }
     }
 }
+
+impl<R> ClockListener for I2c<R>
+where
+    R: Deref<Target = pac::i2c1::RegisterBlock> + RccPeriph + BaudPeriph,
+{
+    /// Recalculate TIMINGR to keep the configured speed.
+    fn clocks_changed(&mut self, freqs: &ClockFreqs) {
+        self.set_timing(freqs);
+    }
+}
 //
 // #[cfg(feature = "embedded_hal")]
 // // #[cfg_attr(docsrs, doc(cfg(feature = "embedded_hal")))]
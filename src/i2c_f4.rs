@@ -5,16 +5,19 @@
 
 // Based on `stm32f4xx-hal`.
 
-use core::ops::Deref;
+use core::{fmt, ops::Deref};
 
 #[cfg(feature = "embedded_hal")]
 use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
 use paste::paste;
 
+#[cfg(feature = "defmt")]
+use crate::util::format_flags;
 use crate::{
+    af_pins::I2cPins,
     clocks::Clocks,
     pac::{self, i2c1, RCC},
-    util::rcc_en_reset,
+    util::{fmt_flags, rcc_en_reset},
 };
 
 #[derive(Clone, Copy)]
@@ -25,7 +28,8 @@ pub enum I2cDevice {
     Three,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
     OVERRUN,
     NACK,
@@ -37,6 +41,43 @@ pub enum Error {
     ARBITRATION,
 }
 
+/// An I2C status register 1 (SR1) value, from `I2c::status()`. Its `Debug` and `defmt::Format`
+/// implementations list the flags that are set, eg "AF + STOPF", for logging.
+#[derive(Clone, Copy, PartialEq)]
+pub struct I2cStatus(pub u32);
+
+impl I2cStatus {
+    const FLAGS: &'static [(u8, &'static str)] = &[
+        (0, "SB"),
+        (1, "ADDR"),
+        (2, "BTF"),
+        (3, "ADD10"),
+        (4, "STOPF"),
+        (6, "RXNE"),
+        (7, "TXE"),
+        (8, "BERR"),
+        (9, "ARLO"),
+        (10, "AF"),
+        (11, "OVR"),
+        (12, "PECERR"),
+        (14, "TIMEOUT"),
+        (15, "SMBALERT"),
+    ];
+}
+
+impl fmt::Debug for I2cStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_flags(self.0, Self::FLAGS, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for I2cStatus {
+    fn format(&self, f: defmt::Formatter) {
+        format_flags(self.0, Self::FLAGS, f)
+    }
+}
+
 /// Represents an Inter-Integrated Circuit (I2C) peripheral.
 pub struct I2c<R> {
     pub regs: R,
@@ -67,6 +108,25 @@ where
         result
     }
 
+    /// Initialize an I2C peripheral as with `new()`, after setting its SCL and SDA pins to their
+    /// alternate functions, with open drain outputs. The pins are checked against this peripheral's
+    /// AF table at compile time; eg `(PB6, PB7)` for I2C1. See the `af_pins` module.
+    pub fn new_with_pins(
+        regs: R,
+        pins: impl I2cPins<R>,
+        device: I2cDevice,
+        speed: u32,
+        clocks: &Clocks,
+    ) -> Self {
+        pins.connect();
+        Self::new(regs, device, speed, clocks)
+    }
+
+    /// Read status register 1 (SR1), eg to log it after an error.
+    pub fn status(&self) -> I2cStatus {
+        I2cStatus(self.regs.sr1.read().bits())
+    }
+
     fn i2c_init(&self, speed: u32, pclk: u32) {
         // Make sure the I2C unit is disabled so we can configure it
         self.regs.cr1.modify(|_, w| w.pe().clear_bit());
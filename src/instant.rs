@@ -1,12 +1,204 @@
 //! This module fits the requirement of `rtic-monotonic`, but has uses beyond that.
+//!
+//! It also provides a monotonic clock, for `Instant::now()`: Backed by the DWT cycle counter, or
+//! SysTick on Cortex-M0+ (G0) cores, or if you prefer to leave DWT free for a debugger. Use it to
+//! profile ISR latency, transfer durations etc. Once it's running, this library's peripheral
+//! busy-wait loops time out after a fixed duration, instead of an iteration count.
+//!
+//! Example:
+//! ```rust
+//! let mut cp = cortex_m::Peripherals::take().unwrap();
+//! instant::init_dwt(&mut cp.DCB, &mut cp.DWT, clock_cfg.systick());
+//!
+//! let start = Instant::now();
+//! spi.write(&buf).ok();
+//! println!("SPI write took {} us", start.elapsed().as_micros());
+//! ```
+//!
+//! If using SysTick, run `instant::on_systick()` from its exception handler:
+//! ```rust
+//! instant::init_systick(&mut cp.SYST, clock_cfg.systick());
+//!
+//! #[exception]
+//! fn SysTick() {
+//!     instant::on_systick();
+//! }
+//! ```
 
 use core::{
     self,
+    cell::Cell,
     cmp::{Ord, Ordering, PartialOrd},
     ops::{Add, Sub},
+    sync::atomic::{AtomicU32, AtomicU8, Ordering as AtomicOrdering},
     time::Duration,
 };
 
+#[cfg(not(feature = "g0"))]
+use cortex_m::peripheral::{DCB, DWT};
+use cortex_m::{
+    interrupt::{self, Mutex},
+    peripheral::{syst::SystClkSource, SCB, SYST},
+};
+
+use crate::MAX_ITERS;
+
+/// Busy-wait loops using `Timeout` expire after this, once a tick source is running.
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// SysTick is a 24-bit down-counter.
+const SYSTICK_RELOAD: u32 = 0x00ff_ffff;
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// The counter backing `Instant::now()`.
+enum TickSource {
+    None = 0,
+    #[cfg(not(feature = "g0"))]
+    Dwt = 1,
+    SysTick = 2,
+}
+
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::None as u8);
+/// The tick source's frequency, in Hz; the core clock.
+static TICK_FREQ: AtomicU32 = AtomicU32::new(0);
+/// Upper 32 bits of the tick count, and (for DWT) the lower 32 bits as of the last read. Used to
+/// extend the hardware counters to 64 bits.
+static TICKS_HIGH: Mutex<Cell<(u32, u32)>> = Mutex::new(Cell::new((0, 0)));
+
+#[cfg(not(feature = "g0"))]
+/// Start the monotonic clock, using the DWT cycle counter. `core_freq` is the core clock speed, in
+/// Hz; eg from `clock_cfg.systick()`. DWT's counter wraps every 2^32 cycles (eg 25s at 170Mhz);
+/// `Instant::now()` extends it, as long as it's called at least once per wrap period. Run this
+/// again if you change the core clock speed.
+pub fn init_dwt(dcb: &mut DCB, dwt: &mut DWT, core_freq: u32) {
+    dcb.enable_trace();
+    // Some cores (eg H7's Cortex-M7) lock DWT after power-up.
+    DWT::unlock();
+    dwt.enable_cycle_counter();
+
+    start(TickSource::Dwt, core_freq);
+}
+
+/// Start the monotonic clock, using SysTick, clocked from the core clock. `core_freq` is the core
+/// clock speed, in Hz. You must run `on_systick()` in the SysTick exception handler. Run this again if
+/// you change the core clock speed.
+pub fn init_systick(syst: &mut SYST, core_freq: u32) {
+    syst.disable_counter();
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(SYSTICK_RELOAD);
+    syst.clear_current();
+
+    start(TickSource::SysTick, core_freq);
+
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+fn start(source: TickSource, core_freq: u32) {
+    interrupt::free(|cs| TICKS_HIGH.borrow(cs).set((0, 0)));
+    TICK_FREQ.store(core_freq, AtomicOrdering::Relaxed);
+    TICK_SOURCE.store(source as u8, AtomicOrdering::Release);
+}
+
+/// Run this in the SysTick exception handler, if using SysTick as the tick source. Counts counter
+/// wraps, every 2^24 cycles.
+pub fn on_systick() {
+    interrupt::free(|cs| {
+        let ticks = TICKS_HIGH.borrow(cs);
+        let (wraps, last) = ticks.get();
+        ticks.set((wraps.wrapping_add(1), last));
+    });
+}
+
+/// Returns true if `init_dwt()` or `init_systick()` has been run.
+pub fn is_running() -> bool {
+    TICK_SOURCE.load(AtomicOrdering::Acquire) != TickSource::None as u8
+}
+
+/// The number of core clock cycles since the tick source was started. Returns 0 if it isn't
+/// running.
+pub fn cycles() -> u64 {
+    match TICK_SOURCE.load(AtomicOrdering::Acquire) {
+        #[cfg(not(feature = "g0"))]
+        s if s == TickSource::Dwt as u8 => interrupt::free(|cs| {
+            let ticks = TICKS_HIGH.borrow(cs);
+            let (mut high, last) = ticks.get();
+
+            let low = DWT::cycle_count();
+            if low < last {
+                high = high.wrapping_add(1);
+            }
+            ticks.set((high, low));
+
+            ((high as u64) << 32) | low as u64
+        }),
+        s if s == TickSource::SysTick as u8 => interrupt::free(|cs| {
+            let (mut wraps, _) = TICKS_HIGH.borrow(cs).get();
+
+            let mut current = SYST::get_current();
+            // If the counter wrapped but the handler hasn't run yet (eg since we're in a critical
+            // section), count the wrap here. Re-read, in case it wrapped after the first read.
+            if SCB::is_pendst_pending() {
+                wraps = wraps.wrapping_add(1);
+                current = SYST::get_current();
+            }
+
+            wraps as u64 * (SYSTICK_RELOAD as u64 + 1) + (SYSTICK_RELOAD - current) as u64
+        }),
+        _ => 0,
+    }
+}
+
+/// Convert a number of core clock cycles to a duration. Returns zero if the tick source isn't
+/// running.
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    let freq = TICK_FREQ.load(AtomicOrdering::Relaxed);
+    if freq == 0 {
+        return Duration::ZERO;
+    }
+
+    Duration::from_nanos((cycles as u128 * 1_000_000_000 / freq as u128) as u64)
+}
+
+/// Run a function, and return its result, along with how long it took.
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = cycles();
+    let result = f();
+    (result, cycles_to_duration(cycles() - start))
+}
+
+/// Bounds busy-wait loops, so they return an error instead of hanging. Expires after `TIMEOUT` if
+/// the tick source is running, and after `MAX_ITERS` polls otherwise; eg during clock setup.
+pub(crate) struct Timeout {
+    /// The start time, and the timeout length, in cycles. `TIMEOUT` is converted to cycles here,
+    /// instead of converting the elapsed cycles each poll, to keep the polling loops tight.
+    start: Option<(u64, u64)>,
+    polls: u32,
+}
+
+impl Timeout {
+    pub(crate) fn new() -> Self {
+        let start = is_running().then(|| {
+            let freq = TICK_FREQ.load(AtomicOrdering::Relaxed) as u64;
+            (cycles(), freq * TIMEOUT.as_millis() as u64 / 1_000)
+        });
+
+        Self { start, polls: 0 }
+    }
+
+    /// Returns true once the timeout has expired. Run this once per loop iteration.
+    pub(crate) fn expired(&mut self) -> bool {
+        match self.start {
+            Some((start, len)) => cycles().wrapping_sub(start) >= len,
+            None => {
+                self.polls += 1;
+                self.polls >= MAX_ITERS
+            }
+        }
+    }
+}
+
 /// A time instant, from the start of a timer, for use with `rtic-monotonic`. Currently only
 /// has microsecond precision.
 #[derive(Eq, PartialEq, PartialOrd, Copy, Clone, Default)]
@@ -18,6 +210,19 @@ pub struct Instant {
 }
 
 impl Instant {
+    /// The time since the monotonic clock was started with `init_dwt()` or `init_systick()`.
+    /// Returns the default (zero) instant if it isn't running.
+    pub fn now() -> Self {
+        Self {
+            count_ns: cycles_to_duration(cycles()).as_nanos() as i64,
+        }
+    }
+
+    /// The time elapsed since this instant, per the monotonic clock.
+    pub fn elapsed(&self) -> Duration {
+        Self::now() - *self
+    }
+
     /// The time, in seconds.
     pub fn as_secs(&self) -> f32 {
         // self.count_us as f32 / 1_000_000.
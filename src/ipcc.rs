@@ -1,7 +1,38 @@
 //! Inter-processor communication controller (IPCC).
 //! Used on STM32WB for communication between cores.
-
-use crate::pac::{self, IPCC, RCC};
+//!
+//! Also includes the mailbox shared tables used to communicate with the CPU2 wireless stack
+//! (BLE, 802.15.4 etc). CPU2 reads the reference table from the start of SRAM2A when it boots, so
+//! reserve that area in `memory.x`, and place `MailboxTables`, its buffers, and queues in SRAM2A too.
+//! These mirror `mbox_def.h` from ST's STM32CubeWB.
+//!
+//! Example:
+//! ```rust
+//! #[link_section = "MB_MEM1"]
+//! static mut TABLES: MailboxTables = MailboxTables::new();
+//! #[link_section = "MB_MEM2"]
+//! static mut SYS_CMD_BUF: [u8; 272] = [0; 272];
+//! // (Other buffers, eg BLE command and event pool, as required by the stack)
+//!
+//! let mut ipcc = Ipcc::new(dp.IPCC);
+//!
+//! unsafe {
+//!     TABLES.init(&MailboxBuffers {
+//!         sys_cmd: SYS_CMD_BUF.as_mut_ptr(),
+//!         ..Default::default()
+//!     });
+//! }
+//!
+//! // Wait for the `C2Ready` system event on `SYS_EVENT_CHANNEL` before sending commands.
+//! ipcc.set_rx_channel(Core::C1, SYS_EVENT_CHANNEL, true);
+//! start_cpu2();
+//! ```
+
+use core::ptr::{self, addr_of, addr_of_mut};
+
+use cortex_m::interrupt;
+
+use crate::pac::{IPCC, PWR, RCC};
 
 // todo: C1_1 and C2_1 etc for channels instead of separate core enum?
 // todo: Consider macros to reduce DRY here, re Core and Channel matching.
@@ -41,7 +72,8 @@ pub enum IpccMode {
     HalfDuplex = 1, // todo qc these
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// IPCC interrupts. Enabled in IPCC_C1CR.
 pub enum IpccInterrupt {
@@ -97,9 +129,10 @@ impl Ipcc {
         // by receiving processor) and the sending processor unmasks the channel free
         // interrupt (CHnFM = 0).
         if self.channel_is_free(core, channel) {
-            // self.regs.asdf.modify(|_, w| w.dasdf.bits(data));
+            // The communication data is posted by the caller, in shared memory.
+            self.set_flag_channel(core, channel);
         } else {
-            // todo: Unmask interrupt?
+            self.set_tx_channel(core, channel, true);
         }
         // – On a TX free interrupt, the sending processor checks which channel became free
         // and masks the channel free interrupt (CHnFM = 1). Then the new communication
@@ -383,6 +416,14 @@ impl Ipcc {
         });
     }
 
+    /// Disable a specific type of IPCC interrupt.
+    pub fn disable_interrupt(&mut self, interrupt: IpccInterrupt) {
+        self.regs.c1cr.modify(|_, w| match interrupt {
+            IpccInterrupt::TxFree => w.txfie().clear_bit(),
+            IpccInterrupt::RxOccupied => w.rxoie().clear_bit(),
+        });
+    }
+
     // Code below is taken from (and modified slightly) from stm32-wb-hal
     // todo: Reconcile it with your API above. Perhaps your API is better since it's directly
     // todo from the RM?
@@ -392,7 +433,7 @@ impl Ipcc {
     }
 
     pub fn is_rx_pending(&self, channel: IpccChannel) -> bool {
-        self.channel_is_free(Core::C2, channel) && self.get_rx_channel(Core::C1, channel)
+        !self.channel_is_free(Core::C2, channel) && self.get_rx_channel(Core::C1, channel)
     }
 
     pub fn get_rx_channel(&self, core: Core, channel: IpccChannel) -> bool {
@@ -523,3 +564,376 @@ impl Ipcc {
         }
     }
 }
+
+// Mailbox shared tables, and IPCC channel assignments, used by the CPU2 wireless stack.
+
+/// IPCC channel for BLE commands, CPU1 to CPU2.
+pub const BLE_CMD_CHANNEL: IpccChannel = IpccChannel::C1;
+/// IPCC channel for BLE events, CPU2 to CPU1.
+pub const BLE_EVENT_CHANNEL: IpccChannel = IpccChannel::C1;
+/// IPCC channel for system commands, CPU1 to CPU2. Half-duplex: CPU2 posts its response in the
+/// same buffer, then clears the channel.
+pub const SYS_CMD_RSP_CHANNEL: IpccChannel = IpccChannel::C2;
+/// IPCC channel for system events, CPU2 to CPU1.
+pub const SYS_EVENT_CHANNEL: IpccChannel = IpccChannel::C2;
+/// IPCC channel for 802.15.4 MAC commands and responses, CPU1 to CPU2. Half-duplex.
+pub const MAC_802_15_4_CMD_RSP_CHANNEL: IpccChannel = IpccChannel::C3;
+/// IPCC channel for 802.15.4 MAC notifications, CPU2 to CPU1.
+pub const MAC_802_15_4_NOTIFICATION_CHANNEL: IpccChannel = IpccChannel::C3;
+/// IPCC channel for returning event buffers to CPU2's memory manager, CPU1 to CPU2.
+pub const MM_RELEASE_BUFFER_CHANNEL: IpccChannel = IpccChannel::C4;
+/// IPCC channel for traces, CPU2 to CPU1.
+pub const TRACES_CHANNEL: IpccChannel = IpccChannel::C4;
+/// IPCC channel for HCI ACL data, CPU1 to CPU2.
+pub const HCI_ACL_DATA_CHANNEL: IpccChannel = IpccChannel::C6;
+
+/// Address of the reference table, at the start of SRAM2A. CPU2 reads it on boot.
+pub const REF_TABLE_ADDR: usize = 0x2003_0000;
+
+/// A node in a circular, doubly-linked list. Used for event queues shared with CPU2; events are
+/// linked through a node at the start of each event buffer. Matches `tl_list.c` from STM32CubeWB.
+#[repr(C)]
+pub struct LinkedListNode {
+    pub next: *mut LinkedListNode,
+    pub prev: *mut LinkedListNode,
+}
+
+impl LinkedListNode {
+    pub const fn new() -> Self {
+        Self {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+        }
+    }
+
+    /// Initialize a list head as empty, ie pointing to itself.
+    ///
+    /// # Safety
+    /// `head` must point to a valid node, that isn't currently in use by CPU2.
+    pub unsafe fn init_head(head: *mut Self) {
+        ptr::write_volatile(addr_of_mut!((*head).next), head);
+        ptr::write_volatile(addr_of_mut!((*head).prev), head);
+    }
+
+    /// Check if a list is empty.
+    ///
+    /// # Safety
+    /// `head` must point to a list head initialized with `init_head`.
+    pub unsafe fn is_empty(head: *const Self) -> bool {
+        interrupt::free(|_| ptr::eq(ptr::read_volatile(addr_of!((*head).next)) as *const _, head))
+    }
+
+    /// Insert a node at the end of a list.
+    ///
+    /// # Safety
+    /// `head` must point to an initialized list head, and `node` to a valid node not already in a
+    /// list.
+    pub unsafe fn insert_tail(head: *mut Self, node: *mut Self) {
+        interrupt::free(|_| {
+            let tail = ptr::read_volatile(addr_of!((*head).prev));
+
+            ptr::write_volatile(addr_of_mut!((*node).next), head);
+            ptr::write_volatile(addr_of_mut!((*node).prev), tail);
+            ptr::write_volatile(addr_of_mut!((*head).prev), node);
+            ptr::write_volatile(addr_of_mut!((*tail).next), node);
+        });
+    }
+
+    /// Remove and return the first node of a list, or `None` if it's empty.
+    ///
+    /// # Safety
+    /// `head` must point to an initialized list head.
+    pub unsafe fn remove_head(head: *mut Self) -> Option<*mut Self> {
+        interrupt::free(|_| {
+            let node = ptr::read_volatile(addr_of!((*head).next));
+            if node == head {
+                return None;
+            }
+
+            let next = ptr::read_volatile(addr_of!((*node).next));
+            ptr::write_volatile(addr_of_mut!((*head).next), next);
+            ptr::write_volatile(addr_of_mut!((*next).prev), head);
+
+            Some(node)
+        })
+    }
+}
+
+impl Default for LinkedListNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(C)]
+/// Written by CPU2, on boot.
+pub struct SafeBootInfoTable {
+    pub version: u32,
+}
+
+#[repr(C)]
+/// Written by CPU2, on boot. Describes the firmware upgrade service. (FUS)
+pub struct FusInfoTable {
+    pub version: u32,
+    pub memory_size: u32,
+    pub fus_info: u32,
+}
+
+#[repr(C)]
+/// Written by CPU2, on boot. Describes the wireless stack.
+pub struct WirelessFwInfoTable {
+    pub version: u32,
+    pub memory_size: u32,
+    pub thread_info: u32,
+    pub ble_info: u32,
+}
+
+#[repr(C)]
+/// Version info written by CPU2 on boot, eg to check the wireless stack is compatible.
+pub struct DeviceInfoTable {
+    pub safe_boot_info_table: SafeBootInfoTable,
+    pub fus_info_table: FusInfoTable,
+    pub wireless_fw_info_table: WirelessFwInfoTable,
+}
+
+#[repr(C)]
+pub struct BleTable {
+    pub pcmd_buffer: *mut u8,
+    /// Command status buffer.
+    pub pcs_buffer: *const u8,
+    pub pevt_queue: *const LinkedListNode,
+    pub phci_acl_data_buffer: *mut u8,
+}
+
+#[repr(C)]
+pub struct ThreadTable {
+    pub nostack_buffer: *const u8,
+    pub clicmdrsp_buffer: *const u8,
+    pub otcmdrsp_buffer: *const u8,
+}
+
+#[repr(C)]
+pub struct SysTable {
+    pub pcmd_buffer: *mut u8,
+    pub sys_queue: *const LinkedListNode,
+}
+
+#[repr(C)]
+/// CPU2 allocates events from the pool, and CPU1 returns them through the free buffer queue.
+pub struct MemManagerTable {
+    pub spare_ble_buffer: *const u8,
+    pub spare_sys_buffer: *const u8,
+    pub blepool: *const u8,
+    pub blepoolsize: u32,
+    pub pevt_free_buffer_queue: *mut LinkedListNode,
+    pub traces_evt_pool: *const u8,
+    pub tracespoolsize: u32,
+}
+
+#[repr(C)]
+pub struct TracesTable {
+    pub traces_queue: *const u8,
+}
+
+#[repr(C)]
+pub struct Mac802_15_4Table {
+    pub p_cmdrsp_buffer: *const u8,
+    pub p_notack_buffer: *const u8,
+    pub evt_queue: *const u8,
+}
+
+#[repr(C)]
+/// The table CPU2 reads from `REF_TABLE_ADDR`, pointing to each of the other tables.
+pub struct RefTable {
+    pub device_info_table: *const DeviceInfoTable,
+    pub ble_table: *const BleTable,
+    pub thread_table: *const ThreadTable,
+    pub sys_table: *const SysTable,
+    pub mem_manager_table: *const MemManagerTable,
+    pub traces_table: *const TracesTable,
+    pub mac_802_15_4_table: *const Mac802_15_4Table,
+}
+
+/// Buffers used by the wireless stack, in SRAM2A. Their sizes depend on the stack, and its
+/// configuration; see ST's AN5289. Leave unused ones null.
+pub struct MailboxBuffers {
+    /// System command buffer; holds one command packet, and its response.
+    pub sys_cmd: *mut u8,
+    /// BLE command buffer; holds one command packet.
+    pub ble_cmd: *mut u8,
+    /// BLE command status buffer.
+    pub ble_cs: *const u8,
+    pub hci_acl_data: *mut u8,
+    /// Pool CPU2 allocates events from.
+    pub evt_pool: *const u8,
+    pub evt_pool_len: u32,
+    /// Buffers CPU2 reserves, so it can always send system and BLE events.
+    pub spare_sys_evt: *const u8,
+    pub spare_ble_evt: *const u8,
+    pub mac_cmd_rsp: *const u8,
+    pub mac_notification: *const u8,
+}
+
+impl Default for MailboxBuffers {
+    fn default() -> Self {
+        Self {
+            sys_cmd: ptr::null_mut(),
+            ble_cmd: ptr::null_mut(),
+            ble_cs: ptr::null(),
+            hci_acl_data: ptr::null_mut(),
+            evt_pool: ptr::null(),
+            evt_pool_len: 0,
+            spare_sys_evt: ptr::null(),
+            spare_ble_evt: ptr::null(),
+            mac_cmd_rsp: ptr::null(),
+            mac_notification: ptr::null(),
+        }
+    }
+}
+
+#[repr(C)]
+/// The shared tables, and event queues used by the wireless stack. Place this in SRAM2A, and set
+/// it up with `init()` before starting CPU2.
+pub struct MailboxTables {
+    pub device_info: DeviceInfoTable,
+    pub ble: BleTable,
+    pub thread: ThreadTable,
+    pub sys: SysTable,
+    pub mem_manager: MemManagerTable,
+    pub traces: TracesTable,
+    pub mac_802_15_4: Mac802_15_4Table,
+    /// BLE events from CPU2.
+    pub ble_event_queue: LinkedListNode,
+    /// System events from CPU2.
+    pub sys_event_queue: LinkedListNode,
+    /// Event buffers CPU1 is done with, to be returned to CPU2 on `MM_RELEASE_BUFFER_CHANNEL`.
+    pub free_buffer_queue: LinkedListNode,
+    pub traces_queue: LinkedListNode,
+}
+
+impl MailboxTables {
+    pub const fn new() -> Self {
+        Self {
+            device_info: DeviceInfoTable {
+                safe_boot_info_table: SafeBootInfoTable { version: 0 },
+                fus_info_table: FusInfoTable {
+                    version: 0,
+                    memory_size: 0,
+                    fus_info: 0,
+                },
+                wireless_fw_info_table: WirelessFwInfoTable {
+                    version: 0,
+                    memory_size: 0,
+                    thread_info: 0,
+                    ble_info: 0,
+                },
+            },
+            ble: BleTable {
+                pcmd_buffer: ptr::null_mut(),
+                pcs_buffer: ptr::null(),
+                pevt_queue: ptr::null(),
+                phci_acl_data_buffer: ptr::null_mut(),
+            },
+            thread: ThreadTable {
+                nostack_buffer: ptr::null(),
+                clicmdrsp_buffer: ptr::null(),
+                otcmdrsp_buffer: ptr::null(),
+            },
+            sys: SysTable {
+                pcmd_buffer: ptr::null_mut(),
+                sys_queue: ptr::null(),
+            },
+            mem_manager: MemManagerTable {
+                spare_ble_buffer: ptr::null(),
+                spare_sys_buffer: ptr::null(),
+                blepool: ptr::null(),
+                blepoolsize: 0,
+                pevt_free_buffer_queue: ptr::null_mut(),
+                traces_evt_pool: ptr::null(),
+                tracespoolsize: 0,
+            },
+            traces: TracesTable {
+                traces_queue: ptr::null(),
+            },
+            mac_802_15_4: Mac802_15_4Table {
+                p_cmdrsp_buffer: ptr::null(),
+                p_notack_buffer: ptr::null(),
+                evt_queue: ptr::null(),
+            },
+            ble_event_queue: LinkedListNode::new(),
+            sys_event_queue: LinkedListNode::new(),
+            free_buffer_queue: LinkedListNode::new(),
+            traces_queue: LinkedListNode::new(),
+        }
+    }
+
+    /// Initialize the event queues, point the tables to the buffers, and write the reference table
+    /// to `REF_TABLE_ADDR`, where CPU2 looks for it on boot. Run this before `start_cpu2()`.
+    ///
+    /// # Safety
+    /// `self` and all buffers must be in SRAM2A, and must live, unmoved, for as long as CPU2 runs.
+    /// The area at `REF_TABLE_ADDR` must be reserved in the linker script for the reference table.
+    pub unsafe fn init(&'static mut self, buffers: &MailboxBuffers) {
+        LinkedListNode::init_head(&mut self.ble_event_queue);
+        LinkedListNode::init_head(&mut self.sys_event_queue);
+        LinkedListNode::init_head(&mut self.free_buffer_queue);
+        LinkedListNode::init_head(&mut self.traces_queue);
+
+        self.ble = BleTable {
+            pcmd_buffer: buffers.ble_cmd,
+            pcs_buffer: buffers.ble_cs,
+            pevt_queue: &self.ble_event_queue,
+            phci_acl_data_buffer: buffers.hci_acl_data,
+        };
+
+        self.sys = SysTable {
+            pcmd_buffer: buffers.sys_cmd,
+            sys_queue: &self.sys_event_queue,
+        };
+
+        self.mem_manager = MemManagerTable {
+            spare_ble_buffer: buffers.spare_ble_evt,
+            spare_sys_buffer: buffers.spare_sys_evt,
+            blepool: buffers.evt_pool,
+            blepoolsize: buffers.evt_pool_len,
+            pevt_free_buffer_queue: &mut self.free_buffer_queue,
+            traces_evt_pool: ptr::null(),
+            tracespoolsize: 0,
+        };
+
+        self.traces = TracesTable {
+            traces_queue: &self.traces_queue as *const _ as *const u8,
+        };
+
+        self.mac_802_15_4 = Mac802_15_4Table {
+            p_cmdrsp_buffer: buffers.mac_cmd_rsp,
+            p_notack_buffer: buffers.mac_notification,
+            evt_queue: ptr::null(),
+        };
+
+        let ref_table = RefTable {
+            device_info_table: &self.device_info,
+            ble_table: &self.ble,
+            thread_table: &self.thread,
+            sys_table: &self.sys,
+            mem_manager_table: &self.mem_manager,
+            traces_table: &self.traces,
+            mac_802_15_4_table: &self.mac_802_15_4,
+        };
+
+        ptr::write_volatile(REF_TABLE_ADDR as *mut RefTable, ref_table);
+    }
+}
+
+impl Default for MailboxTables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Boot CPU2, by setting PWR_CR4, C2BOOT. Set up the shared tables with `MailboxTables::init()`,
+/// and configure the IPCC first: CPU2 signals it's ready with a system event.
+pub fn start_cpu2() {
+    let pwr = unsafe { &(*PWR::ptr()) };
+    pwr.cr4.modify(|_, w| w.c2boot().set_bit());
+}
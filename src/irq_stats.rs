@@ -0,0 +1,237 @@
+//! Interrupt execution time and rate measurement, using the DWT cycle counter. Use this to check
+//! whether interrupt handlers (eg for DMA, or UART) fit your real-time budget, and how much CPU time
+//! they take.
+//!
+//! Each monitored interrupt uses a slot in an `IrqMonitor`, which tracks its number of runs, and its
+//! maximum and total execution time, in CPU cycles. Recording takes a few cycles at entry and exit.
+//! Not available on Cortex-M0+ (G0), which doesn't have a cycle counter.
+//!
+//! Example:
+//! ```rust
+//! const DMA_SLOT: usize = 0;
+//! const UART_SLOT: usize = 1;
+//!
+//! static IRQ_STATS: IrqMonitor<2> = IrqMonitor::new();
+//!
+//! // At init:
+//! irq_stats::enable_cycle_counter(&mut cp.DCB, &mut cp.DWT);
+//!
+//! #[interrupt]
+//! fn DMA1_CH1() {
+//!     IRQ_STATS.measure(DMA_SLOT, || {
+//!         // Handler code
+//!     });
+//! }
+//!
+//! // Elsewhere, eg once a second:
+//! let stats = IRQ_STATS.take(DMA_SLOT);
+//! println!("Max: {}us, load: {}%", stats.max_us(clock_cfg.sysclk()), stats.load() * 100.);
+//! ```
+//!
+//! Times include any higher-priority interrupts that preempt the handler. Windows (the time between
+//! `take()` or `reset()` calls) must be shorter than the cycle counter's period, eg 26 seconds at
+//! 160Mhz.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::{
+    interrupt,
+    peripheral::{DCB, DWT},
+};
+
+/// Enable the DWT cycle counter, which the monitor uses. Some debuggers enable this automatically,
+/// but it's not enabled by default.
+pub fn enable_cycle_counter(dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+/// Statistics for a single interrupt, over a measurement window.
+pub struct IrqStats {
+    /// The number of times the handler ran.
+    pub count: u32,
+    /// The longest run, in CPU cycles.
+    pub max_cycles: u32,
+    /// The total time spent in the handler, in CPU cycles.
+    pub total_cycles: u64,
+    /// The length of the measurement window, in CPU cycles.
+    pub window_cycles: u32,
+}
+
+impl IrqStats {
+    /// The mean run time, in CPU cycles.
+    pub fn mean_cycles(&self) -> u32 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.total_cycles / self.count as u64) as u32
+        }
+    }
+
+    /// The longest run, in microseconds, for a CPU clock `sysclk` in Hz.
+    pub fn max_us(&self, sysclk: u32) -> f32 {
+        self.max_cycles as f32 * 1_000_000. / sysclk as f32
+    }
+
+    /// The mean run time, in microseconds, for a CPU clock `sysclk` in Hz.
+    pub fn mean_us(&self, sysclk: u32) -> f32 {
+        self.mean_cycles() as f32 * 1_000_000. / sysclk as f32
+    }
+
+    /// The rate the handler ran at over the window, in Hz, for a CPU clock `sysclk` in Hz.
+    pub fn rate_hz(&self, sysclk: u32) -> f32 {
+        if self.window_cycles == 0 {
+            0.
+        } else {
+            self.count as f32 * sysclk as f32 / self.window_cycles as f32
+        }
+    }
+
+    /// The portion of CPU time spent in the handler over the window; 0 to 1.
+    pub fn load(&self) -> f32 {
+        if self.window_cycles == 0 {
+            0.
+        } else {
+            self.total_cycles as f32 / self.window_cycles as f32
+        }
+    }
+}
+
+/// Measurements for one interrupt. Each slot is only written by its own handler, which can't
+/// preempt itself, so these don't need read-modify-write atomics.
+struct Slot {
+    count: AtomicU32,
+    max_cycles: AtomicU32,
+    total_lo: AtomicU32,
+    total_hi: AtomicU32,
+    window_start: AtomicU32,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU32::new(0),
+            max_cycles: AtomicU32::new(0),
+            total_lo: AtomicU32::new(0),
+            total_hi: AtomicU32::new(0),
+            window_start: AtomicU32::new(0),
+        }
+    }
+
+    fn record(&self, cycles: u32) {
+        self.count.store(
+            self.count.load(Ordering::Relaxed).wrapping_add(1),
+            Ordering::Relaxed,
+        );
+
+        if cycles > self.max_cycles.load(Ordering::Relaxed) {
+            self.max_cycles.store(cycles, Ordering::Relaxed);
+        }
+
+        let (lo, carry) = self
+            .total_lo
+            .load(Ordering::Relaxed)
+            .overflowing_add(cycles);
+        self.total_lo.store(lo, Ordering::Relaxed);
+        if carry {
+            self.total_hi.store(
+                self.total_hi.load(Ordering::Relaxed).wrapping_add(1),
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Read statistics. Run this in a critical section, so the handler can't update the slot
+    /// part-way through.
+    fn read(&self, now: u32) -> IrqStats {
+        IrqStats {
+            count: self.count.load(Ordering::Relaxed),
+            max_cycles: self.max_cycles.load(Ordering::Relaxed),
+            total_cycles: ((self.total_hi.load(Ordering::Relaxed) as u64) << 32)
+                | self.total_lo.load(Ordering::Relaxed) as u64,
+            window_cycles: now.wrapping_sub(self.window_start.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self, now: u32) {
+        self.count.store(0, Ordering::Relaxed);
+        self.max_cycles.store(0, Ordering::Relaxed);
+        self.total_lo.store(0, Ordering::Relaxed);
+        self.total_hi.store(0, Ordering::Relaxed);
+        self.window_start.store(now, Ordering::Relaxed);
+    }
+}
+
+/// Tracks execution time for up to `N` interrupts. Place this in a `static`; all methods take
+/// `&self`.
+pub struct IrqMonitor<const N: usize> {
+    slots: [Slot; N],
+}
+
+impl<const N: usize> IrqMonitor<N> {
+    /// Create a monitor. The first measurement window starts when the cycle counter is 0; run
+    /// `reset()` after enabling the cycle counter to start it then.
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const SLOT: Slot = Slot::new();
+        Self { slots: [SLOT; N] }
+    }
+
+    /// Call at the start of a handler. Returns the start time, to pass to `exit()`.
+    pub fn enter(&self) -> u32 {
+        DWT::cycle_count()
+    }
+
+    /// Call at the end of a handler, with the time returned by `enter()`. Panics if `slot` is out of
+    /// range.
+    pub fn exit(&self, slot: usize, start: u32) {
+        self.slots[slot].record(DWT::cycle_count().wrapping_sub(start));
+    }
+
+    /// Run a handler's code, recording its execution time in `slot`.
+    pub fn measure<T>(&self, slot: usize, f: impl FnOnce() -> T) -> T {
+        let start = self.enter();
+        let result = f();
+        self.exit(slot, start);
+        result
+    }
+
+    /// Statistics for `slot`, since the last `take()` or `reset()`.
+    pub fn stats(&self, slot: usize) -> IrqStats {
+        interrupt::free(|_| self.slots[slot].read(DWT::cycle_count()))
+    }
+
+    /// Statistics for `slot`, since the last `take()` or `reset()`. This starts a new measurement
+    /// window for the slot.
+    pub fn take(&self, slot: usize) -> IrqStats {
+        interrupt::free(|_| {
+            let now = DWT::cycle_count();
+            let result = self.slots[slot].read(now);
+            self.slots[slot].reset(now);
+            result
+        })
+    }
+
+    /// Clear statistics for all slots, and start a new measurement window.
+    pub fn reset(&self) {
+        interrupt::free(|_| {
+            let now = DWT::cycle_count();
+            for slot in &self.slots {
+                slot.reset(now);
+            }
+        })
+    }
+
+    /// The combined portion of CPU time spent in all monitored handlers, since the last `reset()`.
+    /// This uses each slot's window, so is most useful when they're reset together.
+    pub fn total_load(&self) -> f32 {
+        (0..N).map(|i| self.stats(i).load()).sum()
+    }
+}
+
+impl<const N: usize> Default for IrqMonitor<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -40,7 +40,9 @@
 //! hal = { package = "stm32-hal2", version = "^1.5.5", features = ["l4x3", "l4rt"]}
 //! ```
 //!
-//! If you need `embedded-hal` traits, include the `embedded-hal` feature.
+//! If you need `embedded-hal` traits, include the `embedded-hal` feature. For `embedded-hal-async`'s
+//! `Wait` on GPIO pins, include the `async` feature. For `defmt::Format` implementations of
+//! config, error, and status types, include the `defmt` feature.
 //!
 //! You can review [this section of Cargo.toml](https://github.com/David-OConnor/stm32-hal/blob/main/Cargo.toml#L61)
 //! to see which MCU and runtime features are available.
@@ -334,6 +336,15 @@ pub use stm32wl::stm32wle5 as pac;
 #[cfg(not(any(feature = "f301", feature = "f302")))]
 pub mod adc;
 
+#[cfg(not(feature = "h5"))] // todo: H5 SPI, and H5 AF tables.
+pub mod af_pins;
+
+#[cfg(all(
+    any(feature = "f3", feature = "l4", feature = "g4", feature = "h7"),
+    not(any(feature = "f301", feature = "f302"))
+))]
+pub mod analog_loop;
+
 // bxCAN families: F3, F4, L4,
 // fdCAN families: L5, U5, G4, H7
 // H7 suppords fd and can_ccu. (What's that?)
@@ -349,6 +360,12 @@ pub mod can;
 // pub mod fd_can;
 
 pub mod clocks;
+
+#[cfg(feature = "console")]
+pub mod console;
+
+#[cfg(any(feature = "g4", feature = "h735"))]
+pub mod cordic;
 // todo: You could get CRC working on these.
 #[cfg(not(any(
     feature = "f3",
@@ -359,6 +376,35 @@ pub mod clocks;
 )))]
 pub mod crc;
 
+#[cfg(any(
+    feature = "l4",
+    feature = "l562",
+    feature = "g041",
+    feature = "g081",
+    feature = "g0c1",
+    feature = "g431",
+    feature = "g441",
+    feature = "g471",
+    feature = "g473",
+    feature = "g483",
+    feature = "g484",
+    feature = "g4a1",
+    feature = "wb",
+    feature = "wl",
+    feature = "h735",
+    feature = "h747cm4",
+    feature = "h747cm7",
+    feature = "h753",
+    feature = "h753v",
+    feature = "h7b3",
+    feature = "f405",
+    feature = "f407",
+    feature = "f427",
+    feature = "f429",
+    feature = "f469",
+))]
+pub mod crypto;
+
 #[cfg(not(any(
     feature = "f401",
     feature = "f411",
@@ -370,6 +416,9 @@ pub mod crc;
 // WB doesn't have a DAC. Some G0 variants do - add it! Most F4 variants have it, some don't
 pub mod dac;
 
+#[cfg(not(feature = "h5"))]
+pub mod debounce;
+
 #[cfg(not(any(
     feature = "f3",
     feature = "f4",
@@ -390,20 +439,80 @@ pub mod dfsdm;
 #[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
 pub mod dma;
 
+#[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+pub mod dma_route;
+
+#[cfg(any(
+    feature = "f427",
+    feature = "f429",
+    feature = "f469",
+    all(feature = "h7", not(feature = "h7b3"))
+))]
+pub mod dma2d;
+
+#[cfg(any(feature = "h747cm4", feature = "h747cm7"))]
+pub mod dual_core;
+
 #[cfg(all(feature = "h7", feature = "net"))]
 pub mod ethernet;
 
+// Some variants don't have backup registers, or they're absent from the PAC.
+#[cfg(not(any(
+    feature = "l412",
+    feature = "g050",
+    feature = "g051",
+    feature = "g061",
+    feature = "g0b0",
+    feature = "h5",
+)))]
+pub mod event_log;
+
+pub mod exti;
+
 #[cfg(not(feature = "h5"))] // todo: Come back to
 pub mod flash;
 
-// todo: PAC doesn't yet support these newer H7 MCUs that use FMAC.
-// #[cfg(any(feature = "h723", feature = "h725", feature = "h733", feature = "h735"))]
-// todo: Also G4.
-// pub mod fmac;
+#[cfg(any(
+    feature = "f427",
+    feature = "f429",
+    feature = "f446",
+    feature = "f469",
+    feature = "h7"
+))]
+pub mod fmc;
+
+#[cfg(any(
+    feature = "f427",
+    feature = "f429",
+    feature = "f469",
+    all(feature = "h7", not(feature = "h7b3"))
+))]
+pub mod framebuffer;
+
+#[cfg(any(feature = "g4", feature = "h735"))]
+pub mod fmac;
 
 pub mod gpio;
 
-#[cfg(feature = "wb")]
+#[cfg(any(
+    feature = "l4x6",
+    feature = "h747cm4",
+    feature = "h747cm7",
+    feature = "h753",
+    feature = "h753v",
+    feature = "h7b3",
+    feature = "f405",
+    feature = "f407",
+    feature = "f427",
+    feature = "f429",
+    feature = "f469",
+))]
+pub mod hash;
+
+#[cfg(feature = "heap")]
+pub mod heap;
+
+#[cfg(any(feature = "wb", feature = "h747cm4", feature = "h747cm7"))]
 pub mod hsem;
 
 #[cfg(not(any(feature = "f4")))]
@@ -416,13 +525,32 @@ pub use i2c_f4 as i2c;
 #[cfg(feature = "wb")]
 pub mod ipcc;
 
+// Cortex-M0+ doesn't have a DWT cycle counter.
+#[cfg(not(feature = "g0"))]
+pub mod irq_stats;
+
 pub mod iwdg;
 
+// LTDC is also available on F405 and F407 in the PAC, but these parts don't have a DMA2D.
+#[cfg(any(
+    feature = "f427",
+    feature = "f429",
+    feature = "f469",
+    all(feature = "h7", not(feature = "h7b3"))
+))]
+pub mod ltdc;
+
 pub mod low_power;
 
-#[cfg(any(feature = "h747cm4", feature = "h747cm7"))]
+// The L552 doesn't have a PKA.
+#[cfg(any(feature = "l562", feature = "wb", feature = "wl"))]
+pub mod pka;
+
 pub mod power;
 
+#[cfg(feature = "g4")]
+pub mod presets;
+
 // F3, F4, G0, and WL don't have Quad SPI. L5 and newer H variants (eg H735) use OctoSPI,
 // also supported by this module.
 #[cfg(not(any(
@@ -441,6 +569,28 @@ feature = "h5",
 )))]
 pub mod qspi;
 
+// Uses QUADSPI registers directly; not available on variants with OctoSPI.
+#[cfg(not(any(
+    feature = "f3",
+    feature = "f4",
+    feature = "l4x3",
+    feature = "g0",
+    feature = "g431",
+    feature = "g441",
+    feature = "g471",
+    feature = "g491",
+    feature = "g4a1",
+    feature = "wl",
+    feature = "l5",
+    feature = "h5",
+    feature = "h735",
+    feature = "h7b3",
+)))]
+pub mod qspi_xip;
+
+#[cfg(not(feature = "h5"))] // todo: H5 RCC_RSR layout.
+pub mod reset_cause;
+
 // Note: Some F4 variants support RNG, but we haven't figured out the details yet. Send a PR if interested.
 #[cfg(not(any(
     feature = "f3",
@@ -460,22 +610,70 @@ pub mod rtc;
     feature = "f3",
     feature = "f4",
     feature = "g0",
-    // feature = "g4", // todo: G4 PAC issue re getting channel-specific reg blocks.
     feature = "h7b3",
     feature = "wl",
     feature = "h5", // todo
 )))]
 pub mod sai;
 
+pub mod sd_stream;
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "h7",
+    all(feature = "f4", not(feature = "f410"))
+))]
+pub mod sdmmc;
+
+#[cfg(feature = "l5")]
+pub mod security;
+
+#[cfg(not(feature = "h5"))]
+pub mod selftest;
+
 #[cfg(not(feature = "h5"))] // todo: Add H5 SPI!
 pub mod spi;
 
+pub mod telemetry;
+
 #[cfg(not(feature = "h5"))] // todo temp
 pub mod timer;
 
+#[cfg(not(any(feature = "g0", feature = "h5")))]
+pub mod timer_wheel;
+
+#[cfg(any(
+    feature = "g071",
+    feature = "g081",
+    feature = "g0b1",
+    feature = "g0c1",
+    feature = "g4",
+    feature = "l5"
+))]
+pub mod ucpd;
+
 // #[cfg(not(feature = "h5"))] // todo temp. Needs CR1 and ISR added, among other things.
 pub mod usart;
 
+// Note: Some G0 variants have VREFBUF, but it's missing from their PACs.
+#[cfg(not(any(
+    feature = "f3",
+    feature = "f4",
+    feature = "g050",
+    feature = "g070",
+    feature = "g071",
+    feature = "g081",
+    feature = "h5",
+)))]
+pub mod vrefbuf;
+
+#[cfg(not(feature = "h5"))]
+pub mod wake;
+
+#[cfg(not(any(feature = "l5", feature = "h5")))]
+pub mod ymodem;
+
 #[cfg(any(
     feature = "l4",
     // feature = "g4",
@@ -1,27 +1,48 @@
 //! This module contains code used to place the MCU in low power modes.
 //! Reference section 5.3.3: `Low power modes` of the L4 Reference Manual.
 
+use core::ptr::{read_volatile, write_volatile};
+
 use cfg_if::cfg_if;
 use cortex_m::{asm::wfi, Peripherals};
 
 #[cfg(any(feature = "l4", feature = "l5"))]
-use crate::clocks::{Clocks, MsiRange};
-#[cfg(any(feature = "l4", feature = "l5"))]
-use crate::pac;
-#[cfg(not(feature = "h7"))]
-use crate::pac::PWR;
+use crate::clocks::MsiRange;
+#[cfg(not(any(feature = "f3", feature = "f4")))]
+use crate::gpio::Pull;
+use crate::{
+    clocks::{Clocks, RccError},
+    gpio::{self, Port},
+};
+use crate::{
+    pac::{self, PWR},
+    wake::{self, WAKEUP_PIN_COUNT},
+};
 
 // See L4 Reference Manual section 5.3.6. The values correspond to the PWR_CR1 LPMS bits.
 // todo PWR_CR1, LPMS field.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum StopMode {
     Zero = 0,
     One = 1,
-    #[cfg(not(feature = "g4"))]
+    /// Not available on G0 and G4.
+    #[cfg(not(any(feature = "g0", feature = "g4")))]
     Two = 2,
 }
 
+#[cfg(all(feature = "h7", not(feature = "h7b3")))]
+#[derive(Clone, Copy, PartialEq)]
+/// H7 power domains. D1 contains the CPU, AXI SRAM, and high-bandwidth peripherals; D2 contains
+/// most other peripherals and SRAM1-3, and D3 contains the system configuration, and low-power
+/// peripherals. See H743 RM, section 6.
+pub enum Domain {
+    D1,
+    D2,
+    D3,
+}
+
 /// L4 RM, table 24
 /// This assumes you're using MSI as the clock source, and changes speed by lowering the MSI speed.
 /// You must select an MSI speed of 2Mhz or lower. Note that you may need to adjust peripheral
@@ -144,10 +165,11 @@ cfg_if! {
 
             wfi();
         }
-    } else if #[cfg(any(feature = "l4", feature = "l5", feature = "g0", feature = "g4"))] {
+    } else if #[cfg(any(feature = "l4", feature = "l5", feature = "g0", feature = "g4", feature = "wb", feature = "wl"))] {
         /// Enter Stop 0, Stop 1, or Stop 2 modes. L4 Reference manual, section 5.3.6. Tables 27, 28, and 29.
         /// G0 RMs, tables 30, 31, 32.
         /// G4 Table 45, 47, 47.
+        /// WB RM, tables 31, 32, 33. WL RM, tables 34, 35, 36.
         /// Run `Clocks::reselect_input()` after to re-enable PLL etc after exiting this mode.
         pub fn stop(mode: StopMode) {
             let mut scb = unsafe { Peripherals::steal().SCB };
@@ -159,6 +181,10 @@ cfg_if! {
             // – No interrupt (for WFI) or event (for WFE) is pending
             // – LPMS = (according to mode) in PWR_CR1
             pwr.cr1.modify(|_, w| unsafe { w.lpms().bits(mode as u8) });
+            // On WB, the system enters the shallower of the modes selected by both CPUs. If you're
+            // not running a CPU2 wireless stack, select the same mode for it.
+            #[cfg(feature = "wb")]
+            pwr.c2cr1.modify(|_, w| unsafe { w.lpms().bits(mode as u8) });
 
             // Or, unimplemented:
             // On Return from ISR while:
@@ -171,7 +197,7 @@ cfg_if! {
         }
 
 
-        /// Enter `Standby` mode. See L44 RM table 28. G4 table 47.
+        /// Enter `Standby` mode. See L44 RM table 28. G4 table 47. WB table 34. WL table 37.
         /// Run `Clocks::reselect_input()` after to re-enable PLL etc after exiting this mode.
         pub fn standby() {
             let mut scb = unsafe { Peripherals::steal().SCB };
@@ -184,36 +210,12 @@ cfg_if! {
 
             // – LPMS = “011” in PWR_CR1
             pwr.cr1.modify(|_, w| unsafe { w.lpms().bits(0b011) });
+            #[cfg(feature = "wb")]
+            pwr.c2cr1.modify(|_, w| unsafe { w.lpms().bits(0b011) });
 
             // – WUFx bits are cleared in power status register 1 (PWR_SR1)
-            // (Clear by setting cwfuf bits in `pwr_scr`.)
-            cfg_if! {
-                if #[cfg(feature = "l4")] {
-                    pwr.scr.write(|w| {
-                        w.wuf1().set_bit();
-                        w.wuf2().set_bit();
-                        w.wuf3().set_bit();
-                        w.wuf4().set_bit();
-                        w.wuf5().set_bit()
-                    });
-                } else if #[cfg(feature = "g0")] {
-                    pwr.scr.write(|w| {
-                        w.cwuf1().set_bit();
-                        w.cwuf2().set_bit();
-                        // w.cwuf3().set_bit(); // todo: PAC ommission?
-                        w.cwuf4().set_bit();
-                        w.cwuf5().set_bit();
-                        w.cwuf6().set_bit()
-                    });
-                } else {
-                    pwr.scr.write(|w| {
-                        w.cwuf1().set_bit();
-                        w.cwuf2().set_bit();
-                        w.cwuf3().set_bit();
-                        w.cwuf4().set_bit();
-                        w.cwuf5().set_bit()
-                    });
-                }
+            for pin in 1..=WAKEUP_PIN_COUNT {
+                clear_wakeup_flag(pin);
             }
 
             // todo: `The RTC flag corresponding to the chosen wakeup source (RTC Alarm
@@ -233,7 +235,9 @@ cfg_if! {
         }
 
         /// Enter `Shutdown mode` mode: the lowest-power of the 3 low-power states avail. See
-        /// L4 Table 31. G4 table 48.
+        /// L4 Table 31. G4 table 48. G0 table 33. WB table 35. WL table 38. Not available on
+        /// G0 value line devices (G030, G070).
+        #[cfg(not(any(feature = "g030", feature = "g070")))]
         pub fn shutdown() {
             let mut scb = unsafe { Peripherals::steal().SCB };
             let pwr = unsafe { &(*PWR::ptr()) };
@@ -241,18 +245,14 @@ cfg_if! {
             // – SLEEPDEEP bit is set in Cortex®-M4 System Control register
             scb.set_sleepdeep();
             // – No interrupt (for WFI) or event (for WFE) is pending
-            // – LPMS = “011” in PWR_CR1
+            // – LPMS = “1XX” in PWR_CR1
             pwr.cr1.modify(|_, w| unsafe { w.lpms().bits(0b100) });
+            #[cfg(feature = "wb")]
+            pwr.c2cr1.modify(|_, w| unsafe { w.lpms().bits(0b100) });
             // – WUFx bits are cleared in power status register 1 (PWR_SR1)
-            // (Clear by setting cwfuf bits in `pwr_scr`.)
-
-            // pwr.scr.write(|_, w| {
-            //     w.cwuf1().set_bit();
-            //     w.cwuf2().set_bit();
-            //     w.cwuf3().set_bit();
-            //     w.cwuf4().set_bit();
-            //     w.cwuf5().set_bit();
-            // })
+            for pin in 1..=WAKEUP_PIN_COUNT {
+                clear_wakeup_flag(pin);
+            }
 
             // Or, unimplemented:
             // On return from ISR while:
@@ -266,7 +266,7 @@ cfg_if! {
             // cleared
             wfi();
         }
-    } else { // H7
+    } else if #[cfg(feature = "h7")] {
         /// The CSleep mode applies only to the CPU subsystem. In CSleep mode, the CPU clock is
         /// stopped. The CPU subsystem peripheral clocks operate according to the values of
         /// PERxLPEN bits in RCC_C1_xxxxENR or RCC_DnxxxxENR. See H743 RM, Table 37.
@@ -289,52 +289,552 @@ cfg_if! {
             wfi();
         }
 
-        // /// Stops clocks on the D1 and D2 domain. H742 RM, Table 40.
-        // pub fn dstop(scb: &mut SCB, pwr: &mut PWR) {
-        //
-        //     // -The domain CPU subsystem enters CStop.
-        //     // (Don't call self.cstop, since that handles WFI and reselecting clocks as well).
-        //     scb.set_sleepdeep();
-        //
-        //
-        //     // todo?
-        //     // – The CPU subsystem has an allocated peripheral in the D2 domain and
-        //     // enters CStop.
-        //     // – The CPU subsystem deallocated its last peripheral in the D2 domain.
-        //     // – The PDDS_Dn bit for the domain selects Stop mode.
-        //
-        //     wfi();
-        // }
-
-        // /// Enter `Standby` mode: the lowest-power of the 3 low-power states avail on the
-        // /// STM32f3.
-        // /// To exit: WKUP pin rising edge, RTC alarm event’s rising edge, external Reset in
-        // /// NRST pin, IWDG Reset.
-        // /// RM, table 21.
-        // /// Run `Clocks::reselect_input()` after to re-enable PLL etc after exiting this mode.
-        // pub fn standby() {
-        //     // WFI (Wait for Interrupt) or WFE (Wait for Event) while:
-        //
-        //     // Set SLEEPDEEP bit in ARM® Cortex®-M4 System Control register
-        //     scb.set_sleepdeep();
-        //
-        //     // Set PDDS bit in Power Control register (PWR_CR)
-        //     // This bit is set and cleared by software. It works together with the LPDS bit.
-        //     // 0: Enter Stop mode when the CPU enters Deepsleep. The regulator status
-        //     // depends on the LPDS bit.
-        //     // 1: Enter Standby mode when the CPU enters Deepsleep.
-        //     pwr.cr.modify(|_, w| {
-        //         w.pdds().set_bit();
-        //         // Clear WUF bit in Power Control/Status register (PWR_CSR) (Must do this by setting CWUF bit in
-        //         // PWR_CR.)
-        //         w.cwuf().set_bit()
-        //     });
-
-        //     wfi();
-        // }
+        /// Select Stop (`false`) or Standby (`true`) for a domain, for when its CPU subsystem enters
+        /// CStop. Sets PWR_CPUCR register, PDDS_Dn field.
+        #[cfg(not(feature = "h7b3"))]
+        fn set_pdds(domain: Domain, standby: bool) {
+            let pwr = unsafe { &(*PWR::ptr()) };
+
+            pwr.cpucr.modify(|_, w| match domain {
+                Domain::D1 => w.pdds_d1().bit(standby),
+                Domain::D2 => w.pdds_d2().bit(standby),
+                Domain::D3 => w.pdds_d3().bit(standby),
+            });
+        }
+
+        /// Stops clocks on a domain. H742 RM, Table 40. A domain enters DStop when the CPU enters
+        /// CStop, and has no allocated peripherals in that domain still running. D3 also requires
+        /// `RUN_D3` to be cleared. Run `Clocks::reselect_input()` after to re-enable PLL etc
+        /// after exiting this mode.
+        #[cfg(not(feature = "h7b3"))]
+        pub fn dstop(domain: Domain) {
+            let pwr = unsafe { &(*PWR::ptr()) };
+
+            // – The PDDS_Dn bit for the domain selects Stop mode.
+            set_pdds(domain, false);
+
+            if domain == Domain::D3 {
+                // – RUN_D3 = 0, so D3 follows the CPU subsystem into Stop.
+                pwr.cpucr.modify(|_, w| w.run_d3().clear_bit());
+            }
+
+            // -The domain CPU subsystem enters CStop.
+            cstop();
+        }
+
+        /// Enter DStandby on a domain: Its power is removed, and its register and memory contents
+        /// are lost. H742 RM, Table 41. On wakeup, the domain is reset. Note that D1 can't enter
+        /// DStandby while D2 is in Run or Stop; use `standby()` to power down the whole system.
+        #[cfg(not(feature = "h7b3"))]
+        pub fn dstandby(domain: Domain) {
+            // – The PDDS_Dn bit for the domain selects Standby mode.
+            set_pdds(domain, true);
+
+            // – The domain CPU subsystem enters CStop.
+            cstop();
+        }
+
+        /// Enter system `Stop` mode: All domains in DStop. H742 RM, Table 42. The voltage regulator is set
+        /// in low-power mode; select its voltage scale during stop with PWR_CR1, SVOS.
+        /// Run `Clocks::reselect_input()` after to re-enable PLL etc after exiting this mode.
+        pub fn stop() {
+            let pwr = unsafe { &(*PWR::ptr()) };
+
+            // – All domains select Stop mode.
+            cfg_if! {
+                if #[cfg(feature = "h7b3")] {
+                    pwr.cpucr.modify(|_, w| {
+                        w.retds_cd().clear_bit();
+                        w.pdds_srd().clear_bit();
+                        w.run_srd().clear_bit()
+                    });
+                } else {
+                    pwr.cpucr.modify(|_, w| {
+                        w.pdds_d1().clear_bit();
+                        w.pdds_d2().clear_bit();
+                        w.pdds_d3().clear_bit();
+                        w.run_d3().clear_bit()
+                    });
+                }
+            }
+
+            // – Voltage regulator in low-power mode during Stop.
+            pwr.cr1.modify(|_, w| w.lpds().set_bit());
+
+            cstop();
+        }
+
+        /// Enter system `Standby` mode: All domains in DStandby. H742 RM, Table 43.
+        /// To exit: WKUP pin edge, RTC alarm, wakeup timer, tamper, or timestamp events, external
+        /// reset in NRST pin, IWDG reset.
+        pub fn standby() {
+            let pwr = unsafe { &(*PWR::ptr()) };
+
+            // – All domains select Standby mode.
+            cfg_if! {
+                if #[cfg(feature = "h7b3")] {
+                    pwr.cpucr.modify(|_, w| {
+                        w.retds_cd().set_bit();
+                        w.pdds_srd().set_bit()
+                    });
+                } else {
+                    pwr.cpucr.modify(|_, w| {
+                        w.pdds_d1().set_bit();
+                        w.pdds_d2().set_bit();
+                        w.pdds_d3().set_bit()
+                    });
+                }
+            }
+
+            // – All WKUPF bits in Power Control/Status register (PWR_WKUPFR) are cleared, by setting
+            // WKUPCn bits in PWR_WKUPCR.
+            for pin in 1..=WAKEUP_PIN_COUNT {
+                clear_wakeup_flag(pin);
+            }
+            // – Clear the standby and stop flags.
+            pwr.cpucr.modify(|_, w| w.cssf().set_bit());
+
+            // – The RTC flag corresponding to the chosen wakeup source (RTC Alarm
+            // A, RTC Alarm B, RTC wakeup, tamper or timestamp flags) is cleared. (See `wake::clear()`.)
+
+            cstop();
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// The WKUP pin edge that causes a wakeup. Sets PWR_CR4 register, WPx fields, or PWR_WKUPEPR
+/// register, WKUPPx fields on H7. F3 and F4 only support rising edges.
+pub enum WakeupPolarity {
+    Rising,
+    Falling,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// The cause of a wakeup from Standby or Shutdown mode. These modes exit through a reset, so read
+/// this at the start of the program, eg with `wakeup_source()`.
+pub enum WakeupSource {
+    /// A WKUP pin, starting at 1.
+    Pin(u8),
+    /// An internal source; eg an RTC alarm, wakeup timer, timestamp or tamper event.
+    Internal,
+    /// An IWDG reset.
+    Iwdg,
+    /// An external reset, in the NRST pin.
+    Nrst,
+    /// The MCU didn't wake from a low-power mode; eg a power-on or software reset.
+    Reset,
+}
+
+/// Enable a WKUP pin, starting at 1, so its edge wakes the MCU from Standby and Shutdown modes.
+/// `polarity` is ignored on F3 and F4. Panics if the MCU doesn't have this pin. Sets PWR_CR3
+/// register, EWUPx fields, PWR_CSR, EWUPx on F3 and F4, and PWR_WKUPEPR, WKUPENx on H7.
+pub fn enable_wakeup_pin(pin: u8, polarity: WakeupPolarity) {
+    assert!((1..=WAKEUP_PIN_COUNT).contains(&pin));
+    let pwr = unsafe { &(*PWR::ptr()) };
+    let falling = polarity == WakeupPolarity::Falling;
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            pwr.csr.modify(|r, w| unsafe { w.bits(r.bits() | 1 << wake::ewup_shift(pin)) });
+        } else if #[cfg(feature = "h7")] {
+            pwr.wkupepr.modify(|r, w| unsafe {
+                let polarity_bit = 1 << (pin + 7);
+                let val = if falling {
+                    r.bits() | polarity_bit
+                } else {
+                    r.bits() & !polarity_bit
+                };
+                w.bits(val | 1 << (pin - 1))
+            });
+        } else {
+            // Polarity must be selected before enabling the pin, or the change may trigger a
+            // wakeup flag.
+            pwr.cr4.modify(|r, w| unsafe {
+                let polarity_bit = 1 << (pin - 1);
+                if falling {
+                    w.bits(r.bits() | polarity_bit)
+                } else {
+                    w.bits(r.bits() & !polarity_bit)
+                }
+            });
+            pwr.cr3.modify(|r, w| unsafe { w.bits(r.bits() | 1 << (pin - 1)) });
+        }
+    }
+
+    #[cfg(any(feature = "f3", feature = "f4"))]
+    let _ = falling;
+}
+
+/// Disable a WKUP pin, starting at 1.
+pub fn disable_wakeup_pin(pin: u8) {
+    assert!((1..=WAKEUP_PIN_COUNT).contains(&pin));
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            pwr.csr.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << wake::ewup_shift(pin))) });
+        } else if #[cfg(feature = "h7")] {
+            pwr.wkupepr.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << (pin - 1))) });
+        } else {
+            pwr.cr3.modify(|r, w| unsafe { w.bits(r.bits() & !(1 << (pin - 1))) });
+        }
+    }
+}
+
+#[cfg(feature = "h7")]
+/// Set the pull-up or pull-down on a WKUP pin, starting at 1. Sets PWR_WKUPEPR register,
+/// WKUPPUPDx field.
+pub fn set_wakeup_pull(pin: u8, pull: Pull) {
+    assert!((1..=WAKEUP_PIN_COUNT).contains(&pin));
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    let shift = 16 + 2 * (pin - 1);
+    pwr.wkupepr
+        .modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << shift)) | (pull as u32) << shift) });
+}
+
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "h7")))]
+/// Set a pull-up or pull-down on a GPIO pin, applied in Standby and Shutdown modes, eg to keep
+/// a WKUP pin's level defined. Also enables these pulls, which apply to all ports. Sets
+/// PWR_PUCRx and PWR_PDCRx registers, and PWR_CR3 register, APC field.
+pub fn set_standby_pull(port: Port, pin: u8, pull: Pull) {
+    let pwr = unsafe { &(*PWR::ptr()) };
+    let base = PWR::ptr() as *mut u8;
+
+    // PWR_PUCRA is at offset 0x20, and PWR_PDCRA at 0x24; other ports follow in pairs.
+    let offset = 0x20 + port.cr_val() as usize * 8;
+
+    unsafe {
+        let pucr = base.add(offset) as *mut u32;
+        let pdcr = base.add(offset + 4) as *mut u32;
+
+        let up = read_volatile(pucr) & !(1 << pin);
+        let down = read_volatile(pdcr) & !(1 << pin);
+
+        match pull {
+            Pull::Floating => {
+                write_volatile(pucr, up);
+                write_volatile(pdcr, down);
+            }
+            Pull::Up => {
+                write_volatile(pdcr, down);
+                write_volatile(pucr, up | 1 << pin);
+            }
+            Pull::Dn => {
+                write_volatile(pucr, up);
+                write_volatile(pdcr, down | 1 << pin);
+            }
+        }
+    }
+
+    // APC is bit 10 of PWR_CR3.
+    pwr.cr3.modify(|r, w| unsafe { w.bits(r.bits() | 1 << 10) });
+}
+
+/// Clear the wakeup flag for a WKUP pin, starting at 1. On F3 and F4, this clears the single WUF
+/// flag, shared by all pins. Sets PWR_SCR register, CWUFx fields, PWR_CR, CWUF on F3 and F4, and
+/// PWR_WKUPCR, WKUPCx on H7.
+pub fn clear_wakeup_flag(pin: u8) {
+    assert!((1..=WAKEUP_PIN_COUNT).contains(&pin));
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            pwr.cr.modify(|_, w| w.cwuf().set_bit());
+        } else if #[cfg(feature = "h7")] {
+            pwr.wkupcr.write(|w| unsafe { w.bits(1 << (pin - 1)) });
+        } else {
+            pwr.scr.write(|w| unsafe { w.bits(1 << (pin - 1)) });
+        }
+    }
+}
+
+/// Check if the MCU woke from Standby mode. Reads PWR_SR1 register, SBF field, PWR_CSR, SBF on
+/// F3 and F4, PWR_EXTSCR, C1SBF on WB and WL, and PWR_CPUCR, SBF on H7.
+pub fn standby_flag() -> bool {
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            pwr.csr.read().bits() & (1 << 1) != 0
+        } else if #[cfg(any(feature = "wb", feature = "wl"))] {
+            pwr.extscr.read().bits() & (1 << 9) != 0
+        } else if #[cfg(feature = "h7")] {
+            pwr.cpucr.read().bits() & (1 << 6) != 0
+        } else {
+            pwr.sr1.read().bits() & (1 << 8) != 0
+        }
+    }
+}
+
+/// Clear the Standby flag. Sets PWR_SCR register, CSBF field, PWR_CR, CSBF on F3 and F4,
+/// PWR_EXTSCR, C1CSSF on WB and WL, and PWR_CPUCR, CSSF on H7.
+pub fn clear_standby_flag() {
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            pwr.cr.modify(|r, w| unsafe { w.bits(r.bits() | 1 << 3) });
+        } else if #[cfg(any(feature = "wb", feature = "wl"))] {
+            pwr.extscr.write(|w| unsafe { w.bits(1) });
+        } else if #[cfg(feature = "h7")] {
+            pwr.cpucr.modify(|_, w| w.cssf().set_bit());
+        } else {
+            pwr.scr.write(|w| unsafe { w.bits(1 << 8) });
+        }
+    }
+}
+
+/// Find the cause of a wakeup from Standby or Shutdown mode; run this after reset. Uses the PWR wakeup
+/// and Standby flags, and the RCC reset flags. RCC reset flags persist across resets until cleared,
+/// so clear them after reading this, with RCC_CSR (RCC_RSR on H7), RMVF. Clear the wakeup and
+/// Standby flags with `clear_wakeup_flag()` and `clear_standby_flag()`. On F3 and F4, RTC events
+/// also set the shared wakeup flag; a wakeup is only reported as from a pin when no RTC flag is set.
+pub fn wakeup_source() -> WakeupSource {
+    let pending = wake::pending();
+    if pending.wakeup_pins != 0 {
+        return WakeupSource::Pin(pending.wakeup_pins.trailing_zeros() as u8 + 1);
+    }
+
+    if !standby_flag() {
+        return WakeupSource::Reset;
+    }
+
+    let rcc = unsafe { &(*pac::RCC::ptr()) };
+
+    cfg_if! {
+        if #[cfg(feature = "h7")] {
+            // IWDG1RSTF and PINRSTF fields.
+            let flags = rcc.rsr.read().bits();
+            let (iwdg, nrst) = (flags & (1 << 26) != 0, flags & (1 << 22) != 0);
+        } else {
+            // IWDGRSTF and PINRSTF fields.
+            let flags = rcc.csr.read().bits();
+            let (iwdg, nrst) = (flags & (1 << 29) != 0, flags & (1 << 26) != 0);
+        }
+    }
+
+    if iwdg {
+        WakeupSource::Iwdg
+    } else if nrst {
+        WakeupSource::Nrst
+    } else {
+        WakeupSource::Internal
+    }
+}
+
+// RCC peripheral clock enable registers, by offset.
+cfg_if! {
+    if #[cfg(feature = "f3")] {
+        // AHBENR, APB2ENR, APB1ENR.
+        const RCC_ENR: [usize; 3] = [0x14, 0x18, 0x1c];
+    } else if #[cfg(feature = "f4")] {
+        // AHB1ENR, AHB2ENR, AHB3ENR, APB1ENR, APB2ENR.
+        const RCC_ENR: [usize; 5] = [0x30, 0x34, 0x38, 0x40, 0x44];
+    } else if #[cfg(feature = "g0")] {
+        // IOPENR, AHBENR, APBENR1, APBENR2.
+        const RCC_ENR: [usize; 4] = [0x34, 0x38, 0x3c, 0x40];
+    } else if #[cfg(any(feature = "wb", feature = "wl"))] {
+        // AHB1ENR, AHB2ENR, AHB3ENR, APB1ENR1, APB1ENR2, APB2ENR, APB3ENR.
+        const RCC_ENR: [usize; 7] = [0x48, 0x4c, 0x50, 0x58, 0x5c, 0x60, 0x64];
+    } else if #[cfg(feature = "h7")] {
+        // AHB3ENR, AHB1ENR, AHB2ENR, AHB4ENR, APB3ENR, APB1LENR, APB1HENR, APB2ENR, APB4ENR.
+        const RCC_ENR: [usize; 9] = [0xd4, 0xd8, 0xdc, 0xe0, 0xe4, 0xe8, 0xec, 0xf0, 0xf4];
+    } else {
+        // AHB1ENR, AHB2ENR, AHB3ENR, APB1ENR1, APB1ENR2, APB2ENR.
+        const RCC_ENR: [usize; 6] = [0x48, 0x4c, 0x50, 0x58, 0x5c, 0x60];
+    }
+}
+
+// Offsets of the control registers containing enable bits, and the bits. USART: UE, SPI: SPE,
+// I2C: PE.
+cfg_if! {
+    if #[cfg(feature = "f4")] {
+        const USART_EN: (usize, u32) = (0x0c, 1 << 13);
+    } else {
+        const USART_EN: (usize, u32) = (0, 1);
     }
 }
 
+cfg_if! {
+    if #[cfg(feature = "h7")] {
+        const SPI_EN: (usize, u32) = (0, 1);
+    } else {
+        const SPI_EN: (usize, u32) = (0, 1 << 6);
+    }
+}
+
+const I2C_EN: (usize, u32) = (0, 1);
+
+/// Peripherals whose enable bits are restored by `LowPowerGuard`: Their register blocks, and enable
+/// bit positions.
+const PERIPH_EN: &[(*const u8, (usize, u32))] = &[
+    (pac::USART1::ptr() as *const u8, USART_EN),
+    #[cfg(not(any(feature = "wb", feature = "wl")))]
+    (pac::USART2::ptr() as *const u8, USART_EN),
+    #[cfg(not(any(
+        feature = "f401",
+        feature = "f410",
+        feature = "f411",
+        feature = "f412",
+        feature = "f413",
+        feature = "l4x1",
+        feature = "g0",
+        feature = "wb",
+        feature = "wl",
+    )))]
+    (pac::USART3::ptr() as *const u8, USART_EN),
+    (pac::I2C1::ptr() as *const u8, I2C_EN),
+    #[cfg(not(any(feature = "wb", feature = "f3x4")))]
+    (pac::I2C2::ptr() as *const u8, I2C_EN),
+    #[cfg(any(feature = "h7", feature = "wb"))]
+    (pac::I2C3::ptr() as *const u8, I2C_EN),
+    #[cfg(not(feature = "f301"))]
+    (pac::SPI1::ptr() as *const u8, SPI_EN),
+    #[cfg(not(any(feature = "f3x4", feature = "wb", feature = "wl")))]
+    (pac::SPI2::ptr() as *const u8, SPI_EN),
+    #[cfg(not(any(
+        feature = "f3x4",
+        feature = "f410",
+        feature = "g0",
+        feature = "wb",
+        feature = "wl"
+    )))]
+    (pac::SPI3::ptr() as *const u8, SPI_EN),
+];
+
+/// The number of GPIO ports whose modes `LowPowerGuard` can store.
+const GPIO_PORT_COUNT: usize = 9;
+
+/// Records peripheral state before entering Stop mode, and restores it after waking: Re-selects the
+/// clock input source (eg re-enabling the PLL), re-enables peripheral clocks, and re-sets the enable
+/// bits of USART, SPI, and I2C peripherals. Optionally, sets GPIO pins to analog mode while
+/// stopped, to reduce leakage current, and restores their modes after.
+///
+/// State is restored when `restore()` is called, or when the guard is dropped. Example:
+/// ```rust
+/// let guard = LowPowerGuard::new(&clock_cfg).analog(Port::B, 0xffff);
+/// low_power::stop(StopMode::Two);
+/// guard.restore()?;
+/// ```
+pub struct LowPowerGuard<'a> {
+    clocks: &'a Clocks,
+    rcc_enr: [u32; RCC_ENR.len()],
+    periph_en: [bool; PERIPH_EN.len()],
+    gpio_moder: [Option<(Port, u32)>; GPIO_PORT_COUNT],
+    restored: bool,
+}
+
+impl<'a> LowPowerGuard<'a> {
+    /// Record which peripheral clocks, and USART, SPI, and I2C peripherals are enabled.
+    pub fn new(clocks: &'a Clocks) -> Self {
+        let rcc = pac::RCC::ptr() as *const u8;
+
+        let mut rcc_enr = [0; RCC_ENR.len()];
+        for (val, offset) in rcc_enr.iter_mut().zip(RCC_ENR.iter()) {
+            *val = unsafe { read_volatile(rcc.add(*offset) as *const u32) };
+        }
+
+        let mut periph_en = [false; PERIPH_EN.len()];
+        for (en, (base, (offset, bit))) in periph_en.iter_mut().zip(PERIPH_EN.iter()) {
+            *en = unsafe { read_volatile(base.add(*offset) as *const u32) } & bit != 0;
+        }
+
+        Self {
+            clocks,
+            rcc_enr,
+            periph_en,
+            gpio_moder: [None; GPIO_PORT_COUNT],
+            restored: false,
+        }
+    }
+
+    /// Set pins on a port to analog mode until state is restored, to reduce current consumption
+    /// in Stop mode. `pins` is a bit mask, eg `0b11` for pins 0 and 1. Don't include pins in use
+    /// while stopped, eg wakeup sources, or debug pins if debugging.
+    pub fn analog(mut self, port: Port, pins: u16) -> Self {
+        let regs = unsafe { &(*gpio::regs(port)) };
+        let moder = regs.moder.read().bits();
+
+        // Keep the original modes if this port was already added.
+        let saved = &mut self.gpio_moder[port.cr_val() as usize];
+        if saved.is_none() {
+            *saved = Some((port, moder));
+        }
+
+        let mut analog = 0;
+        for pin in 0..16 {
+            if pins & (1 << pin) != 0 {
+                analog |= 0b11 << (pin * 2);
+            }
+        }
+        regs.moder.write(|w| unsafe { w.bits(moder | analog) });
+
+        self
+    }
+
+    /// Restore recorded state. Returns an error if the clock input can't be re-selected.
+    pub fn restore(mut self) -> Result<(), RccError> {
+        self.restored = true;
+        self.restore_state()
+    }
+
+    fn restore_state(&self) -> Result<(), RccError> {
+        cfg_if! {
+            if #[cfg(any(feature = "f3", feature = "f4"))] {
+                self.clocks.reselect_input();
+            } else {
+                self.clocks.reselect_input()?;
+            }
+        }
+
+        let rcc = pac::RCC::ptr() as *mut u8;
+        for (val, offset) in self.rcc_enr.iter().zip(RCC_ENR.iter()) {
+            unsafe { write_volatile(rcc.add(*offset) as *mut u32, *val) };
+        }
+
+        for (en, (base, (offset, bit))) in self.periph_en.iter().zip(PERIPH_EN.iter()) {
+            if *en {
+                let reg = unsafe { base.add(*offset) as *mut u32 };
+                unsafe { write_volatile(reg, read_volatile(reg) | bit) };
+            }
+        }
+
+        for (port, moder) in self.gpio_moder.iter().flatten() {
+            let regs = unsafe { &(*gpio::regs(*port)) };
+            regs.moder.write(|w| unsafe { w.bits(*moder) });
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for LowPowerGuard<'_> {
+    fn drop(&mut self) {
+        if !self.restored {
+            // Use `restore()` to check for errors.
+            let _ = self.restore_state();
+        }
+    }
+}
+
+/// Enter Stop mode, and restore peripheral state after waking. See `LowPowerGuard`.
+#[cfg(any(feature = "f3", feature = "f4", feature = "h7"))]
+pub fn stop_with_restore(clocks: &Clocks) -> Result<(), RccError> {
+    let guard = LowPowerGuard::new(clocks);
+    stop();
+    guard.restore()
+}
+
+/// Enter a Stop mode, and restore peripheral state after waking. See `LowPowerGuard`.
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "h7")))]
+pub fn stop_with_restore(clocks: &Clocks, mode: StopMode) -> Result<(), RccError> {
+    let guard = LowPowerGuard::new(clocks);
+    stop(mode);
+    guard.restore()
+}
+
 /// This function is used by both `sleep_now` (non-H7), and `csleep` (H7), so that the names
 /// can correctly reflect functionality.
 fn sleep() {
@@ -0,0 +1,441 @@
+//! Support for the LCD-TFT Display Controller (LTDC). Available on F427, F429, F469, and H7.
+//! Includes display timing, and configuration of the two layers; their windows, pixel formats, and
+//! blending. The LTDC pixel clock is set in RCC: PLLSAI on F4, and PLL3R on H7.
+//!
+//! Layer registers are shadowed: Writes take effect when the shadow registers are reloaded,
+//! either immediately, or during the next vertical blanking period. Reloading during vertical blanking
+//! lets you swap framebuffers without tearing; see the `framebuffer` module.
+//!
+//! Example, for a 480x272 display, as on the H735 discovery board:
+//! ```rust
+//! let timing = DisplayTiming {
+//!     width: 480,
+//!     height: 272,
+//!     h_sync: 41,
+//!     h_back_porch: 13,
+//!     h_front_porch: 32,
+//!     v_sync: 10,
+//!     v_back_porch: 2,
+//!     v_front_porch: 2,
+//!     ..Default::default()
+//! };
+//!
+//! let mut ltdc = Ltdc::new(dp.LTDC);
+//! ltdc.init(&timing, 0x00_0000);
+//!
+//! let layer_cfg = LayerConfig {
+//!     width: 480,
+//!     height: 272,
+//!     pixel_format: PixelFormat::Rgb565,
+//!     framebuffer: unsafe { FB.as_ptr() } as u32,
+//!     ..Default::default()
+//! };
+//! ltdc.configure_layer(Layer::L1, &layer_cfg);
+//! ltdc.enable();
+//! ```
+
+use cfg_if::cfg_if;
+
+use crate::{
+    dma2d::ColorMode,
+    pac::{LTDC, RCC},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+/// One of the two LTDC layers.
+pub enum Layer {
+    L1,
+    L2,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// LTDC interrupts. Set in the IER register; cleared in the ICR register.
+pub enum LtdcInterrupt {
+    /// Triggered when the line set with `set_line_interrupt` is reached.
+    Line,
+    FifoUnderrun,
+    TransferError,
+    /// Triggered when shadow registers are reloaded. Eg at vertical blanking, after `reload_on_vblank()`.
+    RegisterReload,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// Layer pixel format. Sets the LxPFCR register, PF field.
+pub enum PixelFormat {
+    Argb8888 = 0b000,
+    Rgb888 = 0b001,
+    Rgb565 = 0b010,
+    Argb1555 = 0b011,
+    Argb4444 = 0b100,
+    /// 8-bit luminance, using the CLUT.
+    L8 = 0b101,
+    /// 4-bit alpha, 4-bit luminance.
+    Al44 = 0b110,
+    /// 8-bit alpha, 8-bit luminance.
+    Al88 = 0b111,
+}
+
+impl PixelFormat {
+    /// The number of bytes used to store each pixel.
+    pub fn bytes_per_pixel(&self) -> u16 {
+        match self {
+            Self::Argb8888 => 4,
+            Self::Rgb888 => 3,
+            Self::L8 | Self::Al44 => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl From<ColorMode> for PixelFormat {
+    fn from(mode: ColorMode) -> Self {
+        match mode {
+            ColorMode::Argb8888 => Self::Argb8888,
+            ColorMode::Rgb888 => Self::Rgb888,
+            ColorMode::Rgb565 => Self::Rgb565,
+            ColorMode::Argb1555 => Self::Argb1555,
+            ColorMode::Argb4444 => Self::Argb4444,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// How a layer is blended with the layers below it (The background, and layer 1, for layer 2).
+/// Sets the LxBFCR register.
+pub enum Blending {
+    /// Blend using the layer's constant alpha only.
+    ConstantAlpha,
+    /// Blend using each pixel's alpha, multiplied by the constant alpha.
+    PixelAlpha,
+}
+
+#[derive(Clone)]
+/// Display timing, from the panel's datasheet. Horizontal values are in pixel clock cycles, and
+/// vertical values are in lines.
+pub struct DisplayTiming {
+    /// Active width.
+    pub width: u16,
+    /// Active height.
+    pub height: u16,
+    /// Horizontal synchronization width.
+    pub h_sync: u16,
+    pub h_back_porch: u16,
+    pub h_front_porch: u16,
+    /// Vertical synchronization height.
+    pub v_sync: u16,
+    pub v_back_porch: u16,
+    pub v_front_porch: u16,
+    /// Horizontal sync is active high. Defaults to false.
+    pub h_sync_active_high: bool,
+    /// Vertical sync is active high. Defaults to false.
+    pub v_sync_active_high: bool,
+    /// Data enable is active high. Defaults to false.
+    pub de_active_high: bool,
+    /// Invert the pixel clock. Defaults to false.
+    pub pixel_clock_inverted: bool,
+}
+
+impl Default for DisplayTiming {
+    /// A 480x272 display, as is common on development boards.
+    fn default() -> Self {
+        Self {
+            width: 480,
+            height: 272,
+            h_sync: 41,
+            h_back_porch: 13,
+            h_front_porch: 32,
+            v_sync: 10,
+            v_back_porch: 2,
+            v_front_porch: 2,
+            h_sync_active_high: false,
+            v_sync_active_high: false,
+            de_active_high: false,
+            pixel_clock_inverted: false,
+        }
+    }
+}
+
+impl DisplayTiming {
+    /// The frame rate, in Hz, for a pixel clock `pixel_clock` in Hz.
+    pub fn frame_rate(&self, pixel_clock: u32) -> f32 {
+        let total_w = self.h_sync + self.h_back_porch + self.width + self.h_front_porch;
+        let total_h = self.v_sync + self.v_back_porch + self.height + self.v_front_porch;
+        pixel_clock as f32 / (total_w as f32 * total_h as f32)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Layer configuration.
+pub struct LayerConfig {
+    /// The window's horizontal position, from the left edge of the active area.
+    pub x: u16,
+    /// The window's vertical position, from the top edge of the active area.
+    pub y: u16,
+    /// Window width, in pixels. The framebuffer uses the same line length.
+    pub width: u16,
+    /// Window height, in lines.
+    pub height: u16,
+    pub pixel_format: PixelFormat,
+    /// The framebuffer's start address.
+    pub framebuffer: u32,
+    /// Constant alpha; 255 is opaque. Defaults to 255.
+    pub constant_alpha: u8,
+    pub blending: Blending,
+    /// The color outside the window, in ARGB8888 format. Defaults to transparent (0).
+    pub default_color: u32,
+}
+
+impl Default for LayerConfig {
+    fn default() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: 480,
+            height: 272,
+            pixel_format: PixelFormat::Rgb565,
+            framebuffer: 0,
+            constant_alpha: 255,
+            blending: Blending::PixelAlpha,
+            default_color: 0,
+        }
+    }
+}
+
+/// Represents an LCD-TFT Display Controller (LTDC) peripheral.
+pub struct Ltdc {
+    pub regs: LTDC,
+}
+
+impl Ltdc {
+    /// Initialize the LTDC peripheral, including enabling and resetting its RCC peripheral clock.
+    pub fn new(regs: LTDC) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                rcc.apb2enr.modify(|_, w| w.ltdcen().set_bit());
+                rcc.apb2rstr.modify(|_, w| w.ltdcrst().set_bit());
+                rcc.apb2rstr.modify(|_, w| w.ltdcrst().clear_bit());
+            } else { // H7
+                rcc.apb3enr.modify(|_, w| w.ltdcen().set_bit());
+                rcc.apb3rstr.modify(|_, w| w.ltdcrst().set_bit());
+                rcc.apb3rstr.modify(|_, w| w.ltdcrst().clear_bit());
+            }
+        }
+
+        Self { regs }
+    }
+
+    /// Set display timing, signal polarity, and the background color, in RGB888 format. Run this
+    /// before configuring layers, then `enable()`. F429 RM, section 16.4.1: LTDC global configuration
+    /// parameters.
+    pub fn init(&mut self, timing: &DisplayTiming, background: u32) {
+        self.disable();
+
+        // Each register holds the accumulated value, minus 1.
+        let hsw = timing.h_sync - 1;
+        let vsh = timing.v_sync - 1;
+        let ahbp = hsw + timing.h_back_porch;
+        let avbp = vsh + timing.v_back_porch;
+        let aaw = ahbp + timing.width;
+        let aah = avbp + timing.height;
+        let totalw = aaw + timing.h_front_porch;
+        let totalh = aah + timing.v_front_porch;
+
+        self.regs.sscr.write(|w| unsafe {
+            w.hsw().bits(hsw);
+            w.vsh().bits(vsh)
+        });
+        self.regs.bpcr.write(|w| unsafe {
+            w.ahbp().bits(ahbp);
+            w.avbp().bits(avbp)
+        });
+        self.regs.awcr.write(|w| unsafe {
+            w.aaw().bits(aaw);
+            w.aah().bits(aah)
+        });
+        self.regs.twcr.write(|w| unsafe {
+            w.totalw().bits(totalw);
+            w.totalh().bits(totalh)
+        });
+
+        self.regs.gcr.modify(|_, w| {
+            w.hspol().bit(timing.h_sync_active_high);
+            w.vspol().bit(timing.v_sync_active_high);
+            w.depol().bit(timing.de_active_high);
+            w.pcpol().bit(timing.pixel_clock_inverted)
+        });
+
+        self.regs.bccr.write(|w| unsafe {
+            w.bcred().bits((background >> 16) as u8);
+            w.bcgreen().bits((background >> 8) as u8);
+            w.bcblue().bits(background as u8)
+        });
+    }
+
+    /// Enable the LTDC, starting output to the display.
+    pub fn enable(&mut self) {
+        self.regs.gcr.modify(|_, w| w.ltdcen().set_bit());
+    }
+
+    /// Disable the LTDC.
+    pub fn disable(&mut self) {
+        self.regs.gcr.modify(|_, w| w.ltdcen().clear_bit());
+    }
+
+    /// Configure and enable a layer. Run `init()` first, since the window position is relative to
+    /// the display timing. This reloads the shadow registers immediately.
+    /// F429 RM, section 16.4.2: Layer programmable parameters.
+    pub fn configure_layer(&mut self, layer: Layer, cfg: &LayerConfig) {
+        let bpcr = self.regs.bpcr.read();
+        let h_start = bpcr.ahbp().bits() + 1 + cfg.x;
+        let v_start = bpcr.avbp().bits() + 1 + cfg.y;
+
+        let layer_regs = match layer {
+            Layer::L1 => &self.regs.layer1,
+            Layer::L2 => &self.regs.layer2,
+        };
+
+        layer_regs.whpcr.write(|w| unsafe {
+            w.whstpos().bits(h_start);
+            w.whsppos().bits(h_start + cfg.width - 1)
+        });
+        layer_regs.wvpcr.write(|w| unsafe {
+            w.wvstpos().bits(v_start);
+            w.wvsppos().bits(v_start + cfg.height - 1)
+        });
+
+        layer_regs
+            .pfcr
+            .write(|w| unsafe { w.pf().bits(cfg.pixel_format as u8) });
+        layer_regs
+            .cacr
+            .write(|w| unsafe { w.consta().bits(cfg.constant_alpha) });
+        layer_regs
+            .dccr
+            .write(|w| unsafe { w.bits(cfg.default_color) });
+
+        // BF1: 0b100 for constant alpha, or 0b110 for pixel alpha x constant alpha. BF2: 0b101 for
+        // 1 - constant alpha, or 0b111 for 1 - (pixel alpha x constant alpha).
+        let (bf1, bf2) = match cfg.blending {
+            Blending::ConstantAlpha => (0b100, 0b101),
+            Blending::PixelAlpha => (0b110, 0b111),
+        };
+        layer_regs.bfcr.write(|w| unsafe {
+            w.bf1().bits(bf1);
+            w.bf2().bits(bf2)
+        });
+
+        layer_regs
+            .cfbar
+            .write(|w| unsafe { w.cfbadd().bits(cfg.framebuffer) });
+
+        // The line length is in bytes, plus the bus width, less 1 byte: 3 on F4, and 7 on H7.
+        #[cfg(feature = "f4")]
+        let bus_extra = 3;
+        #[cfg(feature = "h7")]
+        let bus_extra = 7;
+        let pitch = cfg.width * cfg.pixel_format.bytes_per_pixel();
+        layer_regs.cfblr.write(|w| unsafe {
+            w.cfbp().bits(pitch);
+            w.cfbll().bits(pitch + bus_extra)
+        });
+        layer_regs
+            .cfblnr
+            .write(|w| unsafe { w.cfblnbr().bits(cfg.height) });
+
+        layer_regs.cr.modify(|_, w| w.len().set_bit());
+        self.reload_now();
+    }
+
+    /// Set a layer's constant alpha; 255 is opaque. Eg for fading. This takes effect on the next shadow
+    /// register reload.
+    pub fn set_alpha(&mut self, layer: Layer, alpha: u8) {
+        let layer_regs = match layer {
+            Layer::L1 => &self.regs.layer1,
+            Layer::L2 => &self.regs.layer2,
+        };
+        layer_regs.cacr.write(|w| unsafe { w.consta().bits(alpha) });
+    }
+
+    /// Enable or disable a layer. This takes effect on the next shadow register reload.
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        let layer_regs = match layer {
+            Layer::L1 => &self.regs.layer1,
+            Layer::L2 => &self.regs.layer2,
+        };
+        layer_regs.cr.modify(|_, w| w.len().bit(enabled));
+    }
+
+    /// Set a layer's framebuffer start address. This takes effect on the next shadow register
+    /// reload; see `reload_now()` and `reload_on_vblank()`.
+    pub fn set_framebuffer(&mut self, layer: Layer, addr: u32) {
+        let layer_regs = match layer {
+            Layer::L1 => &self.regs.layer1,
+            Layer::L2 => &self.regs.layer2,
+        };
+
+        layer_regs.cfbar.write(|w| unsafe { w.cfbadd().bits(addr) });
+    }
+
+    /// Reload shadow registers immediately.
+    pub fn reload_now(&mut self) {
+        self.regs.srcr.write(|w| w.imr().set_bit());
+    }
+
+    /// Reload shadow registers during the next vertical blanking period.
+    pub fn reload_on_vblank(&mut self) {
+        self.regs.srcr.write(|w| w.vbr().set_bit());
+    }
+
+    /// Returns true if a reload requested with `reload_on_vblank()` hasn't occured yet.
+    pub fn reload_pending(&self) -> bool {
+        let srcr = self.regs.srcr.read();
+        srcr.vbr().bit_is_set() || srcr.imr().bit_is_set()
+    }
+
+    /// Returns true if the display is currently in its vertical synchronization phase.
+    pub fn in_vsync(&self) -> bool {
+        self.regs.cdsr.read().vsyncs().bit_is_set()
+    }
+
+    /// Set the line that triggers the `Line` interrupt.
+    pub fn set_line_interrupt(&mut self, line: u16) {
+        self.regs.lipcr.write(|w| unsafe { w.lipos().bits(line) });
+    }
+
+    /// Enable an interrupt.
+    pub fn enable_interrupt(&mut self, interrupt: LtdcInterrupt) {
+        self.regs.ier.modify(|_, w| match interrupt {
+            LtdcInterrupt::Line => w.lie().set_bit(),
+            LtdcInterrupt::FifoUnderrun => w.fuie().set_bit(),
+            LtdcInterrupt::TransferError => w.terrie().set_bit(),
+            LtdcInterrupt::RegisterReload => w.rrie().set_bit(),
+        });
+    }
+
+    /// Disable an interrupt.
+    pub fn disable_interrupt(&mut self, interrupt: LtdcInterrupt) {
+        self.regs.ier.modify(|_, w| match interrupt {
+            LtdcInterrupt::Line => w.lie().clear_bit(),
+            LtdcInterrupt::FifoUnderrun => w.fuie().clear_bit(),
+            LtdcInterrupt::TransferError => w.terrie().clear_bit(),
+            LtdcInterrupt::RegisterReload => w.rrie().clear_bit(),
+        });
+    }
+
+    /// Clear an interrupt flag.
+    pub fn clear_interrupt(&mut self, interrupt: LtdcInterrupt) {
+        self.regs.icr.write(|w| match interrupt {
+            LtdcInterrupt::Line => w.clif().set_bit(),
+            LtdcInterrupt::FifoUnderrun => w.cfuif().set_bit(),
+            LtdcInterrupt::TransferError => w.cterrif().set_bit(),
+            LtdcInterrupt::RegisterReload => w.crrif().set_bit(),
+        });
+    }
+}
@@ -0,0 +1,626 @@
+//! Public key acceleration, using the PKA peripheral: Modular exponentiation (eg for RSA), ECDSA
+//! signing and verification, and elliptic curve point multiplication (eg for ECDH key agreement).
+//! Loading operands into PKA RAM, and reading results out of it, is handled internally.
+//!
+//! All operands are big-endian byte slices, as they appear in most protocols and key formats.
+//! Curve parameters are passed as an `EcCurve`; `P256` is included, since it's used by BLE pairing,
+//! and is the most common curve for ECDSA.
+//!
+//! Example, verifying a firmware signature in a bootloader, and deriving a BLE pairing key:
+//! ```rust
+//! let mut pka = Pka::new(dp.PKA);
+//!
+//! let public = EcPoint { x: &PUB_X, y: &PUB_Y };
+//! if pka.ecdsa_verify(&P256, &public, &digest, &sig_r, &sig_s).is_err() {
+//!     // Don't boot
+//! }
+//!
+//! // `private` is 32 random bytes, eg from the RNG peripheral.
+//! let (mut x, mut y) = ([0; 32], [0; 32]);
+//! pka.public_key(&P256, &private, &mut x, &mut y).unwrap();
+//!
+//! let peer = EcPoint { x: &peer_x, y: &peer_y };
+//! let mut shared = [0; 32];
+//! pka.ecdh(&P256, &private, &peer, &mut shared).unwrap();
+//! ```
+
+use cfg_if::cfg_if;
+
+use crate::{
+    pac::{PKA, RCC},
+    util::rcc_en_reset,
+    MAX_ITERS,
+};
+
+/// Largest modulus supported for modular exponentiation, in bytes. (3136 bits)
+pub const MAX_MODEXP_LEN: usize = 392;
+/// Largest curve modulus supported for ECC operations, in bytes. (640 bits)
+pub const MAX_ECC_LEN: usize = 80;
+
+/// Offset of PKA RAM from the peripheral's base address.
+const RAM_START: usize = 0x400;
+/// PKA RAM size, in 32-bit words.
+const RAM_WORDS: usize = 894;
+
+// PKA RAM operand addresses, as offsets from the peripheral's base address. See RM0434, section
+// 24.5: PKA operating modes.
+
+const MONTGOMERY_OP_NB_BITS: usize = 0x404;
+const MONTGOMERY_MODULUS: usize = 0xd5c;
+const MONTGOMERY_RESULT: usize = 0x594;
+
+const MODEXP_EXP_NB_BITS: usize = 0x400;
+const MODEXP_OP_NB_BITS: usize = 0x404;
+const MODEXP_BASE: usize = 0x724;
+const MODEXP_EXPONENT: usize = 0xbac;
+const MODEXP_MODULUS: usize = 0xd5c;
+const MODEXP_RESULT: usize = 0x724;
+
+const ECC_MUL_EXP_NB_BITS: usize = 0x400;
+const ECC_MUL_OP_NB_BITS: usize = 0x404;
+const ECC_MUL_A_SIGN: usize = 0x408;
+const ECC_MUL_A: usize = 0x40c;
+const ECC_MUL_P: usize = 0x460;
+const ECC_MUL_K: usize = 0x508;
+const ECC_MUL_X: usize = 0x55c;
+const ECC_MUL_Y: usize = 0x5b0;
+const ECC_MUL_RESULT_X: usize = 0x55c;
+const ECC_MUL_RESULT_Y: usize = 0x5b0;
+
+const POINT_CHECK_OP_NB_BITS: usize = 0x404;
+const POINT_CHECK_A_SIGN: usize = 0x408;
+const POINT_CHECK_A: usize = 0x40c;
+const POINT_CHECK_B: usize = 0x7fc;
+const POINT_CHECK_P: usize = 0x460;
+const POINT_CHECK_X: usize = 0x55c;
+const POINT_CHECK_Y: usize = 0x5b0;
+const POINT_CHECK_MONTGOMERY: usize = 0x4b4;
+const POINT_CHECK_RESULT: usize = 0x400;
+
+const SIGN_ORDER_NB_BITS: usize = 0x400;
+const SIGN_OP_NB_BITS: usize = 0x404;
+const SIGN_A_SIGN: usize = 0x408;
+const SIGN_A: usize = 0x40c;
+const SIGN_P: usize = 0x460;
+const SIGN_K: usize = 0x508;
+const SIGN_GX: usize = 0x55c;
+const SIGN_GY: usize = 0x5b0;
+const SIGN_HASH: usize = 0xde8;
+const SIGN_PRIVATE_KEY: usize = 0xe3c;
+const SIGN_ORDER: usize = 0xe94;
+const SIGN_RESULT: usize = 0xee8;
+const SIGN_R: usize = 0x700;
+const SIGN_S: usize = 0x754;
+
+const VERIFY_ORDER_NB_BITS: usize = 0x404;
+const VERIFY_OP_NB_BITS: usize = 0x4b4;
+const VERIFY_A_SIGN: usize = 0x45c;
+const VERIFY_A: usize = 0x460;
+const VERIFY_P: usize = 0x4b8;
+const VERIFY_GX: usize = 0x5e8;
+const VERIFY_GY: usize = 0x63c;
+const VERIFY_PUBLIC_X: usize = 0xf40;
+const VERIFY_PUBLIC_Y: usize = 0xf94;
+const VERIFY_R: usize = 0x1098;
+const VERIFY_S: usize = 0xa44;
+const VERIFY_HASH: usize = 0xfe8;
+const VERIFY_ORDER: usize = 0xd5c;
+const VERIFY_RESULT: usize = 0x5b0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// PKA errors.
+pub enum PkaError {
+    /// An operand is empty, too long for the peripheral, or longer than the modulus; or an output
+    /// buffer is shorter than the modulus.
+    OperandLen,
+    /// The PKA RAM was accessed by the CPU while an operation was in progress. (RAMERRF)
+    Ram,
+    /// An access was made outside PKA RAM. (ADDRERRF)
+    Address,
+    /// Timed out waiting for an operation to complete.
+    Timeout,
+    /// The operation completed, but the peripheral reported that it failed; eg an ECDSA nonce that
+    /// results in r or s being 0. Retry with a different nonce.
+    Computation,
+    /// The point isn't on the curve.
+    NotOnCurve,
+    /// The signature is invalid.
+    Verification,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+/// Operating mode. Sets the CR register, MODE field.
+enum Mode {
+    /// Montgomery parameter (R² mod n) computation only.
+    Montgomery = 0x01,
+    /// Montgomery parameter computation then modular exponentiation.
+    ModExp = 0x00,
+    /// Montgomery parameter computation then ECC scalar multiplication.
+    EccMul = 0x20,
+    EcdsaSign = 0x24,
+    EcdsaVerify = 0x26,
+    PointCheck = 0x28,
+}
+
+/// Parameters of a short Weierstrass curve, y^2 = x^3 + ax + b, over the prime field p. All
+/// values are big-endian.
+pub struct EcCurve<'a> {
+    /// The field modulus.
+    pub p: &'a [u8],
+    /// The absolute value of the a coefficient.
+    pub a: &'a [u8],
+    /// True if the a coefficient is negative.
+    pub a_negative: bool,
+    pub b: &'a [u8],
+    /// The order of the base point.
+    pub n: &'a [u8],
+    /// Base point x coordinate.
+    pub gx: &'a [u8],
+    /// Base point y coordinate.
+    pub gy: &'a [u8],
+}
+
+/// NIST P-256, also known as secp256r1 and prime256v1.
+pub const P256: EcCurve<'static> = EcCurve {
+    p: &[
+        0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff,
+    ],
+    a: &[3],
+    a_negative: true,
+    b: &[
+        0x5a, 0xc6, 0x35, 0xd8, 0xaa, 0x3a, 0x93, 0xe7, 0xb3, 0xeb, 0xbd, 0x55, 0x76, 0x98, 0x86,
+        0xbc, 0x65, 0x1d, 0x06, 0xb0, 0xcc, 0x53, 0xb0, 0xf6, 0x3b, 0xce, 0x3c, 0x3e, 0x27, 0xd2,
+        0x60, 0x4b,
+    ],
+    n: &[
+        0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63,
+        0x25, 0x51,
+    ],
+    gx: &[
+        0x6b, 0x17, 0xd1, 0xf2, 0xe1, 0x2c, 0x42, 0x47, 0xf8, 0xbc, 0xe6, 0xe5, 0x63, 0xa4, 0x40,
+        0xf2, 0x77, 0x03, 0x7d, 0x81, 0x2d, 0xeb, 0x33, 0xa0, 0xf4, 0xa1, 0x39, 0x45, 0xd8, 0x98,
+        0xc2, 0x96,
+    ],
+    gy: &[
+        0x4f, 0xe3, 0x42, 0xe2, 0xfe, 0x1a, 0x7f, 0x9b, 0x8e, 0xe7, 0xeb, 0x4a, 0x7c, 0x0f, 0x9e,
+        0x16, 0x2b, 0xce, 0x33, 0x57, 0x6b, 0x31, 0x5e, 0xce, 0xcb, 0xb6, 0x40, 0x68, 0x37, 0xbf,
+        0x51, 0xf5,
+    ],
+};
+
+/// A point on a curve, eg a public key. Coordinates are big-endian.
+pub struct EcPoint<'a> {
+    pub x: &'a [u8],
+    pub y: &'a [u8],
+}
+
+/// Represents the PKA peripheral.
+pub struct Pka {
+    pub regs: PKA,
+}
+
+impl Pka {
+    /// Initialize the PKA peripheral, including enabling and resetting its RCC peripheral clock.
+    pub fn new(regs: PKA) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+
+        cfg_if! {
+            if #[cfg(feature = "l5")] {
+                rcc_en_reset!(ahb2, pka, rcc);
+            } else {
+                rcc_en_reset!(ahb3, pka, rcc);
+            }
+        }
+
+        regs.cr.modify(|_, w| w.en().set_bit());
+
+        Self { regs }
+    }
+
+    /// Compute `base ^ exponent mod modulus`, eg for RSA. `result` must be at least as long as
+    /// `modulus`; the result is written to its first `modulus.len()` bytes. The modulus must be odd.
+    /// This blocks until complete; an RSA-2048 private key operation takes tens of millions of cycles.
+    pub fn mod_exp(
+        &mut self,
+        base: &[u8],
+        exponent: &[u8],
+        modulus: &[u8],
+        result: &mut [u8],
+    ) -> Result<(), PkaError> {
+        let len = modulus.len();
+        if len == 0
+            || len > MAX_MODEXP_LEN
+            || exponent.is_empty()
+            || exponent.len() > len
+            || base.len() > len
+            || result.len() < len
+        {
+            return Err(PkaError::OperandLen);
+        }
+
+        self.write_u32(MODEXP_EXP_NB_BITS, (exponent.len() * 8) as u32);
+        self.write_u32(MODEXP_OP_NB_BITS, (len * 8) as u32);
+        self.write_operand(MODEXP_BASE, base, len);
+        self.write_operand(MODEXP_EXPONENT, exponent, len);
+        self.write_operand(MODEXP_MODULUS, modulus, len);
+
+        let status = self.run(Mode::ModExp, (len * 8) as u32, (exponent.len() * 8) as u32);
+        if status.is_ok() {
+            self.read_operand(MODEXP_RESULT, &mut result[..len]);
+        }
+
+        // The exponent may be a private key.
+        self.wipe_ram();
+        status
+    }
+
+    /// Multiply a point on the curve by a scalar, `k`. The result coordinates are written to
+    /// `x` and `y`, which must be at least as long as the curve modulus.
+    pub fn ecc_mul(
+        &mut self,
+        curve: &EcCurve,
+        k: &[u8],
+        point: &EcPoint,
+        x: &mut [u8],
+        y: &mut [u8],
+    ) -> Result<(), PkaError> {
+        let len = check_curve(curve)?;
+        if k.is_empty()
+            || k.len() > curve.n.len()
+            || point.x.len() > len
+            || point.y.len() > len
+            || x.len() < len
+            || y.len() < len
+        {
+            return Err(PkaError::OperandLen);
+        }
+
+        self.write_u32(ECC_MUL_EXP_NB_BITS, (k.len() * 8) as u32);
+        self.write_u32(ECC_MUL_OP_NB_BITS, bit_len(curve.p));
+        self.write_u32(ECC_MUL_A_SIGN, curve.a_negative as u32);
+        self.write_operand(ECC_MUL_A, curve.a, len);
+        self.write_operand(ECC_MUL_P, curve.p, len);
+        self.write_operand(ECC_MUL_K, k, len);
+        self.write_operand(ECC_MUL_X, point.x, len);
+        self.write_operand(ECC_MUL_Y, point.y, len);
+
+        let status = self.run(Mode::EccMul, bit_len(curve.p), (k.len() * 8) as u32);
+        if status.is_ok() {
+            self.read_operand(ECC_MUL_RESULT_X, &mut x[..len]);
+            self.read_operand(ECC_MUL_RESULT_Y, &mut y[..len]);
+        }
+
+        self.wipe_ram();
+        status
+    }
+
+    /// Check that a point is on the curve. Returns `PkaError::NotOnCurve` if not.
+    pub fn check_point(&mut self, curve: &EcCurve, point: &EcPoint) -> Result<(), PkaError> {
+        let len = check_curve(curve)?;
+        if point.x.len() > len || point.y.len() > len || curve.b.len() > len {
+            return Err(PkaError::OperandLen);
+        }
+
+        // Unlike the other ECC modes, the point check doesn't compute the Montgomery parameter
+        // itself; it must be loaded along with the operands.
+        let mut r2 = [0; MAX_ECC_LEN];
+        self.montgomery(curve.p, &mut r2[..len])?;
+
+        self.write_u32(POINT_CHECK_OP_NB_BITS, bit_len(curve.p));
+        self.write_operand(POINT_CHECK_MONTGOMERY, &r2[..len], len);
+        self.write_u32(POINT_CHECK_A_SIGN, curve.a_negative as u32);
+        self.write_operand(POINT_CHECK_A, curve.a, len);
+        self.write_operand(POINT_CHECK_B, curve.b, len);
+        self.write_operand(POINT_CHECK_P, curve.p, len);
+        self.write_operand(POINT_CHECK_X, point.x, len);
+        self.write_operand(POINT_CHECK_Y, point.y, len);
+
+        let status = self.run(Mode::PointCheck, bit_len(curve.p), 1);
+        if status.is_ok() && self.read_u32(POINT_CHECK_RESULT) != 0 {
+            return Err(PkaError::NotOnCurve);
+        }
+        status
+    }
+
+    /// Compute the Montgomery parameter, R² mod `modulus`, into `result`, which must be as long as
+    /// `modulus`.
+    fn montgomery(&mut self, modulus: &[u8], result: &mut [u8]) -> Result<(), PkaError> {
+        let len = modulus.len();
+
+        self.write_u32(MONTGOMERY_OP_NB_BITS, bit_len(modulus));
+        self.write_operand(MONTGOMERY_MODULUS, modulus, len);
+
+        self.run(Mode::Montgomery, bit_len(modulus), 1)?;
+        self.read_operand(MONTGOMERY_RESULT, result);
+        Ok(())
+    }
+
+    /// Compute the public key for a private key, by multiplying the curve's base point by it.
+    /// The coordinates are written to `x` and `y`, which must be at least as long as the curve
+    /// modulus.
+    pub fn public_key(
+        &mut self,
+        curve: &EcCurve,
+        private_key: &[u8],
+        x: &mut [u8],
+        y: &mut [u8],
+    ) -> Result<(), PkaError> {
+        let base = EcPoint {
+            x: curve.gx,
+            y: curve.gy,
+        };
+        self.ecc_mul(curve, private_key, &base, x, y)
+    }
+
+    /// ECDH key agreement: Compute the shared secret from our private key, and the peer's public
+    /// key. The shared secret is the x coordinate of the resulting point, and is written to
+    /// `shared`, which must be at least as long as the curve modulus. The peer's key is checked to
+    /// be on the curve first, to prevent invalid-curve attacks.
+    pub fn ecdh(
+        &mut self,
+        curve: &EcCurve,
+        private_key: &[u8],
+        peer_public: &EcPoint,
+        shared: &mut [u8],
+    ) -> Result<(), PkaError> {
+        self.check_point(curve, peer_public)?;
+
+        let mut y = [0; MAX_ECC_LEN];
+        let result = self.ecc_mul(curve, private_key, peer_public, shared, &mut y);
+        y.fill(0);
+        result
+    }
+
+    /// Sign a message digest with ECDSA. `k` is the per-signature nonce; it must be random, secret,
+    /// never reused, and in the range 1..n. (eg from the RNG peripheral) `r` and `s` must
+    /// be at least as long as the curve order. If the digest is longer than the order, it's
+    /// truncated to the order's length. If this returns `PkaError::Computation`, retry with a
+    /// new nonce.
+    pub fn ecdsa_sign(
+        &mut self,
+        curve: &EcCurve,
+        private_key: &[u8],
+        k: &[u8],
+        digest: &[u8],
+        r: &mut [u8],
+        s: &mut [u8],
+    ) -> Result<(), PkaError> {
+        let len = check_curve(curve)?;
+        let n_len = curve.n.len();
+        if private_key.is_empty()
+            || private_key.len() > n_len
+            || k.is_empty()
+            || k.len() > n_len
+            || r.len() < n_len
+            || s.len() < n_len
+        {
+            return Err(PkaError::OperandLen);
+        }
+        let digest = &digest[..digest.len().min(n_len)];
+
+        self.write_u32(SIGN_ORDER_NB_BITS, bit_len(curve.n));
+        self.write_u32(SIGN_OP_NB_BITS, bit_len(curve.p));
+        self.write_u32(SIGN_A_SIGN, curve.a_negative as u32);
+        self.write_operand(SIGN_A, curve.a, len);
+        self.write_operand(SIGN_P, curve.p, len);
+        self.write_operand(SIGN_K, k, n_len);
+        self.write_operand(SIGN_GX, curve.gx, len);
+        self.write_operand(SIGN_GY, curve.gy, len);
+        self.write_operand(SIGN_HASH, digest, n_len);
+        self.write_operand(SIGN_PRIVATE_KEY, private_key, n_len);
+        self.write_operand(SIGN_ORDER, curve.n, n_len);
+
+        // Signing and verifying each take about two scalar multiplications.
+        let mut status = self.run(Mode::EcdsaSign, bit_len(curve.p), 2 * bit_len(curve.n));
+        if status.is_ok() {
+            if self.read_u32(SIGN_RESULT) != 0 {
+                status = Err(PkaError::Computation);
+            } else {
+                self.read_operand(SIGN_R, &mut r[..n_len]);
+                self.read_operand(SIGN_S, &mut s[..n_len]);
+            }
+        }
+
+        self.wipe_ram();
+        status
+    }
+
+    /// Verify an ECDSA signature over a message digest. Returns `PkaError::Verification` if the
+    /// signature is invalid. If the digest is longer than the order, it's truncated to the order's
+    /// length.
+    pub fn ecdsa_verify(
+        &mut self,
+        curve: &EcCurve,
+        public_key: &EcPoint,
+        digest: &[u8],
+        r: &[u8],
+        s: &[u8],
+    ) -> Result<(), PkaError> {
+        let len = check_curve(curve)?;
+        let n_len = curve.n.len();
+        if public_key.x.len() > len
+            || public_key.y.len() > len
+            || r.len() > n_len
+            || s.len() > n_len
+        {
+            return Err(PkaError::OperandLen);
+        }
+        let digest = &digest[..digest.len().min(n_len)];
+
+        self.write_u32(VERIFY_ORDER_NB_BITS, bit_len(curve.n));
+        self.write_u32(VERIFY_OP_NB_BITS, bit_len(curve.p));
+        self.write_u32(VERIFY_A_SIGN, curve.a_negative as u32);
+        self.write_operand(VERIFY_A, curve.a, len);
+        self.write_operand(VERIFY_P, curve.p, len);
+        self.write_operand(VERIFY_GX, curve.gx, len);
+        self.write_operand(VERIFY_GY, curve.gy, len);
+        self.write_operand(VERIFY_PUBLIC_X, public_key.x, len);
+        self.write_operand(VERIFY_PUBLIC_Y, public_key.y, len);
+        self.write_operand(VERIFY_R, r, n_len);
+        self.write_operand(VERIFY_S, s, n_len);
+        self.write_operand(VERIFY_HASH, digest, n_len);
+        self.write_operand(VERIFY_ORDER, curve.n, n_len);
+
+        self.run(Mode::EcdsaVerify, bit_len(curve.p), 2 * bit_len(curve.n))?;
+
+        if self.read_u32(VERIFY_RESULT) != 0 {
+            return Err(PkaError::Verification);
+        }
+        Ok(())
+    }
+
+    /// Start an operation with the operands already loaded, and wait for it to complete. `op_bits`
+    /// and `exp_bits` are the operand and exponent (or scalar) lengths, used to size the timeout.
+    /// On timeout, the operation is aborted.
+    fn run(&mut self, mode: Mode, op_bits: u32, exp_bits: u32) -> Result<(), PkaError> {
+        wait(|| self.regs.sr.read().busy().bit_is_clear(), MAX_ITERS)?;
+
+        self.clear_flags();
+
+        // MODE is at bits 13:8. START is bit 1; EN is bit 0.
+        self.regs
+            .cr
+            .modify(|r, w| unsafe { w.bits((r.bits() & !(0x3f << 8)) | ((mode as u32) << 8)) });
+        self.regs.cr.modify(|_, w| w.start().set_bit());
+
+        let status = wait(
+            || {
+                let sr = self.regs.sr.read();
+                sr.procendf().bit_is_set()
+                    || sr.ramerrf().bit_is_set()
+                    || sr.addrerrf().bit_is_set()
+            },
+            poll_limit(op_bits, exp_bits),
+        );
+
+        if status.is_err() {
+            // Clearing EN aborts the operation in progress, so that the next one can start.
+            self.regs.cr.modify(|_, w| w.en().clear_bit());
+            self.regs.cr.modify(|_, w| w.en().set_bit());
+        }
+
+        let sr = self.regs.sr.read();
+        self.clear_flags();
+
+        status?;
+
+        if sr.ramerrf().bit_is_set() {
+            return Err(PkaError::Ram);
+        }
+        if sr.addrerrf().bit_is_set() {
+            return Err(PkaError::Address);
+        }
+        Ok(())
+    }
+
+    fn clear_flags(&mut self) {
+        self.regs.clrfr.write(|w| {
+            w.procendfc().set_bit();
+            w.ramerrfc().set_bit();
+            w.addrerrfc().set_bit()
+        });
+    }
+
+    /// A pointer to a word of PKA RAM, from its offset from the peripheral's base address.
+    fn ram(&self, offset: usize) -> *mut u32 {
+        unsafe { (&*self.regs as *const _ as *mut u8).add(offset) as *mut u32 }
+    }
+
+    fn write_u32(&mut self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile(self.ram(offset), val) }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.ram(offset)) }
+    }
+
+    /// Load a big-endian operand into PKA RAM, as little-endian words, zero-padded to `len` bytes.
+    /// The peripheral requires an additional zero word after each operand.
+    fn write_operand(&mut self, offset: usize, val: &[u8], len: usize) {
+        let words = len.div_ceil(4);
+
+        for i in 0..words {
+            let mut word = 0;
+            for j in 0..4 {
+                let i_byte = i * 4 + j;
+                if i_byte < val.len() {
+                    word |= (val[val.len() - 1 - i_byte] as u32) << (j * 8);
+                }
+            }
+            self.write_u32(offset + i * 4, word);
+        }
+
+        self.write_u32(offset + words * 4, 0);
+    }
+
+    /// Read a result from PKA RAM into a big-endian buffer.
+    fn read_operand(&self, offset: usize, buf: &mut [u8]) {
+        let len = buf.len();
+
+        for i in 0..len.div_ceil(4) {
+            let word = self.read_u32(offset + i * 4);
+            for j in 0..4 {
+                let i_byte = i * 4 + j;
+                if i_byte < len {
+                    buf[len - 1 - i_byte] = (word >> (j * 8)) as u8;
+                }
+            }
+        }
+    }
+
+    /// Clear PKA RAM, so secret operands and intermediate results don't linger.
+    fn wipe_ram(&mut self) {
+        for i in 0..RAM_WORDS {
+            self.write_u32(RAM_START + i * 4, 0);
+        }
+    }
+}
+
+/// Check curve parameter lengths; return the modulus length, in bytes.
+fn check_curve(curve: &EcCurve) -> Result<usize, PkaError> {
+    let len = curve.p.len();
+    if len == 0
+        || len > MAX_ECC_LEN
+        || curve.n.is_empty()
+        || curve.n.len() > len
+        || curve.a.len() > len
+        || curve.gx.len() > len
+        || curve.gy.len() > len
+    {
+        return Err(PkaError::OperandLen);
+    }
+    Ok(len)
+}
+
+/// The length, in bits, of a big-endian value, ignoring leading zeros.
+fn bit_len(val: &[u8]) -> u32 {
+    match val.iter().position(|b| *b != 0) {
+        Some(i) => ((val.len() - i) * 8) as u32 - val[i].leading_zeros(),
+        None => 0,
+    }
+}
+
+/// The number of status polls to wait for an operation, from its operand and exponent lengths, in
+/// bits. Operation time scales with the square of the operand length, times the exponent length; eg
+/// RSA-2048 modular exponentiation takes about 37 million cycles. Each poll takes several cycles, so
+/// this leaves margin.
+fn poll_limit(op_bits: u32, exp_bits: u32) -> u32 {
+    let cycles = (op_bits as u64).pow(2) * exp_bits as u64 / 4;
+    cycles.clamp(MAX_ITERS as u64, u32::MAX as u64) as u32
+}
+
+fn wait(done: impl Fn() -> bool, max_iters: u32) -> Result<(), PkaError> {
+    let mut i = 0;
+    while !done() {
+        i += 1;
+        if i >= max_iters {
+            return Err(PkaError::Timeout);
+        }
+    }
+    Ok(())
+}
@@ -1,8 +1,934 @@
-//! Manage STM32H7 supply configuration. This is required on some H7 variants, to specify
-//! which regulator to use. This must match the way the MCU power pins are wired on the hardware design.
+//! Power control: Core voltage scaling (VOS), including G4 range 1 boost, F4 overdrive, and H7
+//! VOS0. The clocks module raises the voltage range automatically in `Clocks::setup()`, when the
+//! requested HCLK needs it; use this module to select a lower-power range, or to change ranges
+//! without reconfiguring clocks. F3 doesn't have voltage scaling. (The L4 variants this HAL
+//! supports don't have range 1 boost; it's only on L4+).
+//!
+//! It also configures VBAT battery charging, and the programmable voltage detector (PVD) and
+//! peripheral voltage monitors (PVM), including their EXTI lines, for use as interrupts or wakeup
+//! sources. To measure VBAT, use `Adc::read_vbat()`.
+//!
+//! On H747, this module also manages supply configuration, to specify which regulator to use. This
+//! must match the way the MCU power pins are wired on the hardware design.
 
-use crate::pac::PWR;
+use cfg_if::cfg_if;
 
+#[cfg(feature = "h7")]
+pub use crate::clocks::VosRange as VoltageRange;
+#[cfg(all(feature = "h7", not(any(feature = "h7b3", feature = "h735"))))]
+use crate::pac;
+#[cfg(not(any(feature = "f3", feature = "f4")))]
+use crate::pac::FLASH;
+use crate::pac::{PWR, RCC};
+#[cfg(not(feature = "f3"))]
+use crate::{clocks::RccError, MAX_ITERS};
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+use crate::{exti, gpio::Edge};
+
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "h5", feature = "h7")))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Core voltage range. Higher ranges allow higher clock frequencies, and use more power. Sets
+/// PWR_CR1 register, VOS field. Ranges compare by performance, eg `Range1 > Range2`.
+pub enum VoltageRange {
+    #[cfg(feature = "l5")]
+    /// 1.2V. HCLK up to 110Mhz.
+    Range0,
+    #[cfg(feature = "g4")]
+    /// Range 1 boost mode: 1.28V. HCLK up to 170Mhz. Sets PWR_CR5 register, R1MODE field.
+    Range1Boost,
+    /// 1.2V (1.1V on L5). The reset value on most families.
+    Range1,
+    /// 1.0V (0.9V on L5). HCLK up to 26Mhz (16Mhz on G0, WB, and WL). The reset value on L5.
+    Range2,
+}
+
+#[cfg(feature = "f4")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Regulator voltage scaling output. Higher scales allow higher clock frequencies, and use more
+/// power. Sets PWR_CR register, VOS field. Scales compare by performance, eg `Scale1 > Scale2`.
+/// The new scale only takes effect after the PLL is enabled.
+pub enum VoltageRange {
+    #[cfg(not(feature = "f401"))]
+    Scale1,
+    Scale2,
+    #[cfg(not(any(feature = "f405", feature = "f407")))]
+    Scale3,
+}
+
+#[cfg(not(any(feature = "f3", feature = "h5", feature = "h7")))]
+impl VoltageRange {
+    /// VOS field value.
+    fn bits(&self) -> u8 {
+        cfg_if! {
+            if #[cfg(any(feature = "f405", feature = "f407"))] {
+                // A single bit.
+                match self {
+                    Self::Scale1 => 1,
+                    Self::Scale2 => 0,
+                }
+            } else if #[cfg(feature = "f4")] {
+                match self {
+                    #[cfg(not(feature = "f401"))]
+                    Self::Scale1 => 0b11,
+                    Self::Scale2 => 0b10,
+                    Self::Scale3 => 0b01,
+                }
+            } else {
+                match self {
+                    #[cfg(feature = "l5")]
+                    Self::Range0 => 0b00,
+                    #[cfg(feature = "g4")]
+                    Self::Range1Boost => 0b01,
+                    Self::Range1 => 0b01,
+                    Self::Range2 => 0b10,
+                }
+            }
+        }
+    }
+
+    /// Performance level, for comparisons. Higher values allow higher frequencies.
+    fn level(&self) -> u8 {
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                match self {
+                    #[cfg(not(feature = "f401"))]
+                    Self::Scale1 => 2,
+                    Self::Scale2 => 1,
+                    #[cfg(not(any(feature = "f405", feature = "f407")))]
+                    Self::Scale3 => 0,
+                }
+            } else {
+                match self {
+                    #[cfg(feature = "l5")]
+                    Self::Range0 => 2,
+                    #[cfg(feature = "g4")]
+                    Self::Range1Boost => 2,
+                    Self::Range1 => 1,
+                    Self::Range2 => 0,
+                }
+            }
+        }
+    }
+
+    /// The maximum HCLK frequency in this range, in Hz. (HCLK4 on WB, and HCLK3 on WL). On F4
+    /// variants with overdrive, this assumes overdrive is off.
+    pub fn max_hclk(&self) -> u32 {
+        cfg_if! {
+            if #[cfg(feature = "f401")] {
+                match self {
+                    Self::Scale2 => 84_000_000,
+                    Self::Scale3 => 60_000_000,
+                }
+            } else if #[cfg(any(feature = "f405", feature = "f407"))] {
+                match self {
+                    Self::Scale1 => 168_000_000,
+                    Self::Scale2 => 144_000_000,
+                }
+            } else if #[cfg(any(feature = "f410", feature = "f411", feature = "f412", feature = "f413"))] {
+                match self {
+                    Self::Scale1 => 100_000_000,
+                    Self::Scale2 => 84_000_000,
+                    Self::Scale3 => 64_000_000,
+                }
+            } else if #[cfg(feature = "f4")] {
+                match self {
+                    Self::Scale1 => 168_000_000,
+                    Self::Scale2 => 144_000_000,
+                    Self::Scale3 => 120_000_000,
+                }
+            } else if #[cfg(feature = "l4")] {
+                match self {
+                    Self::Range1 => 80_000_000,
+                    Self::Range2 => 26_000_000,
+                }
+            } else if #[cfg(feature = "l5")] {
+                match self {
+                    Self::Range0 => 110_000_000,
+                    Self::Range1 => 80_000_000,
+                    Self::Range2 => 26_000_000,
+                }
+            } else if #[cfg(feature = "g4")] {
+                match self {
+                    Self::Range1Boost => 170_000_000,
+                    Self::Range1 => 150_000_000,
+                    Self::Range2 => 26_000_000,
+                }
+            } else if #[cfg(feature = "wl")] {
+                match self {
+                    Self::Range1 => 48_000_000,
+                    Self::Range2 => 16_000_000,
+                }
+            } else { // G0 and WB.
+                match self {
+                    Self::Range1 => 64_000_000,
+                    Self::Range2 => 16_000_000,
+                }
+            }
+        }
+    }
+
+    /// The lowest-power range that supports a given HCLK frequency. Returns the highest range if
+    /// none do.
+    pub fn required(hclk: u32) -> Self {
+        cfg_if! {
+            if #[cfg(feature = "f401")] {
+                let ranges = [Self::Scale3, Self::Scale2];
+            } else if #[cfg(any(feature = "f405", feature = "f407"))] {
+                let ranges = [Self::Scale2, Self::Scale1];
+            } else if #[cfg(feature = "f4")] {
+                let ranges = [Self::Scale3, Self::Scale2, Self::Scale1];
+            } else if #[cfg(feature = "l5")] {
+                let ranges = [Self::Range2, Self::Range1, Self::Range0];
+            } else if #[cfg(feature = "g4")] {
+                let ranges = [Self::Range2, Self::Range1, Self::Range1Boost];
+            } else {
+                let ranges = [Self::Range2, Self::Range1];
+            }
+        }
+
+        let highest = ranges[ranges.len() - 1];
+        ranges
+            .into_iter()
+            .find(|r| hclk <= r.max_hclk())
+            .unwrap_or(highest)
+    }
+
+    #[cfg(not(feature = "f4"))]
+    /// Flash wait states for this range, at a given HCLK frequency. Sets FLASH_ACR register,
+    /// LATENCY field. L4 RM, section 3.3.3. L5 RM, section 6.3.3. G0 RM, section 3.3.4. G4 RM,
+    /// section 3.3.3. WB RM, section 3.3.4, Table 4. WL RM, section 3.3.4, Table 5.
+    pub fn wait_states(&self, hclk: u32) -> u8 {
+        cfg_if! {
+            if #[cfg(feature = "l4")] {
+                let (step, max) = match self {
+                    Self::Range1 => (16_000_000, 4),
+                    Self::Range2 => (6_000_000, 3),
+                };
+            } else if #[cfg(feature = "l5")] {
+                let (step, max) = match self {
+                    Self::Range0 => (20_000_000, 5),
+                    Self::Range1 => (20_000_000, 3),
+                    Self::Range2 => (8_000_000, 2),
+                };
+            } else if #[cfg(feature = "g0")] {
+                let (step, max) = match self {
+                    Self::Range1 => (24_000_000, 2),
+                    Self::Range2 => (8_000_000, 1),
+                };
+            } else if #[cfg(feature = "g4")] {
+                let (step, max) = match self {
+                    Self::Range1Boost => (34_000_000, 4),
+                    Self::Range1 => (30_000_000, 4),
+                    Self::Range2 => (12_000_000, 2),
+                };
+            } else if #[cfg(feature = "wb")] {
+                let (step, max) = match self {
+                    Self::Range1 => (18_000_000, 3),
+                    Self::Range2 => (6_000_000, 2),
+                };
+            } else { // WL
+                let (step, max) = match self {
+                    Self::Range1 => (18_000_000, 2),
+                    Self::Range2 => (6_000_000, 2),
+                };
+            }
+        }
+
+        // Each wait state covers an additional `step` Hz.
+        (hclk.saturating_sub(1) / step).min(max) as u8
+    }
+}
+
+#[cfg(not(any(feature = "f3", feature = "h5", feature = "h7")))]
+impl PartialOrd for VoltageRange {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        self.level().partial_cmp(&other.level())
+    }
+}
+
+/// Enable the PWR peripheral clock, if required. (Its registers are retained.)
+fn enable_pwr_clock() {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+        } else if #[cfg(any(feature = "l4", feature = "l5", feature = "g4"))] {
+            rcc.apb1enr1.modify(|_, w| w.pwren().set_bit());
+        } else if #[cfg(feature = "g0")] {
+            rcc.apbenr1.modify(|_, w| w.pwren().set_bit());
+        } else {
+            // The PWR clock is always enabled on WB, WL, and H7.
+            let _ = rcc;
+        }
+    }
+}
+
+// The H7 register with the VOS field: PWR_D3CR, or PWR_SRDCR on H7B3.
+#[cfg(all(feature = "h7", not(feature = "h7b3")))]
+macro_rules! vos_cr {
+    ($pwr:expr) => {
+        $pwr.d3cr
+    };
+}
+
+#[cfg(feature = "h7b3")]
+macro_rules! vos_cr {
+    ($pwr:expr) => {
+        $pwr.srdcr
+    };
+}
+
+#[cfg(not(any(feature = "f3", feature = "h5")))]
+/// Read the current voltage range.
+pub fn voltage_range() -> VoltageRange {
+    enable_pwr_clock();
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    cfg_if! {
+        if #[cfg(any(feature = "f405", feature = "f407"))] {
+            if pwr.cr.read().bits() & (1 << 14) != 0 {
+                VoltageRange::Scale1
+            } else {
+                VoltageRange::Scale2
+            }
+        } else if #[cfg(feature = "f4")] {
+            match (pwr.cr.read().bits() >> 14) & 0b11 {
+                #[cfg(not(feature = "f401"))]
+                0b11 => VoltageRange::Scale1,
+                0b01 => VoltageRange::Scale3,
+                _ => VoltageRange::Scale2,
+            }
+        } else if #[cfg(feature = "h7")] {
+            match vos_cr!(pwr).read().vos().bits() {
+                #[cfg(not(any(feature = "h7b3", feature = "h735")))]
+                0b11 if unsafe { (*pac::SYSCFG::ptr()).pwrcr.read().oden().bit_is_set() } => VoltageRange::VOS0,
+                #[cfg(feature = "h735")]
+                0b00 => VoltageRange::VOS0,
+                0b10 => VoltageRange::VOS2,
+                0b01 => VoltageRange::VOS3,
+                _ => VoltageRange::VOS1,
+            }
+        } else {
+            let vos = pwr.cr1.read().vos().bits();
+            match vos {
+                #[cfg(feature = "l5")]
+                0b00 => VoltageRange::Range0,
+                #[cfg(feature = "g4")]
+                0b01 if pwr.cr5.read().r1mode().bit_is_clear() => VoltageRange::Range1Boost,
+                0b10 => VoltageRange::Range2,
+                _ => VoltageRange::Range1,
+            }
+        }
+    }
+}
+
+#[cfg(not(any(feature = "f3", feature = "h5")))]
+/// Set the core voltage range, adjusting flash wait states as required. When increasing
+/// performance, set the range before increasing HCLK; when decreasing it, decrease HCLK first.
+/// `hclk` is the HCLK frequency during the change (HCLK4 on WB, and HCLK3 on WL). Returns
+/// `RccError::Speed` if it's too high for the range.
+///
+/// On G4, when switching to range 1 boost mode from a frequency above 80Mhz, divide HCLK by 2 first.
+/// On F4, flash wait states don't depend on the voltage scale, so aren't changed.
+pub fn set_voltage_range(range: VoltageRange, hclk: u32) -> Result<(), RccError> {
+    cfg_if! {
+        if #[cfg(all(feature = "f4", any(feature = "f427", feature = "f429", feature = "f446", feature = "f469")))] {
+            let pwr = unsafe { &(*PWR::ptr()) };
+            let max_hclk = if pwr.csr.read().odswrdy().bit_is_set() {
+                range.max_hclk() + 24_000_000
+            } else {
+                range.max_hclk()
+            };
+
+            if hclk > max_hclk {
+                return Err(RccError::Speed);
+            }
+
+            apply_voltage_range(range)
+        } else if #[cfg(feature = "f4")] {
+            if hclk > range.max_hclk() {
+                return Err(RccError::Speed);
+            }
+
+            apply_voltage_range(range)
+        } else {
+            if hclk > range.max_hclk() {
+                return Err(RccError::Speed);
+            }
+
+            let flash = unsafe { &(*FLASH::ptr()) };
+
+            #[cfg(feature = "h7")]
+            let (wait_states, wrhighfreq) = range.wait_states(hclk);
+            #[cfg(not(feature = "h7"))]
+            let wait_states = range.wait_states(hclk);
+
+            let set_wait_states = || {
+                flash.acr.modify(|_, w| unsafe {
+                    #[cfg(feature = "h7")]
+                    w.wrhighfreq().bits(wrhighfreq);
+                    w.latency().bits(wait_states)
+                });
+
+                // Check the new value was taken into account, by reading it back.
+                let mut i = 0;
+                while flash.acr.read().latency().bits() != wait_states {
+                    i += 1;
+                    if i >= MAX_ITERS {
+                        return Err(RccError::Hardware);
+                    }
+                }
+                Ok(())
+            };
+
+            // Lower ranges need more wait states at a given frequency: Increase them before
+            // changing the voltage, and decrease them after.
+            let current = flash.acr.read().latency().bits();
+
+            if wait_states > current {
+                set_wait_states()?;
+            }
+
+            apply_voltage_range(range)?;
+
+            if wait_states < current {
+                set_wait_states()?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(any(feature = "f3", feature = "h5", feature = "h7")))]
+/// Set the voltage range, and wait for the regulator to be ready. Doesn't change flash wait states;
+/// when raising the range, the existing wait states remain valid.
+pub(crate) fn apply_voltage_range(range: VoltageRange) -> Result<(), RccError> {
+    enable_pwr_clock();
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    let mut i = 0;
+    macro_rules! wait_hang {
+        () => {
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(RccError::Hardware);
+            }
+        };
+    }
+
+    cfg_if! {
+        if #[cfg(feature = "f4")] {
+            cfg_if! {
+                if #[cfg(any(feature = "f405", feature = "f407"))] {
+                    let mask = 1 << 14;
+                } else {
+                    let mask = 0b11 << 14;
+                }
+            }
+            pwr.cr
+                .modify(|r, w| unsafe { w.bits((r.bits() & !mask) | (range.bits() as u32) << 14) });
+
+            // The scale is only applied when the PLL is on.
+            let rcc = unsafe { &(*RCC::ptr()) };
+            if rcc.cr.read().pllrdy().bit_is_set() {
+                while pwr.csr.read().vosrdy().bit_is_clear() {
+                    wait_hang!();
+                }
+            }
+        } else {
+            // The range can't be changed in Low-power run mode. Wait for the main regulator.
+            while pwr.sr2.read().reglpf().bit_is_set() {
+                wait_hang!();
+            }
+
+            #[cfg(feature = "g4")]
+            pwr.cr5
+                .modify(|_, w| w.r1mode().bit(range != VoltageRange::Range1Boost));
+
+            pwr.cr1.modify(|_, w| unsafe { w.vos().bits(range.bits()) });
+
+            // Wait for the regulator to reach the new voltage.
+            while pwr.sr2.read().vosf().bit_is_set() {
+                wait_hang!();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "h7")]
+/// Set the VOS range, and wait for the regulator to be ready. Handles the VOS0 activation and
+/// deactivation sequences. Doesn't change flash wait states.
+pub(crate) fn apply_voltage_range(range: VoltageRange) -> Result<(), RccError> {
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    let mut i = 0;
+    macro_rules! wait_hang {
+        () => {
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(RccError::Hardware);
+            }
+        };
+    }
+
+    // H743 RM, sefction 6.8.6, and section 6.6.2: Voltage Scaling
+    //  Voltage scaling selection according to performance
+    // These bits control the VCORE voltage level and allow to obtains the best trade-off between
+    // power consumption and performance:
+    // – When increasing the performance, the voltage scaling shall be changed before increasing
+    // the system frequency.
+    // – When decreasing performance, the system frequency shall first be decreased before
+    // changing the voltage scaling.
+    match range {
+        #[cfg(not(any(feature = "h7b3", feature = "h735")))]
+        // Note:H735 etc have VOS0, but not oden; the RM doesn't list these steps.
+        VoltageRange::VOS0 => {
+            let syscfg = unsafe { &(*pac::SYSCFG::ptr()) };
+
+            // VOS0 activation/deactivation sequence: H743 HRM, section 6.6.2:
+            // The system maximum frequency can be reached by boosting the voltage scaling level to
+            // VOS0. This is done through the ODEN bit in the SYSCFG_PWRCR register.
+            // The sequence to activate the VOS0 is the following:
+            // 1. Ensure that the system voltage scaling is set to VOS1 by checking the VOS bits in
+            // PWR D3 domain control register (PWR D3 domain control register (PWR_D3CR))
+            vos_cr!(pwr).modify(|_, w| unsafe { w.vos().bits(VoltageRange::VOS1 as u8) });
+
+            while vos_cr!(pwr).read().vosrdy().bit_is_clear() {
+                wait_hang!();
+            }
+
+            // 2. Enable the SYSCFG clock in the RCC by setting the SYSCFGEN bit in the
+            // RCC_APB4ENR register.
+            let rcc = unsafe { &(*RCC::ptr()) };
+            rcc.apb4enr.modify(|_, w| w.syscfgen().set_bit());
+
+            // 3. Enable the ODEN bit in the SYSCFG_PWRCR register.
+            syscfg.pwrcr.modify(|_, w| w.oden().set_bit());
+
+            // 4. Wait for VOSRDY to be set.
+            i = 0;
+            while vos_cr!(pwr).read().vosrdy().bit_is_clear() {
+                wait_hang!();
+            }
+        }
+        _ => {
+            // The sequence to deactivate the VOS0 is the following:
+            // 1. Ensure that the system frequency was decreased.
+            // 2. Ensure that the SYSCFG clock is enabled in the RCC by setting the SYSCFGEN bit set
+            // in the RCC_APB4ENR register.
+            // 3. Reset the ODEN bit in the SYSCFG_PWRCR register to disable VOS0.
+            #[cfg(not(any(feature = "h7b3", feature = "h735")))]
+            {
+                let rcc = unsafe { &(*RCC::ptr()) };
+                let syscfg = unsafe { &(*pac::SYSCFG::ptr()) };
+
+                if rcc.apb4enr.read().syscfgen().bit_is_set()
+                    && syscfg.pwrcr.read().oden().bit_is_set()
+                {
+                    syscfg.pwrcr.modify(|_, w| w.oden().clear_bit());
+                    while vos_cr!(pwr).read().vosrdy().bit_is_clear() {
+                        wait_hang!();
+                    }
+                    i = 0;
+                }
+            }
+
+            vos_cr!(pwr).modify(|_, w| unsafe { w.vos().bits(range as u8) });
+
+            while vos_cr!(pwr).read().vosrdy().bit_is_clear() {
+                wait_hang!();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(any(feature = "f427", feature = "f429", feature = "f446", feature = "f469"))]
+/// Enable overdrive, which allows HCLK 24Mhz higher than the voltage scale's normal maximum; eg
+/// 180Mhz in Scale 1. The PLL must be enabled, and the system clock must be HSI or HSE. F429 RM,
+/// section 5.1.4. `Clocks::setup()` runs this when required.
+pub fn enable_overdrive() -> Result<(), RccError> {
+    enable_pwr_clock();
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    let mut i = 0;
+    pwr.cr.modify(|_, w| w.oden().set_bit());
+    while pwr.csr.read().odrdy().bit_is_clear() {
+        i += 1;
+        if i >= MAX_ITERS {
+            return Err(RccError::Hardware);
+        }
+    }
+
+    i = 0;
+    pwr.cr.modify(|_, w| w.odswen().set_bit());
+    while pwr.csr.read().odswrdy().bit_is_clear() {
+        i += 1;
+        if i >= MAX_ITERS {
+            return Err(RccError::Hardware);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(any(feature = "f427", feature = "f429", feature = "f446", feature = "f469"))]
+/// Disable overdrive. Reduce HCLK to the voltage scale's normal maximum first.
+pub fn disable_overdrive() {
+    enable_pwr_clock();
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    pwr.cr.modify(|_, w| {
+        w.odswen().clear_bit();
+        w.oden().clear_bit()
+    });
+}
+
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+/// The PVD output's EXTI line.
+pub const PVD_EXTI_LINE: u8 = 16;
+
+#[cfg(not(any(feature = "f3", feature = "f4")))]
+#[derive(Clone, Copy, PartialEq)]
+/// VBAT battery charging resistor. Sets PWR_CR4 register (PWR_CR3 on H7), VBRS field.
+pub enum VbatResistor {
+    /// 5kΩ
+    R5k,
+    /// 1.5kΩ. Charges faster.
+    R1_5k,
+}
+
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// PVD threshold. Sets PWR_CR2 register, PLS field (PWR_CR on F3 and F4, and PWR_CR1 on H7).
+/// On G0, this sets both the PVDFT and PVDRT fields.
+///
+/// Thresholds vary by family; eg on L4, falling-edge thresholds are 2.0V, 2.2V, 2.4V, 2.5V, 2.6V,
+/// 2.8V, and 2.9V, for `Level0` through `Level6`. See the datasheet table "Programmable voltage
+/// detector characteristics".
+pub enum PvdLevel {
+    Level0 = 0,
+    Level1 = 1,
+    Level2 = 2,
+    Level3 = 3,
+    Level4 = 4,
+    Level5 = 5,
+    Level6 = 6,
+    #[cfg(any(feature = "f3", feature = "f4"))]
+    Level7 = 7,
+    #[cfg(not(any(feature = "f3", feature = "f4", feature = "g0")))]
+    /// Compare the PVD_IN pin to the internal reference, instead of comparing VDD to a threshold.
+    External = 7,
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+#[derive(Clone, Copy, PartialEq)]
+/// Peripheral voltage monitors, for independent supplies. Sets PWR_CR2 register, PVMEx fields.
+pub enum Pvm {
+    #[cfg(any(feature = "l4", feature = "l5", feature = "wb"))]
+    /// Monitors VDDUSB, vs 1.2V.
+    Pvm1,
+    #[cfg(any(feature = "l4", feature = "l5"))]
+    /// Monitors VDDIO2, vs 0.9V.
+    Pvm2,
+    /// Monitors VDDA, vs 1.62V.
+    Pvm3,
+    #[cfg(any(feature = "l4", feature = "l5", feature = "g4"))]
+    /// Monitors VDDA, vs 2.2V.
+    Pvm4,
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+impl Pvm {
+    /// The monitor's EXTI line.
+    pub fn exti_line(&self) -> u8 {
+        cfg_if! {
+            if #[cfg(feature = "l4")] {
+                match self {
+                    Self::Pvm1 => 35,
+                    Self::Pvm2 => 36,
+                    Self::Pvm3 => 37,
+                    Self::Pvm4 => 38,
+                }
+            } else if #[cfg(feature = "l5")] {
+                match self {
+                    Self::Pvm1 => 17,
+                    Self::Pvm2 => 18,
+                    Self::Pvm3 => 19,
+                    Self::Pvm4 => 20,
+                }
+            } else if #[cfg(feature = "g4")] {
+                match self {
+                    Self::Pvm3 => 34,
+                    Self::Pvm4 => 35,
+                }
+            } else if #[cfg(feature = "wb")] {
+                match self {
+                    Self::Pvm1 => 31,
+                    Self::Pvm3 => 33,
+                }
+            } else {
+                match self {
+                    Self::Pvm3 => 34,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(any(feature = "f3", feature = "f4")))]
+/// Enable charging the battery on VBAT through an internal resistor, when VDD is present. Only use
+/// this with a rechargeable battery, or supercapacitor. Sets PWR_CR4 register (PWR_CR3 on H7), VBE
+/// and VBRS fields.
+pub fn enable_vbat_charging(resistor: VbatResistor) {
+    enable_pwr_clock();
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    #[cfg(feature = "h7")]
+    pwr.cr3.modify(|_, w| {
+        w.vbrs().bit(resistor == VbatResistor::R1_5k);
+        w.vbe().set_bit()
+    });
+    #[cfg(not(feature = "h7"))]
+    pwr.cr4.modify(|_, w| {
+        w.vbrs().bit(resistor == VbatResistor::R1_5k);
+        w.vbe().set_bit()
+    });
+}
+
+#[cfg(not(any(feature = "f3", feature = "f4")))]
+/// Disable VBAT battery charging.
+pub fn disable_vbat_charging() {
+    enable_pwr_clock();
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    #[cfg(feature = "h7")]
+    pwr.cr3.modify(|_, w| w.vbe().clear_bit());
+    #[cfg(not(feature = "h7"))]
+    pwr.cr4.modify(|_, w| w.vbe().clear_bit());
+}
+
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+/// Enable the programmable voltage detector, which compares VDD to a threshold. Read the result
+/// with `pvd_output()`, or use it as an interrupt with `enable_pvd_interrupt()`.
+pub fn enable_pvd(level: PvdLevel) {
+    enable_pwr_clock();
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    let level = level as u32;
+
+    // PLS is safe to write on some variants, and unsafe on others, so we write the whole register.
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            // PVDE is bit 4. PLS is bits 7:5.
+            pwr.cr
+                .modify(|r, w| unsafe { w.bits((r.bits() & !(0b111 << 5)) | (level << 5) | (1 << 4)) });
+        } else if #[cfg(feature = "h7")] {
+            pwr.cr1
+                .modify(|r, w| unsafe { w.bits((r.bits() & !(0b111 << 5)) | (level << 5) | (1 << 4)) });
+        } else if #[cfg(feature = "g0")] {
+            // PVDE is bit 0. PVDFT is bits 3:1, and PVDRT is bits 6:4.
+            pwr.cr2.modify(|r, w| unsafe {
+                w.bits((r.bits() & !(0b11_1111 << 1)) | (level << 4) | (level << 1) | 1)
+            });
+        } else {
+            // PVDE is bit 0. PLS is bits 3:1.
+            pwr.cr2
+                .modify(|r, w| unsafe { w.bits((r.bits() & !(0b111 << 1)) | (level << 1) | 1) });
+        }
+    }
+}
+
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+/// Disable the programmable voltage detector.
+pub fn disable_pvd() {
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            pwr.cr.modify(|_, w| w.pvde().clear_bit());
+        } else if #[cfg(feature = "h7")] {
+            pwr.cr1.modify(|_, w| w.pvde().clear_bit());
+        } else {
+            pwr.cr2.modify(|_, w| w.pvde().clear_bit());
+        }
+    }
+}
+
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+/// Returns true if VDD (or PVD_IN) is below the PVD threshold. Reads PWR_SR2 register, PVDO field
+/// (PWR_CSR on F3 and F4, and PWR_CSR1 on H7).
+pub fn pvd_output() -> bool {
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            pwr.csr.read().pvdo().bit_is_set()
+        } else if #[cfg(feature = "h7")] {
+            pwr.csr1.read().pvdo().bit_is_set()
+        } else {
+            pwr.sr2.read().pvdo().bit_is_set()
+        }
+    }
+}
+
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+/// Route the PVD output to its EXTI line, as an interrupt, and wakeup source. `Edge::Rising`
+/// triggers when VDD falls below the threshold, and `Edge::Falling` when it rises above it. Unmask
+/// the PVD interrupt in the NVIC (eg `PVD_PVM`), and clear it in the handler with
+/// `clear_pvd_interrupt()`.
+pub fn enable_pvd_interrupt(edge: Edge) {
+    exti::enable(PVD_EXTI_LINE, edge);
+}
+
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+/// Mask the PVD output's EXTI line.
+pub fn disable_pvd_interrupt() {
+    exti::disable(PVD_EXTI_LINE);
+}
+
+#[cfg(not(any(feature = "g030", feature = "g070")))]
+/// Clear the PVD output's EXTI pending flag.
+pub fn clear_pvd_interrupt() {
+    exti::clear_pending(PVD_EXTI_LINE);
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+/// Enable a peripheral voltage monitor, eg to check that VDDA or VDDUSB is present before using the
+/// peripherals it supplies.
+pub fn enable_pvm(pvm: Pvm) {
+    enable_pwr_clock();
+    set_pvm(pvm, true);
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+/// Disable a peripheral voltage monitor.
+pub fn disable_pvm(pvm: Pvm) {
+    set_pvm(pvm, false);
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+fn set_pvm(pvm: Pvm, enabled: bool) {
+    let pwr = unsafe { &(*PWR::ptr()) };
+
+    cfg_if! {
+        if #[cfg(feature = "g4")] {
+            pwr.cr2.modify(|_, w| match pvm {
+                Pvm::Pvm3 => w.pvmen3().bit(enabled),
+                Pvm::Pvm4 => w.pvmen4().bit(enabled),
+            });
+        } else {
+            pwr.cr2.modify(|_, w| match pvm {
+                #[cfg(not(feature = "wl"))]
+                Pvm::Pvm1 => w.pvme1().bit(enabled),
+                #[cfg(any(feature = "l4", feature = "l5"))]
+                Pvm::Pvm2 => w.pvme2().bit(enabled),
+                Pvm::Pvm3 => w.pvme3().bit(enabled),
+                #[cfg(any(feature = "l4", feature = "l5"))]
+                Pvm::Pvm4 => w.pvme4().bit(enabled),
+            });
+        }
+    }
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+/// Returns true if the monitored supply is below its threshold. Reads PWR_SR2 register, PVMOx
+/// fields.
+pub fn pvm_output(pvm: Pvm) -> bool {
+    let sr2 = unsafe { &(*PWR::ptr()) }.sr2.read();
+
+    match pvm {
+        #[cfg(any(feature = "l4", feature = "l5", feature = "wb"))]
+        Pvm::Pvm1 => sr2.pvmo1().bit_is_set(),
+        #[cfg(any(feature = "l4", feature = "l5"))]
+        Pvm::Pvm2 => sr2.pvmo2().bit_is_set(),
+        Pvm::Pvm3 => sr2.pvmo3().bit_is_set(),
+        #[cfg(any(feature = "l4", feature = "l5", feature = "g4"))]
+        Pvm::Pvm4 => sr2.pvmo4().bit_is_set(),
+    }
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+/// Route a peripheral voltage monitor's output to its EXTI line, as an interrupt, and wakeup
+/// source. `Edge::Rising` triggers when the supply falls below the threshold. Clear it in the
+/// handler with `clear_pvm_interrupt()`.
+pub fn enable_pvm_interrupt(pvm: Pvm, edge: Edge) {
+    exti::enable(pvm.exti_line(), edge);
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+/// Mask a peripheral voltage monitor's EXTI line.
+pub fn disable_pvm_interrupt(pvm: Pvm) {
+    exti::disable(pvm.exti_line());
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g4",
+    feature = "wb",
+    feature = "wl"
+))]
+/// Clear a peripheral voltage monitor's EXTI pending flag.
+pub fn clear_pvm_interrupt(pvm: Pvm) {
+    exti::clear_pending(pvm.exti_line());
+}
+
+#[cfg(any(feature = "h747cm4", feature = "h747cm7"))]
 #[derive(Clone, Copy)]
 #[repr(u8)]
 /// SMPS step-down converter voltage output level selection.
@@ -16,6 +942,7 @@ pub enum VoltageLevel {
     V2_5 = 0b10,
 }
 
+#[cfg(any(feature = "h747cm4", feature = "h747cm7"))]
 #[derive(Clone, Copy)]
 /// See RM0399, Table 32. Supply configuration control, for available configurations.
 /// Sets the PWR_CR3 register, LDOEN, SDEN, SDEXTHP, SDLEVEL, and BYPASS fields.
@@ -36,6 +963,7 @@ pub enum SupplyConfig {
     SmpsStepdownDisabledBypass,
 }
 
+#[cfg(any(feature = "h747cm4", feature = "h747cm7"))]
 impl SupplyConfig {
     /// Apply a given supply config. `voltage_level` only affects certain variants.
     pub fn setup(&self, pwr: &mut PWR, voltage_level: VoltageLevel) {
@@ -0,0 +1,508 @@
+//! Preset topologies for motor control and power conversion, on G4. Each constructor sets up TIM1,
+//! ADCs, a comparator, and DAC3 together, so that the PWM outputs, dead time, current sampling
+//! points, and overcurrent protection are consistent with each other, and returns the configured
+//! peripheral handles.
+//!
+//! - PWM is center-aligned, with complementary outputs and dead time inserted between them.
+//! - TIM1 channel 4 sets the sampling point: Its OC4REF rising edge, routed to TRGO2, triggers the
+//!   ADCs. By default, this is right after the counter peak, in the middle of the low-side on time,
+//!   where current flows through low-side shunt resistors.
+//! - A comparator, with its inverting input connected to a DAC3 channel that sets the trip level,
+//!   drives the TIM1 break input. On overcurrent, the hardware forces the outputs to their inactive
+//!   state, without CPU involvement. Outputs stay off until you call `clear_fault()`.
+//!
+//! You must configure the GPIO pins: TIM1 CH1-3 and CH1N-3N in their alternate function, and the
+//! ADC and comparator inputs in analog mode.
+//!
+//! Example:
+//! ```rust
+//! let cfg = InverterConfig {
+//!     pwm_freq: 20_000.,
+//!     dead_time_ns: 500,
+//!     current_channels: (1, 3), // Eg PA0 on ADC1, and PA6 on ADC2.
+//!     overcurrent_threshold: 3_000,
+//!     ..Default::default()
+//! };
+//!
+//! let mut inverter = presets::three_phase_inverter(
+//!     dp.TIM1, dp.ADC1, dp.ADC2, OvercurrentComp::C1, dp.DAC3, &cfg, &clock_cfg,
+//! )
+//! .unwrap();
+//!
+//! inverter.set_duty([0.5, 0.5, 0.5]);
+//! inverter.start();
+//!
+//! // Eg in the ADC end-of-conversion interrupt:
+//! let (i_a, i_b) = inverter.read_currents();
+//! ```
+
+use crate::{
+    adc::{Adc, AdcConfig, AdcDevice, SampleTime, Trigger, TriggerEdge},
+    clocks::Clocks,
+    dac::{Dac, DacChannel, DacConfig, DacMode},
+    pac::{ADC1, ADC2, COMP, DAC3, RCC, TIM1},
+    timer::{Alignment, TimChannel, Timer, TimerConfig},
+};
+
+// TIM1 capture/compare mode registers, which are missing from the G4 PACs.
+const TIM_CCMR1: usize = 0x18;
+const TIM_CCMR2: usize = 0x1c;
+
+/// OCxM value for PWM mode 1: The output is active while the counter is below CCRx.
+const OCM_PWM1: u32 = 0b0110;
+
+/// MMS2 value that outputs OC4REF on TRGO2.
+const MMS2_OC4REF: u8 = 0b0111;
+
+/// COMP INMSEL value selecting DAC3.
+const INMSEL_DAC3: u8 = 0b100;
+
+/// The maximum DTG timer tick multiplier: (32 + 31) * 16.
+const MAX_DEAD_TIME_TICKS: u32 = 1_008;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Preset configuration errors.
+pub enum PresetError {
+    /// The PWM frequency can't be reached with the timer clock.
+    Frequency,
+    /// The dead time is longer than the timer can generate, or longer than half the PWM period.
+    DeadTime,
+    /// The sample delay is longer than half the PWM period.
+    SamplePoint,
+}
+
+#[derive(Clone, Copy)]
+/// The comparator used for overcurrent protection. Its non-inverting input is IO1 (COMP1: PA1,
+/// COMP2: PA7, COMP3: PA0, COMP4: PB0). COMP1 and COMP3 use DAC3 channel 1 as their threshold;
+/// COMP2 and COMP4 use DAC3 channel 2.
+pub enum OvercurrentComp {
+    C1,
+    C2,
+    C3,
+    C4,
+}
+
+impl OvercurrentComp {
+    fn dac_channel(&self) -> DacChannel {
+        match self {
+            Self::C1 | Self::C3 => DacChannel::C1,
+            Self::C2 | Self::C4 => DacChannel::C2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Configuration shared by the presets.
+pub struct InverterConfig {
+    /// PWM frequency, in Hz. Defaults to 20kHz.
+    pub pwm_freq: f32,
+    /// Dead time inserted between each complementary output turning off, and the other turning on,
+    /// in ns. Defaults to 500ns.
+    pub dead_time_ns: u32,
+    /// Delay from the counter peak (the middle of the low-side on time) to the ADC trigger, in ns.
+    /// Use this to skip ringing after switching. Defaults to 0.
+    pub sample_delay_ns: u32,
+    /// ADC channels measuring current. For the three-phase inverter, the first is read by ADC1,
+    /// and the second by ADC2. The half bridge uses the first on ADC1. Defaults to channels 1 and 1.
+    pub current_channels: (u8, u8),
+    /// ADC sample time for the current channels; keep this short, so the sample completes within
+    /// the low-side on time. Defaults to 6.5 cycles.
+    pub sample_time: SampleTime,
+    /// Overcurrent trip level, as a 12-bit DAC3 code. Defaults to full scale, ie effectively off.
+    pub overcurrent_threshold: u16,
+    /// Break input digital filter, between 0 (none) and 15. Filters glitches on the comparator output
+    /// before tripping. Sets TIM1 BDTR register, BKF field. Defaults to 0.
+    pub break_filter: u8,
+    /// Reference voltage, for the DAC. Defaults to 3.3V.
+    pub vref: f32,
+}
+
+impl Default for InverterConfig {
+    fn default() -> Self {
+        Self {
+            pwm_freq: 20_000.,
+            dead_time_ns: 500,
+            sample_delay_ns: 0,
+            current_channels: (1, 1),
+            sample_time: SampleTime::T2,
+            overcurrent_threshold: 4_095,
+            break_filter: 0,
+            vref: 3.3,
+        }
+    }
+}
+
+/// Calculate the BDTR register DTG field for a dead time, from the timer clock. (CKD = 0)
+/// See G4 RM, section 28.6.21.
+fn dead_time_bits(dead_time_ns: u32, timer_clock: u32) -> Result<u8, PresetError> {
+    let ticks = (dead_time_ns as u64 * timer_clock as u64).div_ceil(1_000_000_000) as u32;
+
+    let dtg = match ticks {
+        0..=127 => ticks,
+        128..=254 => 0b1000_0000 | (ticks.div_ceil(2) - 64),
+        255..=504 => 0b1100_0000 | (ticks.div_ceil(8) - 32),
+        505..=MAX_DEAD_TIME_TICKS => 0b1110_0000 | (ticks.div_ceil(16) - 32),
+        _ => return Err(PresetError::DeadTime),
+    };
+
+    Ok(dtg as u8)
+}
+
+/// Set up TIM1 for center-aligned, complementary PWM on `channels`, with dead time, an ADC trigger on
+/// TRGO2, and the comparator on the break input. Leaves the timer and outputs disabled.
+fn setup_timer(
+    regs: TIM1,
+    channels: &[TimChannel],
+    comp: OvercurrentComp,
+    cfg: &InverterConfig,
+    clocks: &Clocks,
+) -> Result<Timer<TIM1>, PresetError> {
+    let timer_cfg = TimerConfig {
+        auto_reload_preload: true,
+        alignment: Alignment::Center1,
+        ..Default::default()
+    };
+
+    let mut timer = Timer::new_tim1(regs, cfg.pwm_freq, timer_cfg, clocks);
+    timer
+        .set_freq(cfg.pwm_freq)
+        .map_err(|_| PresetError::Frequency)?;
+
+    timer
+        .regs
+        .cr1
+        .modify(|_, w| unsafe { w.cms().bits(Alignment::Center1 as u8) });
+
+    let timer_clock = clocks.apb2_timer();
+    let arr = timer.regs.arr.read().bits();
+    // In center-aligned mode, the half-period is ARR ticks of the prescaled clock.
+    let psc = timer.regs.psc.read().bits() + 1;
+    let half_period_ns = (arr as u64 * psc as u64 * 1_000_000_000) / timer_clock as u64;
+
+    if cfg.dead_time_ns as u64 >= half_period_ns {
+        return Err(PresetError::DeadTime);
+    }
+    if cfg.sample_delay_ns as u64 >= half_period_ns {
+        return Err(PresetError::SamplePoint);
+    }
+
+    let dtg = dead_time_bits(cfg.dead_time_ns, timer_clock)?;
+
+    // PWM mode 1 with preload on the phase channels, and channel 4 for the ADC trigger. OC4REF is
+    // active while the counter is below CCR4; it rises while counting down, `sample_delay_ns` after
+    // the peak.
+    for channel in channels.iter().chain(&[TimChannel::C4]) {
+        let (offset, shift) = match channel {
+            TimChannel::C1 => (TIM_CCMR1, 0),
+            TimChannel::C2 => (TIM_CCMR1, 8),
+            TimChannel::C3 => (TIM_CCMR2, 0),
+            TimChannel::C4 => (TIM_CCMR2, 8),
+        };
+        modify_reg(&timer.regs, offset, |r| {
+            // CCxS = output, OCxPE, OCxM[2:0], and OCxM[3], which is separate.
+            let mask = (0b11 | (1 << 3) | (0b111 << 4)) << shift | (1 << (16 + shift));
+            (r & !mask) | ((1 << 3) | ((OCM_PWM1 & 0b111) << 4)) << shift
+        });
+    }
+
+    let delay_ticks =
+        (cfg.sample_delay_ns as u64 * timer_clock as u64 / psc as u64 / 1_000_000_000) as u32;
+    timer.regs.ccr[3].write(|w| unsafe { w.bits(arr.saturating_sub(1 + delay_ticks)) });
+    timer
+        .regs
+        .cr2
+        .modify(|_, w| unsafe { w.mms2().bits(MMS2_OC4REF) });
+
+    // Enable the outputs, and their complements. They don't drive the pins until MOE is set.
+    timer.regs.ccer.modify(|_, w| {
+        for channel in channels {
+            match channel {
+                TimChannel::C1 => w.cc1e().set_bit().cc1ne().set_bit(),
+                TimChannel::C2 => w.cc2e().set_bit().cc2ne().set_bit(),
+                TimChannel::C3 => w.cc3e().set_bit().cc3ne().set_bit(),
+                TimChannel::C4 => w.cc4e().set_bit(),
+            };
+        }
+        w.cc4e().set_bit()
+    });
+
+    // Route the comparator to the break input.
+    timer.regs.af1.modify(|_, w| match comp {
+        OvercurrentComp::C1 => w.bkcmp1e().set_bit(),
+        OvercurrentComp::C2 => w.bkcmp2e().set_bit(),
+        OvercurrentComp::C3 => w.bkcmp3e().set_bit(),
+        OvercurrentComp::C4 => w.bkcmp4e().set_bit(),
+    });
+
+    // Break on a high comparator output. With OSSR and OSSI set, outputs are driven to their
+    // inactive state when disabled, or on break. MOE is only set again by software.
+    timer.regs.bdtr.modify(|_, w| unsafe {
+        w.dtg().bits(dtg);
+        w.ossr().set_bit();
+        w.ossi().set_bit();
+        w.bke().set_bit();
+        w.bkp().set_bit();
+        w.bkf().bits(cfg.break_filter & 0xf);
+        w.aoe().clear_bit();
+        w.moe().clear_bit()
+    });
+
+    // Load the preloaded compare values.
+    timer.reinitialize();
+
+    Ok(timer)
+}
+
+/// Set up the comparator, with DAC3 setting its threshold. Returns the DAC handle.
+fn setup_overcurrent(dac: DAC3, comp: OvercurrentComp, cfg: &InverterConfig) -> Dac<DAC3> {
+    let dac_cfg = DacConfig {
+        mode: DacMode::NormExternalAndPeriphBufDis,
+        ..Default::default()
+    };
+    let mut dac = Dac::new(dac, dac_cfg, cfg.vref);
+
+    let channel = comp.dac_channel();
+    dac.write(channel, cfg.overcurrent_threshold);
+    dac.enable(channel);
+
+    // The comparators are clocked with SYSCFG.
+    let rcc = unsafe { &(*RCC::ptr()) };
+    rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+    let comp_regs = unsafe { &*COMP::ptr() };
+    macro_rules! setup_csr {
+        ($csr:ident) => {
+            comp_regs.$csr.modify(|_, w| unsafe {
+                w.inmsel().bits(INMSEL_DAC3);
+                w.inpsel().clear_bit();
+                w.pol().clear_bit();
+                w.en().set_bit()
+            })
+        };
+    }
+
+    match comp {
+        OvercurrentComp::C1 => setup_csr!(c1csr),
+        OvercurrentComp::C2 => setup_csr!(c2csr),
+        OvercurrentComp::C3 => setup_csr!(c3csr),
+        OvercurrentComp::C4 => setup_csr!(c4csr),
+    }
+
+    dac
+}
+
+/// Set up an ADC for a single channel, converting on each TIM1 TRGO2 rising edge.
+macro_rules! setup_adc {
+    ($adc:expr, $channel:expr, $cfg:expr) => {{
+        let adc = &mut $adc;
+        adc.set_sequence($channel, 1);
+        adc.set_sequence_len(1);
+        adc.set_sample_time($channel, $cfg.sample_time);
+        adc.set_trigger(Trigger::Tim1Trgo2, TriggerEdge::HardwareRising);
+    }};
+}
+
+fn set_duty_portion(timer: &mut Timer<TIM1>, channel: TimChannel, duty: f32) {
+    let arr = timer.regs.arr.read().bits();
+    let val = (duty.clamp(0., 1.) * arr as f32) as u32;
+    let i = match channel {
+        TimChannel::C1 => 0,
+        TimChannel::C2 => 1,
+        TimChannel::C3 => 2,
+        TimChannel::C4 => 3,
+    };
+    timer.regs.ccr[i].write(|w| unsafe { w.bits(val) });
+}
+
+fn modify_reg(regs: &TIM1, offset: usize, f: impl FnOnce(u32) -> u32) {
+    unsafe {
+        let reg = (&**regs as *const _ as *mut u8).add(offset) as *mut u32;
+        core::ptr::write_volatile(reg, f(core::ptr::read_volatile(reg)));
+    }
+}
+
+/// A three-phase inverter, eg for BLDC or PMSM motor control. Phases A, B, and C are TIM1
+/// CH1/CH1N, CH2/CH2N and CH3/CH3N. ADC1 and ADC2 sample two phase currents simultaneously; the
+/// third is their negated sum.
+pub struct ThreePhaseInverter {
+    pub timer: Timer<TIM1>,
+    pub adc1: Adc<ADC1>,
+    pub adc2: Adc<ADC2>,
+    pub dac: Dac<DAC3>,
+    comp: OvercurrentComp,
+}
+
+/// Set up a three-phase inverter. See the module documentation for details.
+pub fn three_phase_inverter(
+    tim1: TIM1,
+    adc1: ADC1,
+    adc2: ADC2,
+    comp: OvercurrentComp,
+    dac: DAC3,
+    cfg: &InverterConfig,
+    clocks: &Clocks,
+) -> Result<ThreePhaseInverter, PresetError> {
+    let timer = setup_timer(
+        tim1,
+        &[TimChannel::C1, TimChannel::C2, TimChannel::C3],
+        comp,
+        cfg,
+        clocks,
+    )?;
+
+    let adc_cfg = AdcConfig {
+        sample_time: cfg.sample_time,
+        ..Default::default()
+    };
+    let mut adc1 = Adc::new_adc1(adc1, AdcDevice::One, adc_cfg.clone(), clocks.systick());
+    let mut adc2 = Adc::new_adc2(adc2, AdcDevice::Two, adc_cfg, clocks.systick());
+
+    setup_adc!(adc1, cfg.current_channels.0, cfg);
+    setup_adc!(adc2, cfg.current_channels.1, cfg);
+
+    let dac = setup_overcurrent(dac, comp, cfg);
+
+    Ok(ThreePhaseInverter {
+        timer,
+        adc1,
+        adc2,
+        dac,
+        comp,
+    })
+}
+
+impl ThreePhaseInverter {
+    /// Arm the ADC triggers, and start switching.
+    pub fn start(&mut self) {
+        self.adc1.regs.cr.modify(|_, w| w.adstart().set_bit());
+        self.adc2.regs.cr.modify(|_, w| w.adstart().set_bit());
+
+        self.timer.regs.bdtr.modify(|_, w| w.moe().set_bit());
+        self.timer.enable();
+    }
+
+    /// Stop switching; outputs are driven to their inactive state.
+    pub fn stop(&mut self) {
+        self.timer.regs.bdtr.modify(|_, w| w.moe().clear_bit());
+        self.timer.disable();
+
+        self.adc1.stop_conversions();
+        self.adc2.stop_conversions();
+    }
+
+    /// Set the duty cycle of each phase, as a portion of the period, between 0. and 1. This is the
+    /// high-side on time; it takes effect at the next update event.
+    pub fn set_duty(&mut self, duty: [f32; 3]) {
+        set_duty_portion(&mut self.timer, TimChannel::C1, duty[0]);
+        set_duty_portion(&mut self.timer, TimChannel::C2, duty[1]);
+        set_duty_portion(&mut self.timer, TimChannel::C3, duty[2]);
+    }
+
+    /// Read the most recent raw current samples, from ADC1 and ADC2.
+    pub fn read_currents(&mut self) -> (u16, u16) {
+        (self.adc1.read_result(), self.adc2.read_result())
+    }
+
+    /// Set the overcurrent trip level, as a 12-bit DAC code.
+    pub fn set_overcurrent_threshold(&mut self, threshold: u16) {
+        self.dac.write(self.comp.dac_channel(), threshold);
+    }
+
+    /// Returns `true` if an overcurrent break has disabled the outputs.
+    pub fn fault(&self) -> bool {
+        self.timer.regs.sr.read().bif().bit_is_set()
+    }
+
+    /// Clear an overcurrent fault, and re-enable the outputs. The break input is level-sensitive; if
+    /// the comparator output is still high, the outputs are disabled again immediately.
+    pub fn clear_fault(&mut self) {
+        self.timer.regs.sr.modify(|_, w| w.bif().clear_bit());
+        self.timer.regs.bdtr.modify(|_, w| w.moe().set_bit());
+    }
+}
+
+/// A half bridge, eg for a synchronous buck or boost converter, or a brushed DC motor with a
+/// second bridge. The switch pair is TIM1 CH1/CH1N. ADC1 samples the current at the middle of the
+/// low-side on time.
+pub struct HalfBridge {
+    pub timer: Timer<TIM1>,
+    pub adc: Adc<ADC1>,
+    pub dac: Dac<DAC3>,
+    comp: OvercurrentComp,
+}
+
+/// Set up a half bridge. `cfg.current_channels.0` sets the ADC1 channel. See the module
+/// documentation for details.
+pub fn half_bridge(
+    tim1: TIM1,
+    adc1: ADC1,
+    comp: OvercurrentComp,
+    dac: DAC3,
+    cfg: &InverterConfig,
+    clocks: &Clocks,
+) -> Result<HalfBridge, PresetError> {
+    let timer = setup_timer(tim1, &[TimChannel::C1], comp, cfg, clocks)?;
+
+    let adc_cfg = AdcConfig {
+        sample_time: cfg.sample_time,
+        ..Default::default()
+    };
+    let mut adc = Adc::new_adc1(adc1, AdcDevice::One, adc_cfg, clocks.systick());
+    setup_adc!(adc, cfg.current_channels.0, cfg);
+
+    let dac = setup_overcurrent(dac, comp, cfg);
+
+    Ok(HalfBridge {
+        timer,
+        adc,
+        dac,
+        comp,
+    })
+}
+
+impl HalfBridge {
+    /// Arm the ADC trigger, and start switching.
+    pub fn start(&mut self) {
+        self.adc.regs.cr.modify(|_, w| w.adstart().set_bit());
+
+        self.timer.regs.bdtr.modify(|_, w| w.moe().set_bit());
+        self.timer.enable();
+    }
+
+    /// Stop switching; outputs are driven to their inactive state.
+    pub fn stop(&mut self) {
+        self.timer.regs.bdtr.modify(|_, w| w.moe().clear_bit());
+        self.timer.disable();
+
+        self.adc.stop_conversions();
+    }
+
+    /// Set the duty cycle, as a portion of the period, between 0. and 1. This is the high-side
+    /// on time; it takes effect at the next update event.
+    pub fn set_duty(&mut self, duty: f32) {
+        set_duty_portion(&mut self.timer, TimChannel::C1, duty);
+    }
+
+    /// Read the most recent raw current sample.
+    pub fn read_current(&mut self) -> u16 {
+        self.adc.read_result()
+    }
+
+    /// Set the overcurrent trip level, as a 12-bit DAC code.
+    pub fn set_overcurrent_threshold(&mut self, threshold: u16) {
+        self.dac.write(self.comp.dac_channel(), threshold);
+    }
+
+    /// Returns `true` if an overcurrent break has disabled the outputs.
+    pub fn fault(&self) -> bool {
+        self.timer.regs.sr.read().bif().bit_is_set()
+    }
+
+    /// Clear an overcurrent fault, and re-enable the outputs.
+    pub fn clear_fault(&mut self) {
+        self.timer.regs.sr.modify(|_, w| w.bif().clear_bit());
+        self.timer.regs.bdtr.modify(|_, w| w.moe().set_bit());
+    }
+}
@@ -21,9 +21,10 @@ cfg_if! {
 // todo: Status-polling mode.
 
 // todo: Is this avail in PAC? Feature-gate if diff on diff platforms?
-const MEM_MAPPED_BASE_ADDR: usize = 0x9000_0000;
+pub(crate) const MEM_MAPPED_BASE_ADDR: usize = 0x9000_0000;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Sets the Qspi mode to single, dual, or quad. Affects the IMODE, ADMODE, ABMODE,
 /// and DMODE fields of the CCR reg. Each of these fields affects a different mode of operation.
@@ -45,7 +46,8 @@ pub enum ProtocolMode {
 //     FourLines = 0b11,
 // }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Sets the Qspi data mode. Affects the DDRM field of the CCR reg.
 pub enum DataMode {
@@ -69,7 +71,8 @@ pub enum FunctionalMode {
 }
 
 /// Address sizes used by the QSPI interface
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AddressSize {
     /// 8 byte address size.
     A8 = 0b00,
@@ -82,23 +85,32 @@ pub enum AddressSize {
 }
 
 /// Sampling mode for the QSPI interface
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SamplingEdge {
     Rising = 0,
     Falling = 1,
 }
 
 /// Indicates an error with the QSPI peripheral.
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum QspiError {
     Busy,
     Underflow,
+    /// The transfer error flag (TEF) was set; eg the address is out of range.
+    TransferError,
+    /// Data to program is in the memory-mapped region, which can't be read while writing.
+    XipSource,
+    /// The XIP page or sector size isn't a non-zero power of two.
+    XipConfig,
 }
 
 // todo: Use bank on suitable MCUs? Which? F7 / H7?
 
 /// A structure for specifying QSPI configuration.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct QspiConfig {
     /// Note: For configuration purposes, use IndirectRead or Indirect Write
     // pub functional_mode: FunctionalMode,
@@ -137,7 +149,8 @@ impl Default for QspiConfig {
 }
 
 /// Interrupt events
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum QspiInterrupt {
     FifoThreshold,
     StatusMatch,
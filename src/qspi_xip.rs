@@ -0,0 +1,331 @@
+//! Erase and program external QSPI NOR flash while code or assets execute from it in memory-mapped
+//! (XIP) mode.
+//!
+//! The flash can't be read while it's being written, so memory-mapped mode is suspended for each
+//! operation: The arbiter aborts memory-mapped mode, switches to indirect mode, sends the command,
+//! polls the flash's busy flag, then restores memory-mapped mode. Each of these windows runs
+//! from a single function placed in RAM, with interrupts disabled, since any instruction fetch or
+//! data read from the memory-mapped region during it would stall, or return garbage. A program spanning
+//! several pages uses one window per page, so interrupts are serviced between pages; erases take one
+//! window per sector, which may be tens of milliseconds. Set interrupt deadlines accordingly.
+//!
+//! Requirements:
+//! - The RAM function is linked in `.data`, which `cortex-m-rt` copies to RAM at startup. This RAM
+//!   must be executable; on H7, DTCM isn't, so link `.data` to AXI SRAM, or another region.
+//! - Build with optimizations (eg `opt-level = "s"` for this crate, including in dev profiles), so
+//!   register accesses are inlined into the RAM function, instead of called from flash.
+//! - Data passed to `program()` can't be in the memory-mapped region; copy it to RAM first.
+//! - On H7 with the caches enabled, invalidate the D-cache (and I-cache, for code) over modified
+//!   areas afterwards.
+//!
+//! Commands default to those of common 3-byte-address NOR flash, eg W25Q and MX25 series.
+//! Not available on variants with OctoSPI.
+//!
+//! Example:
+//! ```rust
+//! let qspi = Qspi::new(dp.QUADSPI, Default::default(), &clock_cfg);
+//! let mut xip = XipArbiter::new(qspi, Default::default());
+//!
+//! // Code and data in the 0x9000_0000 region is now available.
+//!
+//! let buf = [0x55_u8; 64]; // In RAM.
+//! xip.erase_sector(0x10_0000).ok();
+//! xip.program(0x10_0000, &buf).ok();
+//! ```
+
+#[cfg(target_arch = "arm")]
+use core::arch::asm;
+use core::ptr;
+
+use crate::qspi::{ProtocolMode, Qspi, QspiError, MEM_MAPPED_BASE_ADDR};
+
+// Register offsets, in 32-bit words. We use raw accesses in the RAM function, so it doesn't call
+// PAC code in flash.
+const CR: usize = 0;
+const SR: usize = 2; // 0x08
+const FCR: usize = 3; // 0x0C
+const DLR: usize = 4; // 0x10
+const CCR: usize = 5; // 0x14
+const AR: usize = 6; // 0x18
+const DR: usize = 8; // 0x20
+
+const CR_ABORT: u32 = 1 << 1;
+const SR_TEF: u32 = 1 << 0;
+const SR_TCF: u32 = 1 << 1;
+const SR_BUSY: u32 = 1 << 5;
+/// FIFO level, in bits 8:13.
+const SR_FLEVEL_SHIFT: u32 = 8;
+/// The smallest QUADSPI FIFO, among supported families; 16 bytes on L4.
+const FIFO_SIZE: u32 = 16;
+
+// CCR fields.
+const IMODE_SINGLE: u32 = 0b01 << 8;
+const ADMODE_SHIFT: u32 = 10;
+const ADSIZE_SHIFT: u32 = 12;
+const DCYC_SHIFT: u32 = 18;
+const DMODE_SHIFT: u32 = 24;
+const FMODE_INDIRECT_READ: u32 = 0b01 << 26;
+const FMODE_MEMORY_MAPPED: u32 = 0b11 << 26;
+const SINGLE: u32 = ProtocolMode::Single as u32;
+
+/// The flash status register's Write In Progress bit.
+const STATUS_WIP: u8 = 1 << 0;
+
+/// The memory-mapped region's size; it's limited to 256MB.
+const MEM_MAPPED_SIZE: usize = 0x1000_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Flash memory commands, and geometry, used by the arbiter.
+pub struct XipConfig {
+    /// The read instruction used in memory-mapped mode. Defaults to `0xEB` (Fast Read Quad I/O).
+    pub read_instruction: u8,
+    /// Lines used for the address and data phases of memory-mapped reads. The instruction is sent
+    /// on a single line. Defaults to quad; this requires the flash's Quad Enable bit to be set.
+    pub read_mode: ProtocolMode,
+    /// Dummy cycles for memory-mapped reads; this includes any mode bits. Defaults to 6.
+    pub read_dummy_cycles: u8,
+    /// Defaults to `0x06`.
+    pub write_enable: u8,
+    /// Read the status register containing the WIP bit. Defaults to `0x05`.
+    pub read_status: u8,
+    /// Page program, on a single line. Defaults to `0x02`.
+    pub page_program: u8,
+    /// Sector erase. Defaults to `0x20`; 4kB sectors.
+    pub sector_erase: u8,
+    /// Program page size, in bytes; a power of two. Defaults to 256.
+    pub page_size: u32,
+    /// Erase sector size, in bytes; a power of two. Defaults to 4096.
+    pub sector_size: u32,
+}
+
+impl Default for XipConfig {
+    fn default() -> Self {
+        Self {
+            read_instruction: 0xeb,
+            read_mode: ProtocolMode::Quad,
+            read_dummy_cycles: 6,
+            write_enable: 0x06,
+            read_status: 0x05,
+            page_program: 0x02,
+            sector_erase: 0x20,
+            page_size: 256,
+            sector_size: 4_096,
+        }
+    }
+}
+
+/// A single erase or program command, run with memory-mapped mode suspended.
+struct Command {
+    /// Restored after the command, to re-enter memory-mapped mode.
+    xip_ccr: u32,
+    write_enable: u8,
+    read_status: u8,
+    /// The erase or program instruction, with single-line address, and the address size.
+    ccr: u32,
+    address: u32,
+    data: *const u8,
+    len: usize,
+}
+
+/// Arbitrates access to a QSPI flash between memory-mapped reads (XIP), and writes. Holds the `Qspi`
+/// peripheral, so no other code can switch its mode while memory-mapped mode is in use.
+pub struct XipArbiter {
+    pub qspi: Qspi,
+    pub cfg: XipConfig,
+}
+
+impl XipArbiter {
+    /// Create the arbiter, and enter memory-mapped mode.
+    pub fn new(qspi: Qspi, cfg: XipConfig) -> Self {
+        let mut result = Self { qspi, cfg };
+        result.enable_xip();
+        result
+    }
+
+    /// The CCR value for memory-mapped reads.
+    fn xip_ccr(&self) -> u32 {
+        let mode = self.cfg.read_mode as u32;
+
+        self.cfg.read_instruction as u32
+            | IMODE_SINGLE
+            | (mode << ADMODE_SHIFT)
+            | ((self.qspi.cfg.address_size as u32) << ADSIZE_SHIFT)
+            | ((self.cfg.read_dummy_cycles as u32 & 0x1f) << DCYC_SHIFT)
+            | (mode << DMODE_SHIFT)
+            | FMODE_MEMORY_MAPPED
+    }
+
+    fn regs(&self) -> *mut u32 {
+        &*self.qspi.regs as *const _ as *mut u32
+    }
+
+    /// Enter memory-mapped mode. The flash is then readable at `0x9000_0000`. Erases and programs
+    /// restore this mode when complete; use this after using the `Qspi` directly.
+    pub fn enable_xip(&mut self) {
+        let regs = self.regs();
+        let ccr = self.xip_ccr();
+
+        unsafe {
+            ptr::write_volatile(regs.add(CR), ptr::read_volatile(regs.add(CR)) | CR_ABORT);
+            while ptr::read_volatile(regs.add(CR)) & CR_ABORT != 0 {}
+            while ptr::read_volatile(regs.add(SR)) & SR_BUSY != 0 {}
+
+            ptr::write_volatile(regs.add(CCR), ccr);
+        }
+    }
+
+    /// Run a command with memory-mapped mode suspended.
+    fn run(&mut self, instruction: u8, address: u32, data: &[u8]) -> Result<(), QspiError> {
+        let with_data = if data.is_empty() {
+            0
+        } else {
+            SINGLE << DMODE_SHIFT
+        };
+
+        let cmd = Command {
+            xip_ccr: self.xip_ccr(),
+            write_enable: self.cfg.write_enable,
+            read_status: self.cfg.read_status,
+            ccr: instruction as u32
+                | IMODE_SINGLE
+                | (SINGLE << ADMODE_SHIFT)
+                | ((self.qspi.cfg.address_size as u32) << ADSIZE_SHIFT)
+                | with_data,
+            address,
+            data: data.as_ptr(),
+            len: data.len(),
+        };
+
+        if unsafe { run_suspended(self.regs(), &cmd) } {
+            Ok(())
+        } else {
+            Err(QspiError::TransferError)
+        }
+    }
+
+    /// Erase the sector containing `address`. `address` is relative to the start of the flash
+    /// memory, not the memory-mapped region. Returns `QspiError::XipConfig` if the sector size
+    /// isn't a power of two.
+    pub fn erase_sector(&mut self, address: u32) -> Result<(), QspiError> {
+        if !self.cfg.sector_size.is_power_of_two() {
+            return Err(QspiError::XipConfig);
+        }
+
+        let address = address & !(self.cfg.sector_size - 1);
+        self.run(self.cfg.sector_erase, address, &[])
+    }
+
+    /// Program data, starting at `address`, relative to the start of flash memory. This splits the
+    /// write at page boundaries. The area must be erased first. Returns `QspiError::XipSource` if
+    /// `data` is in the memory-mapped region, or `QspiError::XipConfig` if the page size isn't a
+    /// power of two.
+    pub fn program(&mut self, address: u32, data: &[u8]) -> Result<(), QspiError> {
+        if !self.cfg.page_size.is_power_of_two() {
+            return Err(QspiError::XipConfig);
+        }
+
+        let start = data.as_ptr() as usize;
+        if start < MEM_MAPPED_BASE_ADDR + MEM_MAPPED_SIZE
+            && start + data.len() > MEM_MAPPED_BASE_ADDR
+        {
+            return Err(QspiError::XipSource);
+        }
+
+        let mut address = address;
+        let mut data = data;
+
+        while !data.is_empty() {
+            let page_remaining = self.cfg.page_size - address % self.cfg.page_size;
+            let len = data.len().min(page_remaining as usize);
+
+            self.run(self.cfg.page_program, address, &data[..len])?;
+
+            address += len as u32;
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+
+    /// Release the `Qspi` peripheral. It's left in memory-mapped mode.
+    pub fn free(self) -> Qspi {
+        self.qspi
+    }
+}
+
+/// Suspend memory-mapped mode, run a write-enable, the command, and wait for the flash to finish;
+/// then restore memory-mapped mode. This runs from RAM with interrupts disabled, and must not call
+/// any function that isn't inlined; see the module docs. Returns false if there's a transfer error.
+#[inline(never)]
+#[link_section = ".data.qspi_xip"]
+unsafe fn run_suspended(regs: *mut u32, cmd: &Command) -> bool {
+    // Disable interrupts, so no handler reads from the memory-mapped region during this.
+    #[cfg(target_arch = "arm")]
+    let primask: u32;
+    #[cfg(target_arch = "arm")]
+    asm!("mrs {}, PRIMASK", "cpsid i", out(reg) primask, options(nostack, preserves_flags));
+
+    // Exit memory-mapped mode.
+    ptr::write_volatile(regs.add(CR), ptr::read_volatile(regs.add(CR)) | CR_ABORT);
+    while ptr::read_volatile(regs.add(CR)) & CR_ABORT != 0 {}
+    while ptr::read_volatile(regs.add(SR)) & SR_BUSY != 0 {}
+
+    // Write enable: An instruction-only command starts when CCR is written.
+    ptr::write_volatile(regs.add(CCR), cmd.write_enable as u32 | IMODE_SINGLE);
+    while ptr::read_volatile(regs.add(SR)) & SR_TCF == 0 {}
+    ptr::write_volatile(regs.add(FCR), SR_TCF);
+
+    // The erase or program command starts when AR is written. In indirect write mode, its data is
+    // pushed through the FIFO.
+    if cmd.len > 0 {
+        ptr::write_volatile(regs.add(DLR), cmd.len as u32 - 1);
+    }
+    ptr::write_volatile(regs.add(CCR), cmd.ccr);
+    ptr::write_volatile(regs.add(AR), cmd.address);
+
+    let mut i = 0;
+    while i < cmd.len {
+        while (ptr::read_volatile(regs.add(SR)) >> SR_FLEVEL_SHIFT) & 0x3f >= FIFO_SIZE {}
+        ptr::write_volatile(regs.add(DR) as *mut u8, *cmd.data.add(i));
+        i += 1;
+    }
+
+    while ptr::read_volatile(regs.add(SR)) & (SR_TCF | SR_TEF) == 0 {}
+    let ok = ptr::read_volatile(regs.add(SR)) & SR_TEF == 0;
+    ptr::write_volatile(regs.add(FCR), SR_TCF | SR_TEF);
+
+    // Poll the status register until the write completes. A command with no address starts when
+    // CCR is written.
+    if ok {
+        loop {
+            ptr::write_volatile(regs.add(DLR), 0);
+            ptr::write_volatile(
+                regs.add(CCR),
+                cmd.read_status as u32
+                    | IMODE_SINGLE
+                    | (SINGLE << DMODE_SHIFT)
+                    | FMODE_INDIRECT_READ,
+            );
+            while ptr::read_volatile(regs.add(SR)) & SR_TCF == 0 {}
+            let status = ptr::read_volatile(regs.add(DR) as *const u8);
+            ptr::write_volatile(regs.add(FCR), SR_TCF);
+
+            if status & STATUS_WIP == 0 {
+                break;
+            }
+        }
+    }
+
+    while ptr::read_volatile(regs.add(SR)) & SR_BUSY != 0 {}
+
+    // Re-enter memory-mapped mode.
+    ptr::write_volatile(regs.add(CCR), cmd.xip_ccr);
+
+    #[cfg(target_arch = "arm")]
+    if primask & 1 == 0 {
+        asm!("cpsie i", options(nostack, preserves_flags));
+    }
+
+    ok
+}
@@ -0,0 +1,257 @@
+//! Report why the MCU last reset, eg for field diagnostics: Decodes the RCC reset flags (`RCC_CSR`;
+//! `RCC_RSR` on H7), and clears them, so the next reset reports only its own cause. Also stores an
+//! application-defined breadcrumb in a backup register, eg from a panic handler, that survives the
+//! reset, so the next boot can report what happened before it.
+//!
+//! Example:
+//! ```rust
+//! static BREADCRUMB: Breadcrumb = Breadcrumb::new(10);
+//!
+//! // At boot, after setting up the RTC (Which enables backup domain access):
+//! let cause = reset_cause::take();
+//! if let Some(code) = BREADCRUMB.take() {
+//!     defmt::println!("Reset: {}, after panic {}", cause, code);
+//! }
+//!
+//! #[panic_handler]
+//! fn panic(_info: &PanicInfo) -> ! {
+//!     BREADCRUMB.set(PANIC_CODE_SENSOR);
+//!     cortex_m::peripheral::SCB::sys_reset();
+//! }
+//! ```
+
+use core::ptr::{read_volatile, write_volatile};
+
+use cfg_if::cfg_if;
+
+use crate::pac::RCC;
+#[cfg(not(any(
+    feature = "l412",
+    feature = "g050",
+    feature = "g051",
+    feature = "g061",
+    feature = "g0b0"
+)))]
+use crate::rtc::{backup_read, backup_reg_count, backup_write};
+
+// Register offset, and bit positions. We use these since PAC field names vary between families;
+// eg the brownout flag is `BORRSTF`, `PORRSTF`, or `PWRRSTF`.
+cfg_if! {
+    if #[cfg(feature = "h7")] {
+        const CSR: usize = 0xd0; // RCC_RSR
+        const RMVF: u8 = 16;
+        const BORRSTF: u8 = 21;
+        const PINRSTF: u8 = 22;
+        const PORRSTF: Option<u8> = Some(23);
+        const OBLRSTF: Option<u8> = None;
+    } else if #[cfg(feature = "f3")] {
+        const CSR: usize = 0x24;
+        const RMVF: u8 = 24;
+        // F3 doesn't have a separate brownout flag; POR/PDR sets `PORRSTF`.
+        const BORRSTF: u8 = 27;
+        const PINRSTF: u8 = 26;
+        const PORRSTF: Option<u8> = None;
+        const OBLRSTF: Option<u8> = Some(25);
+    } else if #[cfg(feature = "f4")] {
+        const CSR: usize = 0x74;
+        const RMVF: u8 = 24;
+        const BORRSTF: u8 = 25;
+        const PINRSTF: u8 = 26;
+        const PORRSTF: Option<u8> = Some(27);
+        const OBLRSTF: Option<u8> = None;
+    } else {
+        #[cfg(feature = "g0")]
+        const CSR: usize = 0x60;
+        #[cfg(not(feature = "g0"))]
+        const CSR: usize = 0x94;
+        const RMVF: u8 = 23;
+        const BORRSTF: u8 = 27;
+        const PINRSTF: u8 = 26;
+        const PORRSTF: Option<u8> = None;
+        const OBLRSTF: Option<u8> = Some(25);
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "h747cm4")] {
+        // CPU2 flags.
+        const SFTRSTF: u8 = 25;
+        const IWDGRSTF: u8 = 27;
+        const WWDGRSTF: u8 = 29;
+        const LPWRRSTF: u8 = 31;
+    } else if #[cfg(feature = "h7")] {
+        const SFTRSTF: u8 = 24;
+        const IWDGRSTF: u8 = 26;
+        const WWDGRSTF: u8 = 28;
+        const LPWRRSTF: u8 = 30;
+    } else {
+        const SFTRSTF: u8 = 28;
+        const IWDGRSTF: u8 = 29;
+        const WWDGRSTF: u8 = 30;
+        const LPWRRSTF: u8 = 31;
+    }
+}
+
+/// Stored in the upper 16 bits of the breadcrumb register, to identify a breadcrumb set by
+/// `Breadcrumb::set()`, vs left over from power loss of the backup domain.
+#[cfg(not(any(
+    feature = "l412",
+    feature = "g050",
+    feature = "g051",
+    feature = "g061",
+    feature = "g0b0"
+)))]
+const MARKER: u32 = 0xb4ad;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// The cause of the last reset. Internal resets (eg watchdogs) also pull NRST low, setting the pin
+/// reset flag, so this reports the most specific cause set.
+pub enum ResetCause {
+    /// Power-on, or power-down reset. On families without a separate power-on flag (L4, L5, G0,
+    /// G4, WB, WL), this is reported as `Brownout`.
+    PowerOn,
+    /// Brownout reset: VDD dropped below the BOR threshold.
+    Brownout,
+    /// The NRST pin was pulled low externally; eg a reset button, or a debugger.
+    Pin,
+    /// Software reset, eg from `SCB::sys_reset()`.
+    Software,
+    /// The independent watchdog (IWDG) expired.
+    IndependentWatchdog,
+    /// The window watchdog (WWDG) expired, or was refreshed outside its window.
+    WindowWatchdog,
+    /// Low-power reset: Entering Stop, Standby, or Shutdown mode when
+    /// configured by the `nRST_STOP`, `nRST_STDBY`, or `nRST_SHDW` option bits.
+    LowPower,
+    /// Option byte loading, eg after programming option bytes. Not available on F4 or H7.
+    OptionByteLoad,
+    /// No reset flags are set; eg they were already cleared.
+    Unknown,
+}
+
+impl ResetCause {
+    /// Returns true if either watchdog caused the reset.
+    pub fn is_watchdog(&self) -> bool {
+        matches!(self, Self::IndependentWatchdog | Self::WindowWatchdog)
+    }
+}
+
+fn csr() -> *mut u32 {
+    unsafe { (RCC::ptr() as *const u8).add(CSR) as *mut u32 }
+}
+
+/// Read the raw reset flags register: `RCC_CSR`, or `RCC_RSR` on H7.
+pub fn flags() -> u32 {
+    unsafe { read_volatile(csr()) }
+}
+
+/// Decode the reset flags into the cause of the last reset. This doesn't clear the flags.
+pub fn read() -> ResetCause {
+    let flags = flags();
+    let set = |bit: u8| flags & (1 << bit) != 0;
+
+    // Checked from most, to least specific.
+    if set(LPWRRSTF) {
+        ResetCause::LowPower
+    } else if set(WWDGRSTF) {
+        ResetCause::WindowWatchdog
+    } else if set(IWDGRSTF) {
+        ResetCause::IndependentWatchdog
+    } else if set(SFTRSTF) {
+        ResetCause::Software
+    } else if PORRSTF.is_some_and(set) {
+        ResetCause::PowerOn
+    } else if set(BORRSTF) {
+        // On F3, this bit is the POR flag.
+        if cfg!(feature = "f3") {
+            ResetCause::PowerOn
+        } else {
+            ResetCause::Brownout
+        }
+    } else if OBLRSTF.is_some_and(set) {
+        ResetCause::OptionByteLoad
+    } else if set(PINRSTF) {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    }
+}
+
+/// Clear the reset flags, by setting the `RMVF` bit. Otherwise, they accumulate across resets
+/// that don't clear the RCC, eg watchdog and software resets.
+pub fn clear() {
+    unsafe {
+        let val = read_volatile(csr());
+        write_volatile(csr(), val | (1 << RMVF));
+    }
+}
+
+/// Decode the cause of the last reset, and clear the flags. Run this once at boot.
+pub fn take() -> ResetCause {
+    let result = read();
+    clear();
+    result
+}
+
+/// An application-defined 16-bit code, stored in a backup register, that survives resets; eg
+/// set in a panic handler, then reported after the next boot. This struct doesn't hold state,
+/// so it may be placed in a `static`. The backup domain must be writable; this is handled by
+/// `Rtc::new()`.
+#[cfg(not(any(
+    feature = "l412",
+    feature = "g050",
+    feature = "g051",
+    feature = "g061",
+    feature = "g0b0"
+)))]
+pub struct Breadcrumb {
+    /// The backup register used.
+    reg: usize,
+}
+
+#[cfg(not(any(
+    feature = "l412",
+    feature = "g050",
+    feature = "g051",
+    feature = "g061",
+    feature = "g0b0"
+)))]
+impl Breadcrumb {
+    /// Create a breadcrumb using backup register `reg`. Don't overlap it with registers used by
+    /// other purposes, eg an `EventLog`.
+    pub const fn new(reg: usize) -> Self {
+        Self { reg }
+    }
+
+    /// Record a code. This only writes a backup register, so it's safe to run in a panic
+    /// handler, or hard fault handler.
+    pub fn set(&self, code: u16) {
+        if self.reg < backup_reg_count() {
+            backup_write(self.reg, (MARKER << 16) | code as u32);
+        }
+    }
+
+    /// Read the code recorded before the last reset, if one was, without clearing it.
+    pub fn get(&self) -> Option<u16> {
+        if self.reg >= backup_reg_count() {
+            return None;
+        }
+
+        let val = backup_read(self.reg);
+        if val >> 16 == MARKER {
+            Some(val as u16)
+        } else {
+            None
+        }
+    }
+
+    /// Read and clear the code recorded before the last reset, if one was.
+    pub fn take(&self) -> Option<u16> {
+        let result = self.get();
+        if result.is_some() {
+            backup_write(self.reg, 0);
+        }
+        result
+    }
+}
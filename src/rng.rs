@@ -3,14 +3,40 @@
 //! peripheral clock, and provides some methods.
 //! Once this struct is constructed, the freestanding functions `read()`, and `reading_ready()` may be
 //! used to get a random number number, and check if a new one is available.
+//!
+//! `next_u32()` blocks until a number is ready, and handles seed and clock errors. With the
+//! `rand_core` feature, `Rng` implements `RngCore` and `CryptoRng`, eg for use with TLS, or to
+//! generate nonces.
 
 use cfg_if::cfg_if;
 
+#[cfg(not(any(feature = "g0", feature = "wl")))]
+use crate::clocks::Clocks;
 use crate::{
     pac::{RCC, RNG},
     util::rcc_en_reset,
+    MAX_ITERS,
 };
 
+#[cfg(not(any(feature = "g0", feature = "wl")))]
+/// The maximum RNG clock. (48Mhz domain, eg from CLK48 or HSI48)
+const MAX_RNG_CLK: u32 = 48_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u32)]
+/// RNG errors.
+pub enum RngError {
+    /// A seed error, eg from a failed entropy check, that couldn't be recovered from.
+    Seed = 0,
+    /// The RNG clock is too slow, compared to HCLK, or isn't running.
+    Clock = 1,
+    /// The RNG clock source is invalid, or out of range.
+    ClockSource = 2,
+    /// Timed out waiting for a random number, or for recovery to complete.
+    Timeout = 3,
+}
+
 /// Represents a RNG peripheral.
 pub struct Rng {
     pub regs: RNG,
@@ -37,6 +63,110 @@ impl Rng {
         Self { regs }
     }
 
+    #[cfg(not(any(feature = "g0", feature = "wl")))]
+    /// Check that the RNG clock is valid for this clock configuration: it must be running, at most
+    /// 48Mhz, and faster than HCLK / 32, or the clock error check flags an error. This checks CLK48 on
+    /// L4, L5, G4 and WB, and HSI48 on H7.
+    pub fn validate_clock(clock_cfg: &Clocks) -> Result<(), RngError> {
+        cfg_if! {
+            if #[cfg(feature = "h7")] {
+                // The reset RNG clock source is HSI48.
+                if !clock_cfg.hsi48_on {
+                    return Err(RngError::ClockSource);
+                }
+                let rng_clk = 48_000_000;
+            } else {
+                let rng_clk = clock_cfg.usb();
+            }
+        }
+
+        if rng_clk == 0 || rng_clk > MAX_RNG_CLK {
+            return Err(RngError::ClockSource);
+        }
+
+        if rng_clk <= clock_cfg.hclk() / 32 {
+            return Err(RngError::Clock);
+        }
+
+        Ok(())
+    }
+
+    /// Block until a random number is ready, and return it. This recovers from seed errors, and
+    /// returns an error if a clock error persists.
+    pub fn next_u32(&mut self) -> Result<u32, RngError> {
+        let mut i = 0;
+        loop {
+            let sr = self.regs.sr.read();
+
+            if sr.seis().bit_is_set() {
+                self.recover_seed_error()?;
+            } else if sr.ceis().bit_is_set() {
+                // The clock error flag is cleared by software. If the clock is still failing,
+                // the clock configuration needs to be fixed.
+                self.regs.sr.modify(|_, w| w.ceis().clear_bit());
+                if self.regs.sr.read().cecs().bit_is_set() {
+                    return Err(RngError::Clock);
+                }
+            } else if sr.drdy().bit_is_set() {
+                // "When data is not ready (DRDY=”0”) RNG_DR returns zero. It is recommended to always
+                // verify that RNG_DR is different from zero. Because when it is the case a seed error
+                // occurred between RNG_SR polling and RND_DR output reading (rare event)."
+                let val = self.regs.dr.read().bits();
+                if val != 0 {
+                    return Ok(val);
+                }
+            }
+
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(RngError::Timeout);
+            }
+        }
+    }
+
+    /// Recover from a seed error, per the RM. This runs automatically from `next_u32()`.
+    pub fn recover_seed_error(&mut self) -> Result<(), RngError> {
+        cfg_if! {
+            if #[cfg(any(feature = "l5", feature = "wl"))] {
+                // L552 RM, section 32.3.7: Pulse CONDRST to reset the conditioning logic, then wait
+                // for SECS to clear, and clear SEIS.
+                self.regs.cr.modify(|_, w| w.condrst().set_bit());
+                self.regs.cr.modify(|_, w| w.condrst().clear_bit());
+
+                let mut i = 0;
+                while self.regs.cr.read().condrst().bit_is_set() {
+                    i += 1;
+                    if i >= MAX_ITERS {
+                        return Err(RngError::Timeout);
+                    }
+                }
+            } else {
+                // L4 RM, section 24.3.7: Clear SEIS, and read 12 words to clear the pipeline. If SECS
+                // is still set, restart the RNG.
+                self.regs.sr.modify(|_, w| w.seis().clear_bit());
+                for _ in 0..12 {
+                    self.regs.dr.read();
+                }
+
+                if self.regs.sr.read().secs().bit_is_set() {
+                    self.regs.cr.modify(|_, w| w.rngen().clear_bit());
+                    self.regs.cr.modify(|_, w| w.rngen().set_bit());
+                }
+            }
+        }
+
+        let mut i = 0;
+        while self.regs.sr.read().secs().bit_is_set() {
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(RngError::Seed);
+            }
+        }
+        self.regs.sr.modify(|_, w| w.seis().clear_bit());
+
+        Ok(())
+    }
+
     /// Load a random number from the data register
     pub fn read(&mut self) -> i32 {
         // When data is not ready (DRDY=”0”) RNG_DR returns zero.
@@ -74,3 +204,35 @@ pub fn reading_ready() -> bool {
     let regs = unsafe { &(*RNG::ptr()) };
     regs.sr.read().drdy().bit_is_set()
 }
+
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for Rng {
+    /// Panics if the RNG fails; use `try_fill_bytes` to handle errors.
+    fn next_u32(&mut self) -> u32 {
+        Rng::next_u32(self).unwrap()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_u32(self)
+    }
+
+    /// Panics if the RNG fails; use `try_fill_bytes` to handle errors.
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.try_fill_bytes(dest).unwrap()
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        for chunk in dest.chunks_mut(4) {
+            let val = Rng::next_u32(self).map_err(|e| {
+                // Report our error as a custom code.
+                let code = rand_core::Error::CUSTOM_START + e as u32;
+                rand_core::Error::from(core::num::NonZeroU32::new(code).unwrap())
+            })?;
+            chunk.copy_from_slice(&val.to_le_bytes()[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::CryptoRng for Rng {}
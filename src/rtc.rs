@@ -15,6 +15,7 @@ use crate::pac::{EXTI, PWR, RCC, RTC};
 
 /// RTC Clock source.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum RtcClockSource {
     /// 01: LSE oscillator clock used as RTC clock
@@ -26,7 +27,8 @@ pub enum RtcClockSource {
 }
 
 /// RTC error type
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
     /// Invalid input error
     InvalidInputData,
@@ -80,6 +82,7 @@ pub struct Rtc {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Configuration data for the RTC.
 pub struct RtcConfig {
     /// RTC clock source. Defaults to LSI (Low speed internal oscillator)
@@ -112,6 +115,69 @@ impl Default for RtcConfig {
     }
 }
 
+cfg_if! {
+    if #[cfg(feature = "f3")] {
+        /// Nominal LSI frequency, in Hz.
+        pub const LSI_FREQ: u32 = 40_000;
+    } else {
+        /// Nominal LSI frequency, in Hz.
+        pub const LSI_FREQ: u32 = 32_000;
+    }
+}
+
+/// RTC smooth calibration period: 2^20 RTCCLK cycles, ie 32 seconds at 32.768kHz.
+const CAL_PERIOD: f32 = 1_048_576.;
+
+impl RtcConfig {
+    /// Configuration for running from LSI, for boards without an LSE crystal. LSI is much less
+    /// accurate than a crystal; use `Rtc::apply_correction()` to correct it against an external time
+    /// source. The asynchronous prescaler is set low, so `Rtc::set_trim()` can adjust the frequency
+    /// in fine steps, at the cost of slightly higher current draw.
+    pub fn lsi() -> Self {
+        let async_prescaler = 15;
+        Self {
+            clock_source: RtcClockSource::Lsi,
+            async_prescaler,
+            sync_prescaler: (LSI_FREQ / (async_prescaler as u32 + 1) - 1) as u16,
+            bypass_lse_output: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Tracks the RTC clock's actual frequency, using corrections from an external time source, eg GPS or
+/// network time. Used with `Rtc::apply_correction()`. Store `freq` in a backup register (eg
+/// with `f32::to_bits()`) to retain the estimate across resets.
+pub struct DriftEstimate {
+    /// The estimated RTC clock frequency, in Hz.
+    pub freq: f32,
+    /// The weight given to each new measurement, from 0 to 1. Lower values average over more
+    /// corrections, reducing the effect of jitter in the reference. Defaults to 0.5.
+    pub gain: f32,
+    /// The minimum time between corrections, in seconds, for a correction to update the estimate.
+    /// Corrections closer together only step the calendar. Defaults to 600.
+    pub min_interval: u32,
+    /// The reference time of the last correction.
+    last_sync: Option<NaiveDateTime>,
+}
+
+impl DriftEstimate {
+    /// Create an estimate, starting at a nominal, or previously stored frequency.
+    pub fn new(freq: f32) -> Self {
+        Self {
+            freq,
+            gain: 0.5,
+            min_interval: 600,
+            last_sync: None,
+        }
+    }
+
+    /// The estimated error of the RTC clock compared to `nominal_freq`, in parts per million.
+    pub fn error_ppm(&self, nominal_freq: f32) -> f32 {
+        (self.freq / nominal_freq - 1.) * 1_000_000.
+    }
+}
+
 impl Rtc {
     /// Initialize the RTC, including configuration register writes.
     pub fn new(regs: RTC, config: RtcConfig) -> Self {
@@ -413,6 +479,9 @@ impl Rtc {
                 // exti.ftsr1.modify(|_, w| w.ft20().clear_bit());
 
            } else if #[cfg(any(feature = "h747cm4", feature = "h747cm7"))] {
+                #[cfg(feature = "h747cm4")]
+                exti.c2imr1.modify(|_, w| w.mr20().unmasked());
+                #[cfg(feature = "h747cm7")]
                 exti.c1imr1.modify(|_, w| w.mr20().unmasked());
                 exti.rtsr1.modify(|_, w| w.tr20().set_bit());
                 exti.ftsr1.modify(|_, w| w.tr20().clear_bit());
@@ -862,6 +931,198 @@ impl Rtc {
         )
         .unwrap()
     }
+
+    /// Get the current datetime, and the portion of the current second elapsed, from the sub-second
+    /// register.
+    fn get_datetime_subsec(&mut self) -> (NaiveDateTime, f32) {
+        // Reading SSR locks the calendar shadow registers until DR is read, so these are coherent.
+        let ss = self.regs.ssr.read().bits() & 0xffff;
+        let tr = self.regs.tr.read();
+        let dr = self.regs.dr.read();
+
+        let prediv_s = self.regs.prer.read().prediv_s().bits() as u32;
+        // SS counts down from PREDIV_S.
+        let subsec = prediv_s.saturating_sub(ss) as f32 / (prediv_s + 1) as f32;
+
+        let mt: u8 = if dr.mt().bit() { 1 } else { 0 };
+        let datetime = NaiveDate::from_ymd_opt(
+            (bcd2_decode(dr.yt().bits(), dr.yu().bits()) + 2_000) as i32,
+            bcd2_decode(mt, dr.mu().bits()),
+            bcd2_decode(dr.dt().bits(), dr.du().bits()),
+        )
+        .unwrap()
+        .and_hms_opt(
+            bcd2_decode(tr.ht().bits(), tr.hu().bits()),
+            bcd2_decode(tr.mnt().bits(), tr.mnu().bits()),
+            bcd2_decode(tr.st().bits(), tr.su().bits()),
+        )
+        .unwrap();
+
+        (datetime, subsec)
+    }
+
+    /// Trim the RTC for an RTC clock frequency, in Hz, eg as measured by `DriftEstimate`. This sets
+    /// the synchronous prescaler for a coarse adjustment, and smooth calibration (CALR register) for
+    /// the remainder, to about 1ppm. Smooth calibration covers -487 to +488ppm, so the
+    /// asynchronous prescaler must be low enough that one step of the synchronous prescaler is
+    /// within this; `RtcConfig::lsi()` sets this up. Briefly stops the calendar.
+    pub fn set_trim(&mut self, clock_freq: f32) -> Result<(), Error> {
+        let async_div = self.config.async_prescaler as f32 + 1.;
+
+        // Try the synchronous prescaler values on either side of the frequency; use the first whose
+        // remainder is within smooth calibration range.
+        let sync_div_floor = (clock_freq / async_div) as u32;
+        let mut result = None;
+
+        for sync_div in [sync_div_floor, sync_div_floor + 1] {
+            if !(1..=0x8000).contains(&sync_div) {
+                continue;
+            }
+            let coarse = async_div * sync_div as f32;
+            // The relative adjustment required, so the calibrated clock matches the prescalers.
+            let adj = coarse / clock_freq - 1.;
+
+            // See L4 RM, section 38.3.12: RTC smooth digital calibration.
+            // F_cal = F_rtcclk * (1 + (CALP * 512 - CALM) / (2^20 + CALM - CALP * 512))
+            let x = adj * CAL_PERIOD / (1. + adj);
+            // Round to the nearest pulse.
+            let x = if x >= 0. {
+                (x + 0.5) as i32
+            } else {
+                (x - 0.5) as i32
+            };
+
+            let (calp, calm) = if x > 0 { (1, 512 - x) } else { (0, -x) };
+            if (0..=511).contains(&calm) {
+                result = Some((sync_div - 1, calp as u32, calm as u32));
+                break;
+            }
+        }
+
+        let (sync_prescaler, calp, calm) = result.ok_or(Error::InvalidInputData)?;
+        self.config.sync_prescaler = sync_prescaler as u16;
+
+        let async_prescaler = self.config.async_prescaler;
+        self.edit_regs(true, |regs| {
+            regs.prer.modify(|_, w| unsafe {
+                w.prediv_s().bits(sync_prescaler as u16);
+                w.prediv_a().bits(async_prescaler)
+            });
+        });
+
+        self.edit_regs(false, |regs| {
+            // "The software must wait until RECALPF is 0 before writing CALR."
+            cfg_if! {
+                if #[cfg(any(feature = "l5", feature = "g0", feature = "g4", feature = "l412", feature = "wl", feature = "h5"))] {
+                    while regs.icsr.read().recalpf().bit_is_set() {}
+                } else {
+                    while regs.isr.read().recalpf().bit_is_set() {}
+                }
+            }
+            // CALP is bit 15; CALM is bits 8:0. 32-second calibration cycle.
+            regs.calr.write(|w| unsafe { w.bits((calp << 15) | calm) });
+        });
+
+        Ok(())
+    }
+
+    /// Apply a time correction from an external source, eg GPS, or network time. Run this at
+    /// the start of the `reference` second, eg on a GPS PPS edge. This steps the calendar to
+    /// `reference`, and if enough time has passed since the last correction, updates `drift` from
+    /// the error accumulated since, and trims the RTC with it. Returns the RTC's offset from the
+    /// reference before correction, in seconds; positive if the RTC was ahead.
+    pub fn apply_correction(
+        &mut self,
+        reference: &NaiveDateTime,
+        drift: &mut DriftEstimate,
+    ) -> Result<f32, Error> {
+        let (now, subsec) = self.get_datetime_subsec();
+        let offset = (now - *reference).num_seconds() as f32 + subsec;
+
+        let mut update_trim = drift.last_sync.is_none();
+
+        if let Some(last_sync) = drift.last_sync {
+            let interval = (*reference - last_sync).num_seconds();
+            if interval >= drift.min_interval as i64 {
+                // The calendar was exact at the last correction, and the RTC was trimmed for
+                // `drift.freq`; it counted `interval + offset` seconds in `interval` seconds.
+                let measured = drift.freq * (interval as f32 + offset) / interval as f32;
+                drift.freq += drift.gain * (measured - drift.freq);
+                update_trim = true;
+            }
+        }
+
+        if update_trim {
+            self.set_trim(drift.freq)?;
+        }
+        self.set_datetime(reference)?;
+        drift.last_sync = Some(*reference);
+
+        Ok(offset)
+    }
+}
+
+/// Read a backup register. These 32-bit registers are in the backup domain, so they retain their
+/// values in Standby mode, across system resets, and when VDD is off, as long as VBAT is present.
+/// On G0, G4, L5, and WL, these are `TAMP_BKPxR`; on other families, `RTC_BKPxR`.
+#[cfg(not(any(
+    feature = "l412",
+    feature = "g050",
+    feature = "g051",
+    feature = "g061",
+    feature = "g0b0"
+)))]
+pub fn backup_read(index: usize) -> u32 {
+    cfg_if! {
+        if #[cfg(any(feature = "g0", feature = "g4", feature = "l5", feature = "wl"))] {
+            let regs = unsafe { &(*crate::pac::TAMP::ptr()) };
+        } else {
+            let regs = unsafe { &(*RTC::ptr()) };
+        }
+    }
+
+    regs.bkpr[index].read().bits()
+}
+
+/// Write to a backup register. The backup domain must be writable; this is handled by `Rtc::new()`,
+/// which sets the `DBP` bit.
+#[cfg(not(any(
+    feature = "l412",
+    feature = "g050",
+    feature = "g051",
+    feature = "g061",
+    feature = "g0b0"
+)))]
+pub fn backup_write(index: usize, value: u32) {
+    cfg_if! {
+        if #[cfg(any(feature = "g0", feature = "g4", feature = "l5", feature = "wl"))] {
+            let regs = unsafe { &(*crate::pac::TAMP::ptr()) };
+        } else {
+            let regs = unsafe { &(*RTC::ptr()) };
+        }
+    }
+
+    regs.bkpr[index].write(|w| unsafe { w.bits(value) });
+}
+
+/// The number of backup registers available.
+#[cfg(not(any(
+    feature = "l412",
+    feature = "g050",
+    feature = "g051",
+    feature = "g061",
+    feature = "g0b0"
+)))]
+pub fn backup_reg_count() -> usize {
+    cfg_if! {
+        if #[cfg(any(feature = "g0", feature = "g4", feature = "l5", feature = "wl"))] {
+            let regs = unsafe { &(*crate::pac::TAMP::ptr()) };
+        } else {
+            let regs = unsafe { &(*RTC::ptr()) };
+        }
+    }
+
+    regs.bkpr.len()
 }
 
 // Two 32-bit registers (RTC_TR and RTC_DR) contain the seconds, minutes, hours (12- or 24-hour format), day (day
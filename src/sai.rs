@@ -1,8 +1,11 @@
 //! Serial audio interface (SAI) support, for digital audio input and output. Used for I2S, PCM/DSP, TDM,
 //! AC'97 etc. See L443 Reference Manual, section 41, or H743 RM, section 51.
+//! Available on L4, L5, G4, H7, and WB.
 
 use core::ops::Deref;
 
+use cfg_if::cfg_if;
+
 #[cfg(any(feature = "f3", feature = "l4"))]
 use crate::dma::DmaInput;
 #[cfg(not(any(feature = "f4", feature = "l552")))]
@@ -26,7 +29,17 @@ use crate::pac::sai1 as sai;
 use crate::pac::sai4 as sai;
 use crate::{clocks::Clocks, pac::RCC, util::RccPeriph};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// SAI errors.
+pub enum SaiError {
+    /// The sample rate is 0, or can't be generated from the kernel clock with the available MCKDIV
+    /// values.
+    SampleRate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select Master or Slave mode. Sets xCR1 register, MODE field.
 /// The audio subblock can be a transmitter or receiver, in master or slave mode. The master
@@ -52,7 +65,8 @@ pub enum SaiMode {
     SlaveReceiver = 0b11,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select Stereo or Mono mode. Sets xCR1 register, MONO field.
 pub enum Mono {
@@ -60,7 +74,8 @@ pub enum Mono {
     Mono = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select if signals generated by SAI change on SCK rising, or falling edge. Sets xCR1 register,
 /// CKSTR field.
@@ -73,7 +88,8 @@ pub enum ClockStrobe {
     TransmitFallingEdge = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Frame synchronization offset.
 /// Depending on the audio protocol targeted in the application, the Frame synchronization
@@ -87,7 +103,8 @@ pub enum FsOffset {
     BeforeFirstBit = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// This bit is set and cleared by software. It is used to configure the level of the start of frame on the FS
 /// signal. It is meaningless and is not used in AC’97 or SPDIF audio block configuration.
@@ -100,7 +117,8 @@ pub enum FsPolarity {
     ActiveHigh = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Set Where the signal is in the frame. Sets FRCR register, FSDEF field.
 pub enum FsSignal {
@@ -112,7 +130,8 @@ pub enum FsSignal {
     FrameAndChannel = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Set which bit is transmitted first: Least significant, or Most significant. You may have to
 /// choose the one used by your SAI device. Sets xCR1 register, LSBFIRST field.
@@ -123,7 +142,8 @@ pub enum FirstBit {
     LsbFirst = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select the number of connected PDM mics. It is possible to select
 /// between 2,4,6 or 8 microphones. For example, if the application is using 3 microphones, the
@@ -140,7 +160,8 @@ pub enum NumPdmMics {
     N7 = 0b11,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// FIFO threshold. Affects xCR2 reg, FTH field. Affects when SAI interrupts, and
 /// DMA transfers occur.
@@ -159,7 +180,8 @@ pub enum FifoThresh {
     Full = 0b101,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Oversampling ratio for master clock. You may have to
 /// choose the one used by your SAI device. Sets xCR1 register, OSR field.
@@ -170,7 +192,8 @@ pub enum OversamplingRatio {
     FMul512 = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Specify wheather sub-clocks A and B are synchronized. Sets xCR1 register, SYNCEN field.
 pub enum SyncMode {
@@ -185,7 +208,8 @@ pub enum SyncMode {
 }
 
 #[cfg(not(feature = "l4"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Synchronization outputs. Sets xGCR register, SYNCOUT field. Must be set when SAI is diabled.
 /// Not block specific.
@@ -200,7 +224,8 @@ pub enum SyncOut {
 }
 
 #[cfg(not(feature = "l4"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Synchronization inputs. Sets xGCR register, SYNCIn field. Must be set when SAI is diabled.
 /// Not block specific. Syncs this SAI peripheral with the one specified here, if `sync_mode` is
@@ -217,7 +242,8 @@ pub enum SyncIn {
     Sai4 = 3,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select the audio protocol to use. xCR1 register, PRTCFG field.
 pub enum Protocol {
@@ -231,7 +257,8 @@ pub enum Protocol {
     Ac97 = 0b10,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select the data size to use. xCR1 register, DS field.
 pub enum DataSize {
@@ -249,7 +276,8 @@ pub enum DataSize {
     S32 = 0b111,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select the slot size to use. xSLOTR register, SLOTSZ field.
 pub enum SlotSize {
@@ -261,7 +289,8 @@ pub enum SlotSize {
     S32 = 0b10,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Select wheather the master clock is generated. xDR1 register, NOMCK field on H7.
 /// on other variants such as WB, affects the MCKEN and NODIV fields (?).
@@ -275,7 +304,19 @@ pub enum MasterClock {
     NotUsed = 1,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// The value sent by a transmitter while muted. Sets xCR2 register, MUTEVAL field.
+pub enum MuteValue {
+    /// Bit value 0 is sent during the mute mode.
+    Zero = 0,
+    /// The last values are sent during the mute mode. Only valid when 2 or fewer slots are used.
+    LastValue = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// The type of SAI interrupt to configure. Reference Section 41.5 of the L4 RM.
 /// Enabled in xIM register, yIE fields. See H743 RM, section 51.5: SAI interrupts.
 pub enum SaiInterrupt {
@@ -329,7 +370,8 @@ pub enum SaiChannel {
 /// Configuration for the SAI peripheral. Mainly affects the ACR and BCR registers.
 /// Used for either channel. For details, see documentation of individual structs and fields.
 /// You may be forced into certain settings based on the device used.
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct SaiConfig {
     pub mode: SaiMode,
     /// Select protocols between Free, Ac'97, and SPDIF. Defaults to Free.
@@ -395,7 +437,13 @@ pub struct SaiConfig {
     /// Which PDM CK line to enable. Must be 1-4. Defaults to 1. (CK1 in User manuals)
     pub pdm_clock_used: u8,
     /// Master clock divider. Divides the kernel clock input. Defaults to 0, for no division.
+    /// You can set this from a sample rate using `set_sample_rate()`.
     pub mckdiv: u8,
+    /// Receiver only: The number of consecutive mute frames (All declared slots containing 0) received
+    /// before the `MuteDet` flag is set. 6-bit value. Sets xCR2 register, MUTECNT field. Defaults to 0.
+    pub mute_counter: u8,
+    /// Transmitter only: The value sent in each slot while muted. Defaults to sending 0.
+    pub mute_value: MuteValue,
 }
 
 impl Default for SaiConfig {
@@ -427,6 +475,8 @@ impl Default for SaiConfig {
             num_pdm_mics: NumPdmMics::N2,
             pdm_clock_used: 1,
             mckdiv: 0,
+            mute_counter: 0,
+            mute_value: MuteValue::Zero,
         }
     }
 }
@@ -568,6 +618,57 @@ impl SaiConfig {
             ..Default::default()
         }
     }
+
+    /// Set `mckdiv` to generate a sample (frame) rate, in master mode. `sai_ker_ck` is the SAI kernel
+    /// clock frequency, in Hz; eg from `Clocks::sai1_speed()`. Set `master_clock`, `oversampling_ratio`
+    /// and `frame_length` before running this. Returns the actual sample rate, which may differ from
+    /// the requested one if the kernel clock isn't an integer multiple of it. Returns
+    /// `SaiError::SampleRate` if the rate is 0, or out of the divider's range; `mckdiv` is unchanged
+    /// in this case. See H743 RM, section 51.4.8: Clock generator, or L4 RM, section 41.4.7.
+    pub fn set_sample_rate(&mut self, sai_ker_ck: u32, sample_rate: u32) -> Result<u32, SaiError> {
+        cfg_if! {
+            if #[cfg(feature = "l4")] {
+                // F_FS = F_sai_ker_ck / (MCKDIV * 2 * 256), or F_sai_ker_ck / 256 with MCKDIV = 0.
+                let clocks_per_frame = 256;
+            } else {
+                // With MCLK: F_FS = F_sai_ker_ck / (MCKDIV * (OSR + 1) * 256)
+                // Without MCLK (NOMCK = 1): F_FS = F_sai_ker_ck / ((FRL + 1) * MCKDIV)
+                // MCKDIV = 0 divides by 1.
+                let clocks_per_frame = match self.master_clock {
+                    MasterClock::Used => 256 * (self.oversampling_ratio as u32 + 1),
+                    MasterClock::NotUsed => self.frame_length as u32,
+                };
+            }
+        }
+
+        let target = match sample_rate.checked_mul(clocks_per_frame) {
+            Some(t) if t > 0 => t,
+            _ => return Err(SaiError::SampleRate),
+        };
+        // The total division required, rounded to the nearest integer.
+        let div = ((sai_ker_ck as u64 + target as u64 / 2) / target as u64) as u32;
+
+        cfg_if! {
+            if #[cfg(feature = "l4")] {
+                if div == 0 || div / 2 > 0b1111 {
+                    return Err(SaiError::SampleRate);
+                }
+                if div < 2 {
+                    self.mckdiv = 0;
+                    return Ok(sai_ker_ck / clocks_per_frame);
+                }
+                let mckdiv = div / 2;
+                self.mckdiv = mckdiv as u8;
+                Ok(sai_ker_ck / (clocks_per_frame * mckdiv * 2))
+            } else {
+                if div == 0 || div > 0b11_1111 {
+                    return Err(SaiError::SampleRate);
+                }
+                self.mckdiv = div as u8;
+                Ok(sai_ker_ck / (clocks_per_frame * div))
+            }
+        }
+    }
 }
 
 /// Represents the Serial Audio Interface (SAI) peripheral, used for digital audio
@@ -590,19 +691,16 @@ where
 
         // todo: Do we always want to configure and enable both A and B?
 
-        // Set the master clock divider.
-
-        // See H7 RM, Table 421.
-
-        // mckdiv = SAI clock / (sampling freq * 256) ?? (512 for oversampling?)
+        // Set the master clock divider. See H7 RM, Table 421, or `SaiConfig::set_sample_rate()`.
 
-        // with NOMCK = 1: (No master clock)
-        // F_SCK = F_sai_ker_ck / MCKDIV
-        // F_FS = F_sai_ker_ck / ((FRL + 1) * MCKDIV)
+        // 4-bit fields on L4; 6-bit on others.
+        #[cfg(feature = "l4")]
+        let mckdiv_max = 0b1111;
+        #[cfg(not(feature = "l4"))]
+        let mckdiv_max = 0b11_1111;
 
-        // 6-bit fields.
-        assert!(config_a.mckdiv <= 0b111111);
-        assert!(config_b.mckdiv <= 0b111111);
+        assert!(config_a.mckdiv <= mckdiv_max);
+        assert!(config_b.mckdiv <= mckdiv_max);
 
         // For info on modes, reference H743 RM, section 51.4.3: "Configuring and
         // Enabling SAI modes".
@@ -680,17 +778,17 @@ where
             #[cfg(not(feature = "l4"))]
             w.osr().bit(config_b.oversampling_ratio as u8 != 0);
             w.lsbfirst().bit(config_b.first_bit as u8 != 0);
-            w.mckdiv().bits(config_a.mckdiv)
+            w.mckdiv().bits(config_b.mckdiv)
         });
 
-        // todo: Add this to config and don't hard-set.
+        assert!(config_a.mute_counter <= 0b11_1111);
+        assert!(config_b.mute_counter <= 0b11_1111);
+
         regs.cha().cr2.modify(|_, w| unsafe {
             w.comp().bits(0);
             w.cpl().clear_bit();
-            #[cfg(feature = "wb")]
-            w.mutecnt().bits(0); // rec only
-            #[cfg(not(feature = "wb"))]
-            w.muteval().clear_bit(); // xmitter only
+            w.mutecnt().bits(config_a.mute_counter); // rec only
+            w.muteval().bit(config_a.mute_value as u8 != 0); // xmitter only
             w.mute().clear_bit(); // xmitter only
             w.tris().clear_bit(); // xmitter only
                                   // The FIFO pointers can be reinitialized when the SAI is disabled by setting bit FFLUSH in the
@@ -704,10 +802,8 @@ where
         regs.chb().cr2.modify(|_, w| unsafe {
             w.comp().bits(0);
             w.cpl().clear_bit();
-            #[cfg(feature = "wb")]
-            w.mutecnt().bits(0); // rec only
-            #[cfg(not(feature = "wb"))]
-            w.muteval().clear_bit(); // xmitter only
+            w.mutecnt().bits(config_b.mute_counter); // rec only
+            w.muteval().bit(config_b.mute_value as u8 != 0); // xmitter only
             w.mute().clear_bit(); // xmitter only
             w.tris().clear_bit(); // xmitter only
             w.fflush().set_bit();
@@ -743,12 +839,10 @@ where
             (config_a.frame_length / 2) as u8 - 1
         };
 
-        let fsall_bits_b = if let FsSignal::Frame = config_a.fs_signal {
+        let fsall_bits_b = if let FsSignal::Frame = config_b.fs_signal {
             0
         } else {
-            // Hard-set a 50% duty cycle. Don't think this is a safe assumption? Send in an issue
-            // or PR.
-            (config_a.frame_length / 2) as u8 - 1
+            (config_b.frame_length / 2) as u8 - 1
         };
 
         // The audio frame length can be configured to up to 256 bit clock cycles, by setting
@@ -762,7 +856,7 @@ where
         });
 
         regs.chb().frcr.modify(|_, w| unsafe {
-            w.fsoff().bit(config_a.fs_offset as u8 != 0);
+            w.fsoff().bit(config_b.fs_offset as u8 != 0);
             w.fspol().bit(config_b.fs_polarity as u8 != 0);
             w.fsdef().bit(config_b.fs_signal as u8 != 0);
             w.fsall().bits(fsall_bits_b);
@@ -905,6 +999,24 @@ It can generate an interrupt if WCKCFGIE bit is set in SAI_xIM register");
         }
     }
 
+    /// Mute or unmute a transmitting audio subblock. While muted, the value set by the `mute_value`
+    /// config field is sent in each slot. Takes effect at the end of the current frame.
+    pub fn set_mute(&mut self, channel: SaiChannel, mute: bool) {
+        match channel {
+            SaiChannel::A => self.regs.cha().cr2.modify(|_, w| w.mute().bit(mute)),
+            SaiChannel::B => self.regs.chb().cr2.modify(|_, w| w.mute().bit(mute)),
+        }
+    }
+
+    /// Returns true if a receiving audio subblock has detected `mute_counter` consecutive mute frames.
+    /// Clear this flag with `clear_interrupt(SaiInterrupt::MuteDet, channel)`.
+    pub fn mute_detected(&self, channel: SaiChannel) -> bool {
+        match channel {
+            SaiChannel::A => self.regs.cha().sr.read().mutedet().bit_is_set(),
+            SaiChannel::B => self.regs.chb().sr.read().mutedet().bit_is_set(),
+        }
+    }
+
     /// Read a word of data.
     pub fn read(&self, channel: SaiChannel) -> i32 {
         match channel {
@@ -0,0 +1,244 @@
+//! A streaming reader for files on SD cards (or other block devices), that prefetches blocks into
+//! a ring buffer ahead of consumption. Useful for audio playback, or staging firmware images, where
+//! the card's occasional latency spikes (eg during internal garbage collection, which may take
+//! 100s of ms) would otherwise stall the consumer.
+//!
+//! The reader is independent of the filesystem: It's given the file's length, and an iterator over
+//! the device block addresses containing the file, in order. Eg from a FAT cluster chain, or a
+//! contiguous range for a pre-allocated file. Block reads are started on the device using the
+//! `BlockRead` trait, and run in the background, eg using DMA.
+//!
+//! Call `poll()` regularly (eg from your main loop, or the SD or DMA transfer complete interrupt) to
+//! keep the ring full. Consume data with `read()`. Underruns, and the lowest buffer level reached,
+//! are tracked in `stats()`; use these to size the ring buffer.
+//!
+//! Example:
+//! ```rust
+//! static mut BUFS: [[u8; BLOCK_SIZE]; 16] = [[0; BLOCK_SIZE]; 16];
+//!
+//! let blocks = first_block..first_block + file_len.div_ceil(BLOCK_SIZE as u32);
+//! let mut stream = StreamReader::new(sd, blocks, unsafe { &mut BUFS }, file_len);
+//! stream.prefill()?;
+//!
+//! // In the audio DMA half-transfer interrupt:
+//! stream.read(&mut audio_buf[..half])?;
+//!
+//! // In the main loop:
+//! stream.poll()?;
+//! ```
+
+/// Block size, in bytes. SD cards always use 512-byte blocks for SDHC and SDXC.
+pub const BLOCK_SIZE: usize = 512;
+
+/// A block device that can read blocks without blocking the CPU, eg SDMMC or SPI with DMA.
+pub trait BlockRead {
+    type Error;
+
+    /// Start reading a single block into `buf`. This should return immediately, with the transfer
+    /// running in the background.
+    ///
+    /// # Safety
+    /// `buf` points to `BLOCK_SIZE` bytes, which remain valid until `read_complete()` returns
+    /// `Ok(true)`, or an error.
+    unsafe fn start_read(&mut self, block: u32, buf: *mut u8) -> Result<(), Self::Error>;
+
+    /// Returns `Ok(true)` if the read started with `start_read()` has completed.
+    fn read_complete(&mut self) -> Result<bool, Self::Error>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Errors associated with streaming reads.
+pub enum StreamError<E> {
+    /// An error reported by the block device.
+    Device(E),
+    /// The block iterator ended before the file length was reached.
+    MissingBlocks,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+/// Buffering statistics. These let you check if the ring buffer is large enough for your
+/// card, and consumption rate.
+pub struct StreamStats {
+    /// Number of `read()` calls that returned less data than requested, before the end of the file.
+    pub underruns: u32,
+    /// Total number of bytes that were requested, but not available, due to underruns.
+    pub underrun_bytes: u32,
+    /// Number of blocks read from the device.
+    pub blocks_read: u32,
+    /// The lowest number of full blocks that were buffered after a `read()` call. A value near 0
+    /// means an underrun nearly occured.
+    pub min_blocks_buffered: usize,
+}
+
+/// A streaming reader, prefetching a file's blocks into a ring of block buffers.
+pub struct StreamReader<'a, D, I> {
+    device: D,
+    /// Device block addresses of the file, in order.
+    blocks: I,
+    bufs: &'a mut [[u8; BLOCK_SIZE]],
+    /// Index of the buffer being consumed.
+    tail: usize,
+    /// Number of buffers that contain data, starting at `tail`.
+    filled: usize,
+    /// True if a read into the buffer following the filled ones is in progress.
+    in_flight: bool,
+    /// Read position in the buffer at `tail`.
+    pos: usize,
+    /// Bytes of the file not yet requested from the device.
+    fetch_remaining: u32,
+    /// Bytes of the file not yet consumed.
+    consume_remaining: u32,
+    stats: StreamStats,
+}
+
+impl<'a, D, I> StreamReader<'a, D, I>
+where
+    D: BlockRead,
+    I: Iterator<Item = u32>,
+{
+    /// Create a reader. `blocks` yields the device block address of each `BLOCK_SIZE` portion of
+    /// the file, in order. `bufs` is the ring buffer; it must contain at least 2 blocks. This doesn't
+    /// start reading; run `prefill()` or `poll()`.
+    pub fn new(device: D, blocks: I, bufs: &'a mut [[u8; BLOCK_SIZE]], file_len: u32) -> Self {
+        assert!(
+            bufs.len() >= 2,
+            "The ring buffer must contain at least 2 blocks."
+        );
+
+        let min_blocks_buffered = bufs.len();
+
+        Self {
+            device,
+            blocks,
+            bufs,
+            tail: 0,
+            filled: 0,
+            in_flight: false,
+            pos: 0,
+            fetch_remaining: file_len,
+            consume_remaining: file_len,
+            stats: StreamStats {
+                min_blocks_buffered,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Complete a pending block read if it's done, and start the next one if there's space
+    /// in the ring. Doesn't block. Run this regularly, or from the device's transfer complete
+    /// interrupt.
+    pub fn poll(&mut self) -> Result<(), StreamError<D::Error>> {
+        if self.in_flight {
+            if !self.device.read_complete().map_err(StreamError::Device)? {
+                return Ok(());
+            }
+            self.in_flight = false;
+            self.filled += 1;
+            self.stats.blocks_read += 1;
+        }
+
+        if self.fetch_remaining == 0 || self.filled == self.bufs.len() {
+            return Ok(());
+        }
+
+        let block = self.blocks.next().ok_or(StreamError::MissingBlocks)?;
+        let i = (self.tail + self.filled) % self.bufs.len();
+
+        unsafe {
+            self.device
+                .start_read(block, self.bufs[i].as_mut_ptr())
+                .map_err(StreamError::Device)?;
+        }
+        self.in_flight = true;
+        self.fetch_remaining = self.fetch_remaining.saturating_sub(BLOCK_SIZE as u32);
+
+        Ok(())
+    }
+
+    /// Block until the ring buffer is full, or the whole file is buffered. Run this before
+    /// starting playback.
+    pub fn prefill(&mut self) -> Result<(), StreamError<D::Error>> {
+        while self.in_flight || (self.fetch_remaining > 0 && self.filled < self.bufs.len()) {
+            self.poll()?;
+        }
+        Ok(())
+    }
+
+    /// Copy buffered data into `buf`, returning the number of bytes copied. This doesn't wait for
+    /// the device; if less data is buffered than requested, before the end of the file, an underrun
+    /// is recorded. Returns 0 at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, StreamError<D::Error>> {
+        let mut copied = 0;
+
+        while copied < buf.len() && self.filled > 0 && self.consume_remaining > 0 {
+            let block_avail = (BLOCK_SIZE - self.pos).min(self.consume_remaining as usize);
+            let len = block_avail.min(buf.len() - copied);
+
+            buf[copied..copied + len]
+                .copy_from_slice(&self.bufs[self.tail][self.pos..self.pos + len]);
+
+            copied += len;
+            self.pos += len;
+            self.consume_remaining -= len as u32;
+
+            if self.pos == BLOCK_SIZE || self.consume_remaining == 0 {
+                self.tail = (self.tail + 1) % self.bufs.len();
+                self.filled -= 1;
+                self.pos = 0;
+            }
+        }
+
+        let requested = buf.len().min(self.consume_remaining as usize + copied);
+        if copied < requested {
+            self.stats.underruns += 1;
+            self.stats.underrun_bytes += (requested - copied) as u32;
+        }
+
+        if self.consume_remaining > 0 {
+            self.stats.min_blocks_buffered = self.stats.min_blocks_buffered.min(self.filled);
+        }
+
+        // Keep the device busy, if a buffer was freed.
+        self.poll()?;
+
+        Ok(copied)
+    }
+
+    /// The number of bytes buffered and available to read.
+    pub fn available(&self) -> usize {
+        if self.filled == 0 {
+            return 0;
+        }
+        let buffered = self.filled * BLOCK_SIZE - self.pos;
+        buffered.min(self.consume_remaining as usize)
+    }
+
+    /// Returns true once the whole file has been consumed.
+    pub fn is_eof(&self) -> bool {
+        self.consume_remaining == 0
+    }
+
+    /// Buffering statistics, since creation, or the last `reset_stats()`.
+    pub fn stats(&self) -> StreamStats {
+        self.stats
+    }
+
+    /// Reset buffering statistics, eg after a seek, or the start of playback.
+    pub fn reset_stats(&mut self) {
+        self.stats = StreamStats {
+            min_blocks_buffered: self.bufs.len(),
+            ..Default::default()
+        };
+    }
+
+    /// Wait for any pending read to complete, and return the block device.
+    pub fn free(mut self) -> Result<D, StreamError<D::Error>> {
+        while self.in_flight {
+            if self.device.read_complete().map_err(StreamError::Device)? {
+                self.in_flight = false;
+            }
+        }
+        Ok(self.device)
+    }
+}
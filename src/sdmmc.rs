@@ -0,0 +1,859 @@
+//! Support for SD cards, using the SDMMC peripheral (SDIO on F4). Includes card initialization,
+//! 1 and 4-bit bus widths, and block reads and writes using the FIFO. On L5 and H7, blocks can also
+//! be transferred using the peripheral's internal DMA (IDMA). Implements the `embedded-sdmmc` crate's
+//! `BlockDevice` trait with the `embedded_sdmmc` feature, for FAT filesystem support.
+//!
+//! Set up the CLK, CMD, and D0 (and D1 - D3 for a 4-bit bus) pins in alternate function mode; AF12 on
+//! most variants. CMD and D0 - D3 need pull-ups, either internal, or on the board.
+//!
+//! Example:
+//! ```rust
+//! // The kernel clock is set in RCC; it's 48Mhz on F4 and L4, or eg PLL1Q on H7.
+//! let mut sd = Sdmmc::new(dp.SDMMC1, 48_000_000, Default::default());
+//! sd.init_card()?;
+//!
+//! let mut buf = [0; 512];
+//! sd.read_blocks(0, &mut buf)?;
+//!
+//! // Using with `embedded-sdmmc`:
+//! let mut volume_mgr = embedded_sdmmc::VolumeManager::new(sd, time_source);
+//! ```
+
+use core::ops::Deref;
+
+use cortex_m::asm;
+
+#[cfg(any(feature = "l5", feature = "h7"))]
+use crate::sd_stream::BlockRead;
+use crate::{pac::RCC, util::RccPeriph, MAX_ITERS};
+
+/// Block size, in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+// Register offsets. These are the same on SDMMC version 1 (F4 SDIO and L4), and version 2
+// (L5 and H7), although some fields differ. We access registers directly, since their names vary
+// between PACs.
+const POWER: usize = 0x00;
+const CLKCR: usize = 0x04;
+const ARG: usize = 0x08;
+const CMD: usize = 0x0c;
+const RESP1: usize = 0x14;
+const RESP2: usize = 0x18;
+const RESP3: usize = 0x1c;
+const RESP4: usize = 0x20;
+const DTIMER: usize = 0x24;
+const DLEN: usize = 0x28;
+const DCTRL: usize = 0x2c;
+const STA: usize = 0x34;
+const ICR: usize = 0x38;
+const FIFO: usize = 0x80;
+
+// Status flags, common to both versions.
+const STA_CCRCFAIL: u32 = 1 << 0;
+const STA_DCRCFAIL: u32 = 1 << 1;
+const STA_CTIMEOUT: u32 = 1 << 2;
+const STA_DTIMEOUT: u32 = 1 << 3;
+const STA_TXUNDERR: u32 = 1 << 4;
+const STA_RXOVERR: u32 = 1 << 5;
+const STA_CMDREND: u32 = 1 << 6;
+const STA_CMDSENT: u32 = 1 << 7;
+const STA_DATAEND: u32 = 1 << 8;
+const STA_TXFIFOHE: u32 = 1 << 14;
+const STA_RXFIFOHF: u32 = 1 << 15;
+const STA_RXFIFOE: u32 = 1 << 19;
+
+/// The number of words read or written each time the FIFO is half full, or half empty.
+const FIFO_HALF_WORDS: usize = 8;
+
+const POWER_ON: u32 = 0b11;
+const DCTRL_DTDIR: u32 = 1 << 1;
+/// DBLOCKSIZE: 2^9 = 512 bytes.
+const DCTRL_BLOCKSIZE_512: u32 = 9 << 4;
+
+cfg_if::cfg_if! {
+    if #[cfg(any(feature = "l5", feature = "h7"))] {
+        // L552 RM, section 36.8; H743 RM, section 55.10: SDMMC registers.
+        const CLKCR_PWRSAV: u32 = 1 << 12;
+        const CLKCR_WIDBUS_SHIFT: u32 = 14;
+        const CLKCR_HWFC_EN: u32 = 1 << 17;
+        const CLKDIV_MAX: u32 = 0x3ff;
+
+        const CMD_CMDTRANS: u32 = 1 << 6;
+        const CMD_CMDSTOP: u32 = 1 << 7;
+        const CMD_WAITRESP_SHIFT: u32 = 8;
+        const CMD_CPSMEN: u32 = 1 << 12;
+
+        /// Static flags: bits 0 - 11, and 21 - 28.
+        const ICR_ALL: u32 = 0x1fe0_0fff;
+        const STA_DABORT: u32 = 1 << 11;
+        const STA_IDMATE: u32 = 1 << 27;
+
+        const IDMACTRL: usize = 0x50;
+        const IDMABASE0: usize = 0x58;
+    } else {
+        // F429 RM, section 31.9: SDIO registers; L4x6 RM, section 45.8: SDMMC registers.
+        const CLKCR_CLKEN: u32 = 1 << 8;
+        const CLKCR_PWRSAV: u32 = 1 << 9;
+        const CLKCR_BYPASS: u32 = 1 << 10;
+        const CLKCR_WIDBUS_SHIFT: u32 = 11;
+        #[cfg(not(feature = "f4"))]
+        const CLKCR_HWFC_EN: u32 = 1 << 14;
+        const CLKDIV_MAX: u32 = 0xff;
+
+        const CMD_WAITRESP_SHIFT: u32 = 6;
+        const CMD_CPSMEN: u32 = 1 << 10;
+
+        /// Static flags: bits 0 - 10, 22, and 23.
+        const ICR_ALL: u32 = 0x00c0_07ff;
+        const DCTRL_DTEN: u32 = 1 << 0;
+    }
+}
+
+// Card status (R1) bits. See the SD Physical Layer Simplified Specification, section 4.10.1.
+/// Out of range, address error, block length error, erase errors, write protect violation, lock
+/// error, CRC error, illegal command, card ECC failed, CC error, general error, CSD overwrite.
+const R1_ERRORS: u32 = 0xfdf9_8008;
+const R1_READY_FOR_DATA: u32 = 1 << 8;
+const R1_STATE_TRAN: u32 = 4;
+
+/// OCR bits, in the ACMD41 argument and response.
+const OCR_VOLTAGE_WINDOW: u32 = 0x0010_0000; // 3.2 - 3.3V
+const OCR_HCS: u32 = 1 << 30;
+const OCR_BUSY: u32 = 1 << 31;
+
+/// The number of ACMD41 attempts during initialization. Each takes about 0.5ms at 400kHz; the
+/// card must be ready within 1 second.
+const ACMD41_TRIES: u32 = 4_000;
+
+/// Implemented for the PAC types of SDMMC (SDIO) peripherals.
+pub trait SdmmcPeriph: Deref + RccPeriph {}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "f4")] {
+        impl SdmmcPeriph for crate::pac::SDIO {}
+    } else if #[cfg(feature = "l4x6")] {
+        impl SdmmcPeriph for crate::pac::SDMMC1 {}
+    } else if #[cfg(feature = "l4")] {
+        impl SdmmcPeriph for crate::pac::SDMMC {}
+    } else if #[cfg(feature = "l5")] {
+        impl SdmmcPeriph for crate::pac::SDMMC1 {}
+    } else {
+        impl SdmmcPeriph for crate::pac::SDMMC1 {}
+        impl SdmmcPeriph for crate::pac::SDMMC2 {}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// SD card errors.
+pub enum SdmmcError {
+    /// The card didn't respond to a command.
+    CommandTimeout,
+    /// A command response failed its CRC check.
+    CommandCrc,
+    /// The card didn't send, or accept data in time.
+    DataTimeout,
+    /// A data block failed its CRC check.
+    DataCrc,
+    /// The FIFO overflowed during a read, or underflowed during a write. This can happen if the
+    /// CPU is interrupted during a FIFO transfer.
+    Fifo,
+    /// The card reported an error. Contains its status.
+    Card(u32),
+    /// The card didn't finish initialization, or doesn't support 3.3V.
+    UnsupportedCard,
+    /// `init_card()` hasn't been run successfully.
+    NoCard,
+    /// The buffer's length isn't a multiple of the block size, or isn't word-aligned for DMA.
+    Buffer,
+    /// A DMA transfer error.
+    Dma,
+    /// Idling in a loop took too long.
+    Hardware,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// Data bus width. Sets the CLKCR register, WIDBUS field.
+pub enum BusWidth {
+    One = 0b00,
+    Four = 0b01,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// SDMMC configuration.
+pub struct SdmmcConfig {
+    /// The data bus width, set after initialization. Defaults to 4 bits.
+    pub bus_width: BusWidth,
+    /// The bus (SDMMC_CK) frequency after initialization, in Hz. Defaults to 25Mhz; the maximum
+    /// for default speed mode. Initialization takes place at 400kHz.
+    pub freq: u32,
+    /// Stop the bus clock when the bus is idle, to save power. Defaults to false.
+    pub power_save: bool,
+}
+
+impl Default for SdmmcConfig {
+    fn default() -> Self {
+        Self {
+            bus_width: BusWidth::Four,
+            freq: 25_000_000,
+            power_save: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Information about an initialized card.
+pub struct CardInfo {
+    /// True for SDHC and SDXC cards, which are addressed by block. SDSC cards are addressed
+    /// by byte.
+    pub high_capacity: bool,
+    /// The Relative Card Address, assigned during initialization.
+    pub rca: u16,
+    /// Capacity, in 512-byte blocks.
+    pub num_blocks: u32,
+    /// The Card Identification register, as sent by the card; most significant word first.
+    pub cid: [u32; 4],
+    /// The Card-Specific Data register; most significant word first.
+    pub csd: [u32; 4],
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Response {
+    None,
+    Short,
+    /// Short, without a valid CRC; ie R3.
+    ShortNoCrc,
+    Long,
+}
+
+/// Represents an SDMMC peripheral, and the card connected to it.
+pub struct Sdmmc<R> {
+    pub regs: R,
+    pub cfg: SdmmcConfig,
+    kernel_clock: u32,
+    /// The current bus frequency, in Hz.
+    bus_freq: u32,
+    card: Option<CardInfo>,
+    #[cfg(any(feature = "l5", feature = "h7"))]
+    /// For a DMA transfer in progress: (multiple blocks, read).
+    dma_transfer: Option<(bool, bool)>,
+}
+
+impl<R> Sdmmc<R>
+where
+    R: SdmmcPeriph,
+{
+    /// Initialize an SDMMC peripheral, including enabling and resetting its RCC peripheral clock.
+    /// `kernel_clock` is the SDMMC kernel clock frequency, in Hz. This powers the bus, at 400kHz.
+    /// Run `init_card()` to initialize the card.
+    pub fn new(regs: R, kernel_clock: u32, cfg: SdmmcConfig) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+        R::en_reset(rcc);
+
+        let mut result = Self {
+            regs,
+            cfg,
+            kernel_clock,
+            bus_freq: 0,
+            card: None,
+            #[cfg(any(feature = "l5", feature = "h7"))]
+            dma_transfer: None,
+        };
+
+        result.set_clock(400_000, BusWidth::One);
+        result.write_reg(POWER, POWER_ON);
+
+        // The card needs at least 74 bus clock cycles after power on, before the first command;
+        // this gives it at least 0.2ms, since the CPU is rarely more than 5x faster than the kernel
+        // clock.
+        asm::delay(kernel_clock / 1_000);
+
+        result
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        unsafe { (&*self.regs as *const _ as *mut u8).add(offset) as *mut u32 }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.reg(offset)) }
+    }
+
+    fn write_reg(&self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(offset), val) }
+    }
+
+    /// Set the bus clock, as close as possible to, without exceeding `freq`.
+    fn set_clock(&mut self, freq: u32, width: BusWidth) {
+        let mut val = (width as u32) << CLKCR_WIDBUS_SHIFT;
+        if self.cfg.power_save {
+            val |= CLKCR_PWRSAV;
+        }
+
+        cfg_if::cfg_if! {
+            if #[cfg(any(feature = "l5", feature = "h7"))] {
+                // SDMMC_CK = kernel / (2 x CLKDIV). 0 bypasses the divider.
+                let div = if freq >= self.kernel_clock {
+                    0
+                } else {
+                    self.kernel_clock.div_ceil(2 * freq).min(CLKDIV_MAX)
+                };
+                self.bus_freq = if div == 0 {
+                    self.kernel_clock
+                } else {
+                    self.kernel_clock / (2 * div)
+                };
+                // Hardware flow control stops the clock when the FIFO is full (or empty, when
+                // writing), so that polled transfers don't overrun.
+                val |= div | CLKCR_HWFC_EN;
+            } else {
+                // SDMMC_CK = kernel / (CLKDIV + 2).
+                if freq >= self.kernel_clock {
+                    self.bus_freq = self.kernel_clock;
+                    val |= CLKCR_BYPASS;
+                } else {
+                    let div = self.kernel_clock.div_ceil(freq).saturating_sub(2).min(CLKDIV_MAX);
+                    self.bus_freq = self.kernel_clock / (div + 2);
+                    val |= div;
+                }
+                val |= CLKCR_CLKEN;
+                // F4 errata: Hardware flow control on the SDIO peripheral doesn't work, and
+                // can cause CRC errors.
+                #[cfg(not(feature = "f4"))]
+                {
+                    val |= CLKCR_HWFC_EN;
+                }
+            }
+        }
+
+        self.write_reg(CLKCR, val);
+    }
+
+    /// Send a command, and wait for its response. Returns the first response word.
+    fn cmd(&self, index: u8, arg: u32, response: Response, flags: u32) -> Result<u32, SdmmcError> {
+        self.write_reg(ICR, ICR_ALL);
+        self.write_reg(ARG, arg);
+
+        let waitresp = match response {
+            Response::None => 0b00,
+            // On version 1, R3 responses use the short response setting, with its CRC failing.
+            #[cfg(not(any(feature = "l5", feature = "h7")))]
+            Response::Short | Response::ShortNoCrc => 0b01,
+            #[cfg(any(feature = "l5", feature = "h7"))]
+            Response::Short => 0b01,
+            #[cfg(any(feature = "l5", feature = "h7"))]
+            Response::ShortNoCrc => 0b10,
+            Response::Long => 0b11,
+        };
+
+        self.write_reg(
+            CMD,
+            index as u32 | (waitresp << CMD_WAITRESP_SHIFT) | CMD_CPSMEN | flags,
+        );
+
+        let mut i = 0;
+        loop {
+            let sta = self.read_reg(STA);
+
+            if response == Response::None {
+                if sta & STA_CMDSENT != 0 {
+                    break;
+                }
+            } else if sta & STA_CTIMEOUT != 0 {
+                return Err(SdmmcError::CommandTimeout);
+            } else if sta & STA_CCRCFAIL != 0 {
+                if response == Response::ShortNoCrc {
+                    break;
+                }
+                return Err(SdmmcError::CommandCrc);
+            } else if sta & STA_CMDREND != 0 {
+                break;
+            }
+
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(SdmmcError::Hardware);
+            }
+        }
+
+        Ok(self.read_reg(RESP1))
+    }
+
+    /// Send a command with an R1 response, and check the card status for errors.
+    fn cmd_r1(&self, index: u8, arg: u32, flags: u32) -> Result<u32, SdmmcError> {
+        let status = self.cmd(index, arg, Response::Short, flags)?;
+        if status & R1_ERRORS != 0 {
+            return Err(SdmmcError::Card(status));
+        }
+        Ok(status)
+    }
+
+    /// Send an application-specific command; ie CMD55, followed by the command.
+    fn acmd(&self, index: u8, arg: u32, response: Response) -> Result<u32, SdmmcError> {
+        let rca = self.card.map(|c| c.rca).unwrap_or(0);
+        self.cmd_r1(55, (rca as u32) << 16, 0)?;
+        self.cmd(index, arg, response, 0)
+    }
+
+    fn read_long_response(&self) -> [u32; 4] {
+        [
+            self.read_reg(RESP1),
+            self.read_reg(RESP2),
+            self.read_reg(RESP3),
+            self.read_reg(RESP4),
+        ]
+    }
+
+    /// Identify and initialize the card, then set the configured bus width and frequency.
+    /// See the SD Physical Layer Simplified Specification, section 4.2: Card Identification Mode.
+    pub fn init_card(&mut self) -> Result<CardInfo, SdmmcError> {
+        self.card = None;
+        self.set_clock(400_000, BusWidth::One);
+
+        // CMD0: GO_IDLE_STATE
+        self.cmd(0, 0, Response::None, 0)?;
+
+        // CMD8: SEND_IF_COND. 2.7 - 3.6V, with a check pattern. Version 1 cards don't respond.
+        let v2 = match self.cmd(8, 0x1aa, Response::Short, 0) {
+            Ok(r) => {
+                if r & 0xfff != 0x1aa {
+                    return Err(SdmmcError::UnsupportedCard);
+                }
+                true
+            }
+            Err(SdmmcError::CommandTimeout) => false,
+            Err(e) => return Err(e),
+        };
+
+        // ACMD41: SD_SEND_OP_COND. Negotiate the voltage, and request high capacity support.
+        let arg = OCR_VOLTAGE_WINDOW | if v2 { OCR_HCS } else { 0 };
+        let mut ocr = 0;
+        for _ in 0..ACMD41_TRIES {
+            ocr = self.acmd(41, arg, Response::ShortNoCrc)?;
+            if ocr & OCR_BUSY != 0 {
+                break;
+            }
+        }
+        if ocr & OCR_BUSY == 0 {
+            return Err(SdmmcError::UnsupportedCard);
+        }
+        let high_capacity = ocr & OCR_HCS != 0;
+
+        // CMD2: ALL_SEND_CID
+        self.cmd(2, 0, Response::Long, 0)?;
+        let cid = self.read_long_response();
+
+        // CMD3: SEND_RELATIVE_ADDR. The R6 response contains the RCA in its upper half.
+        let rca = (self.cmd(3, 0, Response::Short, 0)? >> 16) as u16;
+
+        // CMD9: SEND_CSD
+        self.cmd(9, (rca as u32) << 16, Response::Long, 0)?;
+        let csd = self.read_long_response();
+
+        // CMD7: SELECT_CARD. Moves the card to the transfer state.
+        self.cmd_r1(7, (rca as u32) << 16, 0)?;
+
+        let card = CardInfo {
+            high_capacity,
+            rca,
+            num_blocks: num_blocks(&csd),
+            cid,
+            csd,
+        };
+        self.card = Some(card);
+
+        // CMD16: SET_BLOCKLEN. High capacity cards have a fixed block length of 512.
+        if !high_capacity {
+            self.cmd_r1(16, BLOCK_SIZE as u32, 0)?;
+        }
+
+        if self.cfg.bus_width == BusWidth::Four {
+            // ACMD6: SET_BUS_WIDTH. 0b10 for 4 bits.
+            self.acmd(6, 0b10, Response::Short)?;
+        }
+
+        self.set_clock(self.cfg.freq, self.cfg.bus_width);
+
+        Ok(card)
+    }
+
+    /// Information about the card, if initialized.
+    pub fn card(&self) -> Option<CardInfo> {
+        self.card
+    }
+
+    /// The bus frequency, in Hz. This may be lower than configured, due to the clock divider's
+    /// resolution.
+    pub fn bus_freq(&self) -> u32 {
+        self.bus_freq
+    }
+
+    /// The address argument for a block; SDSC cards are addressed by byte.
+    fn block_addr(&self, block: u32) -> Result<u32, SdmmcError> {
+        match self.card {
+            Some(c) if c.high_capacity => Ok(block),
+            Some(_) => Ok(block * BLOCK_SIZE as u32),
+            None => Err(SdmmcError::NoCard),
+        }
+    }
+
+    /// Set up the data path for a transfer of `len` bytes.
+    fn start_data(&self, len: usize, read: bool) {
+        // The data timeout is in bus clock periods; the maximum write time for SDHC cards is 250ms.
+        self.write_reg(DTIMER, self.bus_freq / 2);
+        self.write_reg(DLEN, len as u32);
+
+        let dir = if read { DCTRL_DTDIR } else { 0 };
+
+        // On version 2, the transfer is started by the command, using CMDTRANS.
+        #[cfg(any(feature = "l5", feature = "h7"))]
+        self.write_reg(DCTRL, dir | DCTRL_BLOCKSIZE_512);
+        #[cfg(not(any(feature = "l5", feature = "h7")))]
+        self.write_reg(DCTRL, dir | DCTRL_BLOCKSIZE_512 | DCTRL_DTEN);
+    }
+
+    /// Check the status register for data errors.
+    fn data_error(sta: u32) -> Option<SdmmcError> {
+        if sta & STA_DCRCFAIL != 0 {
+            Some(SdmmcError::DataCrc)
+        } else if sta & STA_DTIMEOUT != 0 {
+            Some(SdmmcError::DataTimeout)
+        } else if sta & (STA_TXUNDERR | STA_RXOVERR) != 0 {
+            Some(SdmmcError::Fifo)
+        } else {
+            None
+        }
+    }
+
+    /// Read blocks starting at `start_block` into `buf`, using the FIFO. `buf`'s length must be a
+    /// multiple of 512 bytes.
+    pub fn read_blocks(&mut self, start_block: u32, buf: &mut [u8]) -> Result<(), SdmmcError> {
+        self.read_blocks_inner(start_block, buf)
+    }
+
+    fn read_blocks_inner(&self, start_block: u32, buf: &mut [u8]) -> Result<(), SdmmcError> {
+        if buf.is_empty() || !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(SdmmcError::Buffer);
+        }
+        let addr = self.block_addr(start_block)?;
+        let multiple = buf.len() > BLOCK_SIZE;
+
+        self.start_data(buf.len(), true);
+        // CMD18: READ_MULTIPLE_BLOCK, or CMD17: READ_SINGLE_BLOCK.
+        let index = if multiple { 18 } else { 17 };
+        self.cmd_r1(index, addr, data_cmd_flags())?;
+
+        let mut words = buf.chunks_exact_mut(4);
+        let result = loop {
+            let sta = self.read_reg(STA);
+
+            if let Some(e) = Self::data_error(sta) {
+                break Err(e);
+            }
+
+            if sta & STA_RXFIFOHF != 0 {
+                for _ in 0..FIFO_HALF_WORDS {
+                    match words.next() {
+                        Some(w) => w.copy_from_slice(&self.read_reg(FIFO).to_le_bytes()),
+                        None => break,
+                    }
+                }
+            } else if sta & STA_DATAEND != 0 {
+                // Read any remaining words.
+                while self.read_reg(STA) & STA_RXFIFOE == 0 {
+                    match words.next() {
+                        Some(w) => w.copy_from_slice(&self.read_reg(FIFO).to_le_bytes()),
+                        None => break,
+                    }
+                }
+                break Ok(());
+            }
+        };
+
+        self.finish_data(multiple, result)
+    }
+
+    /// Write blocks starting at `start_block` from `buf`, using the FIFO. `buf`'s length must be a
+    /// multiple of 512 bytes. Blocks until the card has finished programming.
+    pub fn write_blocks(&mut self, start_block: u32, buf: &[u8]) -> Result<(), SdmmcError> {
+        self.write_blocks_inner(start_block, buf)
+    }
+
+    fn write_blocks_inner(&self, start_block: u32, buf: &[u8]) -> Result<(), SdmmcError> {
+        if buf.is_empty() || !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(SdmmcError::Buffer);
+        }
+        let addr = self.block_addr(start_block)?;
+        let multiple = buf.len() > BLOCK_SIZE;
+
+        self.start_data(buf.len(), false);
+        // CMD25: WRITE_MULTIPLE_BLOCK, or CMD24: WRITE_BLOCK.
+        let index = if multiple { 25 } else { 24 };
+        self.cmd_r1(index, addr, data_cmd_flags())?;
+
+        let mut words = buf.chunks_exact(4);
+        let result = loop {
+            let sta = self.read_reg(STA);
+
+            if let Some(e) = Self::data_error(sta) {
+                break Err(e);
+            }
+
+            if sta & STA_DATAEND != 0 {
+                break Ok(());
+            }
+
+            if sta & STA_TXFIFOHE != 0 {
+                for _ in 0..FIFO_HALF_WORDS {
+                    match words.next() {
+                        Some(w) => self.write_reg(FIFO, u32::from_le_bytes(w.try_into().unwrap())),
+                        None => break,
+                    }
+                }
+            }
+        };
+
+        self.finish_data(multiple, result)?;
+        self.wait_ready()
+    }
+
+    /// Stop a multiple block transfer if needed, and clear flags.
+    fn finish_data(
+        &self,
+        multiple: bool,
+        result: Result<(), SdmmcError>,
+    ) -> Result<(), SdmmcError> {
+        #[cfg(any(feature = "l5", feature = "h7"))]
+        let stop_flags = CMD_CMDSTOP;
+        #[cfg(not(any(feature = "l5", feature = "h7")))]
+        let stop_flags = 0;
+
+        if multiple {
+            // CMD12: STOP_TRANSMISSION. We send this on error too, to return the card to the
+            // transfer state.
+            let stop = self.cmd_r1(12, 0, stop_flags);
+            result?;
+            stop?;
+        } else {
+            result?;
+        }
+
+        self.write_reg(ICR, ICR_ALL);
+        Ok(())
+    }
+
+    /// Wait for the card to return to the transfer state, and be ready for data; eg after a write.
+    fn wait_ready(&self) -> Result<(), SdmmcError> {
+        let rca = self.card.ok_or(SdmmcError::NoCard)?.rca;
+
+        let mut i = 0;
+        loop {
+            // CMD13: SEND_STATUS
+            let status = self.cmd_r1(13, (rca as u32) << 16, 0)?;
+            if status & R1_READY_FOR_DATA != 0 && (status >> 9) & 0xf == R1_STATE_TRAN {
+                return Ok(());
+            }
+
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(SdmmcError::DataTimeout);
+            }
+        }
+    }
+
+    #[cfg(any(feature = "l5", feature = "h7"))]
+    /// Start reading blocks into `buf` using the internal DMA. `buf`'s length must be a multiple of
+    /// 512 bytes, and it must be word-aligned. Poll `transfer_complete()`, or enable the `DATAEND`
+    /// interrupt, then run `finish_dma()`.
+    ///
+    /// # Safety
+    /// `buf` must remain valid until `finish_dma()` is run. On H7, SDMMC1's IDMA can only access
+    /// AXI SRAM (Not DTCM, or SRAM1 - 4); if the data cache is enabled, invalidate `buf` after the
+    /// transfer.
+    pub unsafe fn read_blocks_dma(
+        &mut self,
+        start_block: u32,
+        buf: &mut [u8],
+    ) -> Result<(), SdmmcError> {
+        self.start_dma(start_block, buf.as_mut_ptr(), buf.len(), true)
+    }
+
+    #[cfg(any(feature = "l5", feature = "h7"))]
+    /// Start writing blocks from `buf` using the internal DMA. See `read_blocks_dma()` for
+    /// requirements. If the data cache is enabled, clean `buf` before the transfer.
+    ///
+    /// # Safety
+    /// `buf` must remain valid until `finish_dma()` is run.
+    pub unsafe fn write_blocks_dma(
+        &mut self,
+        start_block: u32,
+        buf: &[u8],
+    ) -> Result<(), SdmmcError> {
+        self.start_dma(start_block, buf.as_ptr() as *mut u8, buf.len(), false)
+    }
+
+    #[cfg(any(feature = "l5", feature = "h7"))]
+    fn start_dma(
+        &mut self,
+        start_block: u32,
+        buf: *mut u8,
+        len: usize,
+        read: bool,
+    ) -> Result<(), SdmmcError> {
+        if len == 0 || !len.is_multiple_of(BLOCK_SIZE) || !(buf as usize).is_multiple_of(4) {
+            return Err(SdmmcError::Buffer);
+        }
+        let addr = self.block_addr(start_block)?;
+        let multiple = len > BLOCK_SIZE;
+        self.dma_transfer = Some((multiple, read));
+
+        self.start_data(len, read);
+        self.write_reg(IDMABASE0, buf as u32);
+        // IDMAEN, in single buffer mode.
+        self.write_reg(IDMACTRL, 1);
+
+        let index = match (read, multiple) {
+            (true, true) => 18,
+            (true, false) => 17,
+            (false, true) => 25,
+            (false, false) => 24,
+        };
+
+        if let Err(e) = self.cmd_r1(index, addr, data_cmd_flags()) {
+            self.write_reg(IDMACTRL, 0);
+            self.dma_transfer = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    #[cfg(any(feature = "l5", feature = "h7"))]
+    /// Returns `Ok(true)` when a DMA transfer started with `read_blocks_dma()` or
+    /// `write_blocks_dma()` has completed, or an error if it failed.
+    pub fn transfer_complete(&self) -> Result<bool, SdmmcError> {
+        let sta = self.read_reg(STA);
+
+        if let Some(e) = Self::data_error(sta) {
+            return Err(e);
+        }
+        if sta & (STA_IDMATE | STA_DABORT) != 0 {
+            return Err(SdmmcError::Dma);
+        }
+        Ok(sta & STA_DATAEND != 0)
+    }
+
+    #[cfg(any(feature = "l5", feature = "h7"))]
+    /// Finish a DMA transfer, once `transfer_complete()` returns true, or an error. This stops a
+    /// multiple block transfer, and waits for the card to finish programming after a write.
+    pub fn finish_dma(&mut self) -> Result<(), SdmmcError> {
+        let (multiple, read) = self.dma_transfer.take().ok_or(SdmmcError::NoCard)?;
+
+        let result = self.transfer_complete().map(|_| ());
+        self.write_reg(IDMACTRL, 0);
+
+        self.finish_data(multiple, result)?;
+        if !read {
+            self.wait_ready()?;
+        }
+        Ok(())
+    }
+}
+
+/// Flags for commands that start a data transfer.
+fn data_cmd_flags() -> u32 {
+    #[cfg(any(feature = "l5", feature = "h7"))]
+    return CMD_CMDTRANS;
+    #[cfg(not(any(feature = "l5", feature = "h7")))]
+    return 0;
+}
+
+/// Read bits `hi` to `lo` (inclusive) of a 128-bit register, from its most significant word first.
+fn bits(reg: &[u32; 4], hi: u32, lo: u32) -> u32 {
+    let mut result = 0;
+    for bit in (lo..=hi).rev() {
+        let word = reg[3 - (bit / 32) as usize];
+        result = (result << 1) | ((word >> (bit % 32)) & 1);
+    }
+    result
+}
+
+/// Calculate capacity in 512-byte blocks, from the CSD. See the SD Physical Layer Simplified
+/// Specification, section 5.3: CSD Register.
+fn num_blocks(csd: &[u32; 4]) -> u32 {
+    match bits(csd, 127, 126) {
+        // Version 1: SDSC.
+        0 => {
+            let c_size = bits(csd, 73, 62);
+            let c_size_mult = bits(csd, 49, 47);
+            let read_bl_len = bits(csd, 83, 80);
+            ((c_size + 1) << (c_size_mult + 2 + read_bl_len)) / BLOCK_SIZE as u32
+        }
+        // Version 2: SDHC and SDXC. Capacity is (C_SIZE + 1) x 512KB.
+        _ => (bits(csd, 69, 48) + 1) * 1_024,
+    }
+}
+
+#[cfg(any(feature = "l5", feature = "h7"))]
+impl<R> BlockRead for Sdmmc<R>
+where
+    R: SdmmcPeriph,
+{
+    type Error = SdmmcError;
+
+    unsafe fn start_read(&mut self, block: u32, buf: *mut u8) -> Result<(), Self::Error> {
+        self.start_dma(block, buf, BLOCK_SIZE, true)
+    }
+
+    fn read_complete(&mut self) -> Result<bool, Self::Error> {
+        match self.transfer_complete() {
+            Ok(false) => Ok(false),
+            Ok(true) => {
+                self.finish_dma()?;
+                Ok(true)
+            }
+            Err(e) => {
+                self.finish_dma().ok();
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "embedded_sdmmc")]
+impl<R> embedded_sdmmc::BlockDevice for Sdmmc<R>
+where
+    R: SdmmcPeriph,
+{
+    type Error = SdmmcError;
+
+    fn read(
+        &self,
+        blocks: &mut [embedded_sdmmc::Block],
+        start_block_idx: embedded_sdmmc::BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        for (i, block) in blocks.iter_mut().enumerate() {
+            self.read_blocks_inner(start_block_idx.0 + i as u32, &mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        blocks: &[embedded_sdmmc::Block],
+        start_block_idx: embedded_sdmmc::BlockIdx,
+    ) -> Result<(), Self::Error> {
+        for (i, block) in blocks.iter().enumerate() {
+            self.write_blocks_inner(start_block_idx.0 + i as u32, &block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<embedded_sdmmc::BlockCount, Self::Error> {
+        let card = self.card.ok_or(SdmmcError::NoCard)?;
+        Ok(embedded_sdmmc::BlockCount(card.num_blocks))
+    }
+}
@@ -0,0 +1,231 @@
+//! TrustZone security configuration for STM32L5, from the secure image. Sets up the GTZC (Global
+//! TrustZone controller): TZSC peripheral security, and privilege attribution, and MPCBB SRAM
+//! block security. GPIO pin security is set with `Pin::set_security()`.
+//!
+//! Peripherals attributed as secure are only accessible through their secure aliases; the `SEC_`
+//! prefixed structs in the PAC. This module implements this HAL's peripheral traits for the
+//! secure aliases of common peripherals, so they can be used with the normal constructors, eg
+//! `Usart::new(dp.SEC_USART1, ...)`. Build the secure image with the `trustzone_secure` feature,
+//! so GPIO is accessed through its secure aliases as well.
+//!
+//! HAL structs constructed this way can be kept in a `static`, so non-secure-callable entry
+//! functions can use them without access to the PAC peripherals.
+//!
+//! Example, in a secure image:
+//! ```rust
+//! security::set_periph_security(SecurePeriph::Usart1, true);
+//! security::set_periph_security(SecurePeriph::Rng, true);
+//! // Reserve the top 64kB of SRAM1 for the secure image.
+//! security::set_sram_security(Sram::Sram1, 128 * 1_024, 64 * 1_024, true);
+//! security::lock();
+//!
+//! let mut tx = Pin::new(Port::A, 9, PinMode::Alt(7));
+//! tx.set_security(true);
+//!
+//! let uart = Usart::new(dp.SEC_USART1, 115_200, Default::default(), &clock_cfg);
+//! ```
+
+use crate::{
+    clocks::ClockFreqs,
+    pac::{self, rcc::RegisterBlock, SEC_GTZC_MPCBB1, SEC_GTZC_MPCBB2, SEC_GTZC_TZSC},
+    util::{BaudPeriph, RccPeriph},
+};
+
+/// The offset between a peripheral's non-secure, and secure alias addresses.
+pub const SECURE_ALIAS_OFFSET: usize = 0x1000_0000;
+
+/// MPCBB SRAM blocks are 256 bytes.
+const SRAM_BLOCK_SIZE: usize = 256;
+/// MPCBB vector registers start at this offset; each covers 32 blocks.
+const MPCBB_VCTR_OFFSET: usize = 0x100;
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// Peripherals with TZSC security, and privilege attribution. The value is the bit number in
+/// SECCFGR1 and PRIVCFGR1 for values below 32, and in SECCFGR2 and PRIVCFGR2 for the rest, offset
+/// by 32.
+pub enum SecurePeriph {
+    Tim2 = 0,
+    Tim3 = 1,
+    Tim4 = 2,
+    Tim5 = 3,
+    Tim6 = 4,
+    Tim7 = 5,
+    Wwdg = 6,
+    Iwdg = 7,
+    Spi2 = 8,
+    Spi3 = 9,
+    Usart2 = 10,
+    Usart3 = 11,
+    Uart4 = 12,
+    Uart5 = 13,
+    I2c1 = 14,
+    I2c2 = 15,
+    I2c3 = 16,
+    Crs = 17,
+    Dac = 18,
+    Opamp = 19,
+    Lptim1 = 20,
+    Lpuart1 = 21,
+    I2c4 = 22,
+    Lptim2 = 23,
+    Lptim3 = 24,
+    Fdcan1 = 25,
+    UsbFs = 26,
+    Ucpd1 = 27,
+    Vrefbuf = 28,
+    Comp = 29,
+    Tim1 = 30,
+    Spi1 = 31,
+    Tim8 = 32,
+    Usart1 = 33,
+    Tim15 = 34,
+    Tim16 = 35,
+    Tim17 = 36,
+    Sai1 = 37,
+    Sai2 = 38,
+    Dfsdm1 = 39,
+    Crc = 40,
+    Tsc = 41,
+    Icache = 42,
+    Adc = 43,
+    Aes = 44,
+    Hash = 45,
+    Rng = 46,
+    Pka = 47,
+    Sdmmc1 = 48,
+    FsmcReg = 49,
+    Octospi1Reg = 50,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// SRAM banks with block-based security, by MPCBB.
+pub enum Sram {
+    /// 192kB, at 0x2000_0000. Uses MPCBB1.
+    Sram1,
+    /// 64kB, at 0x2003_0000. Uses MPCBB2.
+    Sram2,
+}
+
+impl Sram {
+    fn size(&self) -> usize {
+        match self {
+            Self::Sram1 => 192 * 1_024,
+            Self::Sram2 => 64 * 1_024,
+        }
+    }
+
+    fn mpcbb_base(&self) -> *mut u8 {
+        match self {
+            Self::Sram1 => SEC_GTZC_MPCBB1::ptr() as *mut u8,
+            Self::Sram2 => SEC_GTZC_MPCBB2::ptr() as *mut u8,
+        }
+    }
+}
+
+/// Set a bit in a 32-bit register.
+unsafe fn set_bit(reg: *mut u32, bit: u8, val: bool) {
+    let orig = core::ptr::read_volatile(reg);
+    let new = if val {
+        orig | (1 << bit)
+    } else {
+        orig & !(1 << bit)
+    };
+    core::ptr::write_volatile(reg, new);
+}
+
+/// Set a peripheral as secure, or non-secure. Sets the TZSC_SECCFGRx register.
+pub fn set_periph_security(periph: SecurePeriph, secure: bool) {
+    let tzsc = unsafe { &(*SEC_GTZC_TZSC::ptr()) };
+
+    let reg = if (periph as u8) < 32 {
+        tzsc.seccfgr1.as_ptr()
+    } else {
+        tzsc.seccfgr2.as_ptr()
+    };
+    unsafe { set_bit(reg, periph as u8 % 32, secure) };
+}
+
+/// Check if a peripheral is secure.
+pub fn periph_is_secure(periph: SecurePeriph) -> bool {
+    let tzsc = unsafe { &(*SEC_GTZC_TZSC::ptr()) };
+
+    let val = if (periph as u8) < 32 {
+        tzsc.seccfgr1.read().bits()
+    } else {
+        tzsc.seccfgr2.read().bits()
+    };
+    val & (1 << (periph as u8 % 32)) != 0
+}
+
+/// Set a peripheral as privileged-only, or accessible by unprivileged code too. Sets the
+/// TZSC_PRIVCFGRx register.
+pub fn set_periph_privilege(periph: SecurePeriph, privileged: bool) {
+    let tzsc = unsafe { &(*SEC_GTZC_TZSC::ptr()) };
+
+    let reg = if (periph as u8) < 32 {
+        tzsc.privcfgr1.as_ptr()
+    } else {
+        tzsc.privcfgr2.as_ptr()
+    };
+    unsafe { set_bit(reg, periph as u8 % 32, privileged) };
+}
+
+/// Set a region of SRAM as secure, or non-secure. `start` and `len` are in bytes, from the start
+/// of the bank, and are rounded to 256-byte blocks: outwards when making the region secure, and
+/// inwards otherwise, so secure data is never exposed. Sets MPCBBx_VCTRy registers.
+pub fn set_sram_security(sram: Sram, start: usize, len: usize, secure: bool) {
+    let end = (start + len).min(sram.size());
+
+    let (first_block, end_block) = if secure {
+        (start / SRAM_BLOCK_SIZE, end.div_ceil(SRAM_BLOCK_SIZE))
+    } else {
+        (start.div_ceil(SRAM_BLOCK_SIZE), end / SRAM_BLOCK_SIZE)
+    };
+
+    let base = sram.mpcbb_base();
+
+    for block in first_block..end_block {
+        unsafe {
+            let reg = base.add(MPCBB_VCTR_OFFSET + (block / 32) * 4) as *mut u32;
+            set_bit(reg, (block % 32) as u8, secure);
+        }
+    }
+}
+
+/// Lock the TZSC configuration until the next reset. Sets TZSC_CR, LCK.
+pub fn lock() {
+    let tzsc = unsafe { &(*SEC_GTZC_TZSC::ptr()) };
+    tzsc.cr.modify(|_, w| w.lck().set_bit());
+}
+
+// Secure aliases of common peripherals. These delegate to the non-secure implementations; the RCC
+// is shared between them.
+
+macro_rules! secure_alias {
+    ($sec:ident, $ns:ident) => {
+        impl RccPeriph for pac::$sec {
+            fn en_reset(rcc: &RegisterBlock) {
+                pac::$ns::en_reset(rcc);
+            }
+        }
+    };
+    ($sec:ident, $ns:ident, baud) => {
+        secure_alias!($sec, $ns);
+
+        impl BaudPeriph for pac::$sec {
+            fn baud(clocks: &ClockFreqs) -> u32 {
+                pac::$ns::baud(clocks)
+            }
+        }
+    };
+}
+
+secure_alias!(SEC_USART1, USART1, baud);
+secure_alias!(SEC_USART2, USART2, baud);
+secure_alias!(SEC_USART3, USART3, baud);
+secure_alias!(SEC_SPI1, SPI1, baud);
+secure_alias!(SEC_SPI2, SPI2, baud);
+secure_alias!(SEC_SPI3, SPI3, baud);
+secure_alias!(SEC_I2C1, I2C1, baud);
+secure_alias!(SEC_I2C2, I2C2, baud);
@@ -0,0 +1,447 @@
+//! Building blocks for Class B (IEC 60730) style startup and periodic self-tests: A March C- RAM
+//! test, a flash CRC check using the CRC peripheral, a cross-check of the main clock against the LSE,
+//! and a CPU register test.
+//!
+//! These detect faults; they don't decide what to do about them. A typical response is to put outputs
+//! in a safe state, log the fault (eg with `event_log`), and reset, or halt.
+//!
+//! Example:
+//! ```rust
+//! // At startup, before initializing `.bss` and `.data`, eg from a `#[pre_init]` function:
+//! cpu_register_test()?;
+//! unsafe { ram_march_c(core::slice::from_raw_parts_mut(0x2000_0000 as *mut u32, 0x4000)) }?;
+//!
+//! // The expected CRC is computed by a post-build tool, and stored at the end of the image.
+//! let mut crc = dp.CRC.crc(&mut dp.RCC);
+//! check_flash_crc(&mut crc, flash_image(), EXPECTED_CRC)?;
+//!
+//! // With the LSE running, eg after setting up the RTC:
+//! let mut tim16 = Timer::new_tim16(dp.TIM16, 1., Default::default(), &clock_cfg);
+//! let measured = check_clock_vs_lse(&mut tim16, &clock_cfg, 0.02)?;
+//!
+//! // Periodically, eg from a low priority task, test a few blocks at a time:
+//! static mut SCRATCH: [u32; 16] = [0; 16];
+//! let mut ram_check = unsafe { RamCheck::new(0x2000_0000 as *mut u32, 0x4000, &mut SCRATCH) }?;
+//! let mut flash_check = FlashCheck::new(flash_image(), EXPECTED_CRC, 1_024);
+//!
+//! loop {
+//!     ram_check.step()?;
+//!     flash_check.step(&mut crc)?;
+//!     // ...
+//! }
+//! ```
+
+use core::ptr;
+
+use cortex_m::interrupt;
+
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "wb", feature = "wl")))]
+use crate::crc::{Config, Crc};
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g0",
+    feature = "wb",
+    feature = "wl"
+))]
+use crate::{clocks::Clocks, pac::TIM16, timer::Timer};
+
+// The RAM test uses all-zeros and all-ones words; the CPU register test uses alternating bits, so
+// adjacent bits are tested against each other.
+const BACKGROUND: u32 = 0;
+#[cfg(target_arch = "arm")]
+const PATTERN_A: u32 = 0xaaaa_aaaa;
+#[cfg(target_arch = "arm")]
+const PATTERN_B: u32 = 0x5555_5555;
+
+/// The LSE frequency, in Hz.
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g0",
+    feature = "wb",
+    feature = "wl"
+))]
+const LSE_FREQ: u32 = 32_768;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Self-test errors.
+pub enum SelfTestError {
+    /// A RAM word didn't read back as written. Contains its address.
+    Ram(u32),
+    /// The flash CRC didn't match. Contains the computed CRC.
+    FlashCrc(u32),
+    /// The measured clock was out of tolerance. Contains the measured frequency, in Hz.
+    Clock(u32),
+    /// A CPU register didn't read back as written.
+    CpuRegister,
+    /// The clock check didn't receive LSE edges; eg the LSE isn't running.
+    Timeout,
+    /// The RAM check's scratch buffer is empty, so it can't make progress.
+    EmptyScratch,
+}
+
+/// Run the March C- algorithm on a RAM region. This detects stuck-at, transition, and coupling
+/// faults between words. It's destructive; the region is all zeros afterwards.
+///
+/// Steps, with each word written and read as 0 or !0:
+/// ↑(w0); ↑(r0, w1); ↑(r1, w0); ↓(r0, w1); ↓(r1, w0); ↕(r0)
+///
+/// # Safety
+/// Nothing else, including interrupt handlers, may use `region` during the test. If `region` includes
+/// the stack, the test may only be run before the stack is in use; eg before `main`, using assembly.
+pub unsafe fn ram_march_c(region: &mut [u32]) -> Result<(), SelfTestError> {
+    let start = region.as_mut_ptr();
+    let len = region.len();
+
+    let zero = BACKGROUND;
+    let one = !BACKGROUND;
+
+    for i in 0..len {
+        ptr::write_volatile(start.add(i), zero);
+    }
+
+    for i in 0..len {
+        march_element(start.add(i), zero, one)?;
+    }
+    for i in 0..len {
+        march_element(start.add(i), one, zero)?;
+    }
+    for i in (0..len).rev() {
+        march_element(start.add(i), zero, one)?;
+    }
+    for i in (0..len).rev() {
+        march_element(start.add(i), one, zero)?;
+    }
+
+    for i in 0..len {
+        check_word(start.add(i), zero)?;
+    }
+
+    Ok(())
+}
+
+/// Read a word, checking it's `expected`, then write `next`.
+unsafe fn march_element(word: *mut u32, expected: u32, next: u32) -> Result<(), SelfTestError> {
+    check_word(word, expected)?;
+    ptr::write_volatile(word, next);
+    Ok(())
+}
+
+unsafe fn check_word(word: *mut u32, expected: u32) -> Result<(), SelfTestError> {
+    if ptr::read_volatile(word) != expected {
+        return Err(SelfTestError::Ram(word as u32));
+    }
+    Ok(())
+}
+
+/// A periodic, non-destructive RAM test. Each step copies a block of the region to a scratch buffer,
+/// runs March C- on it, then restores it, with interrupts disabled. Run `step()` periodically to test
+/// the whole region in turn.
+pub struct RamCheck<'a> {
+    start: *mut u32,
+    len: usize,
+    pos: usize,
+    /// Holds the block under test. Its length sets the block length.
+    scratch: &'a mut [u32],
+    /// The number of times the whole region has been tested.
+    pub passes: u32,
+}
+
+impl<'a> RamCheck<'a> {
+    /// Create a check of `len` words, starting at `start`, testing blocks the length of `scratch`
+    /// at a time. Returns `SelfTestError::EmptyScratch` if `scratch` is empty.
+    ///
+    /// # Safety
+    /// The region must be valid RAM, and must not include the stack of the context running `step()`,
+    /// nor `scratch`.
+    pub unsafe fn new(
+        start: *mut u32,
+        len: usize,
+        scratch: &'a mut [u32],
+    ) -> Result<Self, SelfTestError> {
+        if scratch.is_empty() {
+            return Err(SelfTestError::EmptyScratch);
+        }
+
+        Ok(Self {
+            start,
+            len,
+            pos: 0,
+            scratch,
+            passes: 0,
+        })
+    }
+
+    /// Test the next block. Returns `Ok(true)` when this completes a pass over the region. On error,
+    /// the block under test is restored before returning.
+    pub fn step(&mut self) -> Result<bool, SelfTestError> {
+        let block_len = self.scratch.len().min(self.len - self.pos);
+
+        interrupt::free(|_| unsafe {
+            let block = core::slice::from_raw_parts_mut(self.start.add(self.pos), block_len);
+
+            self.scratch[..block_len].copy_from_slice(block);
+            let result = ram_march_c(block);
+            block.copy_from_slice(&self.scratch[..block_len]);
+
+            result
+        })?;
+
+        self.pos += block_len;
+        if self.pos >= self.len {
+            self.pos = 0;
+            self.passes = self.passes.wrapping_add(1);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "wb", feature = "wl")))]
+/// Compute the CRC of a flash region, using the CRC peripheral with its default configuration:
+/// CRC-32/MPEG-2. (Polynomial 0x04C1_1DB7, initial value 0xFFFF_FFFF, no reflection, no output XOR).
+/// This resets the CRC unit's configuration.
+pub fn flash_crc(crc: &mut Crc, region: &[u8]) -> u32 {
+    crc.set_config(&Config::new());
+    crc.update(region);
+    crc.finish()
+}
+
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "wb", feature = "wl")))]
+/// Check a flash region against its expected CRC-32/MPEG-2. See `flash_crc()`.
+pub fn check_flash_crc(crc: &mut Crc, region: &[u8], expected: u32) -> Result<(), SelfTestError> {
+    let computed = flash_crc(crc, region);
+    if computed != expected {
+        return Err(SelfTestError::FlashCrc(computed));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "wb", feature = "wl")))]
+/// A periodic flash CRC check, processing `chunk_len` bytes per step. The CRC unit must not be used
+/// for anything else until a pass completes.
+pub struct FlashCheck {
+    region: &'static [u8],
+    expected: u32,
+    chunk_len: usize,
+    pos: usize,
+}
+
+#[cfg(not(any(feature = "f3", feature = "f4", feature = "wb", feature = "wl")))]
+impl FlashCheck {
+    pub fn new(region: &'static [u8], expected: u32, chunk_len: usize) -> Self {
+        Self {
+            region,
+            expected,
+            chunk_len,
+            pos: 0,
+        }
+    }
+
+    /// Process the next chunk. Returns `Ok(true)` when this completes a pass over the region, and
+    /// the CRC matched.
+    pub fn step(&mut self, crc: &mut Crc) -> Result<bool, SelfTestError> {
+        if self.pos == 0 {
+            crc.set_config(&Config::new());
+        }
+
+        let end = (self.pos + self.chunk_len).min(self.region.len());
+        crc.update(&self.region[self.pos..end]);
+        self.pos = end;
+
+        if self.pos < self.region.len() {
+            return Ok(false);
+        }
+
+        self.pos = 0;
+        let computed = crc.finish();
+        if computed != self.expected {
+            return Err(SelfTestError::FlashCrc(computed));
+        }
+        Ok(true)
+    }
+}
+
+// Raw TIM16 register offsets, since field names for these vary between PACs.
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g0",
+    feature = "wb",
+    feature = "wl"
+))]
+mod tim16_regs {
+    pub const CR1: usize = 0x00;
+    pub const SR: usize = 0x10;
+    pub const EGR: usize = 0x14;
+    pub const CCMR1: usize = 0x18;
+    pub const CCER: usize = 0x20;
+    pub const PSC: usize = 0x28;
+    pub const ARR: usize = 0x2c;
+    pub const CCR1: usize = 0x34;
+    /// TI1 input selection: TIM16_OR1 (L4, L5, WL) or TIM16_OR (WB) TI1_RMP, or TIM16_TISEL (G0)
+    /// TI1SEL.
+    #[cfg(not(feature = "g0"))]
+    pub const TI1_SEL: usize = 0x50;
+    #[cfg(feature = "g0")]
+    pub const TI1_SEL: usize = 0x68;
+    /// The TI1 selection value for LSE, on all of the above.
+    pub const TI1_LSE: u32 = 0b10;
+}
+
+#[cfg(any(
+    feature = "l4",
+    feature = "l5",
+    feature = "g0",
+    feature = "wb",
+    feature = "wl"
+))]
+/// Measure TIM16's clock against the LSE, and check it's within `tolerance` (eg 0.02 for 2%) of the
+/// configured APB2 timer clock. This detects a failed, or drifting HSI, MSI, HSE, or PLL. Returns the
+/// measured frequency, in Hz. The LSE must be running; eg set up the RTC with it first.
+///
+/// This captures on TIM16 channel 1, with its input connected to the LSE, and reconfigures the timer.
+/// The timer clock must be less than 268Mhz, so 8 LSE periods fit in its 16-bit counter.
+pub fn check_clock_vs_lse(
+    timer: &mut Timer<TIM16>,
+    clocks: &Clocks,
+    tolerance: f32,
+) -> Result<u32, SelfTestError> {
+    use tim16_regs::*;
+
+    // Number of 8-LSE-period intervals to average across.
+    const INTERVALS: u32 = 16;
+    // 8 LSE periods is 244us; this is generous for a timeout.
+    const MAX_ITERS: u32 = 1_000_000;
+
+    let base = &*timer.regs as *const _ as *mut u8;
+    let reg = |offset: usize| unsafe { base.add(offset) as *mut u32 };
+
+    unsafe {
+        ptr::write_volatile(reg(CR1), 0);
+        ptr::write_volatile(reg(TI1_SEL), TI1_LSE);
+        ptr::write_volatile(reg(PSC), 0);
+        ptr::write_volatile(reg(ARR), 0xffff);
+        // CC1S = 01: CC1 is an input, mapped on TI1. IC1PSC = 11: Capture once every 8 events.
+        ptr::write_volatile(reg(CCMR1), 0b01 | (0b11 << 2));
+        // CC1E: Enable capture. Rising edge.
+        ptr::write_volatile(reg(CCER), 1);
+        // Load the prescaler.
+        ptr::write_volatile(reg(EGR), 1);
+        ptr::write_volatile(reg(SR), 0);
+        ptr::write_volatile(reg(CR1), 1);
+    }
+
+    let capture = || -> Result<u16, SelfTestError> {
+        let mut i = 0;
+        // CC1IF. Reading CCR1 clears it.
+        while unsafe { ptr::read_volatile(reg(SR)) } & 0b10 == 0 {
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(SelfTestError::Timeout);
+            }
+        }
+        Ok(unsafe { ptr::read_volatile(reg(CCR1)) } as u16)
+    };
+
+    let result = (|| {
+        // The first capture may be of a partial interval.
+        capture()?;
+        let mut prev = capture()?;
+        let mut ticks = 0;
+
+        for _ in 0..INTERVALS {
+            let now = capture()?;
+            ticks += now.wrapping_sub(prev) as u32;
+            prev = now;
+        }
+        Ok(ticks)
+    })();
+
+    unsafe {
+        ptr::write_volatile(reg(CR1), 0);
+        ptr::write_volatile(reg(CCER), 0);
+        ptr::write_volatile(reg(TI1_SEL), 0);
+    }
+
+    let ticks = result?;
+    let measured = (ticks as u64 * LSE_FREQ as u64 / (8 * INTERVALS) as u64) as u32;
+
+    let expected = clocks.apb2_timer() as f32;
+    if (measured as f32 - expected).abs() > expected * tolerance {
+        return Err(SelfTestError::Clock(measured));
+    }
+
+    Ok(measured)
+}
+
+#[cfg(target_arch = "arm")]
+/// Test the CPU's general purpose registers R0 to R12, by writing alternating bit patterns to each,
+/// and reading them back. R4 to R11 are restored afterwards. This is compatible with Cortex-M0+
+/// (ARMv6-M), so only uses instructions available there.
+pub fn cpu_register_test() -> Result<(), SelfTestError> {
+    let errors: u32;
+    let out_a: u32;
+    let out_b: u32;
+
+    // R0 and R1 hold the patterns; R2 accumulates differences, and R3 is used to read back each
+    // register. Each register under test is written with one pattern, then the other, so each bit
+    // is tested in both states. R0 and R1 are checked on return.
+    unsafe {
+        core::arch::asm!(
+                // Save R4 - R11. Thumb-1 `push` only works on low registers.
+                "push {{r4-r7}}",
+                "mov r4, r8",
+                "mov r5, r9",
+                "mov r6, r10",
+                "mov r7, r11",
+                "push {{r4-r7}}",
+
+                "movs r2, #0",
+
+                // R3 itself.
+                "mov r3, r0", "eors r3, r0", "orrs r2, r3",
+                "mov r3, r1", "eors r3, r1", "orrs r2, r3",
+
+                "mov r4, r0", "mov r3, r4", "eors r3, r0", "orrs r2, r3",
+                "mov r4, r1", "mov r3, r4", "eors r3, r1", "orrs r2, r3",
+                "mov r5, r0", "mov r3, r5", "eors r3, r0", "orrs r2, r3",
+                "mov r5, r1", "mov r3, r5", "eors r3, r1", "orrs r2, r3",
+                "mov r6, r0", "mov r3, r6", "eors r3, r0", "orrs r2, r3",
+                "mov r6, r1", "mov r3, r6", "eors r3, r1", "orrs r2, r3",
+                "mov r7, r0", "mov r3, r7", "eors r3, r0", "orrs r2, r3",
+                "mov r7, r1", "mov r3, r7", "eors r3, r1", "orrs r2, r3",
+                "mov r8, r0", "mov r3, r8", "eors r3, r0", "orrs r2, r3",
+                "mov r8, r1", "mov r3, r8", "eors r3, r1", "orrs r2, r3",
+                "mov r9, r0", "mov r3, r9", "eors r3, r0", "orrs r2, r3",
+                "mov r9, r1", "mov r3, r9", "eors r3, r1", "orrs r2, r3",
+                "mov r10, r0", "mov r3, r10", "eors r3, r0", "orrs r2, r3",
+                "mov r10, r1", "mov r3, r10", "eors r3, r1", "orrs r2, r3",
+                "mov r11, r0", "mov r3, r11", "eors r3, r0", "orrs r2, r3",
+                "mov r11, r1", "mov r3, r11", "eors r3, r1", "orrs r2, r3",
+                "mov r12, r0", "mov r3, r12", "eors r3, r0", "orrs r2, r3",
+                "mov r12, r1", "mov r3, r12", "eors r3, r1", "orrs r2, r3",
+
+                "pop {{r4-r7}}",
+                "mov r8, r4",
+                "mov r9, r5",
+                "mov r10, r6",
+                "mov r11, r7",
+                "pop {{r4-r7}}",
+                inout("r0") PATTERN_A => out_a,
+                inout("r1") PATTERN_B => out_b,
+                out("r2") errors,
+                out("r3") _,
+                out("r12") _,
+        );
+    }
+
+    if errors != 0 || out_a != PATTERN_A || out_b != PATTERN_B {
+        return Err(SelfTestError::CpuRegister);
+    }
+
+    Ok(())
+}
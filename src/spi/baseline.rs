@@ -3,9 +3,9 @@ use core::{ops::Deref, ptr};
 use super::*;
 use crate::{
     check_errors,
+    instant::Timeout,
     pac::{self, RCC},
     util::RccPeriph,
-    MAX_ITERS,
 };
 
 // Depth of FIFO to use. See G4 RM, table 359.
@@ -14,7 +14,8 @@ const FIFO_LEN: usize = 4;
 
 /// Possible interrupt types. Enable these in SPIx_CR2. Check and clear with SR. There is no explicit
 /// way to clear these.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SpiInterrupt {
     /// Tx buffer empty (TXEIE)
     TxBufEmpty,
@@ -25,7 +26,8 @@ pub enum SpiInterrupt {
 }
 
 /// These bits configure the data length for SPI transfers. Sets `SPI_CR2` register, `DS` field.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum DataSize {
     D4 = 0b0011,
@@ -82,10 +84,12 @@ where
             w.crcen().clear_bit();
             // f) Configure SSM and SSI (Notes: 2 & 3).
             w.ssm().bit(cfg.slave_select == SlaveSelect::Software);
-            w.ssi().bit(cfg.slave_select == SlaveSelect::Software);
+            // With software NSS, SSI is the NSS level: High for master, and low to select a slave.
+            w.ssi()
+                .bit(cfg.slave_select == SlaveSelect::Software && cfg.role == SpiRole::Master);
             // g) Configure the MSTR bit (in multimaster NSS configuration, avoid conflict state on
             // NSS if master is configured to prevent MODF error).
-            w.mstr().set_bit();
+            w.mstr().bit(cfg.role == SpiRole::Master);
             w.spe().set_bit() // Enable SPI
         });
 
@@ -111,6 +115,8 @@ where
         // c) Set the FRF bit if the TI protocol is required (keep NSSP bit cleared in TI mode).
         // d) Set the NSSP bit if the NSS pulse mode between two data units is required (keep
         // CHPA and TI bits cleared in NSSP mode).
+        #[cfg(not(feature = "f4"))]
+        regs.cr2.modify(|_, w| w.nssp().bit(cfg.nss_pulse_enabled()));
 
         // f) Initialize LDMA_TX and LDMA_RX bits if DMA is used in packed mode.
         // 4. Write to SPI_CRCPR register: Configure the CRC polynomial if needed.
@@ -124,6 +130,43 @@ where
         Self { regs, cfg }
     }
 
+    /// Initialize an SPI peripheral as with `new()`, then check that its configuration registers
+    /// read back as written, including the baud rate. Returns `SpiError::VerifyFailed` if they don't.
+    pub fn try_new(regs: R, cfg: SpiConfig, baud_rate: BaudRate) -> Result<Self, SpiError> {
+        let result = Self::new(regs, cfg, baud_rate);
+        result.verify_config()?;
+
+        if result.regs.cr1.read().br().bits() != baud_rate as u8 {
+            return Err(SpiError::VerifyFailed);
+        }
+        Ok(result)
+    }
+
+    /// Read back the configuration registers set by `new()`, and compare them to the stored
+    /// configuration. This can be run periodically, eg as part of a self-test.
+    pub fn verify_config(&self) -> Result<(), SpiError> {
+        let cr1 = self.regs.cr1.read();
+        let cr2 = self.regs.cr2.read();
+
+        let ok = cr1.spe().bit_is_set()
+            && cr1.mstr().bit_is_set() == (self.cfg.role == SpiRole::Master)
+            && cr1.cpol().bit_is_set() == (self.cfg.mode.polarity as u8 != 0)
+            && cr1.cpha().bit_is_set() == (self.cfg.mode.phase as u8 != 0)
+            && cr1.ssm().bit_is_set() == (self.cfg.slave_select == SlaveSelect::Software)
+            && cr2.ssoe().bit_is_set() == (self.cfg.slave_select == SlaveSelect::HardwareOutEnable);
+
+        #[cfg(not(feature = "f4"))]
+        let ok = ok
+            && cr2.ds().bits() == self.cfg.data_size as u8
+            && cr2.nssp().bit_is_set() == self.cfg.nss_pulse_enabled();
+
+        if ok {
+            Ok(())
+        } else {
+            Err(SpiError::VerifyFailed)
+        }
+    }
+
     /// Change the SPI baud rate.
     pub fn reclock(&mut self, baud_rate: BaudRate) {
         self.regs.cr1.modify(|_, w| w.spe().clear_bit());
@@ -158,16 +201,26 @@ where
         }
     }
 
+    /// Block until the last data frame is processed. (BSY = 0)
+    pub(super) fn wait_idle(&self) -> Result<(), SpiError> {
+        let mut timeout = Timeout::new();
+        while self.regs.sr.read().bsy().bit_is_set() {
+            if timeout.expired() {
+                return Err(SpiError::Hardware);
+            }
+        }
+        Ok(())
+    }
+
     /// Read a single byte if available, or block until it's available.
     pub fn read(&mut self) -> Result<u8, SpiError> {
         check_errors!(self.regs.sr.read());
 
         // todo: Use fIFO like in H7 code?
 
-        let mut i = 0;
+        let mut timeout = Timeout::new();
         while !self.regs.sr.read().rxne().bit_is_set() {
-            i += 1;
-            if i >= MAX_ITERS {
+            if timeout.expired() {
                 return Err(SpiError::Hardware);
             }
         }
@@ -180,10 +233,9 @@ where
     pub fn write_one(&mut self, byte: u8) -> Result<(), SpiError> {
         check_errors!(self.regs.sr.read());
 
-        let mut i = 0;
+        let mut timeout = Timeout::new();
         while !self.regs.sr.read().txe().bit_is_set() {
-            i += 1;
-            if i >= MAX_ITERS {
+            if timeout.expired() {
                 return Err(SpiError::Hardware);
             }
         }
@@ -315,6 +367,17 @@ where
         self.regs.cr1.modify(|_, w| w.spe().set_bit());
     }
 
+    /// Enable a DMA request when the transmit buffer is empty, and return the data register, for use
+    /// as the destination of a DMA route. (See the `dma_route` module). `request` is this SPI's TX
+    /// input. Use `u16` words for 16-bit frames.
+    #[cfg(not(any(feature = "f4", feature = "l552")))]
+    pub fn dma_sink<W: Word>(&mut self, request: dma::DmaInput) -> dma_route::Endpoint<W> {
+        self.regs.cr2.modify(|_, w| w.txdmaen().set_bit());
+        self.regs.cr1.modify(|_, w| w.spe().set_bit());
+
+        dma_route::Endpoint::register(&self.regs.dr as *const _ as u32, request)
+    }
+
     /// Transmit data using DMA. See L44 RM, section 40.4.9: Communication using DMA.
     /// Note that the `channel` argument is unused on F3 and L4, since it is hard-coded,
     /// and can't be configured using the DMAMUX peripheral. (`dma::mux()` fn).
@@ -5,9 +5,9 @@ use core::{cell::UnsafeCell, ops::Deref, ptr};
 use super::*;
 use crate::{
     check_errors,
+    instant::Timeout,
     pac::{self, RCC},
     util::RccPeriph,
-    MAX_ITERS,
 };
 
 // Depth of FIFO to use. See RM0433 Rev 7, Table 409. Note that 16 is acceptable on this MCU,
@@ -15,7 +15,8 @@ use crate::{
 const FIFO_LEN: usize = 8;
 
 /// Possible interrupt types. Enable these in SPIx_IER. Check with SR. Clear with IFCR
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SpiInterrupt {
     /// Additional number of transactions reload interrupt enable (TSERFIE)
     NumberOfTransactionsReload,
@@ -42,7 +43,8 @@ pub enum SpiInterrupt {
 }
 
 /// Number of bits in at single SPI data frame. Sets `CFGR1` register, `DSIZE` field.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum DataSize {
     D4 = 3,
@@ -109,8 +111,11 @@ where
         // [St forum thread on how to set up SPI in master mode avoiding mode faults:
         // https://community.st.com/s/question/0D50X0000AFrHS6SQN/stm32h7-what-is-the-proper-
         // way-to-make-spi-work-in-master-mode
-        regs.cr1
-            .modify(|_, w| w.ssi().bit(cfg.slave_select == SlaveSelect::Software));
+        // With software NSS, SSI is the NSS level: High for master, and low to select a slave.
+        regs.cr1.modify(|_, w| {
+            w.ssi()
+                .bit(cfg.slave_select == SlaveSelect::Software && cfg.role == SpiRole::Master)
+        });
 
         regs.cfg1.modify(|_, w| {
             w.mbr().bits(baud_rate as u8);
@@ -118,18 +123,23 @@ where
             w.crcen().clear_bit()
         });
 
-        // Specifies minimum time delay (expressed in SPI clock cycles periods) inserted between two
-        // consecutive data frames in master mode. In clock cycles; 0 - 15. (hardware CS)
-        let inter_word_delay = 0;
+        assert!(
+            cfg.inter_frame_delay <= 15 && cfg.ss_setup_delay <= 15,
+            "SPI inter-frame and SS setup delays must be 0 - 15 clock cycles."
+        );
 
         regs.cfg2.modify(|_, w| {
             w.cpol().bit(cfg.mode.polarity as u8 != 0);
             w.cpha().bit(cfg.mode.phase as u8 != 0);
-            w.master().set_bit();
+            w.master().bit(cfg.role == SpiRole::Master);
             w.ssm().bit(cfg.slave_select == SlaveSelect::Software);
             w.ssoe().bit(cfg.slave_select != SlaveSelect::Software);
-            w.midi().bits(inter_word_delay);
-            w.master().set_bit();
+            // SS interleaving between frames, with a width of MIDI clock cycles.
+            w.ssom().bit(cfg.nss_pulse_enabled());
+            // Minimum delay between two consecutive data frames. (hardware CS)
+            w.midi().bits(cfg.inter_frame_delay);
+            // Delay between SS going active, and the first data frame. (hardware CS)
+            w.mssi().bits(cfg.ss_setup_delay);
             w.comm().bits(0b00) // Full-duplex mode
         });
 
@@ -152,6 +162,42 @@ where
         Self { regs, cfg }
     }
 
+    /// Initialize an SPI peripheral as with `new()`, then check that its configuration registers
+    /// read back as written, including the baud rate. Returns `SpiError::VerifyFailed` if they don't.
+    pub fn try_new(regs: R, cfg: SpiConfig, baud_rate: BaudRate) -> Result<Self, SpiError> {
+        let result = Self::new(regs, cfg, baud_rate);
+        result.verify_config()?;
+
+        if result.regs.cfg1.read().mbr().bits() != baud_rate as u8 {
+            return Err(SpiError::VerifyFailed);
+        }
+        Ok(result)
+    }
+
+    /// Read back the configuration registers set by `new()`, and compare them to the stored
+    /// configuration. This can be run periodically, eg as part of a self-test.
+    pub fn verify_config(&self) -> Result<(), SpiError> {
+        let cfg1 = self.regs.cfg1.read();
+        let cfg2 = self.regs.cfg2.read();
+
+        let ok = self.regs.cr1.read().spe().bit_is_set()
+            && cfg1.dsize().bits() == self.cfg.data_size as u8
+            && cfg2.master().bit_is_set() == (self.cfg.role == SpiRole::Master)
+            && cfg2.cpol().bit_is_set() == (self.cfg.mode.polarity as u8 != 0)
+            && cfg2.cpha().bit_is_set() == (self.cfg.mode.phase as u8 != 0)
+            && cfg2.ssm().bit_is_set() == (self.cfg.slave_select == SlaveSelect::Software)
+            && cfg2.ssoe().bit_is_set() == (self.cfg.slave_select != SlaveSelect::Software)
+            && cfg2.ssom().bit_is_set() == self.cfg.nss_pulse_enabled()
+            && cfg2.midi().bits() == self.cfg.inter_frame_delay
+            && cfg2.mssi().bits() == self.cfg.ss_setup_delay;
+
+        if ok {
+            Ok(())
+        } else {
+            Err(SpiError::VerifyFailed)
+        }
+    }
+
     /// Change the SPI baud rate.
     pub fn reclock(&mut self, baud_rate: BaudRate) {
         self.regs.cr1.modify(|_, w| w.spe().clear_bit());
@@ -184,6 +230,17 @@ where
         self.regs.cr1.modify(|_, w| w.spe().clear_bit());
     }
 
+    /// Block until the last data frame is sent. (TXC = 1)
+    pub(super) fn wait_idle(&self) -> Result<(), SpiError> {
+        let mut timeout = Timeout::new();
+        while self.regs.sr.read().txc().bit_is_clear() {
+            if timeout.expired() {
+                return Err(SpiError::Hardware);
+            }
+        }
+        Ok(())
+    }
+
     // todo: Temp C+P from h7xx hal while troubleshooting.
     /// Internal implementation for exchanging a word
     ///
@@ -193,10 +250,9 @@ where
         let status = self.regs.sr.read();
         check_errors!(status);
 
-        let mut i = 0;
+        let mut timeout = Timeout::new();
         while !self.regs.sr.read().dxp().is_available() {
-            i += 1;
-            if i >= MAX_ITERS {
+            if timeout.expired() {
                 return Err(SpiError::Hardware);
             }
         }
@@ -215,10 +271,9 @@ where
     pub fn read(&mut self) -> Result<u8, SpiError> {
         check_errors!(self.regs.sr.read());
 
-        let mut i = 0;
+        let mut timeout = Timeout::new();
         while !self.regs.sr.read().rxp().is_not_empty() {
-            i += 1;
-            if i >= MAX_ITERS {
+            if timeout.expired() {
                 return Err(SpiError::Hardware);
             }
         }
@@ -349,6 +404,20 @@ where
         self.regs.cr1.modify(|_, w| w.cstart().set_bit()); // Must be separate from SPE enable.
     }
 
+    /// Enable a DMA request when the transmit FIFO has space, and return the transmit data
+    /// register, for use as the destination of a DMA route. (See the `dma_route` module). `request`
+    /// is this SPI's TX input. Use `u16` words for 16-bit frames. This starts the transfer; the SPI
+    /// clocks out data as the route supplies it.
+    pub fn dma_sink<W: Word>(&mut self, request: dma::DmaInput) -> dma_route::Endpoint<W> {
+        // TXDMAEN can only be set while the SPI is disabled.
+        self.regs.cr1.modify(|_, w| w.spe().clear_bit());
+        self.regs.cfg1.modify(|_, w| w.txdmaen().set_bit());
+        self.regs.cr1.modify(|_, w| w.spe().set_bit());
+        self.regs.cr1.modify(|_, w| w.cstart().set_bit()); // Must be separate from SPE enable.
+
+        dma_route::Endpoint::register(&self.regs.txdr as *const _ as u32, request)
+    }
+
     pub unsafe fn write_dma(
         &mut self,
         buf: &[u8],
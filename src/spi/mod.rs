@@ -2,7 +2,7 @@
 //! Provides APIs to configure, read, and write from
 //! SPI, with blocking, nonblocking, and DMA functionality.
 
-use core::{ops::Deref, ptr};
+use core::{fmt, ops::Deref, ptr};
 
 cfg_if::cfg_if! {
     if #[cfg(any(feature = "h5", feature = "h7"))] {
@@ -15,8 +15,18 @@ cfg_if::cfg_if! {
 }
 
 use cfg_if::cfg_if;
-
-use crate::{pac, util::RccPeriph};
+use cortex_m::asm;
+
+#[cfg(not(feature = "h5"))]
+use crate::af_pins::SpiPins;
+#[cfg(feature = "defmt")]
+use crate::util::format_flags;
+use crate::{
+    clocks::{ClockFreqs, ClockListener},
+    gpio::{Pin, PinMode},
+    pac,
+    util::{fmt_flags, BaudPeriph, RccPeriph},
+};
 
 cfg_if! {
     if #[cfg(all(feature = "g0", not(any(feature = "g0b1", feature = "g0c1"))))] {
@@ -32,6 +42,8 @@ cfg_if! {
 use crate::dma::DmaInput;
 #[cfg(not(any(feature = "f4", feature = "l552")))]
 use crate::dma::{self, ChannelCfg, Dma, DmaChannel}; // todo temp
+#[cfg(not(any(feature = "f4", feature = "l552")))]
+use crate::dma_route::{self, Word};
 
 #[macro_export]
 macro_rules! check_errors {
@@ -53,7 +65,8 @@ macro_rules! check_errors {
 
 /// SPI error
 #[non_exhaustive]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SpiError {
     /// Overrun occurred
     Overrun,
@@ -63,6 +76,59 @@ pub enum SpiError {
     Crc,
     Hardware,
     DuplexFailed, // todo temp?
+    /// A configuration register didn't read back as written; eg the peripheral clock isn't
+    /// enabled, or the configuration is locked.
+    VerifyFailed,
+}
+
+/// An SPI status register (SR) value, from `Spi::status()`. Its `Debug` and `defmt::Format`
+/// implementations list the flags that are set, eg "OVR + MODF", for logging.
+#[derive(Clone, Copy, PartialEq)]
+pub struct SpiStatus(pub u32);
+
+impl SpiStatus {
+    #[cfg(not(feature = "h7"))]
+    const FLAGS: &'static [(u8, &'static str)] = &[
+        (0, "RXNE"),
+        (1, "TXE"),
+        (3, "UDR"),
+        (4, "CRCERR"),
+        (5, "MODF"),
+        (6, "OVR"),
+        (7, "BSY"),
+        (8, "FRE"),
+    ];
+
+    #[cfg(feature = "h7")]
+    const FLAGS: &'static [(u8, &'static str)] = &[
+        (0, "RXP"),
+        (1, "TXP"),
+        (2, "DXP"),
+        (3, "EOT"),
+        (4, "TXTF"),
+        (5, "UDR"),
+        (6, "OVR"),
+        (7, "CRCE"),
+        (8, "TIFRE"),
+        (9, "MODF"),
+        (10, "TSERF"),
+        (11, "SUSP"),
+        (12, "TXC"),
+        (15, "RXWNE"),
+    ];
+}
+
+impl fmt::Debug for SpiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_flags(self.0, Self::FLAGS, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for SpiStatus {
+    fn format(&self, f: defmt::Formatter) {
+        format_flags(self.0, Self::FLAGS, f)
+    }
 }
 
 /// Set the factor to divide the APB clock by to set baud rate. Sets `SPI_CR1` register, `BR` field.
@@ -80,7 +146,33 @@ pub enum BaudRate {
     Div256 = 0b111,
 }
 
-#[derive(Clone, Copy)]
+impl BaudRate {
+    /// Select the smallest divider that results in an SPI clock at or below `max_freq`. `kernel_clk`
+    /// is the SPI kernel clock; eg `clocks.spi[0]` for SPI1, using the `ClockFreqs` returned by
+    /// `Clocks::freeze()`. Both are in Hz. Uses `Div256` if `max_freq` can't be reached.
+    pub fn from_freq(kernel_clk: u32, max_freq: u32) -> Self {
+        let div = kernel_clk.div_ceil(max_freq.max(1));
+
+        match div {
+            0..=2 => Self::Div2,
+            3..=4 => Self::Div4,
+            5..=8 => Self::Div8,
+            9..=16 => Self::Div16,
+            17..=32 => Self::Div32,
+            33..=64 => Self::Div64,
+            65..=128 => Self::Div128,
+            _ => Self::Div256,
+        }
+    }
+
+    /// The SPI clock frequency resulting from this divider, in Hz.
+    pub fn freq(&self, kernel_clk: u32) -> u32 {
+        kernel_clk >> (*self as u8 + 1)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// FIFO reception threshold Sets `SPI_CR2` register, `FRXTH` field.
 pub enum ReceptionThresh {
@@ -90,7 +182,8 @@ pub enum ReceptionThresh {
     D8 = 1,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Select the duplex communication mode between the 2 devices. Sets `CR1` register, `BIDIMODE`,
 /// and `RXONLY` fields.
 pub enum SpiCommMode {
@@ -102,7 +195,20 @@ pub enum SpiCommMode {
     ReceiveOnly,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Master or slave operation. Sets CR1 register, MSTR field. On H7, sets CFG2 register, `MASTER`
+/// field.
+pub enum SpiRole {
+    /// This device generates the clock.
+    Master,
+    /// The clock is generated by another device. With `SlaveSelect::Software`, the slave is
+    /// always selected.
+    Slave,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Used for managing NSS / CS pin. Sets CR1 register, SSM field.
 /// On H7, sets CFG2 register, `SSOE` field.
 pub enum SlaveSelect {
@@ -129,7 +235,8 @@ cfg_if! {
     if #[cfg(feature = "embedded_hal")] {
         type SpiModeType = embedded_hal::spi::Mode;
     } else {
-        #[derive(Clone, Copy)]
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
         #[repr(u8)]
         /// Clock polarity. Sets CFGR2 register, CPOL field. Stored in the config as a field of `SpiMode`.
         pub enum SpiPolarity {
@@ -139,7 +246,8 @@ cfg_if! {
             IdleHigh = 1,
         }
 
-        #[derive(Clone, Copy)]
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
         #[repr(u8)]
         /// Clock phase. Sets CFGR2 register, CPHA field. Stored in the config as a field of `SpiMode`.
         pub enum SpiPhase {
@@ -149,7 +257,8 @@ cfg_if! {
             CaptureOnSecondTransition = 1,
         }
 
-        #[derive(Clone, Copy)]
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        #[cfg_attr(feature = "defmt", derive(defmt::Format))]
         /// SPI mode. Sets CFGR2 reigster, CPOL and CPHA fields.
         pub struct SpiMode {
             /// Clock polarity
@@ -200,20 +309,37 @@ cfg_if! {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Configuration data for SPI.
 pub struct SpiConfig {
     /// SPI mode associated with Polarity and Phase. Defaults to Mode0: Idle low, capture on first transition.
     pub mode: SpiModeType,
     /// Sets the (duplex) communication mode between the devices. Defaults to full duplex.
     pub comm_mode: SpiCommMode,
+    /// Master or slave operation. Defaults to master.
+    pub role: SpiRole,
     /// Controls use of hardware vs software CS/NSS pin. Defaults to software.
     pub slave_select: SlaveSelect,
     /// Data size. Defaults to 8 bits.
     pub data_size: DataSize,
     /// FIFO reception threshhold. Defaults to 8 bits.
     pub fifo_reception_thresh: ReceptionThresh,
-    // pub cs_delay: f32,
+    /// The maximum SCK frequency, in Hz. If set, the baud rate divider is recalculated to stay at
+    /// or below this when clocks change; see `Clocks::reconfigure()`. Defaults to `None`.
+    pub max_freq: Option<u32>,
+    /// Pulse hardware NSS inactive between data frames, eg for devices that latch each frame on
+    /// NSS going high. Requires `SlaveSelect::HardwareOutEnable`, and CPHA = 0; this is ignored
+    /// when capturing on the second transition. Sets `CR2` register, `NSSP` field. On H7, sets
+    /// `CFG2` register, `SSOM` field; the pulse width is set by `inter_frame_delay`, which must be
+    /// at least 2. Not available on F4. Defaults to false.
+    pub nss_pulse: bool,
+    /// The minimum delay between data frames, in SPI clock cycles; 0 - 15. With `nss_pulse`,
+    /// this is the NSS inactive time. H7 only; sets `CFG2` register, `MIDI` field. Defaults to 0.
+    pub inter_frame_delay: u8,
+    /// The delay between NSS going active, and the first data frame, in SPI clock cycles; 0 - 15.
+    /// Requires hardware NSS. H7 only; sets `CFG2` register, `MSSI` field. Defaults to 0.
+    pub ss_setup_delay: u8,
     // pub swap_miso_mosi: bool,
     // pub suspend_when_inactive: bool,
 }
@@ -231,9 +357,48 @@ impl Default for SpiConfig {
         Self {
             mode: mode0,
             comm_mode: SpiCommMode::FullDuplex,
+            role: SpiRole::Master,
             slave_select: SlaveSelect::Software,
             data_size: DataSize::D8,
             fifo_reception_thresh: ReceptionThresh::D8,
+            max_freq: None,
+            nss_pulse: false,
+            inter_frame_delay: 0,
+            ss_setup_delay: 0,
+        }
+    }
+}
+
+impl SpiConfig {
+    /// NSS pulse mode requires CPHA = 0.
+    #[cfg(not(feature = "f4"))]
+    fn nss_pulse_enabled(&self) -> bool {
+        self.nss_pulse && self.mode.phase as u8 == 0
+    }
+}
+
+/// A GPIO chip select (CS) pin, managed in software, with its device's timing requirements. Use
+/// one for each device sharing a bus, with `Spi::with_software_cs()`. This supports more devices
+/// than there are hardware NSS pins, and devices with different CS timing. Configure the `Spi`
+/// with `SlaveSelect::Software`.
+pub struct SoftwareCs {
+    pub pin: Pin,
+    /// The delay after setting CS low, before the first data frame, in CPU cycles.
+    pub setup_delay: u32,
+    /// The delay after the last data frame, before setting CS high, in CPU cycles.
+    pub hold_delay: u32,
+}
+
+impl SoftwareCs {
+    /// Set a pin high (inactive), then configure it as an output, for use as a CS pin.
+    pub fn new(mut pin: Pin, setup_delay: u32, hold_delay: u32) -> Self {
+        pin.set_high();
+        pin.mode(PinMode::Output);
+
+        Self {
+            pin,
+            setup_delay,
+            hold_delay,
         }
     }
 }
@@ -244,10 +409,62 @@ pub struct Spi<R> {
     pub cfg: SpiConfig,
 }
 
+impl<R> ClockListener for Spi<R>
+where
+    R: Deref<Target = pac::spi1::RegisterBlock> + RccPeriph + BaudPeriph,
+{
+    /// Recalculate the baud rate divider, if `max_freq` is set in the config.
+    fn clocks_changed(&mut self, freqs: &ClockFreqs) {
+        if let Some(max_freq) = self.cfg.max_freq {
+            self.reclock(BaudRate::from_freq(R::baud(freqs), max_freq));
+        }
+    }
+}
+
 impl<R> Spi<R>
 where
     R: Deref<Target = pac::spi1::RegisterBlock> + RccPeriph,
 {
+    /// Initialize an SPI peripheral as with `new()`, after setting its SCK, MISO, and MOSI pins
+    /// to their alternate functions. The pins are checked against this peripheral's AF table at
+    /// compile time; eg `(PA5, PA6, PA7)` for SPI1. See the `af_pins` module.
+    #[cfg(not(feature = "h5"))]
+    pub fn new_with_pins(
+        regs: R,
+        pins: impl SpiPins<R>,
+        cfg: SpiConfig,
+        baud_rate: BaudRate,
+    ) -> Self {
+        pins.connect();
+        Self::new(regs, cfg, baud_rate)
+    }
+
+    /// Run a transaction on a device with a software CS: Set CS low, wait its setup delay, run
+    /// `f`, wait for the last frame to finish, wait its hold delay, then set CS high. CS is set
+    /// high even if `f` returns an error.
+    ///
+    /// Example: `spi.with_software_cs(&mut imu_cs, |spi| spi.transfer(&mut buf))?;`
+    pub fn with_software_cs<T>(
+        &mut self,
+        cs: &mut SoftwareCs,
+        f: impl FnOnce(&mut Self) -> Result<T, SpiError>,
+    ) -> Result<T, SpiError> {
+        cs.pin.set_low();
+        asm::delay(cs.setup_delay);
+
+        let result = f(self).and_then(|v| self.wait_idle().map(|_| v));
+
+        asm::delay(cs.hold_delay);
+        cs.pin.set_high();
+
+        result
+    }
+
+    /// Read the status register (SR), eg to log it after an error.
+    pub fn status(&self) -> SpiStatus {
+        SpiStatus(self.regs.sr.read().bits())
+    }
+
     /// Stop a DMA transfer. Stops the channel, and disables the `txdmaen` and `rxdmaen` bits.
     /// Run this after each transfer completes - you may wish to do this in an interrupt
     /// (eg DMA transfer complete) instead of blocking. `channel2` is an optional second channel
@@ -0,0 +1,456 @@
+//! A lightweight telemetry link: Packets are COBS-framed (Consistent Overhead Byte Stuffing), and
+//! delimited by a 0 byte, so a receiver can resynchronize after lost or corrupted bytes. Send them
+//! over a UART, using DMA or not, over USB CDC, or any transport that implements `TelemetrySink`.
+//!
+//! `Encoder` queues one frame at a time, and never blocks: If the transport hasn't accepted the
+//! previous frame, `send()` returns `TelemetryError::Busy`, and the packet is dropped. Call `poll()`
+//! frequently, eg in a loop or timer interrupt, to push queued bytes to the transport. `Decoder`
+//! reassembles frames from received bytes, eg for command reception.
+//!
+//! With the `postcard` feature, `send_value()` and `deserialize()` serialize structs that implement
+//! `serde`'s `Serialize` and `Deserialize` traits, using the `postcard` wire format.
+//!
+//! Example:
+//! ```rust
+//! #[derive(Serialize)]
+//! struct Status { temp: f32, rpm: u16 }
+//!
+//! // Frames up to 64 bytes, sent by DMA from a static buffer.
+//! static mut TX_BUF: [u8; 64] = [0; 64];
+//! let sink = UartDmaSink::new(uart, unsafe { &mut TX_BUF }, DmaChannel::C2, Default::default(), DmaPeriph::Dma1);
+//! let mut tx: Encoder<_, 64> = Encoder::new(sink);
+//!
+//! // Or, over USB CDC, using the `usbd-serial` crate:
+//! let mut tx: Encoder<_, 64> = Encoder::new(|data: &[u8]| serial.write(data).unwrap_or(0));
+//!
+//! tx.send_value(&Status { temp: 21.5, rpm: 1_200 }).ok();
+//!
+//! let mut rx: Decoder<64> = Decoder::new();
+//! loop {
+//!     tx.poll();
+//!
+//!     if let Some(Ok(packet)) = rx.poll(&mut uart_rx) {
+//!         let cmd: Command = deserialize(packet).unwrap();
+//!     }
+//! }
+//! ```
+
+#[cfg(feature = "postcard")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+use crate::dma::{self, ChannelCfg, DmaChannel, DmaPeriph};
+#[cfg(not(feature = "f4"))]
+use crate::usart::{Usart, UsartInterrupt};
+
+/// Marks the end of a frame. COBS-encoded data contains no other zeros.
+pub const DELIMITER: u8 = 0;
+
+/// COBS blocks contain up to 254 bytes of data, following their code byte.
+const MAX_BLOCK: usize = 254;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Telemetry errors.
+pub enum TelemetryError {
+    /// The packet doesn't fit in the frame buffer, once encoded.
+    BufferTooSmall,
+    /// The previous frame hasn't been sent yet; the packet was dropped.
+    Busy,
+    /// A received frame isn't valid COBS data.
+    InvalidFrame,
+    /// A received frame didn't fit in the decoder's buffer; it was discarded.
+    Overflow,
+    /// Serialization, or deserialization with `postcard` failed.
+    Serialize,
+}
+
+/// The maximum size of a frame for a packet of `len` bytes, including the delimiter.
+pub const fn max_frame_len(len: usize) -> usize {
+    len + len / MAX_BLOCK + 2
+}
+
+/// COBS-encode `src` into `dst`, returning the encoded length. Doesn't add a delimiter.
+pub fn cobs_encode(src: &[u8], dst: &mut [u8]) -> Result<usize, TelemetryError> {
+    if dst.is_empty() {
+        return Err(TelemetryError::BufferTooSmall);
+    }
+
+    // The position of the current block's code byte, which is written once the block ends.
+    let mut code_i = 0;
+    let mut i = 1;
+    let mut code = 1;
+
+    for &byte in src {
+        if byte != 0 {
+            *dst.get_mut(i).ok_or(TelemetryError::BufferTooSmall)? = byte;
+            i += 1;
+            code += 1;
+        }
+
+        if byte == 0 || code == 0xff {
+            dst[code_i] = code;
+            code_i = i;
+            if code_i >= dst.len() {
+                return Err(TelemetryError::BufferTooSmall);
+            }
+            i += 1;
+            code = 1;
+        }
+    }
+
+    dst[code_i] = code;
+    Ok(i)
+}
+
+/// Decode COBS data in place, returning the decoded length. `buf` must not include the delimiter.
+pub fn cobs_decode_in_place(buf: &mut [u8]) -> Result<usize, TelemetryError> {
+    let mut read_i = 0;
+    let mut write_i = 0;
+
+    while read_i < buf.len() {
+        let code = buf[read_i] as usize;
+        if code == 0 {
+            return Err(TelemetryError::InvalidFrame);
+        }
+        read_i += 1;
+
+        let end = read_i + code - 1;
+        if end > buf.len() {
+            return Err(TelemetryError::InvalidFrame);
+        }
+
+        buf.copy_within(read_i..end, write_i);
+        write_i += code - 1;
+        read_i = end;
+
+        // Each block, except a full one, or the last, is followed by a zero.
+        if code != MAX_BLOCK + 1 && read_i < buf.len() {
+            buf[write_i] = 0;
+            write_i += 1;
+        }
+    }
+
+    Ok(write_i)
+}
+
+#[cfg(feature = "postcard")]
+/// Deserialize a packet received by `Decoder`.
+pub fn deserialize<'a, T: Deserialize<'a>>(packet: &'a [u8]) -> Result<T, TelemetryError> {
+    postcard::from_bytes(packet).map_err(|_| TelemetryError::Serialize)
+}
+
+/// A transport that frames are sent over.
+pub trait TelemetrySink {
+    /// Write as many bytes as the transport can currently accept, and return the number written.
+    /// Must not block. Returning 0 is backpressure; the rest is written on a later call.
+    fn write(&mut self, data: &[u8]) -> usize;
+}
+
+/// Allows using a closure as a sink, eg to wrap a USB serial port.
+impl<F: FnMut(&[u8]) -> usize> TelemetrySink for F {
+    fn write(&mut self, data: &[u8]) -> usize {
+        self(data)
+    }
+}
+
+/// A transport that frames are received from.
+pub trait TelemetrySource {
+    /// Read a received byte, if available. Must not block.
+    fn read_byte(&mut self) -> Option<u8>;
+}
+
+#[cfg(not(feature = "f4"))]
+/// Writes bytes while the transmit data register is empty.
+impl<R> TelemetrySink for Usart<R>
+where
+    R: core::ops::Deref<Target = crate::pac::usart1::RegisterBlock>
+        + crate::util::RccPeriph
+        + crate::util::BaudPeriph,
+{
+    fn write(&mut self, data: &[u8]) -> usize {
+        let mut written = 0;
+        for &byte in data {
+            if !self.check_status_flag(UsartInterrupt::TransmitEmpty) {
+                break;
+            }
+            self.write_one(byte);
+            written += 1;
+        }
+        written
+    }
+}
+
+#[cfg(not(feature = "f4"))]
+impl<R> TelemetrySource for Usart<R>
+where
+    R: core::ops::Deref<Target = crate::pac::usart1::RegisterBlock>
+        + crate::util::RccPeriph
+        + crate::util::BaudPeriph,
+{
+    fn read_byte(&mut self) -> Option<u8> {
+        if self.rx_ready() {
+            Some(self.read_one())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+/// Sends frames from a static buffer over a UART, using DMA. Reports the sink as full until the
+/// previous transfer completes.
+pub struct UartDmaSink<R> {
+    pub uart: Usart<R>,
+    buf: &'static mut [u8],
+    channel: DmaChannel,
+    channel_cfg: ChannelCfg,
+    dma_periph: DmaPeriph,
+    in_progress: bool,
+}
+
+#[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+impl<R> UartDmaSink<R>
+where
+    R: core::ops::Deref<Target = crate::pac::usart1::RegisterBlock>
+        + crate::util::RccPeriph
+        + crate::util::BaudPeriph,
+{
+    /// Create a sink. Bytes are copied to `buf` before each transfer; frames larger than it are
+    /// sent in several transfers. Set up the DMA mux for the UART's TX request first, where
+    /// applicable.
+    pub fn new(
+        uart: Usart<R>,
+        buf: &'static mut [u8],
+        channel: DmaChannel,
+        channel_cfg: ChannelCfg,
+        dma_periph: DmaPeriph,
+    ) -> Self {
+        Self {
+            uart,
+            buf,
+            channel,
+            channel_cfg,
+            dma_periph,
+            in_progress: false,
+        }
+    }
+
+    /// Returns `true` if a transfer is in progress. Stops the DMA channel if one has just completed.
+    pub fn busy(&mut self) -> bool {
+        if self.in_progress
+            && self
+                .uart
+                .check_status_flag(UsartInterrupt::TransmissionComplete)
+        {
+            dma::stop(self.dma_periph, self.channel);
+            self.in_progress = false;
+        }
+        self.in_progress
+    }
+}
+
+#[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+impl<R> TelemetrySink for UartDmaSink<R>
+where
+    R: core::ops::Deref<Target = crate::pac::usart1::RegisterBlock>
+        + crate::util::RccPeriph
+        + crate::util::BaudPeriph,
+{
+    fn write(&mut self, data: &[u8]) -> usize {
+        if self.busy() || data.is_empty() {
+            return 0;
+        }
+
+        let len = data.len().min(self.buf.len());
+        self.buf[..len].copy_from_slice(&data[..len]);
+
+        // The buffer is static, and isn't written again until the transfer completes.
+        unsafe {
+            self.uart.write_dma(
+                &self.buf[..len],
+                self.channel,
+                self.channel_cfg.clone(),
+                self.dma_periph,
+            );
+        }
+        self.in_progress = true;
+
+        len
+    }
+}
+
+/// Encodes packets into frames, and sends them over a sink. `N` is the frame buffer size; see
+/// `max_frame_len()`.
+pub struct Encoder<S, const N: usize> {
+    pub sink: S,
+    frame: [u8; N],
+    /// The number of bytes of the current frame written to the sink.
+    sent: usize,
+    /// The current frame's length; 0 if there isn't one.
+    len: usize,
+    /// The number of packets dropped, since the previous frame was still sending.
+    dropped: u32,
+}
+
+impl<S: TelemetrySink, const N: usize> Encoder<S, N> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            frame: [0; N],
+            sent: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Encode and queue a packet, and start sending it.
+    pub fn send(&mut self, packet: &[u8]) -> Result<(), TelemetryError> {
+        self.check_idle()?;
+
+        let len = cobs_encode(packet, &mut self.frame[..N.saturating_sub(1)])?;
+        self.frame[len] = DELIMITER;
+        self.queue(len + 1);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "postcard")]
+    /// Serialize, encode and queue a value, and start sending it.
+    pub fn send_value<T: Serialize>(&mut self, value: &T) -> Result<(), TelemetryError> {
+        self.check_idle()?;
+
+        // This includes the delimiter.
+        let len = postcard::to_slice_cobs(value, &mut self.frame)
+            .map_err(|e| match e {
+                postcard::Error::SerializeBufferFull => TelemetryError::BufferTooSmall,
+                _ => TelemetryError::Serialize,
+            })?
+            .len();
+        self.queue(len);
+
+        Ok(())
+    }
+
+    /// Write queued bytes to the sink. Returns `true` once the frame is fully sent, or if there
+    /// isn't one.
+    pub fn poll(&mut self) -> bool {
+        while self.sent < self.len {
+            let written = self.sink.write(&self.frame[self.sent..self.len]);
+            if written == 0 {
+                return false;
+            }
+            self.sent += written;
+        }
+
+        self.len = 0;
+        self.sent = 0;
+        true
+    }
+
+    /// Returns `true` if there is no frame queued.
+    pub fn is_idle(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of packets dropped, since the previous frame was still sending.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Discard the queued frame, eg if the host has disconnected.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.sent = 0;
+    }
+
+    /// Flush what we can, and reject the packet if the previous frame is still sending.
+    fn check_idle(&mut self) -> Result<(), TelemetryError> {
+        if !self.poll() {
+            self.dropped = self.dropped.wrapping_add(1);
+            return Err(TelemetryError::Busy);
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, len: usize) {
+        self.len = len;
+        self.sent = 0;
+        self.poll();
+    }
+}
+
+/// Reassembles frames from received bytes, and decodes them into packets. `N` is the maximum
+/// encoded frame length, not including the delimiter.
+pub struct Decoder<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// The current frame didn't fit; discard bytes until the next delimiter.
+    overflow: bool,
+}
+
+impl<const N: usize> Default for Decoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Decoder<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Process a received byte. Returns a packet when a frame is complete. Empty frames, eg from
+    /// repeated delimiters, are ignored.
+    pub fn feed(&mut self, byte: u8) -> Option<Result<&[u8], TelemetryError>> {
+        self.push(byte).map(|r| r.map(|len| &self.buf[..len]))
+    }
+
+    /// Read available bytes from a source, until a frame completes, or there are none left.
+    pub fn poll(
+        &mut self,
+        source: &mut impl TelemetrySource,
+    ) -> Option<Result<&[u8], TelemetryError>> {
+        loop {
+            let byte = source.read_byte()?;
+            if let Some(result) = self.push(byte) {
+                return Some(result.map(|len| &self.buf[..len]));
+            }
+        }
+    }
+
+    /// Discard a partially-received frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.overflow = false;
+    }
+
+    /// Process a byte, returning the decoded length when a frame completes.
+    fn push(&mut self, byte: u8) -> Option<Result<usize, TelemetryError>> {
+        if byte != DELIMITER {
+            if self.len < N {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let (len, overflow) = (self.len, self.overflow);
+        self.reset();
+
+        if overflow {
+            return Some(Err(TelemetryError::Overflow));
+        }
+        if len == 0 {
+            return None;
+        }
+
+        Some(cobs_decode_in_place(&mut self.buf[..len]))
+    }
+}
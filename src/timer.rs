@@ -41,11 +41,14 @@ use crate::pac::DMA as DMA1;
 use crate::pac::DMA1;
 // todo: LPTIM (low-power timers) and HRTIM (high-resolution timers). And Advanced control functionality
 use crate::{
-    clocks::Clocks,
+    clocks::{ClockFreqs, ClockListener},
+    debounce::TriggerTimer,
     instant::Instant,
     pac::{self, RCC},
     util::{rcc_en_reset, RccPeriph},
 };
+#[cfg(not(feature = "g0"))]
+use crate::timer_wheel::CompareTimer;
 
 // This `TICK_OVERFLOW_COUNT` must be incremented in firmware in the timer's update interrupt.
 pub static TICK_OVERFLOW_COUNT: AtomicU32 = AtomicU32::new(0);
@@ -152,6 +155,8 @@ pub enum MasterModeSelection {
 }
 
 /// Timer interrupt
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum TimerInterrupt {
     /// Update interrupt can be used for a timeout. DIER UIE to set, ... to clear
     Update,
@@ -180,7 +185,8 @@ pub enum TimerInterrupt {
 }
 
 /// Output alignment. Sets `TIMx_CR1` register, `CMS` field.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Alignment {
     /// Edge-aligned mode. The counter counts up or down depending on the direction bit
     /// (DIR).
@@ -211,7 +217,8 @@ pub enum TimChannel {
 
 /// Timer count direction. Defaults to `Up`.
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum CountDir {
     Up = 0,
     Down = 1,
@@ -312,7 +319,8 @@ pub enum OutputCompare {
 
 /// Update Request source. This bit is set and cleared by software to select the UEV event sources.
 /// Sets `TIMx_CR1` register, `URS` field.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum UpdateReqSrc {
     /// Any of the following events generate an update interrupt or DMA request.
@@ -327,7 +335,8 @@ pub enum UpdateReqSrc {
 
 /// Capture/Compaer DMA selection.
 /// Sets `TIMx_CR2` register, `CCDS` field.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum CaptureCompareDma {
     /// CCx DMA request sent when CCx event occur
@@ -337,7 +346,8 @@ pub enum CaptureCompareDma {
 }
 
 /// Initial configuration data for Timer peripherals.
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct TimerConfig {
     /// If `one_pulse_mode` is true, the counter stops counting at the next update event
     /// (clearing the bit CEN). If false, Counter is not stopped at update event. Defaults to false.
@@ -396,15 +406,16 @@ macro_rules! make_timer {
             paste! {
                 /// Initialize a DFSDM peripheral, including  enabling and resetting
                 /// its RCC peripheral clock.
-                pub fn [<new_ $tim>](regs: pac::$TIMX, freq: f32, cfg: TimerConfig, clocks: &Clocks) -> Self {
+                pub fn [<new_ $tim>](regs: pac::$TIMX, freq: f32, cfg: TimerConfig, clocks: impl Into<ClockFreqs>) -> Self {
                     let rcc = unsafe { &(*RCC::ptr()) };
 
                     // `freq` is in Hz.
                     rcc_en_reset!([<apb $apb>], $tim, rcc);
 
+                    let clocks = clocks.into();
                     let clock_speed = match $apb {
-                        1 => clocks.apb1_timer(),
-                        _ => clocks.apb2_timer(),
+                        1 => clocks.timer1,
+                        _ => clocks.timer2,
                     };
 
 
@@ -571,6 +582,28 @@ macro_rules! make_timer {
                 self.set_freq(1. / period)
             }
 
+            /// Update the timer clock speed, eg after changing clocks, and recalculate PSC and
+            /// ARR to keep the current frequency. Set duty cycles again after, since ARR may change.
+            pub fn update_clocks(&mut self, clocks: impl Into<ClockFreqs>) -> Result<(), ValueError> {
+                let clocks = clocks.into();
+                let period = self.period;
+
+                self.clock_speed = match $apb {
+                    1 => clocks.timer1,
+                    _ => clocks.timer2,
+                };
+
+                if period == 0. {
+                    return Ok(());
+                }
+
+                // `set_freq` doubles the frequency passed for center-aligned modes.
+                match self.cfg.alignment {
+                    Alignment::Edge => self.set_freq(1. / period),
+                    _ => self.set_freq(0.5 / period),
+                }
+            }
+
             /// Set the auto-reload register value. Used for adjusting frequency.
             pub fn set_auto_reload(&mut self, arr: u32) {
                 // todo: Could be u16 or u32 depending on timer resolution,
@@ -886,6 +919,13 @@ macro_rules! make_timer {
             }
         }
 
+        impl ClockListener for Timer<pac::$TIMX> {
+            /// Recalculate PSC and ARR to keep the current frequency.
+            fn clocks_changed(&mut self, freqs: &ClockFreqs) {
+                self.update_clocks(freqs).ok();
+            }
+        }
+
         #[cfg(feature = "monotonic")]
         impl Monotonic for Timer<pac::$TIMX> {
             type Instant = Instant;
@@ -1063,11 +1103,34 @@ macro_rules! make_timer {
     }
 }
 
+/// Lets a timer with capture/compare channels drive a `TimerWheel`. Not on G0, where `set_duty`
+/// isn't implemented yet.
+macro_rules! compare_timer {
+    ($TIMX:ident, $res:ident) => {
+        #[cfg(not(feature = "g0"))]
+        impl CompareTimer for Timer<pac::$TIMX> {
+            fn count(&self) -> u32 {
+                self.read_count()
+            }
+
+            fn max_count(&self) -> u32 {
+                self.get_max_duty() as u32
+            }
+
+            fn set_compare(&mut self, channel: TimChannel, value: u32) {
+                self.set_duty(channel, value as $res);
+            }
+        }
+    };
+}
+
 // We use macros to support the varying number of capture compare channels available on
 // different timers.
 // Note that there's lots of DRY between these implementations.
 macro_rules! cc_4_channels {
     ($TIMX:ident, $res:ident) => {
+        impl TriggerTimer for pac::$TIMX {}
+
         impl Timer<pac::$TIMX> {
             /// Function that allows us to set direction only on timers that have this option.
             pub fn set_dir(&mut self) {
@@ -1446,6 +1509,8 @@ macro_rules! cc_4_channels {
                 self.reinitialize();
             }
         }
+
+        compare_timer!($TIMX, $res);
     }
 }
 
@@ -1689,6 +1754,8 @@ macro_rules! cc_2_channels {
             }
 
         }
+
+        compare_timer!($TIMX, $res);
     }
 }
 
@@ -1894,6 +1961,8 @@ macro_rules! cc_1_channel {
                 self.reinitialize();
             }
         }
+
+        compare_timer!($TIMX, $res);
     }
 }
 
@@ -1961,13 +2030,12 @@ cfg_if! {
             pub fn new(
                 regs: R,
                 freq: f32,
-                clock_cfg: &Clocks,
+                clocks: impl Into<ClockFreqs>,
             ) -> Self {
                 let rcc = unsafe { &(*RCC::ptr()) };
                 R::en_reset(rcc);
 
-                // Self { regs, config, clock_speed: clocks.apb1_timer()  }
-                let mut result = Self { regs, clock_speed: clock_cfg.apb1_timer()  };
+                let mut result = Self { regs, clock_speed: clocks.into().timer1 };
 
                 result.set_freq(freq).ok();
                 result
@@ -1998,6 +2066,17 @@ cfg_if! {
                 self.set_freq(1. / time)
             }
 
+            /// Update the timer clock speed, eg after changing clocks, and recalculate PSC and
+            /// ARR to keep the current frequency.
+            pub fn update_clocks(&mut self, clocks: impl Into<ClockFreqs>) -> Result<(), ValueError> {
+                let psc = self.regs.psc.read().bits() as f32;
+                let arr = self.regs.arr.read().bits() as f32;
+                let freq = self.clock_speed as f32 / ((psc + 1.) * (arr + 1.));
+
+                self.clock_speed = clocks.into().timer1;
+                self.set_freq(freq)
+            }
+
             /// Set the timer frequency, in Hz. Overrides the period or frequency set
             /// in the constructor. If changing frequency frequently, don't use this method, as
             /// it has computational overhead: use `set_auto_reload` and `set_prescaler` methods instead.
@@ -2049,6 +2128,16 @@ cfg_if! {
                 self.regs.cr2.modify(|_, w| unsafe { w.mms().bits(mode as u8) });
             }
         }
+
+        impl<R> ClockListener for BasicTimer<R>
+            where
+                R: Deref<Target = pac::tim6::RegisterBlock> + RccPeriph,
+        {
+            /// Recalculate PSC and ARR to keep the current frequency.
+            fn clocks_changed(&mut self, freqs: &ClockFreqs) {
+                self.update_clocks(freqs).ok();
+            }
+        }
     }
 }
 
@@ -0,0 +1,264 @@
+//! Multiplexes many software timeouts onto a single hardware timer capture/compare channel.
+//! Timeouts may be one-shot or periodic, and notify by setting a flag, running a callback from
+//! the timer's interrupt handler, or both.
+//!
+//! The hardware timer runs freely, counting up from 0 to ARR, at the tick rate you choose with its
+//! prescaler. The wheel sets the compare channel to the nearest deadline; when it fires, run
+//! `service()` from the timer's interrupt handler. Timeouts longer than the counter's period are
+//! supported; the wheel wakes at least once per period to keep track of elapsed time. Other channels,
+//! and the update event, remain available, eg for PWM at the same period.
+//!
+//! Example:
+//! ```rust
+//! // 1Mhz tick rate, on an 80Mhz timer clock, with the counter using its full range.
+//! timer.set_prescaler(80 - 1);
+//! timer.set_auto_reload(u32::MAX);
+//! timer.enable_interrupt(TimerInterrupt::CaptureCompare1);
+//! timer.enable();
+//!
+//! static WHEEL: Mutex<RefCell<TimerWheel<8>>> =
+//!     Mutex::new(RefCell::new(TimerWheel::new(TimChannel::C1)));
+//!
+//! let blink = wheel.start(&mut timer, 500_000, TimeoutMode::Periodic, Some(toggle_led))?;
+//! let timeout = wheel.start(&mut timer, 20_000, TimeoutMode::OneShot, None)?;
+//!
+//! // In the timer interrupt handler:
+//! timer.clear_interrupt(TimerInterrupt::CaptureCompare1);
+//! wheel.service(&mut timer);
+//!
+//! // Elsewhere:
+//! if wheel.take_fired(timeout) { /* ... */ }
+//! ```
+//!
+//! The wheel isn't internally synchronized: If you use it both from the interrupt handler and
+//! from thread context, use it inside a critical section, eg with a `Mutex` as above.
+//!
+//! Callbacks run in the context of the call that finds their timeout expired. This is usually
+//! `service()`, in the interrupt handler, but `start()` and `cancel()` also account for elapsed
+//! time, so they may run callbacks of timeouts that expired since the last update, in the caller's
+//! context. Keep callbacks short, and safe to run from either.
+
+use crate::timer::TimChannel;
+
+/// A timer that can be used by the wheel. This is implemented for `Timer` on timers with
+/// capture/compare channels.
+pub trait CompareTimer {
+    /// The current counter value.
+    fn count(&self) -> u32;
+    /// The maximum counter value, ie the auto-reload value (ARR).
+    fn max_count(&self) -> u32;
+    /// Set a capture/compare channel's compare value (CCR).
+    fn set_compare(&mut self, channel: TimChannel, value: u32);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// Whether a timeout fires once, or repeatedly.
+pub enum TimeoutMode {
+    OneShot,
+    Periodic,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Errors associated with the timer wheel.
+pub enum WheelError {
+    /// All timeout slots are in use.
+    Full,
+    /// Timeouts must be at least 1 tick long.
+    ZeroDuration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Identifies a timeout started with `TimerWheel::start()`.
+pub struct TimeoutId(usize);
+
+#[derive(Clone, Copy)]
+struct Slot {
+    active: bool,
+    fired: bool,
+    mode: TimeoutMode,
+    /// The timeout's duration, in ticks. Used to reload periodic timeouts.
+    duration: u32,
+    /// Ticks until this timeout fires.
+    remaining: u32,
+    callback: Option<fn()>,
+}
+
+impl Slot {
+    const EMPTY: Self = Self {
+        active: false,
+        fired: false,
+        mode: TimeoutMode::OneShot,
+        duration: 0,
+        remaining: 0,
+        callback: None,
+    };
+}
+
+/// Holds up to `N` software timeouts, sharing one hardware timer compare channel.
+pub struct TimerWheel<const N: usize> {
+    slots: [Slot; N],
+    channel: TimChannel,
+    /// The counter value when elapsed time was last accounted for.
+    last_count: u32,
+}
+
+impl<const N: usize> TimerWheel<N> {
+    /// Create a timer wheel, using the hardware timer's compare channel specified. Set up and
+    /// enable the timer, and its compare interrupt for this channel, separately.
+    pub const fn new(channel: TimChannel) -> Self {
+        Self {
+            slots: [Slot::EMPTY; N],
+            channel,
+            last_count: 0,
+        }
+    }
+
+    /// Start a timeout, `ticks` from now. If `callback` is set, it's run when the timeout fires;
+    /// usually from `service()`, in the timer's interrupt handler. Regardless, the fired flag is set;
+    /// check it with `take_fired()`. This fires other timeouts that have expired since the last
+    /// update, running their callbacks in the caller's context.
+    pub fn start<T: CompareTimer>(
+        &mut self,
+        timer: &mut T,
+        ticks: u32,
+        mode: TimeoutMode,
+        callback: Option<fn()>,
+    ) -> Result<TimeoutId, WheelError> {
+        if ticks == 0 {
+            return Err(WheelError::ZeroDuration);
+        }
+
+        let i = self
+            .slots
+            .iter()
+            .position(|s| !s.active && !s.fired)
+            .ok_or(WheelError::Full)?;
+
+        // Account for time elapsed since the last update, so the other timeouts' remaining
+        // time, and this one's, use the same reference.
+        self.advance(timer);
+
+        self.slots[i] = Slot {
+            active: true,
+            fired: false,
+            mode,
+            duration: ticks,
+            remaining: ticks,
+            callback,
+        };
+
+        self.schedule(timer);
+        Ok(TimeoutId(i))
+    }
+
+    /// Stop a timeout, and clear its fired flag. This frees its slot. Like `start()`, this may fire
+    /// other expired timeouts, running their callbacks in the caller's context.
+    pub fn cancel<T: CompareTimer>(&mut self, timer: &mut T, id: TimeoutId) {
+        self.slots[id.0] = Slot::EMPTY;
+        self.schedule(timer);
+    }
+
+    /// Returns true if the timeout is running. One-shot timeouts stop running once fired.
+    pub fn is_active(&self, id: TimeoutId) -> bool {
+        self.slots[id.0].active
+    }
+
+    /// Returns true if the timeout fired since the last call, and clears its flag. For one-shot
+    /// timeouts, clearing the flag frees the slot.
+    pub fn take_fired(&mut self, id: TimeoutId) -> bool {
+        let slot = &mut self.slots[id.0];
+        let fired = slot.fired;
+        slot.fired = false;
+        fired
+    }
+
+    /// The number of ticks until the timeout fires, as of the last `service()` or `start()` call,
+    /// or `None` if it's not running.
+    pub fn ticks_remaining(&self, id: TimeoutId) -> Option<u32> {
+        let slot = &self.slots[id.0];
+        if slot.active {
+            Some(slot.remaining)
+        } else {
+            None
+        }
+    }
+
+    /// Fire expired timeouts, running their callbacks, and set the compare value for the next one.
+    /// Run this from the timer's interrupt handler, after clearing the compare interrupt flag.
+    pub fn service<T: CompareTimer>(&mut self, timer: &mut T) {
+        self.advance(timer);
+        self.schedule(timer);
+    }
+
+    /// Subtract the ticks elapsed since the last update from each timeout, firing those that expire.
+    fn advance<T: CompareTimer>(&mut self, timer: &T) {
+        let now = timer.count();
+        let elapsed = if now >= self.last_count {
+            now - self.last_count
+        } else {
+            timer.max_count() - self.last_count + now + 1
+        };
+        self.last_count = now;
+
+        for slot in self.slots.iter_mut().filter(|s| s.active) {
+            if slot.remaining > elapsed {
+                slot.remaining -= elapsed;
+                continue;
+            }
+
+            slot.fired = true;
+            match slot.mode {
+                TimeoutMode::OneShot => slot.active = false,
+                TimeoutMode::Periodic => {
+                    // Keep the period phase-locked, if we're servicing late.
+                    let late = elapsed - slot.remaining;
+                    slot.remaining = slot.duration - late % slot.duration;
+                }
+            }
+
+            if let Some(callback) = slot.callback {
+                callback();
+            }
+        }
+    }
+
+    /// Set the compare value to the nearest deadline. If there are no running timeouts (or the
+    /// nearest deadline is more than one counter period away), this schedules a wakeup one period away,
+    /// so elapsed time is still tracked.
+    fn schedule<T: CompareTimer>(&mut self, timer: &mut T) {
+        let max = timer.max_count();
+
+        loop {
+            let delay = self
+                .slots
+                .iter()
+                .filter(|s| s.active)
+                .map(|s| s.remaining)
+                .min()
+                .unwrap_or(max)
+                .min(max);
+
+            let target = self.last_count as u64 + delay as u64;
+            let target = if target > max as u64 {
+                target - max as u64 - 1
+            } else {
+                target
+            };
+            timer.set_compare(self.channel, target as u32);
+
+            // If the deadline passed while we were setting it, the compare won't match until the
+            // counter wraps; handle it now instead.
+            let now = timer.count();
+            let elapsed = if now >= self.last_count {
+                now - self.last_count
+            } else {
+                max - self.last_count + now + 1
+            };
+            if elapsed < delay {
+                break;
+            }
+            self.advance(timer);
+        }
+    }
+}
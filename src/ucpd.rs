@@ -0,0 +1,655 @@
+//! USB Type-C and Power Delivery PHY (UCPD), on STM32G0, G4, and L5. Handles CC line
+//! termination (Rp as a source, Rd as a sink) and attach detection, and the BMC-coded physical
+//! layer: ordered-set detection, and message send and receive. The peripheral generates and checks
+//! the CRC; messages passed to and from this module are the header and data objects only.
+//!
+//! This is the transport layer for a USB PD policy engine; it doesn't build or parse messages,
+//! or send GoodCRC replies. A sink must reply to each received message with GoodCRC within
+//! 195µs, so handle the `RxMessageEnd` interrupt promptly.
+//!
+//! UCPD is clocked by HSI16, which must be on; the default `UcpdConfig` assumes it.
+//!
+//! Example, as a sink that negotiates a higher voltage:
+//! ```rust
+//! let mut ucpd = Ucpd::new(dp.UCPD1, UcpdDevice::One, Default::default());
+//! ucpd.set_role(CcRole::Sink);
+//!
+//! // Wait for a source to attach; it pulls one of the CC lines up.
+//! let cc = loop {
+//!     if ucpd.cc_state(CcLine::Cc1) != CcState::Open {
+//!         break CcLine::Cc1;
+//!     }
+//!     if ucpd.cc_state(CcLine::Cc2) != CcState::Open {
+//!         break CcLine::Cc2;
+//!     }
+//! };
+//! ucpd.select_cc(cc);
+//! ucpd.enable_rx();
+//!
+//! let mut buf = [0; MAX_MESSAGE_LEN];
+//! if let Ok((OrderedSet::Sop, len)) = ucpd.receive(&mut buf) {
+//!     // Parse the Source Capabilities message, reply with GoodCRC, then send a Request.
+//!     ucpd.transmit(OrderedSet::Sop, &good_crc).ok();
+//! }
+//! ```
+
+use core::ops::Deref;
+
+use cfg_if::cfg_if;
+
+use crate::{
+    pac::{self, RCC},
+    util::RccPeriph,
+    MAX_ITERS,
+};
+
+/// The maximum PD message length, in bytes: A 2-byte header, and up to 7 4-byte data objects.
+/// Extended messages can be up to 262 bytes, in chunks of this size.
+pub const MAX_MESSAGE_LEN: usize = 30;
+
+// SYSCFG isn't included in all G0 PACs, so we access its CFGR1 register directly.
+#[cfg(feature = "g0")]
+const SYSCFG_CFGR1: usize = 0x4001_0000;
+
+// The G0B1 and G0C1 PACs suffix some register names with `r`.
+#[cfg(any(feature = "g0b1", feature = "g0c1"))]
+macro_rules! cfg1 {
+    ($regs:expr) => {
+        $regs.cfgr1
+    };
+}
+
+#[cfg(not(any(feature = "g0b1", feature = "g0c1")))]
+macro_rules! cfg1 {
+    ($regs:expr) => {
+        $regs.cfg1
+    };
+}
+
+#[cfg(any(feature = "g0b1", feature = "g0c1"))]
+macro_rules! tx_ordset {
+    ($regs:expr) => {
+        $regs.tx_ordsetr
+    };
+}
+
+#[cfg(not(any(feature = "g0b1", feature = "g0c1")))]
+macro_rules! tx_ordset {
+    ($regs:expr) => {
+        $regs.tx_ordset
+    };
+}
+
+#[cfg(any(feature = "g0b1", feature = "g0c1"))]
+macro_rules! tx_paysz {
+    ($regs:expr) => {
+        $regs.tx_payszr
+    };
+}
+
+#[cfg(not(any(feature = "g0b1", feature = "g0c1")))]
+macro_rules! tx_paysz {
+    ($regs:expr) => {
+        $regs.tx_paysz
+    };
+}
+
+#[cfg(any(feature = "g0b1", feature = "g0c1"))]
+macro_rules! rx_ordset {
+    ($regs:expr) => {
+        $regs.rx_ordsetr
+    };
+}
+
+#[cfg(not(any(feature = "g0b1", feature = "g0c1")))]
+macro_rules! rx_ordset {
+    ($regs:expr) => {
+        $regs.rx_ordset
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// UCPD errors.
+pub enum UcpdError {
+    /// Timed out waiting for the peripheral; when receiving, no message started.
+    Timeout,
+    /// The transmission was discarded, since a message was being received. (TXMSGDISC)
+    TxDiscarded,
+    /// The transmission was aborted, due to a hard reset, or an underrun. (TXMSGABT)
+    TxAborted,
+    /// The message is longer than the peripheral supports; 1024 bytes.
+    TxLen,
+    /// The received message failed its CRC check, or had a coding error. (RXERR)
+    Rx,
+    /// Data was received before the previous byte was read. (RXOVR)
+    RxOverrun,
+    /// The received message doesn't fit in the buffer provided.
+    BufferTooSmall,
+    /// A hard reset was received instead of a message. (RXHRSTDET)
+    HardReset,
+    /// The received ordered set isn't one this module handles; eg an SOP extension.
+    UnknownOrderedSet,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// Which UCPD peripheral this is. Used to select its dead battery control.
+pub enum UcpdDevice {
+    One,
+    #[cfg(feature = "g0")]
+    Two,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// A CC line.
+pub enum CcLine {
+    Cc1,
+    Cc2,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+/// Rp pull-up current, advertising the current this source can provide. Sets CR, ANASUBMODE.
+pub enum RpCurrent {
+    /// Default USB power. (80µA)
+    Default = 0b01,
+    /// 1.5A. (180µA)
+    Current1_5A = 0b10,
+    /// 3A. (330µA)
+    Current3A = 0b11,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+/// The Type-C role; sets the CC line termination.
+pub enum CcRole {
+    /// Present Rp on the CC lines, with the current advertised.
+    Source(RpCurrent),
+    /// Present Rd on the CC lines.
+    Sink,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// The voltage state of a CC line, decoded for the current role. (SR, TYPEC_VSTATE_CCx)
+pub enum CcState {
+    /// Nothing attached; or, as a source, an attached cable's Ra.
+    Open,
+    /// As a sink: a source advertising default USB power is attached.
+    SinkDefault,
+    /// As a sink: a source advertising 1.5A is attached.
+    Sink1_5A,
+    /// As a sink: a source advertising 3A is attached.
+    Sink3A,
+    /// As a source: a sink is attached.
+    SourceRd,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// A USB PD ordered set; marks the start of a packet, and who it's for.
+pub enum OrderedSet {
+    /// For the port partner.
+    Sop,
+    /// For the near cable plug.
+    SopPrime,
+    /// For the far cable plug.
+    SopDoublePrime,
+    SopPrimeDebug,
+    SopDoublePrimeDebug,
+    HardReset,
+    CableReset,
+}
+
+impl OrderedSet {
+    /// The K-code sequence written to TX_ORDSET. The first K-code sent is in the low bits.
+    fn tx_val(&self) -> u32 {
+        // RM: Sync-1, Sync-2, Sync-3, RST-1, and RST-2 K-codes.
+        const S1: u32 = 0b11000;
+        const S2: u32 = 0b10001;
+        const S3: u32 = 0b00110;
+        const R1: u32 = 0b00111;
+        const R2: u32 = 0b11001;
+
+        let (k0, k1, k2, k3) = match self {
+            Self::Sop => (S1, S1, S1, S2),
+            Self::SopPrime => (S1, S1, S3, S3),
+            Self::SopDoublePrime => (S1, S3, S1, S3),
+            Self::SopPrimeDebug => (S1, R2, R2, S3),
+            Self::SopDoublePrimeDebug => (S1, R2, S3, S2),
+            Self::HardReset => (R1, R1, R1, R2),
+            Self::CableReset => (R1, S1, R1, S3),
+        };
+        k0 | (k1 << 5) | (k2 << 10) | (k3 << 15)
+    }
+
+    /// Decode RX_ORDSET, RXORDSET.
+    fn from_rx(val: u8) -> Result<Self, UcpdError> {
+        match val {
+            0 => Ok(Self::Sop),
+            1 => Ok(Self::SopPrime),
+            2 => Ok(Self::SopDoublePrime),
+            3 => Ok(Self::SopPrimeDebug),
+            4 => Ok(Self::SopDoublePrimeDebug),
+            5 => Ok(Self::CableReset),
+            _ => Err(UcpdError::UnknownOrderedSet),
+        }
+    }
+
+    /// The RXORDSETEN bit that enables detecting this ordered set.
+    fn rx_enable_bit(&self) -> u16 {
+        match self {
+            Self::Sop => 1 << 0,
+            Self::SopPrime => 1 << 1,
+            Self::SopDoublePrime => 1 << 2,
+            Self::HardReset => 1 << 3,
+            Self::CableReset => 1 << 4,
+            Self::SopPrimeDebug => 1 << 5,
+            Self::SopDoublePrimeDebug => 1 << 6,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// UCPD interrupts. The value is the bit in IMR, SR, and ICR. Reference the UCPD_SR register.
+pub enum UcpdInterrupt {
+    /// TXDR is ready for the next byte.
+    TxDataReq = 0,
+    TxMessageDiscarded = 1,
+    TxMessageSent = 2,
+    TxMessageAborted = 3,
+    HardResetDiscarded = 4,
+    HardResetSent = 5,
+    TxUnderrun = 6,
+    /// RXDR has a byte to read.
+    RxNotEmpty = 8,
+    RxOrderedSetDetected = 9,
+    RxHardResetDetected = 10,
+    RxOverrun = 11,
+    /// A message was received; check for errors, and read it.
+    RxMessageEnd = 12,
+    /// The CC1 voltage state changed.
+    TypeCEventCc1 = 14,
+    /// The CC2 voltage state changed.
+    TypeCEventCc2 = 15,
+    FastRoleSwap = 20,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+/// Transmissions other than messages, and hard resets. Sets CR, TXMODE.
+pub enum SpecialTx {
+    CableReset = 0b01,
+    /// BIST carrier mode 2; a continuous test signal, until the peripheral is disabled.
+    BistCarrier2 = 0b10,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// Kernel clock prescaler. Sets CFG1, PSC_USBPDCLK.
+pub enum UcpdPrescaler {
+    Div1 = 0b000,
+    Div2 = 0b001,
+    Div4 = 0b010,
+    Div8 = 0b011,
+    Div16 = 0b100,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Configuration for UCPD. Can be used with `Default::default()`, which sets up a 286kbps bit
+/// rate (the allowed range is 270 - 330kbps), from HSI16.
+pub struct UcpdConfig {
+    /// Divides HSI16 to the UCPD clock; this should be 6 - 18Mhz. Defaults to 2, for 8Mhz.
+    pub prescaler: UcpdPrescaler,
+    /// Divides the UCPD clock to the half-bit clock; the register value, ie the division is this
+    /// plus 1. Defaults to 13, for a 571kHz half-bit clock.
+    pub half_bit_div: u8,
+    /// Divides the UCPD clock to the interframe gap timer clock; minus 1. Defaults to 16.
+    pub interframe_gap: u8,
+    /// The transition window, used to detect the end of a packet, in half-bit clock cycles;
+    /// minus 1. Must be at least 12µs. Defaults to 7. (14µs)
+    pub transition_window: u8,
+    /// Ordered sets to detect. Hard reset is always detected. Defaults to SOP only, as for a sink
+    /// that doesn't talk to cable plugs.
+    pub rx_ordered_sets: &'static [OrderedSet],
+}
+
+impl Default for UcpdConfig {
+    fn default() -> Self {
+        Self {
+            prescaler: UcpdPrescaler::Div2,
+            half_bit_div: 13,
+            interframe_gap: 16,
+            transition_window: 7,
+            rx_ordered_sets: &[OrderedSet::Sop],
+        }
+    }
+}
+
+/// Represents a USB Type-C and Power Delivery (UCPD) peripheral.
+pub struct Ucpd<R> {
+    pub regs: R,
+    pub cfg: UcpdConfig,
+    pub device: UcpdDevice,
+    role: CcRole,
+}
+
+impl<R> Ucpd<R>
+where
+    R: Deref<Target = pac::ucpd1::RegisterBlock> + RccPeriph,
+{
+    /// Initialize a UCPD peripheral, including enabling and resetting its RCC peripheral clock.
+    /// The CC lines are left with their dead battery (Rd) termination until `set_role` is called.
+    pub fn new(regs: R, device: UcpdDevice, cfg: UcpdConfig) -> Self {
+        let rcc = unsafe { &(*RCC::ptr()) };
+        R::en_reset(rcc);
+
+        let rx_ordsets = cfg
+            .rx_ordered_sets
+            .iter()
+            .fold(OrderedSet::HardReset.rx_enable_bit(), |acc, o| {
+                acc | o.rx_enable_bit()
+            });
+
+        // CFG1 can only be written while the peripheral is disabled.
+        cfg1!(regs).write(|w| unsafe {
+            w.psc_usbpdclk().bits(cfg.prescaler as u8);
+            w.hbitclkdiv().bits(cfg.half_bit_div);
+            w.ifrgap().bits(cfg.interframe_gap);
+            w.transwin().bits(cfg.transition_window);
+            w.rxordseten().bits(rx_ordsets)
+        });
+        cfg1!(regs).modify(|_, w| w.ucpden().set_bit());
+
+        Self {
+            regs,
+            cfg,
+            device,
+            role: CcRole::Sink,
+        }
+    }
+
+    /// Set the Type-C role, and enable the CC line terminations for it; both lines, so attach can
+    /// be detected on either. Also disables the dead battery termination, which otherwise
+    /// presents Rd regardless of this setting.
+    pub fn set_role(&mut self, role: CcRole) {
+        self.regs.cr.modify(|_, w| unsafe {
+            match role {
+                CcRole::Source(current) => {
+                    w.anamode().clear_bit();
+                    w.anasubmode().bits(current as u8);
+                }
+                CcRole::Sink => {
+                    w.anamode().set_bit();
+                }
+            }
+            w.ccenable().bits(0b11)
+        });
+        self.role = role;
+
+        self.disable_dead_battery();
+    }
+
+    /// Disable the dead battery Rd termination, so the CC lines are controlled by this peripheral.
+    fn disable_dead_battery(&mut self) {
+        cfg_if! {
+            if #[cfg(feature = "g0")] {
+                let bit = match self.device {
+                    UcpdDevice::One => 9,
+                    UcpdDevice::Two => 10,
+                };
+                // SYSCFG_CFGR1, UCPDx_STROBE.
+                unsafe {
+                    let reg = SYSCFG_CFGR1 as *mut u32;
+                    core::ptr::write_volatile(reg, core::ptr::read_volatile(reg) | (1 << bit));
+                }
+            } else if #[cfg(feature = "g4")] {
+                let pwr = unsafe { &(*pac::PWR::ptr()) };
+                pwr.cr3.modify(|_, w| w.ucpd1_dbdis().set_bit());
+            } else {
+                let pwr = unsafe { &(*pac::PWR::ptr()) };
+                pwr.cr3.modify(|_, w| w.ucpd_dbdis().set_bit());
+            }
+        }
+    }
+
+    /// Read the voltage state of a CC line, and decode it for the current role.
+    pub fn cc_state(&self, line: CcLine) -> CcState {
+        let sr = self.regs.sr.read();
+        let val = match line {
+            CcLine::Cc1 => sr.typec_vstate_cc1().bits(),
+            CcLine::Cc2 => sr.typec_vstate_cc2().bits(),
+        };
+
+        match self.role {
+            CcRole::Sink => match val {
+                1 => CcState::SinkDefault,
+                2 => CcState::Sink1_5A,
+                3 => CcState::Sink3A,
+                _ => CcState::Open,
+            },
+            // vRa, or vOPEN.
+            CcRole::Source(_) => match val {
+                1 => CcState::SourceRd,
+                _ => CcState::Open,
+            },
+        }
+    }
+
+    /// Select the CC line used for PD communication, once attached; the one the port partner
+    /// terminates. Sets CR, PHYCCSEL.
+    pub fn select_cc(&mut self, line: CcLine) {
+        self.regs
+            .cr
+            .modify(|_, w| w.phyccsel().bit(line == CcLine::Cc2));
+    }
+
+    /// Enable the BMC receiver, on the CC line set with `select_cc`.
+    pub fn enable_rx(&mut self) {
+        self.regs.cr.modify(|_, w| w.phyrxen().set_bit());
+    }
+
+    /// Disable the BMC receiver; eg after detach.
+    pub fn disable_rx(&mut self) {
+        self.regs.cr.modify(|_, w| w.phyrxen().clear_bit());
+    }
+
+    /// Send a message, blocking until it's sent. `data` is the message header and data objects;
+    /// the peripheral adds the preamble, ordered set, CRC, and EOP.
+    pub fn transmit(&mut self, ordered_set: OrderedSet, data: &[u8]) -> Result<(), UcpdError> {
+        if data.len() > 1_023 {
+            return Err(UcpdError::TxLen);
+        }
+
+        self.regs.icr.write(|w| {
+            w.txmsgsentcf().set_bit();
+            w.txmsgdisccf().set_bit();
+            w.txmsgabtcf().set_bit();
+            w.txundcf().set_bit()
+        });
+
+        tx_ordset!(self.regs).write(|w| unsafe { w.txordset().bits(ordered_set.tx_val()) });
+        tx_paysz!(self.regs).write(|w| unsafe { w.txpaysz().bits(data.len() as u16) });
+
+        // TXMODE = 0: Transmission of TX_ORDSET and the payload.
+        self.regs.cr.modify(|_, w| unsafe {
+            w.txmode().bits(0);
+            w.txsend().set_bit()
+        });
+
+        for byte in data {
+            wait(|| {
+                let sr = self.regs.sr.read();
+                sr.txis().bit_is_set() || sr.txmsgdisc().bit_is_set() || sr.txmsgabt().bit_is_set()
+            })?;
+            self.check_tx_errors()?;
+
+            self.regs.txdr.write(|w| unsafe { w.txdata().bits(*byte) });
+        }
+
+        wait(|| {
+            let sr = self.regs.sr.read();
+            sr.txmsgsent().bit_is_set() || sr.txmsgdisc().bit_is_set() || sr.txmsgabt().bit_is_set()
+        })?;
+        self.check_tx_errors()?;
+
+        self.regs.icr.write(|w| w.txmsgsentcf().set_bit());
+        Ok(())
+    }
+
+    /// Check for, and clear, a discarded or aborted transmission.
+    fn check_tx_errors(&mut self) -> Result<(), UcpdError> {
+        let sr = self.regs.sr.read();
+        if sr.txmsgdisc().bit_is_set() {
+            self.regs.icr.write(|w| w.txmsgdisccf().set_bit());
+            Err(UcpdError::TxDiscarded)
+        } else if sr.txmsgabt().bit_is_set() {
+            self.regs.icr.write(|w| {
+                w.txmsgabtcf().set_bit();
+                w.txundcf().set_bit()
+            });
+            Err(UcpdError::TxAborted)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send a hard reset, blocking until it's sent. Sets CR, TXHRST.
+    pub fn send_hard_reset(&mut self) -> Result<(), UcpdError> {
+        self.regs.icr.write(|w| {
+            w.hrstsentcf().set_bit();
+            w.hrstdisccf().set_bit()
+        });
+        self.regs.cr.modify(|_, w| w.txhrst().set_bit());
+
+        wait(|| {
+            let sr = self.regs.sr.read();
+            sr.hrstsent().bit_is_set() || sr.hrstdisc().bit_is_set()
+        })?;
+
+        let discarded = self.regs.sr.read().hrstdisc().bit_is_set();
+        self.regs.icr.write(|w| {
+            w.hrstsentcf().set_bit();
+            w.hrstdisccf().set_bit()
+        });
+
+        if discarded {
+            Err(UcpdError::TxDiscarded)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send a cable reset, or a BIST carrier mode 2 test signal. Blocks until sent.
+    pub fn transmit_special(&mut self, mode: SpecialTx) -> Result<(), UcpdError> {
+        self.regs.icr.write(|w| w.txmsgsentcf().set_bit());
+        self.regs.cr.modify(|_, w| unsafe {
+            w.txmode().bits(mode as u8);
+            w.txsend().set_bit()
+        });
+
+        wait(|| {
+            let sr = self.regs.sr.read();
+            sr.txmsgsent().bit_is_set() || sr.txmsgdisc().bit_is_set() || sr.txmsgabt().bit_is_set()
+        })?;
+        self.check_tx_errors()?;
+
+        self.regs.icr.write(|w| w.txmsgsentcf().set_bit());
+        Ok(())
+    }
+
+    /// Receive a message, blocking until it's complete. Returns the ordered set it started with,
+    /// and its length in bytes, excluding the CRC. Returns `Timeout` if no message starts,
+    /// so this can be polled, or run from the `RxOrderedSetDetected` interrupt.
+    pub fn receive(&mut self, buf: &mut [u8]) -> Result<(OrderedSet, usize), UcpdError> {
+        wait(|| {
+            let sr = self.regs.sr.read();
+            sr.rxorddet().bit_is_set() || sr.rxhrstdet().bit_is_set()
+        })?;
+
+        if self.regs.sr.read().rxhrstdet().bit_is_set() {
+            self.regs.icr.write(|w| w.rxhrstdetcf().set_bit());
+            return Err(UcpdError::HardReset);
+        }
+
+        let ordered_set = rx_ordset!(self.regs).read().rxordset().bits();
+        self.regs.icr.write(|w| w.rxorddetcf().set_bit());
+
+        let mut i = 0;
+        let mut too_small = false;
+
+        loop {
+            wait(|| {
+                let sr = self.regs.sr.read();
+                sr.rxne().bit_is_set() || sr.rxmsgend().bit_is_set()
+            })?;
+
+            // RXMSGEND is set, and there's no data left to read.
+            if self.regs.sr.read().rxne().bit_is_clear() {
+                break;
+            }
+
+            let byte = self.regs.rxdr.read().rxdata().bits();
+            if i < buf.len() {
+                buf[i] = byte;
+                i += 1;
+            } else {
+                too_small = true;
+            }
+        }
+
+        let sr = self.regs.sr.read();
+        self.regs.icr.write(|w| {
+            w.rxmsgendcf().set_bit();
+            w.rxovrcf().set_bit()
+        });
+
+        if sr.rxerr().bit_is_set() {
+            return Err(UcpdError::Rx);
+        }
+        if sr.rxovr().bit_is_set() {
+            return Err(UcpdError::RxOverrun);
+        }
+        if too_small {
+            return Err(UcpdError::BufferTooSmall);
+        }
+
+        Ok((OrderedSet::from_rx(ordered_set)?, i))
+    }
+
+    /// Enable a specific type of UCPD interrupt.
+    pub fn enable_interrupt(&mut self, interrupt: UcpdInterrupt) {
+        self.regs
+            .imr
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << interrupt as u8)) });
+    }
+
+    /// Disable a specific type of UCPD interrupt.
+    pub fn disable_interrupt(&mut self, interrupt: UcpdInterrupt) {
+        self.regs
+            .imr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(1 << interrupt as u8)) });
+    }
+
+    /// Clear an interrupt flag - run this in the interrupt's handler to prevent
+    /// repeat firings. `TxDataReq` and `RxNotEmpty` are cleared by writing TXDR, and reading
+    /// RXDR respectively.
+    pub fn clear_interrupt(&mut self, interrupt: UcpdInterrupt) {
+        self.regs
+            .icr
+            .write(|w| unsafe { w.bits(1 << interrupt as u8) });
+    }
+}
+
+fn wait(done: impl Fn() -> bool) -> Result<(), UcpdError> {
+    let mut i = 0;
+    while !done() {
+        i += 1;
+        if i >= MAX_ITERS {
+            return Err(UcpdError::Timeout);
+        }
+    }
+    Ok(())
+}
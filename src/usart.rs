@@ -7,7 +7,7 @@
 
 // todo: Missing some features (like additional interrupts) on the USARTv3 peripheral . (L5, G etc)
 
-use core::ops::Deref;
+use core::{fmt, ops::Deref};
 
 use cfg_if::cfg_if;
 
@@ -18,24 +18,32 @@ use cfg_if::cfg_if;
 // };
 // #[cfg(feature = "embedded_hal")]
 // use nb;
+#[cfg(not(feature = "h5"))]
+use crate::af_pins::UsartPins;
 #[cfg(any(feature = "f3", feature = "l4"))]
 use crate::dma::DmaInput;
-#[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
-use crate::dma::{self, ChannelCfg, DmaChannel};
 #[cfg(feature = "g0")]
 use crate::pac::DMA as DMA1;
 #[cfg(not(any(feature = "g0", feature = "h5")))]
 use crate::pac::DMA1;
+#[cfg(feature = "defmt")]
+use crate::util::format_flags;
 use crate::{
-    clocks::Clocks,
+    clocks::{ClockFreqs, ClockListener},
+    instant::Timeout,
     pac::{self, RCC},
-    util::{BaudPeriph, RccPeriph},
-    MAX_ITERS,
+    util::{fmt_flags, BaudPeriph, RccPeriph},
+};
+#[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+use crate::{
+    dma::{self, ChannelCfg, DmaChannel},
+    dma_route,
 };
 
 // todo: Prescaler (USART_PRESC) register on v3 (L5, G, H etc)
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// The number of stop bits. (USART_CR2, STOP)
 pub enum StopBits {
@@ -45,7 +53,8 @@ pub enum StopBits {
     S1_5 = 0b11,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// Parity control enable/disable, and even/odd selection (USART_CR1, PCE and PS)
 pub enum Parity {
     EnabledEven,
@@ -53,7 +62,8 @@ pub enum Parity {
     Disabled,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// The length of word to transmit and receive. (USART_CR1, M)
 pub enum WordLen {
     W8,
@@ -73,7 +83,8 @@ impl WordLen {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 /// Set Oversampling16 or Oversampling8 modes.
 pub enum OverSampling {
@@ -81,7 +92,8 @@ pub enum OverSampling {
     O8 = 1,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum IrdaMode {
     /// "IrDA mode disabled
     None,
@@ -93,7 +105,8 @@ pub enum IrdaMode {
 
 /// Serial error
 #[non_exhaustive]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum UartError {
     /// Framing error
     Framing,
@@ -104,10 +117,75 @@ pub enum UartError {
     /// Parity check error
     Parity,
     Hardware,
+    /// A configuration register didn't read back as written; eg the peripheral clock isn't
+    /// enabled, or the register is write-protected.
+    VerifyFailed,
+}
+
+/// A U[S]ART interrupt and status register (ISR) value, from `Usart::status()`; SR on F4. Its
+/// `Debug` and `defmt::Format` implementations list the flags that are set, eg "FE + ORE", for
+/// logging.
+#[derive(Clone, Copy, PartialEq)]
+pub struct UsartStatus(pub u32);
+
+impl UsartStatus {
+    #[cfg(not(feature = "f4"))]
+    const FLAGS: &'static [(u8, &'static str)] = &[
+        (0, "PE"),
+        (1, "FE"),
+        (2, "NE"),
+        (3, "ORE"),
+        (4, "IDLE"),
+        (5, "RXNE"),
+        (6, "TC"),
+        (7, "TXE"),
+        (8, "LBDF"),
+        (9, "CTSIF"),
+        (10, "CTS"),
+        (11, "RTOF"),
+        (12, "EOBF"),
+        (14, "ABRE"),
+        (15, "ABRF"),
+        (16, "BUSY"),
+        (17, "CMF"),
+        (18, "SBKF"),
+        (19, "RWU"),
+        (20, "WUF"),
+        (21, "TEACK"),
+        (22, "REACK"),
+    ];
+
+    #[cfg(feature = "f4")]
+    const FLAGS: &'static [(u8, &'static str)] = &[
+        (0, "PE"),
+        (1, "FE"),
+        (2, "NF"),
+        (3, "ORE"),
+        (4, "IDLE"),
+        (5, "RXNE"),
+        (6, "TC"),
+        (7, "TXE"),
+        (8, "LBD"),
+        (9, "CTS"),
+    ];
+}
+
+impl fmt::Debug for UsartStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_flags(self.0, Self::FLAGS, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for UsartStatus {
+    fn format(&self, f: defmt::Formatter) {
+        format_flags(self.0, Self::FLAGS, f)
+    }
 }
 
 #[cfg(not(feature = "f4"))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 /// The type of USART interrupt to configure. Reference the USART_ISR register.
 pub enum UsartInterrupt {
     /// If the inner value of this is `Some`, its inner value will set
@@ -130,6 +208,8 @@ pub enum UsartInterrupt {
 }
 
 /// Configuration for Usart. Can be used with default::Default.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct UsartConfig {
     /// Word length. Defaults to 8-bits.
     pub word_len: WordLen,
@@ -211,8 +291,9 @@ where
     R: Deref<Target = pac::usart1::RegisterBlock> + RccPeriph + BaudPeriph,
 {
     /// Initialize a U[s]ART peripheral, including configuration register writes, and enabling and
-    /// resetting its RCC peripheral clock. `baud` is the baud rate, in bytes-per-second.
-    pub fn new(regs: R, baud: u32, config: UsartConfig, clock_cfg: &Clocks) -> Self {
+    /// resetting its RCC peripheral clock. `baud` is the baud rate, in bytes-per-second. `clocks` is
+    /// the result of `Clocks::freeze()`, or `&clock_cfg`.
+    pub fn new(regs: R, baud: u32, config: UsartConfig, clocks: impl Into<ClockFreqs>) -> Self {
         let rcc = unsafe { &(*RCC::ptr()) };
         R::en_reset(rcc);
 
@@ -265,7 +346,7 @@ where
             .modify(|_, w| w.fifoen().bit(result.config.fifo_enabled));
 
         // 2. Select the desired baud rate using the USART_BRR register.
-        result.set_baud(baud, clock_cfg).ok();
+        result.set_baud(baud, clocks).ok();
         // 3. Program the number of stop bits in USART_CR2.
         result
             .regs
@@ -312,10 +393,94 @@ where
         result
     }
 
+    /// Initialize a U[s]ART peripheral as with `new()`, then check that its configuration registers
+    /// read back as written. Returns `UartError::VerifyFailed` if they don't.
+    pub fn try_new(
+        regs: R,
+        baud: u32,
+        config: UsartConfig,
+        clocks: impl Into<ClockFreqs>,
+    ) -> Result<Self, UartError> {
+        let result = Self::new(regs, baud, config, clocks);
+        result.verify_config()?;
+        Ok(result)
+    }
+
+    /// Initialize a U[S]ART peripheral as with `new()`, after setting its TX and RX pins to their
+    /// alternate functions. The pins are checked against this peripheral's AF table at compile
+    /// time; eg `(PA9, PA10)` for USART1. See the `af_pins` module.
+    #[cfg(not(feature = "h5"))]
+    pub fn new_with_pins(
+        regs: R,
+        pins: impl UsartPins<R>,
+        baud: u32,
+        config: UsartConfig,
+        clocks: impl Into<ClockFreqs>,
+    ) -> Self {
+        pins.connect();
+        Self::new(regs, baud, config, clocks)
+    }
+
+    /// Read the interrupt and status register (ISR; SR on F4), eg to log it after an error.
+    pub fn status(&self) -> UsartStatus {
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                UsartStatus(self.regs.sr.read().bits())
+            } else {
+                UsartStatus(isr!(self.regs).read().bits())
+            }
+        }
+    }
+
+    /// Read back the configuration registers set by `new()`, and compare them to the stored
+    /// configuration. This can be run periodically, eg as part of a self-test, to detect registers
+    /// that have been corrupted, or reset.
+    pub fn verify_config(&self) -> Result<(), UartError> {
+        let cr1 = cr1!(self.regs).read();
+
+        let mut ok = cr1.ue().bit_is_set()
+            && cr1.te().bit_is_set()
+            && cr1.re().bit_is_set()
+            && cr1.over8().bit_is_set() == (self.config.oversampling as u8 != 0)
+            && cr1.pce().bit_is_set() == (self.config.parity != Parity::Disabled);
+
+        if self.config.parity != Parity::Disabled {
+            ok &= cr1.ps().bit_is_set() == (self.config.parity == Parity::EnabledOdd);
+        }
+
+        // IrDA mode requires STOP to be cleared; `new()` overrides the configured value.
+        let stop_bits = match self.config.irda_mode {
+            IrdaMode::None => self.config.stop_bits as u8,
+            _ => 0,
+        };
+        ok &= self.regs.cr2.read().stop().bits() == stop_bits;
+
+        #[cfg(not(feature = "f4"))]
+        {
+            ok &= self.regs.cr3.read().ovrdis().bit_is_set() == self.config.overrun_disabled;
+        }
+
+        // A BRR of 0 is invalid, and is what we read if the peripheral clock isn't enabled.
+        ok &= self.regs.brr.read().bits() != 0;
+
+        if ok {
+            Ok(())
+        } else {
+            Err(UartError::VerifyFailed)
+        }
+    }
+
     /// Enable this U[s]ART peripheral.
     pub fn enable(&mut self) {
         cr1!(self.regs).modify(|_, w| w.ue().set_bit());
-        while cr1!(self.regs).read().ue().bit_is_clear() {}
+        // This times out instead of hanging if the peripheral clock isn't enabled; `verify_config()`
+        // detects this case.
+        let mut timeout = Timeout::new();
+        while cr1!(self.regs).read().ue().bit_is_clear() {
+            if timeout.expired() {
+                break;
+            }
+        }
     }
 
     /// Disable this U[s]ART peripheral.
@@ -325,26 +490,23 @@ where
     }
 
     /// Set the BAUD rate. Called during init, and can be called later to change BAUD
-    /// during program execution.
-    pub fn set_baud(&mut self, baud: u32, clock_cfg: &Clocks) -> Result<(), UartError> {
+    /// during program execution. Uses the U[S]ART kernel clock from `clocks`, so pass updated
+    /// frequencies to this after changing clocks.
+    pub fn set_baud(&mut self, baud: u32, clocks: impl Into<ClockFreqs>) -> Result<(), UartError> {
         let originally_enabled = cr1!(self.regs).read().ue().bit_is_set();
 
         if originally_enabled {
             cr1!(self.regs).modify(|_, w| w.ue().clear_bit());
-            let mut i = 0;
+            let mut timeout = Timeout::new();
             while cr1!(self.regs).read().ue().bit_is_set() {
-                i += 1;
-                if i >= MAX_ITERS {
+                if timeout.expired() {
                     return Err(UartError::Hardware);
                 }
             }
         }
 
         // To set BAUD rate, see L4 RM section 38.5.4: "USART baud rate generation".
-        // todo: This assumes the USART clock is APB1 or 2 depending on which USART.
-        // todo: Take into account the selectable USART clock in both
-        // todo util::baud implementation, and `clocks` module.
-        let fclk = R::baud(clock_cfg);
+        let fclk = R::baud(&clocks.into());
 
         let usart_div = match self.config.oversampling {
             OverSampling::O16 => fclk / baud,
@@ -381,12 +543,11 @@ where
         cfg_if! {
             if #[cfg(not(feature = "f4"))] {
                 for word in data {
-                    let mut i = 0;
+                    let mut timeout = Timeout::new();
 
                     #[cfg(feature = "h5")]
                     while isr!(self.regs).read().txfe().bit_is_clear() {
-                        i += 1;
-                        if i >= MAX_ITERS {
+                        if timeout.expired() {
                             // return Err(UartError::Hardware);
                         }
                     }
@@ -395,8 +556,7 @@ where
                     // Note: Per these PACs, TXFNF and TXE are on the same field, so this is actually
                     // checking txfnf if the fifo is enabled.
                     while isr!(self.regs).read().txe().bit_is_clear() {
-                        i += 1;
-                        if i >= MAX_ITERS {
+                        if timeout.expired() {
                             return Err(UartError::Hardware);
                         }
                     }
@@ -409,19 +569,17 @@ where
                 // that the transmission of the last frame is complete. This is required for instance when
                 // the USART is disabled or enters the Halt mode to avoid corrupting the last
                 // transmission
-                let mut i = 0;
+                let mut timeout = Timeout::new();
                 while isr!(self.regs).read().tc().bit_is_clear() {
-                        i += 1;
-                        if i >= MAX_ITERS {
+                        if timeout.expired() {
                             return Err(UartError::Hardware);
                         }
                 }
             } else {
                 for word in data {
-                    let mut i = 0;
+                    let mut timeout = Timeout::new();
                     while self.regs.sr.read().txe().bit_is_clear() {
-                        i += 1;
-                        if i >= MAX_ITERS {
+                        if timeout.expired() {
                             return Err(UartError::Hardware);
                         }
                     }
@@ -430,10 +588,9 @@ where
                         .modify(|_, w| unsafe { w.dr().bits(*word as u16) });
 
                 }
-                let mut i = 0;
+                let mut timeout = Timeout::new();
                 while self.regs.sr.read().tc().bit_is_clear() {
-                                            i += 1;
-                        if i >= MAX_ITERS {
+                        if timeout.expired() {
                             return Err(UartError::Hardware);
                         }
                 }
@@ -464,23 +621,21 @@ where
     /// Receive data into a u8 buffer. See L44 RM, section 38.5.3: "Character reception procedure"
     pub fn read(&mut self, buf: &mut [u8]) -> Result<(), UartError> {
         for i in 0..buf.len() {
-            let mut i_ = 0;
+            let mut timeout = Timeout::new();
             cfg_if! {
                 if #[cfg(not(feature = "f4"))] {
                     // Wait for the next bit
 
                     #[cfg(feature = "h5")]
                     while isr!(self.regs).read().rxfne().bit_is_clear() {
-                        i_ += 1;
-                        if i_ >= MAX_ITERS {
+                        if timeout.expired() {
                             return Err(UartError::Hardware);
                         }
                     }
 
                     #[cfg(not(feature = "h5"))]
                     while isr!(self.regs).read().rxne().bit_is_clear() {
-                        i_ += 1;
-                        if i_ >= MAX_ITERS {
+                        if timeout.expired() {
                             return Err(UartError::Hardware);
                         }
                     }
@@ -488,8 +643,7 @@ where
                     buf[i] = self.regs.rdr.read().rdr().bits() as u8;
                 } else {
                     while self.regs.sr.read().rxne().bit_is_clear() {
-                        i_ += 1;
-                        if i_ >= MAX_ITERS {
+                        if timeout.expired() {
                             return Err(UartError::Hardware);
                         }
                     }
@@ -515,6 +669,19 @@ where
         Ok(())
     }
 
+    /// Returns true if a received word is available, eg to read with `read_one()`. Reads the RXNE flag.
+    pub fn rx_ready(&self) -> bool {
+        cfg_if! {
+            if #[cfg(feature = "f4")] {
+                self.regs.sr.read().rxne().bit_is_set()
+            } else if #[cfg(feature = "h5")] {
+                isr!(self.regs).read().rxfne().bit_is_set()
+            } else {
+                isr!(self.regs).read().rxne().bit_is_set()
+            }
+        }
+    }
+
     /// Read a single word, without waiting  until ready for the next. Compared to the `read()` function, this
     /// does not block.
     pub fn read_one(&mut self) -> u8 {
@@ -527,6 +694,23 @@ where
         }
     }
 
+    #[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+    /// Enable a DMA request on each received byte, and return the receive data register, for use as
+    /// the source of a DMA route. (See the `dma_route` module). `request` is this U[S]ART's RX input.
+    pub fn dma_source(&mut self, request: dma::DmaInput) -> dma_route::Endpoint<u8> {
+        self.regs.cr3.modify(|_, w| w.dmar().set_bit());
+        dma_route::Endpoint::register(&self.regs.rdr as *const _ as u32, request)
+    }
+
+    #[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
+    /// Enable a DMA request when the transmit data register is empty, and return it, for use as the
+    /// destination of a DMA route. (See the `dma_route` module). `request` is this U[S]ART's TX
+    /// input.
+    pub fn dma_sink(&mut self, request: dma::DmaInput) -> dma_route::Endpoint<u8> {
+        self.regs.cr3.modify(|_, w| w.dmat().set_bit());
+        dma_route::Endpoint::register(&self.regs.tdr as *const _ as u32, request)
+    }
+
     #[cfg(not(any(feature = "f4", feature = "l552", feature = "h5")))]
     /// Transmit data using DMA. (L44 RM, section 38.5.15)
     /// Note that the `channel` argument is unused on F3 and L4, since it is hard-coded,
@@ -916,6 +1100,16 @@ where
     }
 }
 
+impl<R> ClockListener for Usart<R>
+where
+    R: Deref<Target = pac::usart1::RegisterBlock> + RccPeriph + BaudPeriph,
+{
+    /// Recalculate BRR to keep the current baud rate.
+    fn clocks_changed(&mut self, freqs: &ClockFreqs) {
+        self.set_baud(self.baud, freqs).ok();
+    }
+}
+
 // todo: Use those errors above.
 //
 // #[cfg(feature = "embedded_hal")]
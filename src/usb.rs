@@ -6,6 +6,10 @@
 //! Used on F303, L4x2, L4x3, L4x5, L5, G0, and G4. F4, L4x6 and H7 use the `usb_otg` module.
 //! For G0 series, USB is only available on G0B0, G0B1, G0C1, which the PAC doesn't yet differentiate,
 //! and this library doesn't yet support.
+//!
+//! USB requires a 48Mhz clock; check your clock config with `Clocks::validate_usb()`. These
+//! peripherals don't have hardware VBUS sensing; if your device is self-powered, monitor VBUS
+//! with a GPIO pin, and reset the bus on disconnect.
 
 /*
  Small caveat, the pac for the l4x5 exposes a USB peripheral instead
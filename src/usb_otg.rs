@@ -3,6 +3,9 @@
 //!
 //! Requires the `usbotg_fs` or `usbotg_hs` features.
 //! Used on F4, L4x6, and H7. Others use the `usb` module.
+//!
+//! USB requires a 48Mhz clock; check your clock config with `Clocks::validate_usb()`. To enable
+//! VBUS sensing, eg for self-powered devices, run `set_vbus_sensing()` after building the device.
 
 // Based on `stm3h7xx-hal`
 
@@ -223,3 +226,73 @@ cfg_if! {
         pub type Usb1UlpiBusType = UsbBus<Usb1Ulpi>;
     }
 }
+
+#[derive(Clone, Copy, PartialEq)]
+/// VBUS sensing options, for OTG1.
+pub enum VbusSense {
+    /// VBUS isn't monitored; the device acts as if a host is always connected. Use this for
+    /// bus-powered devices, or if the VBUS pin isn't connected.
+    Disabled,
+    /// VBUS is monitored on the OTG VBUS pin (eg PA9), so the D+ pull-up is only enabled while a
+    /// host is connected. Required for self-powered devices by the USB spec.
+    Enabled,
+}
+
+/// Configure VBUS sensing for OTG1. `synopsys-usb-otg` disables VBUS sensing when the bus is enabled,
+/// so run this after building the `UsbDevice`. Sets the GCCFG register, and the GOTGCTL register
+/// B-session override on newer variants.
+pub fn set_vbus_sensing(sense: VbusSense) {
+    let regs = unsafe { &(*Usb1GlobalRegType::ptr()) };
+    let enabled = sense == VbusSense::Enabled;
+
+    cfg_if! {
+        if #[cfg(feature = "l4")] {
+            let gccfg = &regs.fs_gccfg;
+            let gotgctl = &regs.fs_gotgctl;
+        } else {
+            let gccfg = &regs.gccfg;
+            let gotgctl = &regs.gotgctl;
+        }
+    }
+
+    cfg_if! {
+        if #[cfg(any(
+            feature = "f401",
+            feature = "f405",
+            feature = "f407",
+            feature = "f411",
+            feature = "f427",
+            feature = "f429"
+        ))] {
+            // Older cores: GCCFG bit 21 is NOVBUSSENS, and bit 19 is VBUSBSEN.
+            let _ = gotgctl;
+            gccfg.modify(|r, w| unsafe {
+                let val = if enabled {
+                    (r.bits() & !(1 << 21)) | (1 << 19)
+                } else {
+                    (r.bits() | (1 << 21)) & !(1 << 19)
+                };
+                w.bits(val)
+            });
+        } else {
+            // Newer cores: GCCFG bit 21 is VBDEN. When disabled, we override the B-session valid
+            // signal using GOTGCTL BVALOEN and BVALOVAL.
+            gccfg.modify(|r, w| unsafe {
+                let val = if enabled {
+                    r.bits() | (1 << 21)
+                } else {
+                    r.bits() & !(1 << 21)
+                };
+                w.bits(val)
+            });
+            gotgctl.modify(|r, w| unsafe {
+                let val = if enabled {
+                    r.bits() & !(0b11 << 6)
+                } else {
+                    r.bits() | (0b11 << 6)
+                };
+                w.bits(val)
+            });
+        }
+    }
+}
@@ -1,5 +1,6 @@
 //! This is an internal module that contains utility functionality used by other modules.
 
+use core::fmt;
 #[cfg(feature = "l4")]
 use core::ops::Deref;
 
@@ -13,7 +14,7 @@ use crate::pac::dma1;
 #[cfg(any(feature = "f3", feature = "l4"))]
 use crate::pac::DMA1;
 use crate::{
-    clocks::Clocks,
+    clocks::{ClockFreqs, Clocks},
     pac::{self, rcc::RegisterBlock},
 };
 
@@ -133,21 +134,22 @@ macro_rules! rcc_en_reset {
 
 pub(crate) use rcc_en_reset;
 
-/// Uart only. Important: This assumes we use the default UART clock.
+/// Used to provide the peripheral kernel clock, for calculating U[S]ART baud rates, I2C timings,
+/// and SPI baud rate dividers.
 pub trait BaudPeriph {
-    fn baud(clock_cfg: &Clocks) -> u32;
+    fn baud(clocks: &ClockFreqs) -> u32;
 }
 
 impl BaudPeriph for pac::USART1 {
-    fn baud(clock_cfg: &Clocks) -> u32 {
-        clock_cfg.apb2()
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.usart[0]
     }
 }
 
 #[cfg(not(any(feature = "wb", feature = "wl")))]
 impl BaudPeriph for pac::USART2 {
-    fn baud(clock_cfg: &Clocks) -> u32 {
-        clock_cfg.apb1()
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.usart[1]
     }
 }
 
@@ -163,63 +165,117 @@ impl BaudPeriph for pac::USART2 {
     feature = "wl",
 )))]
 impl BaudPeriph for pac::USART3 {
-    fn baud(clock_cfg: &Clocks) -> u32 {
-        clock_cfg.apb1()
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.usart[2]
     }
 }
 
 cfg_if! {
     if #[cfg(any(feature = "l4x6", feature = "h7"))] {
         impl BaudPeriph for pac::UART4 {
-            fn baud(clock_cfg: &Clocks) -> u32 {
-                clock_cfg.apb1()
+            fn baud(clocks: &ClockFreqs) -> u32 {
+                clocks.usart[3]
             }
         }
 
         impl BaudPeriph for pac::UART5 {
-            fn baud(clock_cfg: &Clocks) -> u32 {
-                clock_cfg.apb1()
+            fn baud(clocks: &ClockFreqs) -> u32 {
+                clocks.usart[4]
             }
         }
 
         #[cfg(any(feature = "h7", feature = "f401"))]
         impl BaudPeriph for pac::USART6 {
-            fn baud(clock_cfg: &Clocks) -> u32 {
-                clock_cfg.apb2()
+            fn baud(clocks: &ClockFreqs) -> u32 {
+                clocks.usart[5]
             }
         }
 
         #[cfg(feature = "h7")]
         impl BaudPeriph for pac::UART7 {
-            fn baud(clock_cfg: &Clocks) -> u32 {
-                clock_cfg.apb1()
+            fn baud(clocks: &ClockFreqs) -> u32 {
+                clocks.usart[6]
             }
         }
 
         #[cfg(feature = "h7")]
         impl BaudPeriph for pac::UART8 {
-            fn baud(clock_cfg: &Clocks) -> u32 {
-                clock_cfg.apb1()
+            fn baud(clocks: &ClockFreqs) -> u32 {
+                clocks.usart[7]
             }
         }
 
         #[cfg(feature = "h735")]
         impl BaudPeriph for pac::UART9 {
-            fn baud(clock_cfg: &Clocks) -> u32 {
-                clock_cfg.apb2()
+            fn baud(clocks: &ClockFreqs) -> u32 {
+                clocks.usart[8]
             }
         }
 
         #[cfg(feature = "h735")]
         impl BaudPeriph for pac::USART10 {
-            fn baud(clock_cfg: &Clocks) -> u32 {
-                clock_cfg.apb2()
+            fn baud(clocks: &ClockFreqs) -> u32 {
+                clocks.usart[9]
             }
         }
 
     }
 }
 
+impl BaudPeriph for pac::I2C1 {
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.i2c[0]
+    }
+}
+
+#[cfg(not(any(feature = "wb", feature = "f3x4")))]
+impl BaudPeriph for pac::I2C2 {
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.i2c[1]
+    }
+}
+
+#[cfg(any(feature = "h7", feature = "wb"))]
+impl BaudPeriph for pac::I2C3 {
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.i2c[2]
+    }
+}
+
+#[cfg(not(feature = "f301"))]
+impl BaudPeriph for pac::SPI1 {
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.spi[0]
+    }
+}
+
+#[cfg(not(any(feature = "f3x4", feature = "wb", feature = "wl")))]
+impl BaudPeriph for pac::SPI2 {
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.spi[1]
+    }
+}
+
+#[cfg(not(any(
+    feature = "f3x4",
+    feature = "f410",
+    feature = "g0",
+    feature = "wb",
+    feature = "wl"
+)))]
+impl BaudPeriph for pac::SPI3 {
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.spi[2]
+    }
+}
+
+#[cfg(feature = "h7")]
+impl BaudPeriph for pac::SPI4 {
+    fn baud(clocks: &ClockFreqs) -> u32 {
+        clocks.spi[3]
+    }
+}
+
 // todo: This trait is currently a one-off for adc, and isn't currently used.
 pub trait VrefPeriph {
     fn vref(clock_cfg: &Clocks) -> u32;
@@ -643,6 +699,78 @@ impl RccPeriph for pac::SAI4 {
     }
 }
 
+#[cfg(all(feature = "f4", not(feature = "f410")))]
+impl RccPeriph for pac::SDIO {
+    fn en_reset(rcc: &RegisterBlock) {
+        rcc_en_reset!(apb2, sdio, rcc);
+    }
+}
+
+#[cfg(all(feature = "l4", not(feature = "l4x6")))]
+impl RccPeriph for pac::SDMMC {
+    fn en_reset(rcc: &RegisterBlock) {
+        rcc_en_reset!(apb2, sdmmc, rcc);
+    }
+
+    fn read_chan() -> DmaChannel {
+        unimplemented!()
+    }
+
+    fn write_chan() -> DmaChannel {
+        unimplemented!()
+    }
+
+    fn read_sel<D: Deref<Target = dma1::RegisterBlock>>(_regs: &mut D) {
+        unimplemented!()
+    }
+
+    fn write_sel<D: Deref<Target = dma1::RegisterBlock>>(_regs: &mut D) {
+        unimplemented!()
+    }
+}
+
+#[cfg(any(feature = "l4x6", feature = "l5", feature = "h7"))]
+impl RccPeriph for pac::SDMMC1 {
+    fn en_reset(rcc: &RegisterBlock) {
+        cfg_if! {
+            if #[cfg(feature = "l4")] {
+                rcc_en_reset!(apb2, sdmmc, rcc);
+            } else if #[cfg(feature = "l5")] {
+                rcc_en_reset!(ahb2, sdmmc1, rcc);
+            } else {
+                rcc_en_reset!(ahb3, sdmmc1, rcc);
+            }
+        }
+    }
+
+    #[cfg(feature = "l4")]
+    fn read_chan() -> DmaChannel {
+        unimplemented!()
+    }
+
+    #[cfg(feature = "l4")]
+    fn write_chan() -> DmaChannel {
+        unimplemented!()
+    }
+
+    #[cfg(feature = "l4")]
+    fn read_sel<D: Deref<Target = dma1::RegisterBlock>>(_regs: &mut D) {
+        unimplemented!()
+    }
+
+    #[cfg(feature = "l4")]
+    fn write_sel<D: Deref<Target = dma1::RegisterBlock>>(_regs: &mut D) {
+        unimplemented!()
+    }
+}
+
+#[cfg(feature = "h7")]
+impl RccPeriph for pac::SDMMC2 {
+    fn en_reset(rcc: &RegisterBlock) {
+        rcc_en_reset!(ahb2, sdmmc2, rcc);
+    }
+}
+
 // #[cfg(any(feature = "g0c1", feature = "g4", feature = "h7"))]
 // impl RccPeriph for pac::FDCAN {
 //     #[cfg(feature = "g4")]
@@ -970,6 +1098,35 @@ cfg_if! {
     }
 }
 
+#[cfg(any(
+    feature = "g071",
+    feature = "g081",
+    feature = "g0b1",
+    feature = "g0c1",
+    feature = "g4",
+    feature = "l5"
+))]
+impl RccPeriph for pac::UCPD1 {
+    fn en_reset(rcc: &RegisterBlock) {
+        cfg_if! {
+            if #[cfg(feature = "g0")] {
+                rcc_en_reset!(apb1, ucpd1, rcc);
+            } else {
+                rcc.apb1enr2.modify(|_, w| w.ucpd1en().set_bit());
+                rcc.apb1rstr2.modify(|_, w| w.ucpd1rst().set_bit());
+                rcc.apb1rstr2.modify(|_, w| w.ucpd1rst().clear_bit());
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "g071", feature = "g081", feature = "g0b1", feature = "g0c1"))]
+impl RccPeriph for pac::UCPD2 {
+    fn en_reset(rcc: &RegisterBlock) {
+        rcc_en_reset!(apb1, ucpd2, rcc);
+    }
+}
+
 // todo: APB1LR2 on L5, and AHB4 on H7. Fix it. (I2C4)
 // I2cDevice::Four => {
 
@@ -1032,3 +1189,37 @@ cfg_if! {
 // }
 
 // L4 and F3 only have DMA on ADC 1 and 2.
+
+/// Write the names of the flags set in a status register value, separated by `+`; eg
+/// "OVR + MODF", or "None". `flags` pairs bit positions with names. Used by the `Debug`
+/// implementations of status types, like `SpiStatus`.
+pub(crate) fn fmt_flags(bits: u32, flags: &[(u8, &str)], f: &mut fmt::Formatter) -> fmt::Result {
+    let mut any = false;
+    for (_, name) in flags.iter().filter(|(bit, _)| bits & (1 << bit) != 0) {
+        if any {
+            f.write_str(" + ")?;
+        }
+        f.write_str(name)?;
+        any = true;
+    }
+    if !any {
+        f.write_str("None")?;
+    }
+    Ok(())
+}
+
+/// The `defmt` equivalent of `fmt_flags()`.
+#[cfg(feature = "defmt")]
+pub(crate) fn format_flags(bits: u32, flags: &[(u8, &str)], f: defmt::Formatter) {
+    let mut any = false;
+    for (_, name) in flags.iter().filter(|(bit, _)| bits & (1 << bit) != 0) {
+        if any {
+            defmt::write!(f, " + ");
+        }
+        defmt::write!(f, "{=str}", name);
+        any = true;
+    }
+    if !any {
+        defmt::write!(f, "None");
+    }
+}
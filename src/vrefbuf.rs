@@ -0,0 +1,150 @@
+//! Support for the internal voltage reference buffer (VREFBUF). This provides a reference voltage
+//! on the VREF+ pin, for use by the ADC, DAC, and external components. This lets you use a precise
+//! ADC and DAC reference without an external reference IC. Note that the VREF+ pin is bonded to
+//! VDDA on some packages; in that case, don't enable the buffer.
+//!
+//! See L4 RM, section 22: Voltage reference buffer (VREFBUF). G4 RM, section 23. H743 RM, section 27.
+
+use cfg_if::cfg_if;
+
+use crate::{pac::VREFBUF, MAX_ITERS};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+/// Internal reference voltage output. Sets the CSR register, VRS field.
+pub enum VrefVoltage {
+    #[cfg(not(feature = "h7"))]
+    /// VREF_OUT1: Around 2.048 V. Requires VDDA >= 2.4 V.
+    V2_048 = 0,
+    #[cfg(not(feature = "h7"))]
+    /// VREF_OUT2: Around 2.5 V. Requires VDDA >= 2.8 V.
+    V2_5 = 1,
+    #[cfg(feature = "g4")]
+    /// VREF_OUT3: Around 2.9 V. Requires VDDA >= 3.15 V.
+    V2_9 = 2,
+    #[cfg(feature = "h7")]
+    /// VREF_OUT1: Around 2.5 V. Requires VDDA >= 2.8 V.
+    V2_5 = 0b000,
+    #[cfg(feature = "h7")]
+    /// VREF_OUT2: Around 2.048 V. Requires VDDA >= 2.4 V.
+    V2_048 = 0b001,
+    #[cfg(feature = "h7")]
+    /// VREF_OUT3: Around 1.8 V.
+    V1_8 = 0b010,
+    #[cfg(feature = "h7")]
+    /// VREF_OUT4: Around 1.5 V.
+    V1_5 = 0b011,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// VREFBUF operating mode. Sets the CSR register, ENVR and HIZ fields. See RM, table
+/// `VREF buffer modes`.
+pub enum VrefMode {
+    /// (ENVR = 0, HIZ = 0): The buffer is off, and the VREF+ pin is pulled down to VSSA.
+    Disabled,
+    /// (ENVR = 0, HIZ = 1): The buffer is off, and the VREF+ pin is high-impedance. Use this
+    /// mode with an external voltage reference.
+    External,
+    /// (ENVR = 1, HIZ = 0): The buffer is on, and drives the VREF+ pin.
+    Internal,
+    /// (ENVR = 1, HIZ = 1): The buffer's output is disconnected, and the VREF+ pin is held by
+    /// the external capacitor. Use this to save power while the ADC and DAC aren't converting.
+    Hold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// VREFBUF errors.
+pub enum VrefError {
+    /// The buffer didn't report ready. Check that VDDA is high enough for the selected voltage, and that
+    /// VREF+ has the required decoupling capacitor.
+    NotReady,
+}
+
+/// Represents a voltage reference buffer (VREFBUF) peripheral.
+pub struct VrefBuf {
+    pub regs: VREFBUF,
+}
+
+impl VrefBuf {
+    /// Initialize the VREFBUF peripheral. On H7, this enables its RCC peripheral clock. On
+    /// other families, VREFBUF is clocked by SYSCFG, which is enabled in `Clocks::setup()`.
+    pub fn new(regs: VREFBUF) -> Self {
+        #[cfg(feature = "h7")]
+        {
+            let rcc = unsafe { &(*crate::pac::RCC::ptr()) };
+            rcc.apb4enr.modify(|_, w| w.vrefen().set_bit());
+        }
+
+        Self { regs }
+    }
+
+    /// Enable the buffer in internal reference mode, with the selected voltage, and wait for its
+    /// output to be ready.
+    pub fn enable(&mut self, voltage: VrefVoltage) -> Result<(), VrefError> {
+        self.set_voltage(voltage);
+        self.set_mode(VrefMode::Internal);
+        self.wait_ready()
+    }
+
+    /// Disable the buffer, pulling VREF+ to VSSA.
+    pub fn disable(&mut self) {
+        self.set_mode(VrefMode::Disabled);
+    }
+
+    /// Set the output voltage. RM: The VRS field may be changed while the buffer is enabled; wait
+    /// for `is_ready()` after changing it.
+    pub fn set_voltage(&mut self, voltage: VrefVoltage) {
+        cfg_if! {
+            if #[cfg(any(feature = "g0", feature = "g4", feature = "h7"))] {
+                self.regs.csr.modify(|_, w| unsafe { w.vrs().bits(voltage as u8) });
+            } else {
+                self.regs.csr.modify(|_, w| w.vrs().bit(voltage as u8 != 0));
+            }
+        }
+    }
+
+    /// Set the operating mode, eg to use an external reference, or to hold VREF+ with the
+    /// buffer's output disconnected.
+    pub fn set_mode(&mut self, mode: VrefMode) {
+        let (envr, hiz) = match mode {
+            VrefMode::Disabled => (false, false),
+            VrefMode::External => (false, true),
+            VrefMode::Internal => (true, false),
+            VrefMode::Hold => (true, true),
+        };
+
+        self.regs.csr.modify(|_, w| {
+            w.envr().bit(envr);
+            w.hiz().bit(hiz)
+        });
+    }
+
+    /// Returns true if the buffer's output voltage has reached its target.
+    pub fn is_ready(&self) -> bool {
+        self.regs.csr.read().vrr().bit_is_set()
+    }
+
+    /// Block until the buffer's output voltage is ready, or return an error if this doesn't happen
+    /// in a reasonable amount of time.
+    pub fn wait_ready(&self) -> Result<(), VrefError> {
+        let mut i = 0;
+        while !self.is_ready() {
+            i += 1;
+            if i >= MAX_ITERS {
+                return Err(VrefError::NotReady);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the 6-bit trim value. The factory trim value for `VREF_OUT1` is loaded at reset; it's
+    /// overwritten if you change this.
+    pub fn set_trim(&mut self, trim: u8) {
+        self.regs
+            .ccr
+            .write(|w| unsafe { w.trim().bits(trim & 0x3f) });
+    }
+}
@@ -0,0 +1,614 @@
+//! Wake source accounting for low-power modes. Arm a set of wake sources before entering Stop or
+//! Standby, and after waking, find which one fired. This combines EXTI pending flags, PWR wakeup
+//! pin flags, and RTC alarm, wakeup timer, timestamp, and tamper flags into a single `WakeSources`
+//! set, or a single `WakeEvent`.
+//!
+//! EXTI lines must have their edges configured separately; eg with `Pin::enable_interrupt()` for GPIO,
+//! `exti::enable()` for other lines, or `Rtc::set_wakeup()`. The PWR peripheral's clock must be enabled; `Rtc::new()` does this.
+//!
+//! Interrupt handlers usually clear their pending flags, and run before code following the `wfi`
+//! instruction. To see the flags, enter the low-power mode from `enter()`, which masks interrupts
+//! until the flags are read. (The MCU still wakes on masked interrupts.) After it returns, any
+//! pending handlers run.
+//!
+//! Example:
+//! ```rust
+//! let sources = WakeSources::new()
+//!     .exti_line(13) // Eg a button on PC13.
+//!     .wakeup_pin(2)
+//!     .rtc_wakeup_timer();
+//!
+//! wake::arm(&sources);
+//!
+//! let fired = wake::enter(|| low_power::stop(StopMode::Two));
+//!
+//! match fired.first() {
+//!     WakeEvent::ExtiLine(13) => (),
+//!     WakeEvent::RtcWakeupTimer => (),
+//!     _ => (),
+//! }
+//!
+//! wake::clear(&fired);
+//! ```
+//!
+//! Only EXTI lines 0 - 63 are covered. EXTI lines for some internal peripherals (direct lines) don't
+//! have pending flags; check the peripheral's own flags for those. On F3 and F4, PWR has a single
+//! wakeup flag, which is also set by RTC events; if no RTC flags are set, it's reported as all armed
+//! wakeup pins.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use cfg_if::cfg_if;
+use cortex_m::interrupt;
+
+use crate::{
+    exti::{EXTI_FPR, EXTI_IMR, EXTI_PR},
+    pac::{EXTI, PWR, RTC},
+};
+
+// Register offsets. PWR and RTC register names vary across families, and PAC field names vary
+// between variants.
+cfg_if! {
+    if #[cfg(any(feature = "f3", feature = "f4"))] {
+        // PWR_CR, CWUF bit.
+        const PWR_CR: usize = 0x00;
+        const CWUF: u32 = 1 << 2;
+        // PWR_CSR: WUF is bit 0. EWUPx bits: See `ewup_shift()`.
+        const PWR_CSR: usize = 0x04;
+    } else if #[cfg(feature = "h7")] {
+        // WKUPCR, WKUPFR, and WKUPEPR. WKUPENx bits start at bit 0.
+        const PWR_WUF_CLEAR: usize = 0x20;
+        const PWR_WUF: usize = 0x24;
+        const PWR_EWUP: usize = 0x28;
+    } else {
+        // SCR, SR1, and CR3. WUFI is bit 15 of SR1.
+        const PWR_WUF_CLEAR: usize = 0x18;
+        const PWR_WUF: usize = 0x10;
+        const PWR_EWUP: usize = 0x08;
+        const WUFI: u32 = 1 << 15;
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(feature = "f410", feature = "f412", feature = "f413"))] {
+        pub(crate) const WAKEUP_PIN_COUNT: u8 = 3;
+    } else if #[cfg(feature = "f446")] {
+        pub(crate) const WAKEUP_PIN_COUNT: u8 = 2;
+    } else if #[cfg(feature = "f4")] {
+        pub(crate) const WAKEUP_PIN_COUNT: u8 = 1;
+    } else if #[cfg(any(feature = "f3", feature = "wl"))] {
+        pub(crate) const WAKEUP_PIN_COUNT: u8 = 3;
+    } else if #[cfg(any(feature = "g0", feature = "h7"))] {
+        pub(crate) const WAKEUP_PIN_COUNT: u8 = 6;
+    } else {
+        pub(crate) const WAKEUP_PIN_COUNT: u8 = 5;
+    }
+}
+
+/// The PWR_CSR EWUPx bit position for a WKUP pin, starting at 1. EWUP1 is bit 8 on both
+/// families; further pins count up from it on F3, and down from it on F4.
+#[cfg(any(feature = "f3", feature = "f4"))]
+pub(crate) const fn ewup_shift(pin: u8) -> u8 {
+    if cfg!(feature = "f3") {
+        7 + pin
+    } else {
+        9 - pin
+    }
+}
+
+/// Convert a set of WKUP pins, with pin 1 at bit 0, to PWR_CSR EWUPx bits.
+#[cfg(any(feature = "f3", feature = "f4"))]
+fn ewup_bits(pins: u8) -> u32 {
+    (1..=WAKEUP_PIN_COUNT)
+        .filter(|pin| pins & (1 << (pin - 1)) != 0)
+        .fold(0, |acc, pin| acc | 1 << ewup_shift(pin))
+}
+
+/// Convert PWR_CSR EWUPx bits to a set of WKUP pins, with pin 1 at bit 0.
+#[cfg(any(feature = "f3", feature = "f4"))]
+fn ewup_pins(csr: u32) -> u8 {
+    (1..=WAKEUP_PIN_COUNT)
+        .filter(|pin| csr & (1 << ewup_shift(*pin)) != 0)
+        .fold(0, |acc, pin| acc | 1 << (pin - 1))
+}
+
+const RTC_WPR: usize = 0x24;
+// RTC_CR: ALRAIE, ALRBIE, WUTIE, and TSIE are bits 12 - 15.
+const RTC_IE_SHIFT: u8 = 12;
+
+cfg_if! {
+    if #[cfg(any(feature = "l5", feature = "g0", feature = "g4", feature = "l412", feature = "wl", feature = "h5"))] {
+        // RTC v3: Flags are in RTC_SR, starting at bit 0, and are cleared using RTC_SCR. Tamper
+        // events are handled by the separate TAMP peripheral, and aren't reported.
+        const RTC_CR: usize = 0x18;
+        const RTC_SR: usize = 0x50;
+        const RTC_SCR: usize = 0x5c;
+        const RTC_FLAG_SHIFT: u8 = 0;
+    } else {
+        // RTC v2: Flags are in RTC_ISR, starting at bit 8, and are cleared by writing 0.
+        const RTC_CR: usize = 0x08;
+        const RTC_ISR: usize = 0x0c;
+        const RTC_FLAG_SHIFT: u8 = 8;
+        // ISR, INIT bit. We must not change this when clearing flags.
+        const RTC_INIT: u32 = 1 << 7;
+        // TAMP1F - TAMP3F, in ISR.
+        const RTC_TAMPER_FLAGS: u32 = 0b111 << 13;
+        // TAMPCR (TAFCR on some families), TAMPIE bit.
+        const RTC_TAMPCR: usize = 0x40;
+        const RTC_TAMPIE: u32 = 1 << 2;
+    }
+}
+
+// RTC flag positions, relative to `RTC_FLAG_SHIFT`, and interrupt enable positions, relative to
+// `RTC_IE_SHIFT`.
+const RTC_ALARM_A: u32 = 1 << 0;
+const RTC_ALARM_B: u32 = 1 << 1;
+const RTC_WAKEUP_TIMER: u32 = 1 << 2;
+const RTC_TIMESTAMP: u32 = 1 << 3;
+
+fn reg(base: *const u8, offset: usize) -> *mut u32 {
+    unsafe { base.add(offset) as *mut u32 }
+}
+
+fn read(base: *const u8, offset: usize) -> u32 {
+    unsafe { read_volatile(reg(base, offset)) }
+}
+
+fn write(base: *const u8, offset: usize, val: u32) {
+    unsafe { write_volatile(reg(base, offset), val) }
+}
+
+fn modify(base: *const u8, offset: usize, f: impl FnOnce(u32) -> u32) {
+    write(base, offset, f(read(base, offset)));
+}
+
+fn exti() -> *const u8 {
+    EXTI::ptr() as *const u8
+}
+
+fn pwr() -> *const u8 {
+    PWR::ptr() as *const u8
+}
+
+fn rtc() -> *const u8 {
+    RTC::ptr() as *const u8
+}
+
+/// Run a closure with the RTC's write protection disabled.
+fn edit_rtc(f: impl FnOnce()) {
+    write(rtc(), RTC_WPR, 0xCA);
+    write(rtc(), RTC_WPR, 0x53);
+    f();
+    write(rtc(), RTC_WPR, 0xFF);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// A single wake source.
+pub enum WakeEvent {
+    /// An EXTI line, eg the GPIO pin number for lines 0 - 15.
+    ExtiLine(u8),
+    /// A WKUP pin, starting at 1.
+    WakeupPin(u8),
+    RtcAlarmA,
+    RtcAlarmB,
+    RtcWakeupTimer,
+    RtcTimestamp,
+    RtcTamper,
+    /// PWR's internal wakeup flag is set, without another flag identifying the source; eg a
+    /// peripheral on a direct EXTI line. Not available on F3, F4, or H7.
+    Internal,
+    /// No armed source's flag is set.
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+/// A set of wake sources. Used both to arm sources, and to report which fired.
+pub struct WakeSources {
+    /// EXTI lines 0 - 63. Bit 0 is line 0.
+    pub exti_lines: u64,
+    /// WKUP pins. Bit 0 is WKUP1.
+    pub wakeup_pins: u8,
+    pub rtc_alarm_a: bool,
+    pub rtc_alarm_b: bool,
+    pub rtc_wakeup_timer: bool,
+    pub rtc_timestamp: bool,
+    /// Not available on families with a separate TAMP peripheral: G0, G4, L412, L5, and WL.
+    pub rtc_tamper: bool,
+    /// PWR's internal wakeup flag. Only reported; not armed by `arm()`.
+    pub internal: bool,
+}
+
+impl WakeSources {
+    /// Create an empty set.
+    pub const fn new() -> Self {
+        Self {
+            exti_lines: 0,
+            wakeup_pins: 0,
+            rtc_alarm_a: false,
+            rtc_alarm_b: false,
+            rtc_wakeup_timer: false,
+            rtc_timestamp: false,
+            rtc_tamper: false,
+            internal: false,
+        }
+    }
+
+    /// Add an EXTI line. Panics if `line` is greater than 63.
+    pub const fn exti_line(mut self, line: u8) -> Self {
+        assert!(line < 64);
+        self.exti_lines |= 1 << line;
+        self
+    }
+
+    /// Add a WKUP pin, starting at 1. Panics if the MCU doesn't have this pin.
+    pub const fn wakeup_pin(mut self, pin: u8) -> Self {
+        assert!(pin >= 1 && pin <= WAKEUP_PIN_COUNT);
+        self.wakeup_pins |= 1 << (pin - 1);
+        self
+    }
+
+    pub const fn rtc_alarm_a(mut self) -> Self {
+        self.rtc_alarm_a = true;
+        self
+    }
+
+    pub const fn rtc_alarm_b(mut self) -> Self {
+        self.rtc_alarm_b = true;
+        self
+    }
+
+    pub const fn rtc_wakeup_timer(mut self) -> Self {
+        self.rtc_wakeup_timer = true;
+        self
+    }
+
+    pub const fn rtc_timestamp(mut self) -> Self {
+        self.rtc_timestamp = true;
+        self
+    }
+
+    pub const fn rtc_tamper(mut self) -> Self {
+        self.rtc_tamper = true;
+        self
+    }
+
+    /// Returns true if the set contains no sources.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::new()
+    }
+
+    /// Returns true if the set contains a given event.
+    pub fn contains(&self, event: WakeEvent) -> bool {
+        match event {
+            WakeEvent::ExtiLine(line) => line < 64 && self.exti_lines & (1 << line) != 0,
+            WakeEvent::WakeupPin(pin) => {
+                (1..=8).contains(&pin) && self.wakeup_pins & (1 << (pin - 1)) != 0
+            }
+            WakeEvent::RtcAlarmA => self.rtc_alarm_a,
+            WakeEvent::RtcAlarmB => self.rtc_alarm_b,
+            WakeEvent::RtcWakeupTimer => self.rtc_wakeup_timer,
+            WakeEvent::RtcTimestamp => self.rtc_timestamp,
+            WakeEvent::RtcTamper => self.rtc_tamper,
+            WakeEvent::Internal => self.internal,
+            WakeEvent::Unknown => self.is_empty(),
+        }
+    }
+
+    /// Sources contained in both sets.
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self {
+            exti_lines: self.exti_lines & other.exti_lines,
+            wakeup_pins: self.wakeup_pins & other.wakeup_pins,
+            rtc_alarm_a: self.rtc_alarm_a && other.rtc_alarm_a,
+            rtc_alarm_b: self.rtc_alarm_b && other.rtc_alarm_b,
+            rtc_wakeup_timer: self.rtc_wakeup_timer && other.rtc_wakeup_timer,
+            rtc_timestamp: self.rtc_timestamp && other.rtc_timestamp,
+            rtc_tamper: self.rtc_tamper && other.rtc_tamper,
+            internal: self.internal && other.internal,
+        }
+    }
+
+    /// A single source from the set. If several are present, WKUP pins (lowest first) take
+    /// priority, then RTC events, then EXTI lines (lowest first), then the internal wakeup flag.
+    pub fn first(&self) -> WakeEvent {
+        if self.wakeup_pins != 0 {
+            WakeEvent::WakeupPin(self.wakeup_pins.trailing_zeros() as u8 + 1)
+        } else if self.rtc_alarm_a {
+            WakeEvent::RtcAlarmA
+        } else if self.rtc_alarm_b {
+            WakeEvent::RtcAlarmB
+        } else if self.rtc_wakeup_timer {
+            WakeEvent::RtcWakeupTimer
+        } else if self.rtc_timestamp {
+            WakeEvent::RtcTimestamp
+        } else if self.rtc_tamper {
+            WakeEvent::RtcTamper
+        } else if self.exti_lines != 0 {
+            WakeEvent::ExtiLine(self.exti_lines.trailing_zeros() as u8)
+        } else if self.internal {
+            WakeEvent::Internal
+        } else {
+            WakeEvent::Unknown
+        }
+    }
+
+    /// Iterate over the EXTI lines in the set.
+    pub fn exti_line_iter(&self) -> impl Iterator<Item = u8> {
+        let lines = self.exti_lines;
+        (0..64).filter(move |i| lines & (1 << i) != 0)
+    }
+
+    fn rtc_bits(&self) -> u32 {
+        let mut result = 0;
+        if self.rtc_alarm_a {
+            result |= RTC_ALARM_A;
+        }
+        if self.rtc_alarm_b {
+            result |= RTC_ALARM_B;
+        }
+        if self.rtc_wakeup_timer {
+            result |= RTC_WAKEUP_TIMER;
+        }
+        if self.rtc_timestamp {
+            result |= RTC_TIMESTAMP;
+        }
+        result
+    }
+
+    fn set_rtc_bits(&mut self, bits: u32) {
+        self.rtc_alarm_a = bits & RTC_ALARM_A != 0;
+        self.rtc_alarm_b = bits & RTC_ALARM_B != 0;
+        self.rtc_wakeup_timer = bits & RTC_WAKEUP_TIMER != 0;
+        self.rtc_timestamp = bits & RTC_TIMESTAMP != 0;
+    }
+
+    #[cfg(any(feature = "f3", feature = "f4"))]
+    fn any_rtc(&self) -> bool {
+        self.rtc_bits() != 0 || self.rtc_tamper
+    }
+}
+
+/// Read the sources that are currently armed: Unmasked EXTI interrupt lines, enabled WKUP pins,
+/// and enabled RTC interrupts. Note that some EXTI lines, eg for internal peripherals, are
+/// unmasked by default.
+pub fn armed() -> WakeSources {
+    let mut result = WakeSources::new();
+
+    for (i, offset) in EXTI_IMR.iter().enumerate() {
+        result.exti_lines |= (read(exti(), *offset) as u64) << (i * 32);
+    }
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            result.wakeup_pins = ewup_pins(read(pwr(), PWR_CSR));
+        } else {
+            result.wakeup_pins = (read(pwr(), PWR_EWUP) & ((1 << WAKEUP_PIN_COUNT) - 1)) as u8;
+        }
+    }
+
+    result.set_rtc_bits(read(rtc(), RTC_CR) >> RTC_IE_SHIFT);
+
+    #[cfg(not(any(
+        feature = "l5",
+        feature = "g0",
+        feature = "g4",
+        feature = "l412",
+        feature = "wl",
+        feature = "h5"
+    )))]
+    {
+        result.rtc_tamper = read(rtc(), RTC_TAMPCR) & RTC_TAMPIE != 0;
+    }
+
+    result
+}
+
+/// Arm wake sources: Unmask EXTI interrupt lines, enable WKUP pins, and enable RTC interrupts.
+/// Clears their flags, so stale flags don't cause an immediate wakeup, or get reported. Other
+/// sources are left unchanged; use `disarm()` to remove them.
+pub fn arm(sources: &WakeSources) {
+    clear(sources);
+
+    for (i, offset) in EXTI_IMR.iter().enumerate() {
+        let lines = (sources.exti_lines >> (i * 32)) as u32;
+        modify(exti(), *offset, |v| v | lines);
+    }
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            let pins = ewup_bits(sources.wakeup_pins);
+            modify(pwr(), PWR_CSR, |v| v | pins);
+        } else {
+            modify(pwr(), PWR_EWUP, |v| v | sources.wakeup_pins as u32);
+        }
+    }
+
+    let rtc_ie = sources.rtc_bits() << RTC_IE_SHIFT;
+
+    #[cfg(not(any(
+        feature = "l5",
+        feature = "g0",
+        feature = "g4",
+        feature = "l412",
+        feature = "wl",
+        feature = "h5"
+    )))]
+    let tamper = sources.rtc_tamper;
+    #[cfg(any(
+        feature = "l5",
+        feature = "g0",
+        feature = "g4",
+        feature = "l412",
+        feature = "wl",
+        feature = "h5"
+    ))]
+    let tamper = false;
+
+    if rtc_ie != 0 || tamper {
+        edit_rtc(|| {
+            modify(rtc(), RTC_CR, |v| v | rtc_ie);
+            #[cfg(not(any(
+                feature = "l5",
+                feature = "g0",
+                feature = "g4",
+                feature = "l412",
+                feature = "wl",
+                feature = "h5"
+            )))]
+            if tamper {
+                modify(rtc(), RTC_TAMPCR, |v| v | RTC_TAMPIE);
+            }
+        });
+    }
+}
+
+/// Disarm wake sources: Mask EXTI interrupt lines, disable WKUP pins, and disable RTC interrupts.
+pub fn disarm(sources: &WakeSources) {
+    for (i, offset) in EXTI_IMR.iter().enumerate() {
+        let lines = (sources.exti_lines >> (i * 32)) as u32;
+        modify(exti(), *offset, |v| v & !lines);
+    }
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            let pins = ewup_bits(sources.wakeup_pins);
+            modify(pwr(), PWR_CSR, |v| v & !pins);
+        } else {
+            modify(pwr(), PWR_EWUP, |v| v & !(sources.wakeup_pins as u32));
+        }
+    }
+
+    let rtc_ie = sources.rtc_bits() << RTC_IE_SHIFT;
+
+    edit_rtc(|| {
+        modify(rtc(), RTC_CR, |v| v & !rtc_ie);
+        #[cfg(not(any(
+            feature = "l5",
+            feature = "g0",
+            feature = "g4",
+            feature = "l412",
+            feature = "wl",
+            feature = "h5"
+        )))]
+        if sources.rtc_tamper {
+            modify(rtc(), RTC_TAMPCR, |v| v & !RTC_TAMPIE);
+        }
+    });
+}
+
+/// Read the sources whose flags are set. This includes sources that aren't armed; use
+/// `fired()` to only include armed ones.
+pub fn pending() -> WakeSources {
+    let mut result = WakeSources::new();
+
+    for (i, offset) in EXTI_PR.iter().enumerate() {
+        result.exti_lines |= (read(exti(), *offset) as u64) << (i * 32);
+    }
+    for (i, offset) in EXTI_FPR.iter().enumerate() {
+        result.exti_lines |= (read(exti(), *offset) as u64) << (i * 32);
+    }
+
+    cfg_if! {
+        if #[cfg(any(feature = "l5", feature = "g0", feature = "g4", feature = "l412", feature = "wl", feature = "h5"))] {
+            result.set_rtc_bits(read(rtc(), RTC_SR) >> RTC_FLAG_SHIFT);
+        } else {
+            let isr = read(rtc(), RTC_ISR);
+            result.set_rtc_bits(isr >> RTC_FLAG_SHIFT);
+            result.rtc_tamper = isr & RTC_TAMPER_FLAGS != 0;
+        }
+    }
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            let csr = read(pwr(), PWR_CSR);
+            // A single WUF flag, which is also set by RTC events.
+            if csr & 1 != 0 && !result.any_rtc() {
+                result.wakeup_pins = ewup_pins(csr);
+            }
+        } else {
+            let wuf = read(pwr(), PWR_WUF);
+            result.wakeup_pins = (wuf & ((1 << WAKEUP_PIN_COUNT) - 1)) as u8;
+            #[cfg(not(feature = "h7"))]
+            {
+                result.internal = wuf & WUFI != 0;
+            }
+        }
+    }
+
+    result
+}
+
+/// Read the armed sources whose flags are set; ie the sources that may have caused a wakeup.
+/// The internal wakeup flag is included if set.
+pub fn fired() -> WakeSources {
+    let pending = pending();
+    let mut result = pending.intersection(&armed());
+    result.internal = pending.internal;
+    result
+}
+
+/// The armed source that caused a wakeup. See `WakeSources::first()` for which is reported if
+/// several have fired.
+pub fn wake_event() -> WakeEvent {
+    fired().first()
+}
+
+/// Clear flags for a set of sources: EXTI pending flags, PWR wakeup flags, and RTC flags. The
+/// internal wakeup flag is cleared by clearing the source peripheral's flag.
+pub fn clear(sources: &WakeSources) {
+    for (i, offset) in EXTI_PR.iter().enumerate() {
+        let lines = (sources.exti_lines >> (i * 32)) as u32;
+        if lines != 0 {
+            // Write 1 to clear.
+            write(exti(), *offset, lines);
+        }
+    }
+    for (i, offset) in EXTI_FPR.iter().enumerate() {
+        let lines = (sources.exti_lines >> (i * 32)) as u32;
+        if lines != 0 {
+            write(exti(), *offset, lines);
+        }
+    }
+
+    cfg_if! {
+        if #[cfg(any(feature = "f3", feature = "f4"))] {
+            if sources.wakeup_pins != 0 {
+                modify(pwr(), PWR_CR, |v| v | CWUF);
+            }
+        } else {
+            if sources.wakeup_pins != 0 {
+                write(pwr(), PWR_WUF_CLEAR, sources.wakeup_pins as u32);
+            }
+        }
+    }
+
+    let rtc_flags = sources.rtc_bits() << RTC_FLAG_SHIFT;
+
+    cfg_if! {
+        if #[cfg(any(feature = "l5", feature = "g0", feature = "g4", feature = "l412", feature = "wl", feature = "h5"))] {
+            if rtc_flags != 0 {
+                write(rtc(), RTC_SCR, rtc_flags);
+            }
+        } else {
+            let mut rtc_flags = rtc_flags;
+            if sources.rtc_tamper {
+                rtc_flags |= RTC_TAMPER_FLAGS;
+            }
+            if rtc_flags != 0 {
+                // Flags are cleared by writing 0; writing 1 has no effect. Keep INIT unchanged.
+                let init = read(rtc(), RTC_ISR) & RTC_INIT;
+                write(rtc(), RTC_ISR, (!rtc_flags & !RTC_INIT) | init);
+            }
+        }
+    }
+}
+
+/// Enter a low-power mode with interrupts masked, and return the armed sources that fired. Pass
+/// the function that enters the mode, eg `|| low_power::stop(StopMode::Two)`. Interrupt handlers
+/// for the wake sources run after this returns.
+pub fn enter(f: impl FnOnce()) -> WakeSources {
+    interrupt::free(|_| {
+        f();
+        fired()
+    })
+}
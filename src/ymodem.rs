@@ -0,0 +1,491 @@
+//! XMODEM and YMODEM receivers, for firmware updates over a serial port. Received data is written
+//! directly to internal flash, erasing pages as they're reached; eg to a bank or region a bootloader
+//! copies or boots from.
+//!
+//! Supports XMODEM-CRC, XMODEM-1K, and single-file YMODEM batches, with 128 and 1024-byte packets. The
+//! original XMODEM checksum mode isn't supported; most senders (eg `sx`/`sb` from lrzsz, Tera Term,
+//! and minicom) use CRC mode when the receiver requests it. With YMODEM, the file size from the header
+//! packet is used to trim padding, and to reject files that don't fit the destination region.
+//!
+//! `Receiver` doesn't block. Call `poll()` frequently, eg in a loop, with the current time in
+//! milliseconds; it reads available bytes, and handles timeouts and retries.
+//!
+//! Example:
+//! ```rust
+//! let uart = Usart::new(dp.USART1, 115_200, Default::default(), &clock_cfg);
+//! let flash = Flash::new(dp.FLASH);
+//!
+//! // Receive into the second half of a 512Kb flash.
+//! let mut rx = Receiver::new(uart, flash, Protocol::Ymodem, 0x0804_0000, 0x4_0000);
+//!
+//! loop {
+//!     match rx.poll(millis()) {
+//!         Ok(Status::Complete { size }) => break, // Eg verify, and swap banks.
+//!         Ok(_) => (),
+//!         Err(e) => break, // The transfer is cancelled.
+//!     }
+//! }
+//! ```
+
+use crate::{
+    flash::{self, Flash},
+    usart::Usart,
+};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Requests CRC mode, and the next file or packet.
+const CRC_REQUEST: u8 = b'C';
+
+/// Start byte, block number, its complement, 1024 data bytes, and a 16-bit CRC.
+const MAX_PACKET_SIZE: usize = 3 + 1_024 + 2;
+
+/// Time between `C` requests while waiting for a transfer, or file to start.
+const HANDSHAKE_INTERVAL_MS: u32 = 1_000;
+/// After this time within a packet without a byte, the packet is discarded, and a NAK sent.
+const BYTE_TIMEOUT_MS: u32 = 1_000;
+/// After this time between packets, a NAK is sent.
+const PACKET_TIMEOUT_MS: u32 = 10_000;
+/// The number of consecutive timeouts, or bad packets before giving up.
+const MAX_RETRIES: u8 = 10;
+/// The number of handshake requests before giving up, if the transfer hasn't started; ie a minute.
+const MAX_HANDSHAKES: u8 = 60;
+
+/// A byte-oriented transport for the receiver.
+pub trait Transport {
+    /// Read a received byte, if available. Must not block.
+    fn read_byte(&mut self) -> Option<u8>;
+    /// Write bytes. May block until they're sent.
+    fn write_bytes(&mut self, data: &[u8]);
+}
+
+impl<R> Transport for Usart<R>
+where
+    R: core::ops::Deref<Target = crate::pac::usart1::RegisterBlock>
+        + crate::util::RccPeriph
+        + crate::util::BaudPeriph,
+{
+    fn read_byte(&mut self) -> Option<u8> {
+        if self.rx_ready() {
+            Some(self.read_one())
+        } else {
+            None
+        }
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) {
+        self.write(data).ok();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Protocol {
+    /// XMODEM-CRC, or XMODEM-1K. The last packet is padded, so the received size is rounded up to the
+    /// packet size.
+    Xmodem,
+    /// YMODEM, with a single file.
+    Ymodem,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// The state of a transfer, returned by `poll()`.
+pub enum Status {
+    /// Waiting for the sender to start.
+    Waiting,
+    /// Receiving; the number of bytes written so far.
+    Receiving(usize),
+    /// The transfer completed successfully. `size` is the number of bytes written.
+    Complete { size: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// Errors that end a transfer. The receiver sends a cancel sequence to the sender, if it's not the
+/// one that cancelled.
+pub enum YmodemError {
+    /// The sender didn't start, or stopped responding.
+    Timeout,
+    /// The sender cancelled the transfer.
+    Cancelled,
+    /// Out of sequence packet, too many bad packets, or an invalid YMODEM header.
+    Protocol,
+    /// The file doesn't fit in the destination region.
+    TooLarge,
+    /// Erasing or writing flash failed.
+    Flash(flash::Error),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    /// Sending `C` requests, waiting for the first packet of a transfer or file.
+    Handshake,
+    /// Receiving data packets.
+    Data,
+    /// (YMODEM) The first EOT was NAKed; waiting for the second.
+    Eot,
+    /// (YMODEM) The file is complete; waiting for the null header that ends the batch.
+    BatchEnd,
+    Complete,
+    Failed,
+}
+
+/// CRC-16/XMODEM: Polynomial 0x1021, initial value 0.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0_u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Parse the file size from a YMODEM header packet: a NUL-terminated file name, followed by the
+/// size, in decimal, terminated by a space or NUL.
+fn parse_header_size(data: &[u8]) -> Option<usize> {
+    let name_end = data.iter().position(|b| *b == 0)?;
+    let fields = &data[name_end + 1..];
+
+    let mut size = 0_usize;
+    let mut digits = 0;
+    for byte in fields {
+        match byte {
+            b'0'..=b'9' => {
+                size = size.checked_mul(10)?.checked_add((byte - b'0') as usize)?;
+                digits += 1;
+            }
+            _ => break,
+        }
+    }
+
+    if digits > 0 {
+        Some(size)
+    } else {
+        None
+    }
+}
+
+/// Receives a file over XMODEM or YMODEM, writing it to flash.
+pub struct Receiver<IO> {
+    pub io: IO,
+    pub flash: Flash,
+    protocol: Protocol,
+    phase: Phase,
+    /// The address to write the next data to.
+    address: usize,
+    /// The end of the destination region.
+    end: usize,
+    /// Flash is erased up to this address.
+    erased_to: usize,
+    /// (YMODEM) From the header packet.
+    file_size: Option<usize>,
+    received: usize,
+    next_block: u8,
+    packet: [u8; MAX_PACKET_SIZE],
+    packet_pos: usize,
+    packet_len: usize,
+    last_cancel: bool,
+    retries: u8,
+    /// The time of the last received byte, or handshake request.
+    last_activity: u32,
+}
+
+impl<IO: Transport> Receiver<IO> {
+    /// Create a receiver, writing to the flash region starting at `address`, with a maximum size of
+    /// `len` bytes. `address` must be the start of a page (sector on F4 and H7); pages are erased as
+    /// they're reached. The region must not contain program memory.
+    pub fn new(io: IO, flash: Flash, protocol: Protocol, address: usize, len: usize) -> Self {
+        Self {
+            io,
+            flash,
+            protocol,
+            phase: Phase::Handshake,
+            address,
+            end: address + len,
+            erased_to: address,
+            file_size: None,
+            received: 0,
+            next_block: if protocol == Protocol::Ymodem { 0 } else { 1 },
+            packet: [0; MAX_PACKET_SIZE],
+            packet_pos: 0,
+            packet_len: 0,
+            last_cancel: false,
+            retries: 0,
+            // Send the first handshake on the first poll.
+            last_activity: 0_u32.wrapping_sub(HANDSHAKE_INTERVAL_MS),
+        }
+    }
+
+    /// The file size from the YMODEM header, if received.
+    pub fn file_size(&self) -> Option<usize> {
+        self.file_size
+    }
+
+    /// Process received bytes, and timeouts. `now_ms` is the current time, in milliseconds; it may
+    /// wrap. After completion, this returns `Status::Complete`, and after an error,
+    /// `YmodemError::Protocol`.
+    pub fn poll(&mut self, now_ms: u32) -> Result<Status, YmodemError> {
+        match self.phase {
+            Phase::Complete => return Ok(self.status()),
+            Phase::Failed => return Err(YmodemError::Protocol),
+            _ => (),
+        }
+
+        while let Some(byte) = self.io.read_byte() {
+            self.last_activity = now_ms;
+
+            if let Err(e) = self.handle_byte(byte) {
+                self.fail(!matches!(e, YmodemError::Cancelled));
+                return Err(e);
+            }
+            if self.phase == Phase::Complete {
+                return Ok(self.status());
+            }
+        }
+
+        if let Err(e) = self.handle_timeouts(now_ms) {
+            self.fail(true);
+            return Err(e);
+        }
+
+        Ok(self.status())
+    }
+
+    /// Cancel the transfer, eg on a user request.
+    pub fn cancel(&mut self) {
+        self.fail(true);
+    }
+
+    /// Release the transport, and flash.
+    pub fn free(self) -> (IO, Flash) {
+        (self.io, self.flash)
+    }
+
+    fn status(&self) -> Status {
+        match self.phase {
+            Phase::Complete => Status::Complete {
+                size: self.received,
+            },
+            Phase::Handshake if self.received == 0 => Status::Waiting,
+            _ => Status::Receiving(self.received),
+        }
+    }
+
+    fn fail(&mut self, send_cancel: bool) {
+        if send_cancel {
+            self.io.write_bytes(&[CAN, CAN, CAN]);
+        }
+        self.phase = Phase::Failed;
+    }
+
+    fn handle_timeouts(&mut self, now_ms: u32) -> Result<(), YmodemError> {
+        let elapsed = now_ms.wrapping_sub(self.last_activity);
+
+        let timeout = match self.phase {
+            Phase::Handshake | Phase::BatchEnd => HANDSHAKE_INTERVAL_MS,
+            _ if self.packet_pos > 0 => BYTE_TIMEOUT_MS,
+            _ => PACKET_TIMEOUT_MS,
+        };
+
+        if elapsed < timeout {
+            return Ok(());
+        }
+
+        let max_retries = if self.phase == Phase::Handshake && self.file_size.is_none() {
+            MAX_HANDSHAKES
+        } else {
+            MAX_RETRIES
+        };
+
+        self.retries += 1;
+        if self.retries > max_retries {
+            return Err(YmodemError::Timeout);
+        }
+
+        self.last_activity = now_ms;
+        self.packet_pos = 0;
+
+        match self.phase {
+            Phase::Handshake | Phase::BatchEnd => self.io.write_bytes(&[CRC_REQUEST]),
+            _ => self.io.write_bytes(&[NAK]),
+        }
+
+        Ok(())
+    }
+
+    fn handle_byte(&mut self, byte: u8) -> Result<(), YmodemError> {
+        if self.packet_pos > 0 {
+            self.packet[self.packet_pos] = byte;
+            self.packet_pos += 1;
+
+            if self.packet_pos == self.packet_len {
+                self.packet_pos = 0;
+                self.handle_packet()?;
+            }
+            return Ok(());
+        }
+
+        // Two consecutive CANs cancel the transfer.
+        let cancel = byte == CAN;
+        if cancel && self.last_cancel {
+            return Err(YmodemError::Cancelled);
+        }
+        self.last_cancel = cancel;
+
+        match byte {
+            SOH | STX => {
+                self.packet_len = if byte == SOH {
+                    3 + 128 + 2
+                } else {
+                    MAX_PACKET_SIZE
+                };
+                self.packet[0] = byte;
+                self.packet_pos = 1;
+            }
+            EOT => self.handle_eot(),
+            // Ignore anything else between packets; eg line noise, or a sender's startup text.
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn handle_eot(&mut self) {
+        match (self.protocol, self.phase) {
+            (Protocol::Xmodem, Phase::Data) => {
+                self.io.write_bytes(&[ACK]);
+                self.phase = Phase::Complete;
+            }
+            // YMODEM: NAK the first EOT, and ACK the second, then request the next file.
+            (Protocol::Ymodem, Phase::Data) => {
+                self.io.write_bytes(&[NAK]);
+                self.phase = Phase::Eot;
+            }
+            (Protocol::Ymodem, Phase::Eot) => {
+                self.io.write_bytes(&[ACK, CRC_REQUEST]);
+                self.phase = Phase::BatchEnd;
+                self.next_block = 0;
+                self.retries = 0;
+            }
+            // A repeated EOT, if our ACK was lost.
+            (_, Phase::Complete) | (_, Phase::BatchEnd) => self.io.write_bytes(&[ACK]),
+            _ => self.io.write_bytes(&[NAK]),
+        }
+    }
+
+    fn handle_packet(&mut self) -> Result<(), YmodemError> {
+        let data_len = self.packet_len - 5;
+        let block = self.packet[1];
+
+        let crc_received =
+            u16::from_be_bytes([self.packet[3 + data_len], self.packet[4 + data_len]]);
+
+        if block != !self.packet[2] || crc16(&self.packet[3..3 + data_len]) != crc_received {
+            self.retries += 1;
+            if self.retries > MAX_RETRIES {
+                return Err(YmodemError::Protocol);
+            }
+            self.io.write_bytes(&[NAK]);
+            return Ok(());
+        }
+
+        // A repeated packet, if our ACK was lost. For a YMODEM header, request the first data packet
+        // again too.
+        if block == self.next_block.wrapping_sub(1) {
+            if self.phase == Phase::Handshake {
+                self.io.write_bytes(&[ACK, CRC_REQUEST]);
+            } else {
+                self.io.write_bytes(&[ACK]);
+            }
+            return Ok(());
+        }
+
+        if block != self.next_block {
+            return Err(YmodemError::Protocol);
+        }
+
+        self.retries = 0;
+
+        if self.protocol == Protocol::Ymodem && block == 0 && self.phase != Phase::Data {
+            return self.handle_header(data_len);
+        }
+
+        let mut len = data_len;
+        if let Some(size) = self.file_size {
+            len = len.min(size - self.received);
+        }
+
+        self.write(len)?;
+
+        self.received += len;
+        self.next_block = self.next_block.wrapping_add(1);
+        self.phase = Phase::Data;
+        self.io.write_bytes(&[ACK]);
+
+        Ok(())
+    }
+
+    /// Handle a YMODEM header packet: Block 0, with the file name and size.
+    fn handle_header(&mut self, data_len: usize) -> Result<(), YmodemError> {
+        let data = &self.packet[3..3 + data_len];
+
+        // An empty file name ends the batch.
+        if data[0] == 0 {
+            self.io.write_bytes(&[ACK]);
+            self.phase = Phase::Complete;
+            return Ok(());
+        }
+
+        // Only single-file batches are supported.
+        if self.phase == Phase::BatchEnd {
+            return Err(YmodemError::Protocol);
+        }
+
+        let size = parse_header_size(data).ok_or(YmodemError::Protocol)?;
+        if size > self.end - self.address {
+            return Err(YmodemError::TooLarge);
+        }
+
+        self.file_size = Some(size);
+        self.next_block = 1;
+        // Stay in the handshake phase, requesting the first data packet.
+        self.io.write_bytes(&[ACK, CRC_REQUEST]);
+
+        Ok(())
+    }
+
+    /// Write the first `len` bytes of the received packet's data to flash, erasing pages as
+    /// required.
+    fn write(&mut self, len: usize) -> Result<(), YmodemError> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let address = self.address + self.received;
+        if address + len > self.end {
+            return Err(YmodemError::TooLarge);
+        }
+
+        while self.erased_to < address + len {
+            let (bank, page, page_size) = self.flash.page_at(self.erased_to);
+            self.flash
+                .erase_page(bank, page)
+                .map_err(YmodemError::Flash)?;
+            self.erased_to += page_size;
+        }
+
+        self.flash
+            .write(address, &self.packet[3..3 + len])
+            .map_err(YmodemError::Flash)
+    }
+}